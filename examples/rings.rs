@@ -0,0 +1,36 @@
+//! An external pattern generator: concentric rings that drift outward one
+//! pixel per frame. See src/external.rs for the protocol, and rings.toml for
+//! a script that uses it. Build with `cargo build --release --example rings`.
+
+use std::io::Write;
+
+fn var(name: &str) -> usize {
+    std::env::var(name)
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .unwrap_or_else(|| panic!("{} is not set", name))
+}
+
+fn main() -> std::io::Result<()> {
+    let (w, h, stride) = (
+        var("SCREEN_TEST_WIDTH"),
+        var("SCREEN_TEST_HEIGHT"),
+        var("SCREEN_TEST_STRIDE"),
+    );
+    let frame = var("SCREEN_TEST_FRAME");
+    let (cx, cy) = (w as f64 / 2.0, h as f64 / 2.0);
+
+    let mut buf = vec![0u8; stride * h];
+    for (y, row) in buf.chunks_exact_mut(stride).enumerate() {
+        for (x, px) in row[..w * 4].chunks_exact_mut(4).enumerate() {
+            let r = (x as f64 - cx).hypot(y as f64 - cy) as usize;
+            let v = if ((r + 32 - frame % 32) / 16).is_multiple_of(2) {
+                255
+            } else {
+                0
+            };
+            px.copy_from_slice(&[v, v, v, 0]);
+        }
+    }
+    std::io::stdout().lock().write_all(&buf)
+}
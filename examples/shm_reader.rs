@@ -0,0 +1,78 @@
+//! Reads frames exported with `--shm-export NAME` and prints each new one's
+//! number, size and mean R, G, B. See src/shm.rs for the layout. Build with
+//! `cargo build --release --example shm_reader` and run with the same NAME.
+
+use std::fs::File;
+use std::os::unix::fs::FileExt;
+use std::time::Duration;
+
+// Offsets into the header
+const HEADER_SIZE: usize = 12;
+const WIDTH: usize = 16;
+const HEIGHT: usize = 20;
+const STRIDE: usize = 24;
+const SEQUENCE: usize = 32;
+const TIMESTAMP: usize = 40;
+
+fn u32_at(buf: &[u8], at: usize) -> usize {
+    u32::from_ne_bytes(buf[at..at + 4].try_into().unwrap()) as usize
+}
+
+fn u64_at(buf: &[u8], at: usize) -> u64 {
+    u64::from_ne_bytes(buf[at..at + 8].try_into().unwrap())
+}
+
+fn main() -> std::io::Result<()> {
+    let name = std::env::args().nth(1).expect("usage: shm_reader NAME");
+    let file = File::open(format!("/dev/shm/{}", name))?;
+    let mut last = 0;
+    loop {
+        // The whole file, header first, then the sequence again: the copy
+        // is good if the sequence was even and hasn't moved since
+        let len = file.metadata()?.len() as usize;
+        if len < 48 {
+            std::thread::sleep(Duration::from_millis(2));
+            continue;
+        }
+        let mut buf = vec![0u8; len];
+        file.read_exact_at(&mut buf, 0)?;
+        let mut again = [0u8; 8];
+        file.read_exact_at(&mut again, SEQUENCE as u64)?;
+        let sequence = u64_at(&buf, SEQUENCE);
+        if sequence % 2 == 1 || sequence != u64::from_ne_bytes(again) || sequence == last {
+            std::thread::sleep(Duration::from_millis(2));
+            continue;
+        }
+        last = sequence;
+
+        let (w, h, stride) = (
+            u32_at(&buf, WIDTH),
+            u32_at(&buf, HEIGHT),
+            u32_at(&buf, STRIDE),
+        );
+        let pixels = &buf[u32_at(&buf, HEADER_SIZE)..];
+        if stride < w * 4 || w == 0 || pixels.len() < stride * h {
+            continue;
+        }
+        let mut sum = [0u64; 3];
+        for row in pixels.chunks_exact(stride).take(h) {
+            for px in row[..w * 4].chunks_exact(4) {
+                // B, G, R, X
+                sum[0] += px[2] as u64;
+                sum[1] += px[1] as u64;
+                sum[2] += px[0] as u64;
+            }
+        }
+        let n = (w * h).max(1) as f64;
+        println!(
+            "frame {} at {} ns: {}x{}, mean R {:.1} G {:.1} B {:.1}",
+            sequence / 2,
+            u64_at(&buf, TIMESTAMP),
+            w,
+            h,
+            sum[0] as f64 / n,
+            sum[1] as f64 / n,
+            sum[2] as f64 / n
+        );
+    }
+}
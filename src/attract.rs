@@ -0,0 +1,123 @@
+//! `--attract`: an unattended show-floor loop of moving patterns, faded
+//! into each other through black, with the whole picture slowly orbiting a
+//! few pixels so nothing sits on the same pixels for long.
+
+use std::time::{Duration, Instant};
+
+use crate::{AppState, Bounce, ChannelFn, PatternKind, RampScroll, Step};
+
+// Fade out at the end of each step and in at the start of the next
+const FADE: Duration = Duration::from_millis(800);
+
+// The picture walks round a square this many pixels either side of center,
+// one pixel every ORBIT_STEP
+const ORBIT: usize = 4;
+const ORBIT_STEP: Duration = Duration::from_secs(30);
+
+/// The built-in sequence, unless the config's [attract] steps replace it
+pub fn default_steps() -> Vec<Step> {
+    vec![
+        // Channels a third of a cycle apart walk the hue around the wheel,
+        // slowly enough to look like a drift rather than a flash
+        Step {
+            pat: PatternKind::Oscillator,
+            osc: [0.0, 1.0 / 3.0, 2.0 / 3.0].map(|phase| ChannelFn {
+                freq_hz: 0.1,
+                phase,
+                ..Default::default()
+            }),
+            ..Default::default()
+        },
+        Step {
+            pat: PatternKind::RampScroll,
+            ramp: RampScroll {
+                speed: 120,
+                light: true,
+            },
+            ..Default::default()
+        },
+        Step {
+            pat: PatternKind::Bounce,
+            bounce: Bounce::default(),
+            ..Default::default()
+        },
+    ]
+}
+
+pub struct Attract {
+    // Where the orbit is timed from
+    started: Instant,
+    // --auto, for when each step ends
+    auto: Option<Duration>,
+}
+
+impl Attract {
+    pub fn new(auto: Option<Duration>) -> Self {
+        Self {
+            started: Instant::now(),
+            auto,
+        }
+    }
+
+    /// Fades and shifts the finished frame
+    pub fn finish(
+        &self,
+        buf: &mut [u8],
+        stride: usize,
+        w: usize,
+        h: usize,
+        state: &AppState,
+        now: Instant,
+    ) {
+        let since = now.saturating_duration_since(state.step_started);
+        let until = state
+            .auto_deadline(self.auto)
+            .map_or(FADE, |end| end.saturating_duration_since(now));
+        let level = since.min(until).as_secs_f64() / FADE.as_secs_f64();
+        if level < 1.0 {
+            fade(buf, stride, w, h, level);
+        }
+
+        let (dx, dy) = orbit(now.saturating_duration_since(self.started));
+        if w > 2 * ORBIT && h > 2 * ORBIT && (dx, dy) != (0, 0) {
+            shift(buf, stride, w, h, dx, dy);
+        }
+    }
+}
+
+// Scales every channel by `level`, 0 to 1
+fn fade(buf: &mut [u8], stride: usize, w: usize, h: usize, level: f64) {
+    let lut: Vec<u8> = (0..=255u32)
+        .map(|v| (v as f64 * level).round() as u8)
+        .collect();
+    for row in buf.chunks_mut(stride).take(h) {
+        for v in &mut row[..w * 4] {
+            *v = lut[*v as usize];
+        }
+    }
+}
+
+// Offset from center at `elapsed`, once round a square of side 2 * ORBIT
+// every 8 * ORBIT steps
+fn orbit(elapsed: Duration) -> (isize, isize) {
+    let side = 2 * ORBIT;
+    let i = (elapsed.as_secs() / ORBIT_STEP.as_secs()) as usize % (4 * side);
+    let (x, y) = match i / side {
+        0 => (i % side, 0),
+        1 => (side, i % side),
+        2 => (side - i % side, side),
+        _ => (0, side - i % side),
+    };
+    (x as isize - ORBIT as isize, y as isize - ORBIT as isize)
+}
+
+// Moves the picture by (dx, dy), what leaves one edge coming back in at the
+// other
+fn shift(buf: &mut [u8], stride: usize, w: usize, h: usize, dx: isize, dy: isize) {
+    let x = dx.rem_euclid(w as isize) as usize * 4;
+    for row in buf.chunks_mut(stride).take(h) {
+        row[..w * 4].rotate_right(x);
+    }
+    let rows = &mut buf[..h * stride];
+    rows.rotate_right(dy.rem_euclid(h as isize) as usize * stride);
+}
@@ -0,0 +1,196 @@
+//! `--av-audio PCM`: an audio click for the AV sync pattern through ALSA,
+//! so lip-sync gear gets a marker on the audio path too, offset from the
+//! flash by `--av-offset` milliseconds (positive: audio late).
+//!
+//! Samples reach the speaker a buffer's length after they are written, so
+//! a click can't wait for its flash to be seen. Instead every flash that
+//! reaches the screen schedules the click of the next one, a flash period
+//! later. That holds as long as no frames are missed; the event log has
+//! the vblank of every flash to check against.
+//!
+//! The PCM is fed from its own thread a few milliseconds at a time, so an
+//! underrun only costs a gap in the audio and never holds up a flip. Every
+//! click is reported back with the time its first sample was written and
+//! the time the device's queue says it plays, both on CLOCK_MONOTONIC like
+//! the vblank timestamps. Only opening the PCM needs the `alsa` feature.
+
+use anyhow::Result;
+use std::sync::mpsc::{Receiver, Sender, channel};
+use std::time::Duration;
+
+/// One click as written to the PCM, all times CLOCK_MONOTONIC
+#[derive(Clone, Copy, Debug)]
+pub struct Click {
+    /// Vblank the flash it marks is expected at
+    pub flash_ns: u64,
+    /// When it was meant to play: the flash plus the offset
+    pub target_ns: u64,
+    /// When its first sample went to the PCM
+    pub written_ns: u64,
+    /// When that sample plays, from the PCM's delay at the time
+    pub play_ns: u64,
+}
+
+#[cfg_attr(not(feature = "alsa"), allow(dead_code))]
+enum Request {
+    // Expected flash and click times
+    Click(u64, u64),
+    Cancel,
+}
+
+pub struct AvAudio {
+    tx: Sender<Request>,
+    clicks: Receiver<Click>,
+    offset_ns: i64,
+}
+
+impl AvAudio {
+    pub fn open(device: &str, offset_ms: f64) -> Result<Self> {
+        let (tx, requests) = channel();
+        let (done, clicks) = channel();
+        pcm::spawn(device, requests, done)?;
+        Ok(Self {
+            tx,
+            clicks,
+            offset_ns: (offset_ms * 1e6) as i64,
+        })
+    }
+
+    /// Called when a flash reaches the screen, with its vblank time and the
+    /// time until the next one
+    pub fn flash(&self, vblank: Duration, period: Duration) {
+        let flash_ns = (vblank + period).as_nanos() as u64;
+        let target_ns = flash_ns.saturating_add_signed(self.offset_ns);
+        let _ = self.tx.send(Request::Click(flash_ns, target_ns));
+    }
+
+    /// Drops the clicks still to come, e.g. once the pattern is left
+    pub fn cancel(&self) {
+        let _ = self.tx.send(Request::Cancel);
+    }
+
+    /// Clicks written since the last call
+    pub fn written(&self) -> Vec<Click> {
+        self.clicks.try_iter().collect()
+    }
+}
+
+#[cfg(not(feature = "alsa"))]
+mod pcm {
+    use super::{Click, Request};
+    use anyhow::Result;
+    use std::sync::mpsc::{Receiver, Sender};
+
+    pub fn spawn(_device: &str, _requests: Receiver<Request>, _done: Sender<Click>) -> Result<()> {
+        anyhow::bail!("--av-audio needs a build with the alsa feature")
+    }
+}
+
+#[cfg(feature = "alsa")]
+mod pcm {
+    use super::{Click, Request};
+    use alsa::pcm::{Access, Format, HwParams, PCM};
+    use alsa::{Direction, ValueOr};
+    use anyhow::{Context, Result};
+    use nix::time::{ClockId, clock_gettime};
+    use std::collections::VecDeque;
+    use std::sync::mpsc::{Receiver, Sender};
+
+    const RATE: u32 = 48_000;
+    // Written at a time; the device buffer holds four of these
+    const PERIOD_FRAMES: usize = 240;
+    const CLICK_HZ: f64 = 1000.0;
+    // About as long as the flash
+    const CLICK_FRAMES: usize = RATE as usize / 30;
+
+    fn mono_ns() -> u64 {
+        clock_gettime(ClockId::CLOCK_MONOTONIC)
+            .map(|ts| ts.tv_sec() as u64 * 1_000_000_000 + ts.tv_nsec() as u64)
+            .unwrap_or(0)
+    }
+
+    pub fn spawn(device: &str, requests: Receiver<Request>, done: Sender<Click>) -> Result<()> {
+        let pcm = PCM::new(device, Direction::Playback, false)
+            .with_context(|| format!("could not open PCM {}", device))?;
+        {
+            let hw = HwParams::any(&pcm)?;
+            hw.set_channels(1)?;
+            hw.set_rate(RATE, ValueOr::Nearest)?;
+            hw.set_format(Format::s16())?;
+            hw.set_access(Access::RWInterleaved)?;
+            hw.set_period_size_near(PERIOD_FRAMES as i64, ValueOr::Nearest)?;
+            hw.set_buffer_size_near(PERIOD_FRAMES as i64 * 4)?;
+            pcm.hw_params(&hw)
+                .with_context(|| format!("PCM {} won't take 48 kHz mono S16", device))?;
+        }
+        let rate = pcm.hw_params_current()?.get_rate()? as u64;
+        eprintln!("AV sync audio on PCM {} at {} Hz", device, rate);
+
+        std::thread::spawn(move || feed(pcm, rate, requests, done));
+        Ok(())
+    }
+
+    // Keeps the PCM topped up with silence, starting each click on the
+    // sample that plays at its target time
+    fn feed(pcm: PCM, rate: u64, requests: Receiver<Request>, done: Sender<Click>) {
+        let Ok(io) = pcm.io_i16() else {
+            return;
+        };
+        let sample_ns = 1_000_000_000 / rate;
+        let mut pending: VecDeque<(u64, u64)> = VecDeque::new();
+        // Samples of the current click played so far
+        let mut playing: Option<usize> = None;
+        let mut buf = [0i16; PERIOD_FRAMES];
+
+        loop {
+            for request in requests.try_iter() {
+                match request {
+                    Request::Click(flash_ns, target_ns) => pending.push_back((flash_ns, target_ns)),
+                    Request::Cancel => pending.clear(),
+                }
+            }
+            let now = mono_ns();
+            let delay = pcm.delay().unwrap_or(0).max(0) as u64;
+            let start_ns = now + delay * sample_ns;
+
+            for (i, sample) in buf.iter_mut().enumerate() {
+                let t = start_ns + i as u64 * sample_ns;
+                while let Some(&(flash_ns, target_ns)) = pending.front()
+                    && target_ns <= t
+                {
+                    pending.pop_front();
+                    // One that is already more than a period late is
+                    // dropped rather than played off its mark
+                    if t - target_ns < PERIOD_FRAMES as u64 * sample_ns {
+                        playing = Some(0);
+                        let _ = done.send(Click {
+                            flash_ns,
+                            target_ns,
+                            written_ns: now,
+                            play_ns: t,
+                        });
+                    }
+                }
+                *sample = match &mut playing {
+                    Some(n) if *n < CLICK_FRAMES => {
+                        let phase = *n as f64 * CLICK_HZ / rate as f64;
+                        *n += 1;
+                        ((phase * std::f64::consts::TAU).sin() * 0.5 * i16::MAX as f64) as i16
+                    }
+                    _ => {
+                        playing = None;
+                        0
+                    }
+                };
+            }
+
+            // An underrun only costs a gap; anything else ends the audio
+            if let Err(e) = io.writei(&buf)
+                && let Err(e) = pcm.try_recover(e, true)
+            {
+                eprintln!("AV sync audio stopped: {}", e);
+                return;
+            }
+        }
+    }
+}
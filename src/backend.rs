@@ -0,0 +1,45 @@
+//! What the pattern code needs from a display, so the same drawing works
+//! on a DRM `Surface` and on an fbdev framebuffer (`--fbdev`).
+
+use anyhow::Result;
+
+use crate::draw::SubpixelOrder;
+use crate::edid;
+
+pub trait Backend {
+    /// Visible size in pixels
+    fn size(&self) -> (usize, usize);
+
+    /// Bytes per row of the stage patterns draw into
+    fn stride(&self) -> usize;
+
+    fn refresh_hz(&self) -> f64;
+
+    /// Frames presented so far, for patterns that step once per frame
+    fn frames_presented(&self) -> u64;
+
+    /// Output and mode, for labels
+    fn mode_label(&self) -> String;
+
+    fn edid(&self) -> Option<&[u8]> {
+        None
+    }
+
+    fn subpixel(&self) -> Option<SubpixelOrder> {
+        None
+    }
+
+    /// Physical width and height of the picture in mm, if the display says
+    fn size_mm(&self) -> Option<(u32, u32)> {
+        self.edid().and_then(edid::physical_size_mm)
+    }
+
+    /// Connector, mode and the state of the link, read afresh, where the
+    /// display has link properties
+    fn link_summary(&self) -> Option<String> {
+        None
+    }
+
+    /// Copies a stage of `size()` pixels in BGRX order onto the screen
+    fn write_and_present(&mut self, src: &[u8], src_stride: usize) -> Result<()>;
+}
@@ -0,0 +1,125 @@
+use anyhow::{Context, Result, bail};
+use std::fs::{File, OpenOptions};
+use std::os::unix::fs::FileExt;
+use std::path::{Path, PathBuf};
+
+const CLASS_DIR: &str = "/sys/class/backlight";
+
+/// A panel backlight under /sys/class/backlight, set in percent of its
+/// max_brightness. The level it had when opened is put back on drop.
+pub struct Backlight {
+    name: String,
+    brightness: File,
+    max: u32,
+    original: u32,
+    raw: u32,
+}
+
+impl Backlight {
+    /// Opens the device `name`, or with None the one the kernel suggests
+    /// userspace should use: firmware first, then platform, then raw
+    pub fn open(name: Option<&str>) -> Result<Self> {
+        let dir = match name {
+            Some(name) => Path::new(CLASS_DIR).join(name),
+            None => find_default()?,
+        };
+        let name = dir
+            .file_name()
+            .map_or_else(String::new, |n| n.to_string_lossy().into_owned());
+        let max = read_u32(&dir.join("max_brightness"))?;
+        if max == 0 {
+            bail!("backlight {} has a max_brightness of 0", name);
+        }
+        let original = read_u32(&dir.join("brightness"))?;
+        let brightness = OpenOptions::new()
+            .write(true)
+            .open(dir.join("brightness"))
+            .with_context(|| format!("could not open backlight {} for writing", name))?;
+        eprintln!(
+            "Backlight: {}, at {} of {} ({}%)",
+            name,
+            original,
+            max,
+            percent_of(original, max)
+        );
+        Ok(Self {
+            name,
+            brightness,
+            max,
+            original,
+            raw: original,
+        })
+    }
+
+    pub fn max(&self) -> u32 {
+        self.max
+    }
+
+    pub fn raw(&self) -> u32 {
+        self.raw
+    }
+
+    /// The current level, rounded to a whole percent
+    pub fn percent(&self) -> u32 {
+        percent_of(self.raw, self.max)
+    }
+
+    /// Sets the level to `percent` of the maximum. Returns whether that
+    /// changed anything.
+    pub fn set_percent(&mut self, percent: u32) -> Result<bool> {
+        let raw = (percent.min(100) as u64 * self.max as u64).div_ceil(100) as u32;
+        if raw == self.raw {
+            return Ok(false);
+        }
+        self.write(raw)?;
+        Ok(true)
+    }
+
+    fn write(&mut self, raw: u32) -> Result<()> {
+        self.brightness
+            .write_all_at(raw.to_string().as_bytes(), 0)
+            .with_context(|| format!("could not set backlight {}", self.name))?;
+        self.raw = raw;
+        Ok(())
+    }
+}
+
+impl Drop for Backlight {
+    fn drop(&mut self) {
+        if self.raw != self.original {
+            let _ = self.write(self.original);
+        }
+    }
+}
+
+fn percent_of(raw: u32, max: u32) -> u32 {
+    ((raw as u64 * 100 + max as u64 / 2) / max as u64) as u32
+}
+
+fn read_u32(path: &Path) -> Result<u32> {
+    let text = std::fs::read_to_string(path)
+        .with_context(|| format!("could not read {}", path.display()))?;
+    text.trim()
+        .parse()
+        .with_context(|| format!("unexpected contents in {}", path.display()))
+}
+
+fn find_default() -> Result<PathBuf> {
+    let mut found: Vec<(usize, PathBuf)> = std::fs::read_dir(CLASS_DIR)
+        .with_context(|| format!("could not list {}", CLASS_DIR))?
+        .flatten()
+        .map(|entry| {
+            let kind = std::fs::read_to_string(entry.path().join("type")).unwrap_or_default();
+            let rank = ["firmware", "platform", "raw"]
+                .iter()
+                .position(|&k| k == kind.trim())
+                .unwrap_or(3);
+            (rank, entry.path())
+        })
+        .collect();
+    found.sort();
+    match found.into_iter().next() {
+        Some((_, path)) => Ok(path),
+        None => bail!("no backlight devices in {}", CLASS_DIR),
+    }
+}
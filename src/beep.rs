@@ -0,0 +1,47 @@
+use anyhow::{Result, anyhow};
+use evdev::{Device as EvDev, EventType, InputEvent, SoundCode};
+
+// Pitch of the AV sync marker tone
+const TONE_HZ: i32 = 1000;
+
+/// Best-effort audio marker through an input device that can make a tone,
+/// usually the PC speaker. Timing is only as good as the speaker driver, so
+/// the visual flash stays the reference.
+pub struct Beeper {
+    dev: EvDev,
+    on: bool,
+}
+
+impl Beeper {
+    /// Finds the first input device that supports SND_TONE
+    pub fn open() -> Result<Self> {
+        let (path, dev) = evdev::enumerate()
+            .find(|(_, dev)| {
+                dev.supported_sounds()
+                    .is_some_and(|s| s.contains(SoundCode::SND_TONE))
+            })
+            .ok_or_else(|| anyhow!("no input device can play a tone"))?;
+        eprintln!("Using beeper: {}, Name: {:?}", path.display(), dev.name());
+        Ok(Self { dev, on: false })
+    }
+
+    /// Starts or stops the tone. A failed write disables nothing; the next
+    /// call simply tries again.
+    pub fn set(&mut self, on: bool) {
+        if self.on == on {
+            return;
+        }
+        let hz = if on { TONE_HZ } else { 0 };
+        let ev = InputEvent::new(EventType::SOUND.0, SoundCode::SND_TONE.0, hz);
+        match self.dev.send_events(&[ev]) {
+            Ok(()) => self.on = on,
+            Err(e) => eprintln!("Beep failed: {}", e),
+        }
+    }
+}
+
+impl Drop for Beeper {
+    fn drop(&mut self) {
+        self.set(false);
+    }
+}
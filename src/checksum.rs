@@ -0,0 +1,109 @@
+//! `--frame-checksum[=N]`: a 64-bit checksum of every presented frame, so a
+//! frozen pipeline shows up as a run of identical values instead of going
+//! unnoticed behind a plausible picture. Checksums go to the timing CSV and
+//! (rate-limited) the event log; a run of identical frames on a pattern
+//! that should be moving is warned about.
+//!
+//! The hash follows xxHash64's round and avalanche, over every Nth row so
+//! that 4K60 stays cheap; the values are not xxHash64 digests.
+
+const PRIME1: u64 = 0x9e37_79b1_85eb_ca87;
+const PRIME2: u64 = 0xc2b2_ae3d_27d4_eb4f;
+const PRIME3: u64 = 0x1656_67b1_9e37_79f9;
+const PRIME4: u64 = 0x85eb_ca77_c2b2_ae63;
+const PRIME5: u64 = 0x27d4_eb2f_1656_67c5;
+
+/// Rows hashed by default: every 4th
+pub const DEFAULT_ROW_STEP: usize = 4;
+
+/// Identical frames beyond what the pattern itself explains before a
+/// warning
+pub const FREEZE_FRAMES: u64 = 8;
+
+#[inline]
+fn round(acc: u64, lane: u64) -> u64 {
+    acc.wrapping_add(lane.wrapping_mul(PRIME2))
+        .rotate_left(31)
+        .wrapping_mul(PRIME1)
+}
+
+/// Hashes the w x h pixels of `buf`, taking rows 0, `row_step`,
+/// 2 * `row_step`, ... Each row is mixed in with its index, so the same
+/// rows in a different order hash differently.
+pub fn frame_checksum(buf: &[u8], stride: usize, w: usize, h: usize, row_step: usize) -> u64 {
+    let mut acc = [
+        PRIME1.wrapping_add(PRIME2),
+        PRIME2,
+        0,
+        PRIME1.wrapping_neg(),
+    ];
+    for y in (0..h).step_by(row_step.max(1)) {
+        let row = &buf[y * stride..y * stride + w * 4];
+        let mut lanes = row.chunks_exact(32);
+        for block in &mut lanes {
+            for (i, a) in acc.iter_mut().enumerate() {
+                let lane = u64::from_le_bytes(block[i * 8..i * 8 + 8].try_into().unwrap());
+                *a = round(*a, lane);
+            }
+        }
+        for (i, word) in lanes.remainder().chunks(8).enumerate() {
+            let mut bytes = [0u8; 8];
+            bytes[..word.len()].copy_from_slice(word);
+            acc[i] = round(acc[i], u64::from_le_bytes(bytes));
+        }
+        acc[0] = round(acc[0], y as u64);
+    }
+
+    let mut h64 = acc[0]
+        .rotate_left(1)
+        .wrapping_add(acc[1].rotate_left(7))
+        .wrapping_add(acc[2].rotate_left(12))
+        .wrapping_add(acc[3].rotate_left(18));
+    for a in acc {
+        h64 = (h64 ^ round(0, a))
+            .wrapping_mul(PRIME1)
+            .wrapping_add(PRIME4);
+    }
+    h64 = h64.wrapping_add((w * h) as u64).wrapping_add(PRIME5);
+    h64 ^= h64 >> 33;
+    h64 = h64.wrapping_mul(PRIME2);
+    h64 ^= h64 >> 29;
+    h64 = h64.wrapping_mul(PRIME3);
+    h64 ^ (h64 >> 32)
+}
+
+/// Counts consecutive presented frames with the same checksum
+#[derive(Default)]
+pub struct FreezeWatch {
+    last: Option<u64>,
+    /// Frames in a row matching the one before, so 0 while content changes
+    pub identical: u64,
+    warned: bool,
+}
+
+impl FreezeWatch {
+    /// Takes the checksum of a frame that reached the screen, with the
+    /// identical run its pattern can explain (None if it needn't change at
+    /// all). Returns true once per run when it grows past that.
+    pub fn presented(&mut self, checksum: u64, allowed: Option<u64>) -> bool {
+        if self.last == Some(checksum) {
+            self.identical += 1;
+        } else {
+            self.identical = 0;
+            self.warned = false;
+        }
+        self.last = Some(checksum);
+
+        let frozen = allowed.is_some_and(|n| self.identical > n + FREEZE_FRAMES);
+        if frozen && !self.warned {
+            self.warned = true;
+            return true;
+        }
+        false
+    }
+
+    /// Whether the current run is long enough to have been warned about
+    pub fn frozen(&self) -> bool {
+        self.warned
+    }
+}
@@ -0,0 +1,1320 @@
+use anyhow::{Context, Result, anyhow, bail};
+
+use crate::PatternKind;
+use crate::color::{OutputRange, YcbcrRange, YuvMatrix};
+use crate::dbus::Bus;
+use crate::draw::Corner;
+use crate::gpio::ButtonAction;
+use crate::nv12::Nv12Layout;
+use crate::patch::parse_hex;
+use crate::rotate::Rotation;
+use crate::surface::{ConnectorSelector, FlipTimeout, ModeSelector, ModeTimings};
+use drm::buffer::DrmFourcc;
+use drm::control::ModeFlags;
+use std::net::{Ipv4Addr, SocketAddr};
+use std::path::PathBuf;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Fastest inverse blink allowed without --allow-flashing: more than three
+/// flashes a second is the usual photosensitive seizure threshold
+pub const BLINK_SAFE_HZ: f64 = 3.0;
+/// Fastest inverse blink at all; past this it is a flicker pattern's job
+pub const BLINK_MAX_HZ: f64 = 30.0;
+/// Slowest inverse blink
+pub const BLINK_MIN_HZ: f64 = 0.5;
+
+const USAGE: &str = "\
+Usage: screen_test [OPTIONS]
+
+Options:
+  --card PATH      DRM device to open (default: first of /dev/dri/card0..2).
+                   Repeat to drive more cards from one process: each extra
+                   card runs its own copy of the script and is addressed
+                   remotely as instance 1, 2, ...
+  --sync-outputs   With more than one --card, have the extra cards follow
+                   the first one's steps and flip them from the same pass,
+                   numbered in a strip along the top, and measure how far
+                   each one's vblank is from the first one's
+  --fbdev PATH     Present on a framebuffer device such as /dev/fb0 instead
+                   of DRM, for systems without KMS. Only the script, P, L,
+                   K, Z, F2, F4 and quit keys, --script, --load-session, --auto,
+                   --max-runtime and the pattern options apply
+  --writeback PATH Present on a writeback connector, such as vkms's, with
+                   atomic commits, and save every frame it writes back to
+                   PATH as a PNG. Runs the same reduced loop as --fbdev,
+                   without needing a keyboard, so it suits CI with --auto.
+                   --connector and --mode choose among writeback connectors
+  --connector NAME Connector to drive, e.g. HDMI-A-1 (default: first connected)
+  --mode WxH[@HZ]  Mode to set (default: the connector's preferred mode)
+  --custom-mode MODELINE
+                   Set these timings instead of a listed mode, given as
+                   one argument in the modeline order: CLOCK_MHZ HDISP
+                   HSYNC_START HSYNC_END HTOTAL VDISP VSYNC_START VSYNC_END
+                   VTOTAL, then any of +hsync -hsync +vsync -vsync
+                   interlace doublescan. For refresh overclocks; timings
+                   the display can't take may leave it without a signal
+  --compare-mode WxH[@HZ]
+                   A second mode to A/B against the first: X switches
+                   between them, keeping the pattern
+  --allow-interlaced
+                   Let mode selection pick interlaced modes (e.g. 1080i);
+                   they are skipped otherwise while a progressive one exists
+  --accept-unknown Also use a connector whose state is unknown rather than
+                   connected, if it has modes (some DP-MST hubs, flaky
+                   detection); such connectors are re-probed first and the
+                   state of every connector is printed
+  --format FMT     Framebuffer format: xrgb8888 (default) or argb8888
+  --nv12 LAYOUT    Also show every frame through an NV12 (4:2:0 YCbCr) plane,
+                   converted in software: full (the whole screen) or overlay
+                   (the middle quarter, over the RGB frame). Refuses to
+                   start if no plane on the CRTC takes NV12
+  --nv12-matrix M  Matrix for --nv12: 601 or 709 (default)
+  --content-type T HDMI content type to signal so the sink picks a matching
+                   picture mode: graphics, photo, cinema, game or no-data
+  --max-bpc N      Set the connector's max bpc property before the modeset,
+                   e.g. 6 or 8 to force a lower link depth. The link depth
+                   and max bpc are shown over the gradients; few drivers
+                   report the depth the link actually runs at
+  --buffers N      Number of framebuffers to cycle through (default: 2). With
+                   3 or more, a frame drawn while a flip is pending is
+                   queued into a spare buffer and flipped as soon as that
+                   one completes
+  --flip-timeout M What to do when a page flip's completion event hasn't
+                   arrived after two frame intervals, as with some buggy
+                   drivers and VMs: assume (default) carries on as if it
+                   had, set-crtc also presents every later frame with
+                   set_crtc (no vsync), off waits indefinitely
+  --auto SECS      Advance to the next step automatically every SECS seconds
+  --checker-cell N Checker cell size in pixels for every checker step
+  --motion-speed N Motion bar speed in pixels per frame for every motion step
+  --grad-vertical  Draw every gradient step vertically
+  --invert         Invert the colors of every pattern
+                   These four apply on top of each script step and of a
+                   loaded session, so they hold for the whole run
+  --dpi N          Pixel density for the ruler pattern, in place of the
+                   picture size the display reports (needed when it
+                   reports none, or a wrong one, as projectors do)
+  --overlay-corner CORNER
+                   Where the status overlay goes: top-left (default),
+                   top-right, bottom-left or bottom-right, as the viewer
+                   sees it with --rotate. Shift+L moves it while running
+  --blink-inverse[=HZ]
+                   Alternate every pattern with its inverse HZ times a
+                   second (default 1), timed by the clock rather than by
+                   flips, for flicker fusion and visible flicker checks.
+                   0.5 to 3 Hz, up to 30 with --allow-flashing
+  --allow-flashing Allow flashing above 3 Hz. Flashing at these rates can
+                   trigger seizures in photosensitive viewers
+  --backlight[=PCT]
+                   Control the panel backlight through /sys/class/backlight,
+                   starting at PCT percent (default: as it is). F5 and F6
+                   step it down and up by 10% (1% with Shift), every change
+                   is logged to --event-log, and the level it had is put
+                   back at exit. Without a device or permission to write
+                   it, a warning and carry on
+  --backlight-device NAME
+                   Which device under /sys/class/backlight (default: the
+                   one the kernel suggests); implies --backlight
+  --ui-scale N     Size of the overlay text, readout, menus, progress bar
+                   and probe reticle, 1 to 8, or auto (the default: one
+                   step per 540 rows, so 2 at 1080p and 4 at 4K). Patterns
+                   are never scaled. Also ui.scale in --config
+  --output-range R Draw every pattern in full (default) or limited range,
+                   where black is 16 and white 235, for video chains that
+                   expect studio swing. Steps with full_range = true are
+                   left alone
+  --logical SIZE   Draw the patterns into a centered rectangle, as content
+                   letterboxed or pillarboxed at the panel's resolution
+                   would be: WxH in pixels (e.g. 1920x1080) or W:H to fit
+                   an aspect ratio (e.g. 4:3). Only on the first card
+  --matte RRGGBB   Color around the --logical rectangle (default 000000;
+                   e.g. 202020 to see whether the set dims the matte). F2
+                   steps it through black, mid grey, magenta and this
+  --logical-marker Draw a 1 px red line just outside the --logical
+                   rectangle, to measure how far its edges are overscanned
+  --max-runtime SECS
+                   Stop after SECS seconds, exiting with status 6
+  --soak DURATION  Unattended soak test, e.g. 48h, 90m or 2d: loop the
+                   script for DURATION (steps advance every --auto SECS,
+                   default 5, or their own dwell), flipping every refresh.
+                   Failed flips, flips missing more than 2 vblanks, verify
+                   mismatches, device errors and keyboard reconnects are
+                   recorded and ridden out, a heartbeat with the cycle count
+                   and RSS is printed every 5 minutes, and a display that
+                   stops flipping for 5 s gets its mode set again once. The
+                   tally goes into --report; only losing the device ends the
+                   run early, with status 9
+  --attract        Trade-show loop: cycle a hue drift, a scrolling ramp and
+                   bouncing text (or the [attract] steps of --config),
+                   fading between them every --auto SECS (default 15), with
+                   labels off and the picture orbiting a few pixels against
+                   burn-in. Runs until any key is pressed
+  --fps-cap N      Present at most N frames per second
+  --fade[=FRAMES]  Cross-fade from each step into the next over FRAMES
+                   presented frames (default 15) instead of cutting, to
+                   watch the panel's transitions; a step's own fade = N
+                   overrides it, 0 cuts. Pressing Right or Left again skips
+                   the fade. Never used into or out of measured patterns
+  --late-tolerance PCT
+                   How far past its frame period a frame of an animation
+                   may reach the screen before it counts as late (default
+                   50). Late frames are counted per step in --report,
+                   logged, and flagged with a red square bottom left
+  --beep           Sound a tone with each AV sync flash, through the PC
+                   speaker or another input device that can play one
+  --av-audio PCM   Play a click with each AV sync flash through an ALSA PCM,
+                   e.g. default or hw:0,0 (needs a build with the alsa
+                   feature)
+  --av-offset MS   Shift the --av-audio click by MS milliseconds, negative
+                   to lead the flash, for calibrating measurement gear
+  --gpio-chip PATH GPIO chip for a scope trigger, e.g. /dev/gpiochip0
+  --gpio-line N    Line on --gpio-chip to pulse on every completed flip and
+                   hold high during script steps with gpio_hold set
+  --gpio-buttons CHIP:ACTION=LINE[,ACTION=LINE...]
+                   Push buttons wired from GPIO lines to ground, e.g.
+                   /dev/gpiochip0:next=17,prev=27,quit=22. Actions are
+                   next, prev and quit. With buttons a keyboard is optional
+  --serial DEV[:BAUD]
+                   Drive an instrument on a serial port (default 9600 baud):
+                   send the trigger bytes from --config once each step has
+                   settled, and optionally take N/P/Q commands back
+                   (needs a build with the serial feature)
+  --no-hints       Don't show the bar of the main keys along the bottom for
+                   the first few seconds, and again after a key that does
+                   nothing of its own
+  --grab           Take the keyboard and any mice for this program alone, so
+                   keys and clicks don't also reach the console or desktop
+                   underneath. Let go on exit, however it comes
+  --leds           Signal tool state on the keyboard LEDs (Scroll Lock)
+  --dbus BUS       Serve org.screentest.Control on the session or system bus
+                   (needs a build with the dbus feature)
+  --config PATH    Read settings from a TOML file (see below)
+  --list           List connectors and their modes, then exit
+  --dump-props     Print every DRM property of the selected connector, its
+                   CRTC and primary plane (type, options, current value),
+                   then exit without a modeset
+  --load-session PATH
+                   Restore a saved script, step and adjustments at startup
+  --save-session PATH
+                   Save the session to PATH on exit (and when S is pressed)
+  --lut PATH       Pass every pixel through a 1D LUT file before it is
+                   presented: 256 or 1024 lines of R G B
+  --record PATH    Record presented frames to a Y4M (4:4:4, BT.709) file,
+                   or to stdout with -
+  --record-raw PATH
+                   Record presented frames as headerless packed RGB (ffmpeg
+                   rawvideo rgb24 at the mode's size and refresh), or to
+                   stdout with -. Frames are written on a background thread;
+                   ones it can't keep up with are dropped from the recording
+  --record-frames N
+                   Stop recording after N frames (default: 600)
+  --record-range R YCbCr range for --record: limited (default) or full
+  --patch-server PORT
+                   Act as a network pattern generator: show the colors
+                   requested over HTTP instead of running the script
+  --preview-listen [ADDR:]PORT
+                   Serve a 480 px wide PNG of the screen on GET /frame, and
+                   a page that keeps it updated on GET /. A WebSocket on /ws
+                   takes JSON commands such as {\"cmd\": \"next\"} (also
+                   previous, goto with step or name, pause with paused,
+                   quit; any of them with instance for another --card) and
+                   pushes state changes. Listens on 127.0.0.1 unless ADDR
+                   is given, e.g. 0.0.0.0:8080; there is no authentication,
+                   so only open it to networks you trust
+  --shm-export NAME
+                   Keep the presented frame in /dev/shm/NAME, after a small
+                   header, for analysis tools to read while the test runs
+                   (layout in src/shm.rs, reader in examples/shm_reader.rs)
+  --event-log PATH Append a JSON-lines log of steps, input, adjustments,
+                   verdicts, link property changes and errors, with
+                   CLOCK_MONOTONIC timestamps
+  --event-log-flips
+                   Also log completed flips (at most 10 per second)
+  --report PATH    Write a JSON report of the run to PATH at exit
+  --checklist PATH Write the steps marked pass (F9) or fail (F10) to PATH
+                   at exit, one per line, with an overall verdict. They are
+                   printed and go into --report as verdicts either way
+  --timing-csv PATH
+                   Write one CSV row of timing data per completed flip
+  --rotate DEG     Turn every frame 0, 90, 180 or 270 degrees clockwise in
+                   software, for a panel mounted on its side or upside down
+                   without a driver rotation property. Patterns are drawn
+                   for the viewer, so horizontal means across their view.
+                   Applies to the first --card only
+  --render-scale N Draw patterns at 1/N of the screen size (1 to 4) and show
+                   each pixel as an N x N block, for animated patterns on
+                   slow devices. Patterns that depend on single pixels
+                   (checker, pixel exact, contrast sensitivity, subpixel
+                   text, chroma subsampling) stay at full size. Speeds in
+                   pixels are stage pixels
+  --profile        Time each phase of the main loop (poll, event handling,
+                   input, drawing, the copy to the back buffer, the flip)
+                   and print a breakdown every 5 s and at exit
+  --profile-trace PATH
+                   With --profile, also write every phase as a span in the
+                   Chrome trace format (open in Perfetto or speedscope)
+  --leak-watch SECS
+                   For long runs: print the process RSS and the number of
+                   framebuffers every SECS seconds (and log them to
+                   --event-log), and warn when RSS keeps growing
+  --leak-warn KB   With --leak-watch, warn once RSS has grown by more than
+                   KB kB without shrinking in between (default 10240)
+  --frame-checksum[=N]
+                   Checksum every presented frame over every Nth row
+                   (default 4) before overlays, for the timing CSV and the
+                   event log, and warn when a moving pattern stops changing
+  --inject-drops N Debug: leave every Nth frame unpresented, so the previous
+                   one stays up for an extra refresh, for checking the drop
+                   handling of capture and analysis tools. Timed animation
+                   keeps its pace; each drop is logged
+  --only LIST      Run only the script's steps showing these patterns, named
+                   as in script files and separated by commas, e.g.
+                   solid,checker,motion
+  --skip LIST      Leave out the script's steps showing these patterns
+  --soak-random[=SEED]
+                   With --soak, show patterns picked at random, with random
+                   parameters, for 2 to 20 s each instead of looping the
+                   script. The same SEED shows the same sequence; without
+                   one it is random, and printed at the end. Patterns that
+                   flicker by design need --allow-flashing
+  --soak-log PATH  Where --soak-random logs each step shown, with the time
+                   (default: screen_test-soak-SEED.log)
+  --shuffle[=SEED] Run the script's steps in a random order, for blind
+                   evaluation. The seed is printed (and reported) so the
+                   same order can be run again; without one it is random.
+                   The progress count and the report also give each
+                   step's place in the unshuffled script
+  --shuffle-within-category
+                   With --shuffle, keep steps of the same pattern together:
+                   the groups are shuffled, and the steps within each
+  --script PATH    Run the .toml script in PATH instead of the built-in one
+  --dump-script PATH
+                   Write the built-in script to PATH, as a starting point
+                   for one of your own, and exit
+  --record-script PATH
+                   Write the steps visited, as last adjusted and with how
+                   long each stayed up, to a script that --script replays
+  --scripts-dir DIR
+                   Load every .toml script in DIR; O opens a menu to switch.
+                   A step with pat = \"external\" shows the raw XRGB frame
+                   its generator command writes to stdout (see
+                   examples/rings.toml); generator_per_frame = true reruns it
+                   every frame
+  --stress-modeset N
+                   Allocate framebuffers and modeset N times, then exit
+  --sweep-outputs[=SECS]
+                   Bench check: drive each connected display in turn at its
+                   preferred (native) mode with a labeled viewing card for
+                   SECS seconds (default 5), then print which ones worked
+                   and exit, with status 4 if any didn't
+  --verify[=all]   Read back the presented buffer and check it matches what
+                   was drawn, once per step (or every frame with =all)
+  --damage         Copy only what changed since each buffer was last written
+                   into it, instead of the whole frame; saves memory
+                   bandwidth on static patterns with a moving overlay
+  -h, --help       Print this help and exit
+
+Keys:
+  F1               Menu: Up/Down to choose, Enter or Space to jump to a
+                   step or toggle an option, Esc or F1 to close
+  F2               With --logical, cycle the matte color
+  Right, Space     Next step
+  Left             Previous step
+  Enter            Continue from a step with pause_here = true, which holds
+                   --auto (or its dwell) until then
+  P                Pause animation and auto-advance
+  S                Save the session (with --save-session)
+  L                Toggle pattern labels
+  Shift+L          Move the status overlay to the next corner, clockwise
+  Shift+I          Toggle blinking the pattern with its inverse
+  -, =             While blinking: 0.5 Hz slower or faster
+  Z                Full black, then full white over any step; a third press
+                   goes back to the step
+  [, ]             Step back or on through the solid colors over any step,
+                   wrapping round; backslash goes back to the step
+  K                Toggle the step count and progress bar (shown with
+                   labels; the bar is left off measurement patterns). On
+                   the last step, a note says the next press exits
+  Y                Toggle full/limited output range
+  F5, F6           With --backlight: backlight down, up by 10% (Shift: 1%)
+  F7               Histogram of the stage in the bottom-right corner, with
+                   16 and 235 marked: luma, then R, G, B, then off. Left
+                   off measurement patterns
+  F8               Toggle the red square that flags a late frame
+  F3               Cycle a second range conversion on top of the output
+                   range, to show a chain that converts twice: off,
+                   compress (16-235 again), expand (16-235 to 0-255 again)
+  M                Cycle the output color matrix (identity, desaturate,
+                   swap R/B, protanopia)
+  7, 8, 9, 0       Show only red, only green, only blue, or all channels,
+                   over any pattern (after --invert, before limited range)
+  F4               Swap red and blue in the finished frame, overlays and
+                   all, to compensate a panel or adapter wired BGR (see the
+                   channel_order step); applied after every other step of
+                   the output, so 7 still lights the panel's red
+  X                Switch between --mode and --compare-mode
+  D                Toggle split screen: two patterns side by side
+  Tab              Split screen: switch which half the keys control
+  Shift+D          Split screen: show where the two patterns differ, each
+                   channel's difference times 1, 4, 16 or 64, then back to
+                   side by side
+  O                Script menu: Up/Down to choose, Enter to load, Esc to close
+  U                Jump to the next step given a name in the script
+  I                Probe: show the R, G, B values under a reticle that the
+                   arrow keys move (PgUp/PgDn change the step).
+                   Shift+arrows size it into a rectangle, read as the
+                   mean, min and max of each channel inside it
+  N                Toggle param nav: Left/Right step the current pattern's
+                   parameter instead of the script
+  H                Solid: type a target color as six hex digits, then Enter
+                   (Backspace corrects, Esc cancels)
+  R, G, B          Solid: select the channel to fine adjust
+  Up, Down         Solid: channel +1/-1, shown against the target
+  G                Gradient, radial: toggle linear-in-light (sRGB) ramp
+  T                Gradient: toggle ticks where the code value steps
+  C                Gradient: switch between one grey ramp and red, green
+                   and blue ramps side by side
+  C                Checker: cycle the cell colors (black/white, red/green,
+                   blue/yellow, red/cyan, then the step's checker_colors)
+  W                Checker: toggle sweeping the cell size (the step's
+                   checker_sweep, or 64 down to 1 px every 8 s)
+  A                Motion: toggle anti-aliased bar edges
+  A                Keystone: cycle the target aspect (16:9, 16:10, 2.39:1)
+  B                Keystone: toggle blinking the outer outline
+  C                Focus: switch between white and green only
+  Up, Down         Radial: falloff exponent +0.25/-0.25
+  Up, Down         Integer scale: block size (2, 3 or 4)
+  Up, Down         Near black: base code value +1/-1 (1 to 8)
+  Up, Down         Gray to gray: levels along each axis (2 to 9)
+  C                Bit depth: cycle luma, red, green and blue
+  G                Gamut corners: cycle the target gamut (Rec.709, DCI-P3,
+                   BT.2020)
+  V                3D eyes: cycle side by side, top and bottom, frame
+                   packing
+  Up, Down         3D eyes: horizontal disparity +1/-1 px (Shift: vertical)
+  PgUp, PgDn       3D eyes: move the split by 1% of the frame
+  V                Radial: switch between bright center and bright edges
+  Up, Down         Hue sweep: value +5%/-5%
+  PgUp, PgDn       Hue sweep: saturation +5%/-5%
+  V                Hue sweep: switch between left to right and around the
+                   center
+  C                Radial: cycle luma, red, green and blue
+  R                Focus: switch between the 1 px checker and circles
+  C                Subpixel text: cycle the subpixel order
+  Up, Down         Line sweep: double/halve the speed
+  Up, Down         Scrolling ramp: double/halve the speed
+  PgUp, PgDn       Line sweep: line width +1/-1
+  V                Line sweep: toggle top-to-bottom/left-to-right
+  E                Motion, line sweep: cycle wrap/bounce/stop at the edges
+  C                Line sweep: cycle the line color
+  C                Flip sequence: cycle the color set
+  J                Flip sequence, pixel inversion, gray to gray, stereo:
+                   shift the phase by one flip, to line the flicker up
+                   with a camera's shutter or swap which eye sees which
+                   image
+  Up, Down         Flip sequence: flips per color
+  R, G, B          Oscillator: select the channel to adjust
+  Up, Down         Oscillator: channel frequency (0.1 Hz steps)
+  PgUp, PgDn       Oscillator: channel phase (30 degree steps)
+  F9, F10          Mark the step pass or fail and go on to the next; the
+                   tally is shown, and printed at exit (see --checklist)
+  Q, Esc           Quit
+
+Signals:
+  SIGUSR1          Next step, as Right: e.g. kill -USR1 $(pidof screen_test)
+  SIGUSR2          Previous step, as Left
+                   Either restarts the step's --auto time, and both are
+                   ignored while a --patch-server client is connected
+  SIGINT, SIGTERM, SIGHUP
+                   Quit cleanly, writing the report and session as Q would
+
+Config file:
+  [serial]
+  trigger = \"M\\r\\n\"      Bytes to send once a step has settled (default none)
+  settle_frames = 3      Frame periods to wait after the step's first flip
+  commands = false       Act on N (next), P (previous) and Q (quit) bytes
+  [output]
+  range = \"limited\"      Output range when --output-range isn't given
+  swap_rb = false        Start with red and blue swapped, as F4 does
+  [backlight]
+  device = \"intel_backlight\"
+                         Backlight device when --backlight-device isn't
+                         given; turns backlight control on
+  level = 50             Backlight level to start at, in percent; turns
+                         backlight control on
+  [[attract.steps]]      A step for --attract to loop through, as in a
+  pat = \"hue_sweep\"      script file; repeat for each, in order
+
+Exit status:
+  0  Quit by the user, or the script ran to its end
+  1  Unexpected error
+  2  Invalid command line
+  3  The DRM device could not be opened
+  4  No connected display (or not the requested connector)
+  5  No keyboard found
+  6  --max-runtime expired
+  7  --verify found a mismatch
+  8  --verify was given but no frame was checked
+  9  The display device was lost during --soak
+";
+
+#[derive(Debug)]
+pub struct Args {
+    pub card: Option<PathBuf>,
+    pub extra_cards: Vec<PathBuf>,
+    pub sync_outputs: bool,
+    pub fbdev: Option<PathBuf>,
+    pub writeback: Option<PathBuf>,
+    pub connector: ConnectorSelector,
+    pub mode: ModeSelector,
+    pub compare_mode: Option<ModeSelector>,
+    pub allow_interlaced: bool,
+    pub accept_unknown: bool,
+    pub format: DrmFourcc,
+    pub buffers: usize,
+    pub flip_timeout: FlipTimeout,
+    pub nv12: Option<Nv12Layout>,
+    pub nv12_matrix: YuvMatrix,
+    pub content_type: Option<String>,
+    pub max_bpc: Option<u32>,
+    pub auto: Option<Duration>,
+    pub max_runtime: Option<Duration>,
+    pub soak: Option<Duration>,
+    pub attract: bool,
+    pub fps_cap: Option<f64>,
+    pub fade: Option<u32>,
+    pub blink_inverse: Option<f64>,
+    pub overlay_corner: Corner,
+    pub allow_flashing: bool,
+    pub late_tolerance: f64,
+    pub overrides: StepOverrides,
+    pub invert: bool,
+    pub output_range: Option<OutputRange>,
+    pub grab: bool,
+    pub no_hints: bool,
+    pub leds: bool,
+    pub gpio_chip: Option<PathBuf>,
+    pub gpio_line: Option<u32>,
+    pub gpio_buttons: Option<(PathBuf, Vec<(ButtonAction, u32)>)>,
+    pub beep: bool,
+    pub av_audio: Option<String>,
+    pub av_offset: f64,
+    pub dbus: Option<Bus>,
+    pub serial: Option<(String, u32)>,
+    pub config: Option<PathBuf>,
+    pub list: bool,
+    pub dump_props: bool,
+    pub dump_script: Option<PathBuf>,
+    pub load_session: Option<PathBuf>,
+    pub save_session: Option<PathBuf>,
+    pub lut: Option<PathBuf>,
+    pub record: Option<PathBuf>,
+    pub record_raw: Option<PathBuf>,
+    pub record_frames: u64,
+    pub record_range: YcbcrRange,
+    pub patch_server: Option<u16>,
+    pub preview_listen: Option<SocketAddr>,
+    pub shm_export: Option<String>,
+    pub report: Option<PathBuf>,
+    pub checklist: Option<PathBuf>,
+    pub event_log: Option<PathBuf>,
+    pub event_log_flips: bool,
+    pub script: Option<PathBuf>,
+    pub only: Vec<PatternKind>,
+    pub skip: Vec<PatternKind>,
+    pub shuffle: Option<u64>,
+    pub soak_random: Option<u64>,
+    pub soak_log: Option<PathBuf>,
+    pub shuffle_by_category: bool,
+    pub record_script: Option<PathBuf>,
+    pub scripts_dir: Option<PathBuf>,
+    pub timing_csv: Option<PathBuf>,
+    pub render_scale: usize,
+    pub ui_scale: Option<usize>,
+    pub backlight: bool,
+    pub backlight_level: Option<u32>,
+    pub backlight_device: Option<String>,
+    pub rotate: Rotation,
+    pub frame_checksum: Option<usize>,
+    pub inject_drops: Option<u64>,
+    pub leak_watch: Option<Duration>,
+    pub leak_warn_kb: Option<u64>,
+    pub profile: bool,
+    pub profile_trace: Option<PathBuf>,
+    pub stress_modeset: Option<usize>,
+    pub sweep_outputs: Option<Duration>,
+    pub verify: Option<Verify>,
+    pub damage: bool,
+    pub logical: Option<Logical>,
+    pub matte: (u8, u8, u8),
+    pub logical_marker: bool,
+}
+
+/// `--logical`: the centered rectangle the patterns are drawn into, as a
+/// size in pixels or an aspect ratio to fit to the screen
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Logical {
+    Size(usize, usize),
+    Aspect(f64),
+}
+
+/// Pattern parameters given on the command line. They are applied on top of
+/// every step, so they hold for the whole run.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct StepOverrides {
+    pub checker_cell: Option<usize>,
+    pub motion_speed: Option<usize>,
+    pub grad_vertical: bool,
+    // Pixel density for the ruler instead of the display's reported size
+    pub dpi: Option<f64>,
+}
+
+/// How often to check the presented buffer against what was drawn
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Verify {
+    Step,
+    All,
+}
+
+impl Default for Args {
+    fn default() -> Self {
+        Self {
+            card: None,
+            extra_cards: Vec::new(),
+            sync_outputs: false,
+            fbdev: None,
+            writeback: None,
+            connector: ConnectorSelector::default(),
+            mode: ModeSelector::default(),
+            compare_mode: None,
+            allow_interlaced: false,
+            accept_unknown: false,
+            format: DrmFourcc::Xrgb8888,
+            buffers: 2,
+            flip_timeout: FlipTimeout::Assume,
+            nv12: None,
+            nv12_matrix: YuvMatrix::Bt709,
+            content_type: None,
+            max_bpc: None,
+            auto: None,
+            max_runtime: None,
+            soak: None,
+            attract: false,
+            fps_cap: None,
+            fade: None,
+            blink_inverse: None,
+            overlay_corner: Corner::TopLeft,
+            allow_flashing: false,
+            late_tolerance: 0.5,
+            overrides: StepOverrides::default(),
+            invert: false,
+            output_range: None,
+            grab: false,
+            no_hints: false,
+            leds: false,
+            gpio_chip: None,
+            gpio_line: None,
+            gpio_buttons: None,
+            beep: false,
+            av_audio: None,
+            av_offset: 0.0,
+            dbus: None,
+            serial: None,
+            config: None,
+            list: false,
+            dump_props: false,
+            dump_script: None,
+            load_session: None,
+            save_session: None,
+            lut: None,
+            record: None,
+            record_raw: None,
+            record_frames: 600,
+            record_range: YcbcrRange::Limited,
+            patch_server: None,
+            preview_listen: None,
+            shm_export: None,
+            report: None,
+            checklist: None,
+            event_log: None,
+            event_log_flips: false,
+            script: None,
+            only: Vec::new(),
+            skip: Vec::new(),
+            shuffle: None,
+            soak_random: None,
+            soak_log: None,
+            shuffle_by_category: false,
+            record_script: None,
+            scripts_dir: None,
+            timing_csv: None,
+            render_scale: 1,
+            ui_scale: None,
+            backlight: false,
+            backlight_level: None,
+            backlight_device: None,
+            rotate: Rotation::None,
+            logical: None,
+            matte: (0, 0, 0),
+            logical_marker: false,
+            frame_checksum: None,
+            inject_drops: None,
+            leak_watch: None,
+            leak_warn_kb: None,
+            profile: false,
+            profile_trace: None,
+            stress_modeset: None,
+            sweep_outputs: None,
+            verify: None,
+            damage: false,
+        }
+    }
+}
+
+impl Args {
+    pub fn parse() -> Result<Self> {
+        Self::parse_from(std::env::args().skip(1))
+    }
+
+    fn parse_from(iter: impl IntoIterator<Item = String>) -> Result<Self> {
+        let mut args = Args::default();
+        let mut it = iter.into_iter();
+
+        while let Some(arg) = it.next() {
+            // Accept both `--flag value` and `--flag=value`
+            let (flag, inline) = match arg.split_once('=') {
+                Some((f, v)) if f.starts_with("--") => (f.to_string(), Some(v.to_string())),
+                _ => (arg.clone(), None),
+            };
+
+            let mut value = || -> Result<String> {
+                inline
+                    .clone()
+                    .or_else(|| it.next())
+                    .ok_or_else(|| anyhow!("{} requires a value", flag))
+            };
+
+            match flag.as_str() {
+                "-h" | "--help" => {
+                    print!("{}", USAGE);
+                    std::process::exit(0);
+                }
+                "--card" => match args.card {
+                    None => args.card = Some(value()?.into()),
+                    Some(_) => args.extra_cards.push(value()?.into()),
+                },
+                "--fbdev" => args.fbdev = Some(value()?.into()),
+                "--sync-outputs" => args.sync_outputs = true,
+                "--writeback" => args.writeback = Some(value()?.into()),
+                "--connector" => args.connector = ConnectorSelector::Name(value()?),
+                "--mode" | "--custom-mode" => {
+                    if !matches!(args.mode, ModeSelector::Preferred) {
+                        bail!("give one of --mode and --custom-mode, once");
+                    }
+                    args.mode = if flag == "--mode" {
+                        parse_mode(&value()?)?
+                    } else {
+                        ModeSelector::Custom(parse_modeline(&value()?)?.to_mode()?)
+                    };
+                }
+                "--compare-mode" => args.compare_mode = Some(parse_mode(&value()?)?),
+                "--allow-interlaced" => args.allow_interlaced = true,
+                "--accept-unknown" => args.accept_unknown = true,
+                "--format" => args.format = parse_format(&value()?)?,
+                "--nv12" => {
+                    args.nv12 = Some(match value()?.to_ascii_lowercase().as_str() {
+                        "full" => Nv12Layout::Full,
+                        "overlay" => Nv12Layout::Overlay,
+                        other => {
+                            bail!("invalid --nv12: {} (expected full or overlay)", other)
+                        }
+                    })
+                }
+                "--nv12-matrix" => {
+                    args.nv12_matrix = match value()?.to_ascii_lowercase().as_str() {
+                        "601" | "bt601" | "bt.601" => YuvMatrix::Bt601,
+                        "709" | "bt709" | "bt.709" => YuvMatrix::Bt709,
+                        other => bail!("invalid --nv12-matrix: {} (expected 601 or 709)", other),
+                    }
+                }
+                "--content-type" => args.content_type = Some(value()?),
+                "--max-bpc" => args.max_bpc = Some(parse_count(&value()?)? as u32),
+                "--buffers" => {
+                    args.buffers = parse_count(&value()?)?;
+                    if args.buffers < 2 {
+                        bail!("--buffers must be at least 2");
+                    }
+                }
+                "--flip-timeout" => {
+                    args.flip_timeout = match value()?.to_ascii_lowercase().as_str() {
+                        "assume" => FlipTimeout::Assume,
+                        "set-crtc" => FlipTimeout::SetCrtc,
+                        "off" => FlipTimeout::Off,
+                        other => bail!(
+                            "invalid --flip-timeout: {} (expected assume, set-crtc or off)",
+                            other
+                        ),
+                    }
+                }
+                "--auto" => args.auto = Some(parse_secs(&value()?)?),
+                "--max-runtime" => args.max_runtime = Some(parse_secs(&value()?)?),
+                "--soak" => args.soak = Some(parse_span(&value()?)?),
+                "--attract" => args.attract = true,
+                "--leak-watch" => args.leak_watch = Some(parse_secs(&value()?)?),
+                "--leak-warn" => args.leak_warn_kb = Some(parse_count(&value()?)? as u64),
+                "--checker-cell" => args.overrides.checker_cell = Some(parse_count(&value()?)?),
+                "--motion-speed" => args.overrides.motion_speed = Some(parse_count(&value()?)?),
+                "--dpi" => args.overrides.dpi = Some(parse_positive(&value()?)?),
+                "--grad-vertical" => args.overrides.grad_vertical = true,
+                "--invert" => args.invert = true,
+                "--blink-inverse" => {
+                    args.blink_inverse = Some(match inline.as_deref() {
+                        Some(hz) => parse_positive(hz)?,
+                        None => 1.0,
+                    })
+                }
+                "--allow-flashing" => args.allow_flashing = true,
+                "--overlay-corner" => {
+                    let v = value()?;
+                    args.overlay_corner = Corner::from_name(&v).ok_or_else(|| {
+                        anyhow!(
+                            "invalid --overlay-corner: {} (expected top-left, top-right, bottom-left or bottom-right)",
+                            v
+                        )
+                    })?;
+                }
+                "--logical" => args.logical = Some(parse_logical(&value()?)?),
+                "--matte" => args.matte = parse_hex(&value()?).context("invalid --matte")?,
+                "--logical-marker" => args.logical_marker = true,
+                "--output-range" => {
+                    args.output_range = Some(match value()?.to_ascii_lowercase().as_str() {
+                        "limited" => OutputRange::Limited,
+                        "full" => OutputRange::Full,
+                        other => bail!(
+                            "invalid --output-range: {} (expected full or limited)",
+                            other
+                        ),
+                    })
+                }
+                "--fps-cap" => args.fps_cap = Some(parse_positive(&value()?)?),
+                "--fade" => {
+                    args.fade = Some(match inline.as_deref() {
+                        Some(frames) => parse_count(frames)? as u32,
+                        None => 15,
+                    })
+                }
+                "--late-tolerance" => args.late_tolerance = parse_positive(&value()?)? / 100.0,
+                "--grab" => args.grab = true,
+                "--no-hints" => args.no_hints = true,
+                "--leds" => args.leds = true,
+                "--gpio-chip" => args.gpio_chip = Some(value()?.into()),
+                "--gpio-line" => {
+                    let line = value()?;
+                    args.gpio_line = Some(
+                        line.parse()
+                            .with_context(|| format!("invalid GPIO line: {}", line))?,
+                    );
+                }
+                "--gpio-buttons" => args.gpio_buttons = Some(parse_buttons(&value()?)?),
+                "--beep" => args.beep = true,
+                "--av-audio" => args.av_audio = Some(value()?),
+                "--av-offset" => {
+                    let ms = value()?;
+                    args.av_offset = ms
+                        .parse()
+                        .ok()
+                        .filter(|v: &f64| v.is_finite() && v.abs() < 1000.0)
+                        .ok_or_else(|| {
+                            anyhow!(
+                                "invalid --av-offset: {} (milliseconds, under a second either way)",
+                                ms
+                            )
+                        })?;
+                }
+                "--dbus" => {
+                    args.dbus = Some(match value()?.to_ascii_lowercase().as_str() {
+                        "session" => Bus::Session,
+                        "system" => Bus::System,
+                        other => {
+                            bail!("invalid --dbus bus: {} (expected session or system)", other)
+                        }
+                    })
+                }
+                "--serial" => args.serial = Some(parse_serial(&value()?)?),
+                "--config" => args.config = Some(value()?.into()),
+                "--list" => args.list = true,
+                "--dump-props" => args.dump_props = true,
+                "--dump-script" => args.dump_script = Some(value()?.into()),
+                "--load-session" => args.load_session = Some(value()?.into()),
+                "--save-session" => args.save_session = Some(value()?.into()),
+                "--lut" => args.lut = Some(value()?.into()),
+                "--record" => args.record = Some(value()?.into()),
+                "--record-raw" => args.record_raw = Some(value()?.into()),
+                "--record-frames" => args.record_frames = parse_count(&value()?)? as u64,
+                "--record-range" => {
+                    args.record_range = match value()?.to_ascii_lowercase().as_str() {
+                        "limited" => YcbcrRange::Limited,
+                        "full" => YcbcrRange::Full,
+                        other => bail!(
+                            "invalid --record-range: {} (expected limited or full)",
+                            other
+                        ),
+                    }
+                }
+                "--patch-server" => args.patch_server = Some(parse_port(&value()?)?),
+                "--preview-listen" => args.preview_listen = Some(parse_listen(&value()?)?),
+                "--shm-export" => args.shm_export = Some(value()?),
+                "--report" => args.report = Some(value()?.into()),
+                "--checklist" => args.checklist = Some(value()?.into()),
+                "--event-log" => args.event_log = Some(value()?.into()),
+                "--event-log-flips" => args.event_log_flips = true,
+                "--script" => args.script = Some(value()?.into()),
+                "--record-script" => args.record_script = Some(value()?.into()),
+                "--scripts-dir" => args.scripts_dir = Some(value()?.into()),
+                "--timing-csv" => args.timing_csv = Some(value()?.into()),
+                "--render-scale" => {
+                    let v = value()?;
+                    args.render_scale = match v.parse() {
+                        Ok(n @ 1..=4) => n,
+                        _ => bail!("invalid --render-scale: {} (expected 1 to 4)", v),
+                    }
+                }
+                "--backlight" => {
+                    args.backlight = true;
+                    if let Some(v) = inline.as_deref() {
+                        args.backlight_level = match v.parse() {
+                            Ok(pct @ 0..=100) => Some(pct),
+                            _ => bail!("invalid --backlight: {} (expected 0 to 100)", v),
+                        };
+                    }
+                }
+                "--backlight-device" => {
+                    args.backlight = true;
+                    args.backlight_device = Some(value()?);
+                }
+                "--ui-scale" => {
+                    let v = value()?;
+                    args.ui_scale = match v.as_str() {
+                        "auto" => None,
+                        _ => match v.parse() {
+                            Ok(n @ 1..=8) => Some(n),
+                            _ => bail!("invalid --ui-scale: {} (expected 1 to 8 or auto)", v),
+                        },
+                    }
+                }
+                "--rotate" => {
+                    let v = value()?;
+                    args.rotate = v
+                        .parse()
+                        .ok()
+                        .and_then(Rotation::from_degrees)
+                        .with_context(|| {
+                            format!("invalid --rotate: {} (expected 0, 90, 180 or 270)", v)
+                        })?;
+                }
+                "--inject-drops" => {
+                    let v = value()?;
+                    args.inject_drops = match v.parse() {
+                        Ok(n @ 2..) => Some(n),
+                        _ => bail!("invalid --inject-drops: {} (expected 2 or more)", v),
+                    }
+                }
+                "--profile" => args.profile = true,
+                "--profile-trace" => args.profile_trace = Some(value()?.into()),
+                "--stress-modeset" => args.stress_modeset = Some(parse_count(&value()?)?),
+                "--sweep-outputs" => {
+                    args.sweep_outputs = Some(match inline.as_deref() {
+                        Some(secs) => parse_secs(secs)?,
+                        None => Duration::from_secs(5),
+                    })
+                }
+                // The mode is optional, so it only comes from `--verify=MODE`
+                "--shuffle" => {
+                    args.shuffle = Some(match inline.as_deref() {
+                        Some(seed) => seed
+                            .parse()
+                            .with_context(|| format!("invalid --shuffle seed: {}", seed))?,
+                        None => SystemTime::now()
+                            .duration_since(UNIX_EPOCH)
+                            .map_or(0, |d| d.as_nanos() as u64),
+                    })
+                }
+                "--shuffle-within-category" => args.shuffle_by_category = true,
+                "--soak-random" => {
+                    args.soak_random = Some(match inline.as_deref() {
+                        Some(seed) => seed
+                            .parse()
+                            .with_context(|| format!("invalid --soak-random seed: {}", seed))?,
+                        None => SystemTime::now()
+                            .duration_since(UNIX_EPOCH)
+                            .map_or(0, |d| d.as_nanos() as u64),
+                    })
+                }
+                "--soak-log" => args.soak_log = Some(value()?.into()),
+                "--only" => args.only = parse_patterns(&value()?, "--only")?,
+                "--skip" => args.skip = parse_patterns(&value()?, "--skip")?,
+                "--frame-checksum" => {
+                    args.frame_checksum = Some(match inline.as_deref() {
+                        Some(n) => parse_count(n)?,
+                        None => crate::checksum::DEFAULT_ROW_STEP,
+                    })
+                }
+                "--damage" => args.damage = true,
+                "--verify" => {
+                    args.verify = Some(match inline.as_deref() {
+                        None | Some("step") => Verify::Step,
+                        Some("all") => Verify::All,
+                        Some(v) => bail!("invalid --verify mode: {} (expected step or all)", v),
+                    })
+                }
+                _ => bail!("unknown argument: {}\n\n{}", arg, USAGE),
+            }
+        }
+
+        if args.record.is_some() && args.record_raw.is_some() {
+            bail!("--record and --record-raw can't be used together");
+        }
+        if args.gpio_chip.is_some() != args.gpio_line.is_some() {
+            bail!("--gpio-chip and --gpio-line must be given together");
+        }
+        // --writeback runs the same reduced loop as --fbdev
+        let basic = match (&args.fbdev, &args.writeback) {
+            (Some(_), Some(_)) => bail!("--fbdev and --writeback can't be used together"),
+            (Some(_), None) => Some("--fbdev"),
+            (None, Some(_)) => Some("--writeback"),
+            (None, None) => None,
+        };
+        if let Some(flag) = basic
+            && args.gpio_buttons.is_some()
+        {
+            bail!("--gpio-buttons is not available with {}", flag);
+        }
+        if let Some(flag) = basic
+            && !args.extra_cards.is_empty()
+        {
+            bail!("--card can only be given once with {}", flag);
+        }
+        if args.sync_outputs && args.extra_cards.is_empty() {
+            bail!("--sync-outputs needs a second --card");
+        }
+        if args.event_log_flips && args.event_log.is_none() {
+            bail!("--event-log-flips needs --event-log");
+        }
+        // These all take the full-size stage, which isn't drawn when scaled
+        if args.render_scale > 1 {
+            for (given, flag) in [
+                (args.record.is_some(), "--record"),
+                (args.record_raw.is_some(), "--record-raw"),
+                (args.preview_listen.is_some(), "--preview-listen"),
+                (args.shm_export.is_some(), "--shm-export"),
+                (args.verify.is_some(), "--verify"),
+                (args.nv12.is_some(), "--nv12"),
+                (args.fbdev.is_some(), "--fbdev"),
+                (args.writeback.is_some(), "--writeback"),
+                (!args.extra_cards.is_empty(), "--card (more than one)"),
+                (args.logical.is_some(), "--logical"),
+                (args.rotate != Rotation::None, "--rotate"),
+            ] {
+                if given {
+                    bail!("--render-scale can't be used with {}", flag);
+                }
+            }
+        }
+        if (args.matte != (0, 0, 0) || args.logical_marker) && args.logical.is_none() {
+            bail!("--matte and --logical-marker need --logical");
+        }
+        if let Some(hz) = args.blink_inverse {
+            let max = if args.allow_flashing {
+                BLINK_MAX_HZ
+            } else {
+                BLINK_SAFE_HZ
+            };
+            if !(BLINK_MIN_HZ..=max).contains(&hz) {
+                bail!(
+                    "--blink-inverse must be {} to {} Hz{}",
+                    BLINK_MIN_HZ,
+                    max,
+                    if args.allow_flashing {
+                        ""
+                    } else {
+                        " (faster needs --allow-flashing)"
+                    }
+                );
+            }
+        }
+        if args.shuffle_by_category && args.shuffle.is_none() {
+            bail!("--shuffle-within-category needs --shuffle");
+        }
+        if args.profile_trace.is_some() && !args.profile {
+            bail!("--profile-trace needs --profile");
+        }
+        if let Some(flag) = basic
+            && args.profile
+        {
+            bail!("--profile is not available with {}", flag);
+        }
+        if args.leak_warn_kb.is_some() && args.leak_watch.is_none() {
+            bail!("--leak-warn needs --leak-watch");
+        }
+        if let Some(flag) = basic
+            && args.leak_watch.is_some()
+        {
+            bail!("--leak-watch is not available with {}", flag);
+        }
+        if args.inject_drops.is_some() && (basic.is_some() || args.patch_server.is_some()) {
+            bail!("--inject-drops can't be used with --fbdev, --writeback or --patch-server");
+        }
+        if args.patch_server.is_some() && args.rotate != Rotation::None {
+            bail!("--rotate can't be used with --patch-server");
+        }
+        if args.patch_server.is_some() && args.auto.is_some() {
+            bail!("--auto has no effect with --patch-server");
+        }
+        if let Some(flag) = basic
+            && args.sweep_outputs.is_some()
+        {
+            bail!("--sweep-outputs is not available with {}", flag);
+        }
+        if let Some(flag) = basic
+            && args.max_bpc.is_some()
+        {
+            bail!("--max-bpc is not available with {}", flag);
+        }
+        if args.soak.is_some() {
+            if let Some(flag) = basic {
+                bail!("--soak is not available with {}", flag);
+            }
+            if args.patch_server.is_some() || args.max_runtime.is_some() {
+                bail!("--soak can't be used with --patch-server or --max-runtime");
+            }
+            args.auto.get_or_insert(Duration::from_secs(5));
+        }
+        if args.soak_random.is_some() {
+            if args.soak.is_none() {
+                bail!("--soak-random needs --soak");
+            }
+            if args.script.is_some()
+                || args.shuffle.is_some()
+                || !args.only.is_empty()
+                || !args.skip.is_empty()
+            {
+                bail!(
+                    "--soak-random picks its own steps, so it can't be used with --script, --shuffle, --only or --skip"
+                );
+            }
+        } else if args.soak_log.is_some() {
+            bail!("--soak-log needs --soak-random");
+        }
+        if args.attract {
+            if let Some(flag) = basic {
+                bail!("--attract is not available with {}", flag);
+            }
+            if args.patch_server.is_some() || args.soak.is_some() || args.script.is_some() {
+                bail!("--attract can't be used with --patch-server, --soak or --script");
+            }
+            args.auto.get_or_insert(Duration::from_secs(15));
+        }
+
+        Ok(args)
+    }
+}
+
+// WxH or WxH@HZ
+fn parse_mode(s: &str) -> Result<ModeSelector> {
+    let (size, refresh) = match s.split_once('@') {
+        Some((size, hz)) => (
+            size,
+            Some(
+                hz.parse()
+                    .with_context(|| format!("invalid refresh rate: {}", hz))?,
+            ),
+        ),
+        None => (s, None),
+    };
+    let (w, h) = size
+        .split_once('x')
+        .ok_or_else(|| anyhow!("mode must look like 1920x1080 or 1920x1080@60: {}", s))?;
+    Ok(ModeSelector::Size {
+        w: w.parse().with_context(|| format!("invalid width: {}", w))?,
+        h: h.parse()
+            .with_context(|| format!("invalid height: {}", h))?,
+        refresh,
+    })
+}
+
+// WxH in pixels or W:H as an aspect ratio
+// Comma separated pattern names, plural or not
+fn parse_patterns(s: &str, flag: &str) -> Result<Vec<PatternKind>> {
+    let mut kinds = Vec::new();
+    for name in s.split(',').map(str::trim).filter(|n| !n.is_empty()) {
+        let kind = PatternKind::from_name(name)
+            .or_else(|| name.strip_suffix('s').and_then(PatternKind::from_name))
+            .ok_or_else(|| {
+                let names: Vec<String> = PatternKind::ALL.iter().map(|k| k.name()).collect();
+                anyhow!(
+                    "unknown pattern for {}: {} (expected one of {})",
+                    flag,
+                    name,
+                    names.join(", ")
+                )
+            })?;
+        kinds.push(kind);
+    }
+    if kinds.is_empty() {
+        bail!("{} needs at least one pattern name", flag);
+    }
+    Ok(kinds)
+}
+
+fn parse_logical(s: &str) -> Result<Logical> {
+    if let Some((w, h)) = s.split_once(':') {
+        let (w, h) = (parse_positive(w)?, parse_positive(h)?);
+        return Ok(Logical::Aspect(w / h));
+    }
+    let (w, h) = s
+        .split_once('x')
+        .ok_or_else(|| anyhow!("--logical must look like 1920x1080 or 4:3: {}", s))?;
+    Ok(Logical::Size(parse_count(w)?, parse_count(h)?))
+}
+
+// CLOCK_MHZ HDISP HSS HSE HTOTAL VDISP VSS VSE VTOTAL [FLAGS...], as cvt
+// prints after `Modeline "name"`, which may be left on
+fn parse_modeline(s: &str) -> Result<ModeTimings> {
+    let mut words = s.split_whitespace().peekable();
+    if words
+        .peek()
+        .is_some_and(|w| w.eq_ignore_ascii_case("modeline"))
+    {
+        words.next();
+    }
+    if words.peek().is_some_and(|w| w.starts_with('"')) {
+        words.next();
+    }
+
+    let clock = words
+        .next()
+        .ok_or_else(|| anyhow!("the modeline is empty"))?;
+    let clock_mhz: f64 = clock
+        .parse()
+        .with_context(|| format!("invalid pixel clock: {}", clock))?;
+    let mut timing = || -> Result<u16> {
+        let w = words
+            .next()
+            .ok_or_else(|| anyhow!("the modeline needs a clock and 8 timings: {}", s))?;
+        w.parse().with_context(|| format!("invalid timing: {}", w))
+    };
+    let h = [timing()?, timing()?, timing()?, timing()?];
+    let v = [timing()?, timing()?, timing()?, timing()?];
+
+    let mut flags = ModeFlags::empty();
+    for w in words {
+        flags |= match w.to_ascii_lowercase().as_str() {
+            "+hsync" => ModeFlags::PHSYNC,
+            "-hsync" => ModeFlags::NHSYNC,
+            "+vsync" => ModeFlags::PVSYNC,
+            "-vsync" => ModeFlags::NVSYNC,
+            "interlace" => ModeFlags::INTERLACE,
+            "doublescan" => ModeFlags::DBLSCAN,
+            _ => bail!(
+                "invalid modeline flag: {} (expected +hsync, -hsync, +vsync, -vsync, interlace or doublescan)",
+                w
+            ),
+        };
+    }
+
+    Ok(ModeTimings {
+        clock_khz: (clock_mhz * 1000.0).round() as u32,
+        h,
+        v,
+        flags,
+    })
+}
+
+// CHIP:ACTION=LINE[,ACTION=LINE...]
+fn parse_buttons(s: &str) -> Result<(PathBuf, Vec<(ButtonAction, u32)>)> {
+    let (chip, buttons) = s
+        .rsplit_once(':')
+        .ok_or_else(|| anyhow!("--gpio-buttons must look like CHIP:next=17,quit=22: {}", s))?;
+    let mut actions = Vec::new();
+    for button in buttons.split(',') {
+        let (action, line) = button
+            .split_once('=')
+            .ok_or_else(|| anyhow!("invalid button: {} (expected ACTION=LINE)", button))?;
+        let line: u32 = line
+            .parse()
+            .with_context(|| format!("invalid GPIO line: {}", line))?;
+        if actions.iter().any(|&(_, l)| l == line) {
+            bail!("GPIO line {} is given twice", line);
+        }
+        actions.push((ButtonAction::parse(action)?, line));
+    }
+    Ok((chip.into(), actions))
+}
+
+// DEV or DEV:BAUD
+fn parse_serial(s: &str) -> Result<(String, u32)> {
+    match s.rsplit_once(':') {
+        Some((dev, baud)) => Ok((
+            dev.to_string(),
+            baud.parse()
+                .with_context(|| format!("invalid baud rate: {}", baud))?,
+        )),
+        None => Ok((s.to_string(), 9600)),
+    }
+}
+
+fn parse_port(s: &str) -> Result<u16> {
+    s.parse().with_context(|| format!("invalid port: {}", s))
+}
+
+// [ADDR:]PORT, on loopback unless an address is given
+fn parse_listen(s: &str) -> Result<SocketAddr> {
+    if let Ok(port) = s.parse() {
+        return Ok((Ipv4Addr::LOCALHOST, port).into());
+    }
+    s.parse()
+        .with_context(|| format!("invalid address, expected [ADDR:]PORT: {}", s))
+}
+
+fn parse_format(s: &str) -> Result<DrmFourcc> {
+    match s.to_ascii_lowercase().as_str() {
+        "xrgb8888" => Ok(DrmFourcc::Xrgb8888),
+        "argb8888" => Ok(DrmFourcc::Argb8888),
+        _ => bail!("unsupported format: {} (expected xrgb8888 or argb8888)", s),
+    }
+}
+
+fn parse_positive(s: &str) -> Result<f64> {
+    let v: f64 = s
+        .parse()
+        .with_context(|| format!("invalid number: {}", s))?;
+    if !v.is_finite() || v <= 0.0 {
+        bail!("value must be positive: {}", s);
+    }
+    Ok(v)
+}
+
+fn parse_count(s: &str) -> Result<usize> {
+    let n: usize = s.parse().with_context(|| format!("invalid count: {}", s))?;
+    if n == 0 {
+        bail!("count must be at least 1: {}", s);
+    }
+    Ok(n)
+}
+
+fn parse_secs(s: &str) -> Result<Duration> {
+    Ok(Duration::from_secs_f64(parse_positive(s)?))
+}
+
+// SECS, or a number with an s, m, h or d suffix
+fn parse_span(s: &str) -> Result<Duration> {
+    let (n, unit) = match s.char_indices().last() {
+        Some((i, 's')) => (&s[..i], 1.0),
+        Some((i, 'm')) => (&s[..i], 60.0),
+        Some((i, 'h')) => (&s[..i], 3600.0),
+        Some((i, 'd')) => (&s[..i], 86400.0),
+        _ => (s, 1.0),
+    };
+    let secs = parse_positive(n).with_context(|| format!("invalid duration: {}", s))?;
+    Ok(Duration::from_secs_f64(secs * unit))
+}
@@ -0,0 +1,346 @@
+use serde::{Deserialize, Serialize};
+
+/// Encodes linear light (0.0..=1.0) to an sRGB signal value with the
+/// piecewise OETF from IEC 61966-2-1.
+pub fn srgb_encode(linear: f64) -> f64 {
+    let l = linear.clamp(0.0, 1.0);
+    if l <= 0.003_130_8 {
+        12.92 * l
+    } else {
+        1.055 * l.powf(1.0 / 2.4) - 0.055
+    }
+}
+
+/// Decodes an sRGB signal value (0.0..=1.0) back to linear light.
+pub fn srgb_decode(encoded: f64) -> f64 {
+    let v = encoded.clamp(0.0, 1.0);
+    if v <= 0.040_45 {
+        v / 12.92
+    } else {
+        ((v + 0.055) / 1.055).powf(2.4)
+    }
+}
+
+/// Linear light to an 8-bit sRGB code value.
+pub fn srgb_encode_u8(linear: f64) -> u8 {
+    (srgb_encode(linear) * 255.0).round() as u8
+}
+
+/// An 8-bit sRGB code value to linear light.
+pub fn srgb_decode_u8(code: u8) -> f64 {
+    srgb_decode(code as f64 / 255.0)
+}
+
+/// A hue in degrees, saturation and value in 0..=1 to 8-bit RGB.
+pub fn hsv_to_rgb(h: f64, s: f64, v: f64) -> (u8, u8, u8) {
+    let h = h.rem_euclid(360.0) / 60.0;
+    let c = v * s;
+    let x = c * (1.0 - (h % 2.0 - 1.0).abs());
+    let (r, g, b) = match h as u32 {
+        0 => (c, x, 0.0),
+        1 => (x, c, 0.0),
+        2 => (0.0, c, x),
+        3 => (0.0, x, c),
+        4 => (x, 0.0, c),
+        _ => (c, 0.0, x),
+    };
+    let m = v - c;
+    let code = |f: f64| ((f + m) * 255.0).round() as u8;
+    (code(r), code(g), code(b))
+}
+
+/// Code value range the RGB output is drawn in
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum OutputRange {
+    /// 0..=255
+    #[default]
+    Full,
+    /// 16..=235 (studio swing): black at 16, white at 235
+    Limited,
+}
+
+impl OutputRange {
+    pub fn name(self) -> &'static str {
+        match self {
+            OutputRange::Full => "full",
+            OutputRange::Limited => "limited",
+        }
+    }
+
+    pub fn next(self) -> Self {
+        match self {
+            OutputRange::Full => OutputRange::Limited,
+            OutputRange::Limited => OutputRange::Full,
+        }
+    }
+}
+
+/// A second range conversion after the output range, as a chain that
+/// converts twice would do, to show what that looks like
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DoubleRange {
+    #[default]
+    Off,
+    /// Full to limited again: black rises and white drops a second time
+    Compress,
+    /// Limited to full again: shadows and highlights clip
+    Expand,
+}
+
+impl DoubleRange {
+    pub fn next(self) -> Self {
+        match self {
+            DoubleRange::Off => DoubleRange::Compress,
+            DoubleRange::Compress => DoubleRange::Expand,
+            DoubleRange::Expand => DoubleRange::Off,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            DoubleRange::Off => "off",
+            DoubleRange::Compress => "compress",
+            DoubleRange::Expand => "expand",
+        }
+    }
+}
+
+/// A full-range code value in limited range, 16 + v * 219 / 255 rounded to
+/// nearest
+pub fn to_limited_range(v: u8) -> u8 {
+    (16 + (v as u32 * 219 + 127) / 255) as u8
+}
+
+/// A limited-range code value stretched to full range, (v - 16) * 255 / 219
+/// rounded to nearest; below 16 and above 235 clip
+pub fn to_full_range(v: u8) -> u8 {
+    let v = v.clamp(16, 235) as u32 - 16;
+    ((v * 255 + 109) / 219) as u8
+}
+
+/// Quantization range for 8-bit YCbCr
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum YcbcrRange {
+    /// Y in 0..=255, Cb/Cr in 0..=255 around 128
+    Full,
+    /// Y in 16..=235, Cb/Cr in 16..=240 (studio swing)
+    Limited,
+}
+
+/// Luma coefficients for R'G'B' to Y'CbCr
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum YuvMatrix {
+    /// SD: Kr 0.299, Kb 0.114
+    Bt601,
+    /// HD: Kr 0.2126, Kb 0.0722
+    Bt709,
+}
+
+impl YuvMatrix {
+    pub fn name(self) -> &'static str {
+        match self {
+            YuvMatrix::Bt601 => "BT.601",
+            YuvMatrix::Bt709 => "BT.709",
+        }
+    }
+
+    fn kr_kb(self) -> (f64, f64) {
+        match self {
+            YuvMatrix::Bt601 => (0.299, 0.114),
+            YuvMatrix::Bt709 => (0.2126, 0.0722),
+        }
+    }
+}
+
+/// Converts 8-bit R'G'B' to Y'CbCr with the given matrix. The inputs are
+/// f64 so averaged (subsampled) values convert without rounding first.
+pub fn rgb_to_ycbcr(r: f64, g: f64, b: f64, matrix: YuvMatrix, range: YcbcrRange) -> (u8, u8, u8) {
+    let (kr, kb) = matrix.kr_kb();
+    let (r, g, b) = (r / 255.0, g / 255.0, b / 255.0);
+    let y = kr * r + (1.0 - kr - kb) * g + kb * b;
+    let cb = (b - y) / (2.0 * (1.0 - kb));
+    let cr = (r - y) / (2.0 * (1.0 - kr));
+
+    let (y_scale, y_off, c_scale) = match range {
+        YcbcrRange::Full => (255.0, 0.0, 255.0),
+        YcbcrRange::Limited => (219.0, 16.0, 224.0),
+    };
+    let q = |v: f64| v.round().clamp(0.0, 255.0) as u8;
+    (
+        q(y_off + y_scale * y),
+        q(128.0 + c_scale * cb),
+        q(128.0 + c_scale * cr),
+    )
+}
+
+/// Converts 8-bit R'G'B' to Y'CbCr with the BT.709 coefficients.
+pub fn rgb_to_ycbcr709(r: u8, g: u8, b: u8, range: YcbcrRange) -> (u8, u8, u8) {
+    rgb_to_ycbcr(r as f64, g as f64, b as f64, YuvMatrix::Bt709, range)
+}
+
+/// Linear-light sRGB for a white of chromaticity (x, y), scaled so the
+/// largest channel is 1.0. Out-of-gamut components are clipped to zero.
+pub fn white_rgb_linear(x: f64, y: f64) -> (f64, f64, f64) {
+    // XYZ with Y = 1, then the standard XYZ to linear sRGB matrix
+    let (cx, cy, cz) = (x / y, 1.0, (1.0 - x - y) / y);
+    let r = 3.2406 * cx - 1.5372 * cy - 0.4986 * cz;
+    let g = -0.9689 * cx + 1.8758 * cy + 0.0415 * cz;
+    let b = 0.0557 * cx - 0.2040 * cy + 1.0570 * cz;
+
+    let (r, g, b) = (r.max(0.0), g.max(0.0), b.max(0.0));
+    let max = r.max(g).max(b).max(f64::MIN_POSITIVE);
+    (r / max, g / max, b / max)
+}
+
+/// Primaries a set of gamut corners is drawn for, all with a D65 white
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Gamut {
+    /// Rec.709, the same primaries as sRGB
+    #[default]
+    Rec709,
+    /// DCI-P3 primaries with a D65 white (Display P3)
+    DciP3,
+    Bt2020,
+}
+
+// D65 as x, y
+const D65: (f64, f64) = (0.3127, 0.3290);
+
+impl Gamut {
+    pub fn next(self) -> Self {
+        match self {
+            Gamut::Rec709 => Gamut::DciP3,
+            Gamut::DciP3 => Gamut::Bt2020,
+            Gamut::Bt2020 => Gamut::Rec709,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Gamut::Rec709 => "Rec.709",
+            Gamut::DciP3 => "DCI-P3",
+            Gamut::Bt2020 => "BT.2020",
+        }
+    }
+
+    /// Red, green and blue as x, y
+    fn primaries(self) -> [(f64, f64); 3] {
+        match self {
+            Gamut::Rec709 => [(0.640, 0.330), (0.300, 0.600), (0.150, 0.060)],
+            Gamut::DciP3 => [(0.680, 0.320), (0.265, 0.690), (0.150, 0.060)],
+            Gamut::Bt2020 => [(0.708, 0.292), (0.170, 0.797), (0.131, 0.046)],
+        }
+    }
+
+    /// Linear RGB to XYZ, white at Y = 1
+    fn to_xyz(self) -> [[f64; 3]; 3] {
+        let xyz = |(x, y): (f64, f64)| [x / y, 1.0, (1.0 - x - y) / y];
+        let p = self.primaries().map(xyz);
+        // Columns are the primaries' XYZ, each scaled so they sum to white
+        let cols = [
+            [p[0][0], p[1][0], p[2][0]],
+            [1.0, 1.0, 1.0],
+            [p[0][2], p[1][2], p[2][2]],
+        ];
+        let s = mul(invert(cols), xyz(D65));
+        cols.map(|row| [row[0] * s[0], row[1] * s[1], row[2] * s[2]])
+    }
+
+    /// The chromaticity x, y of linear RGB in this gamut
+    pub fn xy(self, rgb: [f64; 3]) -> (f64, f64) {
+        let [x, y, z] = mul(self.to_xyz(), rgb);
+        let sum = (x + y + z).max(f64::MIN_POSITIVE);
+        (x / sum, y / sum)
+    }
+
+    /// Linear RGB in this gamut as linear RGB in `signal`, and whether it
+    /// had to be clipped to fit, i.e. lies outside `signal`'s triangle
+    pub fn convert(self, rgb: [f64; 3], signal: Gamut) -> ([f64; 3], bool) {
+        let out = mul(invert(signal.to_xyz()), mul(self.to_xyz(), rgb));
+        // A little slack for rounding in the matrices
+        let clipped = out.iter().any(|&c| !(-1e-4..=1.0 + 1e-4).contains(&c));
+        (out.map(|c| c.clamp(0.0, 1.0)), clipped)
+    }
+}
+
+fn mul(m: [[f64; 3]; 3], v: [f64; 3]) -> [f64; 3] {
+    m.map(|row| row[0] * v[0] + row[1] * v[1] + row[2] * v[2])
+}
+
+fn invert(m: [[f64; 3]; 3]) -> [[f64; 3]; 3] {
+    let cof =
+        |r0: usize, r1: usize, c0: usize, c1: usize| m[r0][c0] * m[r1][c1] - m[r0][c1] * m[r1][c0];
+    let adj = [
+        [cof(1, 2, 1, 2), -cof(0, 2, 1, 2), cof(0, 1, 1, 2)],
+        [-cof(1, 2, 0, 2), cof(0, 2, 0, 2), -cof(0, 1, 0, 2)],
+        [cof(1, 2, 0, 1), -cof(0, 2, 0, 1), cof(0, 1, 0, 1)],
+    ];
+    let det = m[0][0] * adj[0][0] + m[0][1] * adj[1][0] + m[0][2] * adj[2][0];
+    adj.map(|row| row.map(|c| c / det))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn close(a: f64, b: f64) -> bool {
+        (a - b).abs() < 1e-4
+    }
+
+    #[test]
+    fn srgb_reference_points() {
+        assert_eq!(srgb_encode(0.0), 0.0);
+        assert!(close(srgb_encode(1.0), 1.0));
+        // The two pieces meet at the threshold
+        assert!(close(srgb_encode(0.003_130_8), 0.040_45));
+        assert!(close(srgb_decode(0.040_45), 0.003_130_8));
+        // Linear segment below it, not a pure power
+        assert!(close(srgb_encode(0.001), 0.012_92));
+        assert!(close(srgb_encode(0.18), 0.461_356));
+        assert!(close(srgb_encode(0.5), 0.735_357));
+        assert!(close(srgb_decode(0.5), 0.214_041));
+        assert_eq!(srgb_encode_u8(0.5), 188);
+        assert_eq!(srgb_encode_u8(0.18), 118);
+        assert!(close(srgb_decode_u8(128), 0.215_861));
+        // Out of range input clips
+        assert_eq!(srgb_encode(-1.0), 0.0);
+        assert!(close(srgb_decode(2.0), 1.0));
+    }
+
+    #[test]
+    fn srgb_round_trips_every_code() {
+        for code in 0..=255u8 {
+            assert_eq!(srgb_encode_u8(srgb_decode_u8(code)), code);
+        }
+    }
+
+    #[test]
+    fn limited_range_reference_points() {
+        assert_eq!(to_limited_range(0), 16);
+        assert_eq!(to_limited_range(255), 235);
+        assert_eq!(to_limited_range(128), 126);
+        assert_eq!(to_full_range(16), 0);
+        assert_eq!(to_full_range(235), 255);
+        assert_eq!(to_full_range(126), 128);
+        // Outside 16-235 clips
+        assert_eq!(to_full_range(0), 0);
+        assert_eq!(to_full_range(255), 255);
+    }
+
+    #[test]
+    fn limited_range_round_trips() {
+        for v in 0..=255u8 {
+            assert!(to_full_range(to_limited_range(v)).abs_diff(v) <= 1, "{}", v);
+            if v > 0 {
+                assert!(to_limited_range(v) >= to_limited_range(v - 1));
+            }
+        }
+        // Every limited code is hit, so none is lost going back
+        for v in 16..=235u8 {
+            assert_eq!(to_limited_range(to_full_range(v)), v, "{}", v);
+        }
+    }
+}
@@ -0,0 +1,109 @@
+use anyhow::{Context, Result, ensure};
+use serde::Deserialize;
+use std::path::Path;
+
+use crate::Step;
+use crate::color::OutputRange;
+use crate::library::validate_script;
+
+/// Settings read from `--config`, for things too detailed for the command
+/// line. Every section and key is optional.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub serial: SerialConfig,
+    pub output: OutputConfig,
+    pub ui: UiConfig,
+    pub backlight: BacklightConfig,
+    pub attract: AttractConfig,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AttractConfig {
+    /// What --attract loops through, in script step form, instead of the
+    /// built-in sequence
+    pub steps: Option<Vec<Step>>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct BacklightConfig {
+    /// Device under /sys/class/backlight, e.g. "intel_backlight", unless
+    /// --backlight-device says otherwise. Setting either key turns backlight
+    /// control on, as --backlight does.
+    pub device: Option<String>,
+    /// Level in percent to start at, unless --backlight gives one
+    pub level: Option<u32>,
+}
+
+impl BacklightConfig {
+    pub fn enabled(&self) -> bool {
+        self.device.is_some() || self.level.is_some()
+    }
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct UiConfig {
+    /// Size of the overlay, menus and other UI, 1 to 8, unless --ui-scale
+    /// says otherwise; derived from the screen height when left out
+    pub scale: Option<usize>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct OutputConfig {
+    /// Draw every pattern in this range unless --output-range says otherwise
+    pub range: Option<OutputRange>,
+    /// Swap red and blue in every frame written out, for hardware wired BGR;
+    /// F4 toggles it
+    pub swap_rb: bool,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SerialConfig {
+    /// Sent once a step has settled on screen, e.g. "M\r\n". Empty sends
+    /// nothing.
+    pub trigger: String,
+    /// How many frame periods after a step's first flip to wait before
+    /// sending the trigger, so the panel has finished its transition
+    pub settle_frames: u32,
+    /// Act on N (next), P (previous) and Q (quit) bytes from the instrument.
+    /// Off by default so a talkative instrument can't move the script.
+    pub commands: bool,
+}
+
+impl Default for SerialConfig {
+    fn default() -> Self {
+        Self {
+            trigger: String::new(),
+            settle_frames: 3,
+            commands: false,
+        }
+    }
+}
+
+impl Config {
+    pub fn load(path: &Path) -> Result<Self> {
+        let text = std::fs::read_to_string(path)
+            .with_context(|| format!("could not read config {}", path.display()))?;
+        let config: Config = toml::from_str(&text)
+            .with_context(|| format!("could not parse config {}", path.display()))?;
+        ensure!(
+            config.ui.scale.is_none_or(|s| (1..=8).contains(&s)),
+            "ui.scale in {} must be 1 to 8",
+            path.display()
+        );
+        ensure!(
+            config.backlight.level.is_none_or(|l| l <= 100),
+            "backlight.level in {} must be 0 to 100",
+            path.display()
+        );
+        if let Some(steps) = &config.attract.steps {
+            validate_script(steps, &format!("attract.steps in {}", path.display()))?;
+        }
+        Ok(config)
+    }
+}
@@ -0,0 +1,150 @@
+//! Remote control of the main loop. Front ends such as the D-Bus interface
+//! run on their own threads and send commands through a `ControlSender`; the
+//! main loop polls `ControlChannel::wake_fd` and applies them between frames.
+
+use anyhow::{Context, Result, anyhow};
+use serde::Serialize;
+use std::io::{Read, Write};
+use std::os::fd::{AsFd, BorrowedFd};
+use std::os::unix::net::UnixStream;
+use std::sync::Arc;
+use std::sync::mpsc::{Receiver, Sender, SyncSender, TryIter, channel};
+
+use crate::PatternKind;
+
+pub enum Command {
+    NextStep,
+    PreviousStep,
+    GotoStep(usize),
+    /// Go to the step with this `name` in the script
+    GotoName(String),
+    SetSolid(u8, u8, u8),
+    /// Show a solid color as the target for fine adjustment
+    SetTarget(u8, u8, u8),
+    Pause(bool),
+    Quit,
+    // Only D-Bus asks for status and step changes so far
+    #[cfg_attr(not(feature = "dbus"), allow(dead_code))]
+    Status(SyncSender<Status>),
+    /// A command for an extra `--card` instance, numbered from 1
+    Instance(usize, Box<Command>),
+}
+
+impl Command {
+    /// Addresses the command to `instance`, where 0 is the first card
+    pub fn to(self, instance: usize) -> Command {
+        match instance {
+            0 => self,
+            i => Command::Instance(i, Box::new(self)),
+        }
+    }
+}
+
+/// Parses a step target, either `STEP` or `INSTANCE:STEP`, e.g. "1:7"
+#[cfg_attr(not(feature = "dbus"), allow(dead_code))]
+pub fn parse_target(s: &str) -> Result<(usize, usize)> {
+    let (instance, step) = s.split_once(':').unwrap_or(("0", s));
+    let parse = |v: &str| {
+        v.trim().parse().map_err(|_| {
+            anyhow!(
+                "invalid step target: {} (expected STEP or INSTANCE:STEP)",
+                s
+            )
+        })
+    };
+    Ok((parse(instance)?, parse(step)?))
+}
+
+/// Snapshot of where the run is, as returned to remote callers
+#[derive(Clone, Debug, Serialize)]
+pub struct Status {
+    pub step: usize,
+    pub steps: usize,
+    pub pattern: PatternKind,
+    pub paused: bool,
+    pub script: String,
+    /// The output and its link, read when asked for, e.g. "DP-1 1920x1080
+    /// @ 60Hz, link Good, 8 bpc (max 10)"
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub link: Option<String>,
+}
+
+/// Step changes reported back to the front end, e.g. for a D-Bus signal
+#[derive(Clone, Copy, Debug)]
+#[cfg_attr(not(feature = "dbus"), allow(dead_code))]
+pub struct StepChange {
+    pub step: usize,
+    pub pattern: PatternKind,
+}
+
+#[derive(Clone)]
+pub struct ControlSender {
+    tx: Sender<Command>,
+    notify: Arc<UnixStream>,
+}
+
+impl ControlSender {
+    /// Queues a command and wakes the main loop. Returns false once the main
+    /// loop has gone away.
+    pub fn send(&self, cmd: Command) -> bool {
+        if self.tx.send(cmd).is_err() {
+            return false;
+        }
+        // Any byte will do; the main loop drains them all
+        let _ = (&*self.notify).write_all(&[0]);
+        true
+    }
+}
+
+/// The main loop's end of the control channel
+pub struct ControlChannel {
+    rx: Receiver<Command>,
+    wake: UnixStream,
+    changes: Sender<StepChange>,
+    last: Option<(usize, PatternKind)>,
+}
+
+/// Creates both ends of a control channel, plus the receiver for step changes
+pub fn channel_pair() -> Result<(ControlSender, ControlChannel, Receiver<StepChange>)> {
+    let (wake, notify) = UnixStream::pair().context("could not create wake socket")?;
+    wake.set_nonblocking(true)
+        .context("could not set up wake socket")?;
+    let (tx, rx) = channel();
+    let (changes, changes_rx) = channel();
+
+    Ok((
+        ControlSender {
+            tx,
+            notify: Arc::new(notify),
+        },
+        ControlChannel {
+            rx,
+            wake,
+            changes,
+            last: None,
+        },
+        changes_rx,
+    ))
+}
+
+impl ControlChannel {
+    pub fn wake_fd(&self) -> BorrowedFd<'_> {
+        self.wake.as_fd()
+    }
+
+    /// Clears the wakeup and returns every queued command
+    pub fn drain(&mut self) -> TryIter<'_, Command> {
+        let mut buf = [0u8; 64];
+        while matches!((&self.wake).read(&mut buf), Ok(n) if n > 0) {}
+        self.rx.try_iter()
+    }
+
+    /// Called once per loop iteration; passes on the step if it changed
+    pub fn observe(&mut self, step: usize, pattern: PatternKind) {
+        if self.last == Some((step, pattern)) {
+            return;
+        }
+        self.last = Some((step, pattern));
+        let _ = self.changes.send(StepChange { step, pattern });
+    }
+}
@@ -0,0 +1,121 @@
+//! `--damage`: copies only what changed since the last frame into the back
+//! buffer, instead of the whole stage. A mostly static pattern with a HUD
+//! or a moving bar then costs a few rows of writes to uncached memory a
+//! frame rather than the full screen.
+//!
+//! The catch is double buffering: the buffer written next holds the frame
+//! from two writes ago, not the last one, so a change has to reach every
+//! buffer in turn. Each buffer keeps its own list of regions it has missed
+//! since it was last written, and a buffer whose contents are unknown
+//! (first use, a new mode, a new LUT) gets the whole frame.
+
+use std::ops::Range;
+
+// Past this many regions a buffer's list becomes one region bounding them,
+// so a frame full of scattered changes is one copy, not hundreds
+const MAX_REGIONS: usize = 16;
+
+/// Rows `rows` of the frame, `bytes` of each row, pixel aligned
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Region {
+    pub rows: Range<usize>,
+    pub bytes: Range<usize>,
+}
+
+impl Region {
+    fn union(&self, other: &Region) -> Region {
+        Region {
+            rows: self.rows.start.min(other.rows.start)..self.rows.end.max(other.rows.end),
+            bytes: self.bytes.start.min(other.bytes.start)..self.bytes.end.max(other.bytes.end),
+        }
+    }
+}
+
+pub struct Damage {
+    // The frame last written, at the stride it came in with
+    last: Vec<u8>,
+    stride: usize,
+    // For each buffer, what has changed since it was last written; None
+    // when what it holds is unknown
+    missed: Vec<Option<Vec<Region>>>,
+}
+
+impl Damage {
+    pub fn new(buffers: usize) -> Self {
+        Self {
+            last: Vec::new(),
+            stride: 0,
+            missed: vec![None; buffers],
+        }
+    }
+
+    /// Forgets what every buffer holds, so each is written in full next
+    pub fn reset(&mut self) {
+        self.last.clear();
+        self.missed.iter_mut().for_each(|m| *m = None);
+    }
+
+    /// What of `src` to copy into `buffer` to bring it up to date, or None
+    /// for all of it. Either way the buffer counts as up to date afterwards.
+    pub fn take(
+        &mut self,
+        buffer: usize,
+        src: &[u8],
+        stride: usize,
+        rows: usize,
+    ) -> Option<Vec<Region>> {
+        let src = &src[..stride * rows];
+        if self.stride != stride || self.last.len() != src.len() {
+            self.reset();
+            self.stride = stride;
+            self.last.extend_from_slice(src);
+            self.missed[buffer] = Some(Vec::new());
+            return None;
+        }
+
+        let changed = changed_regions(&self.last, src, stride, rows);
+        for region in &changed {
+            for y in region.rows.clone() {
+                let span = y * stride + region.bytes.start..y * stride + region.bytes.end;
+                self.last[span.clone()].copy_from_slice(&src[span]);
+            }
+        }
+        for missed in self.missed.iter_mut().flatten() {
+            missed.extend(changed.iter().cloned());
+            if missed.len() > MAX_REGIONS {
+                let all = missed
+                    .iter()
+                    .skip(1)
+                    .fold(missed[0].clone(), |a, r| a.union(r));
+                *missed = vec![all];
+            }
+        }
+        self.missed[buffer].replace(Vec::new())
+    }
+}
+
+// Where `new` differs from `old`: each run of differing rows becomes one
+// region as wide as the differences in it
+fn changed_regions(old: &[u8], new: &[u8], stride: usize, rows: usize) -> Vec<Region> {
+    let mut regions: Vec<Region> = Vec::new();
+    for y in 0..rows {
+        let (a, b) = (
+            &old[y * stride..(y + 1) * stride],
+            &new[y * stride..(y + 1) * stride],
+        );
+        if a == b {
+            continue;
+        }
+        let first = a.iter().zip(b).position(|(p, q)| p != q).unwrap_or(0);
+        let last = a.iter().zip(b).rposition(|(p, q)| p != q).unwrap_or(first);
+        let row = Region {
+            rows: y..y + 1,
+            bytes: first / 4 * 4..(last / 4 + 1) * 4,
+        };
+        match regions.last_mut() {
+            Some(prev) if prev.rows.end == y => *prev = prev.union(&row),
+            _ => regions.push(row),
+        }
+    }
+    regions
+}
@@ -0,0 +1,164 @@
+//! `--dbus`: the org.screentest.Control interface, served from its own
+//! thread and forwarding every call through the control channel. Only built
+//! with the `dbus` feature so minimal builds don't pull in zbus.
+
+/// Which bus to claim the name on
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Bus {
+    Session,
+    System,
+}
+
+#[cfg(not(feature = "dbus"))]
+pub fn start(
+    _bus: Bus,
+    _tx: crate::control::ControlSender,
+    _changes: std::sync::mpsc::Receiver<crate::control::StepChange>,
+) -> anyhow::Result<()> {
+    anyhow::bail!("--dbus needs a build with the dbus feature")
+}
+
+#[cfg(feature = "dbus")]
+pub use imp::start;
+
+#[cfg(feature = "dbus")]
+mod imp {
+    use anyhow::{Context, Result};
+    use std::sync::mpsc::{Receiver, sync_channel};
+    use std::time::Duration;
+    use zbus::blocking::connection;
+    use zbus::fdo;
+    use zbus::interface;
+    use zbus::object_server::SignalEmitter;
+
+    use super::Bus;
+    use crate::control::{Command, ControlSender, StepChange, parse_target};
+
+    const NAME: &str = "org.screentest.Control";
+    const PATH: &str = "/org/screentest/Control";
+
+    // GetStatus waits at most this long for the main loop to answer
+    const STATUS_TIMEOUT: Duration = Duration::from_secs(1);
+
+    struct Control {
+        tx: ControlSender,
+    }
+
+    impl Control {
+        fn send(&self, cmd: Command) -> fdo::Result<()> {
+            if self.tx.send(cmd) {
+                Ok(())
+            } else {
+                Err(fdo::Error::Failed("screen_test is shutting down".into()))
+            }
+        }
+    }
+
+    #[interface(name = "org.screentest.Control")]
+    impl Control {
+        fn next_step(&self) -> fdo::Result<()> {
+            self.send(Command::NextStep)
+        }
+
+        fn previous_step(&self) -> fdo::Result<()> {
+            self.send(Command::PreviousStep)
+        }
+
+        fn goto_step(&self, index: u32) -> fdo::Result<()> {
+            self.send(Command::GotoStep(index as usize))
+        }
+
+        /// Goes to a step given as "STEP" or "INSTANCE:STEP", e.g. "1:7" for
+        /// step 7 on the second --card
+        fn goto(&self, target: &str) -> fdo::Result<()> {
+            let (instance, step) =
+                parse_target(target).map_err(|e| fdo::Error::InvalidArgs(e.to_string()))?;
+            self.send(Command::GotoStep(step).to(instance))
+        }
+
+        /// Goes to the step the script names `name`
+        fn goto_name(&self, name: &str) -> fdo::Result<()> {
+            self.send(Command::GotoName(name.to_string()))
+        }
+
+        fn set_solid(&self, r: u8, g: u8, b: u8) -> fdo::Result<()> {
+            self.send(Command::SetSolid(r, g, b))
+        }
+
+        fn set_target(&self, r: u8, g: u8, b: u8) -> fdo::Result<()> {
+            self.send(Command::SetTarget(r, g, b))
+        }
+
+        fn pause(&self, paused: bool) -> fdo::Result<()> {
+            self.send(Command::Pause(paused))
+        }
+
+        fn quit(&self) -> fdo::Result<()> {
+            self.send(Command::Quit)
+        }
+
+        /// The status as JSON
+        fn get_status(&self) -> fdo::Result<String> {
+            let (reply_tx, reply_rx) = sync_channel(1);
+            self.send(Command::Status(reply_tx))?;
+            let status = reply_rx
+                .recv_timeout(STATUS_TIMEOUT)
+                .map_err(|_| fdo::Error::Failed("no answer from the display loop".into()))?;
+            serde_json::to_string(&status).map_err(|e| fdo::Error::Failed(e.to_string()))
+        }
+
+        #[zbus(signal)]
+        async fn step_changed(
+            emitter: &SignalEmitter<'_>,
+            index: u32,
+            pattern: &str,
+        ) -> zbus::Result<()>;
+    }
+
+    /// Claims the bus name and starts serving, forwarding calls to `tx`.
+    /// Step changes arriving on `changes` are emitted as StepChanged signals.
+    pub fn start(bus: Bus, tx: ControlSender, changes: Receiver<StepChange>) -> Result<()> {
+        let builder = match bus {
+            Bus::Session => connection::Builder::session(),
+            Bus::System => connection::Builder::system(),
+        }
+        .context("could not connect to D-Bus")?;
+        let conn = builder
+            .name(NAME)
+            .context("invalid bus name")?
+            .serve_at(PATH, Control { tx })
+            .context("could not register the control interface")?
+            .build()
+            .with_context(|| format!("could not claim {} on D-Bus", NAME))?;
+
+        // zbus serves method calls on its own executor; this thread only
+        // turns step changes into signals and keeps the connection alive
+        std::thread::spawn(move || {
+            let Ok(iface) = conn.object_server().interface::<_, Control>(PATH) else {
+                return;
+            };
+            for change in changes {
+                let pattern = serde_json::to_value(change.pattern)
+                    .ok()
+                    .and_then(|v| v.as_str().map(str::to_string))
+                    .unwrap_or_default();
+                let emitter = iface.signal_emitter();
+                if let Err(e) =
+                    zbus::block_on(Control::step_changed(emitter, change.step as u32, &pattern))
+                {
+                    eprintln!("D-Bus: could not emit StepChanged: {}", e);
+                }
+            }
+        });
+
+        eprintln!(
+            "D-Bus control at {} on the {} bus",
+            NAME,
+            match bus {
+                Bus::Session => "session",
+                Bus::System => "system",
+            }
+        );
+        Ok(())
+    }
+}
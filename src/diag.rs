@@ -0,0 +1,272 @@
+use anyhow::{Context, Result};
+use drm::buffer::DrmFourcc;
+use drm::control::property::ValueType;
+use drm::control::{Device as CtrlDevice, ResourceHandle};
+use std::time::{Duration, Instant};
+
+use crate::events::{Event, EventLog};
+use crate::surface::{Card, Frame, SurfaceBuilder, live_framebuffers};
+
+/// Resident set size of this process, from /proc/self/status.
+pub fn current_rss_kb() -> Option<u64> {
+    let status = std::fs::read_to_string("/proc/self/status").ok()?;
+    let line = status.lines().find(|l| l.starts_with("VmRSS:"))?;
+    line.split_whitespace().nth(1)?.parse().ok()
+}
+
+/// `--leak-watch`: samples RSS and the number of live framebuffers at an
+/// interval over a long run, and warns when RSS has kept growing (never
+/// shrinking from one sample to the next) by more than `warn_kb`.
+pub struct LeakWatch {
+    interval: Duration,
+    warn_kb: u64,
+    next: Instant,
+    start_kb: Option<u64>,
+    last_kb: Option<u64>,
+    // Where the current run of growth began; None once it has been warned
+    // about, until RSS shrinks and a new run starts
+    rise_from: Option<(Instant, u64)>,
+}
+
+impl LeakWatch {
+    pub fn new(interval: Duration, warn_kb: u64) -> Self {
+        Self {
+            interval,
+            warn_kb,
+            next: Instant::now(),
+            start_kb: None,
+            last_kb: None,
+            rise_from: None,
+        }
+    }
+
+    /// When the next sample is due, so poll can wake for it
+    pub fn deadline(&self) -> Instant {
+        self.next
+    }
+
+    /// Takes a sample if one is due
+    pub fn check(&mut self, now: Instant, log: Option<&mut EventLog>) {
+        if now < self.next {
+            return;
+        }
+        self.next = now + self.interval;
+        let Some(rss) = current_rss_kb() else {
+            return;
+        };
+        let framebuffers = live_framebuffers();
+        let start = *self.start_kb.get_or_insert(rss);
+        eprintln!(
+            "Leak watch: RSS {} kB ({:+} kB since start), {} framebuffers",
+            rss,
+            rss as i64 - start as i64,
+            framebuffers
+        );
+
+        let mut suspect = None;
+        match self.last_kb {
+            Some(last) if rss < last => self.rise_from = Some((now, rss)),
+            None => self.rise_from = Some((now, rss)),
+            Some(_) => {
+                if let Some((since, from)) = self.rise_from
+                    && rss - from > self.warn_kb
+                {
+                    let over = now.duration_since(since);
+                    eprintln!(
+                        "Warning: RSS has grown by {} kB over {} s without shrinking; possible leak",
+                        rss - from,
+                        over.as_secs()
+                    );
+                    suspect = Some(Event::LeakSuspected {
+                        growth_kb: rss - from,
+                        over_s: over.as_secs(),
+                    });
+                    self.rise_from = None;
+                }
+            }
+        }
+        self.last_kb = Some(rss);
+
+        if let Some(log) = log {
+            log.log(Event::ResourceUsage {
+                rss_kb: rss,
+                framebuffers,
+            });
+            if let Some(event) = suspect {
+                log.log(event);
+            }
+        }
+    }
+}
+
+/// Repeatedly allocates a pair of framebuffers, modesets onto them and tears
+/// everything down again, to shake out driver and leak problems in the
+/// allocation and cleanup paths.
+pub fn stress_modeset(builder: &SurfaceBuilder, iterations: usize) -> Result<()> {
+    let card = builder.open_card()?;
+    let out = builder.select_output(&card)?;
+    let (w, h) = (out.mode.size().0 as u32, out.mode.size().1 as u32);
+
+    eprintln!(
+        "Stress modeset on {}: {} iterations at {}x{}",
+        out.label, iterations, w, h
+    );
+
+    let rss_start = current_rss_kb();
+    let report_every = (iterations / 10).max(1);
+
+    let result = (0..iterations).try_for_each(|i| -> Result<()> {
+        let f0 = Frame::create(&card, w, h, DrmFourcc::Xrgb8888)
+            .with_context(|| format!("iteration {}", i))?;
+        let f1 = match Frame::create(&card, w, h, DrmFourcc::Xrgb8888) {
+            Ok(f) => f,
+            Err(e) => {
+                f0.destroy(&card);
+                return Err(e.context(format!("iteration {}", i)));
+            }
+        };
+
+        let set = card
+            .set_crtc(out.crtc, Some(f0.fb), (0, 0), &[out.con], Some(out.mode))
+            .and_then(|_| card.set_crtc(out.crtc, Some(f1.fb), (0, 0), &[out.con], Some(out.mode)));
+
+        let _ = card.set_crtc(out.crtc, None, (0, 0), &[], None);
+        f0.destroy(&card);
+        f1.destroy(&card);
+
+        set.with_context(|| format!("set_crtc failed at iteration {}", i))?;
+
+        if (i + 1) % report_every == 0 {
+            eprintln!(
+                "  {}/{} ok, rss {} kB",
+                i + 1,
+                iterations,
+                current_rss_kb().map_or("?".to_string(), |kb| kb.to_string())
+            );
+        }
+        Ok(())
+    });
+
+    if let (Some(a), Some(b)) = (rss_start, current_rss_kb()) {
+        eprintln!("RSS {} kB -> {} kB ({:+} kB)", a, b, b as i64 - a as i64);
+    }
+
+    match &result {
+        Ok(()) => eprintln!("Completed {} iterations", iterations),
+        Err(e) => eprintln!("Stress modeset failed: {:#}", e),
+    }
+
+    result
+}
+
+/// `--dump-props`: prints every property of the selected connector, its
+/// CRTC and the CRTC's primary plane, then returns without a modeset.
+pub fn dump_props(builder: &SurfaceBuilder) -> Result<()> {
+    let card = builder.open_card()?;
+    let out = builder.select_output(&card)?;
+
+    println!("Connector {}:", out.label);
+    dump_properties(&card, out.con)?;
+    println!("CRTC {:?}:", out.crtc);
+    dump_properties(&card, out.crtc)?;
+
+    let res = card
+        .resource_handles()
+        .context("could not load resource handles")?;
+    let primary = card
+        .plane_handles()
+        .context("could not list planes")?
+        .into_iter()
+        .filter(|&p| {
+            card.get_plane(p)
+                .is_ok_and(|info| res.filter_crtcs(info.possible_crtcs()).contains(&out.crtc))
+        })
+        .find(|&p| enum_property(&card, p, "type").as_deref() == Some("Primary"));
+    match primary {
+        Some(plane) => {
+            println!("Primary plane {:?}:", plane);
+            dump_properties(&card, plane)?;
+        }
+        None => println!("No primary plane found for this CRTC"),
+    }
+    Ok(())
+}
+
+/// Prints each property of a DRM object: name, flags, type with its range
+/// or enum options, and current value
+pub fn dump_properties<T: ResourceHandle>(card: &Card, handle: T) -> Result<()> {
+    let props = card
+        .get_properties(handle)
+        .context("could not read properties")?;
+    let (handles, values) = props.as_props_and_values();
+    for (&prop, &raw) in handles.iter().zip(values) {
+        let info = card
+            .get_property(prop)
+            .context("could not read a property")?;
+        let ty = info.value_type();
+        let flags = match (info.mutable(), info.atomic()) {
+            (false, _) => " (immutable)",
+            (true, true) => " (atomic)",
+            (true, false) => "",
+        };
+        let (kind, value) = match &ty {
+            ValueType::Boolean => ("bool".to_string(), (raw != 0).to_string()),
+            ValueType::UnsignedRange(min, max) => {
+                (format!("range {}..={}", min, max), raw.to_string())
+            }
+            ValueType::SignedRange(min, max) => (
+                format!("signed range {}..={}", min, max),
+                (raw as i64).to_string(),
+            ),
+            ValueType::Enum(values) => {
+                let (_, entries) = values.values();
+                let names: Vec<_> = entries.iter().map(|e| e.name().to_string_lossy()).collect();
+                let current = values.get_value_from_raw_value(raw).map_or_else(
+                    || format!("{} (not an option)", raw),
+                    |e| e.name().to_string_lossy().into_owned(),
+                );
+                (format!("enum {{{}}}", names.join(", ")), current)
+            }
+            ValueType::Bitmask => ("bitmask".to_string(), format!("{:#x}", raw)),
+            ValueType::Blob => (
+                "blob".to_string(),
+                match raw {
+                    0 => "none".to_string(),
+                    id => match card.get_property_blob(id) {
+                        Ok(data) => format!("id {}, {} bytes", id, data.len()),
+                        Err(_) => format!("id {}", id),
+                    },
+                },
+            ),
+            ValueType::Unknown => ("unknown".to_string(), raw.to_string()),
+            // Object references: CRTC_ID, FB_ID and the like
+            other => (format!("{:?}", other).to_lowercase(), format!("id {}", raw)),
+        };
+        println!(
+            "    {}: {}{} = {}",
+            info.name().to_string_lossy(),
+            kind,
+            flags,
+            value
+        );
+    }
+    Ok(())
+}
+
+// Current value of an enum property by name, if the object has one
+fn enum_property<T: ResourceHandle>(card: &Card, handle: T, name: &str) -> Option<String> {
+    let props = card.get_properties(handle).ok()?;
+    let (handles, values) = props.as_props_and_values();
+    handles.iter().zip(values).find_map(|(&prop, &raw)| {
+        let info = card.get_property(prop).ok()?;
+        if info.name().to_bytes() != name.as_bytes() {
+            return None;
+        }
+        let ValueType::Enum(values) = info.value_type() else {
+            return None;
+        };
+        values
+            .get_value_from_raw_value(raw)
+            .map(|e| e.name().to_string_lossy().into_owned())
+    })
+}
@@ -0,0 +1,1260 @@
+//! Pixel-level drawing primitives shared by the patterns. Everything writes
+//! BGRX pixels into a buffer of the given stride and clips to w x h, or for
+//! put_rgb, which has no w x h, to the row and the buffer.
+
+use font8x8::legacy::BASIC_LEGACY;
+use serde::{Deserialize, Serialize};
+
+use crate::color;
+
+// Sets one pixel. A pixel past the end of its row or of the buffer is
+// dropped rather than wrapped onto the next row or allowed to panic, so a
+// pattern that miscounts loses a few pixels instead of the display.
+pub fn put_rgb(buf: &mut [u8], stride: usize, x: usize, y: usize, r: u8, g: u8, b: u8) {
+    let offset = y * stride + x * 4;
+    if x * 4 + 4 > stride || offset + 4 > buf.len() {
+        return;
+    }
+    put_rgb_unchecked(buf, stride, x, y, r, g, b);
+}
+
+// put_rgb for loops that already keep (x, y) inside the buffer. Out of
+// bounds it wraps or panics.
+#[inline]
+pub fn put_rgb_unchecked(buf: &mut [u8], stride: usize, x: usize, y: usize, r: u8, g: u8, b: u8) {
+    let offset = y * stride + x * 4;
+    buf[offset..offset + 4].copy_from_slice(&xrgb(r, g, b));
+}
+
+#[inline]
+pub fn xrgb(r: u8, g: u8, b: u8) -> [u8; 4] {
+    [b, g, r, 0xff]
+}
+
+// Composites (r, g, b) at opacity a/255 over one BGRX pixel (source over).
+// The primary plane ignores the X byte, so anything translucent has to be
+// blended here against what is already drawn; the result stays opaque.
+#[inline]
+pub fn blend(px: &mut [u8], r: u8, g: u8, b: u8, a: u8) {
+    let (r, g, b) = mix_rgb((px[2], px[1], px[0]), (r, g, b), a);
+    px.copy_from_slice(&xrgb(r, g, b));
+}
+
+// put_rgb at opacity a/255 over the pixel already there, clipped the same way
+#[allow(clippy::too_many_arguments)]
+pub fn put_argb(buf: &mut [u8], stride: usize, x: usize, y: usize, r: u8, g: u8, b: u8, a: u8) {
+    let offset = y * stride + x * 4;
+    if x * 4 + 4 > stride || offset + 4 > buf.len() {
+        return;
+    }
+    blend(&mut buf[offset..offset + 4], r, g, b, a);
+}
+
+// Fills the first w pixels of a row with one color
+pub fn fill_row(row: &mut [u8], w: usize, r: u8, g: u8, b: u8) {
+    let px = xrgb(r, g, b);
+    for dst in row[..w * 4].chunks_exact_mut(4) {
+        dst.copy_from_slice(&px);
+    }
+}
+
+// Copies the first row over rows 1..h, for patterns where every row is identical
+pub fn replicate_first_row(buf: &mut [u8], stride: usize, w: usize, h: usize) {
+    for y in 1..h {
+        buf.copy_within(0..w * 4, y * stride);
+    }
+}
+
+// Replaces every pixel with its complement (255 - v per channel)
+pub fn invert_rgb(buf: &mut [u8], stride: usize, w: usize, h: usize) {
+    for y in 0..h {
+        for px in buf[y * stride..y * stride + w * 4].chunks_exact_mut(4) {
+            px[0] = !px[0];
+            px[1] = !px[1];
+            px[2] = !px[2];
+        }
+    }
+}
+
+// Exchanges the red and blue bytes of every pixel, for a panel or adapter
+// wired BGR: applied to the finished frame as it is written out, it puts
+// every color back where it belongs
+pub fn swap_rb(buf: &mut [u8], stride: usize, w: usize, h: usize) {
+    for y in 0..h {
+        for px in buf[y * stride..y * stride + w * 4].chunks_exact_mut(4) {
+            px.swap(0, 2);
+        }
+    }
+}
+
+// Zeroes the channels not marked in `keep`, given in BGRX byte order
+pub fn mask_channels(buf: &mut [u8], stride: usize, w: usize, h: usize, keep: [bool; 3]) {
+    let mask = [keep[0], keep[1], keep[2], true].map(|k| if k { 0xff } else { 0 });
+    for y in 0..h {
+        for px in buf[y * stride..y * stride + w * 4].chunks_exact_mut(4) {
+            for (v, m) in px.iter_mut().zip(mask) {
+                *v &= m;
+            }
+        }
+    }
+}
+
+// Remaps every channel of every pixel from full to limited range
+pub fn limit_range(buf: &mut [u8], stride: usize, w: usize, h: usize) {
+    remap_channels(buf, stride, w, h, color::to_limited_range);
+}
+
+// Remaps every channel of every pixel from limited to full range
+pub fn expand_range(buf: &mut [u8], stride: usize, w: usize, h: usize) {
+    remap_channels(buf, stride, w, h, color::to_full_range);
+}
+
+fn remap_channels(buf: &mut [u8], stride: usize, w: usize, h: usize, f: fn(u8) -> u8) {
+    let table: [u8; 256] = std::array::from_fn(|v| f(v as u8));
+    for y in 0..h {
+        for px in buf[y * stride..y * stride + w * 4].chunks_exact_mut(4) {
+            px[0] = table[px[0] as usize];
+            px[1] = table[px[1] as usize];
+            px[2] = table[px[2] as usize];
+        }
+    }
+}
+
+pub fn fill_rgb(buf: &mut [u8], stride: usize, w: usize, h: usize, r: u8, g: u8, b: u8) {
+    if h == 0 {
+        return;
+    }
+    fill_row(buf, w, r, g, b);
+    replicate_first_row(buf, stride, w, h);
+}
+
+// Blends a toward b by alpha/255, rounded to nearest
+pub fn mix_rgb(a: (u8, u8, u8), b: (u8, u8, u8), alpha: u8) -> (u8, u8, u8) {
+    let mix = |x: u8, y: u8| {
+        let (x, y, a) = (x as u32, y as u32, alpha as u32);
+        ((x * (255 - a) + y * a + 127) / 255) as u8
+    };
+    (mix(a.0, b.0), mix(a.1, b.1), mix(a.2, b.2))
+}
+
+// Blends the w x h frame in buf toward `from` by (255 - alpha)/255, i.e.
+// alpha 255 leaves buf as drawn; both share the same stride
+pub fn mix_frame(buf: &mut [u8], from: &[u8], stride: usize, w: usize, h: usize, alpha: u8) {
+    for y in 0..h {
+        let row = y * stride..y * stride + w * 4;
+        for (dst, src) in buf[row.clone()]
+            .chunks_exact_mut(4)
+            .zip(from[row].chunks_exact(4))
+        {
+            let (r, g, b) = mix_rgb((src[2], src[1], src[0]), (dst[2], dst[1], dst[0]), alpha);
+            dst.copy_from_slice(&xrgb(r, g, b));
+        }
+    }
+}
+
+// Writes |a - b| per channel, times `gain` and clipped, over w x h pixels
+// of buf, so anywhere two frames differ lights up on black. a and b are
+// packed, w pixels to a row. Returns how many pixels differ at all and the
+// largest difference in any channel.
+pub fn diff_frames(
+    buf: &mut [u8],
+    stride: usize,
+    a: &[u8],
+    b: &[u8],
+    w: usize,
+    h: usize,
+    gain: u32,
+) -> (u64, u8) {
+    let (mut differing, mut max) = (0, 0);
+    for y in 0..h {
+        let row = y * w * 4..(y + 1) * w * 4;
+        for ((dst, pa), pb) in buf[y * stride..y * stride + w * 4]
+            .chunks_exact_mut(4)
+            .zip(a[row.clone()].chunks_exact(4))
+            .zip(b[row].chunks_exact(4))
+        {
+            let d = [0, 1, 2].map(|c| pa[c].abs_diff(pb[c]));
+            let worst = d[0].max(d[1]).max(d[2]);
+            if worst > 0 {
+                differing += 1;
+                max = max.max(worst);
+            }
+            let amp = d.map(|v| (v as u32 * gain).min(255) as u8);
+            dst.copy_from_slice(&xrgb(amp[2], amp[1], amp[0]));
+        }
+    }
+    (differing, max)
+}
+
+/// Which corner of the screen the status overlay is stacked in
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Corner {
+    #[default]
+    TopLeft,
+    TopRight,
+    BottomLeft,
+    BottomRight,
+}
+
+impl Corner {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "top-left" => Some(Corner::TopLeft),
+            "top-right" => Some(Corner::TopRight),
+            "bottom-left" => Some(Corner::BottomLeft),
+            "bottom-right" => Some(Corner::BottomRight),
+            _ => None,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Corner::TopLeft => "top left",
+            Corner::TopRight => "top right",
+            Corner::BottomLeft => "bottom left",
+            Corner::BottomRight => "bottom right",
+        }
+    }
+
+    /// Clockwise round the screen
+    pub fn next(self) -> Self {
+        match self {
+            Corner::TopLeft => Corner::TopRight,
+            Corner::TopRight => Corner::BottomRight,
+            Corner::BottomRight => Corner::BottomLeft,
+            Corner::BottomLeft => Corner::TopLeft,
+        }
+    }
+
+    pub fn right(self) -> bool {
+        matches!(self, Corner::TopRight | Corner::BottomRight)
+    }
+
+    pub fn bottom(self) -> bool {
+        matches!(self, Corner::BottomLeft | Corner::BottomRight)
+    }
+}
+
+// Clips a rectangle at (x, y) of size w x h against a ww x hh buffer and
+// returns the visible part as (x0, y0, width, height). Parts hanging off any
+// edge are cut away; a rectangle entirely outside comes back with zero size.
+fn clamp_rect(
+    x: isize,
+    y: isize,
+    w: usize,
+    h: usize,
+    ww: usize,
+    hh: usize,
+) -> (usize, usize, usize, usize) {
+    let x1 = x.saturating_add_unsigned(w).min(ww as isize);
+    let y1 = y.saturating_add_unsigned(h).min(hh as isize);
+    let x0 = x.clamp(0, ww as isize);
+    let y0 = y.clamp(0, hh as isize);
+    let rw = (x1 - x0).max(0) as usize;
+    let rh = (y1 - y0).max(0) as usize;
+    (x0 as usize, y0 as usize, rw, rh)
+}
+
+#[allow(clippy::too_many_arguments)]
+pub fn fill_rect(
+    buf: &mut [u8],
+    stride: usize,
+    ww: usize,
+    hh: usize,
+    x: isize,
+    y: isize,
+    w: usize,
+    h: usize,
+    r: u8,
+    g: u8,
+    b: u8,
+) {
+    let (x0, y0, rw, rh) = clamp_rect(x, y, w, h, ww, hh);
+    for yy in y0..y0 + rh {
+        fill_row(&mut buf[yy * stride + x0 * 4..], rw, r, g, b);
+    }
+}
+
+// fill_rect at opacity a/255 over what is already drawn
+#[allow(clippy::too_many_arguments)]
+pub fn blend_rect(
+    buf: &mut [u8],
+    stride: usize,
+    ww: usize,
+    hh: usize,
+    x: isize,
+    y: isize,
+    w: usize,
+    h: usize,
+    r: u8,
+    g: u8,
+    b: u8,
+    a: u8,
+) {
+    if a == 255 {
+        return fill_rect(buf, stride, ww, hh, x, y, w, h, r, g, b);
+    }
+    let (x0, y0, rw, rh) = clamp_rect(x, y, w, h, ww, hh);
+    for yy in y0..y0 + rh {
+        let row = &mut buf[yy * stride + x0 * 4..yy * stride + (x0 + rw) * 4];
+        for px in row.chunks_exact_mut(4) {
+            blend(px, r, g, b, a);
+        }
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
+pub fn draw_rect_outline(
+    buf: &mut [u8],
+    stride: usize,
+    ww: usize,
+    hh: usize,
+    x: isize,
+    y: isize,
+    w: usize,
+    h: usize,
+    t: usize,
+    r: u8,
+    g: u8,
+    b: u8,
+) {
+    fill_rect(buf, stride, ww, hh, x, y, w, t, r, g, b);
+    fill_rect(
+        buf,
+        stride,
+        ww,
+        hh,
+        x,
+        y + (h as isize - t as isize),
+        w,
+        t,
+        r,
+        g,
+        b,
+    );
+    fill_rect(buf, stride, ww, hh, x, y, t, h, r, g, b);
+    fill_rect(
+        buf,
+        stride,
+        ww,
+        hh,
+        x + (w as isize - t as isize),
+        y,
+        t,
+        h,
+        r,
+        g,
+        b,
+    );
+}
+
+pub const GLYPH_W: usize = 8;
+pub const GLYPH_H: usize = 8;
+
+pub fn text_width(text: &str, scale: usize) -> usize {
+    text.chars().count() * GLYPH_W * scale
+}
+
+// Draws text with the 8x8 bitmap font, each font pixel a scale x scale block.
+// Anything outside the buffer is clipped.
+#[allow(clippy::too_many_arguments)]
+pub fn draw_text(
+    buf: &mut [u8],
+    stride: usize,
+    w: usize,
+    h: usize,
+    x: usize,
+    y: usize,
+    text: &str,
+    scale: usize,
+    r: u8,
+    g: u8,
+    b: u8,
+) {
+    blend_text(buf, stride, w, h, x, y, text, scale, r, g, b, 255);
+}
+
+// draw_text at opacity a/255 over what is already drawn
+#[allow(clippy::too_many_arguments)]
+pub fn blend_text(
+    buf: &mut [u8],
+    stride: usize,
+    w: usize,
+    h: usize,
+    x: usize,
+    y: usize,
+    text: &str,
+    scale: usize,
+    r: u8,
+    g: u8,
+    b: u8,
+    a: u8,
+) {
+    let scale = scale.max(1);
+    for (i, ch) in text.chars().enumerate() {
+        let glyph = BASIC_LEGACY
+            .get(ch as usize)
+            .unwrap_or(&BASIC_LEGACY[b'?' as usize]);
+        let gx = x + i * GLYPH_W * scale;
+        for (row, bits) in glyph.iter().enumerate() {
+            for col in 0..GLYPH_W {
+                if bits & (1 << col) == 0 {
+                    continue;
+                }
+                blend_rect(
+                    buf,
+                    stride,
+                    w,
+                    h,
+                    (gx + col * scale) as isize,
+                    (y + row * scale) as isize,
+                    scale,
+                    scale,
+                    r,
+                    g,
+                    b,
+                    a,
+                );
+            }
+        }
+    }
+}
+
+// Text on a black box so it stays readable over any pattern
+#[allow(clippy::too_many_arguments)]
+pub fn draw_label(
+    buf: &mut [u8],
+    stride: usize,
+    w: usize,
+    h: usize,
+    x: usize,
+    y: usize,
+    text: &str,
+    scale: usize,
+) {
+    draw_label_over(buf, stride, w, h, x, y, text, scale, 255);
+}
+
+// draw_label on a box that darkens what is under it by a/255 rather than
+// covering it, so the pattern still shows around the text
+#[allow(clippy::too_many_arguments)]
+pub fn draw_label_over(
+    buf: &mut [u8],
+    stride: usize,
+    w: usize,
+    h: usize,
+    x: usize,
+    y: usize,
+    text: &str,
+    scale: usize,
+    a: u8,
+) {
+    let pad = 2 * scale;
+    blend_rect(
+        buf,
+        stride,
+        w,
+        h,
+        x as isize,
+        y as isize,
+        text_width(text, scale) + 2 * pad,
+        GLYPH_H * scale + 2 * pad,
+        0,
+        0,
+        0,
+        a,
+    );
+    draw_text(
+        buf,
+        stride,
+        w,
+        h,
+        x + pad,
+        y + pad,
+        text,
+        scale,
+        255,
+        255,
+        255,
+    );
+}
+
+/// Subpixel layout of a panel: the order of the color stripes within a
+/// pixel, left to right, or top to bottom for the vertical ones
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SubpixelOrder {
+    Rgb,
+    Bgr,
+    VerticalRgb,
+    VerticalBgr,
+}
+
+impl SubpixelOrder {
+    pub const ALL: [SubpixelOrder; 4] = [
+        SubpixelOrder::Rgb,
+        SubpixelOrder::Bgr,
+        SubpixelOrder::VerticalRgb,
+        SubpixelOrder::VerticalBgr,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            SubpixelOrder::Rgb => "RGB",
+            SubpixelOrder::Bgr => "BGR",
+            SubpixelOrder::VerticalRgb => "vertical RGB",
+            SubpixelOrder::VerticalBgr => "vertical BGR",
+        }
+    }
+
+    fn vertical(self) -> bool {
+        matches!(
+            self,
+            SubpixelOrder::VerticalRgb | SubpixelOrder::VerticalBgr
+        )
+    }
+
+    fn reversed(self) -> bool {
+        matches!(self, SubpixelOrder::Bgr | SubpixelOrder::VerticalBgr)
+    }
+}
+
+// FreeType's default LCD filter, run across neighbouring subpixels so an
+// edge spreads over a whole pixel rather than leaving a colored fringe
+const LCD_FILTER: [f32; 5] = [8.0, 77.0, 86.0, 77.0, 8.0];
+
+// Samples per subpixel along each axis
+const AA_SAMPLES: usize = 4;
+
+/// Anti-aliased text: the bitmap font scaled to `size` pixels tall, so at
+/// most sizes glyph edges fall part way into pixels. With `subpixel` each
+/// color channel takes its coverage from its own stripe of the pixel, in
+/// that order; without it every channel gets the whole pixel's coverage.
+/// The text's box is filled with `bg` and clipped to the buffer.
+#[allow(clippy::too_many_arguments)]
+pub fn draw_text_aa(
+    buf: &mut [u8],
+    stride: usize,
+    w: usize,
+    h: usize,
+    x: usize,
+    y: usize,
+    text: &str,
+    size: f64,
+    fg: (u8, u8, u8),
+    bg: (u8, u8, u8),
+    subpixel: Option<SubpixelOrder>,
+) {
+    let scale = size.max(1.0) / GLYPH_H as f64;
+    let glyphs: Vec<&[u8; 8]> = text
+        .chars()
+        .map(|ch| {
+            BASIC_LEGACY
+                .get(ch as usize)
+                .unwrap_or(&BASIC_LEGACY[b'?' as usize])
+        })
+        .collect();
+    let box_w = (glyphs.len() as f64 * GLYPH_W as f64 * scale).ceil() as usize;
+    let box_h = (GLYPH_H as f64 * scale).ceil() as usize;
+    // Whether the font pixel under a point of the text box is set
+    let inside = |fx: f64, fy: f64| {
+        let (col, row) = ((fx / scale) as usize, (fy / scale) as usize);
+        let (i, col) = (col / GLYPH_W, col % GLYPH_W);
+        i < glyphs.len() && row < GLYPH_H && glyphs[i][row] & (1 << col) != 0
+    };
+
+    // Coverage of every third of a pixel along the stripe axis, one line of
+    // them per pixel row (or column, for vertical stripes)
+    let vertical = subpixel.is_some_and(SubpixelOrder::vertical);
+    let (along, across) = if vertical {
+        (box_h, box_w)
+    } else {
+        (box_w, box_h)
+    };
+    let thirds = along * 3;
+    let mut cover = vec![0f32; thirds * across];
+    for c in 0..across {
+        for s in 0..thirds {
+            let mut hits = 0;
+            for i in 0..AA_SAMPLES {
+                for j in 0..AA_SAMPLES {
+                    let a = (s as f64 + (i as f64 + 0.5) / AA_SAMPLES as f64) / 3.0;
+                    let b = c as f64 + (j as f64 + 0.5) / AA_SAMPLES as f64;
+                    let (fx, fy) = if vertical { (b, a) } else { (a, b) };
+                    hits += inside(fx, fy) as usize;
+                }
+            }
+            cover[c * thirds + s] = hits as f32 / (AA_SAMPLES * AA_SAMPLES) as f32;
+        }
+    }
+
+    let mix =
+        |f: u8, b: u8, t: f32| (b as f32 + (f as f32 - b as f32) * t.clamp(0.0, 1.0)).round() as u8;
+    for c in 0..across {
+        let line = &cover[c * thirds..(c + 1) * thirds];
+        let filtered = |s: usize| {
+            LCD_FILTER
+                .iter()
+                .enumerate()
+                .filter_map(|(k, f)| line.get((s + k).checked_sub(2)?).map(|v| v * f / 256.0))
+                .sum::<f32>()
+        };
+        for p in 0..along {
+            let [r, g, b] = match subpixel {
+                None => [(line[3 * p] + line[3 * p + 1] + line[3 * p + 2]) / 3.0; 3],
+                Some(order) => {
+                    let stripes = [filtered(3 * p), filtered(3 * p + 1), filtered(3 * p + 2)];
+                    if order.reversed() {
+                        [stripes[2], stripes[1], stripes[0]]
+                    } else {
+                        stripes
+                    }
+                }
+            };
+            let (px, py) = if vertical { (c, p) } else { (p, c) };
+            put_clipped(
+                buf,
+                stride,
+                w,
+                h,
+                (x + px) as isize,
+                (y + py) as isize,
+                xrgb(mix(fg.0, bg.0, r), mix(fg.1, bg.1, g), mix(fg.2, bg.2, b)),
+            );
+        }
+    }
+}
+
+// Writes one pixel if it lies inside the buffer, for primitives whose shapes
+// may extend past the edges
+fn put_clipped(buf: &mut [u8], stride: usize, w: usize, h: usize, x: isize, y: isize, c: [u8; 4]) {
+    if x >= 0 && y >= 0 && (x as usize) < w && (y as usize) < h {
+        let offset = y as usize * stride + x as usize * 4;
+        buf[offset..offset + 4].copy_from_slice(&c);
+    }
+}
+
+// Blends color into the existing pixel by coverage (0.0..=1.0), clipped
+#[allow(clippy::too_many_arguments)]
+fn blend_clipped(
+    buf: &mut [u8],
+    stride: usize,
+    w: usize,
+    h: usize,
+    x: isize,
+    y: isize,
+    color: (u8, u8, u8),
+    cover: f64,
+) {
+    if x < 0 || y < 0 || x as usize >= w || y as usize >= h {
+        return;
+    }
+    let alpha = (cover.clamp(0.0, 1.0) * 255.0).round() as u8;
+    let (r, g, b) = color;
+    put_argb(buf, stride, x as usize, y as usize, r, g, b, alpha);
+}
+
+// Runs f(major, minor) for every step of the line from (a0, b0) to (a1, b1)
+// along its major axis, restricted to major positions in [lo, hi]. The minor
+// coordinate is the exact rounded midpoint value rather than an incremental
+// error term, so the clipped part of a line is skipped instead of walked, and
+// endpoints are ordered first so A->B and B->A pick the same pixels.
+fn walk_line(
+    (a0, b0): (isize, isize),
+    (a1, b1): (isize, isize),
+    lo: isize,
+    hi: isize,
+    mut f: impl FnMut(isize, isize),
+) {
+    let ((a0, b0), (a1, b1)) = if (a0, b0) <= (a1, b1) {
+        ((a0, b0), (a1, b1))
+    } else {
+        ((a1, b1), (a0, b0))
+    };
+    let da = (a1 - a0) as i64;
+    let db = (b1 - b0) as i64;
+
+    for a in a0.max(lo)..=a1.min(hi) {
+        let b = if da == 0 {
+            b0
+        } else {
+            // round((a - a0) * db / da), ties toward +inf
+            let n = 2 * (a - a0) as i64 * db + da;
+            b0 + n.div_euclid(2 * da) as isize
+        };
+        f(a, b);
+    }
+}
+
+/// A straight line between two pixel centers, inclusive of both endpoints.
+/// Thickness extends perpendicular to the major axis, centered on the ideal
+/// line: odd thicknesses are exactly symmetric, even ones have the extra pixel
+/// on the negative side. Endpoints may lie anywhere, including off-screen.
+#[allow(clippy::too_many_arguments)]
+pub fn draw_line(
+    buf: &mut [u8],
+    stride: usize,
+    w: usize,
+    h: usize,
+    x0: isize,
+    y0: isize,
+    x1: isize,
+    y1: isize,
+    thickness: usize,
+    r: u8,
+    g: u8,
+    b: u8,
+) {
+    let t = thickness.max(1);
+    let before = (t / 2) as isize;
+
+    if (x1 - x0).abs() >= (y1 - y0).abs() {
+        walk_line((x0, y0), (x1, y1), 0, w as isize - 1, |x, y| {
+            fill_rect(buf, stride, w, h, x, y - before, 1, t, r, g, b);
+        });
+    } else {
+        walk_line((y0, x0), (y1, x1), 0, h as isize - 1, |y, x| {
+            fill_rect(buf, stride, w, h, x - before, y, t, 1, r, g, b);
+        });
+    }
+}
+
+/// Xiaolin Wu style anti-aliased line between sub-pixel positions. Each step
+/// along the major axis covers [center - thickness/2, center + thickness/2]
+/// across it; fully covered pixels get the color and the two edge pixels are
+/// blended into what is already there by their coverage.
+#[allow(clippy::too_many_arguments)]
+pub fn draw_line_aa(
+    buf: &mut [u8],
+    stride: usize,
+    w: usize,
+    h: usize,
+    x0: f64,
+    y0: f64,
+    x1: f64,
+    y1: f64,
+    thickness: f64,
+    r: u8,
+    g: u8,
+    b: u8,
+) {
+    let steep = (y1 - y0).abs() > (x1 - x0).abs();
+    let (a0, b0, a1, b1) = if steep {
+        (y0, x0, y1, x1)
+    } else {
+        (x0, y0, x1, y1)
+    };
+    let (a0, b0, a1, b1) = if a0 <= a1 {
+        (a0, b0, a1, b1)
+    } else {
+        (a1, b1, a0, b0)
+    };
+    let (major_len, minor_len) = if steep { (h, w) } else { (w, h) };
+    if major_len == 0 || minor_len == 0 {
+        return;
+    }
+
+    let slope = if a1 > a0 { (b1 - b0) / (a1 - a0) } else { 0.0 };
+    // Thickness is measured perpendicular to the line, so the span across the
+    // major axis grows with the slope
+    let half = thickness.max(0.0) * (1.0 + slope * slope).sqrt() / 2.0;
+
+    let first = a0.round().max(0.0) as isize;
+    let last = (a1.round() as isize).min(major_len as isize - 1);
+    for a in first..=last {
+        let center = b0 + slope * (a as f64 - a0);
+        let (lo, hi) = (center - half + 0.5, center + half + 0.5);
+        for m in lo.floor() as isize..hi.ceil() as isize {
+            let cover = (hi.min(m as f64 + 1.0) - lo.max(m as f64)).clamp(0.0, 1.0);
+            let (x, y) = if steep { (m, a) } else { (a, m) };
+            blend_clipped(buf, stride, w, h, x, y, (r, g, b), cover);
+        }
+    }
+}
+
+// Largest k >= 0 with 4 * (k^2 + dy^2) < limit4, i.e. the half-width of a
+// disc of diameter sqrt(limit4) on row dy, or None if the row misses it
+fn disc_half_width(limit4: i64, dy: i64) -> Option<i64> {
+    let rest = limit4 - 4 * dy * dy;
+    if rest <= 0 {
+        return None;
+    }
+    let mut k = ((rest as f64 / 4.0).sqrt() as i64).max(0);
+    while k > 0 && 4 * k * k >= rest {
+        k -= 1;
+    }
+    while 4 * (k + 1) * (k + 1) < rest {
+        k += 1;
+    }
+    Some(k)
+}
+
+// Fills every pixel whose center lies strictly inside the disc of diameter
+// `outer` around (cx, cy) and not strictly inside the one of diameter `inner`.
+// Working in diameters keeps radius +- thickness/2 integral for odd thickness.
+#[allow(clippy::too_many_arguments)]
+fn fill_annulus(
+    buf: &mut [u8],
+    stride: usize,
+    w: usize,
+    h: usize,
+    cx: isize,
+    cy: isize,
+    outer: i64,
+    inner: i64,
+    c: [u8; 4],
+) {
+    let reach = outer / 2 + 1;
+    let rows = (cy as i64 - reach).max(0)..(cy as i64 + reach + 1).min(h as i64);
+    for y in rows {
+        let dy = y - cy as i64;
+        let Some(out) = disc_half_width(outer * outer, dy) else {
+            continue;
+        };
+        let hole = if inner > 0 {
+            disc_half_width(inner * inner, dy)
+        } else {
+            None
+        };
+
+        let mut span = |from: i64, to: i64| {
+            let x0 = (cx as i64 + from).max(0);
+            let x1 = (cx as i64 + to).min(w as i64 - 1);
+            for x in x0..=x1 {
+                put_clipped(buf, stride, w, h, x as isize, y as isize, c);
+            }
+        };
+        match hole {
+            Some(k) => {
+                span(-out, -k - 1);
+                span(k + 1, out);
+            }
+            None => span(-out, out),
+        }
+    }
+}
+
+/// A filled circle of the given radius around a pixel center
+#[allow(clippy::too_many_arguments)]
+pub fn draw_circle(
+    buf: &mut [u8],
+    stride: usize,
+    w: usize,
+    h: usize,
+    cx: isize,
+    cy: isize,
+    radius: usize,
+    r: u8,
+    g: u8,
+    b: u8,
+) {
+    let outer = 2 * radius as i64 + 1;
+    fill_annulus(buf, stride, w, h, cx, cy, outer, 0, xrgb(r, g, b));
+}
+
+/// A circle outline centered on the ideal radius: pixels whose centers lie
+/// within thickness/2 of it on either side
+#[allow(clippy::too_many_arguments)]
+pub fn draw_circle_outline(
+    buf: &mut [u8],
+    stride: usize,
+    w: usize,
+    h: usize,
+    cx: isize,
+    cy: isize,
+    radius: usize,
+    thickness: usize,
+    r: u8,
+    g: u8,
+    b: u8,
+) {
+    let t = thickness.max(1) as i64;
+    let outer = 2 * radius as i64 + t;
+    let inner = (2 * radius as i64 - t).max(0);
+    fill_annulus(buf, stride, w, h, cx, cy, outer, inner, xrgb(r, g, b));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A black w x h buffer with rows 4 * w bytes apart
+    fn black(w: usize, h: usize) -> Vec<u8> {
+        let mut buf = vec![0; w * h * 4];
+        fill_rgb(&mut buf, w * 4, w, h, 0, 0, 0);
+        buf
+    }
+
+    // The (x, y, w, h) window of a buffer drawn `pad` pixels bigger on every
+    // side, as a buffer of its own
+    fn crop(big: &[u8], big_w: usize, pad: usize, w: usize, h: usize) -> Vec<u8> {
+        let mut out = Vec::with_capacity(w * h * 4);
+        for y in pad..pad + h {
+            out.extend_from_slice(&big[(y * big_w + pad) * 4..(y * big_w + pad + w) * 4]);
+        }
+        out
+    }
+
+    fn lit(buf: &[u8], w: usize, x: usize, y: usize) -> bool {
+        buf[(y * w + x) * 4..(y * w + x) * 4 + 3] != [0, 0, 0]
+    }
+
+    #[test]
+    fn clamp_rect_clips_each_way() {
+        // On a 100 x 50 buffer
+        let cases = [
+            // Fully inside
+            ((10, 5, 20, 10), (10, 5, 20, 10)),
+            // Exactly the buffer
+            ((0, 0, 100, 50), (0, 0, 100, 50)),
+            // Fully left, fully above, fully left and above
+            ((-30, 5, 20, 10), (0, 5, 0, 10)),
+            ((10, -20, 20, 10), (10, 0, 20, 0)),
+            ((-30, -20, 20, 10), (0, 0, 0, 0)),
+            // Touching the left edge from outside
+            ((-20, 5, 20, 10), (0, 5, 0, 10)),
+            // Partly off the left, top, right and bottom
+            ((-5, 5, 20, 10), (0, 5, 15, 10)),
+            ((10, -4, 20, 10), (10, 0, 20, 6)),
+            ((90, 5, 20, 10), (90, 5, 10, 10)),
+            ((10, 45, 20, 10), (10, 45, 20, 5)),
+            // Partly off every side at once
+            ((-5, -5, 200, 100), (0, 0, 100, 50)),
+            // Fully right, fully below
+            ((100, 5, 20, 10), (100, 5, 0, 10)),
+            ((10, 60, 20, 10), (10, 50, 20, 0)),
+            ((500, 500, 20, 10), (100, 50, 0, 0)),
+            // Zero size, inside and outside
+            ((10, 5, 0, 0), (10, 5, 0, 0)),
+            ((10, 5, 0, 10), (10, 5, 0, 10)),
+            ((-10, -10, 0, 0), (0, 0, 0, 0)),
+            // Far enough out that the far edge would overflow
+            ((isize::MAX - 5, 5, 20, 10), (100, 5, 0, 10)),
+            ((isize::MIN, 5, usize::MAX, 10), (0, 5, 100, 10)),
+        ];
+        for ((x, y, w, h), want) in cases {
+            let got = clamp_rect(x, y, w, h, 100, 50);
+            assert_eq!(got, want, "rect {},{} {}x{}", x, y, w, h);
+            // Whatever comes back lies within the buffer
+            assert!(got.0 + got.2 <= 100 && got.1 + got.3 <= 50);
+        }
+    }
+
+    #[test]
+    fn clamp_rect_on_empty_buffer() {
+        assert_eq!(clamp_rect(0, 0, 10, 10, 0, 0), (0, 0, 0, 0));
+        assert_eq!(clamp_rect(-3, 4, 10, 10, 0, 0), (0, 0, 0, 0));
+    }
+
+    #[test]
+    fn mix_rgb_endpoints_and_midpoint() {
+        let (a, b) = ((10, 200, 0), (250, 0, 255));
+        assert_eq!(mix_rgb(a, b, 0), a);
+        assert_eq!(mix_rgb(a, b, 255), b);
+        // 128/255 of the way, rounded to nearest
+        assert_eq!(mix_rgb(a, b, 128), (130, 100, 128));
+        assert_eq!(mix_rgb((0, 0, 0), (255, 255, 255), 128), (128, 128, 128));
+        assert_eq!(mix_rgb((0, 0, 0), (255, 255, 255), 127), (127, 127, 127));
+    }
+
+    #[test]
+    fn mix_rgb_steps_evenly_and_reverses() {
+        for alpha in 0..=255u8 {
+            let up = mix_rgb((0, 0, 0), (255, 255, 255), alpha);
+            assert_eq!(up, (alpha, alpha, alpha));
+            let (x, y) = ((30, 60, 90), (200, 10, 90));
+            let there = mix_rgb(x, y, alpha);
+            let back = mix_rgb(y, x, 255 - alpha);
+            for (p, q) in [(there.0, back.0), (there.1, back.1), (there.2, back.2)] {
+                assert!(
+                    p.abs_diff(q) <= 1,
+                    "alpha {}: {:?} vs {:?}",
+                    alpha,
+                    there,
+                    back
+                );
+            }
+            // Equal colors mix to themselves
+            assert_eq!(mix_rgb(x, x, alpha), x);
+        }
+    }
+
+    const LINES: [(isize, isize, isize, isize); 8] = [
+        (2, 3, 40, 17),
+        (5, 5, 5, 30),
+        (0, 20, 45, 20),
+        (10, 1, 13, 31),
+        (44, 0, 0, 31),
+        (7, 7, 7, 7),
+        (3, 28, 40, 2),
+        (20, 3, 21, 29),
+    ];
+
+    #[test]
+    fn line_includes_both_endpoints() {
+        let (w, h) = (48, 32);
+        for (x0, y0, x1, y1) in LINES {
+            for t in [1, 2, 3] {
+                let mut buf = black(w, h);
+                draw_line(&mut buf, w * 4, w, h, x0, y0, x1, y1, t, 255, 255, 255);
+                assert!(lit(&buf, w, x0 as usize, y0 as usize));
+                assert!(lit(&buf, w, x1 as usize, y1 as usize));
+            }
+        }
+    }
+
+    #[test]
+    fn line_is_the_same_both_ways() {
+        let (w, h) = (48, 32);
+        for (x0, y0, x1, y1) in LINES {
+            for t in [1, 2, 3, 4] {
+                let mut there = black(w, h);
+                let mut back = black(w, h);
+                draw_line(&mut there, w * 4, w, h, x0, y0, x1, y1, t, 255, 255, 255);
+                draw_line(&mut back, w * 4, w, h, x1, y1, x0, y0, t, 255, 255, 255);
+                assert!(there == back, "{:?} thickness {}", (x0, y0, x1, y1), t);
+            }
+            let (fx0, fy0, fx1, fy1) = (x0 as f64 + 0.3, y0 as f64, x1 as f64, y1 as f64 - 0.4);
+            let mut there = black(w, h);
+            let mut back = black(w, h);
+            draw_line_aa(
+                &mut there,
+                w * 4,
+                w,
+                h,
+                fx0,
+                fy0,
+                fx1,
+                fy1,
+                1.5,
+                255,
+                255,
+                255,
+            );
+            draw_line_aa(
+                &mut back,
+                w * 4,
+                w,
+                h,
+                fx1,
+                fy1,
+                fx0,
+                fy0,
+                1.5,
+                255,
+                255,
+                255,
+            );
+            assert!(there == back, "aa {:?}", (x0, y0, x1, y1));
+        }
+    }
+
+    #[test]
+    fn line_off_screen_is_clipped_not_shifted() {
+        // A line running off the buffer draws the same pixels as the part of
+        // it inside a bigger buffer
+        let (w, h, pad) = (40, 24, 30);
+        let (bw, bh) = (w + 2 * pad, h + 2 * pad);
+        let off = pad as isize;
+        for (x0, y0, x1, y1) in [
+            (-20, -10, 50, 30),
+            (-25, 12, 60, 12),
+            (10, -29, 30, 50),
+            (45, 5, -15, 20),
+            (-20, -20, -5, -3),
+            (60, 0, 70, 23),
+        ] {
+            for t in [1, 3] {
+                let mut big = black(bw, bh);
+                draw_line(
+                    &mut big,
+                    bw * 4,
+                    bw,
+                    bh,
+                    x0 + off,
+                    y0 + off,
+                    x1 + off,
+                    y1 + off,
+                    t,
+                    255,
+                    255,
+                    255,
+                );
+                let mut small = black(w, h);
+                draw_line(&mut small, w * 4, w, h, x0, y0, x1, y1, t, 255, 255, 255);
+                assert!(small == crop(&big, bw, pad, w, h), "{:?}", (x0, y0, x1, y1));
+            }
+
+            let mut big = black(bw, bh);
+            let p = off as f64;
+            let (fx0, fy0, fx1, fy1) = (x0 as f64, y0 as f64, x1 as f64, y1 as f64);
+            draw_line_aa(
+                &mut big,
+                bw * 4,
+                bw,
+                bh,
+                fx0 + p,
+                fy0 + p,
+                fx1 + p,
+                fy1 + p,
+                2.0,
+                255,
+                255,
+                255,
+            );
+            let mut small = black(w, h);
+            draw_line_aa(
+                &mut small,
+                w * 4,
+                w,
+                h,
+                fx0,
+                fy0,
+                fx1,
+                fy1,
+                2.0,
+                255,
+                255,
+                255,
+            );
+            assert!(
+                small == crop(&big, bw, pad, w, h),
+                "aa {:?}",
+                (x0, y0, x1, y1)
+            );
+        }
+    }
+
+    #[test]
+    fn circle_is_symmetric_and_clips() {
+        let (w, h) = (41, 41);
+        for radius in [0, 1, 2, 5, 12, 20] {
+            let mut filled = black(w, h);
+            draw_circle(&mut filled, w * 4, w, h, 20, 20, radius, 255, 255, 255);
+            let mut ring = black(w, h);
+            draw_circle_outline(&mut ring, w * 4, w, h, 20, 20, radius, 3, 255, 255, 255);
+            for buf in [&filled, &ring] {
+                for y in 0..h {
+                    for x in 0..w {
+                        let on = lit(buf, w, x, y);
+                        assert_eq!(on, lit(buf, w, w - 1 - x, y), "r {} at {},{}", radius, x, y);
+                        assert_eq!(on, lit(buf, w, x, h - 1 - y), "r {} at {},{}", radius, x, y);
+                        assert_eq!(on, lit(buf, w, y, x), "r {} at {},{}", radius, x, y);
+                    }
+                }
+            }
+            // The center and the four extremes of a filled circle
+            assert!(lit(&filled, w, 20, 20));
+            assert!(lit(&filled, w, 20 + radius, 20) && lit(&filled, w, 20, 20 - radius));
+            if 20 + radius + 1 < w {
+                assert!(!lit(&filled, w, 20 + radius + 1, 20));
+            }
+        }
+
+        // Off the corner, a circle draws the part that shows
+        let (sw, sh, pad) = (20, 20, 20);
+        let mut small = black(sw, sh);
+        draw_circle(&mut small, sw * 4, sw, sh, -3, 17, 9, 255, 255, 255);
+        let mut big = black(sw + 2 * pad, sh + 2 * pad);
+        draw_circle(
+            &mut big,
+            (sw + 2 * pad) * 4,
+            sw + 2 * pad,
+            sh + 2 * pad,
+            -3 + pad as isize,
+            17 + pad as isize,
+            9,
+            255,
+            255,
+            255,
+        );
+        assert!(small == crop(&big, sw + 2 * pad, pad, sw, sh));
+    }
+
+    #[test]
+    fn put_rgb_drops_pixels_off_the_row_or_buffer() {
+        // 3 x 3 pixels, tightly packed, so the pixel after a row's last is
+        // the next row's first
+        let (w, h) = (3, 3);
+        let mut buf = black(w, h);
+        let before = buf.clone();
+        for (x, y) in [
+            (3, 0),
+            (3, 1),
+            (100, 0),
+            (0, 3),
+            (2, 3),
+            (0, usize::MAX / 64),
+        ] {
+            put_rgb(&mut buf, w * 4, x, y, 255, 255, 255);
+            put_argb(&mut buf, w * 4, x, y, 255, 255, 255, 128);
+        }
+        assert!(buf == before);
+
+        put_rgb(&mut buf, w * 4, 2, 2, 255, 0, 0);
+        assert_eq!(&buf[buf.len() - 4..], &xrgb(255, 0, 0));
+    }
+
+    #[test]
+    fn shapes_off_a_tiny_buffer_keep_to_their_rows() {
+        // Rows padded with a marker, which no shape may overwrite
+        const PAD: u8 = 0x5a;
+        for (w, h) in [(1, 1), (2, 3), (5, 2)] {
+            let stride = w * 4 + 8;
+            let mut buf = vec![PAD; stride * h];
+            fill_rect(&mut buf, stride, w, h, -4, -4, 20, 20, 255, 255, 255);
+            blend_rect(&mut buf, stride, w, h, -4, 1, 20, 20, 0, 255, 0, 100);
+            draw_rect_outline(&mut buf, stride, w, h, -1, -1, w + 2, h + 2, 3, 255, 0, 0);
+            draw_line(&mut buf, stride, w, h, -10, -7, 30, 12, 5, 0, 0, 255);
+            draw_line_aa(
+                &mut buf, stride, w, h, -10.0, 20.0, 30.5, -3.0, 3.0, 0, 0, 255,
+            );
+            draw_circle(&mut buf, stride, w, h, 2, 1, 10, 9, 9, 9);
+            draw_circle_outline(&mut buf, stride, w, h, -5, -5, 6, 4, 9, 9, 9);
+            draw_text(&mut buf, stride, w, h, 0, 0, "WIDE TEXT", 3, 1, 2, 3);
+            for y in 0..h {
+                assert!(
+                    buf[y * stride + w * 4..(y + 1) * stride]
+                        .iter()
+                        .all(|&b| b == PAD),
+                    "{}x{} row {}",
+                    w,
+                    h,
+                    y
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn blend_composites_over_and_stays_opaque() {
+        let mut px = xrgb(0, 100, 200);
+        blend(&mut px, 255, 255, 255, 0);
+        assert_eq!(px, xrgb(0, 100, 200));
+        blend(&mut px, 255, 0, 0, 255);
+        assert_eq!(px, xrgb(255, 0, 0));
+
+        let mut px = xrgb(0, 0, 0);
+        blend(&mut px, 255, 255, 255, 128);
+        assert_eq!(px, xrgb(128, 128, 128));
+        // Again at the same opacity covers half of what is left
+        blend(&mut px, 255, 255, 255, 128);
+        assert_eq!(px, xrgb(192, 192, 192));
+        assert_eq!(px[3], 0xff);
+    }
+
+    #[test]
+    fn blend_rect_matches_put_argb() {
+        let (w, h) = (6, 4);
+        let mut by_rect = black(w, h);
+        fill_rect(&mut by_rect, w * 4, w, h, 0, 0, 3, h, 40, 80, 160);
+        let mut by_pixel = by_rect.clone();
+        blend_rect(&mut by_rect, w * 4, w, h, 1, 1, 10, 2, 250, 10, 60, 77);
+        for y in 1..3 {
+            for x in 1..w {
+                put_argb(&mut by_pixel, w * 4, x, y, 250, 10, 60, 77);
+            }
+        }
+        assert!(by_rect == by_pixel);
+    }
+}
@@ -0,0 +1,70 @@
+use drm::control::{Device as CtrlDevice, connector};
+
+use crate::surface::Card;
+
+const HEADER: [u8; 8] = [0x00, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0x00];
+
+/// CIE 1931 xy of the D65 white point, used when the EDID has none
+pub const D65: (f64, f64) = (0.3127, 0.3290);
+
+/// Reads the raw EDID blob from the connector's EDID property, if the driver
+/// exposes one
+pub fn read_edid(card: &Card, con: connector::Handle) -> Option<Vec<u8>> {
+    let props = card.get_properties(con).ok()?;
+    let (handles, values) = props.as_props_and_values();
+    handles.iter().zip(values).find_map(|(&prop, &value)| {
+        let info = card.get_property(prop).ok()?;
+        if info.name().to_bytes() != b"EDID" || value == 0 {
+            return None;
+        }
+        card.get_property_blob(value).ok()
+    })
+}
+
+/// Image size in mm from the first detailed timing descriptor (bytes
+/// 66..=68, 12 bits each), or failing that the base block's screen size in
+/// cm (bytes 21 and 22). None if neither is filled in.
+pub fn physical_size_mm(edid: &[u8]) -> Option<(u32, u32)> {
+    if edid.len() < 128 || edid[..8] != HEADER {
+        return None;
+    }
+
+    // A zero pixel clock marks a display descriptor rather than a timing
+    let dtd = (edid[54] != 0 || edid[55] != 0).then(|| {
+        let w = edid[66] as u32 | (edid[68] as u32 >> 4) << 8;
+        let h = edid[67] as u32 | (edid[68] as u32 & 0xf) << 8;
+        (w, h)
+    });
+    let base = (edid[21] as u32 * 10, edid[22] as u32 * 10);
+    [dtd, Some(base)]
+        .into_iter()
+        .flatten()
+        .find(|&(w, h)| w > 0 && h > 0)
+}
+
+/// Active size in pixels of the first detailed timing descriptor, which
+/// the EDID standard reserves for the display's native mode: 12 bits each,
+/// the low byte at 56 (h) and 59 (v) and the high nibble at 58 and 61
+pub fn native_size(edid: &[u8]) -> Option<(u32, u32)> {
+    if edid.len() < 128 || edid[..8] != HEADER || (edid[54] == 0 && edid[55] == 0) {
+        return None;
+    }
+    let w = edid[56] as u32 | (edid[58] as u32 >> 4) << 8;
+    let h = edid[59] as u32 | (edid[61] as u32 >> 4) << 8;
+    (w > 0 && h > 0).then_some((w, h))
+}
+
+/// The white point from the base block's color characteristics (bytes
+/// 25..=34): 10-bit binary fractions split into a high byte and two low bits.
+/// None if the block is malformed or the white point was left zero.
+pub fn white_point(edid: &[u8]) -> Option<(f64, f64)> {
+    if edid.len() < 128 || edid[..8] != HEADER {
+        return None;
+    }
+
+    let low = edid[26];
+    let x = ((edid[33] as u16) << 2 | ((low >> 2) & 0b11) as u16) as f64 / 1024.0;
+    let y = ((edid[34] as u16) << 2 | (low & 0b11) as u16) as f64 / 1024.0;
+
+    (x > 0.0 && y > 0.0).then_some((x, y))
+}
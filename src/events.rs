@@ -0,0 +1,282 @@
+//! `--event-log`: an append-only JSON-lines stream of what the tool did and
+//! when, for lining up with external measurement equipment. Every line has
+//! `mono_ns` (CLOCK_MONOTONIC, the clock DRM vblank timestamps use) and
+//! `wall_unix_ns`, plus an `event` name and that event's fields.
+
+use anyhow::{Context, Result, anyhow};
+use nix::time::{ClockId, clock_gettime};
+use serde::Serialize;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{Receiver, Sender, channel};
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use crate::PatternKind;
+
+// Flip events are capped at this rate so a long run doesn't produce one
+// line per refresh
+const FLIP_EVENT_INTERVAL: Duration = Duration::from_millis(100);
+// Likewise for frame checksums, which are logged whenever they are on
+const CHECKSUM_EVENT_INTERVAL: Duration = Duration::from_secs(1);
+
+#[derive(Debug, Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum Event {
+    StepChanged {
+        index: usize,
+        /// The step's name in the script, if it has one
+        #[serde(skip_serializing_if = "Option::is_none")]
+        name: Option<String>,
+        pattern: PatternKind,
+        /// The step's parameters as defined in the script
+        params: serde_json::Value,
+    },
+    FlipCompleted {
+        seq: u32,
+        /// Vblank time from the kernel, on CLOCK_MONOTONIC
+        vblank_ns: u64,
+        latency_us: u64,
+        missed_vblanks: u64,
+    },
+    /// A presented frame's --frame-checksum, in hex, and how many frames
+    /// before it in a row had the same one
+    FrameChecksum {
+        seq: u32,
+        checksum: String,
+        identical_frames: u64,
+    },
+    /// An animating pattern presented too many identical frames in a row
+    FrameFrozen {
+        identical_frames: u64,
+        checksum: String,
+    },
+    /// A frame of an animation reached the screen late: its vblank and the
+    /// interval since the frame before, against the frame period
+    FrameLate {
+        seq: u32,
+        step: usize,
+        interval_us: u64,
+        period_us: u64,
+    },
+    /// --inject-drops left this frame unpresented
+    FrameDropInjected {
+        frame: u64,
+    },
+    /// A --sync-outputs pass completed on every output flipped for it, with
+    /// each output's vblank (null where it sat the pass out)
+    OutputsPresented {
+        pass: u64,
+        vblank_ns: Vec<Option<u64>>,
+    },
+    /// An AV sync flash reached the screen
+    AvMarker {
+        flip: u64,
+        vblank_ns: u64,
+    },
+    /// An AV sync click went to the PCM (--av-audio), with the vblank it
+    /// marks, when it was meant to play, and when it was written and plays
+    AvClick {
+        flash_ns: u64,
+        target_ns: u64,
+        written_ns: u64,
+        play_ns: u64,
+    },
+    /// A --leak-watch sample
+    ResourceUsage {
+        rss_kb: u64,
+        framebuffers: usize,
+    },
+    /// RSS grew by more than --leak-warn without shrinking in between
+    LeakSuspected {
+        growth_kb: u64,
+        over_s: u64,
+    },
+    ParameterChanged {
+        description: String,
+    },
+    /// --backlight set a new level: in percent, and the raw value written
+    /// with the device's maximum
+    BacklightChanged {
+        percent: u32,
+        raw: u32,
+        max: u32,
+    },
+    VerdictRecorded {
+        name: String,
+        pass: bool,
+        detail: String,
+    },
+    InputReceived {
+        key: String,
+    },
+    /// A connector link property changed, e.g. link-status to Bad
+    LinkChanged {
+        property: String,
+        from: Option<String>,
+        to: Option<String>,
+    },
+    Error {
+        message: String,
+    },
+}
+
+impl Event {
+    // Events worth getting to disk straight away, so a crash right after
+    // still leaves the context in the file
+    fn flushes(&self) -> bool {
+        matches!(
+            self,
+            Event::StepChanged { .. }
+                | Event::FrameFrozen { .. }
+                | Event::LeakSuspected { .. }
+                | Event::LinkChanged { .. }
+                | Event::Error { .. }
+        )
+    }
+}
+
+#[derive(Serialize)]
+struct Line<'a> {
+    mono_ns: u64,
+    wall_unix_ns: u64,
+    #[serde(flatten)]
+    event: &'a Event,
+}
+
+/// Sends events to a writer thread, so logging never waits on the disk
+pub struct EventLog {
+    path: PathBuf,
+    tx: Option<Sender<(u64, u64, Event)>>,
+    writer: Option<JoinHandle<Result<()>>>,
+    flips: bool,
+    last_flip: Option<Instant>,
+    last_checksum: Option<Instant>,
+    last_sync: Option<Instant>,
+}
+
+pub fn monotonic_ns() -> u64 {
+    clock_gettime(ClockId::CLOCK_MONOTONIC).map_or(0, |ts| {
+        ts.tv_sec() as u64 * 1_000_000_000 + ts.tv_nsec() as u64
+    })
+}
+
+impl EventLog {
+    /// Opens `path` for appending. Flip events are only logged with `flips`.
+    pub fn open(path: &Path, flips: bool) -> Result<Self> {
+        let file = File::options()
+            .create(true)
+            .append(true)
+            .open(path)
+            .with_context(|| format!("could not open event log {}", path.display()))?;
+        let (tx, rx) = channel();
+        let writer = std::thread::spawn(move || write_events(rx, BufWriter::new(file)));
+
+        Ok(Self {
+            path: path.to_path_buf(),
+            tx: Some(tx),
+            writer: Some(writer),
+            flips,
+            last_flip: None,
+            last_checksum: None,
+            last_sync: None,
+        })
+    }
+
+    /// Timestamps the event now and queues it
+    pub fn log(&mut self, event: Event) {
+        let wall = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |d| d.as_nanos() as u64);
+        if let Some(tx) = &self.tx
+            && tx.send((monotonic_ns(), wall, event)).is_err()
+        {
+            // The writer failed; finish() reports why
+            self.tx = None;
+        }
+    }
+
+    /// Logs a completed flip, if flip events are on and the rate allows
+    pub fn flip(&mut self, seq: u32, vblank: Duration, latency: Duration, missed: u64) {
+        let now = Instant::now();
+        if !self.flips
+            || self
+                .last_flip
+                .is_some_and(|last| now.duration_since(last) < FLIP_EVENT_INTERVAL)
+        {
+            return;
+        }
+        self.last_flip = Some(now);
+        self.log(Event::FlipCompleted {
+            seq,
+            vblank_ns: vblank.as_nanos() as u64,
+            latency_us: latency.as_micros() as u64,
+            missed_vblanks: missed,
+        });
+    }
+
+    /// Logs a --sync-outputs pass, if flip events are on and the rate allows
+    pub fn outputs_presented(&mut self, pass: u64, vblanks: &[Option<Duration>]) {
+        let now = Instant::now();
+        if !self.flips
+            || self
+                .last_sync
+                .is_some_and(|last| now.duration_since(last) < FLIP_EVENT_INTERVAL)
+        {
+            return;
+        }
+        self.last_sync = Some(now);
+        self.log(Event::OutputsPresented {
+            pass,
+            vblank_ns: vblanks
+                .iter()
+                .map(|v| v.map(|v| v.as_nanos() as u64))
+                .collect(),
+        });
+    }
+
+    /// Logs a presented frame's checksum, if the rate allows
+    pub fn checksum(&mut self, seq: u32, checksum: u64, identical: u64) {
+        let now = Instant::now();
+        if self
+            .last_checksum
+            .is_some_and(|last| now.duration_since(last) < CHECKSUM_EVENT_INTERVAL)
+        {
+            return;
+        }
+        self.last_checksum = Some(now);
+        self.log(Event::FrameChecksum {
+            seq,
+            checksum: format!("{:016x}", checksum),
+            identical_frames: identical,
+        });
+    }
+
+    /// Waits for every queued event to be written
+    pub fn finish(mut self) -> Result<()> {
+        self.tx = None;
+        self.writer
+            .take()
+            .map_or(Ok(Ok(())), |w| w.join())
+            .map_err(|_| anyhow!("event log thread panicked"))?
+            .with_context(|| format!("writing event log {} failed", self.path.display()))
+    }
+}
+
+fn write_events(rx: Receiver<(u64, u64, Event)>, mut out: BufWriter<File>) -> Result<()> {
+    for (mono_ns, wall_unix_ns, event) in rx {
+        let line = Line {
+            mono_ns,
+            wall_unix_ns,
+            event: &event,
+        };
+        serde_json::to_writer(&mut out, &line)?;
+        out.write_all(b"\n")?;
+        if event.flushes() {
+            out.flush()?;
+        }
+    }
+    out.flush()?;
+    Ok(())
+}
@@ -0,0 +1,129 @@
+//! The exit status contract for scripts wrapping the tool. Failures that
+//! automation needs to tell apart are tagged by attaching an `Exit` as
+//! context to the error; anything untagged exits with 1.
+
+use std::fmt;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Exit {
+    /// Invalid command line
+    Usage,
+    /// The DRM device could not be opened
+    DeviceOpen,
+    /// No connected display, or not the one asked for
+    NoDisplay,
+    /// No keyboard to read input from
+    InputUnavailable,
+    /// --max-runtime ran out
+    Timeout,
+    /// --verify found a frame that differs from what was drawn
+    VerifyMismatch,
+    /// A check that was asked for never ran
+    VerdictsMissing,
+    /// The display device went away, or stopped working, partway through
+    DeviceLost,
+}
+
+/// Status for errors without an `Exit` tag
+pub const UNEXPECTED: u8 = 1;
+
+impl Exit {
+    pub fn code(self) -> u8 {
+        match self {
+            Exit::Usage => 2,
+            Exit::DeviceOpen => 3,
+            Exit::NoDisplay => 4,
+            Exit::InputUnavailable => 5,
+            Exit::Timeout => 6,
+            Exit::VerifyMismatch => 7,
+            Exit::VerdictsMissing => 8,
+            Exit::DeviceLost => 9,
+        }
+    }
+}
+
+impl fmt::Display for Exit {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Exit::Usage => "invalid command line",
+            Exit::DeviceOpen => "display device unavailable",
+            Exit::NoDisplay => "no display to drive",
+            Exit::InputUnavailable => "no input device",
+            Exit::Timeout => "maximum run time reached",
+            Exit::VerifyMismatch => "scanout verification failed",
+            Exit::VerdictsMissing => "a requested check never ran",
+            Exit::DeviceLost => "display device lost",
+        })
+    }
+}
+
+impl std::error::Error for Exit {}
+
+/// The exit status for an error: that of the outermost `Exit` tag on it
+pub fn code(err: &anyhow::Error) -> u8 {
+    err.downcast_ref::<Exit>()
+        .map_or(UNEXPECTED, |exit| exit.code())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::{Context, Result, anyhow};
+
+    const ALL: [Exit; 8] = [
+        Exit::Usage,
+        Exit::DeviceOpen,
+        Exit::NoDisplay,
+        Exit::InputUnavailable,
+        Exit::Timeout,
+        Exit::VerifyMismatch,
+        Exit::VerdictsMissing,
+        Exit::DeviceLost,
+    ];
+
+    #[test]
+    fn codes_are_distinct_and_leave_success_and_unexpected_free() {
+        let mut codes: Vec<u8> = ALL.iter().map(|e| e.code()).collect();
+        codes.sort();
+        codes.dedup();
+        assert_eq!(codes.len(), ALL.len(), "two statuses share a code");
+        assert!(!codes.contains(&0) && !codes.contains(&UNEXPECTED));
+    }
+
+    #[test]
+    fn untagged_errors_are_unexpected() {
+        assert_eq!(code(&anyhow!("boom")), UNEXPECTED);
+        assert_eq!(code(&anyhow!("boom").context("while testing")), UNEXPECTED);
+    }
+
+    #[test]
+    fn tags_survive_later_context() {
+        for exit in ALL {
+            let err = anyhow!("boom")
+                .context(exit)
+                .context("while testing")
+                .context("in main");
+            assert_eq!(code(&err), exit.code(), "{:?}", exit);
+            let err: anyhow::Error = exit.into();
+            assert_eq!(
+                code(&err.context("while testing")),
+                exit.code(),
+                "{:?}",
+                exit
+            );
+        }
+    }
+
+    #[test]
+    fn outermost_tag_wins() {
+        let err = anyhow!("boom")
+            .context(Exit::DeviceLost)
+            .context(Exit::NoDisplay);
+        assert_eq!(code(&err), Exit::NoDisplay.code());
+        let err: Result<()> = Err(anyhow!("boom")).context(Exit::DeviceOpen);
+        assert_eq!(
+            code(&err.context(Exit::Usage).unwrap_err()),
+            Exit::Usage.code()
+        );
+    }
+}
@@ -0,0 +1,148 @@
+//! External pattern generators: a script step with `pat = "external"` names
+//! a shell command that draws the frame. It runs with the frame geometry in
+//! the environment and writes exactly SCREEN_TEST_STRIDE * SCREEN_TEST_HEIGHT
+//! bytes of XRGB8888 (B, G, R, X byte order) to stdout:
+//!
+//! - SCREEN_TEST_WIDTH, SCREEN_TEST_HEIGHT: size in pixels
+//! - SCREEN_TEST_STRIDE: bytes per row, always WIDTH * 4
+//! - SCREEN_TEST_FRAME: completed flips so far, for animation
+//! - SCREEN_TEST_STEP: the script step as JSON, for parameters
+//!
+//! stderr is passed through. A generator that fails, writes too little or
+//! takes longer than GENERATOR_TIMEOUT is killed and the step shows magenta.
+
+use anyhow::{Context, Result, bail, ensure};
+use nix::poll::{PollFd, PollFlags, PollTimeout, poll};
+use std::io::Read;
+use std::os::fd::AsFd;
+use std::process::{Child, Command, Stdio};
+use std::time::{Duration, Instant};
+
+/// How long one run of a generator may take, output and exit included
+pub const GENERATOR_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Runs `command` for one frame and returns its pixels, `w * 4` bytes per row
+pub fn generate(command: &str, w: usize, h: usize, frame: u64, step_json: &str) -> Result<Vec<u8>> {
+    let stride = w * 4;
+    let mut child = Command::new("sh")
+        .arg("-c")
+        .arg(command)
+        .env("SCREEN_TEST_WIDTH", w.to_string())
+        .env("SCREEN_TEST_HEIGHT", h.to_string())
+        .env("SCREEN_TEST_STRIDE", stride.to_string())
+        .env("SCREEN_TEST_FRAME", frame.to_string())
+        .env("SCREEN_TEST_STEP", step_json)
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .spawn()
+        .with_context(|| format!("could not start generator `{}`", command))?;
+
+    let deadline = Instant::now() + GENERATOR_TIMEOUT;
+    let mut pixels = vec![0u8; stride * h];
+    let result =
+        read_frame(&mut child, &mut pixels, deadline).and_then(|()| wait(&mut child, deadline));
+    if result.is_err() {
+        let _ = child.kill();
+        let _ = child.wait();
+    }
+    result.with_context(|| format!("generator `{}` failed", command))?;
+    Ok(pixels)
+}
+
+fn read_frame(child: &mut Child, pixels: &mut [u8], deadline: Instant) -> Result<()> {
+    let Some(out) = child.stdout.as_mut() else {
+        bail!("no pipe to the generator");
+    };
+    let mut filled = 0;
+    while filled < pixels.len() {
+        let left = deadline.saturating_duration_since(Instant::now());
+        let ms = PollTimeout::try_from(left.as_millis().min(i32::MAX as u128))
+            .unwrap_or(PollTimeout::MAX);
+        let mut fds = [PollFd::new(out.as_fd(), PollFlags::POLLIN)];
+        if poll(&mut fds, ms).context("could not wait for the generator")? == 0 {
+            bail!(
+                "timed out after {:?} with {} of {} bytes",
+                GENERATOR_TIMEOUT,
+                filled,
+                pixels.len()
+            );
+        }
+        let n = out
+            .read(&mut pixels[filled..])
+            .context("could not read from the generator")?;
+        ensure!(
+            n > 0,
+            "output ended after {} of {} bytes",
+            filled,
+            pixels.len()
+        );
+        filled += n;
+    }
+    Ok(())
+}
+
+// The generator has to exit cleanly too, so a crash after the last byte
+// still counts as a failure
+fn wait(child: &mut Child, deadline: Instant) -> Result<()> {
+    loop {
+        if let Some(status) = child.try_wait()? {
+            ensure!(status.success(), "exited with {}", status);
+            return Ok(());
+        }
+        if Instant::now() >= deadline {
+            bail!("did not exit within {:?}", GENERATOR_TIMEOUT);
+        }
+        std::thread::sleep(Duration::from_millis(1));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Writes one B, G, R, X pixel per screen pixel
+    const BGRX: &str = "i=0; while [ $i -lt $((SCREEN_TEST_WIDTH * SCREEN_TEST_HEIGHT)) ]; \
+                        do printf '\\001\\002\\003\\000'; i=$((i + 1)); done";
+
+    #[test]
+    fn reads_a_whole_frame() {
+        let pixels = generate(BGRX, 5, 3, 0, "{}").unwrap();
+        assert_eq!(pixels.len(), 5 * 4 * 3);
+        assert!(pixels.chunks_exact(4).all(|p| p == [1, 2, 3, 0]));
+    }
+
+    #[test]
+    fn passes_geometry_frame_and_step() {
+        let check = "[ \"$SCREEN_TEST_WIDTH $SCREEN_TEST_HEIGHT $SCREEN_TEST_STRIDE\" = '7 2 28' ] \
+                     && [ \"$SCREEN_TEST_FRAME\" = 42 ] \
+                     && [ \"$SCREEN_TEST_STEP\" = '{\"pat\":\"external\"}' ] \
+                     && head -c 56 /dev/zero";
+        generate(check, 7, 2, 42, r#"{"pat":"external"}"#).unwrap();
+        assert!(generate(check, 7, 2, 41, r#"{"pat":"external"}"#).is_err());
+    }
+
+    #[test]
+    fn short_output_fails() {
+        let err = generate("head -c 10 /dev/zero", 4, 4, 0, "{}").unwrap_err();
+        assert!(
+            format!("{:#}", err).contains("output ended after 10 of 64 bytes"),
+            "{:#}",
+            err
+        );
+    }
+
+    #[test]
+    fn failing_exit_fails_even_after_a_whole_frame() {
+        let err = generate("head -c 64 /dev/zero; exit 3", 4, 4, 0, "{}").unwrap_err();
+        assert!(format!("{:#}", err).contains("exited with"), "{:#}", err);
+        assert!(generate("exit 1", 4, 4, 0, "{}").is_err());
+    }
+
+    #[test]
+    fn slow_generator_times_out() {
+        let start = Instant::now();
+        let err = generate("sleep 5", 4, 4, 0, "{}").unwrap_err();
+        assert!(format!("{:#}", err).contains("timed out"), "{:#}", err);
+        assert!(start.elapsed() < GENERATOR_TIMEOUT * 3);
+    }
+}
@@ -0,0 +1,331 @@
+//! `--fbdev PATH`: presents on a Linux framebuffer device such as /dev/fb0,
+//! for systems where DRM/KMS isn't available to the user. Geometry, stride
+//! and pixel layout come from the fbdev screeninfo; patterns are drawn in
+//! BGRX as always and packed into the framebuffer's layout on the way.
+//!
+//! Where the virtual screen is at least twice the visible height, frames
+//! alternate between the two halves and are shown by panning; otherwise
+//! they are written straight to the visible screen and can tear. Either way
+//! a frame waits for vblank first if the driver supports FBIO_WAITFORVSYNC.
+
+use anyhow::{Context, Result, bail, ensure};
+use nix::libc::c_ulong;
+use nix::sys::mman::{MapFlags, ProtFlags, mmap, munmap};
+use std::ffi::c_void;
+use std::fs::{File, OpenOptions};
+use std::num::NonZeroUsize;
+use std::os::fd::AsRawFd;
+use std::path::{Path, PathBuf};
+use std::ptr::NonNull;
+
+use crate::backend::Backend;
+
+// Layouts from <linux/fb.h>
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default)]
+struct Bitfield {
+    offset: u32,
+    length: u32,
+    msb_right: u32,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default)]
+struct VarScreeninfo {
+    xres: u32,
+    yres: u32,
+    xres_virtual: u32,
+    yres_virtual: u32,
+    xoffset: u32,
+    yoffset: u32,
+    bits_per_pixel: u32,
+    grayscale: u32,
+    red: Bitfield,
+    green: Bitfield,
+    blue: Bitfield,
+    transp: Bitfield,
+    nonstd: u32,
+    activate: u32,
+    height: u32,
+    width: u32,
+    accel_flags: u32,
+    pixclock: u32,
+    left_margin: u32,
+    right_margin: u32,
+    upper_margin: u32,
+    lower_margin: u32,
+    hsync_len: u32,
+    vsync_len: u32,
+    sync: u32,
+    vmode: u32,
+    rotate: u32,
+    colorspace: u32,
+    reserved: [u32; 4],
+}
+
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default)]
+struct FixScreeninfo {
+    id: [u8; 16],
+    smem_start: c_ulong,
+    smem_len: u32,
+    type_: u32,
+    type_aux: u32,
+    visual: u32,
+    xpanstep: u16,
+    ypanstep: u16,
+    ywrapstep: u16,
+    line_length: u32,
+    mmio_start: c_ulong,
+    mmio_len: u32,
+    accel: u32,
+    capabilities: u16,
+    reserved: [u16; 2],
+}
+
+// The ioctl numbers don't encode sizes, so check the layouts here
+const _: () = assert!(std::mem::size_of::<VarScreeninfo>() == 160);
+#[cfg(target_pointer_width = "64")]
+const _: () = assert!(std::mem::size_of::<FixScreeninfo>() == 80);
+
+const FB_TYPE_PACKED_PIXELS: u32 = 0;
+const FB_VISUAL_TRUECOLOR: u32 = 2;
+
+nix::ioctl_read_bad!(fbioget_vscreeninfo, 0x4600, VarScreeninfo);
+nix::ioctl_read_bad!(fbioget_fscreeninfo, 0x4602, FixScreeninfo);
+nix::ioctl_write_ptr_bad!(fbiopan_display, 0x4606, VarScreeninfo);
+nix::ioctl_write_ptr!(fbio_waitforvsync, b'F', 0x20, u32);
+
+pub struct FbDev {
+    file: File,
+    path: PathBuf,
+    id: String,
+    map: NonNull<c_void>,
+    map_len: usize,
+    var: VarScreeninfo,
+    line_length: usize,
+    bytes_per_pixel: usize,
+    // Whether the BGRX stage can be copied as is
+    native: bool,
+    // Visible page when panning between two, otherwise always 0
+    page: usize,
+    pages: usize,
+    // Off after the first failure, so an unsupported ioctl costs one call
+    vsync: bool,
+    refresh_hz: f64,
+    presented: u64,
+}
+
+impl FbDev {
+    pub fn open(path: &Path) -> Result<Self> {
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(path)
+            .with_context(|| format!("could not open {}", path.display()))?;
+        let fd = file.as_raw_fd();
+
+        let mut var = VarScreeninfo::default();
+        let mut fix = FixScreeninfo::default();
+        // SAFETY: both structs match the kernel's layout and outlive the calls
+        unsafe {
+            fbioget_vscreeninfo(fd, &mut var).context("could not read the screen info")?;
+            fbioget_fscreeninfo(fd, &mut fix).context("could not read the fixed screen info")?;
+        }
+
+        ensure!(
+            fix.type_ == FB_TYPE_PACKED_PIXELS && fix.visual == FB_VISUAL_TRUECOLOR,
+            "{} is not a packed truecolor framebuffer",
+            path.display()
+        );
+        let bytes_per_pixel = match var.bits_per_pixel {
+            16 | 24 | 32 => var.bits_per_pixel as usize / 8,
+            bpp => bail!(
+                "{} uses {} bits per pixel, which is not supported",
+                path.display(),
+                bpp
+            ),
+        };
+
+        let (w, h) = (var.xres as usize, var.yres as usize);
+        let line_length = fix.line_length as usize;
+        let page_len = line_length * h;
+        ensure!(
+            w > 0
+                && h > 0
+                && line_length >= w * bytes_per_pixel
+                && page_len <= fix.smem_len as usize,
+            "{} reports an unusable geometry",
+            path.display()
+        );
+        let pages = if var.yres_virtual >= 2 * var.yres
+            && fix.ypanstep > 0
+            && 2 * page_len <= fix.smem_len as usize
+        {
+            2
+        } else {
+            1
+        };
+        let map_len = page_len * pages;
+
+        // SAFETY: a fresh shared mapping of the device, unmapped in Drop
+        let map = unsafe {
+            mmap(
+                None,
+                NonZeroUsize::new(map_len).unwrap(),
+                ProtFlags::PROT_READ | ProtFlags::PROT_WRITE,
+                MapFlags::MAP_SHARED,
+                &file,
+                0,
+            )
+        }
+        .with_context(|| format!("could not map {}", path.display()))?;
+
+        let field = |f: Bitfield| (f.offset, f.length);
+        let native = bytes_per_pixel == 4
+            && field(var.red) == (16, 8)
+            && field(var.green) == (8, 8)
+            && field(var.blue) == (0, 8);
+
+        // Pixel clock in picoseconds per pixel, when the driver gives one
+        let htotal = var.xres + var.left_margin + var.right_margin + var.hsync_len;
+        let vtotal = var.yres + var.upper_margin + var.lower_margin + var.vsync_len;
+        let frame_ps = var.pixclock as f64 * htotal as f64 * vtotal as f64;
+        let refresh_hz = if frame_ps > 0.0 {
+            1e12 / frame_ps
+        } else {
+            60.0
+        };
+
+        let id = String::from_utf8_lossy(&fix.id)
+            .trim_end_matches('\0')
+            .to_string();
+        eprintln!(
+            "Using framebuffer: {} ({}), {}x{} {} bpp{}",
+            path.display(),
+            id,
+            w,
+            h,
+            var.bits_per_pixel,
+            if pages == 2 { ", panning" } else { "" }
+        );
+
+        Ok(Self {
+            file,
+            path: path.to_path_buf(),
+            id,
+            map,
+            map_len,
+            var,
+            line_length,
+            bytes_per_pixel,
+            native,
+            page: 0,
+            pages,
+            vsync: true,
+            refresh_hz,
+            presented: 0,
+        })
+    }
+
+    fn wait_for_vsync(&mut self) {
+        if self.vsync {
+            let screen = 0u32;
+            // SAFETY: the argument is a u32 that outlives the call
+            self.vsync = unsafe { fbio_waitforvsync(self.file.as_raw_fd(), &screen) }.is_ok();
+        }
+    }
+
+    // Packs a BGRX row into the framebuffer's pixel layout
+    fn pack_row(&self, dst: &mut [u8], src: &[u8]) {
+        let channel = |v: u8, f: Bitfield| -> u32 {
+            match f.length {
+                0 => 0,
+                len => ((v as u32) >> 8u32.saturating_sub(len)) << f.offset,
+            }
+        };
+        let bpp = self.bytes_per_pixel;
+        for (d, s) in dst.chunks_exact_mut(bpp).zip(src.chunks_exact(4)) {
+            let v = channel(s[2], self.var.red)
+                | channel(s[1], self.var.green)
+                | channel(s[0], self.var.blue);
+            d.copy_from_slice(&v.to_le_bytes()[..bpp]);
+        }
+    }
+}
+
+impl Backend for FbDev {
+    fn size(&self) -> (usize, usize) {
+        (self.var.xres as usize, self.var.yres as usize)
+    }
+
+    fn stride(&self) -> usize {
+        self.var.xres as usize * 4
+    }
+
+    fn refresh_hz(&self) -> f64 {
+        self.refresh_hz
+    }
+
+    fn frames_presented(&self) -> u64 {
+        self.presented
+    }
+
+    fn mode_label(&self) -> String {
+        let (w, h) = self.size();
+        format!("{} {}x{} @ {:.0}Hz", self.id, w, h, self.refresh_hz)
+    }
+
+    // Drivers that don't know leave these 0 or all ones
+    fn size_mm(&self) -> Option<(u32, u32)> {
+        let known = |mm: u32| mm > 0 && mm != u32::MAX;
+        (known(self.var.width) && known(self.var.height))
+            .then_some((self.var.width, self.var.height))
+    }
+
+    fn write_and_present(&mut self, src: &[u8], src_stride: usize) -> Result<()> {
+        let (w, h) = self.size();
+        ensure!(src.len() >= src_stride * h, "source buffer too small");
+
+        let target = (self.page + 1) % self.pages;
+        if self.pages == 1 {
+            self.wait_for_vsync();
+        }
+
+        // SAFETY: the mapping is map_len bytes and lives as long as self
+        let map =
+            unsafe { std::slice::from_raw_parts_mut(self.map.as_ptr().cast::<u8>(), self.map_len) };
+        let base = target * self.line_length * h;
+        let n = w * self.bytes_per_pixel;
+        for y in 0..h {
+            let dst = &mut map[base + y * self.line_length..][..n];
+            let row = &src[y * src_stride..][..w * 4];
+            if self.native {
+                dst.copy_from_slice(row);
+            } else {
+                self.pack_row(dst, row);
+            }
+        }
+
+        if self.pages == 2 {
+            self.wait_for_vsync();
+            let mut var = self.var;
+            var.xoffset = 0;
+            var.yoffset = (target * h) as u32;
+            // SAFETY: var matches the kernel's layout and outlives the call
+            unsafe { fbiopan_display(self.file.as_raw_fd(), &var) }
+                .with_context(|| format!("could not pan {}", self.path.display()))?;
+        }
+        self.page = target;
+        self.presented += 1;
+        Ok(())
+    }
+}
+
+impl Drop for FbDev {
+    fn drop(&mut self) {
+        // SAFETY: the mapping came from mmap with this length and is not
+        // used after this
+        let _ = unsafe { munmap(self.map, self.map_len) };
+    }
+}
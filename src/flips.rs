@@ -0,0 +1,359 @@
+//! Frames between being handed to the surface and reaching the screen. Each
+//! frame goes into the queue with what it was drawn for, and when its flip
+//! completes that is what the checksum, AV sync, late frame and --verify
+//! bookkeeping is done against, rather than whatever the loop has moved on
+//! to since.
+
+use anyhow::{Context, Result};
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+
+use crate::audio::AvAudio;
+use crate::beep::Beeper;
+use crate::checksum::FreezeWatch;
+use crate::cli::Verify;
+use crate::events::{Event, EventLog};
+use crate::gpio::Trigger;
+use crate::report::StepLog;
+use crate::soak::{AnomalyKind, Soak};
+use crate::surface::Surface;
+use crate::timing::{LateFrames, TimingCsv};
+use crate::{AppState, LATE_MARK, PatternKind, av_flash_timing};
+
+/// What a frame handed to the surface was drawn for, kept until its flip
+/// completes
+#[derive(Default)]
+pub struct InFlight {
+    /// Whether it belongs to a gpio_hold step
+    pub gpio_hold: Option<bool>,
+    /// Step it was drawn for
+    pub step: Option<(usize, PatternKind)>,
+    /// Whether it is an AV sync flash
+    pub av_flash: Option<bool>,
+    /// Step index, if it is to be verified
+    pub verify: Option<usize>,
+    /// Checksum with the identical run its pattern explains
+    pub checksum: Option<(u64, Option<u64>)>,
+    /// --sync-outputs pass it was drawn in
+    pub sync_pass: Option<u64>,
+    /// Drawn as a frame of an animation, so due a frame after the one before
+    pub animated: bool,
+}
+
+/// Where an arriving frame's bookkeeping is written
+pub struct Logs<'a> {
+    pub event_log: Option<&'a mut EventLog>,
+    pub timing_csv: Option<&'a mut TimingCsv>,
+    pub step_log: &'a mut StepLog,
+    pub soak: Option<&'a mut Soak>,
+    pub errors: &'a mut Vec<String>,
+}
+
+/// The flip queue, and what is tracked about the frames coming off it
+pub struct Flips {
+    // Frames handed to the surface whose flips haven't completed, oldest
+    // first: one, or two with --buffers 3 or more
+    queue: VecDeque<InFlight>,
+    /// Step of the last frame presented
+    pub shown_step: Option<usize>,
+    trigger: Option<Trigger>,
+    beeper: Option<Beeper>,
+    // --av-audio, and whether it has a click scheduled
+    av_audio: Option<AvAudio>,
+    av_armed: bool,
+    // Whether the last frame presented was an AV sync flash
+    av_showing: bool,
+    pub late_frames: LateFrames,
+    /// Until when the marker that flags a late frame is up
+    pub late_shown: Option<Instant>,
+    /// --frame-checksum: the last checksum presented
+    pub last_checksum: Option<u64>,
+    pub freeze_watch: FreezeWatch,
+    // Step index of the last frame picked for verification
+    verified_step: Option<usize>,
+    pub verify_checked: u64,
+    pub verify_mismatches: u64,
+}
+
+impl Flips {
+    pub fn new(
+        trigger: Option<Trigger>,
+        beeper: Option<Beeper>,
+        av_audio: Option<AvAudio>,
+        late_tolerance: f64,
+    ) -> Self {
+        Flips {
+            queue: VecDeque::new(),
+            shown_step: None,
+            trigger,
+            beeper,
+            av_audio,
+            av_armed: false,
+            av_showing: false,
+            late_frames: LateFrames::new(late_tolerance),
+            late_shown: None,
+            last_checksum: None,
+            freeze_watch: FreezeWatch::default(),
+            verified_step: None,
+            verify_checked: 0,
+            verify_mismatches: 0,
+        }
+    }
+
+    pub fn submit(&mut self, frame: InFlight) {
+        self.queue.push_back(frame);
+    }
+
+    /// After a modeset or an error: whatever was in flight is not coming back
+    pub fn clear(&mut self) {
+        self.queue.clear();
+    }
+
+    /// The frame whose flip just completed. Called straight after the event,
+    /// so the trigger's edge tracks the flip as closely as the loop allows.
+    pub fn complete(&mut self) -> InFlight {
+        let frame = self.queue.pop_front().unwrap_or_default();
+        if let Some(trigger) = &mut self.trigger {
+            trigger.flip(frame.gpio_hold);
+        }
+        frame
+    }
+
+    /// The step index to verify the frame about to be drawn for, if any:
+    /// every frame, or the first of each step
+    pub fn pick_verify(&mut self, mode: Option<Verify>, step: usize) -> Option<usize> {
+        let verify = mode
+            .is_some_and(|mode| mode == Verify::All || self.verified_step != Some(step))
+            .then_some(step);
+        if verify.is_some() {
+            self.verified_step = verify;
+        }
+        verify
+    }
+
+    /// Does the bookkeeping for `frame`, now on screen. True when the
+    /// pattern has to be redrawn because of it.
+    pub fn presented(
+        &mut self,
+        frame: &InFlight,
+        surface: &mut Surface,
+        state: &mut AppState,
+        fps_cap: Option<f64>,
+        stage: &[u8],
+        mut logs: Logs,
+    ) -> Result<bool> {
+        let mut need_redraw = false;
+
+        if let Some((sum, allowed)) = frame.checksum {
+            self.last_checksum = Some(sum);
+            if self.freeze_watch.presented(sum, allowed) {
+                let msg = format!(
+                    "Warning: {:?} is animating but {} frames in a row were identical (checksum {:016x})",
+                    state.pattern, self.freeze_watch.identical, sum
+                );
+                eprintln!("{}", msg);
+                if let Some(log) = logs.event_log.as_deref_mut() {
+                    log.log(Event::FrameFrozen {
+                        identical_frames: self.freeze_watch.identical,
+                        checksum: format!("{:016x}", sum),
+                    });
+                }
+            }
+            if let (Some(log), Some(sample)) = (logs.event_log.as_deref_mut(), &surface.last_flip) {
+                log.checksum(sample.seq, sum, self.freeze_watch.identical);
+            }
+        }
+
+        if let (Some(csv), Some(sample)) = (logs.timing_csv, &surface.last_flip) {
+            csv.write(sample, state.script_idx, frame.checksum.map(|(sum, _)| sum))
+                .context("could not write timing CSV")?;
+        }
+
+        if let (Some(log), Some(sample)) = (logs.event_log.as_deref_mut(), &surface.last_flip) {
+            log.flip(sample.seq, sample.timestamp, sample.latency, sample.missed);
+        }
+
+        self.av_sync(frame.av_flash, surface, logs.event_log.as_deref_mut());
+
+        // Only frame to frame within one step's animation; the first of a
+        // step, or one after a still frame, has nothing to be late for
+        if let Some(sample) = &surface.last_flip {
+            match frame.step {
+                Some((idx, pattern)) if frame.animated && self.shown_step == Some(idx) => {
+                    let hz = surface.refresh_hz();
+                    let fps = fps_cap.map_or(hz, |cap| cap.min(hz));
+                    let period = Duration::from_secs_f64(1.0 / fps);
+                    let late = self.late_frames.frame(sample.timestamp, period);
+                    if pattern == PatternKind::Stereo
+                        && state.pattern == PatternKind::Stereo
+                        && state.stereo_void.is_none()
+                        && (late.is_some() || sample.missed > 0)
+                    {
+                        eprintln!(
+                            "Warning: stereo crosstalk measurement aborted: flip {} was late, so the eyes may have swapped",
+                            sample.seq
+                        );
+                        state.stereo_void = Some(sample.seq);
+                        need_redraw = true;
+                    }
+                    if let Some(interval) = late {
+                        logs.step_log.late_frame();
+                        self.late_shown = Some(Instant::now() + LATE_MARK);
+                        let ms = |d: Duration| d.as_secs_f64() * 1000.0;
+                        match logs.event_log.as_deref_mut() {
+                            Some(log) => log.log(Event::FrameLate {
+                                seq: sample.seq,
+                                step: idx,
+                                interval_us: interval.as_micros() as u64,
+                                period_us: period.as_micros() as u64,
+                            }),
+                            None => eprintln!(
+                                "Late frame: flip {} came {:.2} ms after the one before, for a {:.2} ms period",
+                                sample.seq,
+                                ms(interval),
+                                ms(period)
+                            ),
+                        }
+                    }
+                }
+                _ => self.late_frames.resync(),
+            }
+        }
+        if let Some((idx, _)) = frame.step {
+            self.shown_step = Some(idx);
+        }
+
+        // Frames aren't queued with --verify, so the stage still holds
+        // exactly what went into the buffer that just became the front
+        if let Some(step) = frame.verify {
+            self.verify_checked += 1;
+            if let Some(row) = surface.front_mismatch(stage, surface.stride())? {
+                self.verify_mismatches += 1;
+                let msg = format!(
+                    "VERIFY MISMATCH: step {} flip {}: scanout differs from what was drawn from row {}",
+                    step,
+                    surface.flips_completed(),
+                    row
+                );
+                eprintln!("{}", msg);
+                if let Some(log) = logs.event_log.as_deref_mut() {
+                    log.log(Event::Error {
+                        message: msg.clone(),
+                    });
+                }
+                if let Some(soak) = logs.soak {
+                    soak.record(AnomalyKind::VerifyMismatch, msg.clone());
+                }
+                logs.errors.push(msg);
+            }
+        }
+
+        Ok(need_redraw)
+    }
+
+    // The marker, tone and click that go with the AV sync pattern's flashes:
+    // `av_flash` is whether the frame now on screen is one, None off the
+    // pattern
+    fn av_sync(
+        &mut self,
+        av_flash: Option<bool>,
+        surface: &Surface,
+        mut event_log: Option<&mut EventLog>,
+    ) {
+        let flash = av_flash.unwrap_or(false);
+        if flash && !self.av_showing {
+            let vblank = surface.last_flip.map_or(Duration::ZERO, |s| s.timestamp);
+            let vblank_ns = vblank.as_nanos() as u64;
+            match event_log.as_deref_mut() {
+                Some(log) => log.log(Event::AvMarker {
+                    flip: surface.flips_completed(),
+                    vblank_ns,
+                }),
+                None => eprintln!(
+                    "AV marker: flip {}, vblank {} ns",
+                    surface.flips_completed(),
+                    vblank_ns
+                ),
+            }
+            if let Some(audio) = &self.av_audio {
+                let flips = av_flash_timing(surface.refresh_hz()).0;
+                audio.flash(
+                    vblank,
+                    Duration::from_secs_f64(flips as f64 / surface.refresh_hz()),
+                );
+                self.av_armed = true;
+            }
+        }
+        self.av_showing = flash;
+        if let Some(beeper) = &mut self.beeper {
+            beeper.set(flash);
+        }
+        if let Some(audio) = &self.av_audio {
+            if av_flash.is_none() && self.av_armed {
+                audio.cancel();
+                self.av_armed = false;
+            }
+            for click in audio.written() {
+                match event_log.as_deref_mut() {
+                    Some(log) => log.log(Event::AvClick {
+                        flash_ns: click.flash_ns,
+                        target_ns: click.target_ns,
+                        written_ns: click.written_ns,
+                        play_ns: click.play_ns,
+                    }),
+                    None => eprintln!(
+                        "AV click: plays {} ns for flash at {} ns ({:+.2} ms)",
+                        click.play_ns,
+                        click.flash_ns,
+                        (click.play_ns as f64 - click.flash_ns as f64) / 1e6
+                    ),
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn frame(idx: usize) -> InFlight {
+        InFlight {
+            step: Some((idx, PatternKind::Solid)),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn flips_complete_in_the_order_frames_went_in() {
+        let mut flips = Flips::new(None, None, None, 0.5);
+        flips.submit(frame(3));
+        flips.submit(frame(4));
+        assert_eq!(flips.complete().step.map(|(i, _)| i), Some(3));
+        assert_eq!(flips.complete().step.map(|(i, _)| i), Some(4));
+        // An event with nothing queued, after a modeset say
+        assert!(flips.complete().step.is_none());
+
+        flips.submit(frame(5));
+        flips.clear();
+        assert!(flips.complete().step.is_none());
+    }
+
+    #[test]
+    fn verify_picks_each_step_once_or_every_frame() {
+        let mut flips = Flips::new(None, None, None, 0.5);
+        assert_eq!(flips.pick_verify(None, 0), None);
+
+        let picks: Vec<_> = [0, 0, 1, 1, 0]
+            .into_iter()
+            .map(|step| flips.pick_verify(Some(Verify::Step), step))
+            .collect();
+        assert_eq!(picks, [Some(0), None, Some(1), None, Some(0)]);
+
+        let picks: Vec<_> = [0, 0, 1]
+            .into_iter()
+            .map(|step| flips.pick_verify(Some(Verify::All), step))
+            .collect();
+        assert_eq!(picks, [Some(0), Some(0), Some(1)]);
+    }
+}
@@ -0,0 +1,253 @@
+//! GPIO through the gpiochip character device (GPIO uAPI v2): a scope
+//! trigger output for rigs where a probe watches a pin, and push buttons for
+//! fixtures without a keyboard.
+
+use anyhow::{Context, Result, bail, ensure};
+use std::fs::{File, OpenOptions};
+use std::io::Read;
+use std::os::fd::{AsFd, AsRawFd, BorrowedFd, FromRawFd};
+use std::path::Path;
+use std::time::Duration;
+
+// Layouts from <linux/gpio.h>
+const GPIO_V2_LINES_MAX: usize = 64;
+const GPIO_MAX_NAME_SIZE: usize = 32;
+const GPIO_V2_LINE_NUM_ATTRS_MAX: usize = 10;
+const GPIO_V2_LINE_FLAG_ACTIVE_LOW: u64 = 1 << 1;
+const GPIO_V2_LINE_FLAG_INPUT: u64 = 1 << 2;
+const GPIO_V2_LINE_FLAG_OUTPUT: u64 = 1 << 3;
+const GPIO_V2_LINE_FLAG_EDGE_RISING: u64 = 1 << 4;
+const GPIO_V2_LINE_FLAG_BIAS_PULL_UP: u64 = 1 << 8;
+
+// Presses of one button closer together than this are contact bounce
+const DEBOUNCE: Duration = Duration::from_millis(50);
+
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+struct LineAttribute {
+    id: u32,
+    padding: u32,
+    value: u64,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+struct LineConfigAttribute {
+    attr: LineAttribute,
+    mask: u64,
+}
+
+#[repr(C)]
+struct LineConfig {
+    flags: u64,
+    num_attrs: u32,
+    padding: [u32; 5],
+    attrs: [LineConfigAttribute; GPIO_V2_LINE_NUM_ATTRS_MAX],
+}
+
+#[repr(C)]
+struct LineRequest {
+    offsets: [u32; GPIO_V2_LINES_MAX],
+    consumer: [u8; GPIO_MAX_NAME_SIZE],
+    config: LineConfig,
+    num_lines: u32,
+    event_buffer_size: u32,
+    padding: [u32; 5],
+    fd: i32,
+}
+
+#[repr(C)]
+struct LineValues {
+    bits: u64,
+    mask: u64,
+}
+
+// struct gpio_v2_line_event, as read from a request with edge detection
+const LINE_EVENT_SIZE: usize = 48;
+const LINE_EVENT_OFFSET: usize = 12;
+
+// The ioctl numbers encode these sizes, so a layout mistake would fail every call
+const _: () = assert!(std::mem::size_of::<LineRequest>() == 592);
+const _: () = assert!(std::mem::size_of::<LineValues>() == 16);
+
+nix::ioctl_readwrite!(gpio_v2_get_line, 0xB4, 0x07, LineRequest);
+nix::ioctl_readwrite!(gpio_v2_line_set_values, 0xB4, 0x0F, LineValues);
+
+/// One output line. It rests low, or high while a step marked `gpio_hold`
+/// is on screen, and every flip completion pulses it away from that level
+/// and back, a few microseconds wide.
+pub struct Trigger {
+    line: File,
+    rest: bool,
+}
+
+// Claims `offsets` on `chip` with `flags` and returns the request's fd
+fn request_lines(chip: &Path, offsets: &[u32], flags: u64) -> Result<File> {
+    ensure!(
+        offsets.len() <= GPIO_V2_LINES_MAX,
+        "at most {} GPIO lines",
+        GPIO_V2_LINES_MAX
+    );
+    let chip_file = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open(chip)
+        .with_context(|| format!("could not open {}", chip.display()))?;
+
+    let mut req = LineRequest {
+        offsets: [0; GPIO_V2_LINES_MAX],
+        consumer: [0; GPIO_MAX_NAME_SIZE],
+        config: LineConfig {
+            flags,
+            num_attrs: 0,
+            padding: [0; 5],
+            attrs: [LineConfigAttribute::default(); GPIO_V2_LINE_NUM_ATTRS_MAX],
+        },
+        num_lines: offsets.len() as u32,
+        event_buffer_size: 0,
+        padding: [0; 5],
+        fd: -1,
+    };
+    req.offsets[..offsets.len()].copy_from_slice(offsets);
+    let name = b"screen_test";
+    req.consumer[..name.len()].copy_from_slice(name);
+
+    // SAFETY: req is a fully initialized gpio_v2_line_request
+    unsafe { gpio_v2_get_line(chip_file.as_raw_fd(), &mut req) }.with_context(|| {
+        format!(
+            "could not claim line(s) {:?} on {}",
+            offsets,
+            chip.display()
+        )
+    })?;
+    // SAFETY: on success the kernel hands us a new fd that nothing else owns
+    Ok(unsafe { File::from_raw_fd(req.fd) })
+}
+
+impl Trigger {
+    pub fn open(chip: &Path, offset: u32) -> Result<Self> {
+        let line = request_lines(chip, &[offset], GPIO_V2_LINE_FLAG_OUTPUT)?;
+        let mut trigger = Self { line, rest: false };
+        trigger.write(false)?;
+        Ok(trigger)
+    }
+
+    fn write(&mut self, high: bool) -> Result<()> {
+        let mut values = LineValues {
+            bits: high as u64,
+            mask: 1,
+        };
+        // SAFETY: values is a valid gpio_v2_line_values for a one-line request
+        unsafe { gpio_v2_line_set_values(self.line.as_raw_fd(), &mut values) }
+            .context("could not set GPIO line")?;
+        Ok(())
+    }
+
+    /// Called right after a flip completes. `hold` is whether the frame now
+    /// on screen belongs to a held step, if known. A failed write only costs
+    /// the pulse, never the run.
+    pub fn flip(&mut self, hold: Option<bool>) {
+        if let Some(hold) = hold {
+            self.rest = hold;
+        }
+        if let Err(e) = self.write(!self.rest).and_then(|()| self.write(self.rest)) {
+            eprintln!("{:#}", e);
+        }
+    }
+}
+
+impl Drop for Trigger {
+    fn drop(&mut self) {
+        let _ = self.write(false);
+    }
+}
+
+/// What a push button does
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ButtonAction {
+    Next,
+    Previous,
+    Quit,
+}
+
+impl ButtonAction {
+    pub fn parse(name: &str) -> Result<Self> {
+        Ok(match name.to_ascii_lowercase().as_str() {
+            "next" => ButtonAction::Next,
+            "prev" | "previous" => ButtonAction::Previous,
+            "quit" => ButtonAction::Quit,
+            _ => bail!(
+                "invalid button action: {} (expected next, prev or quit)",
+                name
+            ),
+        })
+    }
+}
+
+/// Push buttons on input lines, wired to ground: the lines are pulled up
+/// and read active low, so a press is a rising edge of the logical value.
+/// Bounce is filtered here rather than by the kernel, whose debounce not
+/// every GPIO controller supports.
+pub struct Buttons {
+    lines: File,
+    actions: Vec<(u32, ButtonAction)>,
+    // Kernel timestamp of each button's last accepted press
+    last_press: Vec<Option<u64>>,
+}
+
+impl Buttons {
+    pub fn open(chip: &Path, actions: &[(ButtonAction, u32)]) -> Result<Self> {
+        let offsets: Vec<u32> = actions.iter().map(|&(_, offset)| offset).collect();
+        let lines = request_lines(
+            chip,
+            &offsets,
+            GPIO_V2_LINE_FLAG_INPUT
+                | GPIO_V2_LINE_FLAG_ACTIVE_LOW
+                | GPIO_V2_LINE_FLAG_BIAS_PULL_UP
+                | GPIO_V2_LINE_FLAG_EDGE_RISING,
+        )?;
+        Ok(Self {
+            lines,
+            actions: actions
+                .iter()
+                .map(|&(action, offset)| (offset, action))
+                .collect(),
+            last_press: vec![None; actions.len()],
+        })
+    }
+
+    pub fn fd(&self) -> BorrowedFd<'_> {
+        self.lines.as_fd()
+    }
+
+    /// Reads the edges waiting on the lines, once poll says there are some,
+    /// and returns the presses that aren't bounce
+    pub fn read_actions(&mut self) -> Result<Vec<ButtonAction>> {
+        let mut buf = [0u8; 16 * LINE_EVENT_SIZE];
+        let n = self
+            .lines
+            .read(&mut buf)
+            .context("could not read the GPIO buttons")?;
+
+        let mut actions = Vec::new();
+        for event in buf[..n].chunks_exact(LINE_EVENT_SIZE) {
+            let timestamp_ns = u64::from_ne_bytes(event[..8].try_into().unwrap());
+            let offset = u32::from_ne_bytes(
+                event[LINE_EVENT_OFFSET..LINE_EVENT_OFFSET + 4]
+                    .try_into()
+                    .unwrap(),
+            );
+            let Some(i) = self.actions.iter().position(|&(o, _)| o == offset) else {
+                continue;
+            };
+            if self.last_press[i]
+                .is_some_and(|at| timestamp_ns.saturating_sub(at) < DEBOUNCE.as_nanos() as u64)
+            {
+                continue;
+            }
+            self.last_press[i] = Some(timestamp_ns);
+            actions.push(self.actions[i].1);
+        }
+        Ok(actions)
+    }
+}
@@ -0,0 +1,138 @@
+//! `--grab`: exclusive access (EVIOCGRAB) to the keyboard and the pointing
+//! devices, so keys and clicks meant for the test don't also reach a console
+//! or compositor underneath. The kernel drops a grab when its device is
+//! closed, so not even SIGKILL can leave the console deaf; Drop ungrabs on
+//! top of that, on every way out that unwinds.
+
+use std::ops::{Deref, DerefMut};
+use std::path::Path;
+
+use evdev::{Device as EvDev, RelativeAxisCode};
+
+/// An input device, grabbed if that was asked for and possible
+pub struct Grabbed {
+    dev: EvDev,
+    grabbed: bool,
+}
+
+impl Grabbed {
+    pub fn new(mut dev: EvDev, grab: bool) -> Self {
+        let grabbed = grab
+            && dev
+                .grab()
+                .inspect_err(|e| {
+                    eprintln!(
+                        "Warning: could not grab {}: {}",
+                        dev.name().unwrap_or("input device"),
+                        e
+                    )
+                })
+                .is_ok();
+        Self { dev, grabbed }
+    }
+}
+
+impl Deref for Grabbed {
+    type Target = EvDev;
+
+    fn deref(&self) -> &EvDev {
+        &self.dev
+    }
+}
+
+impl DerefMut for Grabbed {
+    fn deref_mut(&mut self) -> &mut EvDev {
+        &mut self.dev
+    }
+}
+
+impl Drop for Grabbed {
+    fn drop(&mut self) {
+        if self.grabbed {
+            let _ = self.dev.ungrab();
+        }
+    }
+}
+
+/// Grabs every mouse, trackball and the like other than the keyboard at
+/// `keyboard`, so the pointer stays put and clicks go nowhere
+pub fn grab_pointers(keyboard: Option<&Path>) -> Vec<Grabbed> {
+    let pointers: Vec<Grabbed> = evdev::enumerate()
+        .filter(|(path, dev)| {
+            Some(path.as_path()) != keyboard
+                && dev
+                    .supported_relative_axes()
+                    .is_some_and(|axes| axes.contains(RelativeAxisCode::REL_X))
+        })
+        .map(|(_, dev)| Grabbed::new(dev, true))
+        .filter(|dev| dev.grabbed)
+        .collect();
+    if !pointers.is_empty() {
+        eprintln!("Grabbed {} pointing device(s)", pointers.len());
+    }
+    pointers
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use evdev::uinput::VirtualDevice;
+    use evdev::{AttributeSet, KeyCode};
+    use std::path::PathBuf;
+    use std::time::{Duration, Instant};
+
+    // A virtual mouse and its event node, or None where uinput isn't usable
+    fn virtual_mouse() -> Option<(VirtualDevice, PathBuf)> {
+        let made = VirtualDevice::builder().and_then(|b| {
+            b.name("screen_test grab test")
+                .with_keys(&AttributeSet::from_iter([KeyCode::BTN_LEFT]))?
+                .with_relative_axes(&AttributeSet::from_iter([
+                    RelativeAxisCode::REL_X,
+                    RelativeAxisCode::REL_Y,
+                ]))?
+                .build()
+        });
+        let mut dev = match made {
+            Ok(dev) => dev,
+            Err(e) => {
+                eprintln!("no uinput ({}), skipping", e);
+                return None;
+            }
+        };
+        let node = dev.enumerate_dev_nodes_blocking().ok()?.next()?.ok()?;
+        Some((dev, node))
+    }
+
+    // Opens `node`, waiting a little for udev to create it
+    fn open(node: &Path) -> EvDev {
+        let deadline = Instant::now() + Duration::from_secs(2);
+        loop {
+            match EvDev::open(node) {
+                Ok(dev) => return dev,
+                Err(e) if Instant::now() >= deadline => panic!("{}: {}", node.display(), e),
+                Err(_) => std::thread::sleep(Duration::from_millis(20)),
+            }
+        }
+    }
+
+    #[test]
+    fn grab_holds_until_dropped() {
+        let Some((_mouse, node)) = virtual_mouse() else {
+            return;
+        };
+
+        let held = Grabbed::new(open(&node), true);
+        assert!(held.grabbed);
+        // Nobody else gets it while it is held
+        let mut other = open(&node);
+        assert!(other.grab().is_err(), "grabbed twice");
+
+        drop(held);
+        other.grab().expect("still grabbed after drop");
+        other.ungrab().unwrap();
+
+        // Not asking for a grab leaves it free
+        let _free = Grabbed::new(open(&node), false);
+        other.grab().expect("grabbed without asking");
+    }
+}
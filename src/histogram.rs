@@ -0,0 +1,190 @@
+//! Histogram overlay (F7): the distribution of code values in the stage,
+//! as luma or as R, G and B drawn over each other, plotted in the
+//! bottom-right corner with markers at the limited-range black and white
+//! levels, 16 and 235.
+//!
+//! Like the probe it reads the stage once the patterns are drawn and before
+//! anything goes on top. A strided subset of the pixels is counted, a few
+//! times a second at most, which is plenty for the shape of the plot and
+//! keeps it cheap at 4K.
+
+use std::time::{Duration, Instant};
+
+use crate::draw::{GLYPH_H, blend_rect, draw_text, fill_rect, put_rgb, text_width};
+
+// Pixels counted per update, at most
+const SAMPLES: usize = 1 << 18;
+
+// Between updates while the pattern animates
+const INTERVAL: Duration = Duration::from_millis(250);
+
+// Bins the plot marks
+const MARKERS: [usize; 2] = [16, 235];
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum HistogramMode {
+    Luma,
+    Rgb,
+}
+
+pub struct Histogram {
+    pub mode: HistogramMode,
+    // Luma, then R, G, B
+    bins: [[u32; 256]; 4],
+    updated: Option<Instant>,
+}
+
+impl Histogram {
+    pub fn new(mode: HistogramMode) -> Self {
+        Self {
+            mode,
+            bins: [[0; 256]; 4],
+            updated: None,
+        }
+    }
+
+    /// Luma, then R, G and B, then off
+    pub fn next(histogram: Option<Self>) -> Option<Self> {
+        match histogram.map(|h| h.mode) {
+            None => Some(Histogram::new(HistogramMode::Luma)),
+            Some(HistogramMode::Luma) => Some(Histogram::new(HistogramMode::Rgb)),
+            Some(HistogramMode::Rgb) => None,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self.mode {
+            HistogramMode::Luma => "luma",
+            HistogramMode::Rgb => "R, G, B",
+        }
+    }
+
+    /// Counts the `w` x `h` stage in `buf`, unless it was counted less than
+    /// INTERVAL ago; `force` counts it anyway, for a frame that will stay up
+    pub fn update(
+        &mut self,
+        buf: &[u8],
+        stride: usize,
+        w: usize,
+        h: usize,
+        now: Instant,
+        force: bool,
+    ) {
+        if !force && self.updated.is_some_and(|at| now < at + INTERVAL) {
+            return;
+        }
+        self.updated = Some(now);
+        self.bins = [[0; 256]; 4];
+        let step = ((w * h) as f64 / SAMPLES as f64).sqrt().ceil().max(1.0) as usize;
+        for row in buf.chunks(stride).take(h).step_by(step) {
+            for px in row[..w * 4].chunks_exact(4).step_by(step) {
+                // B, G, R, X
+                let (r, g, b) = (px[2] as u32, px[1] as u32, px[0] as u32);
+                // BT.709 luma of the code values
+                let y = (2126 * r + 7152 * g + 722 * b + 5000) / 10000;
+                self.bins[0][y as usize] += 1;
+                self.bins[1][r as usize] += 1;
+                self.bins[2][g as usize] += 1;
+                self.bins[3][b as usize] += 1;
+            }
+        }
+    }
+
+    /// Plots the last count in the bottom-right corner, at UI scale `ui`
+    pub fn draw(&self, buf: &mut [u8], stride: usize, w: usize, h: usize, ui: usize) {
+        // A bin is ui / 2 pixels wide, as near as fits in half the screen
+        let mut bin_w = ui.div_ceil(2);
+        while bin_w > 1 && 256 * bin_w > w / 2 {
+            bin_w -= 1;
+        }
+        let text_scale = ui.div_ceil(2);
+        let pad = 2 * ui;
+        let (plot_w, plot_h) = (256 * bin_w, (64 * ui).min(h / 3));
+        let text_h = GLYPH_H * text_scale;
+        let (box_w, box_h) = (plot_w + 2 * pad, plot_h + text_h + 3 * pad);
+        // Clear of the progress bar along the bottom
+        let margin = 4 * ui;
+        if box_w + margin > w || box_h + margin + ui > h {
+            return;
+        }
+        let (bx, by) = (w - box_w - margin, h - box_h - margin - ui);
+        blend_rect(
+            buf,
+            stride,
+            w,
+            h,
+            bx as isize,
+            by as isize,
+            box_w,
+            box_h,
+            0,
+            0,
+            0,
+            192,
+        );
+
+        let (px, py) = (bx + pad, by + pad);
+        let channels = match self.mode {
+            HistogramMode::Luma => &self.bins[..1],
+            HistogramMode::Rgb => &self.bins[1..],
+        };
+        let peak = channels
+            .iter()
+            .flat_map(|c| c.iter())
+            .copied()
+            .max()
+            .unwrap_or(0)
+            .max(1) as u64;
+        let height = |count: u32| (count as u64 * plot_h as u64).div_ceil(peak) as usize;
+        for bin in 0..256 {
+            let x = px + bin * bin_w;
+            match self.mode {
+                HistogramMode::Luma => {
+                    let bar = height(self.bins[0][bin]);
+                    let y = (py + plot_h - bar) as isize;
+                    fill_rect(buf, stride, w, h, x as isize, y, bin_w, bar, 220, 220, 220);
+                }
+                // The channels add where they overlap, so all three shows as
+                // white
+                HistogramMode::Rgb => {
+                    let bars = [1, 2, 3].map(|c| height(self.bins[c][bin]));
+                    for up in 0..bars.iter().copied().max().unwrap_or(0) {
+                        let [r, g, b] = bars.map(|bar| if up < bar { 255 } else { 0 });
+                        for dx in 0..bin_w {
+                            put_rgb(buf, stride, x + dx, py + plot_h - 1 - up, r, g, b);
+                        }
+                    }
+                }
+            }
+        }
+
+        let label_y = py + plot_h + pad;
+        for bin in MARKERS {
+            let x = px + bin * bin_w + bin_w / 2;
+            fill_rect(
+                buf,
+                stride,
+                w,
+                h,
+                x as isize,
+                py as isize,
+                1,
+                plot_h,
+                255,
+                200,
+                0,
+            );
+            let text = bin.to_string();
+            let tx = x.saturating_sub(text_width(&text, text_scale) / 2);
+            draw_text(
+                buf, stride, w, h, tx, label_y, &text, text_scale, 255, 200, 0,
+            );
+        }
+        // Midway, clear of the marker labels
+        let name = self.name();
+        let tx = px + plot_w.saturating_sub(text_width(name, text_scale)) / 2;
+        draw_text(
+            buf, stride, w, h, tx, label_y, name, text_scale, 255, 255, 255,
+        );
+    }
+}
@@ -0,0 +1,307 @@
+//! Further `--card`s: each extra card gets its own surface, script state and
+//! stage, and is drawn and flipped from the main loop alongside the first.
+//! They are addressed remotely as instance 1, 2, ... (the first card is
+//! instance 0) and run their script on their own, wrapping at the end.
+//!
+//! With --sync-outputs they follow the first card's step instead, and are
+//! drawn and flipped only in the same pass as it (see sync.rs).
+//!
+//! An extra card that fails, e.g. unplugged mid-run, is shut down with the
+//! error recorded for the report; the rest of the run carries on.
+
+use anyhow::Result;
+use std::os::fd::{AsFd, BorrowedFd};
+use std::path::PathBuf;
+use std::time::{Duration, Instant};
+
+use crate::cli::StepOverrides;
+use crate::control::{Command, Status};
+use crate::draw::Corner;
+use crate::report::{FlipSummary, InstanceReport, StepLog};
+use crate::surface::{Surface, SurfaceBuilder};
+use crate::sync::draw_sync_strip;
+use crate::{AppState, PatternKind, Step, draw_overlay, draw_pattern};
+
+pub struct Instance {
+    index: usize,
+    device: PathBuf,
+    // None once the card has failed
+    surface: Option<Surface>,
+    connector: String,
+    mode: String,
+    flips: FlipSummary,
+    state: AppState,
+    stage: Vec<u8>,
+    need_redraw: bool,
+    step_log: StepLog,
+    errors: Vec<String>,
+    // --sync-outputs: follow the first card, and the pass of the flip in
+    // progress with the vblank sequence of the flip before it
+    synced: bool,
+    sync_pass: Option<(u64, Option<u32>)>,
+}
+
+impl Instance {
+    pub fn open(
+        index: usize,
+        builder: SurfaceBuilder,
+        device: PathBuf,
+        script: Vec<Step>,
+        origin: Option<Vec<usize>>,
+        overrides: StepOverrides,
+    ) -> Result<Self> {
+        let surface = builder.card(&device).build()?;
+        let mut state = AppState::new()?;
+        state.load_script(script);
+        state.script_origin = origin;
+        state.set_overrides(overrides);
+
+        Ok(Self {
+            index,
+            device,
+            connector: surface.connector_label().to_string(),
+            mode: surface.mode_name(),
+            flips: FlipSummary::from_stats(&surface.flip_stats),
+            stage: vec![0u8; surface.disp_h * surface.stride()],
+            surface: Some(surface),
+            state,
+            need_redraw: true,
+            step_log: StepLog::default(),
+            errors: Vec::new(),
+            synced: false,
+            sync_pass: None,
+        })
+    }
+
+    /// The --ui-scale setting for this card's overlay
+    pub fn ui_scale(mut self, scale: Option<usize>) -> Self {
+        self.state.ui_scale = scale;
+        self
+    }
+
+    /// Leaves stepping and drawing to `follow`, for --sync-outputs
+    pub fn synced(mut self, synced: bool) -> Self {
+        self.synced = synced;
+        self
+    }
+
+    /// Whether a frame could be flipped now; a failed card never holds
+    /// anything up
+    pub fn can_present(&self) -> bool {
+        self.surface.as_ref().is_none_or(|s| !s.is_flipping())
+    }
+
+    pub fn fd(&self) -> Option<BorrowedFd<'_>> {
+        self.surface.as_ref().map(|s| s.card.as_fd())
+    }
+
+    /// The earliest time this instance needs the loop to come round again
+    pub fn deadline(&self, auto: Option<Duration>) -> Option<Instant> {
+        let surface = self.surface.as_ref()?;
+        if self.synced {
+            return surface.flip_deadline();
+        }
+        let wants_frame = (self.need_redraw || self.state.animating()) && !surface.is_flipping();
+        [
+            wants_frame.then(Instant::now),
+            self.state.auto_deadline(auto),
+            surface.flip_deadline(),
+        ]
+        .into_iter()
+        .flatten()
+        .min()
+    }
+
+    /// Services this card's DRM events once its fd is readable
+    pub fn handle_drm_events(&mut self) {
+        if let Some(surface) = &mut self.surface {
+            let result = surface.handle_drm_events();
+            self.check(result);
+        }
+    }
+
+    /// Applies a remote command addressed to this instance. Quit is left to
+    /// the caller, as it ends the whole run.
+    pub fn command(&mut self, cmd: Command) {
+        let state = &mut self.state;
+        match cmd {
+            Command::NextStep => {
+                if state.next_step() {
+                    state.goto_step(0);
+                }
+            }
+            Command::PreviousStep => state.previous_step(),
+            Command::GotoStep(idx) => {
+                if !state.goto_step(idx) {
+                    eprintln!("Remote: instance {} has no step {}", self.index, idx);
+                }
+            }
+            Command::GotoName(name) => match state.step_named(&name) {
+                Some(idx) => {
+                    state.goto_step(idx);
+                }
+                None => eprintln!("Remote: instance {} has no step named {}", self.index, name),
+            },
+            Command::SetSolid(r, g, b) => {
+                state.pattern = PatternKind::Solid;
+                state.custom_solid = Some((r, g, b));
+            }
+            Command::SetTarget(r, g, b) => state.set_solid_target((r, g, b)),
+            Command::Pause(paused) => {
+                if state.paused != paused {
+                    state.toggle_pause();
+                }
+            }
+            Command::Status(reply) => {
+                let _ = reply.send(Status {
+                    step: state.script_idx,
+                    steps: state.script.len(),
+                    pattern: state.pattern,
+                    paused: state.paused,
+                    script: format!("instance {}", self.index),
+                    link: None,
+                });
+            }
+            Command::Quit | Command::Instance(..) => {}
+        }
+        self.need_redraw = true;
+    }
+
+    /// Advances the script when the step's time is up, then draws and flips
+    /// a frame if one is wanted and the last flip has completed
+    pub fn update(&mut self, now: Instant, auto: Option<Duration>) {
+        self.step_log.observe(
+            self.state.script_idx,
+            self.state.original_index(),
+            self.state.step_name(),
+            self.state.pattern,
+            now,
+        );
+        if !self.synced
+            && self
+                .state
+                .auto_deadline(auto)
+                .is_some_and(|deadline| now >= deadline)
+        {
+            if self.state.next_step() {
+                self.state.goto_step(0);
+            }
+            self.need_redraw = true;
+        }
+
+        // A flip whose event never came; an error here disables the card
+        if let Some(surface) = &mut self.surface {
+            let result = surface.expire_flip(now);
+            self.check(result);
+        }
+        if self.synced || !(self.need_redraw || self.state.animating()) {
+            return;
+        }
+        self.draw(now, None);
+    }
+
+    /// --sync-outputs: shows `step` of the script and flips a frame for
+    /// `pass` of the first card's drawing. Returns false if the card is
+    /// still busy with the last one, or has failed.
+    pub fn follow(&mut self, step: usize, pass: u64, now: Instant) -> bool {
+        if self.state.script_idx != step {
+            self.state.goto_step(step);
+        }
+        self.draw(now, Some(pass))
+    }
+
+    /// The pass and vblank of a --sync-outputs flip that has completed since
+    /// the last call
+    pub fn take_presented(&mut self) -> Option<(u64, Duration)> {
+        let surface = self.surface.as_ref()?;
+        if surface.is_flipping() {
+            return None;
+        }
+        let (pass, before) = self.sync_pass.take()?;
+        // A flip given up on leaves the last one's sample
+        let sample = surface.last_flip.filter(|s| Some(s.seq) != before)?;
+        Some((pass, sample.timestamp))
+    }
+
+    fn draw(&mut self, now: Instant, pass: Option<u64>) -> bool {
+        let Some(surface) = &mut self.surface else {
+            return false;
+        };
+        if surface.is_flipping() {
+            return false;
+        }
+
+        let (w, h, stride) = (surface.disp_w, surface.disp_h, surface.stride());
+        let mut overlay = Vec::new();
+        draw_pattern(
+            &mut self.state,
+            surface,
+            &mut self.stage,
+            stride,
+            w,
+            h,
+            now,
+            &mut overlay,
+        );
+        if self.state.labels {
+            overlay.insert(
+                0,
+                format!(
+                    "instance {} step {}/{}: {:?}",
+                    self.index,
+                    self.state.script_idx + 1,
+                    self.state.script.len(),
+                    self.state.pattern
+                ),
+            );
+            let ui = self.state.ui_scale(h);
+            draw_overlay(&mut self.stage, stride, w, h, &overlay, ui, Corner::TopLeft);
+        }
+        if let Some(pass) = pass {
+            draw_sync_strip(&mut self.stage, stride, w, h, self.index, pass);
+        }
+
+        let before = surface.last_flip.map(|s| s.seq);
+        let result = surface
+            .write_to_back(&self.stage, stride)
+            .and_then(|()| surface.flip());
+        self.need_redraw = false;
+        self.sync_pass = pass.map(|pass| (pass, before));
+        let ok = result.is_ok();
+        self.check(result);
+        ok
+    }
+
+    // Shuts the card down on an error, keeping what the report needs
+    fn check(&mut self, result: Result<impl Sized>) {
+        let Err(e) = result else {
+            return;
+        };
+        let msg = format!(
+            "instance {} ({}): {:#}; disabled",
+            self.index,
+            self.device.display(),
+            e
+        );
+        eprintln!("{}", msg);
+        self.errors.push(msg);
+        if let Some(surface) = self.surface.take() {
+            self.flips = FlipSummary::from_stats(&surface.flip_stats);
+        }
+    }
+
+    pub fn report(self, now: Instant) -> InstanceReport {
+        InstanceReport {
+            index: self.index,
+            device: self.device.display().to_string(),
+            connector: self.connector,
+            mode: self.mode,
+            steps: self.step_log.finish(now),
+            flips: match &self.surface {
+                Some(surface) => FlipSummary::from_stats(&surface.flip_stats),
+                None => self.flips,
+            },
+            errors: self.errors,
+        }
+    }
+}
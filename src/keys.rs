@@ -0,0 +1,872 @@
+//! The keyboard of the main loop: what each key press does to the pattern
+//! state and to the operator's view around it. Keys that only mean
+//! something on one pattern are in `pattern_key`; anything nobody has
+//! claimed moves on to the next step.
+
+use anyhow::Result;
+use drm::control::Mode;
+use evdev::KeyCode;
+use std::time::Instant;
+
+use crate::cli::Args;
+use crate::draw::{Corner, SubpixelOrder};
+use crate::histogram::Histogram;
+use crate::library::ScriptLibrary;
+use crate::motion::Mover;
+use crate::probe::Probe;
+use crate::{
+    AppState, ChannelMask, G2G_MAX_LEVELS, GradMode, LINE_COLORS, NEAR_BLACK_MAX, Param,
+    PatternKind, QuickFill, checker_pair_name, next_checker_colors, next_diff_gain, next_matte,
+    next_step_seen,
+};
+
+/// What the keys change outside the pattern state
+pub struct Controls {
+    /// Script library entry in use
+    pub active_script: usize,
+    /// Selected entry while the script menu is open
+    pub menu: Option<usize>,
+    /// Pixel readout under a movable reticle, while probe mode is on
+    pub probe: Option<Probe>,
+    /// F7's histogram of the stage, while it's shown
+    pub histogram: Option<Histogram>,
+    pub shift: bool,
+    /// Around the --logical rectangle; F2 changes it
+    pub matte: (u8, u8, u8),
+    /// Shift+L moves the status overlay round the corners
+    pub overlay_corner: Corner,
+    /// In split mode the other half's state; `state` is always the focused
+    /// half, so every key binding works on whichever side is being edited
+    pub split: Option<AppState>,
+    pub focus_right: bool,
+    /// Shift+D in split mode: show how the two patterns differ instead, each
+    /// channel's difference multiplied by this
+    pub diff_gain: Option<u32>,
+    /// Debug: redraw and flip every frame regardless of need_redraw, to tell
+    /// a pattern that fails to request redraws from a timing problem
+    pub always_redraw: bool,
+    /// Debug: the last few flip events' vblank timestamps and sequence
+    /// numbers in the top-right corner
+    pub flip_log: bool,
+    /// F8 turns off the marker that flags a late frame
+    pub late_marker: bool,
+    /// When the key hints were last put up: at the start, and again after a
+    /// key that has no job of its own
+    pub hints_shown: Option<Instant>,
+    /// --compare-mode: whether the second mode is showing, and a switch
+    /// waiting for the flip in flight to finish
+    pub compare_b: bool,
+    pub pending_mode: Option<Mode>,
+    /// The level the backlight is kept at outside the sweep step
+    pub backlight_level: Option<u32>,
+}
+
+impl Controls {
+    pub fn new(args: &Args, active_script: usize, backlight_level: Option<u32>) -> Self {
+        Controls {
+            active_script,
+            menu: None,
+            probe: None,
+            histogram: None,
+            shift: false,
+            matte: args.matte,
+            overlay_corner: args.overlay_corner,
+            split: None,
+            focus_right: false,
+            diff_gain: None,
+            always_redraw: false,
+            flip_log: false,
+            late_marker: true,
+            hints_shown: (!args.no_hints && !args.attract).then(Instant::now),
+            compare_b: false,
+            pending_mode: None,
+            backlight_level,
+        }
+    }
+}
+
+/// What a key press can see but not change
+pub struct KeyContext<'a> {
+    pub args: &'a Args,
+    pub library: &'a ScriptLibrary,
+    pub disp_w: usize,
+    pub disp_h: usize,
+    /// What the connector reports, for stepping on from it
+    pub subpixel: Option<SubpixelOrder>,
+    /// --compare-mode's two modes
+    pub compare_modes: Option<[Mode; 2]>,
+    /// Whether there is a backlight to adjust
+    pub backlight: bool,
+    /// Step of the last frame presented
+    pub shown_step: Option<usize>,
+    /// Whether the script starts over after its last step
+    pub looping: bool,
+}
+
+/// Acts on a key press. True when it ends the run.
+pub fn handle_key(
+    code: KeyCode,
+    state: &mut AppState,
+    controls: &mut Controls,
+    ctx: &KeyContext,
+) -> Result<bool> {
+    if let Some(sel) = &mut controls.menu {
+        let n = ctx.library.scripts.len();
+        match code {
+            KeyCode::KEY_UP => *sel = (*sel + n - 1) % n,
+            KeyCode::KEY_DOWN => *sel = (*sel + 1) % n,
+            KeyCode::KEY_ENTER => {
+                controls.active_script = *sel;
+                state.load_script(ctx.library.scripts[controls.active_script].steps.clone());
+                controls.menu = None;
+            }
+            KeyCode::KEY_ESC | KeyCode::KEY_O => controls.menu = None,
+            _ => {}
+        }
+        return Ok(false);
+    }
+
+    if let Some(digits) = &mut state.hex_entry {
+        match code {
+            KeyCode::KEY_BACKSPACE => {
+                digits.pop();
+            }
+            KeyCode::KEY_ENTER if digits.len() == 6 => {
+                let v = u32::from_str_radix(digits, 16).unwrap_or(0);
+                state.hex_entry = None;
+                state.set_solid_target(((v >> 16) as u8, (v >> 8) as u8, v as u8));
+            }
+            KeyCode::KEY_ESC => state.hex_entry = None,
+            _ => {
+                if let Some(c) = hex_digit(code)
+                    && digits.len() < 6
+                {
+                    digits.push(c);
+                }
+            }
+        }
+        return Ok(false);
+    }
+
+    // Q still quits from the menu; every other key is the menu's
+    if state.menu.is_some() && code != KeyCode::KEY_Q {
+        state.menu_key(code);
+        return Ok(false);
+    }
+
+    // The arrows move the probe; everything else works as usual
+    if let Some(probe) = &mut controls.probe
+        && probe.key(code, controls.shift, ctx.disp_w, ctx.disp_h)
+    {
+        return Ok(false);
+    }
+
+    match code {
+        KeyCode::KEY_Q | KeyCode::KEY_ESC => return Ok(true),
+        // Done at the checkpoint: on with the run
+        KeyCode::KEY_ENTER if state.held => {
+            if next_step_seen(state, ctx.shown_step, ctx.looping) {
+                return Ok(true);
+            }
+        }
+        KeyCode::KEY_RIGHT if state.param_nav => {
+            state.step_param(true);
+        }
+        KeyCode::KEY_LEFT if state.param_nav => {
+            state.step_param(false);
+        }
+        // A second press finishes the fade into a step
+        // instead of leaving it
+        KeyCode::KEY_RIGHT | KeyCode::KEY_SPACE | KeyCode::KEY_LEFT if state.fade.is_some() => {
+            state.fade = None;
+        }
+        KeyCode::KEY_RIGHT | KeyCode::KEY_SPACE => {
+            if next_step_seen(state, ctx.shown_step, ctx.looping) {
+                return Ok(true);
+            }
+        }
+        KeyCode::KEY_LEFT => {
+            state.previous_step();
+        }
+        KeyCode::KEY_P => {
+            state.toggle_pause();
+        }
+        KeyCode::KEY_S => {
+            if let Some(path) = &ctx.args.save_session {
+                match state.session().save(path) {
+                    Ok(()) => eprintln!("Saved session: {}", path.display()),
+                    Err(e) => eprintln!("{:#}", e),
+                }
+            }
+        }
+        KeyCode::KEY_L if controls.shift => {
+            controls.overlay_corner = controls.overlay_corner.next();
+            state.show_readout(format!("overlay {}", controls.overlay_corner.name()));
+        }
+        KeyCode::KEY_L => {
+            state.labels = !state.labels;
+        }
+        KeyCode::KEY_K => {
+            state.progress = !state.progress;
+        }
+        KeyCode::KEY_Z => {
+            state.quick_fill = QuickFill::next(state.quick_fill);
+        }
+        KeyCode::KEY_LEFTBRACE | KeyCode::KEY_RIGHTBRACE => {
+            state.quick_fill = None;
+            state.step_quick_solid(code == KeyCode::KEY_RIGHTBRACE);
+        }
+        KeyCode::KEY_BACKSLASH => {
+            state.quick_solid = None;
+        }
+        KeyCode::KEY_N => {
+            state.param_nav = !state.param_nav;
+        }
+        KeyCode::KEY_U => match state.next_bookmark() {
+            Some(name) => state.show_readout(name),
+            None => state.show_readout("no named steps".to_string()),
+        },
+        KeyCode::KEY_I if controls.shift => {
+            let hz = state
+                .blink
+                .map_or(ctx.args.blink_inverse.or(Some(1.0)), |_| None);
+            state.set_blink(hz);
+        }
+        KeyCode::KEY_MINUS | KeyCode::KEY_EQUAL if state.blink.is_some() => {
+            let d = if code == KeyCode::KEY_EQUAL {
+                0.5
+            } else {
+                -0.5
+            };
+            state.set_blink(state.blink.map(|(hz, _)| hz + d));
+        }
+        KeyCode::KEY_I => {
+            controls.probe = match controls.probe {
+                Some(_) => None,
+                None => Some(Probe::new(ctx.disp_w, ctx.disp_h)),
+            };
+        }
+        // Deliberately left out of the help text
+        KeyCode::KEY_F12 => {
+            controls.always_redraw = !controls.always_redraw;
+            state.show_readout(
+                if controls.always_redraw {
+                    "redraw: every frame"
+                } else {
+                    "redraw: event-driven"
+                }
+                .to_string(),
+            );
+        }
+        // Also left out of the help text
+        KeyCode::KEY_F11 => {
+            controls.flip_log = !controls.flip_log;
+        }
+        KeyCode::KEY_O => {
+            controls.menu = Some(controls.active_script);
+        }
+        KeyCode::KEY_F1 => {
+            state.menu = Some(state.script_idx);
+        }
+        // The checklist: mark the step and move on
+        KeyCode::KEY_F9 | KeyCode::KEY_F10 => {
+            let pass = code == KeyCode::KEY_F9;
+            state.mark_step(pass);
+            eprintln!(
+                "{}: {}",
+                state.step_label(),
+                if pass { "PASS" } else { "FAIL" }
+            );
+            if next_step_seen(state, ctx.shown_step, ctx.looping) {
+                return Ok(true);
+            }
+        }
+        KeyCode::KEY_F2 if ctx.args.logical.is_some() => {
+            controls.matte = next_matte(ctx.args, controls.matte);
+            let (r, g, b) = controls.matte;
+            state.show_readout(format!("matte #{:02x}{:02x}{:02x}", r, g, b));
+        }
+        KeyCode::KEY_Y => {
+            state.output_range = state.output_range.next();
+            state.show_readout(format!("{} range", state.output_range.name()));
+        }
+        KeyCode::KEY_F5 | KeyCode::KEY_F6 if ctx.backlight => {
+            let step = if controls.shift { 1 } else { 10 };
+            let level = controls.backlight_level.unwrap_or(100);
+            let level = match code {
+                KeyCode::KEY_F5 => level.saturating_sub(step),
+                _ => (level + step).min(100),
+            };
+            controls.backlight_level = Some(level);
+            state.show_readout(format!("backlight {}%", level));
+        }
+        KeyCode::KEY_F7 => {
+            controls.histogram = Histogram::next(controls.histogram.take());
+            state.show_readout(format!(
+                "histogram: {}",
+                controls.histogram.as_ref().map_or("off", Histogram::name)
+            ));
+        }
+        KeyCode::KEY_F8 => {
+            controls.late_marker = !controls.late_marker;
+            state.show_readout(format!(
+                "late frame marker {}",
+                if controls.late_marker { "on" } else { "off" }
+            ));
+        }
+        KeyCode::KEY_F4 => {
+            state.swap_rb = !state.swap_rb;
+            state.show_readout(format!(
+                "red/blue swap {}",
+                if state.swap_rb { "on" } else { "off" }
+            ));
+        }
+        KeyCode::KEY_F3 => {
+            state.double_range = state.double_range.next();
+            state.show_readout(format!("double conversion: {}", state.double_range.name()));
+        }
+        KeyCode::KEY_7 | KeyCode::KEY_8 | KeyCode::KEY_9 | KeyCode::KEY_0 => {
+            state.channel_mask = match code {
+                KeyCode::KEY_7 => ChannelMask::Red,
+                KeyCode::KEY_8 => ChannelMask::Green,
+                KeyCode::KEY_9 => ChannelMask::Blue,
+                _ => ChannelMask::All,
+            };
+            state.show_readout(state.channel_mask.name().to_string());
+        }
+        KeyCode::KEY_M => {
+            state.matrix = state.matrix.next();
+            state.show_readout(format!("matrix {}", state.matrix.name()));
+        }
+        KeyCode::KEY_D if controls.shift && controls.split.is_some() => {
+            controls.diff_gain = next_diff_gain(controls.diff_gain);
+            state.show_readout(match controls.diff_gain {
+                Some(gain) => format!("difference x{}", gain),
+                None => "side by side".to_string(),
+            });
+        }
+        KeyCode::KEY_D => {
+            controls.split = match controls.split {
+                Some(_) => None,
+                None => {
+                    // The new half starts from the script but keeps
+                    // the command line's overrides
+                    let mut other = AppState::new()?;
+                    other.set_overrides(state.overrides);
+                    other.invert = state.invert;
+                    other.blink = state.blink;
+                    other.allow_flashing = state.allow_flashing;
+                    other.channel_mask = state.channel_mask;
+                    other.swap_rb = state.swap_rb;
+                    other.quarter_turn = state.quarter_turn;
+                    other.ui_scale = state.ui_scale;
+                    other.output_range = state.output_range;
+                    other.double_range = state.double_range;
+                    Some(other)
+                }
+            };
+            controls.focus_right = false;
+            controls.diff_gain = None;
+        }
+        KeyCode::KEY_X => {
+            if let Some(modes) = ctx.compare_modes {
+                controls.pending_mode = Some(modes[!controls.compare_b as usize]);
+            }
+        }
+        KeyCode::KEY_TAB => {
+            if let Some(other) = &mut controls.split {
+                std::mem::swap(state, other);
+                controls.focus_right = !controls.focus_right;
+            }
+        }
+        _ if pattern_key(state, code, controls.shift, ctx.subpixel) => {}
+        _ => {
+            if !ctx.args.no_hints {
+                controls.hints_shown = Some(Instant::now());
+            }
+            if next_step_seen(state, ctx.shown_step, ctx.looping) {
+                return Ok(true);
+            }
+        }
+    }
+    Ok(false)
+}
+
+// The keys that belong to the pattern showing. False if `code` is not one
+// of them there.
+fn pattern_key(
+    state: &mut AppState,
+    code: KeyCode,
+    shift: bool,
+    subpixel: Option<SubpixelOrder>,
+) -> bool {
+    match code {
+        KeyCode::KEY_G if matches!(state.pattern, PatternKind::Gradient | PatternKind::Radial) => {
+            state.grad_light = !state.grad_light;
+            state.show_readout(
+                if state.grad_light {
+                    "linear light"
+                } else {
+                    "linear code"
+                }
+                .to_string(),
+            );
+        }
+        KeyCode::KEY_C if matches!(state.pattern, PatternKind::Gradient) => {
+            state.grad_mode = state.grad_mode.next();
+            state.show_readout(
+                match state.grad_mode {
+                    GradMode::Luma => "grey ramp",
+                    GradMode::Rgb => "red, green and blue ramps",
+                }
+                .to_string(),
+            );
+        }
+        KeyCode::KEY_T if matches!(state.pattern, PatternKind::Gradient) => {
+            state.grad_ticks = !state.grad_ticks;
+            state.show_readout(format!(
+                "code value ticks {}",
+                if state.grad_ticks { "on" } else { "off" }
+            ));
+        }
+        KeyCode::KEY_UP if matches!(state.pattern, PatternKind::Radial) => {
+            state.radial.exponent = (state.radial.exponent + 0.25).min(8.0);
+            state.show_readout(format!("exponent {:.2}", state.radial.exponent));
+        }
+        KeyCode::KEY_DOWN if matches!(state.pattern, PatternKind::Radial) => {
+            state.radial.exponent = (state.radial.exponent - 0.25).max(0.25);
+            state.show_readout(format!("exponent {:.2}", state.radial.exponent));
+        }
+        KeyCode::KEY_UP if matches!(state.pattern, PatternKind::HueSweep) => {
+            state.hue.value = ((state.hue.value + 0.05) * 20.0).round() / 20.0;
+            state.hue.value = state.hue.value.min(1.0);
+            state.show_readout(format!("value {:.0}%", state.hue.value * 100.0));
+        }
+        KeyCode::KEY_DOWN if matches!(state.pattern, PatternKind::HueSweep) => {
+            state.hue.value = ((state.hue.value - 0.05) * 20.0).round() / 20.0;
+            state.hue.value = state.hue.value.max(0.0);
+            state.show_readout(format!("value {:.0}%", state.hue.value * 100.0));
+        }
+        KeyCode::KEY_PAGEUP if matches!(state.pattern, PatternKind::HueSweep) => {
+            let s = ((state.hue.saturation + 0.05) * 20.0).round() / 20.0;
+            state.hue.saturation = s.min(1.0);
+            state.show_readout(format!("saturation {:.0}%", state.hue.saturation * 100.0));
+        }
+        KeyCode::KEY_PAGEDOWN if matches!(state.pattern, PatternKind::HueSweep) => {
+            let s = ((state.hue.saturation - 0.05) * 20.0).round() / 20.0;
+            state.hue.saturation = s.max(0.0);
+            state.show_readout(format!("saturation {:.0}%", state.hue.saturation * 100.0));
+        }
+        KeyCode::KEY_V if matches!(state.pattern, PatternKind::HueSweep) => {
+            state.hue.radial = !state.hue.radial;
+            state.show_readout(
+                if state.hue.radial {
+                    "around the center"
+                } else {
+                    "left to right"
+                }
+                .to_string(),
+            );
+        }
+        KeyCode::KEY_G if matches!(state.pattern, PatternKind::GamutCorners) => {
+            state.gamut = state.gamut.next();
+            state.show_readout(format!("{} target", state.gamut.name()));
+        }
+        KeyCode::KEY_V if matches!(state.pattern, PatternKind::EyeAlignment) => {
+            state.eyes.layout = state.eyes.layout.next();
+            state.eyes.split = 0.5;
+            state.show_readout(state.eyes.layout.name().to_string());
+        }
+        KeyCode::KEY_UP | KeyCode::KEY_DOWN
+            if matches!(state.pattern, PatternKind::EyeAlignment) =>
+        {
+            let d = if code == KeyCode::KEY_UP { 1 } else { -1 };
+            if shift {
+                state.eyes.disparity_y += d;
+            } else {
+                state.eyes.disparity_x += d;
+            }
+            state.show_readout(format!(
+                "disparity {:+} x {:+} y px",
+                state.eyes.disparity_x, state.eyes.disparity_y
+            ));
+        }
+        KeyCode::KEY_PAGEUP | KeyCode::KEY_PAGEDOWN
+            if matches!(state.pattern, PatternKind::EyeAlignment) =>
+        {
+            let d = if code == KeyCode::KEY_PAGEUP {
+                0.01
+            } else {
+                -0.01
+            };
+            let split = ((state.eyes.split + d) * 100.0).round() / 100.0;
+            state.eyes.split = split.clamp(0.1, 0.9);
+            state.show_readout(format!("split at {:.0}%", state.eyes.split * 100.0));
+        }
+        KeyCode::KEY_V if matches!(state.pattern, PatternKind::Radial) => {
+            state.radial.inverse = !state.radial.inverse;
+            state.show_readout(
+                if state.radial.inverse {
+                    "bright edges"
+                } else {
+                    "bright center"
+                }
+                .to_string(),
+            );
+        }
+        KeyCode::KEY_C if matches!(state.pattern, PatternKind::BitDepth) => {
+            state.bits_channels = state.bits_channels.next();
+            state.show_readout(state.bits_channels.name().to_string());
+        }
+        KeyCode::KEY_C if matches!(state.pattern, PatternKind::Radial) => {
+            state.radial.channels = state.radial.channels.next();
+            state.show_readout(state.radial.channels.name().to_string());
+        }
+        KeyCode::KEY_J
+            if matches!(
+                state.pattern,
+                PatternKind::FlipSequence
+                    | PatternKind::PixelInversion
+                    | PatternKind::GrayToGray
+                    | PatternKind::Stereo
+            ) =>
+        {
+            state.phase_offset += 1;
+            state.show_readout(format!(
+                "phase offset {} of {} flips",
+                state.phase_offset % state.flicker_cycle(),
+                state.flicker_cycle()
+            ));
+        }
+        KeyCode::KEY_UP if matches!(state.pattern, PatternKind::IntegerScale) => {
+            state.int_scale = (state.int_scale + 1).min(4);
+            state.show_readout(format!("{}x blocks", state.int_scale));
+        }
+        KeyCode::KEY_DOWN if matches!(state.pattern, PatternKind::IntegerScale) => {
+            state.int_scale = (state.int_scale - 1).max(2);
+            state.show_readout(format!("{}x blocks", state.int_scale));
+        }
+        KeyCode::KEY_UP if matches!(state.pattern, PatternKind::GrayToGray) => {
+            state.g2g_levels = (state.g2g_levels + 1).min(G2G_MAX_LEVELS);
+            state.show_readout(format!("{} gray levels", state.g2g_levels));
+        }
+        KeyCode::KEY_DOWN if matches!(state.pattern, PatternKind::GrayToGray) => {
+            state.g2g_levels = (state.g2g_levels - 1).max(2);
+            state.show_readout(format!("{} gray levels", state.g2g_levels));
+        }
+        KeyCode::KEY_UP if matches!(state.pattern, PatternKind::NearBlack) => {
+            state.near_black = (state.near_black + 1).min(NEAR_BLACK_MAX);
+            state.show_readout(format!("base level {}", state.near_black));
+        }
+        KeyCode::KEY_DOWN if matches!(state.pattern, PatternKind::NearBlack) => {
+            state.near_black = (state.near_black - 1).max(1);
+            state.show_readout(format!("base level {}", state.near_black));
+        }
+        KeyCode::KEY_A if matches!(state.pattern, PatternKind::Keystone) => {
+            state.keystone.aspect = state.keystone.aspect.next();
+            state.show_readout(format!("target {}", state.keystone.aspect.name()));
+        }
+        KeyCode::KEY_B if matches!(state.pattern, PatternKind::Keystone) => {
+            state.keystone.blink = !state.keystone.blink;
+            state.show_readout(format!(
+                "outline blink {}",
+                if state.keystone.blink { "on" } else { "off" }
+            ));
+        }
+        KeyCode::KEY_C if matches!(state.pattern, PatternKind::Focus) => {
+            state.focus.green = !state.focus.green;
+            state.show_readout(if state.focus.green { "green" } else { "white" }.to_string());
+        }
+        KeyCode::KEY_R if matches!(state.pattern, PatternKind::Focus) => {
+            state.focus.rings = !state.focus.rings;
+            state.show_readout(
+                if state.focus.rings {
+                    "circles"
+                } else {
+                    "checker"
+                }
+                .to_string(),
+            );
+        }
+        KeyCode::KEY_A if matches!(state.pattern, PatternKind::Motion) => {
+            state.motion_aa = !state.motion_aa;
+            state.show_readout(format!(
+                "anti-aliasing {}",
+                if state.motion_aa { "on" } else { "off" }
+            ));
+        }
+        KeyCode::KEY_E if matches!(state.pattern, PatternKind::Motion | PatternKind::LineSweep) => {
+            state.edge = state.edge.next();
+            state.show_readout(format!("edge: {}", state.edge.name()));
+        }
+        KeyCode::KEY_UP if matches!(state.pattern, PatternKind::RampScroll) => {
+            state.ramp.speed = state.ramp.speed.saturating_mul(2);
+            state.show_readout(format!("ramp speed {} px/s", state.ramp.speed));
+        }
+        KeyCode::KEY_DOWN if matches!(state.pattern, PatternKind::RampScroll) => {
+            state.ramp.speed = (state.ramp.speed / 2).max(1);
+            state.show_readout(format!("ramp speed {} px/s", state.ramp.speed));
+        }
+        KeyCode::KEY_UP if matches!(state.pattern, PatternKind::LineSweep) => {
+            state.line.speed = state.line.speed.saturating_mul(2);
+            state.show_readout(format!("line speed {} px/s", state.line.speed));
+        }
+        KeyCode::KEY_DOWN if matches!(state.pattern, PatternKind::LineSweep) => {
+            state.line.speed = (state.line.speed / 2).max(1);
+            state.show_readout(format!("line speed {} px/s", state.line.speed));
+        }
+        KeyCode::KEY_PAGEUP if matches!(state.pattern, PatternKind::LineSweep) => {
+            state.line.width += 1;
+            state.show_readout(format!("line width {} px", state.line.width));
+        }
+        KeyCode::KEY_PAGEDOWN if matches!(state.pattern, PatternKind::LineSweep) => {
+            state.line.width = (state.line.width - 1).max(1);
+            state.show_readout(format!("line width {} px", state.line.width));
+        }
+        KeyCode::KEY_V if matches!(state.pattern, PatternKind::LineSweep) => {
+            state.line.vertical = !state.line.vertical;
+            state.line_mover = Mover::default();
+            state.show_readout(
+                if state.line.vertical {
+                    "sweep left to right"
+                } else {
+                    "sweep top to bottom"
+                }
+                .to_string(),
+            );
+        }
+        KeyCode::KEY_W if matches!(state.pattern, PatternKind::Checker) => {
+            state.checker_sweep = match state.checker_sweep {
+                Some(_) => None,
+                None => Some(state.current_step().checker_sweep.unwrap_or_default()),
+            };
+            state.sweep_t = 0.0;
+            state.sweep_last = None;
+            state.show_readout(format!(
+                "cell sweep {}",
+                if state.checker_sweep.is_some() {
+                    "on"
+                } else {
+                    "off"
+                }
+            ));
+        }
+        KeyCode::KEY_C if matches!(state.pattern, PatternKind::Checker) => {
+            state.checker_colors =
+                next_checker_colors(state.checker_colors, state.current_step().checker_colors);
+            state.show_readout(format!(
+                "checker {}",
+                checker_pair_name(state.checker_colors)
+            ));
+        }
+        KeyCode::KEY_C if matches!(state.pattern, PatternKind::LineSweep) => {
+            let next = LINE_COLORS
+                .iter()
+                .position(|&c| c == state.line.color)
+                .map_or(0, |i| (i + 1) % LINE_COLORS.len());
+            state.line.color = LINE_COLORS[next];
+            let (r, g, b) = state.line.color;
+            state.show_readout(format!("line color {} {} {}", r, g, b));
+        }
+        KeyCode::KEY_C if matches!(state.pattern, PatternKind::SubpixelText) => {
+            let current = state.subpixel.or(subpixel);
+            let next = SubpixelOrder::ALL
+                .iter()
+                .position(|&o| Some(o) == current)
+                .map_or(0, |i| (i + 1) % SubpixelOrder::ALL.len());
+            state.subpixel = Some(SubpixelOrder::ALL[next]);
+            state.show_readout(format!(
+                "subpixel order {}",
+                SubpixelOrder::ALL[next].name()
+            ));
+        }
+        KeyCode::KEY_C if matches!(state.pattern, PatternKind::FlipSequence) => {
+            state.seq_colors = state.seq_colors.next();
+            state.show_readout(format!(
+                "flip colors {}",
+                Param::SeqColors(state.seq_colors).value_label()
+            ));
+        }
+        KeyCode::KEY_UP if matches!(state.pattern, PatternKind::FlipSequence) => {
+            state.seq_hold += 1;
+            state.show_readout(format!("flips per color {}", state.seq_hold));
+        }
+        KeyCode::KEY_DOWN if matches!(state.pattern, PatternKind::FlipSequence) => {
+            state.seq_hold = (state.seq_hold - 1).max(1);
+            state.show_readout(format!("flips per color {}", state.seq_hold));
+        }
+        KeyCode::KEY_H if matches!(state.pattern, PatternKind::Solid) => {
+            state.hex_entry = Some(String::new());
+        }
+        KeyCode::KEY_R if matches!(state.pattern, PatternKind::Solid) => {
+            state.solid_channel = 0;
+        }
+        KeyCode::KEY_G if matches!(state.pattern, PatternKind::Solid) => {
+            state.solid_channel = 1;
+        }
+        KeyCode::KEY_B if matches!(state.pattern, PatternKind::Solid) => {
+            state.solid_channel = 2;
+        }
+        KeyCode::KEY_UP if matches!(state.pattern, PatternKind::Solid) => {
+            state.nudge_solid(1);
+        }
+        KeyCode::KEY_DOWN if matches!(state.pattern, PatternKind::Solid) => {
+            state.nudge_solid(-1);
+        }
+        KeyCode::KEY_R if matches!(state.pattern, PatternKind::Oscillator) => {
+            state.osc_channel = 0;
+            state.show_osc_readout();
+        }
+        KeyCode::KEY_G if matches!(state.pattern, PatternKind::Oscillator) => {
+            state.osc_channel = 1;
+            state.show_osc_readout();
+        }
+        KeyCode::KEY_B if matches!(state.pattern, PatternKind::Oscillator) => {
+            state.osc_channel = 2;
+            state.show_osc_readout();
+        }
+        KeyCode::KEY_UP if matches!(state.pattern, PatternKind::Oscillator) => {
+            let f = &mut state.osc[state.osc_channel];
+            f.freq_hz += 0.1;
+            state.show_osc_readout();
+        }
+        KeyCode::KEY_DOWN if matches!(state.pattern, PatternKind::Oscillator) => {
+            let f = &mut state.osc[state.osc_channel];
+            f.freq_hz = (f.freq_hz - 0.1).max(0.0);
+            state.show_osc_readout();
+        }
+        KeyCode::KEY_PAGEUP if matches!(state.pattern, PatternKind::Oscillator) => {
+            let f = &mut state.osc[state.osc_channel];
+            f.phase = (f.phase + 1.0 / 12.0).rem_euclid(1.0);
+            state.show_osc_readout();
+        }
+        KeyCode::KEY_PAGEDOWN if matches!(state.pattern, PatternKind::Oscillator) => {
+            let f = &mut state.osc[state.osc_channel];
+            f.phase = (f.phase - 1.0 / 12.0).rem_euclid(1.0);
+            state.show_osc_readout();
+        }
+        _ => return false,
+    }
+    true
+}
+
+// The hex digit a key types, for target entry
+fn hex_digit(code: KeyCode) -> Option<char> {
+    let c = match code {
+        KeyCode::KEY_0 => '0',
+        KeyCode::KEY_1 => '1',
+        KeyCode::KEY_2 => '2',
+        KeyCode::KEY_3 => '3',
+        KeyCode::KEY_4 => '4',
+        KeyCode::KEY_5 => '5',
+        KeyCode::KEY_6 => '6',
+        KeyCode::KEY_7 => '7',
+        KeyCode::KEY_8 => '8',
+        KeyCode::KEY_9 => '9',
+        KeyCode::KEY_A => 'A',
+        KeyCode::KEY_B => 'B',
+        KeyCode::KEY_C => 'C',
+        KeyCode::KEY_D => 'D',
+        KeyCode::KEY_E => 'E',
+        KeyCode::KEY_F => 'F',
+        _ => return None,
+    };
+    Some(c)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Step;
+
+    fn steps(pats: &[PatternKind]) -> AppState {
+        let mut state = AppState::new().unwrap();
+        state.load_script(
+            pats.iter()
+                .map(|&pat| Step {
+                    pat,
+                    ..Default::default()
+                })
+                .collect(),
+        );
+        state
+    }
+
+    fn press(code: KeyCode, state: &mut AppState, controls: &mut Controls) -> bool {
+        let args = Args::default();
+        let library = ScriptLibrary::builtin();
+        let ctx = KeyContext {
+            args: &args,
+            library: &library,
+            disp_w: 640,
+            disp_h: 480,
+            subpixel: None,
+            compare_modes: None,
+            backlight: false,
+            shown_step: Some(state.script_idx),
+            looping: false,
+        };
+        handle_key(code, state, controls, &ctx).unwrap()
+    }
+
+    #[test]
+    fn pattern_keys_only_act_on_their_pattern() {
+        let mut state = steps(&[
+            PatternKind::Gradient,
+            PatternKind::Checker,
+            PatternKind::Solid,
+        ]);
+        let mut controls = Controls::new(&Args::default(), 0, None);
+        controls.hints_shown = None;
+
+        // T is the gradient's tick marks...
+        let ticks = state.grad_ticks;
+        assert!(!press(KeyCode::KEY_T, &mut state, &mut controls));
+        assert_eq!(state.grad_ticks, !ticks);
+        assert_eq!(state.script_idx, 0);
+        assert!(controls.hints_shown.is_none());
+
+        // ...and means nothing on the checker, so it moves on and brings
+        // the hints back
+        state.next_step();
+        let ticks = state.grad_ticks;
+        assert!(!press(KeyCode::KEY_T, &mut state, &mut controls));
+        assert_eq!(state.grad_ticks, ticks);
+        assert_eq!(state.script_idx, 2);
+        assert!(controls.hints_shown.is_some());
+    }
+
+    #[test]
+    fn open_menus_take_the_keys() {
+        let mut state = steps(&[PatternKind::Solid, PatternKind::Checker]);
+        let mut controls = Controls::new(&Args::default(), 0, None);
+
+        // The script menu: arrows move the selection, not the step
+        controls.menu = Some(0);
+        assert!(!press(KeyCode::KEY_RIGHT, &mut state, &mut controls));
+        assert!(!press(KeyCode::KEY_Q, &mut state, &mut controls));
+        assert_eq!(state.script_idx, 0);
+        assert!(!press(KeyCode::KEY_ESC, &mut state, &mut controls));
+        assert_eq!(controls.menu, None);
+
+        // Hex entry on a solid takes digits until Enter
+        assert!(!press(KeyCode::KEY_H, &mut state, &mut controls));
+        for code in [
+            KeyCode::KEY_1,
+            KeyCode::KEY_2,
+            KeyCode::KEY_3,
+            KeyCode::KEY_4,
+            KeyCode::KEY_A,
+            KeyCode::KEY_B,
+        ] {
+            assert!(!press(code, &mut state, &mut controls));
+        }
+        assert_eq!(state.hex_entry.as_deref(), Some("1234AB"));
+        assert!(!press(KeyCode::KEY_ENTER, &mut state, &mut controls));
+        assert_eq!(state.hex_entry, None);
+        assert_eq!(state.script_idx, 0);
+
+        // With nothing open, Q ends the run
+        assert!(press(KeyCode::KEY_Q, &mut state, &mut controls));
+    }
+}
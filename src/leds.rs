@@ -0,0 +1,79 @@
+use anyhow::{Context, Result, ensure};
+use evdev::{AttributeSet, Device as EvDev, EventType, InputEvent, LedCode};
+use std::path::Path;
+use std::time::{Duration, Instant};
+
+const BLINK_PERIOD: Duration = Duration::from_millis(500);
+
+/// Uses the keyboard LEDs to signal tool state so the operator doesn't have to
+/// look away from the panel: Scroll Lock is lit while paused and blinks while
+/// auto-advance is running. The original LED state is restored on drop.
+pub struct Leds {
+    dev: EvDev,
+    saved: AttributeSet<LedCode>,
+    scroll: Option<bool>,
+    epoch: Instant,
+}
+
+impl Leds {
+    pub fn open(path: &Path) -> Result<Self> {
+        // A second handle on the keyboard so LED writes don't interfere with
+        // event reads on the main one
+        let dev = EvDev::open(path)
+            .with_context(|| format!("could not open {} for LEDs", path.display()))?;
+
+        let has_scroll = dev
+            .supported_leds()
+            .is_some_and(|leds| leds.contains(LedCode::LED_SCROLLL));
+        ensure!(has_scroll, "keyboard has no Scroll Lock LED");
+
+        let saved = dev.get_led_state().context("could not read LED state")?;
+
+        Ok(Self {
+            dev,
+            saved,
+            scroll: None,
+            epoch: Instant::now(),
+        })
+    }
+
+    /// When the LEDs next need updating, if they are animating at all.
+    pub fn next_change(&self, paused: bool, auto_advancing: bool) -> Option<Instant> {
+        if paused || !auto_advancing {
+            return None;
+        }
+        let period = BLINK_PERIOD.as_millis();
+        let next = (self.epoch.elapsed().as_millis() / period + 1) * period;
+        Some(self.epoch + Duration::from_millis(next as u64))
+    }
+
+    pub fn update(&mut self, paused: bool, auto_advancing: bool) {
+        let on = if paused {
+            true
+        } else if auto_advancing {
+            let phase = self.epoch.elapsed().as_millis() / BLINK_PERIOD.as_millis();
+            phase.is_multiple_of(2)
+        } else {
+            false
+        };
+
+        if self.scroll != Some(on) {
+            self.set(LedCode::LED_SCROLLL, on);
+            self.scroll = Some(on);
+        }
+    }
+
+    fn set(&mut self, led: LedCode, on: bool) {
+        let ev = InputEvent::new(EventType::LED.0, led.0, on as i32);
+        let _ = self.dev.send_events(&[ev]);
+    }
+}
+
+impl Drop for Leds {
+    fn drop(&mut self) {
+        for led in [LedCode::LED_NUML, LedCode::LED_CAPSL, LedCode::LED_SCROLLL] {
+            let on = self.saved.contains(led);
+            self.set(led, on);
+        }
+    }
+}
@@ -0,0 +1,454 @@
+use anyhow::{Context, Result, bail, ensure};
+use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+use std::time::Instant;
+
+use crate::{AppState, PatternKind, SOLIDS, Step};
+
+/// A whole test sequence that can be switched to at runtime
+pub struct NamedScript {
+    pub name: String,
+    pub steps: Vec<Step>,
+}
+
+// On-disk form of one script file. The name defaults to the file stem.
+#[derive(Serialize, Deserialize)]
+struct ScriptFile {
+    name: Option<String>,
+    steps: Vec<Step>,
+}
+
+/// The built-in script followed by any loaded from a scripts directory
+pub struct ScriptLibrary {
+    pub scripts: Vec<NamedScript>,
+}
+
+impl ScriptLibrary {
+    pub fn builtin() -> Self {
+        Self {
+            scripts: vec![NamedScript {
+                name: "Built-in".to_string(),
+                steps: AppState::create_script(),
+            }],
+        }
+    }
+
+    /// Adds every `.toml` script in `dir`, in file name order so the menu is
+    /// stable between runs
+    pub fn load_dir(&mut self, dir: &Path) -> Result<()> {
+        let mut paths = std::fs::read_dir(dir)
+            .with_context(|| format!("could not read scripts directory {}", dir.display()))?
+            .map(|entry| entry.map(|e| e.path()))
+            .collect::<std::io::Result<Vec<_>>>()
+            .with_context(|| format!("could not list scripts directory {}", dir.display()))?;
+        paths.retain(|p| p.extension().is_some_and(|ext| ext == "toml"));
+        paths.sort();
+
+        for path in paths {
+            self.scripts.push(load_script(&path)?);
+        }
+
+        Ok(())
+    }
+}
+
+/// SplitMix64: advances `state` and returns the next value of a sequence
+/// fixed by the starting state
+pub fn splitmix64(state: &mut u64) -> u64 {
+    *state = state.wrapping_add(0x9e37_79b9_7f4a_7c15);
+    let mut z = *state;
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    z ^ (z >> 31)
+}
+
+/// Keeps the steps whose pattern is in `only` (every one if it's empty) and
+/// not in `skip`, for --only and --skip
+pub fn filter_steps(
+    steps: &mut Vec<Step>,
+    only: &[PatternKind],
+    skip: &[PatternKind],
+) -> Result<()> {
+    let total = steps.len();
+    steps.retain(|step| (only.is_empty() || only.contains(&step.pat)) && !skip.contains(&step.pat));
+    ensure!(
+        !steps.is_empty(),
+        "--only and --skip leave none of the script's {} steps",
+        total
+    );
+    eprintln!("Kept {} of the script's {} steps", steps.len(), total);
+    Ok(())
+}
+
+// Fisher-Yates shuffle driven by SplitMix64
+fn shuffle<T>(items: &mut [T], x: &mut u64) {
+    for i in (1..items.len()).rev() {
+        let j = (splitmix64(x) % (i as u64 + 1)) as usize;
+        items.swap(i, j);
+    }
+}
+
+/// Puts `steps` in an order fixed by `seed`, so a blind run can be repeated
+/// from its seed. With `by_pattern`, steps showing the same pattern stay
+/// together: the groups are shuffled, then the steps within each group.
+/// Returns the original index of each step in its new place.
+pub fn shuffle_steps(steps: &mut Vec<Step>, seed: u64, by_pattern: bool) -> Vec<usize> {
+    let mut x = seed;
+    let order: Vec<usize> = if by_pattern {
+        let mut groups: Vec<Vec<usize>> = Vec::new();
+        for (i, step) in steps.iter().enumerate() {
+            match groups.iter_mut().find(|g| steps[g[0]].pat == step.pat) {
+                Some(group) => group.push(i),
+                None => groups.push(vec![i]),
+            }
+        }
+        shuffle(&mut groups, &mut x);
+        for group in &mut groups {
+            shuffle(group, &mut x);
+        }
+        groups.concat()
+    } else {
+        let mut order: Vec<usize> = (0..steps.len()).collect();
+        shuffle(&mut order, &mut x);
+        order
+    };
+    *steps = order.iter().map(|&i| steps[i].clone()).collect();
+    order
+}
+
+/// Reads and validates one script file
+pub fn load_script(path: &Path) -> Result<NamedScript> {
+    let text = std::fs::read_to_string(path)
+        .with_context(|| format!("could not read script {}", path.display()))?;
+    let file: ScriptFile = toml::from_str(&text)
+        .with_context(|| format!("could not parse script {}", path.display()))?;
+    validate_script(&file.steps, &format!("script {}", path.display()))?;
+
+    let name = file.name.unwrap_or_else(|| {
+        path.file_stem()
+            .map(|s| s.to_string_lossy().into_owned())
+            .unwrap_or_default()
+    });
+    Ok(NamedScript {
+        name,
+        steps: file.steps,
+    })
+}
+
+/// `--record-script`: turns an interactive run into a script that replays
+/// it. Every visit to a step becomes one entry holding the step as it was
+/// last adjusted and how long it stayed up, so a replay shows the same
+/// thing for the same time. The file is rewritten whenever a visit ends, so
+/// it survives a crash.
+pub struct ScriptRecorder {
+    path: PathBuf,
+    steps: Vec<Step>,
+    current: Option<(usize, Step, Instant)>,
+}
+
+impl ScriptRecorder {
+    pub fn new(path: &Path) -> Self {
+        Self {
+            path: path.to_path_buf(),
+            steps: Vec::new(),
+            current: None,
+        }
+    }
+
+    /// Called every main loop iteration with the script index and the step
+    /// as currently adjusted. Changes on the same index update the visit in
+    /// place; a different index ends it.
+    pub fn observe(&mut self, index: usize, step: Step, now: Instant) -> Result<()> {
+        match &mut self.current {
+            Some((idx, current, _)) if *idx == index => {
+                *current = step;
+                Ok(())
+            }
+            _ => {
+                let ended = self.close(now);
+                self.current = Some((index, step, now));
+                if ended { self.save() } else { Ok(()) }
+            }
+        }
+    }
+
+    /// Ends the last visit and writes the script
+    pub fn finish(mut self, now: Instant) -> Result<()> {
+        self.close(now);
+        self.save()
+    }
+
+    fn close(&mut self, now: Instant) -> bool {
+        let Some((_, mut step, since)) = self.current.take() else {
+            return false;
+        };
+        // Rounded to the millisecond so the file stays readable
+        step.dwell = Some((now.duration_since(since).as_secs_f64() * 1000.0).round() / 1000.0);
+        self.steps.push(step);
+        true
+    }
+
+    fn save(&self) -> Result<()> {
+        let file = ScriptFile {
+            name: self
+                .path
+                .file_stem()
+                .map(|s| format!("Recorded {}", s.to_string_lossy())),
+            steps: self.steps.clone(),
+        };
+        let text = toml::to_string(&file).context("could not serialize recorded script")?;
+        std::fs::write(&self.path, text)
+            .with_context(|| format!("could not write script {}", self.path.display()))
+    }
+}
+
+/// `--dump-script`: writes the built-in script to `path` as a script file
+/// to start a custom one from. Each step only lists what differs from the
+/// defaults, which is all the loader needs, so the file stays readable.
+pub fn dump_script(path: &Path) -> Result<()> {
+    let defaults = toml::Table::try_from(Step::default())?;
+    let steps = AppState::create_script()
+        .iter()
+        .map(|step| {
+            let mut table = toml::Table::try_from(step)?;
+            table.retain(|key, value| key == "pat" || defaults.get(key) != Some(value));
+            Ok(toml::Value::Table(table))
+        })
+        .collect::<Result<Vec<_>>>()?;
+    let mut file = toml::Table::new();
+    file.insert("name".into(), "Built-in".into());
+    file.insert("steps".into(), toml::Value::Array(steps));
+    let text = format!(
+        "# The built-in script, written by --dump-script. Edit it and run it\n\
+         # with --script. A step gets the default for any field it leaves out.\n\n{}",
+        toml::to_string(&file).context("could not serialize the script")?
+    );
+    std::fs::write(path, text)
+        .with_context(|| format!("could not write script {}", path.display()))?;
+    eprintln!("Wrote the built-in script to {}", path.display());
+    Ok(())
+}
+
+// More than any screen has columns for
+const MAX_BARS: usize = 8192;
+
+/// Rejects scripts the main loop can't run: no steps at all, a solid index
+/// past the palette, an external step without a generator, or a dwell that
+/// isn't a number of seconds. A script whose steps are all the same is allowed
+/// but almost certainly a mistake, so it gets a warning.
+pub fn validate_script(steps: &[Step], what: &str) -> Result<()> {
+    ensure!(!steps.is_empty(), "{} has no steps", what);
+    if let Some(i) = steps.iter().position(|s| s.solid_idx >= SOLIDS.len()) {
+        bail!(
+            "{} step {} has solid index {}, but there are only {} solids",
+            what,
+            i + 1,
+            steps[i].solid_idx,
+            SOLIDS.len()
+        );
+    }
+    for (i, step) in steps.iter().enumerate() {
+        if let Some(name) = &step.name {
+            ensure!(
+                !name.is_empty(),
+                "{} step {} has an empty name",
+                what,
+                i + 1
+            );
+            if let Some(j) = steps[..i].iter().position(|s| s.name == step.name) {
+                bail!(
+                    "{} steps {} and {} are both named {}",
+                    what,
+                    j + 1,
+                    i + 1,
+                    name
+                );
+            }
+        }
+    }
+    if let Some(i) = steps
+        .iter()
+        .position(|s| s.pat == PatternKind::External && s.generator.is_none())
+    {
+        bail!("{} step {} is external but has no generator", what, i + 1);
+    }
+    if let Some(i) = steps.iter().position(|s| s.bar_count > MAX_BARS) {
+        bail!(
+            "{} step {} has {} color bars, more than {}",
+            what,
+            i + 1,
+            steps[i].bar_count,
+            MAX_BARS
+        );
+    }
+    if let Some(i) = steps
+        .iter()
+        .position(|s| !(s.radial.exponent.is_finite() && s.radial.exponent > 0.0))
+    {
+        bail!("{} step {} has an invalid radial exponent", what, i + 1);
+    }
+    if let Some(i) = steps.iter().position(|s| {
+        !((0.0..=1.0).contains(&s.hue.saturation) && (0.0..=1.0).contains(&s.hue.value))
+    }) {
+        bail!(
+            "{} step {} has a hue sweep saturation or value outside 0 to 1",
+            what,
+            i + 1
+        );
+    }
+    if let Some(i) = steps.iter().position(|s| {
+        s.checker_sweep
+            .is_some_and(|sweep| !(sweep.period_s.is_finite() && sweep.period_s > 0.0))
+    }) {
+        bail!(
+            "{} step {} has an invalid checker sweep period",
+            what,
+            i + 1
+        );
+    }
+    if let Some(i) = steps
+        .iter()
+        .position(|s| s.dwell.is_some_and(|d| !(d.is_finite() && d >= 0.0)))
+    {
+        bail!("{} step {} has an invalid dwell", what, i + 1);
+    }
+    if steps.len() > 1 && steps.iter().all(|s| *s == steps[0]) {
+        eprintln!(
+            "Warning: all {} steps of {} are identical",
+            steps.len(),
+            what
+        );
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("screen_test_{}_{}.toml", std::process::id(), name))
+    }
+
+    #[test]
+    fn empty_script_is_rejected() {
+        let err = validate_script(&[], "script x").unwrap_err();
+        assert_eq!(err.to_string(), "script x has no steps");
+
+        let path = temp_path("empty");
+        std::fs::write(&path, "name = \"Empty\"\nsteps = []\n").unwrap();
+        let result = load_script(&path);
+        std::fs::remove_file(&path).unwrap();
+        let err = result.err().expect("an empty script file loaded");
+        assert!(
+            format!("{:#}", err).contains("has no steps"),
+            "unexpected error: {:#}",
+            err
+        );
+    }
+
+    #[test]
+    fn single_step_script_runs() {
+        let steps = vec![Step::default()];
+        validate_script(&steps, "script x").unwrap();
+
+        let path = temp_path("single");
+        std::fs::write(&path, "[[steps]]\npat = \"solid\"\n").unwrap();
+        let result = load_script(&path);
+        std::fs::remove_file(&path).unwrap();
+        let script = result.unwrap();
+        assert_eq!(script.steps.len(), 1);
+        assert_eq!(
+            script.name,
+            format!("screen_test_{}_single", std::process::id())
+        );
+
+        let mut state = AppState::new().unwrap();
+        state.load_script(script.steps);
+        assert!(state.next_step(), "the only step is also the last");
+        assert_eq!(state.script_idx, 0);
+        state.previous_step();
+        assert_eq!(state.script_idx, 0);
+    }
+
+    #[test]
+    fn identical_steps_are_only_a_warning() {
+        validate_script(&[Step::default(), Step::default()], "script x").unwrap();
+    }
+
+    #[test]
+    fn bad_steps_are_rejected() {
+        let bad = [
+            Step {
+                solid_idx: SOLIDS.len(),
+                ..Step::default()
+            },
+            Step {
+                pat: PatternKind::External,
+                ..Step::default()
+            },
+            Step {
+                dwell: Some(f64::NAN),
+                ..Step::default()
+            },
+            Step {
+                bar_count: MAX_BARS + 1,
+                ..Step::default()
+            },
+        ];
+        for step in bad {
+            let steps = [Step::default(), step];
+            let err = validate_script(&steps, "script x").unwrap_err();
+            assert!(
+                err.to_string().starts_with("script x step 2 "),
+                "unexpected error: {}",
+                err
+            );
+        }
+    }
+
+    #[test]
+    fn recorded_script_replays_the_session() {
+        let path = temp_path("recorded");
+        let t0 = Instant::now();
+        let at = |s: f64| t0 + std::time::Duration::from_secs_f64(s);
+        let solid = |i: usize| Step {
+            solid_idx: i,
+            ..Step::default()
+        };
+        let gradient = Step {
+            pat: PatternKind::Gradient,
+            ..Step::default()
+        };
+
+        let mut recorder = ScriptRecorder::new(&path);
+        recorder.observe(0, solid(0), at(0.0)).unwrap();
+        // Adjusting the step in place changes what gets recorded
+        recorder.observe(0, solid(3), at(1.0)).unwrap();
+        assert!(!path.exists(), "written before any visit ended");
+        recorder.observe(1, gradient.clone(), at(2.5)).unwrap();
+        assert_eq!(load_script(&path).unwrap().steps.len(), 1);
+        recorder.observe(0, solid(0), at(3.0)).unwrap();
+        recorder.finish(at(3.2504)).unwrap();
+
+        let result = load_script(&path);
+        std::fs::remove_file(&path).unwrap();
+        let script = result.unwrap();
+        assert_eq!(
+            script.name,
+            format!("Recorded screen_test_{}_recorded", std::process::id())
+        );
+        let with_dwell = |step: Step, dwell: f64| Step {
+            dwell: Some(dwell),
+            ..step
+        };
+        assert_eq!(
+            script.steps,
+            vec![
+                with_dwell(solid(3), 2.5),
+                with_dwell(gradient, 0.5),
+                with_dwell(solid(0), 0.25),
+            ]
+        );
+    }
+}
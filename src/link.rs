@@ -0,0 +1,111 @@
+//! Watches the connector's link properties through a run: link-status going
+//! Bad after a cable glitch, HDCP coming and going, the link depth changing.
+//! Every change is printed, logged with --event-log and kept for --report.
+
+use anyhow::Result;
+use std::time::{Duration, Instant, SystemTime};
+
+use crate::events::{Event, EventLog};
+use crate::report::{self, LinkEvent};
+use crate::surface::LinkProps;
+
+// Between readings; the properties are cheap to read but rarely change
+const POLL_INTERVAL: Duration = Duration::from_secs(2);
+
+pub struct LinkWatch {
+    last: LinkProps,
+    next_poll: Instant,
+    events: Vec<LinkEvent>,
+}
+
+impl LinkWatch {
+    pub fn new(first: LinkProps, now: Instant) -> Self {
+        Self {
+            last: first,
+            next_poll: now + POLL_INTERVAL,
+            events: Vec::new(),
+        }
+    }
+
+    pub fn deadline(&self) -> Instant {
+        self.next_poll
+    }
+
+    pub fn due(&self, now: Instant) -> bool {
+        now >= self.next_poll
+    }
+
+    /// Has the next check come round straight away, e.g. for a status
+    /// request
+    pub fn poll_now(&mut self) {
+        self.next_poll = Instant::now();
+    }
+
+    pub fn current(&self) -> &LinkProps {
+        &self.last
+    }
+
+    /// Takes a fresh reading and records what changed since the last one.
+    /// Returns true if the link has just gone bad, so the mode wants setting
+    /// again. A failed read is tried again at the next poll.
+    pub fn update(
+        &mut self,
+        props: Result<LinkProps>,
+        now: Instant,
+        mut log: Option<&mut EventLog>,
+    ) -> bool {
+        self.next_poll = now + POLL_INTERVAL;
+        let Ok(props) = props else {
+            return false;
+        };
+        let went_bad = props.link_bad() && !self.last.link_bad();
+        for ((property, from), (_, to)) in self.last.fields().into_iter().zip(props.fields()) {
+            if from == to {
+                continue;
+            }
+            let show = |v: &Option<String>| v.clone().unwrap_or_else(|| "none".to_string());
+            eprintln!(
+                "Link: {} changed from {} to {}",
+                property,
+                show(&from),
+                show(&to)
+            );
+            if let Some(log) = log.as_deref_mut() {
+                log.log(Event::LinkChanged {
+                    property: property.to_string(),
+                    from: from.clone(),
+                    to: to.clone(),
+                });
+            }
+            self.events.push(LinkEvent {
+                unix_ms: report::unix_ms(SystemTime::now()),
+                property: property.to_string(),
+                from,
+                to,
+                recovery: None,
+            });
+        }
+        self.last = props;
+        went_bad
+    }
+
+    /// Notes how setting the mode again after the link went bad turned out,
+    /// on the event that reported it
+    pub fn recovered(&mut self, outcome: String) {
+        eprintln!("Link: {}", outcome);
+        if let Some(event) = self
+            .events
+            .iter_mut()
+            .rev()
+            .find(|e| e.property == "link-status")
+        {
+            event.recovery = Some(outcome);
+        }
+        // Read again straight away, to see if it took
+        self.poll_now();
+    }
+
+    pub fn report(self) -> Vec<LinkEvent> {
+        self.events
+    }
+}
@@ -0,0 +1,77 @@
+use anyhow::{Context, Result, bail, ensure};
+use std::path::Path;
+
+/// Per-channel 8-bit lookup tables, red, green, blue
+pub type Lut = [[u8; 256]; 3];
+
+/// Reads a 1D LUT from a text file: one `R G B` entry per line, separated by
+/// commas or whitespace, with blank lines and `#` comments ignored. 256
+/// entries hold 8-bit values; 1024 entries hold 10-bit values and are
+/// sampled down to 8 bits in and out.
+pub fn load_lut(path: &Path) -> Result<Lut> {
+    let text = std::fs::read_to_string(path)
+        .with_context(|| format!("could not read LUT {}", path.display()))?;
+
+    let mut entries = Vec::new();
+    for (n, line) in text.lines().enumerate() {
+        let line = line.split('#').next().unwrap_or("").trim();
+        if line.is_empty() {
+            continue;
+        }
+        let fields: Vec<&str> = line
+            .split(|c: char| c == ',' || c.is_whitespace())
+            .filter(|f| !f.is_empty())
+            .collect();
+        ensure!(
+            fields.len() == 3,
+            "{} line {}: expected 3 values, found {}",
+            path.display(),
+            n + 1,
+            fields.len()
+        );
+        let mut rgb = [0u16; 3];
+        for (v, f) in rgb.iter_mut().zip(&fields) {
+            *v = f.parse().with_context(|| {
+                format!("{} line {}: invalid value {}", path.display(), n + 1, f)
+            })?;
+        }
+        entries.push(rgb);
+    }
+
+    let max = match entries.len() {
+        256 => 255,
+        1024 => 1023,
+        n => bail!(
+            "{} has {} entries; a LUT needs 256 or 1024",
+            path.display(),
+            n
+        ),
+    };
+    if let Some(v) = entries.iter().flatten().find(|&&v| v > max) {
+        bail!(
+            "{} has value {}, above the maximum {}",
+            path.display(),
+            v,
+            max
+        );
+    }
+
+    let mut lut = [[0u8; 256]; 3];
+    for i in 0..256 {
+        let e = entries[i * max as usize / 255];
+        for c in 0..3 {
+            lut[c][i] = ((e[c] as u32 * 255 + max as u32 / 2) / max as u32) as u8;
+        }
+    }
+    Ok(lut)
+}
+
+/// Copies one row of BGRX pixels through the LUT
+pub fn apply_row(lut: &Lut, dst: &mut [u8], src: &[u8]) {
+    for (d, s) in dst.chunks_exact_mut(4).zip(src.chunks_exact(4)) {
+        d[0] = lut[2][s[0] as usize];
+        d[1] = lut[1][s[1] as usize];
+        d[2] = lut[0][s[2] as usize];
+        d[3] = s[3];
+    }
+}
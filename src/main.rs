@@ -1,225 +1,864 @@
+mod attract;
+mod audio;
+mod backend;
+mod backlight;
+mod beep;
+mod checksum;
+mod cli;
+mod color;
+mod config;
+mod control;
+mod damage;
+mod dbus;
+mod diag;
+mod draw;
+mod edid;
+mod events;
+mod exit;
+mod external;
+mod fbdev;
+mod flips;
+mod gpio;
+mod grab;
+mod histogram;
+mod instance;
+mod keys;
+mod leds;
+mod library;
+mod link;
+mod lut;
+mod mainloop;
+mod matrix;
+mod motion;
+mod notify;
+mod nv12;
+mod patch;
+mod preview;
+mod probe;
+mod profile;
+mod record;
+mod report;
+mod rotate;
+mod roulette;
+mod serial;
+mod session;
+mod shm;
+mod signals;
+mod soak;
+mod surface;
+mod sync;
+mod timecode;
+mod timing;
+mod writeback;
+mod ws;
+
 use anyhow::{Context, Result, anyhow, ensure};
 
-use drm::Device as DrmDevice;
-use drm::buffer::{Buffer, DrmFourcc};
-use drm::control as ctrl;
-use drm::control::dumbbuffer::DumbBuffer;
-use drm::control::{Device as CtrlDevice, PageFlipFlags, connector, crtc, framebuffer};
 use evdev::{Device as EvDev, EventSummary, KeyCode};
-use std::fs::{File, OpenOptions};
-use std::os::unix::io::{AsFd, BorrowedFd};
-use std::time::Instant;
+use std::collections::VecDeque;
+use std::io::IsTerminal;
+use std::os::unix::io::AsFd;
+use std::path::{Path, PathBuf};
+use std::process::ExitCode;
+use std::time::{Duration, Instant, SystemTime};
 
-use nix::poll::{PollFd, PollFlags, poll};
+use nix::errno::Errno;
+use nix::poll::{PollFd, PollFlags, PollTimeout, poll};
+use serde::{Deserialize, Serialize};
 
-#[derive(Debug)]
-struct Card(File);
+use backend::Backend;
+use cli::{Args, BLINK_MAX_HZ, BLINK_MIN_HZ, BLINK_SAFE_HZ, Logical, StepOverrides};
+use color::{DoubleRange, Gamut, OutputRange};
+use config::Config;
+use draw::{
+    Corner, GLYPH_H, SubpixelOrder, blend_rect, blend_text, draw_circle, draw_circle_outline,
+    draw_label, draw_label_over, draw_line, draw_line_aa, draw_rect_outline, draw_text,
+    draw_text_aa, expand_range, fill_rect, fill_rgb, fill_row, invert_rgb, limit_range,
+    mask_channels, mix_rgb, put_rgb, put_rgb_unchecked, replicate_first_row, swap_rb, text_width,
+    xrgb,
+};
+use exit::Exit;
+use fbdev::FbDev;
+use grab::Grabbed;
+use library::ScriptLibrary;
+use mainloop::MainLoop;
+use matrix::ColorMatrix;
+use motion::{EdgeBehavior, Mover};
+use patch::Patch;
+use rotate::{Rotation, rotate_frame};
+use session::Session;
+use shm::ShmExport;
+use surface::{ConnectorSelector, ModeSelector, SurfaceBuilder, expand_row, list_outputs};
+use timing::{FlipSample, FpsMeter};
+use writeback::Writeback;
 
-impl AsFd for Card {
-    fn as_fd(&self) -> BorrowedFd<'_> {
-        self.0.as_fd()
-    }
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum PatternKind {
+    #[default]
+    Solid,
+    Gradient,
+    Checker,
+    Motion,
+    LineSweep,
+    Viewing,
+    PixelExact,
+    Patches,
+    NearBlack,
+    BitDepth,
+    FlipSequence,
+    ColorChecker,
+    GamutCorners,
+    Oscillator,
+    EdidWhite,
+    ContrastSensitivity,
+    SubpixelText,
+    ChromaSubsampling,
+    ChannelOrder,
+    ColorBars,
+    PovDots,
+    PixelInversion,
+    GrayToGray,
+    AvSync,
+    Keystone,
+    Focus,
+    Radial,
+    IntegerScale,
+    HueSweep,
+    RampScroll,
+    Ruler,
+    BacklightSweep,
+    Bounce,
+    Stereo,
+    EyeAlignment,
+    Timecode,
+    External,
 }
 
-impl DrmDevice for Card {}
+impl PatternKind {
+    const ALL: [PatternKind; 37] = [
+        PatternKind::Solid,
+        PatternKind::Gradient,
+        PatternKind::Checker,
+        PatternKind::Motion,
+        PatternKind::LineSweep,
+        PatternKind::Viewing,
+        PatternKind::PixelExact,
+        PatternKind::Patches,
+        PatternKind::NearBlack,
+        PatternKind::BitDepth,
+        PatternKind::FlipSequence,
+        PatternKind::ColorChecker,
+        PatternKind::GamutCorners,
+        PatternKind::Oscillator,
+        PatternKind::EdidWhite,
+        PatternKind::ContrastSensitivity,
+        PatternKind::SubpixelText,
+        PatternKind::ChromaSubsampling,
+        PatternKind::ChannelOrder,
+        PatternKind::ColorBars,
+        PatternKind::PovDots,
+        PatternKind::PixelInversion,
+        PatternKind::GrayToGray,
+        PatternKind::AvSync,
+        PatternKind::Keystone,
+        PatternKind::Focus,
+        PatternKind::Radial,
+        PatternKind::IntegerScale,
+        PatternKind::HueSweep,
+        PatternKind::RampScroll,
+        PatternKind::Ruler,
+        PatternKind::BacklightSweep,
+        PatternKind::Bounce,
+        PatternKind::Stereo,
+        PatternKind::EyeAlignment,
+        PatternKind::Timecode,
+        PatternKind::External,
+    ];
+
+    // The name script files use, which --only and --skip take too
+    fn name(self) -> String {
+        match serde_json::to_value(self) {
+            Ok(serde_json::Value::String(name)) => name,
+            _ => format!("{:?}", self),
+        }
+    }
 
-impl CtrlDevice for Card {}
+    fn from_name(name: &str) -> Option<Self> {
+        serde_json::from_value(serde_json::Value::String(name.to_string())).ok()
+    }
 
-impl Card {
-    fn open_default() -> Result<Self> {
-        let mut options = OpenOptions::new();
-        options.read(true).write(true);
+    // Patterns that flash or flicker by design
+    fn flickers(self) -> bool {
+        matches!(
+            self,
+            PatternKind::FlipSequence
+                | PatternKind::PixelInversion
+                | PatternKind::GrayToGray
+                | PatternKind::AvSync
+                | PatternKind::Stereo
+        )
+    }
 
-        // Try card0 first, then card1, then card2
-        for i in 0..=2 {
-            let path = format!("/dev/dri/card{}", i);
-            if let Ok(file) = options.open(&path) {
-                eprintln!("Opened DRM device: {}", path);
-                return Ok(Card(file));
-            }
+    // Patterns whose point is detail at the pixel level, which --render-scale
+    // would destroy
+    fn needs_native_pixels(self) -> bool {
+        matches!(
+            self,
+            PatternKind::Checker
+                | PatternKind::PixelExact
+                | PatternKind::ContrastSensitivity
+                | PatternKind::SubpixelText
+                | PatternKind::ChromaSubsampling
+                | PatternKind::PixelInversion
+                | PatternKind::Focus
+                | PatternKind::IntegerScale
+                | PatternKind::Ruler
+        )
+    }
+
+    // Patterns read by an instrument or checked to the pixel, which nothing
+    // optional should be drawn over
+    fn measured(self) -> bool {
+        self.needs_native_pixels()
+            || matches!(
+                self,
+                PatternKind::Patches
+                    | PatternKind::NearBlack
+                    | PatternKind::ColorChecker
+                    | PatternKind::GamutCorners
+                    | PatternKind::EdidWhite
+                    | PatternKind::AvSync
+                    | PatternKind::BacklightSweep
+                    | PatternKind::Stereo
+                    | PatternKind::Timecode
+            )
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum GradMode {
+    #[default]
+    Luma,
+    // Red, green and blue ramps in three bands across the ramp, so all
+    // three channels can be judged at once
+    Rgb,
+}
+
+impl GradMode {
+    // The channels each band across the ramp lights, with its label
+    fn bands(self) -> &'static [([bool; 3], &'static str)] {
+        match self {
+            GradMode::Luma => &[([true; 3], "")],
+            GradMode::Rgb => &[
+                ([true, false, false], "red"),
+                ([false, true, false], "green"),
+                ([false, false, true], "blue"),
+            ],
         }
+    }
 
-        Err(anyhow!(
-            "Could not open any DRM device (tried card0, card1, card2)"
-        ))
+    fn next(self) -> Self {
+        match self {
+            GradMode::Luma => GradMode::Rgb,
+            GradMode::Rgb => GradMode::Luma,
+        }
     }
 }
 
-struct Frame {
-    db: DumbBuffer,
-    fb: framebuffer::Handle,
-    _disp_w: usize,
-    disp_h: usize,
-    stride: usize,
+// Full black or full white shown over any step by Z, for quick field checks
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum QuickFill {
+    Black,
+    White,
+}
+
+impl QuickFill {
+    // Black, then white, then back to the step
+    fn next(fill: Option<Self>) -> Option<Self> {
+        match fill {
+            None => Some(QuickFill::Black),
+            Some(QuickFill::Black) => Some(QuickFill::White),
+            Some(QuickFill::White) => None,
+        }
+    }
+
+    fn label(self) -> &'static str {
+        match self {
+            QuickFill::Black => "FULL BLACK - Z for white",
+            QuickFill::White => "FULL WHITE - Z to go back",
+        }
+    }
 }
 
-struct Surface {
-    card: Card,
-    crtc: crtc::Handle,
-    disp_w: usize,
-    disp_h: usize,
-    frames: [Frame; 2],
-    front: usize,
-    is_flipping: bool,
+// Channels the output keeps, set by 7, 8, 9 and 0 over any pattern; the
+// others are zeroed, i.e. shown black
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum ChannelMask {
+    #[default]
+    All,
+    Red,
+    Green,
+    Blue,
 }
 
-impl Surface {
-    fn open_default() -> Result<Self> {
-        let card = Card::open_default()?;
+impl ChannelMask {
+    // In BGRX byte order
+    fn keep(self) -> Option<[bool; 3]> {
+        match self {
+            ChannelMask::All => None,
+            ChannelMask::Red => Some([false, false, true]),
+            ChannelMask::Green => Some([false, true, false]),
+            ChannelMask::Blue => Some([true, false, false]),
+        }
+    }
 
-        let res = card
-            .resource_handles()
-            .context("could not load resource handles")?;
+    fn name(self) -> &'static str {
+        match self {
+            ChannelMask::All => "all channels",
+            ChannelMask::Red => "red only",
+            ChannelMask::Green => "green only",
+            ChannelMask::Blue => "blue only",
+        }
+    }
+}
 
-        let mut selected = None;
+// What the radial gradient and the bit depth staircases light: all three
+// channels, or one alone
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum RadialChannels {
+    #[default]
+    Luma,
+    Red,
+    Green,
+    Blue,
+}
 
-        for &con in res.connectors() {
-            let info = card.get_connector(con, false)?;
-            if info.state() != connector::State::Connected || info.modes().is_empty() {
-                continue;
-            }
+impl RadialChannels {
+    fn apply(self, v: u8) -> (u8, u8, u8) {
+        match self {
+            RadialChannels::Luma => (v, v, v),
+            RadialChannels::Red => (v, 0, 0),
+            RadialChannels::Green => (0, v, 0),
+            RadialChannels::Blue => (0, 0, v),
+        }
+    }
 
-            let mode = info
-                .modes()
-                .iter()
-                .find(|m| m.mode_type().contains(ctrl::ModeTypeFlags::PREFERRED))
-                .or_else(|| info.modes().get(0))
-                .cloned()
-                .ok_or_else(|| anyhow!("connector has no modes"))?;
+    fn name(self) -> &'static str {
+        match self {
+            RadialChannels::Luma => "luma",
+            RadialChannels::Red => "red",
+            RadialChannels::Green => "green",
+            RadialChannels::Blue => "blue",
+        }
+    }
+
+    fn next(self) -> Self {
+        match self {
+            RadialChannels::Luma => RadialChannels::Red,
+            RadialChannels::Red => RadialChannels::Green,
+            RadialChannels::Green => RadialChannels::Blue,
+            RadialChannels::Blue => RadialChannels::Luma,
+        }
+    }
+}
 
-            let enc = info
-                .current_encoder()
-                .ok_or_else(|| anyhow!("no current encoder"))?;
+// Radial gradient for vignetting, projector lens shading and edge-lit
+// rolloff: the level is (1 - d)^exponent, where d runs from 0 at the center
+// to 1 in the corners. `inverse` turns it into one minus that, so the edges
+// are bright and the center dark.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+struct Radial {
+    exponent: f64,
+    inverse: bool,
+    channels: RadialChannels,
+}
 
-            let enc_info = card.get_encoder(enc)?;
+impl Default for Radial {
+    fn default() -> Self {
+        Self {
+            exponent: 1.0,
+            inverse: false,
+            channels: RadialChannels::Luma,
+        }
+    }
+}
 
-            let crtc = enc_info.crtc().ok_or_else(|| anyhow!("no crtc"))?;
+// Full circle of hues at one saturation and value, for hue discontinuities
+// and banding in saturated colors: hue runs left to right, or round the
+// center counterclockwise from red on the right with `radial`.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+struct HueSweep {
+    saturation: f64,
+    value: f64,
+    radial: bool,
+}
 
-            selected = Some((con, crtc, mode));
-            break;
+impl Default for HueSweep {
+    fn default() -> Self {
+        Self {
+            saturation: 1.0,
+            value: 1.0,
+            radial: false,
         }
+    }
+}
 
-        let (con, crtc, mode) = selected.ok_or_else(|| anyhow!("no connected display"))?;
+// A mid gray in light held while --backlight steps the backlight from 0 to
+// 100% in `steps` even levels, `hold` seconds each, for the panel's PWM and
+// step behavior
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+struct BacklightSweep {
+    steps: u32,
+    hold: f64,
+}
 
-        let (disp_w, disp_h) = (mode.size().0 as u32, mode.size().1 as u32);
+impl Default for BacklightSweep {
+    fn default() -> Self {
+        Self {
+            steps: 11,
+            hold: 2.0,
+        }
+    }
+}
 
-        let fmt = DrmFourcc::Xrgb8888;
+// Text drifting diagonally over black and bouncing off the edges while its
+// hue turns slowly, like an old screensaver; for --attract
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+struct Bounce {
+    text: String,
+    // Across, in pixels per second; down goes at three quarters of it
+    speed: u32,
+}
 
-        let make_frame = || -> Result<Frame> {
-            let db = card.create_dumb_buffer((disp_w, disp_h), fmt, 32)?;
+impl Default for Bounce {
+    fn default() -> Self {
+        Self {
+            text: "screen_test".to_string(),
+            speed: 180,
+        }
+    }
+}
 
-            let fb = card.add_framebuffer(&db, 24, 32)?;
+// Color sets for the flip sequence, one full-screen color per flip
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum SeqColors {
+    #[default]
+    Rgb,
+    Rgbw,
+    BlackWhite,
+}
 
-            let stride = db.pitch();
+impl SeqColors {
+    fn colors(self) -> &'static [(&'static str, (u8, u8, u8))] {
+        match self {
+            SeqColors::Rgb => &[
+                ("red", (255, 0, 0)),
+                ("green", (0, 255, 0)),
+                ("blue", (0, 0, 255)),
+            ],
+            SeqColors::Rgbw => &[
+                ("red", (255, 0, 0)),
+                ("green", (0, 255, 0)),
+                ("blue", (0, 0, 255)),
+                ("white", (255, 255, 255)),
+            ],
+            SeqColors::BlackWhite => &[("black", (0, 0, 0)), ("white", (255, 255, 255))],
+        }
+    }
 
-            Ok(Frame {
-                db,
-                fb,
-                _disp_w: disp_w as usize,
-                disp_h: disp_h as usize,
-                stride: stride as usize,
-            })
-        };
+    fn next(self) -> Self {
+        match self {
+            SeqColors::Rgb => SeqColors::Rgbw,
+            SeqColors::Rgbw => SeqColors::BlackWhite,
+            SeqColors::BlackWhite => SeqColors::Rgb,
+        }
+    }
+}
 
-        let f0 = make_frame()?;
-        let f1 = make_frame()?;
+// One channel of the full-field oscillator: a sine swinging around mid grey.
+// phase is a fraction of a cycle, amplitude a fraction of the full swing.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+struct ChannelFn {
+    freq_hz: f64,
+    phase: f64,
+    amplitude: f64,
+}
 
-        card.set_crtc(crtc, Some(f0.fb), (0, 0), &[con], Some(mode))
-            .context("failed to set crtc")?;
+impl Default for ChannelFn {
+    fn default() -> Self {
+        Self {
+            freq_hz: 1.0,
+            phase: 0.0,
+            amplitude: 1.0,
+        }
+    }
+}
 
-        Ok(Self {
-            card,
-            crtc,
-            disp_w: disp_w as usize,
-            disp_h: disp_h as usize,
-            frames: [f0, f1],
-            front: 0,
-            is_flipping: false,
-        })
+impl ChannelFn {
+    fn level(&self, t: f64) -> u8 {
+        let s = (std::f64::consts::TAU * (self.freq_hz * t + self.phase)).sin();
+        (127.5 + 127.5 * self.amplitude.clamp(0.0, 1.0) * s).round() as u8
     }
+}
+
+const OSC_CHANNELS: [&str; 3] = ["R", "G", "B"];
 
-    #[inline]
-    fn back(&self) -> usize {
-        1 - self.front
+// A line `width` px thick crossing a black screen at `speed` px/s, top to
+// bottom, or left to right when `vertical`, for line-scan camera sync
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+struct LineSweep {
+    speed: u32,
+    width: usize,
+    color: (u8, u8, u8),
+    vertical: bool,
+}
+
+impl Default for LineSweep {
+    fn default() -> Self {
+        Self {
+            speed: 240,
+            width: 1,
+            color: (255, 255, 255),
+            vertical: false,
+        }
     }
+}
+
+// A horizontal ramp, dark to bright and back so it tiles, scrolling right
+// at `speed` px/s, for banding in motion and smoothness. `light` spaces the
+// levels evenly in light rather than code value, as for the gradient.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+struct RampScroll {
+    speed: u32,
+    light: bool,
+}
 
-    #[inline]
-    fn stride(&self) -> usize {
-        self.frames[0].stride
+impl Default for RampScroll {
+    fn default() -> Self {
+        Self {
+            speed: 240,
+            light: false,
+        }
     }
+}
 
-    fn write_to_back(&mut self, src: &[u8]) -> Result<()> {
-        let frame = &mut self.frames[self.back()];
-        ensure!(
-            src.len() >= frame.stride * frame.disp_h,
-            "source buffer too small"
-        );
+// Screen shape the keystone pattern's centered box is drawn at
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+enum TargetAspect {
+    #[default]
+    #[serde(rename = "16:9")]
+    Wide,
+    #[serde(rename = "16:10")]
+    Wide16x10,
+    #[serde(rename = "2.39:1")]
+    Scope,
+}
 
-        let mut map = self.card.map_dumb_buffer(&mut frame.db)?;
+impl TargetAspect {
+    fn ratio(self) -> f64 {
+        match self {
+            TargetAspect::Wide => 16.0 / 9.0,
+            TargetAspect::Wide16x10 => 16.0 / 10.0,
+            TargetAspect::Scope => 2.39,
+        }
+    }
 
-        for y in 0..frame.disp_h {
-            let p0 = y * frame.stride;
-            let p1 = p0 + frame.stride;
-            let src_row = &src[p0..p1]; // If the row is longer i.e src_strice > frame.stride, only copy up to frame.stride pixels
-            let dst_row = &mut map[p0..p1];
-            dst_row.copy_from_slice(src_row);
+    fn name(self) -> &'static str {
+        match self {
+            TargetAspect::Wide => "16:9",
+            TargetAspect::Wide16x10 => "16:10",
+            TargetAspect::Scope => "2.39:1",
         }
+    }
 
-        Ok(())
+    fn next(self) -> Self {
+        match self {
+            TargetAspect::Wide => TargetAspect::Wide16x10,
+            TargetAspect::Wide16x10 => TargetAspect::Scope,
+            TargetAspect::Scope => TargetAspect::Wide,
+        }
     }
+}
+
+// Projector alignment: the box for the target screen shape, and whether the
+// outer outline blinks so it can be found when the image overshoots
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+struct Keystone {
+    aspect: TargetAspect,
+    blink: bool,
+}
+
+// Projector focus: a 1 px checker over the whole screen, or concentric
+// 1 px circles for field curvature, in white or in green alone
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+struct Focus {
+    green: bool,
+    rings: bool,
+}
 
-    fn flip(&mut self) -> Result<()> {
-        ensure!(!self.is_flipping, "flip already pending");
+// How a 3D panel packs the two eyes' pictures into one frame
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum EyeLayout {
+    // Left eye in the left part, right eye in the right
+    #[default]
+    SideBySide,
+    // Left eye on top
+    TopBottom,
+    // HDMI frame packing: left eye on top, right below a band of active
+    // space 1/49 of the frame high, as 1080p packs into 2205 lines
+    FramePacking,
+}
 
-        let target_frame = &self.frames[self.back()];
+impl EyeLayout {
+    fn next(self) -> Self {
+        match self {
+            EyeLayout::SideBySide => EyeLayout::TopBottom,
+            EyeLayout::TopBottom => EyeLayout::FramePacking,
+            EyeLayout::FramePacking => EyeLayout::SideBySide,
+        }
+    }
 
-        self.card
-            .page_flip(self.crtc, target_frame.fb, PageFlipFlags::EVENT, None)?;
+    fn name(self) -> &'static str {
+        match self {
+            EyeLayout::SideBySide => "side by side",
+            EyeLayout::TopBottom => "top and bottom",
+            EyeLayout::FramePacking => "frame packing",
+        }
+    }
+}
 
-        self.is_flipping = true;
+// A crosshair and grid for each eye of a 3D panel, the two crosshairs
+// offset by a disparity in opposite directions, for checking the panel or
+// headset splits the frame where it should and lines the eyes up. `split`
+// is where the left eye's part ends, as a fraction of the frame; frame
+// packing goes by its own geometry instead.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+struct EyeAlignment {
+    layout: EyeLayout,
+    split: f64,
+    // Right eye's crosshair against the left's, in pixels of each eye's
+    // picture; each moves half of it
+    disparity_x: i32,
+    disparity_y: i32,
+}
 
-        Ok(())
+impl Default for EyeAlignment {
+    fn default() -> Self {
+        Self {
+            layout: EyeLayout::SideBySide,
+            split: 0.5,
+            disparity_x: 0,
+            disparity_y: 0,
+        }
     }
+}
 
-    fn handle_drm_events(&mut self) -> Result<bool> {
-        for event in self.card.receive_events()? {
-            if let ctrl::Event::PageFlip(_) = event {
-                if self.is_flipping {
-                    self.front = self.back();
-                    self.is_flipping = false;
-                    return Ok(true);
-                }
+impl EyeAlignment {
+    // Each eye's part of a w x h frame as x, y, width, height, left first
+    fn areas(&self, w: usize, h: usize) -> [(usize, usize, usize, usize); 2] {
+        let split = self.split.clamp(0.1, 0.9);
+        match self.layout {
+            EyeLayout::SideBySide => {
+                let sx = (w as f64 * split).round() as usize;
+                [(0, 0, sx, h), (sx, 0, w - sx, h)]
             }
+            EyeLayout::TopBottom => {
+                let sy = (h as f64 * split).round() as usize;
+                [(0, 0, w, sy), (0, sy, w, h - sy)]
+            }
+            EyeLayout::FramePacking => {
+                let gap = h / 49;
+                let eye_h = (h - gap) / 2;
+                [(0, 0, w, eye_h), (0, eye_h + gap, w, eye_h)]
+            }
+        }
+    }
+}
+
+// What C steps the sweep line through
+const LINE_COLORS: [(u8, u8, u8); 4] = [(255, 255, 255), (255, 0, 0), (0, 255, 0), (0, 0, 255)];
+
+// An animated checkerboard whose cell size falls from `from` to `to` px over
+// `period_s` seconds, then starts over. It falls evenly on a log scale, so
+// every halving of the size gets the same time.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+struct CheckerSweep {
+    from: usize,
+    to: usize,
+    period_s: f64,
+}
+
+impl Default for CheckerSweep {
+    fn default() -> Self {
+        Self {
+            from: 64,
+            to: 1,
+            period_s: 8.0,
         }
+    }
+}
+
+impl CheckerSweep {
+    // Cell size `t` seconds into the sweep
+    fn cell_at(self, t: f64) -> usize {
+        let (from, to) = (self.from.max(1) as f64, self.to.max(1) as f64);
+        let frac = (t / self.period_s).fract();
+        (from * (to / from).powf(frac)).round() as usize
+    }
+}
+
+// Light and dark colors of a two-color pattern
+type ColorPair = [(u8, u8, u8); 2];
+
+// What C steps the checkerboard through, light cell first. A step's own
+// checker_colors join the cycle after these.
+const CHECKER_PAIRS: [(&str, ColorPair); 4] = [
+    ("black/white", [(255, 255, 255), (0, 0, 0)]),
+    ("red/green", [(255, 0, 0), (0, 255, 0)]),
+    ("blue/yellow", [(0, 0, 255), (255, 255, 0)]),
+    ("red/cyan", [(255, 0, 0), (0, 255, 255)]),
+];
 
-        Ok(false)
+// The pair after `current` in the C cycle, where `own` is the step's
+// checker_colors
+fn next_checker_colors(current: ColorPair, own: Option<ColorPair>) -> ColorPair {
+    let mut pairs: Vec<_> = CHECKER_PAIRS.iter().map(|&(_, pair)| pair).collect();
+    if let Some(own) = own
+        && !pairs.contains(&own)
+    {
+        pairs.push(own);
     }
+    let next = pairs
+        .iter()
+        .position(|&p| p == current)
+        .map_or(0, |i| (i + 1) % pairs.len());
+    pairs[next]
 }
 
-impl Drop for Surface {
-    fn drop(&mut self) {
-        let _ = self.card.set_crtc(self.crtc, None, (0, 0), &[], None);
-        for f in &self.frames {
-            let _ = self.card.destroy_framebuffer(f.fb);
-            let _ = self.card.destroy_dumb_buffer(f.db);
+fn checker_pair_name(pair: ColorPair) -> String {
+    match CHECKER_PAIRS.iter().find(|(_, p)| *p == pair) {
+        Some((name, _)) => name.to_string(),
+        None => {
+            let [(r1, g1, b1), (r2, g2, b2)] = pair;
+            format!("{} {} {}/{} {} {}", r1, g1, b1, r2, g2, b2)
         }
     }
 }
 
-#[derive(Clone, Copy, Debug, Default)]
-enum PatternKind {
-    #[default]
-    Solid,
-    Gradient,
-    Checker,
-    Motion,
-    Viewing,
+// An entry of the F1 menu
+#[derive(Clone, Copy, PartialEq)]
+enum MenuItem {
+    Step(usize),
+    Labels,
+    Pause,
+    ParamNav,
+    Matrix,
+    Range,
+    DoubleRange,
+    Progress,
 }
 
-#[derive(Clone, Copy, Debug, Default)]
-enum GradMode {
-    #[default]
-    Luma,
+// One point in a pattern's parameter space, for param nav
+#[derive(Clone, Copy, PartialEq)]
+enum Param {
+    Solid(usize),
+    Gradient { vertical: bool, light: bool },
+    CheckerCell(usize),
+    MotionSpeed(usize),
+    LineSpeed(u32),
+    RampSpeed(u32),
+    SeqColors(SeqColors),
+}
+
+impl Param {
+    fn value_label(self) -> String {
+        match self {
+            Param::Solid(idx) => {
+                let (r, g, b) = SOLIDS[idx];
+                format!("{} ({}, {}, {})", idx, r, g, b)
+            }
+            Param::Gradient { vertical, light } => format!(
+                "{}, {}",
+                if vertical { "vertical" } else { "horizontal" },
+                if light { "linear light" } else { "linear code" }
+            ),
+            Param::CheckerCell(cell) => format!("{} px", cell),
+            Param::MotionSpeed(speed) => format!("{} px/frame", speed),
+            Param::LineSpeed(speed) | Param::RampSpeed(speed) => format!("{} px/s", speed),
+            Param::SeqColors(colors) => format!("{:?}", colors).to_lowercase(),
+        }
+    }
+}
+
+// How long the red marker for a late frame stays up
+const LATE_MARK: Duration = Duration::from_millis(300);
+
+// How often to look for a keyboard after the one in use was unplugged
+const KEYBOARD_RESCAN: Duration = Duration::from_secs(2);
+
+// Longest the keyboard is left unread after a batch of keys changed the step
+// and before that step reaches the screen, so a stuck flip can't lock out Q
+const NAV_HOLD: Duration = Duration::from_millis(500);
+
+// How long the heads-up readout stays up after the last adjustment
+const READOUT_TIME: Duration = Duration::from_millis(1500);
+
+// The parameter param nav walks for each pattern and every value it takes,
+// or None for patterns without one
+fn param_space(pattern: PatternKind) -> Option<(&'static str, Vec<Param>)> {
+    match pattern {
+        PatternKind::Solid => Some(("solid color", (0..SOLIDS.len()).map(Param::Solid).collect())),
+        PatternKind::Gradient => Some((
+            "gradient",
+            [(false, false), (true, false), (false, true), (true, true)]
+                .map(|(vertical, light)| Param::Gradient { vertical, light })
+                .to_vec(),
+        )),
+        PatternKind::Checker => Some((
+            "checker cell",
+            [1, 2, 3, 4, 8, 16, 32, 64].map(Param::CheckerCell).to_vec(),
+        )),
+        PatternKind::Motion => Some((
+            "motion speed",
+            [1, 2, 4, 8, 16, 32, 64].map(Param::MotionSpeed).to_vec(),
+        )),
+        PatternKind::LineSweep => Some((
+            "line speed",
+            [60, 120, 240, 480, 960, 1920]
+                .map(Param::LineSpeed)
+                .to_vec(),
+        )),
+        PatternKind::RampScroll => Some((
+            "ramp speed",
+            [60, 120, 240, 480, 960, 1920]
+                .map(Param::RampSpeed)
+                .to_vec(),
+        )),
+        PatternKind::FlipSequence => Some((
+            "flip colors",
+            [SeqColors::Rgb, SeqColors::Rgbw, SeqColors::BlackWhite]
+                .map(Param::SeqColors)
+                .to_vec(),
+        )),
+        _ => None,
+    }
 }
 
 const SOLIDS: &[(u8, u8, u8)] = &[
@@ -231,172 +870,1144 @@ const SOLIDS: &[(u8, u8, u8)] = &[
     (0, 0, 0),
 ];
 
-fn put_rgb(buf: &mut [u8], stride: usize, x: usize, y: usize, r: u8, g: u8, b: u8) {
-    let offset = y * stride + x * 4;
+// What the overlay calls each of SOLIDS when [ and ] step through them
+const SOLID_NAMES: &[&str] = &["RED", "GREEN", "BLUE", "WHITE", "50% GRAY", "BLACK"];
 
-    assert!(offset + 3 < buf.len(), "put_rgb out of bounds {}, {}", x, y);
+// One color per position along the ramp, so the per-pixel work is a lookup.
+// With `light` the ramp is linear in luminance and sRGB-encoded, otherwise it
+// is linear in code values. The ramp is grey; each band of an RGB ramp
+// keeps only its own channel of it.
+fn gradient_lut(len: usize, mode: GradMode, light: bool) -> Vec<(u8, u8, u8)> {
+    match mode {
+        GradMode::Luma | GradMode::Rgb => (0..len)
+            .map(|t| {
+                let v = if light {
+                    color::srgb_encode_u8(t as f64 / (len - 1).max(1) as f64)
+                } else {
+                    ((t * 255) / (len - 1).max(1)) as u8
+                };
+                (v, v, v)
+            })
+            .collect(),
+    }
+}
 
-    buf[offset + 0] = b;
-    buf[offset + 1] = g;
-    buf[offset + 2] = r;
-    buf[offset + 3] = 0xff;
+// The gradient's ramp rising over the first half of the width and falling
+// back over the second, so the last column leads into the first, shifted
+// right by `offset` px. Every row is the same, so only the first is drawn.
+fn draw_ramp_scroll(buf: &mut [u8], stride: usize, w: usize, h: usize, offset: usize, light: bool) {
+    if w == 0 || h == 0 {
+        return;
+    }
+    let half = w / 2;
+    let rising = gradient_lut(half + 1, GradMode::Luma, light);
+    for x in 0..w {
+        let p = (x + w - offset % w) % w;
+        let (r, g, b) = rising[if p <= half { p } else { w - p }];
+        buf[x * 4..x * 4 + 4].copy_from_slice(&xrgb(r, g, b));
+    }
+    replicate_first_row(buf, stride, w, h);
 }
 
-fn fill_rgb(buf: &mut [u8], stride: usize, w: usize, h: usize, r: u8, g: u8, b: u8) {
-    for y in 0..h {
-        for x in 0..w {
-            put_rgb(buf, stride, x, y, r, g, b);
-        }
+// Pixels per mm across and down the stage, from --dpi or else the picture
+// size the display reports, with where the figure came from
+fn pixels_per_mm(state: &AppState, surface: &dyn Backend) -> Option<((f64, f64), String)> {
+    if let Some(dpi) = state.overrides.dpi {
+        return Some(((dpi / 25.4, dpi / 25.4), format!("--dpi {}", dpi)));
     }
+    let (mm_w, mm_h) = surface.size_mm()?;
+    let (w, h) = surface.size();
+    let (x, y) = (w as f64 / mm_w as f64, h as f64 / mm_h as f64);
+    Some((
+        if state.quarter_turn { (y, x) } else { (x, y) },
+        format!("display reports {} x {} mm", mm_w, mm_h),
+    ))
 }
 
-fn draw_gradient(
+// Millimetre rulers along all four edges, starting from the top left: a
+// tick every mm (left out when they would be under 3 px apart), longer
+// every 5 and longest every 10 with the cm written by it. The largest of a
+// 100, 50, 20 or 10 mm square that fits is outlined in the middle, its
+// outer edges the stated size apart. Returns that size, if one fits.
+fn draw_ruler(
     buf: &mut [u8],
     stride: usize,
     w: usize,
     h: usize,
-    mode: GradMode,
-    vertical: bool,
-) {
-    match mode {
-        GradMode::Luma => {
-            let len = if vertical { h } else { w };
-            for y in 0..h {
-                for x in 0..w {
-                    let t = if vertical { y } else { x };
-                    let v = ((t * 255) / (len - 1).max(1)) as u8;
-                    put_rgb(buf, stride, x, y, v, v, v);
-                }
+    (px_x, px_y): (f64, f64),
+) -> Option<u32> {
+    fill_rgb(buf, stride, w, h, 0, 0, 0);
+    let scale = (h / 540).max(1);
+    let long = (w.min(h) / 30).max(6);
+    for (vertical, ppm, len, across) in [(false, px_x, w, h), (true, px_y, h, w)] {
+        // Under a pixel per cm there is nothing to draw
+        if ppm < 0.1 {
+            continue;
+        }
+        let fine = ppm >= 3.0;
+        for mm in 0.. {
+            let pos = (mm as f64 * ppm).round() as usize;
+            if pos >= len {
+                break;
+            }
+            let tick = match mm {
+                m if m % 10 == 0 => long,
+                m if m % 5 == 0 => long * 2 / 3,
+                _ if fine => long / 3,
+                _ => continue,
+            };
+            for start in [0, across.saturating_sub(tick)] {
+                let (x, y, rw, rh) = if vertical {
+                    (start, pos, tick, 1)
+                } else {
+                    (pos, start, 1, tick)
+                };
+                fill_rect(
+                    buf, stride, w, h, x as isize, y as isize, rw, rh, 255, 255, 255,
+                );
+            }
+            if mm % 10 == 0 && mm > 0 {
+                let text = format!("{}", mm / 10);
+                let (x, y) = if vertical {
+                    (long + 2 * scale, pos.saturating_sub(GLYPH_H * scale / 2))
+                } else {
+                    (
+                        pos.saturating_sub(text_width(&text, scale) / 2),
+                        long + 2 * scale,
+                    )
+                };
+                draw_label(buf, stride, w, h, x, y, &text, scale);
             }
         }
     }
-}
 
-fn draw_checkerboard(buf: &mut [u8], stride: usize, w: usize, h: usize, cell: usize) {
-    let cell = cell.max(1);
+    let room =
+        |side: u32, ppm: f64, len: usize| side as f64 * ppm <= len.saturating_sub(6 * long) as f64;
+    let side = [100, 50, 20, 10]
+        .into_iter()
+        .find(|&side| room(side, px_x, w) && room(side, px_y, h))?;
+    let (sw, sh) = (
+        (side as f64 * px_x).round() as usize,
+        (side as f64 * px_y).round() as usize,
+    );
+    let (x, y) = ((w - sw) / 2, (h - sh) / 2);
+    draw_rect_outline(
+        buf, stride, w, h, x as isize, y as isize, sw, sh, 1, 255, 255, 255,
+    );
+    let text = format!("{} mm", side);
+    draw_label(
+        buf,
+        stride,
+        w,
+        h,
+        w.saturating_sub(text_width(&text, scale)) / 2,
+        h.saturating_sub(GLYPH_H * scale) / 2,
+        &text,
+        scale,
+    );
+    Some(side)
+}
 
-    for y in 0..h {
-        let by = (y / cell) & 1;
+// Every column of the horizontal sweep is one hue, so only the first row is
+// computed. Around the center, hue is the angle, in whole degrees so each
+// is looked up rather than converted per pixel.
+fn draw_hue_sweep(buf: &mut [u8], stride: usize, w: usize, h: usize, hue: HueSweep) {
+    if w == 0 || h == 0 {
+        return;
+    }
+    let color = |deg: f64| {
+        let (r, g, b) = color::hsv_to_rgb(deg, hue.saturation, hue.value);
+        xrgb(r, g, b)
+    };
+    if !hue.radial {
         for x in 0..w {
-            let bx = (x / cell) & 1;
-            let white = (bx ^ by) == 0;
-            let v = if white { 255 } else { 0 };
-            put_rgb(buf, stride, x, y, v, v, v);
+            buf[x * 4..x * 4 + 4].copy_from_slice(&color(360.0 * x as f64 / w as f64));
+        }
+        replicate_first_row(buf, stride, w, h);
+        return;
+    }
+    let lut: Vec<[u8; 4]> = (0..360).map(|deg| color(deg as f64)).collect();
+    let (cx, cy) = ((w - 1) as f32 / 2.0, (h - 1) as f32 / 2.0);
+    for y in 0..h {
+        let row = &mut buf[y * stride..y * stride + w * 4];
+        for (x, px) in row.chunks_exact_mut(4).enumerate() {
+            let deg = (cy - y as f32).atan2(x as f32 - cx).to_degrees();
+            px.copy_from_slice(&lut[(deg.rem_euclid(360.0) as usize).min(359)]);
         }
     }
 }
 
-fn draw_motion_bar(buf: &mut [u8], stride: usize, w: usize, h: usize, x_pos: usize, bar_w: usize) {
-    fill_rgb(buf, stride, w, h, 128, 128, 128);
+// Where the six primaries and secondaries fall along the horizontal sweep
+fn draw_hue_labels(buf: &mut [u8], stride: usize, w: usize, h: usize) {
+    let scale = (h / 540).max(1);
+    for (i, name) in ["R", "Y", "G", "C", "B", "M"].iter().enumerate() {
+        let x = i * w / 6;
+        fill_rect(buf, stride, w, h, x as isize, 0, 1, h / 16, 0, 0, 0);
+        draw_label(buf, stride, w, h, x + scale, h / 16, name, scale);
+    }
+}
 
-    let x0 = x_pos.min(w.saturating_sub(1));
-    let x1 = (x_pos + bar_w).min(w);
+// The radial gradient is symmetric about both axes, so one quadrant is
+// computed and mirrored into the other three. Levels come from a table
+// indexed by the distance in quarter pixels, leaving a sqrt per pixel of
+// the quadrant.
+fn draw_radial(buf: &mut [u8], stride: usize, w: usize, h: usize, radial: Radial, light: bool) {
+    if w == 0 || h == 0 {
+        return;
+    }
+    let (cx, cy) = ((w - 1) as f32 / 2.0, (h - 1) as f32 / 2.0);
+    let reach = (cx * cx + cy * cy).sqrt().max(1.0);
+    let lut: Vec<[u8; 4]> = (0..(reach * 4.0).ceil() as usize + 2)
+        .map(|i| {
+            let d = (i as f64 / 4.0 / reach as f64).min(1.0);
+            let level = (1.0 - d).powf(radial.exponent);
+            let level = if radial.inverse { 1.0 - level } else { level };
+            let v = if light {
+                color::srgb_encode_u8(level)
+            } else {
+                (level * 255.0).round() as u8
+            };
+            let (r, g, b) = radial.channels.apply(v);
+            xrgb(r, g, b)
+        })
+        .collect();
 
-    for y in 0..h {
-        for x in x0..x1 {
-            put_rgb(buf, stride, x, y, 230, 230, 230);
+    for y in 0..h.div_ceil(2) {
+        let dy = cy - y as f32;
+        let rows = [y * stride, (h - 1 - y) * stride];
+        for x in 0..w.div_ceil(2) {
+            let dx = cx - x as f32;
+            let px = lut[((dx * dx + dy * dy).sqrt() * 4.0 + 0.5) as usize];
+            for row in rows {
+                for col in [x, w - 1 - x] {
+                    buf[row + col * 4..row + col * 4 + 4].copy_from_slice(&px);
+                }
+            }
         }
     }
 }
 
-fn clamp_rect(
-    x: isize,
-    y: isize,
+fn draw_gradient(
+    buf: &mut [u8],
+    stride: usize,
     w: usize,
     h: usize,
-    ww: usize,
-    hh: usize,
-) -> (usize, usize, usize, usize) {
-    let mut x0 = x.max(0) as usize;
-    let mut y0 = y.max(0) as usize;
-    let mut rw = w.min(ww.saturating_sub(x0));
-    let mut rh = h.min(hh.saturating_sub(y0));
-    if x0 >= ww {
-        x0 = 0;
-        rw = 0;
-    }
-    if y0 >= hh {
-        y0 = 0;
-        rh = 0;
+    mode: GradMode,
+    vertical: bool,
+    light: bool,
+) {
+    let bands = mode.bands();
+    let n = bands.len();
+    let keep = |(r, g, b): (u8, u8, u8), [kr, kg, kb]: [bool; 3]| {
+        (r * kr as u8, g * kg as u8, b * kb as u8)
+    };
+    if vertical {
+        for (y, &color) in gradient_lut(h, mode, light).iter().enumerate() {
+            for (i, &(channels, _)) in bands.iter().enumerate() {
+                let (x0, x1) = (i * w / n, (i + 1) * w / n);
+                let (r, g, b) = keep(color, channels);
+                fill_row(&mut buf[y * stride + x0 * 4..], x1 - x0, r, g, b);
+            }
+        }
+    } else {
+        let lut = gradient_lut(w, mode, light);
+        for (i, &(channels, _)) in bands.iter().enumerate() {
+            let (y0, y1) = (i * h / n, (i + 1) * h / n);
+            if y0 == y1 {
+                continue;
+            }
+            let band = &mut buf[y0 * stride..];
+            for (x, &color) in lut.iter().enumerate() {
+                let (r, g, b) = keep(color, channels);
+                band[x * 4..x * 4 + 4].copy_from_slice(&xrgb(r, g, b));
+            }
+            replicate_first_row(band, stride, w, y1 - y0);
+        }
     }
-    (x0, y0, rw, rh)
 }
-fn fill_rect(
+
+// Red ticks along one edge wherever the ramp steps from one code value to the
+// next, found from the same table draw_gradient fills from, so a visible band
+// can be matched to the codes either side of it. The code every 32 levels is
+// written next to its tick. Returns how many steps there are.
+fn draw_gradient_ticks(
     buf: &mut [u8],
     stride: usize,
-    ww: usize,
-    hh: usize,
-    x: isize,
-    y: isize,
     w: usize,
     h: usize,
-    r: u8,
-    g: u8,
-    b: u8,
-) {
-    let (x0, y0, rw, rh) = clamp_rect(x, y, w, h, ww, hh);
-    for yy in y0..y0 + rh {
-        for xx in x0..x0 + rw {
-            put_rgb(buf, stride, xx, yy, r, g, b);
+    mode: GradMode,
+    vertical: bool,
+    light: bool,
+) -> usize {
+    let (len, across) = if vertical { (h, w) } else { (w, h) };
+    if len == 0 || across == 0 {
+        return 0;
+    }
+    let lut = gradient_lut(len, mode, light);
+    let tick = (across / 16).max(1);
+    let scale = (h / 540).max(1);
+    let label_h = GLYPH_H * scale + 4 * scale;
+
+    let mut steps = 0;
+    // Labels go in order along the ramp; this is where the last one ended
+    let mut free_from = 0;
+    for pos in 1..len {
+        // Grey ramp, so red stands for all three channels
+        let (from, to) = (lut[pos - 1].0, lut[pos].0);
+        if from == to {
+            continue;
+        }
+        steps += 1;
+        if vertical {
+            fill_rect(buf, stride, w, h, 0, pos as isize, tick, 1, 255, 0, 0);
+        } else {
+            fill_rect(buf, stride, w, h, pos as isize, 0, 1, tick, 255, 0, 0);
+        }
+
+        if from / 32 == to / 32 || pos < free_from {
+            continue;
+        }
+        let text = to.to_string();
+        let label_w = text_width(&text, scale) + 4 * scale;
+        if vertical {
+            if pos + label_h <= h {
+                draw_label(buf, stride, w, h, tick + scale, pos, &text, scale);
+                free_from = pos + label_h;
+            }
+        } else if pos + label_w <= w {
+            draw_label(buf, stride, w, h, pos, tick + scale, &text, scale);
+            free_from = pos + label_w;
         }
     }
+    steps
 }
-fn draw_rect_outline(
+
+// Code value and relative luminance at five points along the ramp, read back
+// from what was actually drawn, and the name of each band of an RGB ramp
+fn draw_gradient_labels(
     buf: &mut [u8],
     stride: usize,
-    ww: usize,
-    hh: usize,
-    x: isize,
-    y: isize,
     w: usize,
     h: usize,
-    t: usize,
-    r: u8,
-    g: u8,
-    b: u8,
+    mode: GradMode,
+    vertical: bool,
 ) {
-    fill_rect(buf, stride, ww, hh, x, y, w, t, r, g, b);
-    fill_rect(
-        buf,
-        stride,
-        ww,
-        hh,
-        x,
-        y as isize + (h as isize - t as isize),
-        w,
-        t,
-        r,
-        g,
-        b,
-    );
-    fill_rect(buf, stride, ww, hh, x, y, t, h, r, g, b);
-    fill_rect(
-        buf,
-        stride,
-        ww,
-        hh,
-        x as isize + (w as isize - t as isize),
-        y,
-        t,
-        h,
-        r,
-        g,
-        b,
-    );
-}
-fn draw_crosshair(buf: &mut [u8], stride: usize, w: usize, h: usize, r: u8, g: u8, b: u8) {
-    let cx = w / 2;
-    let cy = h / 2;
-    // horizontal line
-    for x in 0..w {
-        put_rgb(buf, stride, x, cy, r, g, b);
+    if w == 0 || h == 0 {
+        return;
     }
-    // vertical line
-    for y in 0..h {
-        put_rgb(buf, stride, cx, y, r, g, b);
+    let scale = (h / 540).max(1);
+    let bands = mode.bands();
+    if bands.len() > 1 {
+        // A quarter of the way into each band, clear of the ticks along the
+        // edge and the values along the middle
+        let n = bands.len();
+        for (i, &(_, name)) in bands.iter().enumerate() {
+            let (x, y) = if vertical {
+                (i * w / n + w / (4 * n), 0)
+            } else {
+                (0, i * h / n + h / (4 * n))
+            };
+            draw_label(buf, stride, w, h, x, y, name, scale);
+        }
     }
+    let len = if vertical { h } else { w };
+    // Labels that can't all fit side by side would only cover each other
+    let widest = text_width("255 100.0%", scale) + 4 * scale;
+    let tallest = GLYPH_H * scale + 4 * scale;
+    if 5 * if vertical { tallest } else { widest } > len {
+        return;
+    }
+
+    // Sample every point before drawing so no label reads another's box
+    let marks: Vec<(usize, u8)> = (0..5)
+        .map(|q| {
+            let pos = q * (len - 1) / 4;
+            let (x, y) = if vertical { (w / 2, pos) } else { (pos, h / 2) };
+            // Whichever channel the band there lights; all three are equal
+            // on a grey ramp
+            let p = &buf[y * stride + x * 4..];
+            (pos, p[0].max(p[1]).max(p[2]))
+        })
+        .collect();
+
+    for (pos, code) in marks {
+        let text = format!("{} {:.1}%", code, color::srgb_decode_u8(code) * 100.0);
+
+        let label_w = text_width(&text, scale) + 4 * scale;
+        let label_h = GLYPH_H * scale + 4 * scale;
+        let (x, y) = if vertical {
+            (w / 2, pos.min(h.saturating_sub(label_h)))
+        } else {
+            (pos.min(w.saturating_sub(label_w)), h / 2)
+        };
+        draw_label(buf, stride, w, h, x, y, &text, scale);
+    }
+}
+
+// Every row is one of two phases of the same pattern, so build a single strip
+// covering a full period of slack and copy a window of it per row. `offset`
+// shifts the whole pattern horizontally by that many pixels. The top left
+// cell is `colors[0]`.
+fn draw_checkerboard(
+    buf: &mut [u8],
+    stride: usize,
+    w: usize,
+    h: usize,
+    cell: usize,
+    offset: usize,
+    colors: ColorPair,
+) {
+    let cell = cell.max(1);
+    let period = 2 * cell;
+    let pixels = colors.map(|(r, g, b)| xrgb(r, g, b));
+
+    let mut strip = vec![0u8; (w + period) * 4];
+    for (i, px) in strip.chunks_exact_mut(4).enumerate() {
+        px.copy_from_slice(&pixels[(i / cell) & 1]);
+    }
+
+    for y in 0..h {
+        let by = (y / cell) & 1;
+        let start = (offset % period + by * cell) % period;
+        let row = y * stride;
+        buf[row..row + w * 4].copy_from_slice(&strip[start * 4..(start + w) * 4]);
+    }
+}
+
+// Campbell-Robson chart: spatial frequency sweeps logarithmically from 1/256
+// to 1/2 cycles per pixel left to right, and contrast falls logarithmically
+// from 100% to 0.1% top to bottom, around mid grey. Where the grating fades
+// out traces the contrast sensitivity curve. The sine depends only on x and
+// the contrast only on y, so both are computed once per column and row.
+fn draw_csf(buf: &mut [u8], stride: usize, w: usize, h: usize) {
+    const MIN_FREQ: f64 = 1.0 / 256.0;
+    const MAX_FREQ: f64 = 0.5;
+    const MIN_CONTRAST: f64 = 0.001;
+
+    // Integrating the frequency keeps the phase continuous as it sweeps
+    let mut phase = 0.0f64;
+    let wave: Vec<f64> = (0..w)
+        .map(|x| {
+            let t = x as f64 / (w - 1).max(1) as f64;
+            let s = phase.sin();
+            phase += std::f64::consts::TAU * MIN_FREQ * (MAX_FREQ / MIN_FREQ).powf(t);
+            s
+        })
+        .collect();
+
+    for y in 0..h {
+        let t = y as f64 / (h - 1).max(1) as f64;
+        let contrast = MIN_CONTRAST.powf(t);
+        let row = &mut buf[y * stride..y * stride + w * 4];
+        for (px, s) in row.chunks_exact_mut(4).zip(&wave) {
+            let v = (127.5 + 127.5 * contrast * s).round() as u8;
+            px.copy_from_slice(&xrgb(v, v, v));
+        }
+    }
+}
+
+const SUBPIXEL_SAMPLE: &str = "Sphinx of black quartz, judge my vow 0123456789";
+const SUBPIXEL_SIZES: [f64; 9] = [8.0, 9.0, 10.0, 11.0, 12.0, 14.0, 16.0, 20.0, 24.0];
+
+// Sample text at small sizes, grayscale anti-aliased on the left and
+// subpixel anti-aliased for `order` on the right, dark on light in the top
+// half and light on dark in the bottom. Where `order` matches the panel the
+// right side looks crisper; where it doesn't, edges grow color fringes.
+fn draw_subpixel_text(buf: &mut [u8], stride: usize, w: usize, h: usize, order: SubpixelOrder) {
+    const WHITE: (u8, u8, u8) = (255, 255, 255);
+    const BLACK: (u8, u8, u8) = (0, 0, 0);
+
+    let half = w / 2;
+    let scale = (h / 540).max(1);
+    fill_rgb(buf, stride, w, h, 255, 255, 255);
+    fill_rect(
+        buf,
+        stride,
+        w,
+        h,
+        0,
+        (h / 2) as isize,
+        w,
+        h - h / 2,
+        0,
+        0,
+        0,
+    );
+    // A hairline between the halves, so nothing reads as one long line
+    fill_rect(buf, stride, w, h, half as isize, 0, 1, h, 128, 128, 128);
+
+    for (x0, cols, subpixel) in [(0, half, None), (half + 1, w - half - 1, Some(order))] {
+        let region = &mut buf[x0 * 4..];
+        let label = match subpixel {
+            None => "grayscale AA".to_string(),
+            Some(order) => format!("subpixel AA, {}", order.name()),
+        };
+        for (y0, fg, bg) in [(0, BLACK, WHITE), (h / 2, WHITE, BLACK)] {
+            draw_label(region, stride, cols, h, 16, y0 + 8, &label, scale);
+            let mut y = y0 + 16 + (GLYPH_H + 8) * scale;
+            for size in SUBPIXEL_SIZES {
+                draw_text_aa(
+                    region,
+                    stride,
+                    cols,
+                    h,
+                    16,
+                    y,
+                    SUBPIXEL_SAMPLE,
+                    size,
+                    fg,
+                    bg,
+                    subpixel,
+                );
+                y += (size * 1.5).ceil() as usize;
+            }
+        }
+    }
+}
+
+// Color pairs that differ mostly in chroma, so 4:2:0 or 4:2:2 subsampling
+// blurs their single-pixel detail into a flat mix
+const CHROMA_PAIRS: [ColorPair; 2] = [[(255, 0, 0), (0, 0, 255)], [(255, 0, 255), (0, 255, 0)]];
+
+// A grid of single-pixel chroma detail: columns, rows and a checker (left to
+// right) in each color pair (top to bottom). Through full 4:4:4 every panel
+// shows crisp lines; with chroma subsampled, columns blur under 4:2:2 and
+// everything blurs under 4:2:0.
+fn draw_chroma_subsampling(buf: &mut [u8], stride: usize, w: usize, h: usize) {
+    const PANELS: [&str; 3] = ["1 px columns", "1 px rows", "1 px checker"];
+
+    let scale = (h / 540).max(1);
+    for (row, [a, b]) in CHROMA_PAIRS.into_iter().enumerate() {
+        let (y0, y1) = (
+            row * h / CHROMA_PAIRS.len(),
+            (row + 1) * h / CHROMA_PAIRS.len(),
+        );
+        for (col, label) in PANELS.into_iter().enumerate() {
+            let (x0, x1) = (col * w / PANELS.len(), (col + 1) * w / PANELS.len());
+            for y in y0..y1 {
+                for x in x0..x1 {
+                    let first = match col {
+                        0 => x.is_multiple_of(2),
+                        1 => y.is_multiple_of(2),
+                        _ => (x + y).is_multiple_of(2),
+                    };
+                    let (r, g, b) = if first { a } else { b };
+                    put_rgb_unchecked(buf, stride, x, y, r, g, b);
+                }
+            }
+            draw_label(buf, stride, w, h, x0 + 8, y0 + 8, label, scale);
+        }
+    }
+}
+
+// The top row of SMPTE color bars: 75% white, yellow, cyan, green,
+// magenta, red and blue
+const SMPTE_BARS: [(u8, u8, u8); 7] = [
+    (191, 191, 191),
+    (191, 191, 0),
+    (0, 191, 191),
+    (0, 191, 0),
+    (191, 0, 191),
+    (191, 0, 0),
+    (0, 0, 191),
+];
+
+// One full-height bar per color, left to right. Bar i covers columns
+// i * w / n up to (i + 1) * w / n, so widths differ by at most a pixel.
+fn draw_color_bars(buf: &mut [u8], stride: usize, w: usize, h: usize, colors: &[(u8, u8, u8)]) {
+    let n = colors.len();
+    for (i, &(r, g, b)) in colors.iter().enumerate() {
+        let (x0, x1) = (i * w / n, (i + 1) * w / n);
+        fill_rect(buf, stride, w, h, x0 as isize, 0, x1 - x0, h, r, g, b);
+    }
+}
+
+// Three black panels with a large R, G and B drawn in pure red, green and
+// blue, each named underneath in white. A letter in the wrong color gives
+// away hardware that puts the channels in a different order.
+fn draw_channel_order(buf: &mut [u8], stride: usize, w: usize, h: usize, ui: usize) {
+    const CHANNELS: [(&str, &str, (u8, u8, u8)); 3] = [
+        ("R", "should be red", (255, 0, 0)),
+        ("G", "should be green", (0, 255, 0)),
+        ("B", "should be blue", (0, 0, 255)),
+    ];
+
+    fill_rgb(buf, stride, w, h, 64, 64, 64);
+    let n = CHANNELS.len();
+    let gap = 8 * ui;
+    let panel_w = w.saturating_sub((n + 1) * gap) / n;
+    let panel_h = h / 2;
+    let y = (h - panel_h) / 2;
+    let letter_scale = (panel_w.min(panel_h) * 2 / 3 / GLYPH_H).max(1);
+    for (i, (letter, name, (r, g, b))) in CHANNELS.into_iter().enumerate() {
+        let x = gap + i * (panel_w + gap);
+        fill_rect(
+            buf, stride, w, h, x as isize, y as isize, panel_w, panel_h, 0, 0, 0,
+        );
+        let lx = x + panel_w.saturating_sub(text_width(letter, letter_scale)) / 2;
+        let ly = y + panel_h.saturating_sub(GLYPH_H * letter_scale) / 2;
+        draw_text(buf, stride, w, h, lx, ly, letter, letter_scale, r, g, b);
+        let nx = x + panel_w.saturating_sub(text_width(name, 2 * ui)) / 2;
+        let ny = y + panel_h + GLYPH_H * ui;
+        draw_text(buf, stride, w, h, nx, ny, name, 2 * ui, 255, 255, 255);
+    }
+}
+
+// Band `bits` of the bit depth pattern at x of w: the ramp quantized to
+// 2^bits levels, spread over the full code range
+fn bit_depth_level(x: usize, w: usize, bits: u32) -> u8 {
+    let top = (1u32 << bits) - 1;
+    let step = (x * (top as usize + 1) / w.max(1)).min(top as usize) as u32;
+    (step * 255 / top) as u8
+}
+
+// Eight bands stacked top to bottom, each the same left-to-right ramp in
+// one channel quantized to 1, 2, ... 8 bits. Every band up to the panel's
+// real depth shows clean, even steps; past it, neighbouring steps merge or
+// shimmer with FRC, so 6-bit+FRC shows up as bands 7 and 8 looking like 6.
+fn draw_bit_depth(
+    buf: &mut [u8],
+    stride: usize,
+    w: usize,
+    h: usize,
+    channels: RadialChannels,
+    ui: usize,
+    labels: bool,
+) {
+    const BANDS: usize = 8;
+    for y in 0..h {
+        let bits = (y * BANDS / h.max(1)).min(BANDS - 1) as u32 + 1;
+        let row = &mut buf[y * stride..];
+        for x in 0..w {
+            let (r, g, b) = channels.apply(bit_depth_level(x, w, bits));
+            row[x * 4..x * 4 + 4].copy_from_slice(&xrgb(r, g, b));
+        }
+    }
+    if labels {
+        for band in 0..BANDS {
+            let y = band * h / BANDS + GLYPH_H * ui / 2;
+            let text = format!("{}-bit", band + 1);
+            draw_label(buf, stride, w, h, GLYPH_H * ui, y, &text, 2 * ui);
+        }
+    }
+}
+
+const G2G_MAX_LEVELS: usize = 9;
+
+// Level i of n on the gray-to-gray grid's axes, evenly spaced from black to
+// white
+fn g2g_level(i: usize, n: usize) -> u8 {
+    (i * 255 / (n - 1)) as u8
+}
+
+// An n x n grid of blocks on black, block (row i, column j) switching
+// between level i and level j every flip, so each off-diagonal block shows
+// the transition both ways and overshoot at any pair stands out as a halo
+// or flash against its neighbours. `phase` is which of the two frames this
+// is; the diagonal holds still.
+fn draw_gray_to_gray(buf: &mut [u8], stride: usize, w: usize, h: usize, n: usize, phase: usize) {
+    fill_rgb(buf, stride, w, h, 0, 0, 0);
+    let side = w.min(h) * 9 / 10;
+    let cell = side / n;
+    let gap = (cell / 8).max(1);
+    let (x0, y0) = ((w - cell * n) / 2, (h - cell * n) / 2);
+    for i in 0..n {
+        for j in 0..n {
+            let v = if phase == 0 {
+                g2g_level(i, n)
+            } else {
+                g2g_level(j, n)
+            };
+            let (x, y) = (x0 + j * cell + gap / 2, y0 + i * cell + gap / 2);
+            let size = cell.saturating_sub(gap);
+            fill_rect(
+                buf, stride, w, h, x as isize, y as isize, size, size, v, v, v,
+            );
+        }
+    }
+}
+
+// Where the dots pattern puts its dot on flip `frame`: a fixed pseudo-random
+// place for every flip of a given seed. A camera exposure over many refreshes
+// shows one dot per refresh; a flip that misses its vblank leaves its dot up
+// for two, so it stands out brighter and the exposure has fewer dots.
+fn pov_dot_position(seed: u64, frame: u64, w: usize, h: usize) -> (usize, usize) {
+    let mut x = seed ^ frame.wrapping_mul(0xd1b5_4a32_d192_ed03);
+    let v = library::splitmix64(&mut x);
+    (
+        ((v & 0xffff_ffff) % w.max(1) as u64) as usize,
+        ((v >> 32) % h.max(1) as u64) as usize,
+    )
+}
+
+// Approximate sRGB values of the 24 patches of the classic color checker
+// chart, row by row
+const COLOR_CHECKER: [(&str, (u8, u8, u8)); 24] = [
+    ("dark skin", (115, 82, 68)),
+    ("light skin", (194, 150, 130)),
+    ("blue sky", (98, 122, 157)),
+    ("foliage", (87, 108, 67)),
+    ("blue flower", (133, 128, 177)),
+    ("bluish green", (103, 189, 170)),
+    ("orange", (214, 126, 44)),
+    ("purplish blue", (80, 91, 166)),
+    ("moderate red", (193, 90, 99)),
+    ("purple", (94, 60, 108)),
+    ("yellow green", (157, 188, 64)),
+    ("orange yellow", (224, 163, 46)),
+    ("blue", (56, 61, 150)),
+    ("green", (70, 148, 73)),
+    ("red", (175, 54, 60)),
+    ("yellow", (231, 199, 31)),
+    ("magenta", (187, 86, 149)),
+    ("cyan", (8, 133, 161)),
+    ("white 9.5", (243, 243, 242)),
+    ("neutral 8", (200, 200, 200)),
+    ("neutral 6.5", (160, 160, 160)),
+    ("neutral 5", (122, 122, 121)),
+    ("neutral 3.5", (85, 85, 85)),
+    ("black 2", (52, 52, 52)),
+];
+
+// 6x4 grid of color checker patches centered on a black background, sized so
+// each patch is square and large enough for a colorimeter
+fn draw_color_checker(buf: &mut [u8], stride: usize, w: usize, h: usize, labels: bool) {
+    const COLS: usize = 6;
+    const ROWS: usize = 4;
+
+    fill_rgb(buf, stride, w, h, 0, 0, 0);
+
+    let pitch = (w * 9 / 10 / COLS).min(h * 9 / 10 / ROWS);
+    let gap = pitch / 10;
+    let patch = pitch - gap;
+    let x0 = (w.saturating_sub(pitch * COLS - gap)) / 2;
+    let y0 = (h.saturating_sub(pitch * ROWS - gap)) / 2;
+    let scale = (patch / 120).max(1);
+
+    for (i, &(name, (r, g, b))) in COLOR_CHECKER.iter().enumerate() {
+        let x = x0 + (i % COLS) * pitch;
+        let y = y0 + (i / COLS) * pitch;
+        fill_rect(
+            buf, stride, w, h, x as isize, y as isize, patch, patch, r, g, b,
+        );
+
+        if labels && text_width(name, scale) <= patch {
+            let (tr, tg, tb) = if (r as u32 + g as u32 + b as u32) > 3 * 128 {
+                (0, 0, 0)
+            } else {
+                (255, 255, 255)
+            };
+            draw_text(
+                buf,
+                stride,
+                w,
+                h,
+                x + (patch - text_width(name, scale)) / 2,
+                y + patch - (GLYPH_H + 2) * scale,
+                name,
+                scale,
+                tr,
+                tg,
+                tb,
+            );
+        }
+    }
+}
+
+// The output sets no colorimetry on the connector, so the sink takes every
+// frame as sRGB, which has the Rec.709 primaries
+const SIGNAL_GAMUT: Gamut = Gamut::Rec709;
+
+// The six gamut corners, full red, green, blue, cyan, magenta and yellow of
+// `target`, in a row of primaries over a row of secondaries, with a white
+// and a 50% (linear) gray patch as the neutral reference. Each is sent as
+// the nearest color the signaling can carry, with the target's x, y on it
+// for the meter to be checked against. Returns true if any corner had to be
+// clipped to fit.
+fn draw_gamut_corners(
+    buf: &mut [u8],
+    stride: usize,
+    w: usize,
+    h: usize,
+    target: Gamut,
+    labels: bool,
+) -> bool {
+    const PATCHES: [(&str, [f64; 3]); 8] = [
+        ("R", [1.0, 0.0, 0.0]),
+        ("G", [0.0, 1.0, 0.0]),
+        ("B", [0.0, 0.0, 1.0]),
+        ("W", [1.0, 1.0, 1.0]),
+        ("C", [0.0, 1.0, 1.0]),
+        ("M", [1.0, 0.0, 1.0]),
+        ("Y", [1.0, 1.0, 0.0]),
+        ("50%", [0.5, 0.5, 0.5]),
+    ];
+    const COLS: usize = 4;
+
+    fill_rgb(buf, stride, w, h, 0, 0, 0);
+    let pitch = (w * 9 / 10 / COLS).min(h * 9 / 10 / 2);
+    let gap = pitch / 16;
+    let patch = pitch - gap;
+    let x0 = w.saturating_sub(pitch * COLS - gap) / 2;
+    let y0 = h.saturating_sub(pitch * 2 - gap) / 2;
+    let scale = (patch / 160).max(1);
+
+    let mut any_clipped = false;
+    for (i, (name, rgb)) in PATCHES.into_iter().enumerate() {
+        let (signal, clipped) = target.convert(rgb, SIGNAL_GAMUT);
+        any_clipped |= clipped;
+        let [r, g, b] = signal.map(color::srgb_encode_u8);
+        let (x, y) = (x0 + (i % COLS) * pitch, y0 + (i / COLS) * pitch);
+        fill_rect(
+            buf, stride, w, h, x as isize, y as isize, patch, patch, r, g, b,
+        );
+        if !labels {
+            continue;
+        }
+        let (cx, cy) = target.xy(rgb);
+        let lines = [
+            name.to_string(),
+            format!("x {:.3} y {:.3}", cx, cy),
+            format!("sent {} {} {}", r, g, b),
+            if clipped { "clipped" } else { "" }.to_string(),
+        ];
+        let (tr, tg, tb) = if (r as u32 + g as u32 + b as u32) > 3 * 128 {
+            (0, 0, 0)
+        } else {
+            (255, 255, 255)
+        };
+        let mut ty = y + GLYPH_H * scale;
+        for line in lines.iter().filter(|l| !l.is_empty()) {
+            if text_width(line, scale) <= patch {
+                let tx = x + (patch - text_width(line, scale)) / 2;
+                draw_text(buf, stride, w, h, tx, ty, line, scale, tr, tg, tb);
+            }
+            ty += (GLYPH_H + 2) * scale;
+        }
+    }
+    any_clipped
+}
+
+// Near-black strips (1..=5) in the top-left corner and near-white strips
+// (250..=254) mirrored in the bottom-right, on black. Each strip should be
+// distinguishable from its neighbours and the background on a well set up
+// display.
+fn draw_patches(buf: &mut [u8], stride: usize, w: usize, h: usize) {
+    const STRIPS: usize = 5;
+
+    fill_rgb(buf, stride, w, h, 0, 0, 0);
+
+    // A fifth of the short side, so the two corners stay apart at any size,
+    // and no more than half of it on displays too small for one pixel a strip
+    let colors_area = (w.min(h) / 5).max(STRIPS).min(w.min(h) / 2);
+    let color_area = colors_area / STRIPS;
+
+    for i in 0..STRIPS {
+        let dark = 1 + i as u8;
+        fill_rect(
+            buf,
+            stride,
+            w,
+            h,
+            0,
+            (i * color_area) as isize,
+            colors_area,
+            color_area,
+            dark,
+            dark,
+            dark,
+        );
+
+        let light = 250 + i as u8;
+        fill_rect(
+            buf,
+            stride,
+            w,
+            h,
+            (w - colors_area) as isize,
+            (h - colors_area + i * color_area) as isize,
+            colors_area,
+            color_area,
+            light,
+            light,
+            light,
+        );
+    }
+}
+
+const NEAR_BLACK_MAX: u8 = 8;
+
+// The patches on the near-black pattern's `base` fill, left to right: two
+// code values below it, stopping at black, and two above
+fn near_black_levels(base: u8) -> [u8; 4] {
+    [base.saturating_sub(2), base - 1, base + 1, base + 2]
+}
+
+// A fill at code value `base` with a row of patches a step or two either
+// side of it, for black crush and bleed right at the threshold of
+// visibility. The value is written large in the corner and under each
+// patch, only bright enough to find, so the eye stays adapted to the dark.
+fn draw_near_black(buf: &mut [u8], stride: usize, w: usize, h: usize, base: u8, ui: usize) {
+    const TEXT: u8 = 40;
+
+    fill_rgb(buf, stride, w, h, base, base, base);
+
+    let levels = near_black_levels(base);
+    let n = levels.len();
+    let side = (w / (2 * n + 1)).min(h / 3);
+    let gap = (w - n * side) / (n + 1);
+    let y = (h - side) / 2;
+    for (i, &v) in levels.iter().enumerate() {
+        let x = gap + i * (side + gap);
+        fill_rect(
+            buf, stride, w, h, x as isize, y as isize, side, side, v, v, v,
+        );
+        let label = v.to_string();
+        let tx = x + side.saturating_sub(text_width(&label, 2 * ui)) / 2;
+        let ty = y + side + GLYPH_H * ui;
+        draw_text(buf, stride, w, h, tx, ty, &label, 2 * ui, TEXT, TEXT, TEXT);
+    }
+
+    // No bigger than fits above the patches, with a margin as wide
+    let big = (8 * ui).min(y / 2 / GLYPH_H).max(1);
+    let value = format!("{}", base);
+    draw_text(buf, stride, w, h, big, big, &value, big, TEXT, TEXT, TEXT);
+}
+
+// Bar spanning [x_pos, x_pos + bar_w) over a gray background. With `aa` the
+// edge columns are blended by how much of them the bar covers, so sub-pixel
+// positions render smoothly; without it the bar snaps to whole pixels. The
+// screen wraps around: whatever hangs off the right edge re-enters on the left.
+#[allow(clippy::too_many_arguments)]
+fn draw_motion_bar(
+    buf: &mut [u8],
+    stride: usize,
+    w: usize,
+    h: usize,
+    x_pos: f64,
+    bar_w: usize,
+    aa: bool,
+) {
+    const BG: (u8, u8, u8) = (128, 128, 128);
+    const FG: (u8, u8, u8) = (230, 230, 230);
+
+    if w == 0 || h == 0 {
+        return;
+    }
+
+    fill_row(buf, w, BG.0, BG.1, BG.2);
+
+    // Keep at least one column of background so the two partially covered
+    // edge columns can never land on the same pixel
+    let bar_w = bar_w.min(w - 1);
+    let x_pos = x_pos.rem_euclid(w as f64);
+    let start = if aa { x_pos } else { x_pos.floor() };
+    let end = start + bar_w as f64;
+
+    for x in start.floor() as usize..end.ceil() as usize {
+        let cover = (end.min(x as f64 + 1.0) - start.max(x as f64)).clamp(0.0, 1.0);
+        let (r, g, b) = mix_rgb(BG, FG, (cover * 255.0).round() as u8);
+        put_rgb(buf, stride, x % w, 0, r, g, b);
+    }
+
+    replicate_first_row(buf, stride, w, h);
+}
+
+// One eye's picture for the 3D alignment pattern: a grid in eighths, a
+// frame round the edge so a cropped or shifted half shows, and a crosshair
+// moved (dx, dy) from the center with the eye's letter by it, in the eye's
+// anaglyph color, red for left and cyan for right
+#[allow(clippy::too_many_arguments)]
+fn draw_eye(
+    buf: &mut [u8],
+    stride: usize,
+    w: usize,
+    h: usize,
+    right: bool,
+    dx: i32,
+    dy: i32,
+    ui: usize,
+) {
+    fill_rgb(buf, stride, w, h, 0, 0, 0);
+    if w == 0 || h == 0 {
+        return;
+    }
+    for i in 1..8 {
+        let (x, y) = ((i * w / 8) as isize, (i * h / 8) as isize);
+        draw_line(buf, stride, w, h, x, 0, x, h as isize - 1, 1, 64, 64, 64);
+        draw_line(buf, stride, w, h, 0, y, w as isize - 1, y, 1, 64, 64, 64);
+    }
+    draw_rect_outline(buf, stride, w, h, 0, 0, w, h, 1, 255, 255, 255);
+
+    let (r, g, b) = if right { (0, 255, 255) } else { (255, 0, 0) };
+    let cx = (w / 2) as isize + dx as isize;
+    let cy = (h / 2) as isize + dy as isize;
+    let arm = (w.min(h) / 6) as isize;
+    let t = ui.max(1);
+    draw_line(buf, stride, w, h, cx - arm, cy, cx + arm, cy, t, r, g, b);
+    draw_line(buf, stride, w, h, cx, cy - arm, cx, cy + arm, t, r, g, b);
+    let letter = if right { "R" } else { "L" };
+    let scale = 4 * ui;
+    let (lx, ly) = (cx + arm / 4, cy + arm / 4);
+    if lx >= 0 && ly >= 0 {
+        draw_text(
+            buf,
+            stride,
+            w,
+            h,
+            lx as usize,
+            ly as usize,
+            letter,
+            scale,
+            r,
+            g,
+            b,
+        );
+    }
+}
+
+fn draw_crosshair(buf: &mut [u8], stride: usize, w: usize, h: usize, r: u8, g: u8, b: u8) {
+    let cx = (w / 2) as isize;
+    let cy = (h / 2) as isize;
+    draw_line(buf, stride, w, h, 0, cy, w as isize - 1, cy, 1, r, g, b);
+    draw_line(buf, stride, w, h, cx, 0, cx, h as isize - 1, 1, r, g, b);
+}
+
+// The largest box of the given aspect ratio that fits in w x h, centered:
+// letterboxed on a display narrower than the ratio, pillarboxed on a wider
+// one. Returns (x, y, width, height).
+fn fit_aspect(w: usize, h: usize, ratio: f64) -> (usize, usize, usize, usize) {
+    let (bw, bh) = if w as f64 / h.max(1) as f64 > ratio {
+        (((h as f64 * ratio).round() as usize).min(w), h)
+    } else {
+        (w, ((w as f64 / ratio).round() as usize).min(h))
+    };
+    ((w - bw) / 2, (h - bh) / 2, bw, bh)
+}
+
+// Squaring a projected image to its screen: a full-frame outline (left off
+// while `outline` is false, for blinking), diagonals corner to corner, the
+// box of the target aspect inside the outline, ticks every 1% along every
+// edge and emphasized center lines
+fn draw_keystone(
+    buf: &mut [u8],
+    stride: usize,
+    w: usize,
+    h: usize,
+    aspect: TargetAspect,
+    outline: bool,
+) {
+    fill_rgb(buf, stride, w, h, 0, 0, 0);
+    if w == 0 || h == 0 {
+        return;
+    }
+    let t = (w.min(h) / 540).max(1);
+    let (right, bottom) = (w as isize - 1, h as isize - 1);
+
+    draw_line(buf, stride, w, h, 0, 0, right, bottom, t, 128, 128, 128);
+    draw_line(buf, stride, w, h, right, 0, 0, bottom, t, 128, 128, 128);
+
+    // Ticks grow at every 5% and again at every 10%
+    let short = (w.min(h) / 60).max(2);
+    for i in 1..100 {
+        let len = match i {
+            _ if i % 10 == 0 => short * 3,
+            _ if i % 5 == 0 => short * 2,
+            _ => short,
+        };
+        let (x, y) = ((i * w / 100) as isize, (i * h / 100) as isize);
+        // Where the ticks on the bottom and right edges start
+        let (far_y, far_x) = (h as isize - len as isize, w as isize - len as isize);
+        fill_rect(buf, stride, w, h, x, 0, t, len, 255, 255, 255);
+        fill_rect(buf, stride, w, h, x, far_y, t, len, 255, 255, 255);
+        fill_rect(buf, stride, w, h, 0, y, len, t, 255, 255, 255);
+        fill_rect(buf, stride, w, h, far_x, y, len, t, 255, 255, 255);
+    }
+
+    // Centered, two ticks thick
+    let (cx, cy) = ((w / 2) as isize - t as isize, (h / 2) as isize - t as isize);
+    fill_rect(buf, stride, w, h, cx, 0, 2 * t, h, 255, 255, 0);
+    fill_rect(buf, stride, w, h, 0, cy, w, 2 * t, 255, 255, 0);
+
+    // Inside the outline, so the two stay apart on the side where the box
+    // fills the screen
+    let inset = 2 * t;
+    let (bx, by, bw, bh) = fit_aspect(
+        w.saturating_sub(2 * inset),
+        h.saturating_sub(2 * inset),
+        aspect.ratio(),
+    );
+    draw_rect_outline(
+        buf,
+        stride,
+        w,
+        h,
+        (bx + inset) as isize,
+        (by + inset) as isize,
+        bw,
+        bh,
+        t,
+        0,
+        255,
+        255,
+    );
+
+    if outline {
+        draw_rect_outline(buf, stride, w, h, 0, 0, w, h, t, 255, 255, 255);
+    }
+}
+
+// Focus chart: fine detail everywhere at once, with a sparse grid of plus
+// marks on black pads so focus can be judged region by region. Returns the
+// spacing of the circles.
+fn draw_focus(buf: &mut [u8], stride: usize, w: usize, h: usize, focus: Focus) -> usize {
+    let (r, g, b) = if focus.green {
+        (0, 255, 0)
+    } else {
+        (255, 255, 255)
+    };
+    let spacing = (w.min(h) / 32).max(4);
+    if focus.rings {
+        fill_rgb(buf, stride, w, h, 0, 0, 0);
+        let (cx, cy) = ((w / 2) as isize, (h / 2) as isize);
+        // Out to the corners
+        let reach = (w / 2).max(1).pow(2) + (h / 2).max(1).pow(2);
+        let reach = (reach as f64).sqrt() as usize;
+        for radius in (spacing..=reach).step_by(spacing) {
+            draw_circle_outline(buf, stride, w, h, cx, cy, radius, 1, r, g, b);
+        }
+    } else {
+        draw_checkerboard(buf, stride, w, h, 1, 0, [(0, 0, 0), (r, g, b)]);
+    }
+
+    // An 8x8 grid of marks, each in the middle of its cell
+    let arm = (w.min(h) / 64).clamp(2, 12);
+    for i in 0..8 {
+        for j in 0..8 {
+            let x = ((2 * i + 1) * w / 16) as isize;
+            let y = ((2 * j + 1) * h / 16) as isize;
+            let (a, pad) = (arm as isize, arm as isize + 2);
+            let side = 2 * pad as usize + 1;
+            fill_rect(buf, stride, w, h, x - pad, y - pad, side, side, 0, 0, 0);
+            fill_rect(buf, stride, w, h, x - a, y, 2 * arm + 1, 1, r, g, b);
+            fill_rect(buf, stride, w, h, x, y - a, 1, 2 * arm + 1, r, g, b);
+        }
+    }
+    spacing
+}
+
+// Whether the blinking keystone outline is showing in the frame drawn after
+// `flip_count` flips: half of every second
+fn keystone_outline_on(flip_count: u64, refresh_hz: f64) -> bool {
+    let period = refresh_hz.round().max(2.0) as u64;
+    flip_count % period < period / 2
 }
+
 fn draw_viewing_card(buf: &mut [u8], stride: usize, w: usize, h: usize) {
+    if w == 0 || h == 0 {
+        return;
+    }
+
     // black background
     fill_rgb(buf, stride, w, h, 0, 0, 0);
 
@@ -404,9 +2015,13 @@ fn draw_viewing_card(buf: &mut [u8], stride: usize, w: usize, h: usize) {
     let t = (w.min(h) / 200).max(2);
     draw_rect_outline(buf, stride, w, h, 0, 0, w, h, t, 255, 255, 255);
 
-    // corner boxes with high-contrast content
-    let box_w = (w / 5).max(80);
-    let box_h = (h / 5).max(80);
+    // corner boxes with high-contrast content, a fifth of each side so they
+    // stay clear of each other and of the center circle at any size
+    let m = t * 2;
+    let box_w = w / 5;
+    let box_h = h / 5;
+    let right = w.saturating_sub(box_w + m);
+    let bottom = h.saturating_sub(box_h + m);
     // TL: white box
     fill_rect(
         buf,
@@ -427,18 +2042,18 @@ fn draw_viewing_card(buf: &mut [u8], stride: usize, w: usize, h: usize) {
         for xx in 0..box_w {
             let on = ((xx / cell + yy / cell) & 1) == 0;
             let v = if on { 255 } else { 0 };
-            put_rgb(buf, stride, w - box_w - t * 2 + xx, t * 2 + yy, v, v, v);
+            put_rgb(buf, stride, right + xx, m + yy, v, v, v);
         }
     }
     // BL: vertical color bars (R,G,B)
-    let seg = (box_w / 3).max(8);
+    let seg = box_w / 3;
     fill_rect(
         buf,
         stride,
         w,
         h,
         t as isize * 2,
-        (h - box_h - t * 2) as isize,
+        bottom as isize,
         seg,
         box_h,
         255,
@@ -451,7 +2066,7 @@ fn draw_viewing_card(buf: &mut [u8], stride: usize, w: usize, h: usize) {
         w,
         h,
         (t * 2 + seg) as isize,
-        (h - box_h - t * 2) as isize,
+        bottom as isize,
         seg,
         box_h,
         0,
@@ -464,7 +2079,7 @@ fn draw_viewing_card(buf: &mut [u8], stride: usize, w: usize, h: usize) {
         w,
         h,
         (t * 2 + 2 * seg) as isize,
-        (h - box_h - t * 2) as isize,
+        bottom as isize,
         seg,
         box_h,
         0,
@@ -475,80 +2090,688 @@ fn draw_viewing_card(buf: &mut [u8], stride: usize, w: usize, h: usize) {
     for yy in 0..box_h {
         for xx in 0..box_w {
             let v = if ((xx + yy) / 8) % 2 == 0 { 220 } else { 30 };
-            put_rgb(
-                buf,
-                stride,
-                w - box_w - t * 2 + xx,
-                h - box_h - t * 2 + yy,
-                v,
-                v,
-                v,
-            );
+            put_rgb(buf, stride, right + xx, bottom + yy, v, v, v);
         }
     }
 
-    // center crosshair
+    // anti-aliased diagonals; stepping or beating along them shows resampling
+    let (fw, fh) = ((w - 1) as f64, (h - 1) as f64);
+    draw_line_aa(buf, stride, w, h, 0.0, 0.0, fw, fh, 1.0, 96, 96, 96);
+    draw_line_aa(buf, stride, w, h, fw, 0.0, 0.0, fh, 1.0, 96, 96, 96);
+
+    // center circle, which only stays round if pixels are square
+    let (cx, cy) = ((w / 2) as isize, (h / 2) as isize);
+    let radius = w.min(h) / 3;
+    draw_circle_outline(buf, stride, w, h, cx, cy, radius, t, 255, 255, 255);
+
+    // center crosshair and dot
     draw_crosshair(buf, stride, w, h, 255, 255, 0);
+    draw_circle(buf, stride, w, h, cx, cy, t * 2, 255, 255, 0);
 }
 
-fn open_keyboard() -> Result<EvDev> {
-    for (path, dev) in evdev::enumerate() {
-        if dev
-            .supported_keys()
-            .map_or(false, |keys| keys.contains(KeyCode::KEY_SPACE))
-        {
-            eprintln!("Using keyboard: {}, Name: {:?}", path.display(), dev.name());
+// A bar `ui` px tall along the bottom edge, lit over the first
+// (idx + 1) / len of the width and dimmed over the rest
+fn draw_progress(
+    buf: &mut [u8],
+    stride: usize,
+    w: usize,
+    h: usize,
+    idx: usize,
+    len: usize,
+    ui: usize,
+) {
+    let done = w * (idx + 1).min(len) / len.max(1);
+    let bar = ui.min(h);
+    let y = (h - bar) as isize;
+    blend_rect(buf, stride, w, h, 0, y, done, bar, 255, 255, 255, 192);
+    blend_rect(
+        buf,
+        stride,
+        w,
+        h,
+        done as isize,
+        y,
+        w - done,
+        bar,
+        0,
+        0,
+        0,
+        192,
+    );
+}
 
-            return Ok(dev);
-        }
-    }
-    Err(anyhow!("can't find device"))
+const HINTS: &str = "Right/Space next  Left previous  F1 menu  L labels  Q quit";
+// How long the key hints stay up before fading, and how long the fade takes
+const HINT_HOLD: Duration = Duration::from_secs(4);
+const HINT_FADE: Duration = Duration::from_secs(1);
+
+// Opacity of the key hints `since` they were put up, None once gone
+fn hint_alpha(since: Duration) -> Option<u8> {
+    let fading = since.checked_sub(HINT_HOLD).unwrap_or_default();
+    (fading < HINT_FADE)
+        .then(|| (255.0 * (1.0 - fading.as_secs_f64() / HINT_FADE.as_secs_f64())).round() as u8)
 }
 
-#[derive(Clone, Copy, Default)]
-struct Step {
-    pat: PatternKind,
-    solid_idx: usize,
-    grad_mode: GradMode,
-    grad_vertical: bool,
-    checker_cell: usize,
-    motion_speed: usize,
+// The main keys in a line along the bottom, clear of the progress bar, on a
+// box that darkens the pattern; all of it at opacity alpha/255
+fn draw_hints(buf: &mut [u8], stride: usize, w: usize, h: usize, alpha: u8, ui: usize) {
+    let mut scale = ui;
+    while scale > 1 && text_width(HINTS, scale) + 4 * scale > w {
+        scale -= 1;
+    }
+    let box_w = text_width(HINTS, scale) + 4 * scale;
+    let box_h = GLYPH_H * scale + 4 * scale;
+    let x = w.saturating_sub(box_w) / 2;
+    let y = h.saturating_sub(box_h + 3 * ui);
+    let shade = (alpha as u32 * 160 / 255) as u8;
+    blend_rect(
+        buf, stride, w, h, x as isize, y as isize, box_w, box_h, 0, 0, 0, shade,
+    );
+    let (tx, ty) = (x + 2 * scale, y + 2 * scale);
+    blend_text(
+        buf, stride, w, h, tx, ty, HINTS, scale, 255, 255, 255, alpha,
+    );
 }
 
-struct AppState {
+// Status lines stacked in `corner`, first line nearest the top, on boxes
+// that darken the pattern rather than hide it, at text scale `ui` or as
+// near as fits. The buffer is the viewer's way up, so with --rotate the
+// corner is still the one the viewer sees.
+fn draw_overlay(
+    buf: &mut [u8],
+    stride: usize,
+    w: usize,
+    h: usize,
+    lines: &[String],
+    ui: usize,
+    corner: Corner,
+) {
+    let widest = lines.iter().map(String::as_str).max_by_key(|l| l.len());
+    let size = |scale: usize| {
+        let wide = widest.map_or(0, |l| text_width(l, scale)) + 8 * scale;
+        (wide, 4 * scale + lines.len() * (GLYPH_H + 4) * scale)
+    };
+    let mut scale = ui;
+    while scale > 1 && (size(scale).0 > w || size(scale).1 > h) {
+        scale -= 1;
+    }
+    let line_h = (GLYPH_H + 4) * scale;
+    let margin = 4 * scale;
+    for (i, line) in lines.iter().enumerate() {
+        // The label's box is the text and 2 * scale padding each side
+        let box_w = text_width(line, scale) + 4 * scale;
+        let x = if corner.right() {
+            w.saturating_sub(margin + box_w)
+        } else {
+            margin
+        };
+        let y = if corner.bottom() {
+            h.saturating_sub(margin + (lines.len() - i) * line_h)
+        } else {
+            margin + i * line_h
+        };
+        draw_label_over(buf, stride, w, h, x, y, line, scale, 192);
+    }
+}
+
+// A --patch-server patch on black, or all black before the first request
+fn draw_patch(buf: &mut [u8], stride: usize, w: usize, h: usize, patch: Option<Patch>) {
+    fill_rgb(buf, stride, w, h, 0, 0, 0);
+    if let Some(patch) = patch {
+        let (x, y, pw, ph) = patch.rect(w, h);
+        let (r, g, b) = patch.rgb;
+        fill_rect(buf, stride, w, h, x as isize, y as isize, pw, ph, r, g, b);
+    }
+}
+
+// The flips in `flips`, newest first, on a box in the top-right corner:
+// sequence number, vblank time, the gap to the flip before and the latency
+fn draw_flip_log(
+    buf: &mut [u8],
+    stride: usize,
+    w: usize,
+    h: usize,
+    flips: &VecDeque<FlipSample>,
+    ui: usize,
+) {
+    let mut lines = vec!["seq        vblank s      gap ms  lat ms".to_string()];
+    let mut prev = None;
+    let mut rows: Vec<String> = flips
+        .iter()
+        .map(|f| {
+            let gap = prev.map_or("-".to_string(), |p: Duration| {
+                format!(
+                    "{:.3}",
+                    (f.timestamp.as_secs_f64() - p.as_secs_f64()) * 1000.0
+                )
+            });
+            prev = Some(f.timestamp);
+            format!(
+                "{:<10} {:<13.6} {:>7} {:>7.3}{}",
+                f.seq,
+                f.timestamp.as_secs_f64(),
+                gap,
+                f.latency.as_secs_f64() * 1000.0,
+                match f.missed {
+                    0 => String::new(),
+                    n => format!(" {} MISSED", n),
+                }
+            )
+        })
+        .collect();
+    rows.reverse();
+    lines.extend(rows);
+
+    let mut scale = ui;
+    let widest = lines.iter().map(|l| text_width(l, 1)).max().unwrap_or(0);
+    while scale > 1 && (widest + 4) * scale > w {
+        scale -= 1;
+    }
+    let line_h = (GLYPH_H + 4) * scale;
+    let box_w = (widest + 4) * scale;
+    let x = w.saturating_sub(box_w + 4 * scale);
+    let y = 4 * scale;
+    blend_rect(
+        buf,
+        stride,
+        w,
+        h,
+        x as isize,
+        y as isize,
+        box_w,
+        lines.len() * line_h,
+        0,
+        0,
+        0,
+        192,
+    );
+    for (i, line) in lines.iter().enumerate() {
+        let (r, g, b) = if line.ends_with("MISSED") {
+            (255, 96, 96)
+        } else {
+            (255, 255, 255)
+        };
+        let ty = y + i * line_h + 2 * scale;
+        draw_text(buf, stride, w, h, x + 2 * scale, ty, line, scale, r, g, b);
+    }
+}
+
+// Large centered text for the parameter readout, readable across a room
+fn draw_readout(buf: &mut [u8], stride: usize, w: usize, h: usize, text: &str, ui: usize) {
+    let mut scale = (ui * 9 / 2).max(2);
+    while scale > 1 && (text_width(text, scale) + 4 * scale > w || (GLYPH_H + 4) * scale > h) {
+        scale -= 1;
+    }
+    let box_w = text_width(text, scale) + 4 * scale;
+    let box_h = GLYPH_H * scale + 4 * scale;
+    draw_label(
+        buf,
+        stride,
+        w,
+        h,
+        w.saturating_sub(box_w) / 2,
+        h.saturating_sub(box_h) / 2,
+        text,
+        scale,
+    );
+}
+
+// The script library as a centered list: `>` marks the selection and `*` the
+// script currently running
+#[allow(clippy::too_many_arguments)]
+fn draw_script_menu(
+    buf: &mut [u8],
+    stride: usize,
+    w: usize,
+    h: usize,
+    library: &ScriptLibrary,
+    selected: usize,
+    active: usize,
+    ui: usize,
+) {
+    let names: Vec<String> = library.scripts.iter().map(|s| s.name.clone()).collect();
+    draw_menu(
+        buf,
+        stride,
+        w,
+        h,
+        "Scripts",
+        &names,
+        selected,
+        Some(active),
+        ui,
+    );
+}
+
+// A titled, centered list with `>` at the selection and `*` at `marked`. A
+// list too long for the screen shows the part around the selection, and
+// one too wide is drawn smaller than `ui` asks for.
+#[allow(clippy::too_many_arguments)]
+fn draw_menu(
+    buf: &mut [u8],
+    stride: usize,
+    w: usize,
+    h: usize,
+    title: &str,
+    entries: &[String],
+    selected: usize,
+    marked: Option<usize>,
+    ui: usize,
+) {
+    let widest = std::iter::once(title)
+        .chain(entries.iter().map(String::as_str))
+        .map(|l| text_width(&format!(">* {}", l), 1) + 4)
+        .max()
+        .unwrap_or(0);
+    let mut scale = (ui * 3 / 2).max(1);
+    while scale > 1 && widest * scale > w {
+        scale -= 1;
+    }
+    let line_h = (GLYPH_H + 6) * scale;
+
+    let fits = (h / line_h).saturating_sub(1).max(1);
+    let first = selected
+        .saturating_sub(fits / 2)
+        .min(entries.len().saturating_sub(fits));
+    let lines: Vec<String> = std::iter::once(title.to_string())
+        .chain(
+            entries
+                .iter()
+                .enumerate()
+                .skip(first)
+                .take(fits)
+                .map(|(i, entry)| {
+                    format!(
+                        "{}{} {}",
+                        if i == selected { ">" } else { " " },
+                        if marked == Some(i) { "*" } else { " " },
+                        entry
+                    )
+                }),
+        )
+        .collect();
+
+    let box_w = lines
+        .iter()
+        .map(|l| text_width(l, scale))
+        .max()
+        .unwrap_or(0)
+        + 4 * scale;
+    let x = w.saturating_sub(box_w) / 2;
+    let y0 = h.saturating_sub(lines.len() * line_h) / 2;
+    for (i, line) in lines.iter().enumerate() {
+        draw_label(buf, stride, w, h, x, y0 + i * line_h, line, scale);
+    }
+}
+
+// Integer scaling check: a ruler strip of alternately colored columns with
+// a white tick every 10, then a 1 px checker and vertical and horizontal
+// line pairs, all drawn at 1/n of the screen and blown up to n x n blocks
+// as --render-scale does. Shown through an integer scaler the blocks stay
+// even; a fractional one makes some columns wider than others.
+fn draw_integer_scale(buf: &mut [u8], stride: usize, w: usize, h: usize, n: usize) {
+    let (lw, lh) = (w.div_ceil(n), h.div_ceil(n));
+    let mut low = vec![0u8; lw * 4 * lh];
+    let ruler = (lh / 16).clamp(1, 8);
+    let panels = (ruler + 3).min(lh);
+    for y in 0..lh {
+        let row = &mut low[y * lw * 4..(y + 1) * lw * 4];
+        for (x, px) in row.chunks_exact_mut(4).enumerate() {
+            let (r, g, b) = if y < ruler {
+                if x % 2 == 0 {
+                    (255, 0, 0)
+                } else {
+                    (0, 255, 255)
+                }
+            } else if y < panels {
+                if x % 10 == 0 && y < ruler + 2 {
+                    (255, 255, 255)
+                } else {
+                    (0, 0, 0)
+                }
+            } else {
+                let on = match 3 * x / lw {
+                    0 => (x + y) % 2 == 0,
+                    1 => x % 2 == 0,
+                    _ => y % 2 == 0,
+                };
+                if on { (255, 255, 255) } else { (0, 0, 0) }
+            };
+            px.copy_from_slice(&xrgb(r, g, b));
+        }
+    }
+
+    let mut wide = vec![0u8; lw * n * 4];
+    for y in 0..h {
+        if y % n == 0 {
+            expand_row(&mut wide, &low[(y / n) * lw * 4..], n);
+        }
+        buf[y * stride..y * stride + w * 4].copy_from_slice(&wide[..w * 4]);
+    }
+}
+
+// A 1px frame on the outermost pixels plus a sparse 1px grid. Any scaling or
+// overscan makes the frame vanish or the grid lines blur/beat.
+fn draw_pixel_exact(buf: &mut [u8], stride: usize, w: usize, h: usize, label: &str) {
+    const GRID: usize = 32;
+
+    if w == 0 || h == 0 {
+        return;
+    }
+
+    fill_rgb(buf, stride, w, h, 0, 0, 0);
+
+    for y in (GRID..h).step_by(GRID) {
+        for x in 0..w {
+            put_rgb_unchecked(buf, stride, x, y, 96, 96, 96);
+        }
+    }
+    for x in (GRID..w).step_by(GRID) {
+        for y in 0..h {
+            put_rgb_unchecked(buf, stride, x, y, 96, 96, 96);
+        }
+    }
+
+    for x in 0..w {
+        put_rgb_unchecked(buf, stride, x, 0, 255, 255, 255);
+        put_rgb_unchecked(buf, stride, x, h - 1, 255, 255, 255);
+    }
+    for y in 0..h {
+        put_rgb_unchecked(buf, stride, 0, y, 255, 255, 255);
+        put_rgb_unchecked(buf, stride, w - 1, y, 255, 255, 255);
+    }
+
+    let scale = (h / 270).max(1);
+    let text = format!("{} - all 4 edges must show a 1px white line", label);
+    let tx = w.saturating_sub(text_width(&text, scale)) / 2;
+    draw_label(buf, stride, w, h, tx, h / 2, &text, scale);
+}
+
+fn open_keyboard() -> Result<(PathBuf, EvDev)> {
+    for (path, dev) in evdev::enumerate() {
+        if dev
+            .supported_keys()
+            .is_some_and(|keys| keys.contains(KeyCode::KEY_SPACE))
+        {
+            eprintln!("Using keyboard: {}, Name: {:?}", path.display(), dev.name());
+
+            return Ok((path, dev));
+        }
+    }
+    Err(anyhow!("can't find device"))
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+struct Step {
+    pat: PatternKind,
+    // Bookmark for jumping here with U or a remote goto; unique in a script
+    name: Option<String>,
+    solid_idx: usize,
+    // Shown instead of SOLIDS[solid_idx], e.g. a fine-adjusted color
+    solid_rgb: Option<(u8, u8, u8)>,
+    grad_mode: GradMode,
+    grad_vertical: bool,
+    grad_light: bool,
+    checker_cell: usize,
+    // Light and dark cell colors; None is white and black
+    checker_colors: Option<ColorPair>,
+    // Sweep the cell size instead of holding checker_cell
+    checker_sweep: Option<CheckerSweep>,
+    motion_speed: usize,
+    line: LineSweep,
+    keystone: Keystone,
+    focus: Focus,
+    radial: Radial,
+    hue: HueSweep,
+    ramp: RampScroll,
+    backlight: BacklightSweep,
+    bounce: Bounce,
+    eyes: EyeAlignment,
+    // Target gamut of the gamut corners
+    gamut: Gamut,
+    // What the motion bar and the line sweep do at the edge of the screen
+    edge: EdgeBehavior,
+    seq_colors: SeqColors,
+    seq_hold: usize,
+    // Block size the integer scaling pattern simulates, 2 to 4; 0 is 2
+    int_scale: usize,
+    // Code value the near-black pattern fills with, 1 to 8; 0 is 2
+    near_black: u8,
+    // Channel the bit depth staircases are drawn in
+    bits_channels: RadialChannels,
+    // Gray levels along each axis of the gray-to-gray grid, 2 to 9; 0 is 5
+    g2g_levels: usize,
+    osc: [ChannelFn; 3],
+    // Color bars: how many, cycling through the colors given (default the
+    // SMPTE top row); a count of 0 shows each color once
+    bar_count: usize,
+    bar_colors: Vec<(u8, u8, u8)>,
+    // Persistence-of-vision dots: diameter in pixels (0 picks one from the
+    // screen height), color (default white) and the seed of their positions
+    dot_size: usize,
+    dot_rgb: Option<(u8, u8, u8)>,
+    dot_seed: u64,
+    // Stripe order for the subpixel text pattern; None takes the one the
+    // connector reports
+    subpixel: Option<SubpixelOrder>,
+    // Draw this step in full range even with limited range output, for
+    // patterns that probe below black or above white
+    full_range: bool,
+    // Hold the --gpio-line trigger high while this step is on screen
+    gpio_hold: bool,
+    // Shell command producing the frame for an external step, and whether to
+    // run it for every frame rather than once per step
+    generator: Option<String>,
+    generator_per_frame: bool,
+    // Seconds before advancing on its own, in place of --auto
+    dwell: Option<f64>,
+    // Frames to cross-fade into this step over, in place of --fade; 0 cuts
+    fade: Option<u32>,
+    // Hold auto-advance on this step until Enter is pressed, e.g. for a
+    // manual measurement in an otherwise automatic run
+    pause_here: bool,
+}
+
+struct AppState {
     pattern: PatternKind,
     solid_idx: usize,
+    // A solid color from the step, or set remotely or by fine adjust, shown
+    // instead of SOLIDS[solid_idx] until the step changes
+    custom_solid: Option<(u8, u8, u8)>,
+    // Fine adjust: a color to match, the channel Up/Down nudges, and the
+    // hex digits typed so far while a target is being entered
+    solid_target: Option<(u8, u8, u8)>,
+    solid_channel: usize,
+    hex_entry: Option<String>,
     grad_mode: GradMode,
     grad_vertical: bool,
+    grad_light: bool,
     checker_cell: usize,
-    motion_x: isize,
+    checker_colors: ColorPair,
+    checker_sweep: Option<CheckerSweep>,
+    sweep_t: f64,
+    sweep_last: Option<Instant>,
+    motion: Mover,
     motion_speed: usize,
-    motion_dir: i32,
+    line: LineSweep,
+    line_mover: Mover,
+    ramp_mover: Mover,
+    edge: EdgeBehavior,
+    keystone: Keystone,
+    focus: Focus,
+    radial: Radial,
+    hue: HueSweep,
+    ramp: RampScroll,
+    backlight: BacklightSweep,
+    bounce: Bounce,
+    eyes: EyeAlignment,
+    gamut: Gamut,
+    bounce_x: Mover,
+    bounce_y: Mover,
+    subpixel: Option<SubpixelOrder>,
+    seq_colors: SeqColors,
+    seq_hold: usize,
+    int_scale: usize,
+    near_black: u8,
+    bits_channels: RadialChannels,
+    g2g_levels: usize,
+    osc: [ChannelFn; 3],
+    osc_channel: usize,
+    osc_t: f64,
+    osc_last: Option<Instant>,
+    // Rate the dots pattern is drawn at, i.e. its flip rate
+    dot_rate: FpsMeter,
+    // Flips added to the count the flicker patterns go by, so J can step
+    // them against a camera's rolling shutter
+    phase_offset: u64,
+    // The flip that voided the stereo crosstalk run: a frame shown twice
+    // swaps the eyes from then on
+    stereo_void: Option<u32>,
+    // The operator's pass or fail for each step marked so far, by step
+    // label, in the order first marked; marking a step again replaces it
+    results: Vec<(String, bool)>,
+    // Cross-fade into the step under way; Right or Left again skips it
+    fade: Option<Fade>,
+    motion_aa: bool,
+    // Gradient: tick the code value boundaries
+    grad_ticks: bool,
+    labels: bool,
+    // Step count and a bar along the bottom edge showing how far through
+    // the script this is
+    progress: bool,
+    paused: bool,
+    // On a pause_here step until Enter releases it
+    held: bool,
+    // Shown instead of the step until Z is pressed through to the end, or
+    // the step changes
+    quick_fill: Option<QuickFill>,
+    // Index into SOLIDS shown instead of the step while [ and ] step
+    // through them, until backslash or the step changes
+    quick_solid: Option<usize>,
+    param_nav: bool,
+    // Selected entry while the F1 menu is open
+    menu: Option<usize>,
+    matrix: ColorMatrix,
+    invert: bool,
+    // Alternating with the inverse at this many Hz, and since when, so the
+    // phase follows the clock rather than the flips
+    blink: Option<(f64, Instant)>,
+    // --allow-flashing: the blink may go past BLINK_SAFE_HZ
+    allow_flashing: bool,
+    channel_mask: ChannelMask,
+    // Red and blue exchanged in the finished frame, for BGR hardware
+    swap_rb: bool,
+    // --rotate 90 or 270, so the display's across is the stage's down
+    quarter_turn: bool,
+    // --ui-scale, or None to go by the screen height
+    ui_scale: Option<usize>,
+    // The backlight level with --backlight, for labels
+    backlight_pct: Option<u32>,
+    output_range: OutputRange,
+    double_range: DoubleRange,
+    readout: Option<(String, Instant)>,
+    overrides: StepOverrides,
+    // Output of the current step's generator at the size it was made for,
+    // kept while the step lasts unless it runs per frame. A failure is kept
+    // instead, so a broken generator is reported once and not rerun.
+    generated: Option<(usize, usize, Vec<u8>)>,
+    generator_error: Option<String>,
 
     script: Vec<Step>,
+    // With --shuffle, the original index of each step in `script`
+    script_origin: Option<Vec<usize>>,
     script_idx: usize,
+    step_started: Instant,
 }
 
 impl AppState {
-    fn new() -> Self {
+    fn new() -> Result<Self> {
         let script = AppState::create_script();
+        library::validate_script(&script, "built-in script")?;
 
         let mut appstate = Self {
             pattern: PatternKind::Solid,
             solid_idx: 0,
+            custom_solid: None,
+            solid_target: None,
+            solid_channel: 0,
+            hex_entry: None,
             grad_mode: GradMode::Luma,
             grad_vertical: false,
+            grad_light: false,
             checker_cell: 8,
-            motion_x: 0,
+            checker_colors: CHECKER_PAIRS[0].1,
+            checker_sweep: None,
+            sweep_t: 0.0,
+            sweep_last: None,
+            motion: Mover::default(),
             motion_speed: 8,
-            motion_dir: 1,
+            line: LineSweep::default(),
+            line_mover: Mover::default(),
+            edge: EdgeBehavior::Wrap,
+            keystone: Keystone::default(),
+            focus: Focus::default(),
+            radial: Radial::default(),
+            hue: HueSweep::default(),
+            ramp: RampScroll::default(),
+            ramp_mover: Mover::default(),
+            backlight: BacklightSweep::default(),
+            bounce: Bounce::default(),
+            eyes: EyeAlignment::default(),
+            gamut: Gamut::default(),
+            bounce_x: Mover::default(),
+            bounce_y: Mover::default(),
+            backlight_pct: None,
+            subpixel: None,
+            seq_colors: SeqColors::Rgb,
+            seq_hold: 1,
+            int_scale: 2,
+            near_black: 2,
+            bits_channels: RadialChannels::Luma,
+            g2g_levels: 5,
+            osc: Default::default(),
+            osc_channel: 0,
+            osc_t: 0.0,
+            osc_last: None,
+            dot_rate: FpsMeter::default(),
+            phase_offset: 0,
+            stereo_void: None,
+            results: Vec::new(),
+            fade: None,
+            motion_aa: false,
+            grad_ticks: false,
+            labels: true,
+            progress: true,
+            paused: false,
+            held: false,
+            quick_fill: None,
+            quick_solid: None,
+            param_nav: false,
+            menu: None,
+            matrix: ColorMatrix::Identity,
+            invert: false,
+            blink: None,
+            allow_flashing: false,
+            channel_mask: ChannelMask::All,
+            swap_rb: false,
+            quarter_turn: false,
+            ui_scale: None,
+            output_range: OutputRange::Full,
+            double_range: DoubleRange::Off,
+            readout: None,
+            generated: None,
+            generator_error: None,
+            overrides: StepOverrides::default(),
             script,
+            script_origin: None,
             script_idx: 0,
+            step_started: Instant::now(),
         };
 
         appstate.apply_current_step();
 
-        return appstate;
+        Ok(appstate)
     }
 
     fn create_script() -> Vec<Step> {
@@ -576,216 +2799,3483 @@ impl AppState {
             ..Default::default()
         });
 
-        // for &gm in &[GradMode::Red, GradMode::Green, GradMode::Blue] {
-        //     script.push(Step {
-        //         pat: PatternKind::Gradient,
-        //         grad_mode: gm,
-        //         grad_vertical: false,
-        //         ..Default::default()
-        //     });
-        // }
+        script.push(Step {
+            pat: PatternKind::Gradient,
+            grad_mode: GradMode::Luma,
+            grad_vertical: false,
+            grad_light: true,
+            ..Default::default()
+        });
 
-        for &c in &[8] {
-            script.push(Step {
-                pat: PatternKind::Checker,
-                checker_cell: c,
-                ..Default::default()
-            });
-        }
+        script.push(Step {
+            pat: PatternKind::Radial,
+            ..Default::default()
+        });
 
-        for &s in &[16] {
-            script.push(Step {
-                pat: PatternKind::Motion,
-                motion_speed: s,
-                ..Default::default()
-            });
-        }
+        script.push(Step {
+            pat: PatternKind::Gradient,
+            grad_mode: GradMode::Rgb,
+            grad_vertical: false,
+            ..Default::default()
+        });
 
         script.push(Step {
-            pat: PatternKind::Viewing,
+            pat: PatternKind::HueSweep,
             ..Default::default()
         });
 
-        return script;
-    }
+        script.push(Step {
+            pat: PatternKind::Patches,
+            ..Default::default()
+        });
 
-    fn current_step(&self) -> Step {
-        self.script[self.script_idx]
-    }
+        script.push(Step {
+            pat: PatternKind::NearBlack,
+            ..Default::default()
+        });
 
-    fn apply_current_step(&mut self) {
-        let step = self.current_step();
-        self.pattern = step.pat;
-        self.solid_idx = step.solid_idx;
-        self.grad_mode = step.grad_mode;
-        self.grad_vertical = step.grad_vertical;
-        self.checker_cell = step.checker_cell;
-        self.motion_speed = step.motion_speed;
-        self.motion_x = 0;
-        self.motion_dir = 1;
-    }
+        script.push(Step {
+            pat: PatternKind::BitDepth,
+            ..Default::default()
+        });
 
-    // Returns if program should quit
-    fn next_step(&mut self) -> bool {
-        self.script_idx += 1;
+        script.push(Step {
+            pat: PatternKind::Checker,
+            checker_cell: 8,
+            ..Default::default()
+        });
 
-        if self.script_idx >= self.script.len() {
-            return true;
-        }
+        script.push(Step {
+            pat: PatternKind::Checker,
+            checker_cell: 8,
+            checker_colors: Some(CHECKER_PAIRS[1].1),
+            ..Default::default()
+        });
 
-        self.apply_current_step();
+        script.push(Step {
+            pat: PatternKind::Checker,
+            checker_sweep: Some(CheckerSweep::default()),
+            ..Default::default()
+        });
 
-        return false;
-    }
+        script.push(Step {
+            pat: PatternKind::ContrastSensitivity,
+            ..Default::default()
+        });
 
-    fn previous_step(&mut self) {
-        self.script_idx = (self.script_idx + self.script.len() - 1) % self.script.len();
-        self.apply_current_step();
-    }
-}
+        script.push(Step {
+            pat: PatternKind::Motion,
+            motion_speed: 16,
+            ..Default::default()
+        });
 
-fn main() -> Result<()> {
-    let mut surface = Surface::open_default()?;
+        script.push(Step {
+            pat: PatternKind::LineSweep,
+            ..Default::default()
+        });
 
-    let mut kb = open_keyboard()?;
+        script.push(Step {
+            pat: PatternKind::RampScroll,
+            ..Default::default()
+        });
 
-    let mut stage = vec![0u8; surface.disp_h * surface.stride()];
+        script.push(Step {
+            pat: PatternKind::ColorChecker,
+            ..Default::default()
+        });
 
-    let mut state = AppState::new();
+        script.push(Step {
+            pat: PatternKind::GamutCorners,
+            ..Default::default()
+        });
 
-    surface.write_to_back(&stage)?;
-    surface.flip()?;
+        script.push(Step {
+            pat: PatternKind::ColorBars,
+            ..Default::default()
+        });
 
-    let mut last_frame = Instant::now();
+        script.push(Step {
+            pat: PatternKind::FlipSequence,
+            seq_colors: SeqColors::Rgb,
+            seq_hold: 1,
+            ..Default::default()
+        });
 
-    let mut need_redraw = true;
+        script.push(Step {
+            pat: PatternKind::PovDots,
+            ..Default::default()
+        });
 
-    'mainloop: loop {
-        let (drm_ready, kb_ready) = {
-            let mut fds = [
-                PollFd::new(surface.card.as_fd(), PollFlags::POLLIN),
-                PollFd::new(kb.as_fd(), PollFlags::POLLIN),
-            ];
+        script.push(Step {
+            pat: PatternKind::PixelInversion,
+            ..Default::default()
+        });
 
-            let _ = poll(&mut fds, 30u16)?;
+        script.push(Step {
+            pat: PatternKind::GrayToGray,
+            ..Default::default()
+        });
 
-            let drm_ready = fds[0]
-                .revents()
-                .unwrap_or(PollFlags::empty())
-                .contains(PollFlags::POLLIN);
+        script.push(Step {
+            pat: PatternKind::AvSync,
+            ..Default::default()
+        });
 
-            let kb_ready = fds[1]
-                .revents()
-                .unwrap_or(PollFlags::empty())
-                .contains(PollFlags::POLLIN);
+        script.push(Step {
+            pat: PatternKind::EyeAlignment,
+            ..Default::default()
+        });
 
-            (drm_ready, kb_ready)
-        };
+        script.push(Step {
+            pat: PatternKind::Timecode,
+            ..Default::default()
+        });
 
-        if drm_ready {
-            surface.handle_drm_events()?;
-        }
+        // Channels a third of a cycle apart walk the hue around the wheel
+        script.push(Step {
+            pat: PatternKind::Oscillator,
+            osc: [0.0, 1.0 / 3.0, 2.0 / 3.0].map(|phase| ChannelFn {
+                phase,
+                ..Default::default()
+            }),
+            ..Default::default()
+        });
 
-        if kb_ready {
-            if let Ok(events) = kb.fetch_events() {
-                for event in events {
-                    if let EventSummary::Key(_, code, 1) = event.destructure() {
-                        match code {
-                            KeyCode::KEY_Q | KeyCode::KEY_ESC => break 'mainloop,
-                            KeyCode::KEY_RIGHT | KeyCode::KEY_SPACE => {
-                                if state.next_step() {
-                                    break 'mainloop;
-                                }
-                            }
-                            KeyCode::KEY_LEFT => {
-                                state.previous_step();
-                            }
-                            _ => {
-                                if state.next_step() {
-                                    break 'mainloop;
-                                }
-                            }
-                        }
+        script.push(Step {
+            pat: PatternKind::EdidWhite,
+            ..Default::default()
+        });
 
-                        need_redraw = true;
-                    }
-                }
-            }
-        }
+        script.push(Step {
+            pat: PatternKind::Ruler,
+            ..Default::default()
+        });
 
-        let now = Instant::now();
-        let _dt = now.duration_since(last_frame);
-        last_frame = now;
-
-        let should_draw = need_redraw || matches!(state.pattern, PatternKind::Motion);
-
-        if should_draw {
-            match state.pattern {
-                PatternKind::Solid => {
-                    let (r, g, b) = SOLIDS[state.solid_idx];
-
-                    fill_rgb(
-                        &mut stage,
-                        surface.stride(),
-                        surface.disp_w,
-                        surface.disp_h,
-                        r,
-                        g,
-                        b,
-                    );
-                }
-                PatternKind::Gradient => {
-                    draw_gradient(
-                        &mut stage,
-                        surface.stride(),
-                        surface.disp_w,
-                        surface.disp_h,
-                        state.grad_mode,
-                        state.grad_vertical,
-                    );
-                }
-                PatternKind::Checker => {
-                    draw_checkerboard(
-                        &mut stage,
-                        surface.stride(),
-                        surface.disp_w,
-                        surface.disp_h,
-                        state.checker_cell,
-                    );
-                }
-                PatternKind::Motion => {
-                    let bar_w = (surface.disp_w / 40).max(8);
-                    state.motion_x = state.motion_x
-                        + (state.motion_dir as isize) * (state.motion_speed as isize);
-
-                    if state.motion_x < 0 {
-                        state.motion_x = (surface.disp_w as isize) - 1
-                    } else if state.motion_x as usize >= surface.disp_w {
-                        state.motion_x = 0;
+        // Long enough to get through every level
+        let sweep = BacklightSweep::default();
+        script.push(Step {
+            pat: PatternKind::BacklightSweep,
+            dwell: Some(sweep.steps as f64 * sweep.hold),
+            backlight: sweep,
+            ..Default::default()
+        });
+
+        script.push(Step {
+            pat: PatternKind::SubpixelText,
+            ..Default::default()
+        });
+
+        script.push(Step {
+            pat: PatternKind::ChannelOrder,
+            ..Default::default()
+        });
+
+        script.push(Step {
+            pat: PatternKind::ChromaSubsampling,
+            ..Default::default()
+        });
+
+        script.push(Step {
+            pat: PatternKind::Viewing,
+            ..Default::default()
+        });
+
+        script.push(Step {
+            pat: PatternKind::Keystone,
+            ..Default::default()
+        });
+
+        script.push(Step {
+            pat: PatternKind::Focus,
+            ..Default::default()
+        });
+
+        script.push(Step {
+            pat: PatternKind::Focus,
+            focus: Focus {
+                rings: true,
+                ..Default::default()
+            },
+            ..Default::default()
+        });
+
+        script.push(Step {
+            pat: PatternKind::PixelExact,
+            ..Default::default()
+        });
+
+        script.push(Step {
+            pat: PatternKind::IntegerScale,
+            ..Default::default()
+        });
+
+        script
+    }
+
+    // Switches to a different script from its first step
+    fn load_script(&mut self, script: Vec<Step>) {
+        self.script = script;
+        self.script_origin = None;
+        self.script_idx = 0;
+        self.apply_current_step();
+    }
+
+    // Where the current step was in the script before --shuffle, if it ran
+    // shuffled
+    fn original_index(&self) -> Option<usize> {
+        self.script_origin
+            .as_ref()
+            .map(|origin| origin[self.script_idx])
+    }
+
+    // "N/M", with the step's unshuffled place after it when shuffled
+    fn progress_label(&self) -> String {
+        let count = format!("{}/{}", self.script_idx + 1, self.script.len());
+        match self.original_index() {
+            Some(i) => format!("{} (script step {})", count, i + 1),
+            None => count,
+        }
+    }
+
+    fn current_step(&self) -> &Step {
+        &self.script[self.script_idx]
+    }
+
+    fn apply_current_step(&mut self) {
+        let step = self.current_step().clone();
+        self.quick_fill = None;
+        self.quick_solid = None;
+        self.pattern = step.pat;
+        self.solid_idx = step.solid_idx;
+        self.custom_solid = step.solid_rgb;
+        self.solid_target = None;
+        self.hex_entry = None;
+        self.grad_mode = step.grad_mode;
+        self.grad_vertical = step.grad_vertical;
+        self.grad_light = step.grad_light;
+        self.checker_cell = step.checker_cell;
+        self.checker_colors = step.checker_colors.unwrap_or(CHECKER_PAIRS[0].1);
+        self.checker_sweep = step.checker_sweep;
+        self.sweep_t = 0.0;
+        self.sweep_last = None;
+        self.motion_speed = step.motion_speed;
+        self.seq_colors = step.seq_colors;
+        self.seq_hold = step.seq_hold.max(1);
+        self.int_scale = step.int_scale.clamp(2, 4);
+        self.bits_channels = step.bits_channels;
+        self.near_black = match step.near_black {
+            0 => 2,
+            n => n.min(NEAR_BLACK_MAX),
+        };
+        self.g2g_levels = match step.g2g_levels {
+            0 => 5,
+            n => n.clamp(2, G2G_MAX_LEVELS),
+        };
+        self.osc = step.osc;
+        self.osc_t = 0.0;
+        self.osc_last = None;
+        self.motion = Mover::default();
+        self.line = step.line;
+        self.line.width = self.line.width.max(1);
+        self.line_mover = Mover::default();
+        self.edge = step.edge;
+        self.keystone = step.keystone;
+        self.focus = step.focus;
+        self.radial = step.radial;
+        self.hue = step.hue;
+        self.ramp = step.ramp;
+        self.backlight = step.backlight;
+        self.bounce = step.bounce.clone();
+        self.eyes = step.eyes;
+        self.gamut = step.gamut;
+        self.ramp_mover = Mover::default();
+        self.bounce_x = Mover::default();
+        self.bounce_y = Mover::default();
+        self.subpixel = step.subpixel;
+        self.held = step.pause_here;
+        self.stereo_void = None;
+        self.generated = None;
+        self.generator_error = None;
+        self.step_started = Instant::now();
+        self.apply_overrides();
+        if self.pattern == PatternKind::PixelInversion {
+            eprintln!(
+                "Warning: pixel inversion flips every pixel between black and white on every refresh and flickers by design"
+            );
+        }
+    }
+
+    fn set_overrides(&mut self, overrides: StepOverrides) {
+        self.overrides = overrides;
+        self.apply_overrides();
+    }
+
+    fn apply_overrides(&mut self) {
+        let o = self.overrides;
+        if let Some(cell) = o.checker_cell {
+            self.checker_cell = cell;
+        }
+        if let Some(speed) = o.motion_speed {
+            self.motion_speed = speed;
+        }
+        if o.grad_vertical {
+            self.grad_vertical = true;
+        }
+    }
+
+    // motion_speed is in pixels per refresh at the display's native rate
+    fn advance_motion(&mut self, now: Instant, refresh_hz: f64, w: usize, bar_w: usize) {
+        let speed = self.motion_speed as f64 * refresh_hz;
+        self.motion.advance(now, speed, w, bar_w, self.edge);
+    }
+
+    fn advance_line(&mut self, now: Instant, extent: usize) {
+        let speed = self.line.speed as f64;
+        let width = self.line.width;
+        self.line_mover
+            .advance(now, speed, extent, width, self.edge);
+    }
+
+    // Always wraps, whatever the edge setting, since the ramp tiles
+    fn advance_ramp(&mut self, now: Instant, w: usize) {
+        let speed = self.ramp.speed as f64;
+        self.ramp_mover
+            .advance(now, speed, w, 0, EdgeBehavior::Wrap);
+    }
+
+    // Size multiple for the overlay and other UI on a stage `h` rows tall:
+    // the setting, or one step per 540 rows
+    fn ui_scale(&self, h: usize) -> usize {
+        self.ui_scale.unwrap_or(h / 540).clamp(1, 8)
+    }
+
+    fn advance_sweep(&mut self, now: Instant) {
+        let dt = self
+            .sweep_last
+            .map_or(0.0, |last| now.duration_since(last).as_secs_f64());
+        self.sweep_last = Some(now);
+        self.sweep_t += dt;
+    }
+
+    fn advance_osc(&mut self, now: Instant) {
+        let dt = self
+            .osc_last
+            .map_or(0.0, |last| now.duration_since(last).as_secs_f64());
+        self.osc_last = Some(now);
+        self.osc_t += dt;
+    }
+
+    fn osc_levels(&self) -> (u8, u8, u8) {
+        let [r, g, b] = self.osc.map(|f| f.level(self.osc_t));
+        (r, g, b)
+    }
+
+    fn animating(&self) -> bool {
+        ((matches!(
+            self.pattern,
+            PatternKind::Motion
+                | PatternKind::LineSweep
+                | PatternKind::RampScroll
+                | PatternKind::FlipSequence
+                | PatternKind::PovDots
+                | PatternKind::PixelInversion
+                | PatternKind::GrayToGray
+                | PatternKind::Oscillator
+                | PatternKind::AvSync
+                | PatternKind::Bounce
+                | PatternKind::Stereo
+                | PatternKind::Timecode
+        ) || (self.pattern == PatternKind::Keystone && self.keystone.blink)
+            || (self.pattern == PatternKind::Checker && self.checker_sweep.is_some())
+            || (self.pattern == PatternKind::External && self.current_step().generator_per_frame))
+            || self.blink.is_some())
+            && !self.paused
+            && self.quick_fill.is_none()
+            && self.quick_solid.is_none()
+    }
+
+    // Shift+I and - / =: blinking with the inverse at `hz`, or not at all,
+    // held to the range --allow-flashing allows
+    fn set_blink(&mut self, hz: Option<f64>) {
+        let max = if self.allow_flashing {
+            BLINK_MAX_HZ
+        } else {
+            BLINK_SAFE_HZ
+        };
+        self.blink = hz.map(|hz| {
+            let since = self.blink.map_or_else(Instant::now, |(_, since)| since);
+            (hz.clamp(BLINK_MIN_HZ, max), since)
+        });
+        self.show_readout(match self.blink {
+            Some((hz, _)) if hz >= max && !self.allow_flashing => {
+                format!("inverse blink {:.1} Hz (faster needs --allow-flashing)", hz)
+            }
+            Some((hz, _)) => format!("inverse blink {:.1} Hz", hz),
+            None => "inverse blink off".to_string(),
+        });
+    }
+
+    // The backlight sweep's level at `now`, as its index and percent, and
+    // when the next one is due if there is one
+    fn backlight_sweep(&self, now: Instant) -> Option<(u32, u32, Option<Instant>)> {
+        if self.pattern != PatternKind::BacklightSweep {
+            return None;
+        }
+        let n = self.backlight.steps.max(2);
+        let hold = self.backlight.hold.max(0.1);
+        let elapsed = now
+            .saturating_duration_since(self.step_started)
+            .as_secs_f64();
+        let i = ((elapsed / hold) as u32).min(n - 1);
+        let next =
+            (i + 1 < n).then(|| self.step_started + Duration::from_secs_f64(hold * (i + 1) as f64));
+        Some((i, i * 100 / (n - 1), next))
+    }
+
+    fn auto_deadline(&self, auto: Option<Duration>) -> Option<Instant> {
+        let dwell = self.current_step().dwell.map(Duration::from_secs_f64);
+        dwell
+            .or(auto)
+            .filter(|_| !self.paused && !self.held)
+            .map(|dwell| self.step_started + dwell)
+    }
+
+    // How many frames in a row the current pattern repeats on its own while
+    // it animates, for --frame-checksum; None if it may hold still anyway
+    fn identical_frames_allowed(&self, refresh_hz: f64) -> Option<u64> {
+        if self.paused {
+            return None;
+        }
+        // A static pattern blinking repeats for half a period at a time
+        let blink = self
+            .blink
+            .map(|(hz, _)| (refresh_hz / (2.0 * hz)).ceil() as u64);
+        let own = match self.pattern {
+            // Stopped at the edge, the frames are meant to repeat
+            PatternKind::Motion | PatternKind::LineSweep if self.edge == EdgeBehavior::Stop => None,
+            PatternKind::Motion if self.motion_speed > 0 => Some(0),
+            PatternKind::LineSweep => {
+                Some((refresh_hz / self.line.speed.max(1) as f64).ceil() as u64)
+            }
+            PatternKind::RampScroll => {
+                Some((refresh_hz / self.ramp.speed.max(1) as f64).ceil() as u64)
+            }
+            PatternKind::FlipSequence => Some(self.seq_hold as u64 - 1),
+            PatternKind::Keystone => Some(refresh_hz.round() as u64 / 2),
+            PatternKind::PovDots
+            | PatternKind::PixelInversion
+            | PatternKind::GrayToGray
+            | PatternKind::Stereo => Some(0),
+            _ => None,
+        };
+        own.or(blink)
+    }
+
+    // Flips before a flicker pattern repeats, which bounds its phase offset
+    fn flicker_cycle(&self) -> u64 {
+        match self.pattern {
+            PatternKind::FlipSequence => (self.seq_colors.colors().len() * self.seq_hold) as u64,
+            _ => 2,
+        }
+    }
+
+    // Whether the step is holding up an auto-advance that would otherwise
+    // run, so the operator needs telling
+    fn held_for_operator(&self, auto: Option<Duration>) -> bool {
+        self.held && (auto.is_some() || self.current_step().dwell.is_some())
+    }
+
+    // The current step as adjusted so far, for --record-script: what it
+    // would take to show this again
+    fn effective_step(&self) -> Step {
+        Step {
+            pat: self.pattern,
+            solid_idx: self.solid_idx,
+            solid_rgb: self.custom_solid,
+            grad_mode: self.grad_mode,
+            grad_vertical: self.grad_vertical,
+            grad_light: self.grad_light,
+            checker_cell: self.checker_cell,
+            checker_colors: (self.checker_colors != CHECKER_PAIRS[0].1)
+                .then_some(self.checker_colors),
+            checker_sweep: self.checker_sweep,
+            motion_speed: self.motion_speed,
+            line: self.line,
+            edge: self.edge,
+            keystone: self.keystone,
+            focus: self.focus,
+            radial: self.radial,
+            hue: self.hue,
+            ramp: self.ramp,
+            backlight: self.backlight,
+            bounce: self.bounce.clone(),
+            eyes: self.eyes,
+            gamut: self.gamut,
+            seq_colors: self.seq_colors,
+            seq_hold: self.seq_hold,
+            int_scale: self.int_scale,
+            near_black: self.near_black,
+            bits_channels: self.bits_channels,
+            g2g_levels: self.g2g_levels,
+            osc: self.osc,
+            subpixel: self.subpixel,
+            ..self.current_step().clone()
+        }
+    }
+
+    // The F1 menu: every step of the script, then the options it can toggle
+    fn menu_items(&self) -> Vec<(MenuItem, String)> {
+        let on_off = |on: bool| if on { "on" } else { "off" };
+        let mut items: Vec<(MenuItem, String)> = self
+            .script
+            .iter()
+            .enumerate()
+            .map(|(i, step)| {
+                let text = match &step.name {
+                    Some(name) => format!("{:>2} {:?} [{}]", i + 1, step.pat, name),
+                    None => format!("{:>2} {:?}", i + 1, step.pat),
+                };
+                (MenuItem::Step(i), text)
+            })
+            .collect();
+        items.push((MenuItem::Labels, format!("labels: {}", on_off(self.labels))));
+        items.push((MenuItem::Pause, format!("pause: {}", on_off(self.paused))));
+        items.push((
+            MenuItem::ParamNav,
+            format!("param nav: {}", on_off(self.param_nav)),
+        ));
+        items.push((
+            MenuItem::Matrix,
+            format!("color matrix: {}", self.matrix.name()),
+        ));
+        items.push((
+            MenuItem::Range,
+            format!("output range: {}", self.output_range.name()),
+        ));
+        items.push((
+            MenuItem::DoubleRange,
+            format!("double conversion: {}", self.double_range.name()),
+        ));
+        items.push((
+            MenuItem::Progress,
+            format!("progress bar: {}", on_off(self.progress)),
+        ));
+        items
+    }
+
+    // A key while the menu is open. Picking a step jumps to it and closes
+    // the menu; picking an option applies it and leaves the menu up so the
+    // change can be seen. Esc or F1 close it on the pattern as it was.
+    fn menu_key(&mut self, code: KeyCode) {
+        let Some(sel) = self.menu else {
+            return;
+        };
+        let items = self.menu_items();
+        let n = items.len();
+        let sel = sel.min(n - 1);
+        match code {
+            KeyCode::KEY_UP => self.menu = Some((sel + n - 1) % n),
+            KeyCode::KEY_DOWN => self.menu = Some((sel + 1) % n),
+            KeyCode::KEY_ENTER | KeyCode::KEY_SPACE => match items[sel].0 {
+                MenuItem::Step(i) => {
+                    self.goto_step(i);
+                    self.menu = None;
+                }
+                MenuItem::Labels => self.labels = !self.labels,
+                MenuItem::Pause => self.toggle_pause(),
+                MenuItem::ParamNav => self.param_nav = !self.param_nav,
+                MenuItem::Matrix => self.matrix = self.matrix.next(),
+                MenuItem::Range => self.output_range = self.output_range.next(),
+                MenuItem::DoubleRange => self.double_range = self.double_range.next(),
+                MenuItem::Progress => self.progress = !self.progress,
+            },
+            KeyCode::KEY_ESC | KeyCode::KEY_F1 => self.menu = None,
+            _ => {}
+        }
+    }
+
+    fn toggle_pause(&mut self) {
+        self.paused = !self.paused;
+        // Give the step a full dwell again after resuming, and don't let the
+        // bar jump by the time spent paused
+        self.step_started = Instant::now();
+        self.motion.hold();
+        self.line_mover.hold();
+        self.ramp_mover.hold();
+        self.osc_last = None;
+        self.sweep_last = None;
+    }
+
+    // Returns if program should quit, i.e. we were already on the last step.
+    // The index is left alone in that case so it never points past the script.
+    fn next_step(&mut self) -> bool {
+        if self.script_idx + 1 >= self.script.len() {
+            return true;
+        }
+
+        self.script_idx += 1;
+        self.apply_current_step();
+
+        false
+    }
+
+    fn step_name(&self) -> Option<&str> {
+        self.current_step().name.as_deref()
+    }
+
+    // [ and ]: the solid before or after the one shown, wrapping round, or
+    // the first or last one coming from the step
+    fn step_quick_solid(&mut self, forward: bool) {
+        let n = SOLIDS.len();
+        self.quick_solid = Some(match (self.quick_solid, forward) {
+            (Some(i), true) => (i + 1) % n,
+            (Some(i), false) => (i + n - 1) % n,
+            (None, true) => 0,
+            (None, false) => n - 1,
+        });
+    }
+
+    // What the checklist calls the current step: its name, or its number
+    // and pattern
+    fn step_label(&self) -> String {
+        match self.step_name() {
+            Some(name) => name.to_string(),
+            None => format!("{} {}", self.script_idx + 1, self.pattern.name()),
+        }
+    }
+
+    fn mark_step(&mut self, pass: bool) {
+        let label = self.step_label();
+        match self.results.iter_mut().find(|(l, _)| *l == label) {
+            Some(result) => result.1 = pass,
+            None => self.results.push((label, pass)),
+        }
+    }
+
+    // Running tally for the overlay, None until anything is marked
+    fn results_label(&self) -> Option<String> {
+        if self.results.is_empty() {
+            return None;
+        }
+        let passed = self.results.iter().filter(|(_, pass)| *pass).count();
+        Some(format!(
+            "checklist: {} pass, {} fail, {} of {} steps marked",
+            passed,
+            self.results.len() - passed,
+            self.results.len(),
+            self.script.len()
+        ))
+    }
+
+    fn step_named(&self, name: &str) -> Option<usize> {
+        self.script
+            .iter()
+            .position(|step| step.name.as_deref() == Some(name))
+    }
+
+    // Jumps to the next named step after this one, wrapping around, and
+    // returns its name; None if no step has one
+    fn next_bookmark(&mut self) -> Option<String> {
+        let len = self.script.len();
+        let idx = (1..=len)
+            .map(|d| (self.script_idx + d) % len)
+            .find(|&i| self.script[i].name.is_some())?;
+        self.goto_step(idx);
+        self.step_name().map(str::to_string)
+    }
+
+    // Returns false if the index is past the end of the script
+    fn goto_step(&mut self, idx: usize) -> bool {
+        if idx >= self.script.len() {
+            return false;
+        }
+        self.script_idx = idx;
+        self.apply_current_step();
+        true
+    }
+
+    fn previous_step(&mut self) {
+        let len = self.script.len();
+        self.script_idx = (self.script_idx.min(len - 1) + len - 1) % len;
+        self.apply_current_step();
+    }
+
+    fn current_param(&self) -> Option<Param> {
+        match self.pattern {
+            PatternKind::Solid => Some(Param::Solid(self.solid_idx)),
+            PatternKind::Gradient => Some(Param::Gradient {
+                vertical: self.grad_vertical,
+                light: self.grad_light,
+            }),
+            PatternKind::Checker => Some(Param::CheckerCell(self.checker_cell)),
+            PatternKind::Motion => Some(Param::MotionSpeed(self.motion_speed)),
+            PatternKind::LineSweep => Some(Param::LineSpeed(self.line.speed)),
+            PatternKind::RampScroll => Some(Param::RampSpeed(self.ramp.speed)),
+            PatternKind::FlipSequence => Some(Param::SeqColors(self.seq_colors)),
+            _ => None,
+        }
+    }
+
+    fn apply_param(&mut self, param: Param) {
+        match param {
+            Param::Solid(idx) => {
+                self.solid_idx = idx;
+                self.custom_solid = None;
+            }
+            Param::Gradient { vertical, light } => {
+                self.grad_vertical = vertical;
+                self.grad_light = light;
+            }
+            Param::CheckerCell(cell) => self.checker_cell = cell,
+            Param::MotionSpeed(speed) => self.motion_speed = speed,
+            Param::LineSpeed(speed) => self.line.speed = speed,
+            Param::RampSpeed(speed) => self.ramp.speed = speed,
+            Param::SeqColors(colors) => self.seq_colors = colors,
+        }
+    }
+
+    // Moves through the current pattern's parameter list, wrapping at both
+    // ends. A value that isn't in the list (e.g. from a custom script) steps
+    // to the first or last entry.
+    fn step_param(&mut self, forward: bool) {
+        let Some((name, params)) = param_space(self.pattern) else {
+            return;
+        };
+        let len = params.len();
+        let next = match params.iter().position(|&p| Some(p) == self.current_param()) {
+            Some(i) if forward => (i + 1) % len,
+            Some(i) => (i + len - 1) % len,
+            None if forward => 0,
+            None => len - 1,
+        };
+        self.apply_param(params[next]);
+        self.show_readout(format!("{} {}", name, params[next].value_label()));
+    }
+
+    // Replaces any readout already up, so quick repeated adjustments just
+    // extend it
+    fn show_readout(&mut self, text: String) {
+        self.readout = Some((text, Instant::now() + READOUT_TIME));
+    }
+
+    fn solid_rgb(&self) -> (u8, u8, u8) {
+        self.custom_solid.unwrap_or(SOLIDS[self.solid_idx])
+    }
+
+    // Shows `target` and starts fine adjustment from it
+    fn set_solid_target(&mut self, target: (u8, u8, u8)) {
+        self.pattern = PatternKind::Solid;
+        self.solid_target = Some(target);
+        self.custom_solid = Some(target);
+    }
+
+    // Moves the selected channel of the solid color by `delta` code values
+    fn nudge_solid(&mut self, delta: i32) {
+        let (r, g, b) = self.solid_rgb();
+        let mut rgb = [r, g, b];
+        let c = &mut rgb[self.solid_channel];
+        *c = (*c as i32 + delta).clamp(0, 255) as u8;
+        self.custom_solid = Some((rgb[0], rgb[1], rgb[2]));
+        self.show_readout(format!(
+            "{} {}",
+            OSC_CHANNELS[self.solid_channel], rgb[self.solid_channel]
+        ));
+    }
+
+    fn show_osc_readout(&mut self) {
+        let f = self.osc[self.osc_channel];
+        self.show_readout(format!(
+            "{} {:.1} Hz {:.0} deg",
+            OSC_CHANNELS[self.osc_channel],
+            f.freq_hz,
+            f.phase * 360.0
+        ));
+    }
+
+    fn readout_deadline(&self) -> Option<Instant> {
+        self.readout.as_ref().map(|&(_, until)| until)
+    }
+
+    // Returns true if the readout just went away and the pattern under it
+    // needs redrawing
+    fn expire_readout(&mut self, now: Instant) -> bool {
+        if self.readout_deadline().is_some_and(|until| now >= until) {
+            self.readout = None;
+            return true;
+        }
+        false
+    }
+}
+
+// AV sync marker timing in flips: one flash per second of refreshes, about
+// a thirtieth of a second long so a 30 fps camera can't miss it
+fn av_flash_timing(refresh_hz: f64) -> (u64, u64) {
+    let period = refresh_hz.round().max(1.0) as u64;
+    let len = (refresh_hz / 30.0).round().clamp(1.0, period as f64) as u64;
+    (period, len)
+}
+
+// Phase of the 1 px checker in the frame drawn after `flip_count` flips:
+// every frame is the inverse of the one before, whatever the loop gets up to
+// between flips
+fn inversion_phase(flip_count: u64) -> usize {
+    (flip_count & 1) as usize
+}
+
+// --checklist: one line per marked step, PASS or FAIL then its label, and
+// a summary line, for whoever signs off the panel
+fn write_checklist(path: &Path, results: &[(String, bool)], steps: usize) -> Result<()> {
+    let failed = results.iter().filter(|(_, pass)| !pass).count();
+    let mut text = String::new();
+    for (label, pass) in results {
+        text.push_str(&format!(
+            "{}  {}\n",
+            if *pass { "PASS" } else { "FAIL" },
+            label
+        ));
+    }
+    text.push_str(&format!(
+        "{}: {} of {} steps marked, {} failed\n",
+        if failed == 0 { "PASS" } else { "FAIL" },
+        results.len(),
+        steps,
+        failed
+    ));
+    std::fs::write(path, text)
+        .with_context(|| format!("could not write the checklist to {}", path.display()))?;
+    eprintln!("Wrote checklist: {}", path.display());
+    Ok(())
+}
+
+// What Shift+D steps the difference gain through, then back to side by side
+fn next_diff_gain(gain: Option<u32>) -> Option<u32> {
+    match gain {
+        None => Some(1),
+        Some(1) => Some(4),
+        Some(4) => Some(16),
+        Some(16) => Some(64),
+        Some(_) => None,
+    }
+}
+
+// next_step for keys: the run only ends from a last step that has been on
+// screen for a frame, so a burst of presses can't run straight off the end
+// of the script past a step nobody saw. With `looping` (--soak or --attract)
+// the script wraps to its first step instead of ending the run
+fn next_step_seen(state: &mut AppState, shown_step: Option<usize>, looping: bool) -> bool {
+    if state.next_step() {
+        if !looping {
+            return shown_step == Some(state.script_idx);
+        }
+        if shown_step == Some(state.script_idx) {
+            state.goto_step(0);
+        }
+        return false;
+    }
+    if state.script_idx + 1 == state.script.len() {
+        state.show_readout(
+            if looping {
+                "last step - next press wraps to the first"
+            } else {
+                "last step - next press exits"
+            }
+            .to_string(),
+        );
+    }
+    false
+}
+
+// What is left of the input hold set when a batch of input moved to
+// another step: it lasts until a frame of `step` has been presented, or
+// until NAV_HOLD runs out
+fn nav_hold_left(
+    hold: Option<Instant>,
+    now: Instant,
+    shown_step: Option<usize>,
+    step: usize,
+) -> Option<Instant> {
+    hold.filter(|&until| now < until && shown_step != Some(step))
+}
+
+// The outgoing step's last frame, blended into the incoming one by a step
+// every frame drawn rather than by the clock, so a fade runs the same frames
+// however loaded the machine is
+struct Fade {
+    from: Vec<u8>,
+    frames: u32,
+    drawn: u32,
+}
+
+// Whether the frame drawn after `flip_count` completed flips is a flash
+fn av_flash_on(flip_count: u64, refresh_hz: f64) -> bool {
+    let (period, len) = av_flash_timing(refresh_hz);
+    flip_count % period < len
+}
+
+// Renders the current pattern of `state` into a w x h region of `buf` with
+// rows `stride` bytes apart, which may be a window into a larger buffer
+#[allow(clippy::too_many_arguments)]
+fn draw_pattern(
+    state: &mut AppState,
+    surface: &dyn Backend,
+    buf: &mut [u8],
+    stride: usize,
+    w: usize,
+    h: usize,
+    now: Instant,
+    overlay: &mut Vec<String>,
+) {
+    match state.pattern {
+        _ if let Some(fill) = state.quick_fill => {
+            let v = if fill == QuickFill::White { 255 } else { 0 };
+            fill_rgb(buf, stride, w, h, v, v, v);
+            overlay.push(fill.label().to_string());
+        }
+        _ if let Some(i) = state.quick_solid => {
+            let (r, g, b) = SOLIDS[i];
+            fill_rgb(buf, stride, w, h, r, g, b);
+            overlay.push(format!(
+                "{} - [ and ] for the others, \\ to go back",
+                SOLID_NAMES[i]
+            ));
+        }
+        PatternKind::Solid => {
+            let (r, g, b) = state.solid_rgb();
+
+            fill_rgb(buf, stride, w, h, r, g, b);
+            if let Some(digits) = &state.hex_entry {
+                overlay.push(format!("target #{:_<6}", digits));
+            } else if let Some((tr, tg, tb)) = state.solid_target {
+                overlay.push(format!(
+                    "target #{:02X}{:02X}{:02X}  current #{:02X}{:02X}{:02X}",
+                    tr, tg, tb, r, g, b
+                ));
+                let delta: Vec<String> = [(r, tr), (g, tg), (b, tb)]
+                    .iter()
+                    .enumerate()
+                    .map(|(i, &(cur, target))| {
+                        format!(
+                            "{}{} {:+}",
+                            if i == state.solid_channel { ">" } else { " " },
+                            OSC_CHANNELS[i],
+                            cur as i32 - target as i32
+                        )
+                    })
+                    .collect();
+                overlay.push(format!("delta {}", delta.join(" ")));
+            }
+        }
+        PatternKind::Gradient => {
+            draw_gradient(
+                buf,
+                stride,
+                w,
+                h,
+                state.grad_mode,
+                state.grad_vertical,
+                state.grad_light,
+            );
+            if state.grad_ticks {
+                let steps = draw_gradient_ticks(
+                    buf,
+                    stride,
+                    w,
+                    h,
+                    state.grad_mode,
+                    state.grad_vertical,
+                    state.grad_light,
+                );
+                if state.labels && steps > 0 {
+                    let len = if state.grad_vertical { h } else { w };
+                    overlay.push(format!(
+                        "{} code value steps, {:.1} px apart",
+                        steps,
+                        len as f64 / (steps + 1) as f64
+                    ));
+                }
+            }
+            if state.labels {
+                draw_gradient_labels(buf, stride, w, h, state.grad_mode, state.grad_vertical);
+                overlay.push(
+                    if state.grad_light {
+                        "linear in light (sRGB encoded)"
+                    } else {
+                        "linear in code values"
+                    }
+                    .to_string(),
+                );
+            }
+        }
+        PatternKind::HueSweep => {
+            let hue = state.hue;
+            draw_hue_sweep(buf, stride, w, h, hue);
+            if state.labels {
+                if !hue.radial {
+                    draw_hue_labels(buf, stride, w, h);
+                }
+                overlay.push(format!(
+                    "hue sweep, saturation {:.0}%, value {:.0}%, {}",
+                    hue.saturation * 100.0,
+                    hue.value * 100.0,
+                    if hue.radial {
+                        "around the center"
+                    } else {
+                        "left to right"
+                    }
+                ));
+            }
+        }
+        PatternKind::Radial => {
+            let radial = state.radial;
+            draw_radial(buf, stride, w, h, radial, state.grad_light);
+            if state.labels {
+                overlay.push(format!(
+                    "radial {}, exponent {:.2}, {}, {}",
+                    radial.channels.name(),
+                    radial.exponent,
+                    if radial.inverse {
+                        "bright edges"
+                    } else {
+                        "bright center"
+                    },
+                    if state.grad_light {
+                        "linear in light (sRGB encoded)"
+                    } else {
+                        "linear in code values"
+                    }
+                ));
+            }
+        }
+        PatternKind::Checker => {
+            let wanted = match state.checker_sweep {
+                Some(sweep) => {
+                    state.advance_sweep(now);
+                    sweep.cell_at(state.sweep_t)
+                }
+                None => state.checker_cell,
+            };
+            // At least four cells across the short side, however small the
+            // display
+            let cell = wanted.min(w.min(h) / 4).max(1);
+            draw_checkerboard(buf, stride, w, h, cell, 0, state.checker_colors);
+            if let Some(sweep) = state.checker_sweep
+                && state.labels
+            {
+                overlay.push(format!(
+                    "cell {} px, sweeping {} to {} px every {} s",
+                    cell, sweep.from, sweep.to, sweep.period_s
+                ));
+            } else if cell != wanted && state.labels {
+                overlay.push(format!("cell {} px, {} on this display", wanted, cell));
+            }
+            if state.checker_colors != CHECKER_PAIRS[0].1 && state.labels {
+                overlay.push(checker_pair_name(state.checker_colors));
+            }
+        }
+        PatternKind::Motion => {
+            let bar_w = (w / 40).max(8);
+            state.advance_motion(now, surface.refresh_hz(), w, bar_w);
+
+            draw_motion_bar(buf, stride, w, h, state.motion.pos, bar_w, state.motion_aa);
+        }
+        PatternKind::LineSweep => {
+            let line = state.line;
+            state.advance_line(now, if line.vertical { w } else { h });
+            let pos = state.line_mover.pos.floor() as usize;
+            let (r, g, b) = line.color;
+
+            fill_rgb(buf, stride, w, h, 0, 0, 0);
+            if line.vertical {
+                fill_rect(buf, stride, w, h, pos as isize, 0, line.width, h, r, g, b);
+            } else {
+                fill_rect(buf, stride, w, h, 0, pos as isize, w, line.width, r, g, b);
+            }
+            overlay.push(format!(
+                "{} {}  {} px/s, {} px",
+                if line.vertical { "x" } else { "y" },
+                pos,
+                line.speed,
+                line.width
+            ));
+        }
+        PatternKind::RampScroll => {
+            state.advance_ramp(now, w);
+            let offset = state.ramp_mover.pos.floor() as usize;
+            draw_ramp_scroll(buf, stride, w, h, offset, state.ramp.light);
+            if state.labels {
+                overlay.push(format!(
+                    "scrolling ramp {} px/s, brightest at x {}",
+                    state.ramp.speed,
+                    (offset + w / 2) % w.max(1)
+                ));
+            }
+        }
+        PatternKind::FlipSequence => {
+            let colors = state.seq_colors.colors();
+            let flips = surface.frames_presented() + state.phase_offset;
+            let idx = (flips / state.seq_hold as u64) as usize % colors.len();
+            let (name, (r, g, b)) = colors[idx];
+
+            fill_rgb(buf, stride, w, h, r, g, b);
+            overlay.push(format!(
+                "{} flip {} ({} per color), phase offset {}",
+                name,
+                surface.frames_presented(),
+                state.seq_hold,
+                state.phase_offset % state.flicker_cycle()
+            ));
+        }
+        PatternKind::PovDots => {
+            let step = state.current_step();
+            let size = match step.dot_size {
+                0 => (h / 50).max(2),
+                n => n,
+            };
+            let (r, g, b) = step.dot_rgb.unwrap_or((255, 255, 255));
+            let (x, y) = pov_dot_position(step.dot_seed, surface.frames_presented(), w, h);
+
+            fill_rgb(buf, stride, w, h, 0, 0, 0);
+            draw_circle(buf, stride, w, h, x as isize, y as isize, size / 2, r, g, b);
+            state.dot_rate.presented(now);
+            if state.labels {
+                overlay.push(format!(
+                    "flip {}, {:.1} flips/s (refresh {:.2} Hz)",
+                    surface.frames_presented(),
+                    state.dot_rate.fps,
+                    surface.refresh_hz()
+                ));
+            }
+        }
+        PatternKind::PixelInversion => {
+            let phase = inversion_phase(surface.frames_presented() + state.phase_offset);
+            draw_checkerboard(buf, stride, w, h, 1, phase, CHECKER_PAIRS[0].1);
+            if state.labels {
+                overlay.push(format!(
+                    "flip {}, phase {} (offset {}) - flickers by design",
+                    surface.frames_presented(),
+                    phase,
+                    state.phase_offset % state.flicker_cycle()
+                ));
+            }
+        }
+        // Frame-sequential 3D: a white square in the left half for the left
+        // eye and in the right half for the right, alternating every flip, so
+        // through the glasses any ghost of the other eye's square shows on
+        // black. A marker in the top corner on the same side tells the
+        // glasses' sync from the eyes being swapped.
+        PatternKind::Stereo => match state.stereo_void {
+            Some(seq) => {
+                fill_rgb(buf, stride, w, h, 128, 128, 128);
+                overlay.push(format!(
+                    "stereo: ABORTED, flip {} was late and may have swapped the eyes; leave the step and come back to start again",
+                    seq
+                ));
+            }
+            None => {
+                let right = inversion_phase(surface.frames_presented() + state.phase_offset) == 1;
+                fill_rgb(buf, stride, w, h, 0, 0, 0);
+                let side = (h / 2).min(w / 4);
+                let half = if right { w / 2 } else { 0 };
+                let x = half + (w / 2 - side) / 2;
+                let y = (h - side) / 2;
+                fill_rect(
+                    buf, stride, w, h, x as isize, y as isize, side, side, 255, 255, 255,
+                );
+                let mark = (h / 20).max(4);
+                let mx = if right { w.saturating_sub(mark) } else { 0 };
+                fill_rect(buf, stride, w, h, mx as isize, 0, mark, mark, 255, 255, 255);
+                if state.labels {
+                    overlay.push(format!(
+                        "stereo: {} eye, flip {} (offset {}) - flickers by design",
+                        if right { "right" } else { "left" },
+                        surface.frames_presented(),
+                        state.phase_offset % state.flicker_cycle()
+                    ));
+                }
+            }
+        },
+        // Each eye's picture in its part of the frame, the crosshairs moved
+        // apart by the disparity
+        PatternKind::EyeAlignment => {
+            let eyes = state.eyes;
+            let ui = state.ui_scale(h);
+            fill_rgb(buf, stride, w, h, 0, 0, 0);
+            let (dx, dy) = (eyes.disparity_x, eyes.disparity_y);
+            for (i, (x, y, ew, eh)) in eyes.areas(w, h).into_iter().enumerate() {
+                let right = i == 1;
+                // Half the disparity each way, the odd pixel to the right eye
+                let (ox, oy) = if right {
+                    (dx - dx / 2, dy - dy / 2)
+                } else {
+                    (-(dx / 2), -(dy / 2))
+                };
+                let eye = &mut buf[y * stride + x * 4..];
+                draw_eye(eye, stride, ew, eh, right, ox, oy, ui);
+            }
+            if state.labels {
+                let [(_, _, lw, lh), _] = eyes.areas(w, h);
+                let split = match eyes.layout {
+                    EyeLayout::SideBySide => format!("split at x {}", lw),
+                    EyeLayout::TopBottom => format!("split at y {}", lh),
+                    EyeLayout::FramePacking => {
+                        format!("eyes {}x{}, {} lines between", lw, lh, h - 2 * lh)
+                    }
+                };
+                overlay.push(format!(
+                    "3D {}, {}, disparity {:+} x {:+} y px",
+                    eyes.layout.name(),
+                    split,
+                    dx,
+                    dy
+                ));
+            }
+        }
+        // 75% bars over the frame index, the wall clock and the index again
+        // as a strip a capture can be decoded from, all redrawn every flip,
+        // so a capture chain's latency can be read off any captured frame
+        PatternKind::Timecode => {
+            let bars_h = h / 2;
+            draw_color_bars(buf, stride, w, bars_h, &SMPTE_BARS);
+            let frame = surface.frames_presented();
+            let ui = state.ui_scale(h);
+            let strip_y = timecode::draw(
+                &mut buf[bars_h * stride..],
+                stride,
+                w,
+                h - bars_h,
+                frame,
+                SystemTime::now(),
+                ui,
+            );
+            if state.labels {
+                // Read the strip back as a capture would, to show it decodes
+                let row_y = bars_h + strip_y;
+                let read = match (row_y < h)
+                    .then(|| timecode::read_row(&buf[row_y * stride..row_y * stride + w * 4], w))
+                    .flatten()
+                {
+                    Some(index) => format!("reads back {}", index),
+                    None => "does not decode, too small".to_string(),
+                };
+                overlay.push(format!(
+                    "timecode: frame {}, strip at row {} {}",
+                    frame, row_y, read
+                ));
+            }
+        }
+        PatternKind::GrayToGray => {
+            let n = state.g2g_levels;
+            let phase = inversion_phase(surface.frames_presented() + state.phase_offset);
+            draw_gray_to_gray(buf, stride, w, h, n, phase);
+            if state.labels {
+                let levels: Vec<String> = (0..n).map(|i| g2g_level(i, n).to_string()).collect();
+                overlay.push(format!(
+                    "gray to gray, every flip: rows from {}, columns to {} (the same levels)",
+                    levels.join(" "),
+                    levels.join(" ")
+                ));
+            }
+        }
+        PatternKind::AvSync => {
+            fill_rgb(buf, stride, w, h, 0, 0, 0);
+            if av_flash_on(surface.frames_presented(), surface.refresh_hz()) {
+                let (bw, bh) = (w * 3 / 5, h * 3 / 5);
+                fill_rect(
+                    buf,
+                    stride,
+                    w,
+                    h,
+                    ((w - bw) / 2) as isize,
+                    ((h - bh) / 2) as isize,
+                    bw,
+                    bh,
+                    255,
+                    255,
+                    255,
+                );
+            }
+            if state.labels {
+                let (period, len) = av_flash_timing(surface.refresh_hz());
+                overlay.push(format!(
+                    "AV sync: {}-flip flash every {} flips",
+                    len, period
+                ));
+            }
+        }
+        PatternKind::Oscillator => {
+            state.advance_osc(now);
+            let (r, g, b) = state.osc_levels();
+
+            fill_rgb(buf, stride, w, h, r, g, b);
+            if state.labels {
+                for (i, f) in state.osc.iter().enumerate() {
+                    overlay.push(format!(
+                        "{}{} {:.1} Hz {:.0} deg {:.0}%",
+                        if i == state.osc_channel { ">" } else { " " },
+                        OSC_CHANNELS[i],
+                        f.freq_hz,
+                        f.phase * 360.0,
+                        f.amplitude * 100.0
+                    ));
+                }
+            }
+        }
+        PatternKind::Ruler => match pixels_per_mm(state, surface) {
+            Some(((px_x, px_y), source)) => {
+                let side = draw_ruler(buf, stride, w, h, (px_x, px_y));
+                if state.labels {
+                    overlay.push(format!(
+                        "{:.2} x {:.2} px/mm ({:.1} x {:.1} dpi), {}; numbers are cm",
+                        px_x,
+                        px_y,
+                        px_x * 25.4,
+                        px_y * 25.4,
+                        source
+                    ));
+                    if side.is_none() {
+                        overlay.push("no room for a 10 mm square".to_string());
+                    }
+                }
+            }
+            None => {
+                fill_rgb(buf, stride, w, h, 0, 0, 0);
+                overlay.push("the display reports no picture size; pass --dpi".to_string());
+            }
+        },
+        PatternKind::BacklightSweep => {
+            let v = color::srgb_encode_u8(0.5);
+            fill_rgb(buf, stride, w, h, v, v, v);
+            let (i, pct, _) = state.backlight_sweep(now).unwrap_or_default();
+            match state.backlight_pct {
+                Some(actual) if state.labels => overlay.push(format!(
+                    "backlight sweep: level {} of {}, {}% (backlight at {}%)",
+                    i + 1,
+                    state.backlight.steps.max(2),
+                    pct,
+                    actual
+                )),
+                Some(_) => {}
+                None => overlay.push("backlight sweep: needs --backlight".to_string()),
+            }
+        }
+        PatternKind::Bounce => {
+            fill_rgb(buf, stride, w, h, 0, 0, 0);
+            let text = &state.bounce.text;
+            let scale = (h / 180).max(1);
+            let (tw, th) = (text_width(text, scale), GLYPH_H * scale);
+            let speed = state.bounce.speed as f64;
+            let edge = EdgeBehavior::Bounce;
+            state.bounce_x.advance(now, speed, w, tw, edge);
+            state.bounce_y.advance(now, speed * 0.75, h, th, edge);
+            // Once round the wheel every 30 s
+            let t = now
+                .saturating_duration_since(state.step_started)
+                .as_secs_f64();
+            let (r, g, b) = color::hsv_to_rgb(t * 12.0, 1.0, 1.0);
+            let (x, y) = (state.bounce_x.pos as usize, state.bounce_y.pos as usize);
+            draw_text(buf, stride, w, h, x, y, text, scale, r, g, b);
+            if state.labels {
+                overlay.push(format!("bouncing text {} px/s", state.bounce.speed));
+            }
+        }
+        PatternKind::EdidWhite => {
+            let edid_white = surface.edid().and_then(edid::white_point);
+            let (x, y) = edid_white.unwrap_or(edid::D65);
+            // A mid gray in light, tinted toward the panel's declared white
+            let (r, g, b) = color::white_rgb_linear(x, y);
+            let [r, g, b] = [r, g, b].map(|c| color::srgb_encode_u8(0.5 * c));
+
+            fill_rgb(buf, stride, w, h, r, g, b);
+            if state.labels {
+                overlay.push(format!(
+                    "white x {:.4} y {:.4} ({})",
+                    x,
+                    y,
+                    if edid_white.is_some() {
+                        "EDID"
+                    } else {
+                        "no EDID white point, D65"
+                    }
+                ));
+                overlay.push(format!("fill {} {} {}", r, g, b));
+            }
+        }
+        PatternKind::ColorChecker => {
+            draw_color_checker(buf, stride, w, h, state.labels);
+        }
+        PatternKind::GamutCorners => {
+            let clipped = draw_gamut_corners(buf, stride, w, h, state.gamut, state.labels);
+            if state.labels {
+                overlay.push(format!(
+                    "gamut corners: {} target, {} signaling",
+                    state.gamut.name(),
+                    SIGNAL_GAMUT.name()
+                ));
+                if clipped {
+                    overlay.push(format!(
+                        "{} reaches outside {}, so its corners fall back to the {} primaries",
+                        state.gamut.name(),
+                        SIGNAL_GAMUT.name(),
+                        SIGNAL_GAMUT.name()
+                    ));
+                }
+            }
+        }
+        PatternKind::Patches => {
+            draw_patches(buf, stride, w, h);
+        }
+        PatternKind::NearBlack => {
+            draw_near_black(buf, stride, w, h, state.near_black, state.ui_scale(h));
+        }
+        PatternKind::BitDepth => {
+            let ui = state.ui_scale(h);
+            draw_bit_depth(buf, stride, w, h, state.bits_channels, ui, state.labels);
+            if state.labels {
+                overlay.push(format!(
+                    "bit depth: {}, 1 to 8 bits top to bottom",
+                    state.bits_channels.name()
+                ));
+            }
+        }
+        PatternKind::ContrastSensitivity => {
+            draw_csf(buf, stride, w, h);
+            if state.labels {
+                overlay.push("frequency rises to the right, contrast falls downward".to_string());
+            }
+        }
+        PatternKind::SubpixelText => {
+            // Without a reported layout RGB is the usual one
+            let order = state
+                .subpixel
+                .or(surface.subpixel())
+                .unwrap_or(SubpixelOrder::Rgb);
+            draw_subpixel_text(buf, stride, w, h, order);
+            if state.labels {
+                overlay.push(format!(
+                    "subpixel order {} ({})",
+                    order.name(),
+                    if state.subpixel.is_some() {
+                        "chosen"
+                    } else if surface.subpixel().is_some() {
+                        "reported by the connector"
+                    } else {
+                        "connector doesn't say"
+                    }
+                ));
+            }
+        }
+        PatternKind::ChromaSubsampling => {
+            draw_chroma_subsampling(buf, stride, w, h);
+        }
+        PatternKind::ChannelOrder => {
+            draw_channel_order(buf, stride, w, h, state.ui_scale(h));
+            if state.labels {
+                overlay.push(format!(
+                    "each letter in its own color; R showing blue means the panel is wired BGR, F4 swaps (now {})",
+                    if state.swap_rb { "on" } else { "off" }
+                ));
+            }
+        }
+        PatternKind::ColorBars => {
+            let step = state.current_step();
+            let palette = match step.bar_colors.as_slice() {
+                [] => &SMPTE_BARS[..],
+                colors => colors,
+            };
+            let count = match step.bar_count {
+                0 => palette.len(),
+                n => n,
+            };
+            let colors: Vec<_> = palette.iter().copied().cycle().take(count).collect();
+            draw_color_bars(buf, stride, w, h, &colors);
+            if state.labels {
+                overlay.push(format!("{} bars of {} colors", count, palette.len()));
+            }
+        }
+        PatternKind::Viewing => {
+            draw_viewing_card(buf, stride, w, h);
+            if state.labels
+                && let Some(link) = surface.link_summary()
+            {
+                overlay.push(link);
+            }
+        }
+        PatternKind::Keystone => {
+            let keystone = state.keystone;
+            let outline = !keystone.blink
+                || keystone_outline_on(surface.frames_presented(), surface.refresh_hz());
+            draw_keystone(buf, stride, w, h, keystone.aspect, outline);
+            if state.labels {
+                let (_, _, bw, bh) = fit_aspect(w, h, keystone.aspect.ratio());
+                overlay.push(format!(
+                    "target {}: {}x{} of {}x{}{}",
+                    keystone.aspect.name(),
+                    bw,
+                    bh,
+                    w,
+                    h,
+                    if keystone.blink {
+                        ", outline blinking"
+                    } else {
+                        ""
+                    }
+                ));
+            }
+        }
+        PatternKind::Focus => {
+            let focus = state.focus;
+            let spacing = draw_focus(buf, stride, w, h, focus);
+            if state.labels {
+                overlay.push(format!(
+                    "focus: {} in {}",
+                    if focus.rings {
+                        format!("1 px circles every {spacing} px")
+                    } else {
+                        "1 px checker".to_string()
+                    },
+                    if focus.green { "green" } else { "white" }
+                ));
+            }
+        }
+        PatternKind::PixelExact => {
+            draw_pixel_exact(buf, stride, w, h, &surface.mode_label());
+        }
+        PatternKind::IntegerScale => {
+            let n = state.int_scale;
+            draw_integer_scale(buf, stride, w, h, n);
+            if state.labels {
+                overlay.push(format!(
+                    "integer scale {}x: {}x{} drawn as {}x{} blocks",
+                    n,
+                    w.div_ceil(n),
+                    h.div_ceil(n),
+                    n,
+                    n
+                ));
+            }
+        }
+        PatternKind::External => {
+            draw_external(state, surface.frames_presented(), buf, stride, w, h);
+            if let Some(e) = &state.generator_error {
+                overlay.push("generator failed, see stderr".to_string());
+                overlay.push(e.clone());
+            }
+        }
+    }
+
+    // In this order: the channel mask picks from what the inverted pattern
+    // shows, and masked channels still become limited range black. The R/B
+    // swap for BGR hardware comes after all of it, overlays included, as
+    // the frame is written out, so red only still lights the panel's red.
+    state.matrix.apply(buf, stride, w, h);
+    let blink = state.blink.map(|(hz, since)| {
+        if state.labels {
+            overlay.push(format!("inverse blink {:.1} Hz", hz));
+        }
+        // A half period each way
+        (now.saturating_duration_since(since).as_secs_f64() * hz * 2.0) as u64 % 2 == 1
+    });
+    if state.invert != blink.unwrap_or(false) {
+        invert_rgb(buf, stride, w, h);
+    }
+    if let Some(keep) = state.channel_mask.keep() {
+        mask_channels(buf, stride, w, h, keep);
+    }
+    if state.output_range == OutputRange::Limited && !state.current_step().full_range {
+        limit_range(buf, stride, w, h);
+    }
+    // Last, as if a later stage of the chain converted once more
+    match state.double_range {
+        DoubleRange::Off => {}
+        DoubleRange::Compress => limit_range(buf, stride, w, h),
+        DoubleRange::Expand => expand_range(buf, stride, w, h),
+    }
+}
+
+// The matte colors F2 steps through: black, mid grey, magenta, and --matte
+// if it is none of those
+fn next_matte(args: &Args, matte: (u8, u8, u8)) -> (u8, u8, u8) {
+    let mut colors = vec![(0, 0, 0), (128, 128, 128), (255, 0, 255)];
+    if !colors.contains(&args.matte) {
+        colors.push(args.matte);
+    }
+    let i = colors.iter().position(|&c| c == matte).unwrap_or(0);
+    colors[(i + 1) % colors.len()]
+}
+
+// With --logical, fills the matte around the centered rectangle the
+// patterns are drawn into and returns the rectangle as x, y, w, h; without
+// it, the whole screen. The matte and marker are output colors, so they are
+// put into limited range here rather than by the patterns' post pass.
+fn logical_area(
+    args: &Args,
+    matte: (u8, u8, u8),
+    range: OutputRange,
+    buf: &mut [u8],
+    stride: usize,
+    w: usize,
+    h: usize,
+) -> (usize, usize, usize, usize) {
+    let Some(logical) = args.logical else {
+        return (0, 0, w, h);
+    };
+    let (x, y, lw, lh) = match logical {
+        Logical::Size(lw, lh) => {
+            let (lw, lh) = (lw.min(w), lh.min(h));
+            ((w - lw) / 2, (h - lh) / 2, lw, lh)
+        }
+        Logical::Aspect(ratio) => fit_aspect(w, h, ratio),
+    };
+    let out = |(r, g, b): (u8, u8, u8)| match range {
+        OutputRange::Full => (r, g, b),
+        OutputRange::Limited => (
+            color::to_limited_range(r),
+            color::to_limited_range(g),
+            color::to_limited_range(b),
+        ),
+    };
+
+    let (r, g, b) = out(matte);
+    let (right, bottom) = (x + lw, y + lh);
+    fill_rect(buf, stride, w, h, 0, 0, w, y, r, g, b);
+    fill_rect(
+        buf,
+        stride,
+        w,
+        h,
+        0,
+        bottom as isize,
+        w,
+        h - bottom,
+        r,
+        g,
+        b,
+    );
+    fill_rect(buf, stride, w, h, 0, y as isize, x, lh, r, g, b);
+    fill_rect(
+        buf,
+        stride,
+        w,
+        h,
+        right as isize,
+        y as isize,
+        w - right,
+        lh,
+        r,
+        g,
+        b,
+    );
+    if args.logical_marker {
+        // In the matte, so no pattern can cover it
+        let (r, g, b) = out((255, 0, 0));
+        let (mx, my) = (x as isize - 1, y as isize - 1);
+        draw_rect_outline(buf, stride, w, h, mx, my, lw + 2, lh + 2, 1, r, g, b);
+    }
+    (x, y, lw, lh)
+}
+
+// Status line for limited range output, which is easy to forget is on
+fn range_label(state: &AppState) -> Option<String> {
+    let base = (state.output_range == OutputRange::Limited).then(|| {
+        if state.current_step().full_range {
+            "limited range output, but this step is full range".to_string()
+        } else {
+            "limited range output (16-235)".to_string()
+        }
+    });
+    let second = match state.double_range {
+        DoubleRange::Off => return base,
+        DoubleRange::Compress => "DOUBLE CONVERSION: compressed to 16-235 again",
+        DoubleRange::Expand => "DOUBLE CONVERSION: expanded from 16-235 again",
+    };
+    Some(match base {
+        Some(base) => format!("{}; {}", base, second),
+        None => format!("full range output; {}", second),
+    })
+}
+
+// Copies the current step's generated frame into `buf`, running the
+// generator first if there is nothing usable cached. Any failure leaves the
+// region magenta.
+fn draw_external(
+    state: &mut AppState,
+    frame: u64,
+    buf: &mut [u8],
+    stride: usize,
+    w: usize,
+    h: usize,
+) {
+    let step = state.current_step();
+    let fresh = step.generator_per_frame
+        || state
+            .generated
+            .as_ref()
+            .is_none_or(|&(gw, gh, _)| (gw, gh) != (w, h));
+    if fresh && state.generator_error.is_none() {
+        let command = step.generator.as_deref().unwrap_or_default();
+        let step_json = serde_json::to_string(step).unwrap_or_default();
+        match external::generate(command, w, h, frame, &step_json) {
+            Ok(pixels) => state.generated = Some((w, h, pixels)),
+            Err(e) => {
+                eprintln!("Step {}: {:#}", state.script_idx + 1, e);
+                state.generated = None;
+                state.generator_error = Some(format!("{:#}", e));
+            }
+        }
+    }
+
+    match &state.generated {
+        Some((_, _, pixels)) if state.generator_error.is_none() => {
+            for (dst, src) in buf.chunks_mut(stride).zip(pixels.chunks_exact(w * 4)) {
+                dst[..w * 4].copy_from_slice(src);
+            }
+        }
+        _ => fill_rgb(buf, stride, w, h, 255, 0, 255),
+    }
+}
+
+fn poll_timeout(deadline: Option<Instant>) -> PollTimeout {
+    match deadline {
+        None => PollTimeout::NONE,
+        Some(deadline) => {
+            // Round up so we don't wake a fraction of a millisecond early and spin
+            let us = deadline
+                .saturating_duration_since(Instant::now())
+                .as_micros();
+            let ms = us.div_ceil(1000).min(i32::MAX as u128);
+            PollTimeout::try_from(ms).unwrap_or(PollTimeout::MAX)
+        }
+    }
+}
+
+// A desktop session holding the display makes the modeset fail or fight it,
+// so say so up front and, at a terminal, ask before going on
+fn warn_if_compositor_running(builder: &SurfaceBuilder) -> Result<()> {
+    let mut signs = Vec::new();
+    for var in ["WAYLAND_DISPLAY", "DISPLAY"] {
+        if std::env::var_os(var).is_some_and(|v| !v.is_empty()) {
+            signs.push(format!("{} is set", var));
+        }
+    }
+    if builder.master_taken() == Some(true) {
+        signs.push("another process is DRM master on the card".to_string());
+    }
+    if signs.is_empty() {
+        return Ok(());
+    }
+    eprintln!(
+        "Warning: a compositor looks to be running ({})",
+        signs.join(", ")
+    );
+    eprintln!(
+        "Setting the mode will likely fail or fight with it; run from a text console \
+         (e.g. Ctrl+Alt+F3) with the desktop stopped"
+    );
+    if !std::io::stdin().is_terminal() {
+        return Ok(());
+    }
+    eprint!("Continue anyway? [y/N] ");
+    let mut answer = String::new();
+    std::io::stdin().read_line(&mut answer)?;
+    if !answer.trim().eq_ignore_ascii_case("y") {
+        return Err(anyhow!("stopped: the display is in use").context(Exit::DeviceOpen));
+    }
+    Ok(())
+}
+
+fn surface_builder(args: &Args) -> SurfaceBuilder {
+    let mut builder = SurfaceBuilder::new()
+        .connector(args.connector.clone())
+        .mode(args.mode.clone())
+        .format(args.format)
+        .flip_timeout(args.flip_timeout)
+        .buffer_count(args.buffers)
+        .content_type(args.content_type.clone())
+        .max_bpc(args.max_bpc)
+        .nv12(args.nv12.map(|layout| (layout, args.nv12_matrix)))
+        .allow_interlaced(args.allow_interlaced)
+        .accept_unknown(args.accept_unknown);
+    if let Some(path) = &args.card {
+        builder = builder.card(path);
+    }
+    builder
+}
+
+fn main() -> ExitCode {
+    match run() {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            eprintln!("Error: {:?}", e);
+            ExitCode::from(exit::code(&e))
+        }
+    }
+}
+
+fn run() -> Result<()> {
+    let args = Args::parse().context(Exit::Usage)?;
+
+    let config = args
+        .config
+        .as_deref()
+        .map(Config::load)
+        .transpose()?
+        .unwrap_or_default();
+
+    if let Some(path) = &args.dump_script {
+        return library::dump_script(path);
+    }
+
+    let builder = surface_builder(&args);
+
+    if args.list {
+        return list_outputs(&builder.open_card()?);
+    }
+
+    if args.dump_props {
+        return diag::dump_props(&builder);
+    }
+
+    if let ModeSelector::Custom(mode) = &args.mode {
+        let (w, h) = mode.size();
+        eprintln!(
+            "Custom mode {}x{}: {:.3} MHz pixel clock, {:.3} Hz",
+            w,
+            h,
+            mode.clock() as f64 / 1000.0,
+            surface::exact_refresh(mode)
+        );
+        eprintln!("Warning: timings the display can't take may leave it without a signal");
+    }
+
+    if let Some(iterations) = args.stress_modeset {
+        return diag::stress_modeset(&builder, iterations);
+    }
+
+    if let Some(path) = &args.fbdev {
+        return run_fbdev(&args, path, &config);
+    }
+
+    warn_if_compositor_running(&builder)?;
+
+    if let Some(path) = &args.writeback {
+        return run_writeback(&args, &builder, path, &config);
+    }
+
+    if let Some(dwell) = args.sweep_outputs {
+        return sweep_outputs(&args, &builder, dwell);
+    }
+
+    MainLoop::open(args, config, builder)?.run()
+}
+
+// --sweep-outputs: each connected display in turn at its preferred mode,
+// showing the viewing card labeled with the connector and mode for `dwell`.
+// A display passes if the modeset works and a flip completes on it.
+fn sweep_outputs(args: &Args, builder: &SurfaceBuilder, dwell: Duration) -> Result<()> {
+    let outputs = surface::connected_outputs(&builder.open_card()?)?;
+    if outputs.is_empty() {
+        return Err(anyhow!("no connected displays to sweep")).context(Exit::NoDisplay);
+    }
+    signals::install()?;
+
+    let mut results = Vec::new();
+    for (i, output) in outputs.iter().enumerate() {
+        if signals::shutdown_requested() {
+            break;
+        }
+        eprintln!("Display {} of {}: {}", i + 1, outputs.len(), output.name);
+        let builder = surface_builder(args)
+            .connector(ConnectorSelector::Name(output.name.clone()))
+            .mode(ModeSelector::Preferred);
+        let result = sweep_one(builder, output.native, (i + 1, outputs.len()), dwell);
+        if let Err(e) = &result {
+            eprintln!("  failed: {:#}", e);
+        }
+        results.push((&output.name, result));
+    }
+
+    println!("Output sweep:");
+    for (name, result) in &results {
+        match result {
+            Ok(note) => println!("  {:<12} ok      {}", name, note),
+            Err(e) => println!("  {:<12} FAILED  {:#}", name, e),
+        }
+    }
+    for output in outputs.iter().skip(results.len()) {
+        println!("  {:<12} skipped", output.name);
+    }
+    let failed = results.iter().filter(|(_, r)| r.is_err()).count();
+    if failed > 0 {
+        return Err(anyhow!(
+            "{} of {} displays failed the sweep",
+            failed,
+            outputs.len()
+        ))
+        .context(Exit::NoDisplay);
+    }
+    Ok(())
+}
+
+// Shows the card on one display; the mode it ran at on success
+fn sweep_one(
+    builder: SurfaceBuilder,
+    native: Option<(u32, u32)>,
+    (n, of): (usize, usize),
+    dwell: Duration,
+) -> Result<String> {
+    let mut surface = builder.build()?;
+    let (w, h, stride) = (surface.disp_w, surface.disp_h, surface.stride());
+    let matches_native = native.is_none_or(|(nw, nh)| (nw as usize, nh as usize) == (w, h));
+    let note = match native {
+        Some(_) if matches_native => format!("{}, native", surface.mode_name()),
+        Some((nw, nh)) => format!("{}, NOT the EDID native {}x{}", surface.mode_name(), nw, nh),
+        None => format!("{}, no EDID native mode", surface.mode_name()),
+    };
+
+    let mut stage = vec![0u8; h * stride];
+    draw_viewing_card(&mut stage, stride, w, h);
+    let lines = [
+        surface.connector_label().to_string(),
+        note.clone(),
+        format!("display {} of {}", n, of),
+    ];
+    let scale = (h / 270).max(1);
+    let line_h = (GLYPH_H + 4) * scale;
+    let top = h.saturating_sub(lines.len() * line_h) / 2;
+    for (i, line) in lines.iter().enumerate() {
+        let x = w.saturating_sub(text_width(line, scale)) / 2;
+        draw_label(&mut stage, stride, w, h, x, top + i * line_h, line, scale);
+    }
+    surface.write_to_back(&stage, stride)?;
+    surface.flip()?;
+
+    let until = Instant::now() + dwell;
+    while !signals::shutdown_requested() {
+        let now = Instant::now();
+        if now >= until {
+            break;
+        }
+        let wake = surface.flip_deadline().map_or(until, |d| d.min(until));
+        let mut fds = [PollFd::new(surface.card.as_fd(), PollFlags::POLLIN)];
+        match poll(&mut fds, poll_timeout(Some(wake))) {
+            Ok(_) | Err(Errno::EINTR) => {}
+            Err(e) => return Err(e.into()),
+        }
+        if fds[0]
+            .revents()
+            .is_some_and(|r| r.contains(PollFlags::POLLIN))
+        {
+            surface.handle_drm_events()?;
+        }
+        surface.expire_flip(Instant::now())?;
+    }
+    ensure!(
+        surface.flip_stats.count > 0,
+        "the flip never completed on {}",
+        surface.mode_name()
+    );
+    Ok(note)
+}
+
+// --fbdev: a reduced loop for framebuffer devices. There are no flip events
+// to pace it, so animated patterns are presented on a timer at the refresh
+// rate, and of the keys only the script, pause and label ones apply.
+fn run_fbdev(args: &Args, path: &Path, config: &Config) -> Result<()> {
+    let mut fb = FbDev::open(path).context(Exit::DeviceOpen)?;
+    let (_, kb) = open_keyboard().context(Exit::InputUnavailable)?;
+    run_basic(args, &mut fb, Some(Grabbed::new(kb, args.grab)), config)
+}
+
+fn run_writeback(
+    args: &Args,
+    builder: &SurfaceBuilder,
+    path: &Path,
+    config: &Config,
+) -> Result<()> {
+    let card = builder.open_card()?;
+    let mut wb =
+        Writeback::open(card, &args.connector, &args.mode, path).context(Exit::NoDisplay)?;
+    // Headless runs have nothing to type on
+    let kb = match open_keyboard() {
+        Ok((_, kb)) => Some(Grabbed::new(kb, args.grab)),
+        Err(e) => {
+            eprintln!(
+                "No keyboard ({:#}); --auto or --max-runtime will have to end the run",
+                e
+            );
+            None
+        }
+    };
+    run_basic(args, &mut wb, kb, config)
+}
+
+// The reduced loop --fbdev and --writeback share: the script, a few keys
+// and --auto, presenting on a Backend at its refresh rate
+fn run_basic(
+    args: &Args,
+    display: &mut dyn Backend,
+    mut kb: Option<Grabbed>,
+    config: &Config,
+) -> Result<()> {
+    let _pointers = if args.grab {
+        grab::grab_pointers(None)
+    } else {
+        Vec::new()
+    };
+    let mut state = AppState::new()?;
+    let mut steps = match &args.script {
+        Some(path) => library::load_script(path)?.steps,
+        None => AppState::create_script(),
+    };
+    if !args.only.is_empty() || !args.skip.is_empty() {
+        library::filter_steps(&mut steps, &args.only, &args.skip)?;
+    }
+    let origin = args
+        .shuffle
+        .map(|seed| library::shuffle_steps(&mut steps, seed, args.shuffle_by_category));
+    if let Some(seed) = args.shuffle {
+        eprintln!(
+            "Shuffled the script with seed {} (--shuffle={} repeats this order)",
+            seed, seed
+        );
+    }
+    state.load_script(steps);
+    state.script_origin = origin;
+    if let Some(path) = &args.load_session {
+        state.restore(Session::load(path)?)?;
+    }
+    state.set_overrides(args.overrides);
+    if args.invert {
+        state.invert = true;
+    }
+    state.allow_flashing = args.allow_flashing;
+    state.blink = args.blink_inverse.map(|hz| (hz, Instant::now()));
+    if let Some(range) = args.output_range.or(config.output.range) {
+        state.output_range = range;
+    }
+    state.swap_rb = config.output.swap_rb;
+    state.quarter_turn = args.rotate.is_quarter_turn();
+    state.ui_scale = args.ui_scale.or(config.ui.scale);
+
+    // With --rotate, stage holds the frame the viewer's way up and turned
+    // holds it as the display takes it
+    let (disp_w, disp_h) = display.size();
+    let (w, h) = args.rotate.upright_size(disp_w, disp_h);
+    let stride = match args.rotate {
+        Rotation::None => display.stride(),
+        _ => w * 4,
+    };
+    let mut stage = vec![0u8; stride * h];
+    let mut turned = match args.rotate {
+        Rotation::None => Vec::new(),
+        _ => vec![0u8; display.stride() * disp_h],
+    };
+    let mut shm = args
+        .shm_export
+        .as_deref()
+        .map(ShmExport::create)
+        .transpose()?;
+    let period = Duration::from_secs_f64(1.0 / display.refresh_hz());
+    let mut next_frame = Instant::now();
+    let mut need_redraw = true;
+    let mut matte = args.matte;
+    let overlay_corner = args.overlay_corner;
+
+    signals::install()?;
+    let deadline = args.max_runtime.map(|max| Instant::now() + max);
+
+    loop {
+        let wants_frame = need_redraw || state.animating();
+        let timeout = poll_timeout(
+            [
+                wants_frame.then_some(next_frame),
+                state.auto_deadline(args.auto),
+                state.readout_deadline(),
+                deadline,
+            ]
+            .into_iter()
+            .flatten()
+            .min(),
+        );
+        let mut fds: Vec<_> = kb
+            .iter()
+            .map(|kb| PollFd::new(kb.as_fd(), PollFlags::POLLIN))
+            .collect();
+        match poll(&mut fds, timeout) {
+            Ok(_) | Err(Errno::EINTR) => {}
+            Err(e) => return Err(e.into()),
+        }
+        let kb_ready = fds
+            .first()
+            .and_then(PollFd::revents)
+            .is_some_and(|r| !r.is_empty());
+
+        if signals::shutdown_requested() {
+            return Ok(());
+        }
+        let now = Instant::now();
+        if deadline.is_some_and(|d| now >= d) {
+            return Err(Exit::Timeout.into());
+        }
+
+        if let Some(kb) = kb.as_mut().filter(|_| kb_ready) {
+            let events: Vec<_> = kb.fetch_events().context("keyboard read failed")?.collect();
+            for event in events {
+                if let EventSummary::Key(_, code, 1) = event.destructure() {
+                    match code {
+                        KeyCode::KEY_Q | KeyCode::KEY_ESC => return Ok(()),
+                        // Past the last step ends the run
+                        KeyCode::KEY_RIGHT | KeyCode::KEY_SPACE if state.next_step() => {
+                            return Ok(());
+                        }
+                        KeyCode::KEY_ENTER if state.held && state.next_step() => {
+                            return Ok(());
+                        }
+                        KeyCode::KEY_LEFT => state.previous_step(),
+                        KeyCode::KEY_P => state.toggle_pause(),
+                        KeyCode::KEY_L => state.labels = !state.labels,
+                        KeyCode::KEY_K => state.progress = !state.progress,
+                        KeyCode::KEY_Z => state.quick_fill = QuickFill::next(state.quick_fill),
+                        KeyCode::KEY_LEFTBRACE | KeyCode::KEY_RIGHTBRACE => {
+                            state.quick_fill = None;
+                            state.step_quick_solid(code == KeyCode::KEY_RIGHTBRACE);
+                        }
+                        KeyCode::KEY_BACKSLASH => state.quick_solid = None,
+                        KeyCode::KEY_F2 => matte = next_matte(args, matte),
+                        KeyCode::KEY_F4 => state.swap_rb = !state.swap_rb,
+                        _ => {}
+                    }
+                    need_redraw = true;
+                }
+            }
+        }
+
+        if state
+            .auto_deadline(args.auto)
+            .is_some_and(|deadline| now >= deadline)
+        {
+            if state.next_step() {
+                return Ok(());
+            }
+            need_redraw = true;
+        }
+
+        if (need_redraw || state.animating()) && now >= next_frame {
+            let mut overlay = Vec::new();
+            let (lx, ly, lw, lh) =
+                logical_area(args, matte, state.output_range, &mut stage, stride, w, h);
+            let content = &mut stage[ly * stride + lx * 4..];
+            draw_pattern(
+                &mut state,
+                display,
+                content,
+                stride,
+                lw,
+                lh,
+                now,
+                &mut overlay,
+            );
+            if state.labels {
+                overlay.insert(
+                    0,
+                    format!(
+                        "step {}/{}: {:?}",
+                        state.script_idx + 1,
+                        state.script.len(),
+                        state.pattern
+                    ),
+                );
+                overlay.push(display.mode_label());
+            } else {
+                overlay.clear();
+            }
+            if let Some(label) = range_label(&state) {
+                overlay.push(label);
+            }
+            if state.channel_mask != ChannelMask::All {
+                overlay.push(format!("showing {}", state.channel_mask.name()));
+            }
+            if state.held_for_operator(args.auto) {
+                overlay.insert(0, "PAUSED - press Enter to continue".to_string());
+            }
+            let ui = state.ui_scale(h);
+            if state.labels && state.progress && !state.pattern.measured() {
+                draw_progress(
+                    &mut stage,
+                    stride,
+                    w,
+                    h,
+                    state.script_idx,
+                    state.script.len(),
+                    ui,
+                );
+            }
+            if !overlay.is_empty() {
+                draw_overlay(&mut stage, stride, w, h, &overlay, ui, overlay_corner);
+            }
+            if state.swap_rb {
+                swap_rb(&mut stage, stride, w, h);
+            }
+            let out = match args.rotate {
+                Rotation::None => &stage,
+                rot => {
+                    rotate_frame(&stage, stride, w, h, &mut turned, display.stride(), rot);
+                    &turned
+                }
+            };
+            display.write_and_present(out, display.stride())?;
+            if let Some(shm) = &mut shm {
+                shm.submit(out, display.stride(), disp_w, disp_h)?;
+            }
+            need_redraw = false;
+            next_frame = (next_frame + period).max(now);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A state running `steps`, on the first of them
+    fn state_with(steps: Vec<Step>) -> AppState {
+        let mut state = AppState::new().unwrap();
+        state.load_script(steps);
+        state
+    }
+
+    // Padding past each row, filled with PAD so stray writes show
+    const PAD: u8 = 0xa5;
+
+    fn canvas(w: usize, h: usize) -> (Vec<u8>, usize) {
+        let stride = w * 4 + 12;
+        (vec![PAD; stride * h.max(1)], stride)
+    }
+
+    fn px(buf: &[u8], stride: usize, x: usize, y: usize) -> (u8, u8, u8) {
+        let p = &buf[y * stride + x * 4..];
+        (p[2], p[1], p[0])
+    }
+
+    fn padding_untouched(buf: &[u8], stride: usize, w: usize, h: usize) -> bool {
+        (0..h).all(|y| {
+            buf[y * stride + w * 4..(y + 1) * stride]
+                .iter()
+                .all(|&b| b == PAD)
+        })
+    }
+
+    // A display that only has a size, for drawing patterns without one
+    struct Fake {
+        w: usize,
+        h: usize,
+        stride: usize,
+        frames: u64,
+    }
+
+    impl Backend for Fake {
+        fn size(&self) -> (usize, usize) {
+            (self.w, self.h)
+        }
+
+        fn stride(&self) -> usize {
+            self.stride
+        }
+
+        fn refresh_hz(&self) -> f64 {
+            60.0
+        }
+
+        fn frames_presented(&self) -> u64 {
+            self.frames
+        }
+
+        fn mode_label(&self) -> String {
+            format!("TEST {}x{}", self.w, self.h)
+        }
+
+        fn write_and_present(&mut self, _src: &[u8], _src_stride: usize) -> Result<()> {
+            Ok(())
+        }
+    }
+
+    // Draws the current step of `state` at w x h as of `frames` flips, and
+    // returns the stage, its stride and the overlay lines
+    fn render(
+        state: &mut AppState,
+        w: usize,
+        h: usize,
+        frames: u64,
+    ) -> (Vec<u8>, usize, Vec<String>) {
+        let (mut buf, stride) = canvas(w, h);
+        let fake = Fake {
+            w,
+            h,
+            stride,
+            frames,
+        };
+        let mut overlay = Vec::new();
+        draw_pattern(
+            state,
+            &fake,
+            &mut buf,
+            stride,
+            w,
+            h,
+            Instant::now(),
+            &mut overlay,
+        );
+        (buf, stride, overlay)
+    }
+
+    // One step of `pat` with its defaults, and a generator for an external one
+    fn step_of(pat: PatternKind) -> Step {
+        Step {
+            pat,
+            generator: (pat == PatternKind::External).then(|| {
+                "head -c $((SCREEN_TEST_STRIDE * SCREEN_TEST_HEIGHT)) /dev/zero".to_string()
+            }),
+            ..Default::default()
+        }
+    }
+
+    fn solids(n: usize) -> Vec<Step> {
+        (0..n)
+            .map(|i| Step {
+                solid_idx: i,
+                ..Default::default()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn previous_from_first_step_wraps_to_last() {
+        let mut state = state_with(solids(3));
+        state.previous_step();
+        assert_eq!(state.script_idx, 2);
+        assert_eq!(state.solid_idx, 2);
+        assert!(state.next_step());
+        assert_eq!(state.script_idx, 2);
+    }
+
+    #[test]
+    fn next_from_last_step_ends_without_moving() {
+        let mut state = state_with(solids(3));
+        assert!(!state.next_step());
+        assert!(!state.next_step());
+        assert_eq!(state.script_idx, 2);
+        assert!(state.next_step());
+        assert_eq!(state.script_idx, 2);
+        assert_eq!(state.current_step().solid_idx, 2);
+        state.previous_step();
+        assert_eq!(state.script_idx, 1);
+    }
+
+    #[test]
+    fn single_step_script_stays_on_its_step() {
+        let mut state = state_with(solids(1));
+        assert!(state.next_step());
+        assert_eq!(state.script_idx, 0);
+        state.previous_step();
+        assert_eq!(state.script_idx, 0);
+        assert!(state.next_step());
+        assert_eq!(state.script_idx, 0);
+    }
+
+    // The gradient as it was drawn before it went through lookup tables,
+    // pixel by pixel
+    fn naive_gradient(w: usize, h: usize, vertical: bool, light: bool) -> Vec<(u8, u8, u8)> {
+        let len = if vertical { h } else { w };
+        let mut out = Vec::new();
+        for y in 0..h {
+            for x in 0..w {
+                let t = if vertical { y } else { x };
+                let v = if light {
+                    color::srgb_encode_u8(t as f64 / (len - 1).max(1) as f64)
+                } else {
+                    ((t * 255) / (len - 1).max(1)) as u8
+                };
+                out.push((v, v, v));
+            }
+        }
+        out
+    }
+
+    #[test]
+    fn gradient_matches_per_pixel_reference() {
+        for (w, h) in [(1, 1), (2, 1), (7, 5), (256, 3), (641, 481), (3840, 2)] {
+            for vertical in [false, true] {
+                for light in [false, true] {
+                    let (mut buf, stride) = canvas(w, h);
+                    draw_gradient(&mut buf, stride, w, h, GradMode::Luma, vertical, light);
+                    let want = naive_gradient(w, h, vertical, light);
+                    for y in 0..h {
+                        for x in 0..w {
+                            assert_eq!(
+                                px(&buf, stride, x, y),
+                                want[y * w + x],
+                                "{}x{} vertical {} light {} at {},{}",
+                                w,
+                                h,
+                                vertical,
+                                light,
+                                x,
+                                y
+                            );
+                        }
+                    }
+                    assert!(padding_untouched(&buf, stride, w, h));
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn gradient_spans_black_to_white() {
+        let (w, h) = (3840, 2160);
+        let (mut buf, stride) = canvas(w, h);
+        draw_gradient(&mut buf, stride, w, h, GradMode::Luma, false, false);
+        assert_eq!(px(&buf, stride, 0, h - 1), (0, 0, 0));
+        assert_eq!(px(&buf, stride, w - 1, h - 1), (255, 255, 255));
+    }
+
+    #[test]
+    fn checkerboard_matches_per_pixel_reference() {
+        for (w, h) in [(1, 1), (5, 3), (64, 64), (641, 481)] {
+            for cell in [1, 2, 3, 8, 100] {
+                for offset in [0, 1, cell, 2 * cell + 1] {
+                    for (_, colors) in CHECKER_PAIRS {
+                        let (mut buf, stride) = canvas(w, h);
+                        draw_checkerboard(&mut buf, stride, w, h, cell, offset, colors);
+                        for y in 0..h {
+                            for x in 0..w {
+                                // The baseline's cell parity, light where even
+                                let light = ((((x + offset) / cell) ^ (y / cell)) & 1) == 0;
+                                let want = colors[if light { 0 } else { 1 }];
+                                assert_eq!(
+                                    px(&buf, stride, x, y),
+                                    want,
+                                    "{}x{} cell {} offset {} at {},{}",
+                                    w,
+                                    h,
+                                    cell,
+                                    offset,
+                                    x,
+                                    y
+                                );
+                            }
+                        }
+                        assert!(padding_untouched(&buf, stride, w, h));
                     }
+                }
+            }
+        }
+    }
+
+    // 1x1, 1xN and Nx1, where any size arithmetic that can go below zero
+    // or divide by a zero cell does
+    const DEGENERATE: [(usize, usize); 6] = [(1, 1), (1, 2), (2, 1), (1, 480), (640, 1), (3, 3)];
+
+    #[test]
+    fn degenerate_sizes_render() {
+        for (w, h) in DEGENERATE {
+            let (mut buf, stride) = canvas(w, h);
+            draw_checkerboard(&mut buf, stride, w, h, 8, 0, CHECKER_PAIRS[0].1);
+            assert_eq!(px(&buf, stride, 0, 0), (255, 255, 255));
+            assert!(padding_untouched(&buf, stride, w, h), "checker {}x{}", w, h);
+
+            let (mut buf, stride) = canvas(w, h);
+            draw_patches(&mut buf, stride, w, h);
+            assert!(padding_untouched(&buf, stride, w, h), "patches {}x{}", w, h);
 
-                    draw_motion_bar(
-                        &mut stage,
-                        surface.stride(),
-                        surface.disp_w,
-                        surface.disp_h,
-                        state.motion_x as usize,
-                        bar_w,
+            let (mut buf, stride) = canvas(w, h);
+            draw_viewing_card(&mut buf, stride, w, h);
+            assert!(padding_untouched(&buf, stride, w, h), "viewing {}x{}", w, h);
+
+            for aa in [false, true] {
+                for x in [0.0, 0.5, w as f64 - 0.5, w as f64 * 3.0] {
+                    let (mut buf, stride) = canvas(w, h);
+                    draw_motion_bar(&mut buf, stride, w, h, x, 8, aa);
+                    assert!(
+                        padding_untouched(&buf, stride, w, h),
+                        "motion {}x{} at {}",
+                        w,
+                        h,
+                        x
                     );
                 }
-                PatternKind::Viewing => {
-                    draw_viewing_card(&mut stage, surface.stride(), surface.disp_w, surface.disp_h);
+            }
+        }
+    }
+
+    #[test]
+    fn motion_bar_leaves_background_on_narrow_screens() {
+        // The bar is narrowed to keep a background column however wide it
+        // is asked to be
+        for w in [2, 3, 9] {
+            let (mut buf, stride) = canvas(w, 1);
+            draw_motion_bar(&mut buf, stride, w, 1, 0.0, 100, false);
+            assert!((0..w).any(|x| px(&buf, stride, x, 0) == (128, 128, 128)));
+        }
+    }
+
+    #[test]
+    fn patches_mirror_near_black_and_near_white() {
+        for (w, h, area) in [
+            (1920, 1080, 216),
+            (640, 480, 96),
+            (1366, 768, 153),
+            (64, 64, 12),
+        ] {
+            let (mut buf, stride) = canvas(w, h);
+            draw_patches(&mut buf, stride, w, h);
+            let strip = area / 5;
+            for i in 0..5 {
+                let (cx, cy) = (area / 2, i * strip + strip / 2);
+                let dark = 1 + i as u8;
+                assert_eq!(px(&buf, stride, cx, cy), (dark, dark, dark), "{}x{}", w, h);
+                let light = 250 + i as u8;
+                assert_eq!(
+                    px(&buf, stride, w - 1 - cx, h - area + cy),
+                    (light, light, light),
+                    "{}x{}",
+                    w,
+                    h
+                );
+            }
+            assert_eq!(px(&buf, stride, w / 2, h / 2), (0, 0, 0));
+            assert!(padding_untouched(&buf, stride, w, h));
+        }
+    }
+
+    #[test]
+    fn every_pattern_renders_at_odd_sizes() {
+        for (w, h) in [(64, 64), (641, 481), (1, 1), (7, 3)] {
+            for pat in PatternKind::ALL {
+                let mut state = state_with(vec![step_of(pat)]);
+                state.labels = true;
+                for frames in [0, 1, 2] {
+                    let (buf, stride, _) = render(&mut state, w, h, frames);
+                    assert!(
+                        padding_untouched(&buf, stride, w, h),
+                        "{:?} at {}x{} wrote outside its rows",
+                        pat,
+                        w,
+                        h
+                    );
                 }
             }
         }
+    }
 
-        if should_draw && !surface.is_flipping {
-            surface.write_to_back(&stage)?;
-            surface.flip()?;
+    #[test]
+    fn color_checker_has_24_equal_patches_in_order() {
+        for (w, h) in [(1920, 1080), (1024, 768), (641, 481)] {
+            let (mut buf, stride) = canvas(w, h);
+            draw_color_checker(&mut buf, stride, w, h, false);
 
-            need_redraw = false;
+            let mut area: std::collections::HashMap<(u8, u8, u8), usize> = Default::default();
+            let mut lit = Vec::new();
+            for y in 0..h {
+                for x in 0..w {
+                    let c = px(&buf, stride, x, y);
+                    *area.entry(c).or_default() += 1;
+                    if c != (0, 0, 0) {
+                        lit.push((x, y));
+                    }
+                }
+            }
+            // The 24 colors and the black around them
+            assert_eq!(area.len(), 25, "{}x{}", w, h);
+            let patch = area[&COLOR_CHECKER[0].1];
+            assert!(patch > 0);
+            for (name, c) in COLOR_CHECKER {
+                assert_eq!(area[&c], patch, "{} at {}x{}", name, w, h);
+            }
+
+            // Dark skin top left, bluish green top right, white bottom left
+            // and black 2 bottom right
+            let (top, bottom) = (lit[0].1, lit[lit.len() - 1].1);
+            let row = |y: usize| lit.iter().filter(move |p| p.1 == y).map(|p| p.0);
+            let corner = |x, y| px(&buf, stride, x, y);
+            assert_eq!(corner(row(top).min().unwrap(), top), COLOR_CHECKER[0].1);
+            assert_eq!(corner(row(top).max().unwrap(), top), COLOR_CHECKER[5].1);
+            assert_eq!(
+                corner(row(bottom).min().unwrap(), bottom),
+                COLOR_CHECKER[18].1
+            );
+            assert_eq!(
+                corner(row(bottom).max().unwrap(), bottom),
+                COLOR_CHECKER[23].1
+            );
         }
     }
 
-    Ok(())
+    // Columns of a motion bar frame brighter than the background
+    fn bar_columns(x_pos: f64, w: usize, bar_w: usize, aa: bool) -> Vec<usize> {
+        let (mut buf, stride) = canvas(w, 2);
+        draw_motion_bar(&mut buf, stride, w, 2, x_pos, bar_w, aa);
+        // Every row is the same
+        assert!(buf[..w * 4] == buf[stride..stride + w * 4]);
+        (0..w).filter(|&x| px(&buf, stride, x, 0).0 > 128).collect()
+    }
+
+    #[test]
+    fn motion_bar_straddling_the_right_edge_shows_on_both_sides() {
+        let (w, bar_w) = (320, 16);
+        for aa in [false, true] {
+            let cols = bar_columns((w - bar_w / 2) as f64, w, bar_w, aa);
+            assert!(
+                cols.contains(&(w - 1)) && cols.contains(&0),
+                "aa {}: {:?}",
+                aa,
+                cols
+            );
+            assert_eq!(cols.len(), bar_w);
+        }
+    }
+
+    #[test]
+    fn motion_bar_lit_columns_near_both_edges() {
+        let (w, bar_w) = (100, 10);
+        let wrapped = |from: usize| -> Vec<usize> {
+            let mut cols: Vec<usize> = (from..from + bar_w).map(|x| x % w).collect();
+            cols.sort();
+            cols
+        };
+        for x in [0, 1, 2, 89, 90, 91, 95, 99] {
+            assert_eq!(
+                bar_columns(x as f64, w, bar_w, false),
+                wrapped(x),
+                "at {}",
+                x
+            );
+            // Positions past the width or below zero wrap round the same way
+            assert_eq!(bar_columns((x + w) as f64, w, bar_w, false), wrapped(x));
+            assert_eq!(
+                bar_columns(x as f64 - w as f64, w, bar_w, false),
+                wrapped(x)
+            );
+        }
+        // Without anti-aliasing a fractional position snaps down
+        assert_eq!(bar_columns(99.7, w, bar_w, false), wrapped(99));
+
+        // With it, the bar spans one column more, the two at its ends partly lit
+        for x in [0.5, 89.25, 94.5, 99.5] {
+            let mut want: Vec<usize> = (0..=bar_w).map(|i| (x as usize + i) % w).collect();
+            want.sort();
+            assert_eq!(bar_columns(x, w, bar_w, true), want, "at {}", x);
+        }
+    }
+
+    #[test]
+    fn csf_contrast_falls_and_frequency_rises() {
+        let (w, h) = (1024, 256);
+        let (mut buf, stride) = canvas(w, h);
+        draw_csf(&mut buf, stride, w, h);
+        assert!(padding_untouched(&buf, stride, w, h));
+        let row = |y: usize| -> Vec<u8> { (0..w).map(|x| px(&buf, stride, x, y).0).collect() };
+
+        // Gray throughout, and the sweep starts at phase zero, mid gray
+        for y in 0..h {
+            let (r, g, b) = px(&buf, stride, 0, y);
+            assert_eq!((r, g, b), (128, 128, 128), "row {}", y);
+            for x in (0..w).step_by(37) {
+                let (r, g, b) = px(&buf, stride, x, y);
+                assert!(r == g && g == b, "({}, {}) is not gray", x, y);
+            }
+        }
+
+        // Full contrast along the top, next to none along the bottom
+        let top = row(0);
+        assert!(*top.iter().max().unwrap() >= 250 && *top.iter().min().unwrap() <= 5);
+        assert!(row(h - 1).iter().all(|&v| v == 127 || v == 128));
+
+        // Contrast never grows going down
+        let spread = |y: usize| {
+            let r = row(y);
+            r.iter().max().unwrap() - r.iter().min().unwrap()
+        };
+        for y in 1..h {
+            assert!(
+                spread(y) <= spread(y - 1),
+                "row {} is stronger than the one above",
+                y
+            );
+        }
+
+        // Cycles get shorter going right: count crossings of mid gray
+        let crossings = |xs: &[u8]| {
+            xs.windows(2)
+                .filter(|p| (p[0] >= 128) != (p[1] >= 128))
+                .count()
+        };
+        let (left, right) = (crossings(&top[..w / 4]), crossings(&top[w * 3 / 4..]));
+        assert!(
+            left >= 1 && right > 4 * left,
+            "{} crossings on the left, {} on the right",
+            left,
+            right
+        );
+    }
+
+    #[test]
+    fn rings_example_loads_and_draws() {
+        let root = Path::new(env!("CARGO_MANIFEST_DIR"));
+        let script = library::load_script(&root.join("examples/rings.toml")).unwrap();
+        assert_eq!(script.steps.len(), 2);
+        assert!(script.steps.iter().all(|s| s.pat == PatternKind::External));
+        assert!(!script.steps[0].generator_per_frame && script.steps[1].generator_per_frame);
+
+        // cargo test builds the examples, but into the debug directory
+        let rings = root.join("target/debug/examples/rings");
+        if !rings.exists() {
+            eprintln!("{} is not built, skipping the drawing", rings.display());
+            return;
+        }
+        let steps = script
+            .steps
+            .into_iter()
+            .map(|s| Step {
+                generator: Some(rings.display().to_string()),
+                ..s
+            })
+            .collect();
+        let mut state = state_with(steps);
+        let (w, h) = (64, 48);
+        let (buf, stride, _) = render(&mut state, w, h, 0);
+        assert!(padding_untouched(&buf, stride, w, h));
+        assert_eq!(px(&buf, stride, 32, 24), (255, 255, 255));
+        assert_eq!(px(&buf, stride, 52, 24), (0, 0, 0));
+
+        // The animated step runs again each frame, and the rings move out
+        state.next_step();
+        assert_eq!(render(&mut state, w, h, 0).0, buf);
+        let (moved, _, _) = render(&mut state, w, h, 16);
+        assert_eq!(px(&moved, stride, 32, 24), (0, 0, 0));
+    }
+
+    #[test]
+    fn failing_generator_shows_magenta() {
+        for generator in ["exit 1", "head -c 10 /dev/zero", "no-such-generator-here"] {
+            let mut state = state_with(vec![Step {
+                pat: PatternKind::External,
+                generator: Some(generator.to_string()),
+                ..Default::default()
+            }]);
+            let (w, h) = (16, 9);
+            let (buf, stride, _) = render(&mut state, w, h, 0);
+            for y in 0..h {
+                for x in 0..w {
+                    assert_eq!(px(&buf, stride, x, y), (255, 0, 255), "`{}`", generator);
+                }
+            }
+            assert!(padding_untouched(&buf, stride, w, h));
+            assert!(state.generator_error.is_some());
+        }
+    }
+
+    // The bar covering column x of w when n bars share the width
+    fn bar_at(x: usize, w: usize, n: usize) -> usize {
+        (0..n)
+            .find(|&i| i * w / n <= x && x < (i + 1) * w / n)
+            .unwrap()
+    }
+
+    #[test]
+    fn color_bars_default_to_smpte_in_equal_widths() {
+        let mut state = state_with(vec![step_of(PatternKind::ColorBars)]);
+        let (w, h) = (700, 4);
+        let (buf, stride, _) = render(&mut state, w, h, 0);
+        for (i, &color) in SMPTE_BARS.iter().enumerate() {
+            for x in [i * 100, i * 100 + 50, i * 100 + 99] {
+                for y in 0..h {
+                    assert_eq!(px(&buf, stride, x, y), color, "({}, {})", x, y);
+                }
+            }
+        }
+        assert!(padding_untouched(&buf, stride, w, h));
+    }
+
+    #[test]
+    fn color_bars_cycle_their_palette_and_split_uneven_widths() {
+        let palette = vec![(255, 0, 0), (0, 255, 0), (0, 0, 255)];
+        for (w, count) in [(703, 10), (641, 7), (7, 20), (1, 3)] {
+            let mut state = state_with(vec![Step {
+                bar_count: count,
+                bar_colors: palette.clone(),
+                ..step_of(PatternKind::ColorBars)
+            }]);
+            let (buf, stride, _) = render(&mut state, w, 3, 0);
+            for x in 0..w {
+                let want = palette[bar_at(x, w, count) % palette.len()];
+                assert_eq!(
+                    px(&buf, stride, x, 1),
+                    want,
+                    "{} bars at {}: x {}",
+                    count,
+                    w,
+                    x
+                );
+            }
+            assert!(padding_untouched(&buf, stride, w, 3));
+
+            // Neighbouring bars always differ here, so runs are bars, and
+            // none is more than a pixel wider than another
+            let row: Vec<_> = (0..w).map(|x| px(&buf, stride, x, 1)).collect();
+            let mut runs = vec![1];
+            for pair in row.windows(2) {
+                if pair[0] == pair[1] {
+                    *runs.last_mut().unwrap() += 1;
+                } else {
+                    runs.push(1);
+                }
+            }
+            if count <= w {
+                assert_eq!(runs.len(), count);
+                assert!(runs.iter().max().unwrap() - runs.iter().min().unwrap() <= 1);
+            }
+        }
+    }
+
+    #[test]
+    fn pixel_inversion_inverts_on_every_flip() {
+        assert_eq!(
+            (0..6).map(inversion_phase).collect::<Vec<_>>(),
+            [0, 1, 0, 1, 0, 1]
+        );
+
+        let mut state = state_with(vec![step_of(PatternKind::PixelInversion)]);
+        let (w, h) = (9, 5);
+        let frames: Vec<Vec<u8>> = (0..4).map(|f| render(&mut state, w, h, f).0).collect();
+        let (_, stride) = canvas(w, h);
+        for f in 0..3 {
+            for y in 0..h {
+                for x in 0..w {
+                    let (a, b) = (
+                        px(&frames[f], stride, x, y),
+                        px(&frames[f + 1], stride, x, y),
+                    );
+                    assert_eq!(
+                        (a.0 ^ b.0, a.1 ^ b.1, a.2 ^ b.2),
+                        (255, 255, 255),
+                        "({}, {}) did not invert from flip {} to {}",
+                        x,
+                        y,
+                        f,
+                        f + 1
+                    );
+                    // A 1 px checker: each pixel differs from its neighbours
+                    if x > 0 {
+                        assert_ne!(a, px(&frames[f], stride, x - 1, y));
+                    }
+                }
+            }
+        }
+        assert_eq!(frames[0], frames[2]);
+
+        // Nudging the phase swaps which frame gets which checker
+        state.phase_offset = 1;
+        assert_eq!(render(&mut state, w, h, 0).0, frames[1]);
+        assert_eq!(render(&mut state, w, h, 1).0, frames[0]);
+    }
+
+    #[test]
+    fn a_burst_of_presses_stops_on_the_unseen_last_step() {
+        for looping in [false, true] {
+            let mut state = state_with(solids(3));
+            let shown_step = Some(0);
+            // One batch of four presses while step 1 is on screen
+            for _ in 0..4 {
+                assert!(!next_step_seen(&mut state, shown_step, looping));
+            }
+            assert_eq!(state.script_idx, 2, "looping {}", looping);
+            let readout = state.readout.as_ref().map(|(text, _)| text.as_str());
+            assert!(readout.is_some_and(|r| r.starts_with("last step")));
+
+            // Input waits for the step to be presented, or for the hold to run out
+            let now = Instant::now();
+            let hold = Some(now + NAV_HOLD);
+            assert_eq!(nav_hold_left(hold, now, shown_step, 2), hold);
+            assert_eq!(nav_hold_left(hold, now, Some(2), 2), None);
+            assert_eq!(nav_hold_left(hold, now + NAV_HOLD, shown_step, 2), None);
+
+            // Once it is on screen, the next press ends the run or wraps
+            let shown_step = Some(2);
+            assert_eq!(next_step_seen(&mut state, shown_step, looping), !looping);
+            assert_eq!(state.script_idx, if looping { 0 } else { 2 });
+        }
+    }
+
+    // Bounding box (x0, y0, x1, y1), exclusive at the far end, of the pixels
+    // matching `hit`
+    fn bbox(
+        buf: &[u8],
+        stride: usize,
+        w: usize,
+        h: usize,
+        hit: impl Fn((u8, u8, u8)) -> bool,
+    ) -> Option<(usize, usize, usize, usize)> {
+        let mut found: Option<(usize, usize, usize, usize)> = None;
+        for y in 0..h {
+            for x in 0..w {
+                if hit(px(buf, stride, x, y)) {
+                    let (x0, y0, x1, y1) = found.unwrap_or((x, y, x + 1, y + 1));
+                    found = Some((x0.min(x), y0.min(y), x1.max(x + 1), y1.max(y + 1)));
+                }
+            }
+        }
+        found
+    }
+
+    const GOLDEN_SIZES: [(usize, usize); 4] = [(64, 64), (320, 240), (641, 481), (1366, 768)];
+
+    #[test]
+    fn element_sizes_scale_with_the_display() {
+        for (w, h) in GOLDEN_SIZES {
+            let short = w.min(h);
+
+            // Checker cells shrink to fit at least four across the short side
+            let mut state = state_with(vec![Step {
+                checker_cell: 100,
+                ..step_of(PatternKind::Checker)
+            }]);
+            let (buf, stride, _) = render(&mut state, w, h, 0);
+            let cell = 100.min(short / 4);
+            let first = px(&buf, stride, 0, 0);
+            assert!(
+                (0..cell).all(|x| px(&buf, stride, x, 0) == first)
+                    && px(&buf, stride, cell, 0) != first,
+                "{}x{}: checker cell is not {} px",
+                w,
+                h,
+                cell
+            );
+
+            // Near-black and near-white corners a fifth of the short side, apart
+            let mut state = state_with(vec![step_of(PatternKind::Patches)]);
+            let (buf, stride, _) = render(&mut state, w, h, 0);
+            let area = short / 5;
+            let strips = area / 5 * 5;
+            let dark = bbox(&buf, stride, w, h, |(r, _, _)| (1..=5).contains(&r));
+            let light = bbox(&buf, stride, w, h, |(r, _, _)| (250..=254).contains(&r));
+            assert_eq!(dark, Some((0, 0, area, strips)), "{}x{}", w, h);
+            assert_eq!(
+                light,
+                Some((w - area, h - area, w, h - area + strips)),
+                "{}x{}",
+                w,
+                h
+            );
+
+            // The viewing card's top corner boxes stay a fifth of the width
+            // and clear of each other
+            let mut state = state_with(vec![step_of(PatternKind::Viewing)]);
+            state.labels = false;
+            let (buf, stride, _) = render(&mut state, w, h, 0);
+            let m = 2 * (short / 200).max(2);
+            // Along its top row, clear of the diagonal through the corner
+            let white = |x: usize| px(&buf, stride, x, m) == (255, 255, 255);
+            assert!(
+                (m + w / 10..m + w / 5).all(white) && !white(m + w / 5),
+                "{}x{}: top left box",
+                w,
+                h
+            );
+            assert!(m + w / 5 < w - w / 5 - m, "{}x{}: corner boxes touch", w, h);
+
+            // Gradient labels only where all five fit
+            let mut state = state_with(vec![step_of(PatternKind::Gradient)]);
+            let (buf, stride, _) = render(&mut state, w, h, 0);
+            let plain = naive_gradient(w, h, false, false);
+            let labelled =
+                (0..h).any(|y| (0..w).any(|x| px(&buf, stride, x, y) != plain[y * w + x]));
+            let scale = (h / 540).max(1);
+            let fits = 5 * (text_width("255 100.0%", scale) + 4 * scale) <= w;
+            assert_eq!(labelled, fits, "{}x{}: gradient labels", w, h);
+            assert_eq!(labelled, w >= 641, "{}x{}: gradient labels", w, h);
+        }
+    }
+
+    #[test]
+    fn checker_colors_come_from_the_step_and_cycle() {
+        // The built-in script has a colored checker after the plain one
+        let script = AppState::create_script();
+        let checkers: Vec<_> = script
+            .iter()
+            .filter(|s| s.pat == PatternKind::Checker)
+            .map(|s| s.checker_colors)
+            .collect();
+        assert!(checkers.contains(&None) && checkers.contains(&Some(CHECKER_PAIRS[1].1)));
+
+        let own = [(10, 20, 30), (200, 100, 0)];
+        let mut state = state_with(vec![
+            Step {
+                checker_cell: 8,
+                checker_colors: Some(own),
+                ..step_of(PatternKind::Checker)
+            },
+            Step {
+                checker_cell: 8,
+                ..step_of(PatternKind::Checker)
+            },
+        ]);
+        let (buf, stride, _) = render(&mut state, 32, 32, 0);
+        assert_eq!(px(&buf, stride, 0, 0), own[0]);
+        assert_eq!(px(&buf, stride, 8, 0), own[1]);
+
+        // C runs through the built-in pairs, then the step's own, then round
+        let mut seen = vec![state.checker_colors];
+        for _ in 0..CHECKER_PAIRS.len() + 1 {
+            state.checker_colors = next_checker_colors(state.checker_colors, Some(own));
+            seen.push(state.checker_colors);
+        }
+        let mut want: Vec<_> = CHECKER_PAIRS.iter().map(|&(_, p)| p).collect();
+        want.insert(0, own);
+        want.push(own);
+        assert_eq!(seen, want);
+        // Without a pair of its own, the cycle is just the built-in ones
+        assert_eq!(
+            next_checker_colors(CHECKER_PAIRS[3].1, None),
+            CHECKER_PAIRS[0].1
+        );
+
+        // The next step starts from its own colors, black and white here
+        state.next_step();
+        let (buf, stride, _) = render(&mut state, 32, 32, 0);
+        assert_eq!(px(&buf, stride, 0, 0), (255, 255, 255));
+        assert_eq!(px(&buf, stride, 8, 0), (0, 0, 0));
+    }
+
+    // Red, green and blue of the top left pixel after drawing `state`
+    fn corner(state: &mut AppState) -> (u8, u8, u8) {
+        let (buf, stride, _) = render(state, 4, 4, 0);
+        px(&buf, stride, 0, 0)
+    }
+
+    #[test]
+    fn limited_output_range_applies_unless_the_step_is_full_range() {
+        // White, mid gray and black
+        let mut state = state_with(solids(6).split_off(3));
+        state.output_range = OutputRange::Limited;
+        for want in [235, 126, 16] {
+            assert_eq!(corner(&mut state), (want, want, want));
+            state.next_step();
+        }
+
+        let mut state = state_with(vec![Step {
+            solid_idx: 3,
+            full_range: true,
+            ..Default::default()
+        }]);
+        state.output_range = OutputRange::Limited;
+        assert_eq!(corner(&mut state), (255, 255, 255));
+        assert!(range_label(&state).is_some_and(|l| l.contains("this step is full range")));
+    }
+
+    #[test]
+    fn rgb_gradient_bands_ramp_only_their_channel() {
+        for (w, h) in [(300, 90), (7, 2), (2, 7), (641, 481)] {
+            for vertical in [false, true] {
+                for light in [false, true] {
+                    let (mut buf, stride) = canvas(w, h);
+                    draw_gradient(&mut buf, stride, w, h, GradMode::Rgb, vertical, light);
+                    let ramp = naive_gradient(w, h, vertical, light);
+                    for y in 0..h {
+                        for x in 0..w {
+                            // Bands run across the ramp
+                            let band = if vertical {
+                                bar_at(x, w, 3)
+                            } else {
+                                bar_at(y, h, 3)
+                            };
+                            let (v, _, _) = ramp[y * w + x];
+                            let mut want = [0; 3];
+                            want[band] = v;
+                            assert_eq!(
+                                px(&buf, stride, x, y),
+                                (want[0], want[1], want[2]),
+                                "{}x{} vertical {} light {} at {},{}",
+                                w,
+                                h,
+                                vertical,
+                                light,
+                                x,
+                                y
+                            );
+                        }
+                    }
+                    assert!(padding_untouched(&buf, stride, w, h));
+                }
+            }
+        }
+    }
+
+    const HUES: [(u8, u8, u8); 6] = [
+        (255, 0, 0),
+        (255, 255, 0),
+        (0, 255, 0),
+        (0, 255, 255),
+        (0, 0, 255),
+        (255, 0, 255),
+    ];
+
+    #[test]
+    fn hue_sweep_puts_primaries_at_sixths() {
+        for w in [360, 720, 1920] {
+            let h = 5;
+            let (mut buf, stride) = canvas(w, h);
+            draw_hue_sweep(&mut buf, stride, w, h, HueSweep::default());
+            for (i, &want) in HUES.iter().enumerate() {
+                for y in 0..h {
+                    assert_eq!(
+                        px(&buf, stride, i * w / 6, y),
+                        want,
+                        "{} wide, sixth {}",
+                        w,
+                        i
+                    );
+                }
+            }
+            // It runs to just short of red again
+            let (r, g, b) = px(&buf, stride, w - 1, 0);
+            assert!(
+                r == 255 && g == 0 && b > 0 && b < 10,
+                "last column {:?}",
+                (r, g, b)
+            );
+            assert!(padding_untouched(&buf, stride, w, h));
+        }
+
+        // Saturation and value scale it down toward gray and black
+        let (w, h) = (360, 1);
+        let (mut buf, stride) = canvas(w, h);
+        let dim = HueSweep {
+            saturation: 0.0,
+            value: 0.5,
+            radial: false,
+        };
+        draw_hue_sweep(&mut buf, stride, w, h, dim);
+        assert!((0..w).all(|x| px(&buf, stride, x, 0) == (128, 128, 128)));
+    }
+
+    #[test]
+    fn radial_hue_sweep_is_the_angle() {
+        let (w, h) = (101, 101);
+        let (mut buf, stride) = canvas(w, h);
+        let hue = HueSweep {
+            radial: true,
+            ..Default::default()
+        };
+        draw_hue_sweep(&mut buf, stride, w, h, hue);
+        // Counterclockwise from red on the right, as on a color wheel
+        let at_angle = |deg: f64| {
+            let rad = deg.to_radians();
+            let x = 50.0 + 40.0 * rad.cos();
+            let y = 50.0 - 40.0 * rad.sin();
+            px(&buf, stride, x.round() as usize, y.round() as usize)
+        };
+        for (i, &want) in HUES.iter().enumerate() {
+            // Half a degree past each, clear of the rounding at the boundary
+            let (r, g, b) = at_angle(60.0 * i as f64 + 0.5);
+            let close = |a: u8, b: u8| a.abs_diff(b) <= 8;
+            assert!(
+                close(r, want.0) && close(g, want.1) && close(b, want.2),
+                "{} degrees: {:?}, not {:?}",
+                60 * i,
+                (r, g, b),
+                want
+            );
+        }
+        assert!(padding_untouched(&buf, stride, w, h));
+    }
+
+    #[test]
+    fn channel_mask_composes_with_invert_and_range() {
+        // Red, green, blue, white, gray and black solids
+        let mut state = state_with(solids(6));
+        let each = |state: &mut AppState| -> Vec<(u8, u8, u8)> {
+            (0..6)
+                .map(|i| {
+                    state.goto_step(i);
+                    corner(state)
+                })
+                .collect()
+        };
+
+        state.channel_mask = ChannelMask::Red;
+        assert_eq!(
+            each(&mut state),
+            [
+                (255, 0, 0),
+                (0, 0, 0),
+                (0, 0, 0),
+                (255, 0, 0),
+                (128, 0, 0),
+                (0, 0, 0)
+            ]
+        );
+        state.channel_mask = ChannelMask::Blue;
+        assert_eq!(
+            each(&mut state),
+            [
+                (0, 0, 0),
+                (0, 0, 0),
+                (0, 0, 255),
+                (0, 0, 255),
+                (0, 0, 128),
+                (0, 0, 0)
+            ]
+        );
+
+        // Inverting comes first, so what the mask drops stays dark
+        state.invert = true;
+        state.channel_mask = ChannelMask::Green;
+        assert_eq!(
+            each(&mut state),
+            [
+                (0, 255, 0),
+                (0, 0, 0),
+                (0, 255, 0),
+                (0, 0, 0),
+                (0, 127, 0),
+                (0, 255, 0)
+            ]
+        );
+
+        // Limiting the range comes after both, so dark is 16 rather than 0
+        state.output_range = OutputRange::Limited;
+        assert_eq!(
+            each(&mut state),
+            [
+                (16, 235, 16),
+                (16, 16, 16),
+                (16, 235, 16),
+                (16, 16, 16),
+                (16, 125, 16),
+                (16, 235, 16)
+            ]
+        );
+
+        // All masks nothing
+        state.channel_mask = ChannelMask::All;
+        state.invert = false;
+        assert_eq!(each(&mut state)[0], (235, 16, 16));
+    }
+
+    #[test]
+    fn red_blue_swap_comes_after_the_whole_pipeline() {
+        // What the buffer holds once the frame is written out
+        let written = |state: &mut AppState| {
+            let (mut buf, stride, _) = render(state, 4, 4, 0);
+            swap_rb(&mut buf, stride, 4, 4);
+            px(&buf, stride, 0, 0)
+        };
+
+        // Red lands in the blue bytes, which a BGR panel shows as red
+        let mut state = state_with(solids(3));
+        assert_eq!(written(&mut state), (0, 0, 255));
+        state.next_step();
+        assert_eq!(written(&mut state), (0, 255, 0));
+        state.next_step();
+        assert_eq!(written(&mut state), (255, 0, 0));
+
+        // Red, inverted to cyan, masked down to blue and limited: the swap
+        // moves the finished value, range included
+        state.goto_step(0);
+        state.invert = true;
+        state.channel_mask = ChannelMask::Blue;
+        state.output_range = OutputRange::Limited;
+        assert_eq!(written(&mut state), (235, 16, 16));
+
+        // Each letter's color moves wholesale on the channel order pattern
+        let count = |buf: &[u8], stride: usize, want: (u8, u8, u8)| {
+            (0..120)
+                .flat_map(|y| (0..160).map(move |x| (x, y)))
+                .filter(|&(x, y)| px(buf, stride, x, y) == want)
+                .count()
+        };
+        let mut state = state_with(vec![step_of(PatternKind::ChannelOrder)]);
+        let (mut buf, stride, _) = render(&mut state, 160, 120, 0);
+        let (red, green, blue) = (
+            count(&buf, stride, (255, 0, 0)),
+            count(&buf, stride, (0, 255, 0)),
+            count(&buf, stride, (0, 0, 255)),
+        );
+        assert!(red > 0 && green > 0 && blue > 0);
+        swap_rb(&mut buf, stride, 160, 120);
+        assert_eq!(count(&buf, stride, (0, 0, 255)), red);
+        assert_eq!(count(&buf, stride, (0, 255, 0)), green);
+        assert_eq!(count(&buf, stride, (255, 0, 0)), blue);
+        assert!(padding_untouched(&buf, stride, 160, 120));
+    }
+
+    #[test]
+    fn double_range_conversion_applies_after_the_base_range() {
+        // White, mid gray and black, through every base and second conversion
+        let cases = [
+            (OutputRange::Full, DoubleRange::Off, [255, 128, 0]),
+            (OutputRange::Full, DoubleRange::Compress, [235, 126, 16]),
+            (OutputRange::Full, DoubleRange::Expand, [255, 130, 0]),
+            (OutputRange::Limited, DoubleRange::Off, [235, 126, 16]),
+            (OutputRange::Limited, DoubleRange::Compress, [218, 124, 30]),
+            (OutputRange::Limited, DoubleRange::Expand, [255, 128, 0]),
+        ];
+        for (range, double, want) in cases {
+            let mut state = state_with(solids(6).split_off(3));
+            state.output_range = range;
+            state.double_range = double;
+            for (i, v) in want.into_iter().enumerate() {
+                state.goto_step(i);
+                assert_eq!(
+                    corner(&mut state),
+                    (v, v, v),
+                    "{:?} {:?} step {}",
+                    range,
+                    double,
+                    i
+                );
+            }
+            let label = range_label(&state);
+            assert_eq!(
+                label.is_some(),
+                range == OutputRange::Limited || double != DoubleRange::Off
+            );
+            if double != DoubleRange::Off {
+                assert!(label.unwrap().contains("DOUBLE CONVERSION"));
+            }
+        }
+    }
+
+    #[test]
+    fn ramp_scroll_shifts_right_and_tiles() {
+        for w in [2, 9, 640, 641] {
+            let h = 3;
+            let row = |offset: usize| -> Vec<u8> {
+                let (mut buf, stride) = canvas(w, h);
+                draw_ramp_scroll(&mut buf, stride, w, h, offset, false);
+                assert!(padding_untouched(&buf, stride, w, h));
+                assert!((0..w).all(|x| px(&buf, stride, x, h - 1) == px(&buf, stride, x, 0)));
+                (0..w).map(|x| px(&buf, stride, x, 0).0).collect()
+            };
+            let still = row(0);
+            assert_eq!((still[0], still[w / 2]), (0, 255), "{} wide", w);
+
+            // Rising then falling, with no jump where the end meets the start
+            let steps: Vec<u8> = (0..w)
+                .map(|x| still[x].abs_diff(still[(x + 1) % w]))
+                .collect();
+            let most = steps.iter().copied().max().unwrap();
+            assert!(
+                most as usize <= 255 / (w / 2).max(1) + 1,
+                "{} wide: a step of {}",
+                w,
+                most
+            );
+
+            for offset in [1, 7, w - 1, w, w + 3, 5 * w + 2] {
+                let moved = row(offset);
+                for x in 0..w {
+                    assert_eq!(
+                        moved[(x + offset) % w],
+                        still[x],
+                        "{} wide, offset {}, x {}",
+                        w,
+                        offset,
+                        x
+                    );
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn near_black_patches_sit_either_side_of_the_base() {
+        assert_eq!(near_black_levels(1), [0, 0, 2, 3]);
+        assert_eq!(near_black_levels(2), [0, 1, 3, 4]);
+        assert_eq!(near_black_levels(NEAR_BLACK_MAX), [6, 7, 9, 10]);
+
+        for (w, h) in [(1920, 1080), (640, 480), (90, 30)] {
+            for base in 1..=NEAR_BLACK_MAX {
+                let (mut buf, stride) = canvas(w, h);
+                draw_near_black(&mut buf, stride, w, h, base, 1);
+                assert!(padding_untouched(&buf, stride, w, h));
+                // The fill, away from the text and patches
+                let gray = |v: u8| (v, v, v);
+                assert_eq!(px(&buf, stride, w - 1, h / 2), gray(base));
+                assert_eq!(px(&buf, stride, w / 2, 0), gray(base));
+
+                // Patch centers, evenly spaced across the middle row
+                let side = (w / 9).min(h / 3);
+                let gap = (w - 4 * side) / 5;
+                for (i, v) in near_black_levels(base).into_iter().enumerate() {
+                    let x = gap + i * (side + gap) + side / 2;
+                    assert_eq!(
+                        px(&buf, stride, x, h / 2),
+                        gray(v),
+                        "{}x{} base {} patch {}",
+                        w,
+                        h,
+                        base,
+                        i
+                    );
+                }
+            }
+        }
+
+        // A step's base is clamped to 1-8, and 0 means the default of 2
+        for (asked, got) in [(0, 2), (1, 1), (5, 5), (8, 8), (200, 8)] {
+            let state = state_with(vec![Step {
+                near_black: asked,
+                ..step_of(PatternKind::NearBlack)
+            }]);
+            assert_eq!(state.near_black, got, "asked for {}", asked);
+        }
+    }
+
+    #[test]
+    fn bit_depth_bands_hold_two_to_the_bits_levels() {
+        use std::collections::BTreeSet;
+
+        assert_eq!(bit_depth_level(0, 100, 1), 0);
+        assert_eq!(bit_depth_level(99, 100, 1), 255);
+        assert_eq!(bit_depth_level(255, 256, 8), 255);
+
+        for w in [256, 640, 1920, 300] {
+            let h = 80;
+            let channels = [
+                RadialChannels::Luma,
+                RadialChannels::Red,
+                RadialChannels::Green,
+                RadialChannels::Blue,
+            ];
+            for channels in channels {
+                let (mut buf, stride) = canvas(w, h);
+                draw_bit_depth(&mut buf, stride, w, h, channels, 1, false);
+                assert!(padding_untouched(&buf, stride, w, h));
+                for bits in 1..=8u32 {
+                    let y = (bits as usize - 1) * h / 8 + h / 16;
+                    let values: BTreeSet<_> = (0..w)
+                        .map(|x| {
+                            let (r, g, b) = px(&buf, stride, x, y);
+                            let v = channels.apply(255);
+                            // Only the chosen channel lights up
+                            assert_eq!((r & !v.0, g & !v.1, b & !v.2), (0, 0, 0));
+                            r.max(g).max(b)
+                        })
+                        .collect();
+                    assert_eq!(
+                        values.len(),
+                        1 << bits,
+                        "{} wide, {}, {} bits",
+                        w,
+                        channels.name(),
+                        bits
+                    );
+                    assert_eq!(values.first(), Some(&0));
+                    assert_eq!(values.last(), Some(&255));
+                }
+            }
+        }
+    }
 }
@@ -0,0 +1,2100 @@
+//! The main loop on the first card. `MainLoop::open` sets up the surface,
+//! the inputs and everything else the run uses, and `run` goes round until
+//! the script ends or the run is stopped. Each pass waits for something to
+//! happen, takes in flip completions, remote commands and input, moves the
+//! script along, then draws and flips a frame if one is due. However the
+//! loop ends, `finish` writes up the results.
+//!
+//! Further --cards are driven from here too, but keep their own state (see
+//! instance.rs).
+
+use anyhow::{Context, Result};
+use drm::control::Mode;
+use evdev::{EventSummary, KeyCode};
+use nix::errno::Errno;
+use nix::poll::{PollFd, PollFlags, poll};
+use std::os::unix::io::AsFd;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant, SystemTime};
+
+use crate::attract::{self, Attract};
+use crate::audio::AvAudio;
+use crate::backend::Backend;
+use crate::backlight::Backlight;
+use crate::beep::Beeper;
+use crate::checksum::frame_checksum;
+use crate::cli::Args;
+use crate::config::{AttractConfig, Config};
+use crate::control::{self, Command, ControlChannel, Status};
+use crate::dbus;
+use crate::diag::LeakWatch;
+use crate::draw::{diff_frames, draw_line, fill_rect, mix_frame, swap_rb};
+use crate::events::{Event, EventLog};
+use crate::exit::Exit;
+use crate::flips::{Flips, InFlight, Logs};
+use crate::gpio::{ButtonAction, Buttons, Trigger};
+use crate::grab::{self, Grabbed};
+use crate::instance::Instance;
+use crate::keys::{self, Controls, KeyContext};
+use crate::leds::Leds;
+use crate::library::{self, NamedScript, ScriptLibrary, ScriptRecorder};
+use crate::link::LinkWatch;
+use crate::lut;
+use crate::notify::Notifier;
+use crate::patch::PatchServer;
+use crate::preview::Preview;
+use crate::profile::{Phase, Profiler};
+use crate::record::{RecordFormat, Recorder};
+use crate::report::{self, FlipSummary, Report, StepLog, Verdict};
+use crate::rotate::{Rotation, rotate_frame};
+use crate::roulette::Roulette;
+use crate::serial::{SerialCommand, SerialLink};
+use crate::session::Session;
+use crate::shm::ShmExport;
+use crate::signals;
+use crate::soak::{self, Soak};
+use crate::surface::{Surface, SurfaceBuilder};
+use crate::sync::{SyncWatch, draw_sync_strip};
+use crate::timing::{FpsMeter, FrameBudget, FrameCap, TimingCsv};
+use crate::ws;
+use crate::{
+    AppState, ChannelMask, Fade, KEYBOARD_RESCAN, NAV_HOLD, PatternKind, av_flash_on,
+    draw_flip_log, draw_hints, draw_menu, draw_overlay, draw_patch, draw_pattern, draw_progress,
+    draw_readout, draw_script_menu, hint_alpha, logical_area, nav_hold_left, next_step_seen,
+    open_keyboard, param_space, poll_timeout, range_label, surface_builder, write_checklist,
+};
+
+pub struct MainLoop {
+    args: Args,
+    surface: Surface,
+    state: AppState,
+    library: ScriptLibrary,
+    controls: Controls,
+    flips: Flips,
+    need_redraw: bool,
+
+    // None while the keyboard is unplugged, with the time of the next rescan
+    kb: Option<Grabbed>,
+    kb_rescan: Option<Instant>,
+    // Held for the run; dropping them lets go
+    _pointers: Vec<Grabbed>,
+    leds: Option<Leds>,
+    buttons: Option<Buttons>,
+    serial: Option<SerialLink>,
+    // Set when a batch of keys changes the step: the next batch waits until
+    // a frame of the new step is presented, or until then at the latest
+    nav_hold: Option<Instant>,
+    patch_server: Option<PatchServer>,
+    // Remote front ends share one control channel
+    control: Option<ControlChannel>,
+    preview: Option<Preview>,
+    backlight: Option<Backlight>,
+
+    stage: Vec<u8>,
+    // The smaller stage for --render-scale, sized on first use
+    low: Vec<u8>,
+    // What patterns are drawn into with --rotate, the viewer's way up
+    upright: Vec<u8>,
+    // The patterns alone as last drawn, and for which step, to fade from
+    last_pattern: Vec<u8>,
+    last_step: Option<(usize, PatternKind)>,
+    // Each half of a split drawn over the whole area, for difference mode
+    diff: [Vec<u8>; 2],
+    // --compare-mode: the two modes X switches between
+    compare_modes: Option<[Mode; 2]>,
+    frame_cap: FrameCap,
+    frame_budget: FrameBudget,
+    // --inject-drops: frames due so far, and the end of the refresh a drop
+    // holds the previous frame up for
+    frames_due: u64,
+    drop_hold: Option<Instant>,
+
+    // Extra --cards, and with --sync-outputs the pass they flip in
+    instances: Vec<Instance>,
+    sync_watch: Option<SyncWatch>,
+    sync_pass: u64,
+
+    soak: Option<Soak>,
+    roulette: Option<Roulette>,
+    attract: Option<Attract>,
+    // Whether the script starts over after its last step rather than ending the run
+    looping: bool,
+    // --max-runtime, and whether the run was stopped by it
+    deadline: Option<Instant>,
+    timed_out: bool,
+
+    started: SystemTime,
+    step_log: StepLog,
+    script_recorder: Option<ScriptRecorder>,
+    recorder: Option<Recorder>,
+    shm: Option<ShmExport>,
+    timing_csv: Option<TimingCsv>,
+    event_log: Option<EventLog>,
+    logged_step: Option<(usize, PatternKind)>,
+    fps_meter: FpsMeter,
+    notifier: Notifier,
+    profiler: Profiler,
+    leak_watch: Option<LeakWatch>,
+    link_watch: Option<LinkWatch>,
+    errors: Vec<String>,
+}
+
+// What poll found waiting
+struct Ready {
+    drm: bool,
+    kb: bool,
+    buttons: bool,
+    patch: bool,
+    control: bool,
+    serial: bool,
+    // One per instance, in order
+    instances: Vec<bool>,
+}
+
+impl MainLoop {
+    /// Sets the mode on the first card and opens everything the run uses
+    /// around it
+    pub fn open(args: Args, config: Config, builder: SurfaceBuilder) -> Result<Self> {
+        let mut surface = builder.build()?;
+        eprintln!("Bit depth: {}", surface.link_depth());
+        // --verify compares the stage with the front buffer, so it can't have a
+        // newer frame drawn while one is still on its way
+        surface.queue_frames = args.verify.is_none();
+        if args.damage {
+            surface.track_damage();
+        }
+
+        // Claimed before looking for a keyboard, which buttons make optional
+        let buttons = args
+            .gpio_buttons
+            .as_ref()
+            .map(|(chip, actions)| Buttons::open(chip, actions))
+            .transpose()
+            .context("GPIO buttons unavailable")?;
+
+        let (kb_path, kb, kb_rescan) = match open_keyboard() {
+            Ok((path, kb)) => (Some(path), Some(Grabbed::new(kb, args.grab)), None),
+            Err(e) if buttons.is_some() => {
+                eprintln!("No keyboard ({:#}); using the GPIO buttons", e);
+                (None, None, Some(Instant::now() + KEYBOARD_RESCAN))
+            }
+            Err(e) => return Err(e.context(Exit::InputUnavailable)),
+        };
+
+        let pointers = if args.grab {
+            grab::grab_pointers(kb_path.as_deref())
+        } else {
+            Vec::new()
+        };
+
+        let leds = match &kb_path {
+            Some(path) if args.leds => open_leds(path),
+            _ => None,
+        };
+
+        let backlight = open_backlight(&args, &config);
+        let backlight_level = args
+            .backlight_level
+            .or(config.backlight.level)
+            .or(backlight.as_ref().map(Backlight::percent));
+
+        if let Some(path) = &args.lut {
+            surface.set_lut(Some(lut::load_lut(path)?));
+            eprintln!("Loaded LUT: {}", path.display());
+        }
+
+        let stage = vec![0u8; surface.disp_h * surface.stride()];
+        if args.rotate != Rotation::None {
+            let (w, h) = args.rotate.upright_size(surface.disp_w, surface.disp_h);
+            eprintln!(
+                "Rotating {} degrees: patterns are drawn at {}x{}",
+                args.rotate.degrees(),
+                w,
+                h
+            );
+        }
+        if args.render_scale > 1 {
+            eprintln!(
+                "Render scale 1/{}: patterns are drawn at {}x{} and shown in {}x{} blocks",
+                args.render_scale,
+                surface.disp_w.div_ceil(args.render_scale),
+                surface.disp_h.div_ceil(args.render_scale),
+                args.render_scale,
+                args.render_scale
+            );
+        }
+
+        let compare_modes = args
+            .compare_mode
+            .as_ref()
+            .map(|sel| surface.find_mode(sel, args.allow_interlaced))
+            .transpose()?
+            .map(|b| [surface.mode, b]);
+
+        let beeper = if args.beep {
+            match Beeper::open() {
+                Ok(beeper) => Some(beeper),
+                Err(e) => {
+                    eprintln!("AV sync tone disabled: {:#}", e);
+                    None
+                }
+            }
+        } else {
+            None
+        };
+        // Without gpiochip access the trigger is simply off
+        let trigger = match (&args.gpio_chip, args.gpio_line) {
+            (Some(chip), Some(line)) => match Trigger::open(chip, line) {
+                Ok(trigger) => Some(trigger),
+                Err(e) => {
+                    eprintln!("GPIO trigger disabled: {:#}", e);
+                    None
+                }
+            },
+            _ => None,
+        };
+
+        let serial = args
+            .serial
+            .as_ref()
+            .map(|(dev, baud)| SerialLink::open(dev, *baud, config.serial))
+            .transpose()?;
+
+        let av_audio = args
+            .av_audio
+            .as_deref()
+            .map(|device| AvAudio::open(device, args.av_offset))
+            .transpose()?;
+        let mut flips = Flips::new(trigger, beeper, av_audio, args.late_tolerance);
+
+        let mut state = AppState::new()?;
+        let (library, active_script, roulette) = load_scripts(&args, &config.attract, &mut state)?;
+        let controls = Controls::new(&args, active_script, backlight_level);
+
+        if let Some(path) = &args.load_session {
+            state.restore(Session::load(path)?)?;
+            eprintln!("Loaded session: {}", path.display());
+        }
+        // After the session, so the command line wins
+        state.set_overrides(args.overrides);
+        if args.invert {
+            state.invert = true;
+        }
+        state.allow_flashing = args.allow_flashing;
+        state.blink = args.blink_inverse.map(|hz| (hz, Instant::now()));
+        if let Some(range) = args.output_range.or(config.output.range) {
+            state.output_range = range;
+        }
+        state.swap_rb = config.output.swap_rb;
+        state.quarter_turn = args.rotate.is_quarter_turn();
+        state.ui_scale = args.ui_scale.or(config.ui.scale);
+
+        // Extra --cards start from the same script as the first
+        let mut instances = Vec::new();
+        for (i, path) in args.extra_cards.iter().enumerate() {
+            instances.push(
+                Instance::open(
+                    i + 1,
+                    surface_builder(&args),
+                    path.clone(),
+                    state.script.clone(),
+                    state.script_origin.clone(),
+                    args.overrides,
+                )?
+                .synced(args.sync_outputs)
+                .ui_scale(state.ui_scale),
+            );
+        }
+        let sync_watch = args
+            .sync_outputs
+            .then(|| SyncWatch::new(instances.len(), surface.refresh_hz()));
+
+        surface.write_to_back(&stage, surface.stride())?;
+        surface.flip()?;
+        flips.submit(InFlight::default());
+
+        let frame_cap = FrameCap::new(args.fps_cap);
+        let frame_budget = FrameBudget::new(surface.refresh_hz());
+
+        let record = match (&args.record, &args.record_raw) {
+            (Some(path), _) => Some((path, RecordFormat::Y4m(args.record_range))),
+            (None, Some(path)) => Some((path, RecordFormat::Raw)),
+            (None, None) => None,
+        };
+        let recorder = record
+            .map(|(path, format)| {
+                Recorder::start(
+                    path,
+                    format,
+                    surface.disp_w,
+                    surface.disp_h,
+                    surface.refresh_hz(),
+                    args.record_frames,
+                )
+            })
+            .transpose()?;
+        let shm = args
+            .shm_export
+            .as_deref()
+            .map(ShmExport::create)
+            .transpose()?;
+
+        let timing_csv = args
+            .timing_csv
+            .as_deref()
+            .map(TimingCsv::create)
+            .transpose()?;
+
+        let event_log = args
+            .event_log
+            .as_deref()
+            .map(|path| EventLog::open(path, args.event_log_flips))
+            .transpose()?;
+
+        let patch_server = args.patch_server.map(PatchServer::start).transpose()?;
+        let remote = (args.dbus.is_some() || args.preview_listen.is_some())
+            .then(control::channel_pair)
+            .transpose()?;
+        let (control, preview) = match remote {
+            Some((tx, channel, changes)) => {
+                if let Some(bus) = args.dbus {
+                    dbus::start(bus, tx.clone(), changes)?;
+                }
+                let preview = args
+                    .preview_listen
+                    .map(|port| Preview::start(port, tx))
+                    .transpose()?;
+                (Some(channel), preview)
+            }
+            None => (None, None),
+        };
+
+        let script_recorder = args.record_script.as_deref().map(ScriptRecorder::new);
+
+        signals::install()?;
+        signals::install_nav()?;
+        let profiler = Profiler::new(args.profile, args.profile_trace.as_deref())?;
+        let leak_watch = args
+            .leak_watch
+            .map(|interval| LeakWatch::new(interval, args.leak_warn_kb.unwrap_or(10 * 1024)));
+
+        // Read at the start, then every couple of seconds and on a status request
+        let link_watch = match surface.link_props() {
+            Ok(props) => {
+                eprintln!("Output: {}", surface.link_summary(&props));
+                Some(LinkWatch::new(props, Instant::now()))
+            }
+            Err(e) => {
+                eprintln!("Link properties unavailable: {:#}", e);
+                None
+            }
+        };
+
+        Ok(Self {
+            surface,
+            state,
+            library,
+            controls,
+            flips,
+            need_redraw: true,
+            kb,
+            kb_rescan,
+            _pointers: pointers,
+            leds,
+            buttons,
+            serial,
+            nav_hold: None,
+            patch_server,
+            control,
+            preview,
+            backlight,
+            stage,
+            low: Vec::new(),
+            upright: Vec::new(),
+            last_pattern: Vec::new(),
+            last_step: None,
+            diff: [Vec::new(), Vec::new()],
+            compare_modes,
+            frame_cap,
+            frame_budget,
+            frames_due: 0,
+            drop_hold: None,
+            instances,
+            sync_watch,
+            sync_pass: 0,
+            soak: args.soak.map(Soak::new),
+            roulette,
+            attract: args.attract.then(|| Attract::new(args.auto)),
+            looping: args.soak.is_some() || args.attract,
+            deadline: args.max_runtime.map(|max| Instant::now() + max),
+            timed_out: false,
+            started: SystemTime::now(),
+            step_log: StepLog::default(),
+            script_recorder,
+            recorder,
+            shm,
+            timing_csv,
+            event_log,
+            logged_step: None,
+            fps_meter: FpsMeter::default(),
+            notifier: Notifier::from_env(),
+            profiler,
+            leak_watch,
+            link_watch,
+            errors: Vec::new(),
+            args,
+        })
+    }
+
+    /// Goes round until the run is over, then writes up the results
+    pub fn run(mut self) -> Result<()> {
+        // An error still reaches the report. With --soak it goes back in, to
+        // be recorded and carried on from.
+        let mut outcome = self.passes(None);
+        while self.soak.is_some()
+            && let Err(e) = &outcome
+            && e.downcast_ref::<Exit>() != Some(&Exit::DeviceLost)
+        {
+            outcome = self.passes(outcome.err());
+        }
+        self.finish(outcome)
+    }
+
+    fn passes(&mut self, resumed: Option<anyhow::Error>) -> Result<()> {
+        if let (Some(e), Some(soak)) = (resumed, &mut self.soak) {
+            soak.resume(e)?;
+            self.flips.clear();
+            self.need_redraw = true;
+        }
+        while !self.pass()? {}
+        Ok(())
+    }
+
+    // Once round the loop. True when the run is over.
+    fn pass(&mut self) -> Result<bool> {
+        self.profiler.tick();
+        self.observe();
+
+        // The hints fade by the clock, so they are redrawn while up, and
+        // once more when they are gone to clear them away
+        if let Some(at) = self.controls.hints_shown {
+            if hint_alpha(at.elapsed()).is_none() {
+                self.controls.hints_shown = None;
+            }
+            self.need_redraw = true;
+        }
+
+        // Once for the pass, so the wait and the frame agree
+        let animating = animating(
+            &self.controls,
+            &self.state,
+            self.soak.is_some(),
+            self.args.attract,
+        );
+        let ready = self.wait(animating)?;
+
+        if signals::shutdown_requested() {
+            return Ok(true);
+        }
+        self.notifier.tick(Instant::now());
+        if self.deadline.is_some_and(|d| Instant::now() >= d) {
+            self.timed_out = true;
+            return Ok(true);
+        }
+        if self
+            .soak
+            .as_ref()
+            .is_some_and(|s| Instant::now() >= s.end())
+        {
+            return Ok(true);
+        }
+
+        // DRM first: a flip completion must never wait behind input handling
+        let mut flipped = ready.drm && self.surface.handle_drm_events()?;
+        // What the frame that just reached the screen was drawn for
+        let mut presented = InFlight::default();
+        if flipped {
+            presented = self.flips.complete();
+        }
+        self.instance_events(&presented, ready.instances);
+
+        if ready.patch
+            && let Some(server) = &mut self.patch_server
+            && server.drain()
+        {
+            self.need_redraw = true;
+        }
+        if ready.control && self.remote_commands() {
+            return Ok(true);
+        }
+        if ready.serial && self.serial_commands() {
+            return Ok(true);
+        }
+        self.profiler.lap(Phase::Events);
+
+        // Any adjustment shows a readout, so a new one means a parameter changed
+        let readout_before = self.state.readout_deadline();
+
+        if ready.kb && self.keys()? {
+            return Ok(true);
+        }
+        if self.nav_signals() {
+            return Ok(true);
+        }
+        if ready.buttons && self.button_actions() {
+            return Ok(true);
+        }
+        if self.kb_rescan.is_some_and(|at| Instant::now() >= at) {
+            self.rescan_keyboard();
+        }
+        self.profiler.lap(Phase::Input);
+
+        if self
+            .state
+            .auto_deadline(self.args.auto)
+            .is_some_and(|deadline| Instant::now() >= deadline)
+        {
+            if self.state.next_step() {
+                if !self.looping {
+                    return Ok(true);
+                }
+                self.start_over();
+            }
+            self.need_redraw = true;
+        }
+
+        if let Some(leds) = &mut self.leds {
+            leds.update(self.state.paused, self.args.auto.is_some());
+        }
+        self.update_backlight();
+
+        if let Some(log) = &mut self.event_log
+            && self.state.readout_deadline() != readout_before
+            && let Some((text, _)) = &self.state.readout
+        {
+            log.log(Event::ParameterChanged {
+                description: text.clone(),
+            });
+        }
+
+        if self.state.expire_readout(Instant::now()) {
+            self.need_redraw = true;
+        }
+
+        // A burst of key events can take long enough that the pending flip
+        // completes meanwhile; pick it up now rather than a loop later so the
+        // next frame isn't held back. At most one completion per pass, so
+        // each is matched with its own frame.
+        if !flipped && (self.surface.poll_flip()? || self.surface.expire_flip(Instant::now())?) {
+            flipped = true;
+            presented = self.flips.complete();
+        }
+
+        if let Some(soak) = &mut self.soak
+            && soak.check_flips(&mut self.surface, flipped)?
+        {
+            self.flips.clear();
+            self.need_redraw = true;
+        }
+
+        self.check_link();
+
+        if flipped {
+            self.arrived(&presented)?;
+        }
+
+        if let Some(link) = &mut self.serial {
+            let now = Instant::now();
+            if let Some(step) = presented.step {
+                link.presented(step, now, self.frame_budget.interval);
+            }
+            link.tick(now);
+        }
+
+        if !self.surface.is_flipping()
+            && let Some(mode) = self.controls.pending_mode.take()
+        {
+            self.switch_mode(mode);
+        }
+
+        let now = Instant::now();
+        for instance in &mut self.instances {
+            instance.update(now, self.args.auto);
+        }
+
+        if self.frame_due(now, animating) {
+            if self.patch_server.is_some() {
+                self.draw_patch_frame(now)?;
+            } else {
+                self.draw_frame(now, animating)?;
+            }
+            self.need_redraw = false;
+        }
+        Ok(false)
+    }
+
+    // Passes the step on to whatever follows it, as it stands at the top of
+    // a pass
+    fn observe(&mut self) {
+        if let Some(watch) = &mut self.leak_watch {
+            watch.check(Instant::now(), self.event_log.as_mut());
+        }
+        if let Some(soak) = &mut self.soak {
+            soak.tick(Instant::now(), self.event_log.as_mut());
+            // Nobody is around to release pause_here steps
+            self.state.held = false;
+        }
+        let state = &self.state;
+        self.step_log.observe(
+            state.script_idx,
+            state.original_index(),
+            state.step_name(),
+            state.pattern,
+            Instant::now(),
+        );
+        if let Some(recorder) = &mut self.script_recorder
+            && let Err(e) =
+                recorder.observe(state.script_idx, state.effective_step(), Instant::now())
+        {
+            eprintln!("Script recording stopped: {:#}", e);
+            self.errors.push(format!("{:#}", e));
+            self.script_recorder = None;
+        }
+        if let Some(control) = &mut self.control {
+            control.observe(state.script_idx, state.pattern);
+        }
+        if let Some(preview) = &mut self.preview {
+            self.fps_meter.tick(Instant::now());
+            preview.publish(ws::state_message(
+                state,
+                &self.library.scripts[self.controls.active_script].name,
+                self.fps_meter.fps,
+            ));
+        }
+        if let Some(roulette) = &mut self.roulette {
+            roulette.shown(state.script_idx, state.current_step());
+        }
+        if let Some(log) = &mut self.event_log
+            && self.logged_step != Some((state.script_idx, state.pattern))
+        {
+            self.logged_step = Some((state.script_idx, state.pattern));
+            log.log(Event::StepChanged {
+                index: state.script_idx,
+                name: state.step_name().map(str::to_string),
+                pattern: state.pattern,
+                params: serde_json::to_value(state.current_step()).unwrap_or_default(),
+            });
+        }
+        self.notifier.status(&format!(
+            "step {}/{}: {:?}",
+            state.script_idx + 1,
+            state.script.len(),
+            state.pattern
+        ));
+    }
+
+    // Sleeps until something can actually change: input, a flip completing,
+    // or the next timer. Animation is paced by flip events, so a static
+    // pattern with nothing armed blocks indefinitely.
+    fn wait(&mut self, animating: bool) -> Result<Ready> {
+        let wants_frame = (self.need_redraw || animating) && self.surface.can_present();
+        self.drop_hold = self.drop_hold.filter(|&until| Instant::now() < until);
+        self.nav_hold = nav_hold_left(
+            self.nav_hold,
+            Instant::now(),
+            self.flips.shown_step,
+            self.state.script_idx,
+        );
+        let (args, state) = (&self.args, &self.state);
+        let timeout = poll_timeout(
+            [
+                wants_frame.then(|| {
+                    let at = self.frame_cap.ready_at().unwrap_or_else(Instant::now);
+                    self.drop_hold.map_or(at, |until| at.max(until))
+                }),
+                state.auto_deadline(args.auto),
+                state.readout_deadline(),
+                self.notifier.watchdog_deadline(),
+                self.deadline,
+                self.serial.as_ref().and_then(|s| s.deadline()),
+                self.kb_rescan,
+                self.leds
+                    .as_ref()
+                    .and_then(|l| l.next_change(state.paused, args.auto.is_some())),
+                self.instances
+                    .iter()
+                    .filter_map(|i| i.deadline(args.auto))
+                    .min(),
+                self.profiler.deadline(),
+                self.surface.flip_deadline(),
+                self.leak_watch.as_ref().map(LeakWatch::deadline),
+                self.link_watch.as_ref().map(LinkWatch::deadline),
+                self.soak.as_ref().map(Soak::deadline),
+                state
+                    .backlight_sweep(Instant::now())
+                    .and_then(|(_, _, next)| next)
+                    .filter(|_| self.backlight.is_some()),
+                self.nav_hold,
+            ]
+            .into_iter()
+            .flatten()
+            .min(),
+        );
+
+        let nav_free = self.nav_hold.is_none();
+        let mut fds = vec![PollFd::new(self.surface.card.as_fd(), PollFlags::POLLIN)];
+        let kb_fd = fds.len();
+        if let Some(kb) = self.kb.as_ref().filter(|_| nav_free) {
+            fds.push(PollFd::new(kb.as_fd(), PollFlags::POLLIN));
+        }
+        let buttons_fd = fds.len();
+        if let Some(buttons) = self.buttons.as_ref().filter(|_| nav_free) {
+            fds.push(PollFd::new(buttons.fd(), PollFlags::POLLIN));
+        }
+        let patch_fd = fds.len();
+        if let Some(server) = &self.patch_server {
+            fds.push(PollFd::new(server.wake_fd(), PollFlags::POLLIN));
+        }
+        let control_fd = fds.len();
+        if let Some(control) = &self.control {
+            fds.push(PollFd::new(control.wake_fd(), PollFlags::POLLIN));
+        }
+        let serial_fd = fds.len();
+        if let Some(serial) = &self.serial {
+            fds.push(PollFd::new(serial.fd(), PollFlags::POLLIN));
+        }
+        // One entry per instance still running, in order
+        let instances_fd = fds.len();
+        fds.extend(
+            self.instances
+                .iter()
+                .filter_map(|i| i.fd())
+                .map(|fd| PollFd::new(fd, PollFlags::POLLIN)),
+        );
+
+        self.profiler.lap(Phase::Other);
+        match poll(&mut fds, timeout) {
+            // A signal interrupted the wait; checked by the caller
+            Ok(_) | Err(Errno::EINTR) => {}
+            Err(e) => return Err(e.into()),
+        }
+        self.profiler.lap(Phase::Poll);
+
+        // A hangup has to be read too, or poll would keep returning it
+        let ready_or_gone = |fd: Option<&PollFd>| {
+            fd.is_some_and(|fd| {
+                fd.revents().is_some_and(|r| {
+                    r.intersects(PollFlags::POLLIN | PollFlags::POLLHUP | PollFlags::POLLERR)
+                })
+            })
+        };
+        let readable = |fd: Option<&PollFd>| {
+            fd.is_some_and(|fd| {
+                fd.revents()
+                    .unwrap_or(PollFlags::empty())
+                    .contains(PollFlags::POLLIN)
+            })
+        };
+        let mut instance_fds = fds[instances_fd..].iter();
+        Ok(Ready {
+            drm: readable(fds.first()),
+            kb: self.kb.is_some() && nav_free && ready_or_gone(fds.get(kb_fd)),
+            buttons: self.buttons.is_some() && nav_free && ready_or_gone(fds.get(buttons_fd)),
+            patch: self.patch_server.is_some() && readable(fds.get(patch_fd)),
+            control: self.control.is_some() && readable(fds.get(control_fd)),
+            serial: self.serial.is_some() && ready_or_gone(fds.get(serial_fd)),
+            instances: self
+                .instances
+                .iter()
+                .map(|i| i.fd().is_some() && ready_or_gone(instance_fds.next()))
+                .collect(),
+        })
+    }
+
+    // The extra cards' DRM events, and with --sync-outputs the passes now
+    // complete on every output
+    fn instance_events(&mut self, presented: &InFlight, ready: Vec<bool>) {
+        for (instance, ready) in self.instances.iter_mut().zip(ready) {
+            if ready {
+                instance.handle_drm_events();
+            }
+        }
+        let Some(watch) = &mut self.sync_watch else {
+            return;
+        };
+        let mut done: Vec<(u64, Vec<Option<Duration>>)> = Vec::new();
+        if let (Some(pass), Some(sample)) = (presented.sync_pass, &self.surface.last_flip) {
+            done.extend(
+                watch
+                    .completed(0, pass, sample.timestamp)
+                    .map(|v| (pass, v)),
+            );
+        }
+        for (i, instance) in self.instances.iter_mut().enumerate() {
+            if let Some((pass, vblank)) = instance.take_presented() {
+                done.extend(watch.completed(i + 1, pass, vblank).map(|v| (pass, v)));
+            }
+        }
+        if let Some(log) = &mut self.event_log {
+            for (pass, vblanks) in done {
+                log.outputs_presented(pass, &vblanks);
+            }
+        }
+    }
+
+    // Commands from D-Bus and WebSocket clients. True when one ends the run.
+    fn remote_commands(&mut self) -> bool {
+        let Some(control) = &mut self.control else {
+            return false;
+        };
+        for cmd in control.drain() {
+            let cmd = match cmd {
+                Command::Instance(i, cmd) if !matches!(*cmd, Command::Quit) => {
+                    match i.checked_sub(1).and_then(|i| self.instances.get_mut(i)) {
+                        Some(instance) => instance.command(*cmd),
+                        None => eprintln!("Remote: no instance {}", i),
+                    }
+                    continue;
+                }
+                // Quit ends the run whichever instance it names
+                Command::Instance(_, cmd) => *cmd,
+                cmd => cmd,
+            };
+            let script = &self.library.scripts[self.controls.active_script].name;
+            if remote_command(
+                cmd,
+                &mut self.state,
+                &self.surface,
+                self.link_watch.as_mut(),
+                script,
+                self.flips.shown_step,
+                self.looping,
+            ) {
+                return true;
+            }
+        }
+        self.need_redraw = true;
+        false
+    }
+
+    // Commands over --serial. True when one ends the run.
+    fn serial_commands(&mut self) -> bool {
+        let Some(link) = &mut self.serial else {
+            return false;
+        };
+        match link.read_commands() {
+            Ok(cmds) => {
+                // Like keys, ignored while a patch client is in charge
+                for cmd in cmds.into_iter().filter(|_| self.patch_server.is_none()) {
+                    match cmd {
+                        SerialCommand::Next => {
+                            if next_step_seen(&mut self.state, self.flips.shown_step, self.looping)
+                            {
+                                return true;
+                            }
+                        }
+                        SerialCommand::Previous => self.state.previous_step(),
+                        SerialCommand::Quit => return true,
+                    }
+                    self.need_redraw = true;
+                }
+            }
+            Err(e) => {
+                eprintln!("Serial link disabled: {:#}", e);
+                self.serial = None;
+            }
+        }
+        false
+    }
+
+    // Key presses waiting on the keyboard. True when one ends the run.
+    fn keys(&mut self) -> Result<bool> {
+        let Some(kb) = &mut self.kb else {
+            return Ok(false);
+        };
+        // An unplugged keyboard reports an error instead of events
+        let fetched = kb.fetch_events().map(|events| events.collect::<Vec<_>>());
+        let events = match fetched {
+            Ok(events) => events,
+            Err(e) if e.kind() == std::io::ErrorKind::WouldBlock => return Ok(false),
+            Err(e) => {
+                self.keyboard_lost(e);
+                return Ok(false);
+            }
+        };
+
+        let step_before = self.state.script_idx;
+        let ctx = KeyContext {
+            args: &self.args,
+            library: &self.library,
+            disp_w: self.surface.disp_w,
+            disp_h: self.surface.disp_h,
+            subpixel: self.surface.subpixel,
+            compare_modes: self.compare_modes,
+            backlight: self.backlight.is_some(),
+            shown_step: self.flips.shown_step,
+            looping: self.looping,
+        };
+        for event in events {
+            // Held for resizing the probe
+            if let EventSummary::Key(_, KeyCode::KEY_LEFTSHIFT | KeyCode::KEY_RIGHTSHIFT, v) =
+                event.destructure()
+            {
+                self.controls.shift = v != 0;
+                // A modifier on its own is not a command
+                continue;
+            }
+            if let EventSummary::Key(_, code, 1) = event.destructure() {
+                if let Some(log) = &mut self.event_log {
+                    log.log(Event::InputReceived {
+                        key: format!("{:?}", code),
+                    });
+                }
+
+                // Any key ends the attract loop
+                if self.args.attract {
+                    return Ok(true);
+                }
+
+                // The patch client is in charge; only quitting is allowed
+                if self.patch_server.is_some() {
+                    if matches!(code, KeyCode::KEY_Q | KeyCode::KEY_ESC) {
+                        return Ok(true);
+                    }
+                    continue;
+                }
+
+                if keys::handle_key(code, &mut self.state, &mut self.controls, &ctx)? {
+                    return Ok(true);
+                }
+
+                self.need_redraw = true;
+            }
+        }
+        if self.state.script_idx != step_before {
+            self.nav_hold = Some(Instant::now() + NAV_HOLD);
+        }
+        Ok(false)
+    }
+
+    // Steps asked for by signal. Like keys, ignored while a patch client is
+    // in charge; stepping restarts the step's --auto time as any other way
+    // of moving does. True when the run ends.
+    fn nav_signals(&mut self) -> bool {
+        let (next, previous) = signals::take_nav();
+        if (next == 0 && previous == 0) || self.patch_server.is_some() {
+            return false;
+        }
+        let step_before = self.state.script_idx;
+        for _ in 0..next {
+            if next_step_seen(&mut self.state, self.flips.shown_step, self.looping) {
+                return true;
+            }
+        }
+        for _ in 0..previous {
+            self.state.previous_step();
+        }
+        if self.state.script_idx != step_before {
+            self.nav_hold = Some(Instant::now() + NAV_HOLD);
+        }
+        self.need_redraw = true;
+        false
+    }
+
+    // Presses of the --gpio-buttons. True when one ends the run.
+    fn button_actions(&mut self) -> bool {
+        let Some(buttons) = &mut self.buttons else {
+            return false;
+        };
+        match buttons.read_actions() {
+            // Like keys, ignored while a patch client is in charge
+            Ok(actions) if self.patch_server.is_none() => {
+                let step_before = self.state.script_idx;
+                for action in actions {
+                    match action {
+                        ButtonAction::Next => {
+                            if next_step_seen(&mut self.state, self.flips.shown_step, self.looping)
+                            {
+                                return true;
+                            }
+                        }
+                        ButtonAction::Previous => self.state.previous_step(),
+                        ButtonAction::Quit => return true,
+                    }
+                    self.need_redraw = true;
+                }
+                if self.state.script_idx != step_before {
+                    self.nav_hold = Some(Instant::now() + NAV_HOLD);
+                }
+            }
+            Ok(actions) => return actions.contains(&ButtonAction::Quit),
+            Err(e) => {
+                eprintln!("GPIO buttons disabled: {:#}", e);
+                self.buttons = None;
+            }
+        }
+        false
+    }
+
+    fn keyboard_lost(&mut self, e: std::io::Error) {
+        let msg = format!(
+            "Keyboard disconnected ({}); waiting for one to be plugged in",
+            e
+        );
+        eprintln!("{}", msg);
+        if let Some(log) = &mut self.event_log {
+            log.log(Event::Error { message: msg });
+        }
+        self.kb = None;
+        self.leds = None;
+        self.kb_rescan = Some(Instant::now() + KEYBOARD_RESCAN);
+        if let Some(soak) = &mut self.soak {
+            soak.keyboard_lost(Instant::now());
+        }
+    }
+
+    fn rescan_keyboard(&mut self) {
+        match open_keyboard() {
+            Ok((path, dev)) => {
+                self.kb = Some(Grabbed::new(dev, self.args.grab));
+                self.kb_rescan = None;
+                if let Some(soak) = &mut self.soak {
+                    soak.keyboard_back(Instant::now());
+                }
+                if self.args.leds {
+                    self.leds = open_leds(&path);
+                }
+            }
+            Err(_) => self.kb_rescan = Some(Instant::now() + KEYBOARD_RESCAN),
+        }
+    }
+
+    // --auto past the last step of a looping run: a soak counts the cycle,
+    // and a random soak draws a fresh lot of steps rather than repeating
+    fn start_over(&mut self) {
+        if let Some(soak) = &mut self.soak {
+            soak.cycle_done();
+        }
+        if let Some(roulette) = &mut self.roulette {
+            let steps = roulette.batch();
+            self.library.scripts[self.controls.active_script].steps = steps.clone();
+            self.state.load_script(steps);
+            roulette.new_batch();
+        }
+        self.state.goto_step(0);
+    }
+
+    // Keeps the backlight at the sweep step's level, or else at the level
+    // set for the run
+    fn update_backlight(&mut self) {
+        if let Some(light) = &mut self.backlight {
+            let sweep = self.state.backlight_sweep(Instant::now());
+            let want = sweep
+                .map(|(_, pct, _)| pct)
+                .or(self.controls.backlight_level);
+            match want.map(|pct| light.set_percent(pct)) {
+                Some(Ok(true)) => {
+                    if let Some(log) = &mut self.event_log {
+                        log.log(Event::BacklightChanged {
+                            percent: light.percent(),
+                            raw: light.raw(),
+                            max: light.max(),
+                        });
+                    }
+                    self.need_redraw = true;
+                }
+                Some(Err(e)) => {
+                    eprintln!("Warning: backlight control disabled: {:#}", e);
+                    self.errors.push(format!("{:#}", e));
+                    self.backlight = None;
+                }
+                _ => {}
+            }
+        }
+        self.state.backlight_pct = self.backlight.as_ref().map(Backlight::percent);
+        if let Some(other) = &mut self.controls.split {
+            other.backlight_pct = self.state.backlight_pct;
+        }
+    }
+
+    fn check_link(&mut self) {
+        let Some(watch) = &mut self.link_watch else {
+            return;
+        };
+        if !(watch.due(Instant::now())
+            && watch.update(
+                self.surface.link_props(),
+                Instant::now(),
+                self.event_log.as_mut(),
+            ))
+        {
+            return;
+        }
+        // The recovery the kernel asks for: set the mode again, so the link
+        // trains anew, at a lower rate if it has to
+        let outcome = match self.surface.remodeset() {
+            Ok(()) => {
+                self.flips.clear();
+                self.need_redraw = true;
+                "link-status went Bad, mode set again".to_string()
+            }
+            Err(e) => {
+                let msg = format!(
+                    "link-status went Bad, setting the mode again failed: {:#}",
+                    e
+                );
+                self.errors.push(msg.clone());
+                msg
+            }
+        };
+        watch.recovered(outcome);
+    }
+
+    // The frame `presented` has just reached the screen
+    fn arrived(&mut self, presented: &InFlight) -> Result<()> {
+        // Only once a frame is actually on screen, so units ordered after
+        // this one never race the modeset
+        self.notifier.ready();
+        self.fps_meter.presented(Instant::now());
+
+        let logs = Logs {
+            event_log: self.event_log.as_mut(),
+            timing_csv: self.timing_csv.as_mut(),
+            step_log: &mut self.step_log,
+            soak: self.soak.as_mut(),
+            errors: &mut self.errors,
+        };
+        if self.flips.presented(
+            presented,
+            &mut self.surface,
+            &mut self.state,
+            self.args.fps_cap,
+            &self.stage,
+            logs,
+        )? {
+            self.need_redraw = true;
+        }
+
+        if let Some(server) = &mut self.patch_server {
+            server.presented(self.surface.flips_completed());
+        }
+        Ok(())
+    }
+
+    // --compare-mode's switch, once no flip is in the way
+    fn switch_mode(&mut self, mode: Mode) {
+        let size = (self.surface.disp_w, self.surface.disp_h);
+        match self.surface.set_mode(mode) {
+            Ok(()) => {
+                self.controls.compare_b = !self.controls.compare_b;
+                eprintln!("Mode: {}", self.surface.mode_name());
+                self.stage = vec![0u8; self.surface.disp_h * self.surface.stride()];
+                self.frame_budget = FrameBudget::new(self.surface.refresh_hz());
+                // A recording can't change size midway
+                if (self.surface.disp_w, self.surface.disp_h) != size
+                    && let Some(recorder) = self.recorder.take()
+                {
+                    eprintln!("Recording stopped at the mode switch");
+                    if let Err(e) = recorder.finish() {
+                        eprintln!("{:#}", e);
+                        self.errors.push(format!("{:#}", e));
+                    }
+                }
+                self.need_redraw = true;
+            }
+            Err(e) => eprintln!("Mode switch failed: {:#}", e),
+        }
+    }
+
+    // Whether to draw and flip a frame now
+    fn frame_due(&mut self, now: Instant, animating: bool) -> bool {
+        let due = (self.need_redraw || animating)
+            && self.surface.can_present()
+            // Synced outputs flip together, so all of them wait for the slowest
+            && (self.sync_watch.is_none() || self.instances.iter().all(Instance::can_present))
+            && self.frame_cap.ready(now)
+            && self.drop_hold.is_none_or(|until| now >= until);
+
+        // Skipping the frame outright leaves the last one up for another
+        // refresh; timed animation catches up on the next one
+        if due && let Some(n) = self.args.inject_drops {
+            self.frames_due += 1;
+            if self.frames_due.is_multiple_of(n) {
+                self.drop_hold = Some(now + self.frame_budget.interval);
+                match &mut self.event_log {
+                    Some(log) => log.log(Event::FrameDropInjected {
+                        frame: self.frames_due,
+                    }),
+                    None => eprintln!("Injected drop: frame {}", self.frames_due),
+                }
+                return false;
+            }
+        }
+        due
+    }
+
+    // Nothing but the patch: overlays and the color matrix would spoil the
+    // measurement
+    fn draw_patch_frame(&mut self, now: Instant) -> Result<()> {
+        let Some(server) = &mut self.patch_server else {
+            return Ok(());
+        };
+        let (w, h, stride) = (
+            self.surface.disp_w,
+            self.surface.disp_h,
+            self.surface.stride(),
+        );
+        self.profiler.lap(Phase::Other);
+        draw_patch(&mut self.stage, stride, w, h, server.current());
+        let checksum = self
+            .args
+            .frame_checksum
+            .map(|rows| (frame_checksum(&self.stage, stride, w, h, rows), None));
+        if self.state.swap_rb {
+            swap_rb(&mut self.stage, stride, w, h);
+        }
+        self.profiler.lap(Phase::Draw);
+        self.surface.write_to_back(&self.stage, stride)?;
+        self.profiler.lap(Phase::Write);
+        self.surface.flip()?;
+        self.profiler.lap(Phase::Flip);
+        self.flips.submit(InFlight {
+            checksum,
+            ..Default::default()
+        });
+        self.frame_cap.presented(now);
+        server.drawn();
+
+        self.share_frame(now)
+    }
+
+    // The step's pattern with everything that goes on top of it, drawn and
+    // flipped
+    fn draw_frame(&mut self, now: Instant, animating: bool) -> Result<()> {
+        self.profiler.lap(Phase::Other);
+        let cpu_start = Instant::now();
+        let mut overlay = Vec::new();
+        let mut checksum = None;
+        let (args, surface) = (&self.args, &self.surface);
+
+        // With --render-scale, everything is drawn into a stage 1/scale
+        // the size and expanded on the way into the back buffer.
+        // Split screen always draws at full size.
+        let scale = if self.controls.split.is_none() && !self.state.pattern.needs_native_pixels() {
+            args.render_scale
+        } else {
+            1
+        };
+        let (w, h) = if scale > 1 {
+            (
+                surface.disp_w.div_ceil(scale),
+                surface.disp_h.div_ceil(scale),
+            )
+        } else if args.rotate != Rotation::None {
+            args.rotate.upright_size(surface.disp_w, surface.disp_h)
+        } else {
+            (surface.disp_w, surface.disp_h)
+        };
+        // Worked out before the buffer below is borrowed
+        let status = self.status_lines(scale, w, h);
+        let (stride, buf) = if scale > 1 {
+            self.low.resize(w * 4 * h, 0);
+            (w * 4, &mut self.low[..])
+        } else if args.rotate != Rotation::None {
+            self.upright.resize(w * 4 * h, 0);
+            (w * 4, &mut self.upright[..])
+        } else {
+            (surface.stride(), &mut self.stage[..])
+        };
+        let state = &mut self.state;
+        let controls = &mut self.controls;
+
+        let (lx, ly, lw, lh) =
+            logical_area(args, controls.matte, state.output_range, buf, stride, w, h);
+        let content = &mut buf[ly * stride + lx * 4..];
+        draw_patterns(
+            state,
+            controls,
+            surface,
+            content,
+            stride,
+            lw,
+            lh,
+            &mut self.diff,
+            now,
+            &mut overlay,
+        );
+
+        if let Some(attract) = &self.attract {
+            attract.finish(content, stride, lw, lh, state, now);
+        }
+
+        // Fades blend the patterns alone, before anything goes on top
+        let fading = controls.split.is_none()
+            && (args.fade.is_some()
+                || state
+                    .script
+                    .iter()
+                    .any(|step| step.fade.is_some_and(|n| n > 0)));
+        if fading {
+            if let Some((idx, before)) = self.last_step
+                && idx != state.script_idx
+            {
+                let frames = state.current_step().fade.or(args.fade).unwrap_or(0);
+                // Never into or out of anything read by an instrument
+                state.fade = (frames > 0
+                    && !before.measured()
+                    && !state.pattern.measured()
+                    && self.last_pattern.len() == buf.len())
+                .then(|| Fade {
+                    from: self.last_pattern.clone(),
+                    frames,
+                    drawn: 0,
+                });
+            }
+            self.last_step = Some((state.script_idx, state.pattern));
+            self.last_pattern.clear();
+            self.last_pattern.extend_from_slice(buf);
+            blend_fade(&mut state.fade, buf, stride, w, h);
+        }
+
+        // Before anything else goes on top of the patterns
+        if let Some(rows) = args.frame_checksum {
+            let sum = frame_checksum(buf, stride, w, h, rows);
+            let allowed = match &controls.split {
+                None => state.identical_frames_allowed(surface.refresh_hz()),
+                Some(_) => None,
+            };
+            checksum = Some((sum, allowed));
+        }
+        if let Some(probe) = &mut controls.probe {
+            overlay.extend(probe.sample(buf, stride, surface.disp_w, surface.disp_h, scale));
+        }
+        let mut plot = controls
+            .histogram
+            .as_mut()
+            .filter(|_| !state.pattern.measured());
+        if let Some(plot) = &mut plot {
+            plot.update(buf, stride, w, h, now, !animating);
+        }
+        if self.sync_watch.is_some() {
+            draw_sync_strip(buf, stride, w, h, 0, self.sync_pass);
+        }
+        let ui = state.ui_scale(h);
+
+        if state.labels && state.progress {
+            overlay.push(state.progress_label());
+            if !state.pattern.measured() {
+                let idx = state.script_idx;
+                draw_progress(buf, stride, w, h, idx, state.script.len(), ui);
+            }
+        }
+        if state.labels
+            && !state.pattern.measured()
+            && let Some(alpha) = controls
+                .hints_shown
+                .and_then(|at| hint_alpha(now.saturating_duration_since(at)))
+        {
+            draw_hints(buf, stride, w, h, alpha, ui);
+        }
+        if state.labels
+            && let Some((text, _)) = &state.readout
+        {
+            draw_readout(buf, stride, w, h, text, ui);
+        }
+
+        if let Some(sel) = controls.menu {
+            let library = &self.library;
+            draw_script_menu(buf, stride, w, h, library, sel, controls.active_script, ui);
+        } else if let Some(sel) = state.menu {
+            let entries: Vec<String> = state
+                .menu_items()
+                .into_iter()
+                .map(|(_, text)| text)
+                .collect();
+            draw_menu(
+                buf,
+                stride,
+                w,
+                h,
+                "Menu",
+                &entries,
+                sel.min(entries.len() - 1),
+                Some(state.script_idx),
+                ui,
+            );
+        }
+
+        if state.held_for_operator(args.auto) {
+            overlay.insert(0, "PAUSED - press Enter to continue".to_string());
+        }
+        overlay.extend(status);
+        draw_overlay(buf, stride, w, h, &overlay, ui, controls.overlay_corner);
+        if controls.flip_log {
+            draw_flip_log(buf, stride, w, h, &surface.recent_flips, ui);
+        }
+        if let Some(plot) = &plot {
+            plot.draw(buf, stride, w, h, ui);
+        }
+        if controls.late_marker
+            && self.flips.late_shown.is_some_and(|until| now < until)
+            && !state.pattern.measured()
+        {
+            // Bottom left, clear of the progress bar
+            let size = 8 * ui;
+            let y = h.saturating_sub(size + 5 * ui);
+            let (x, y) = (4 * ui as isize, y as isize);
+            fill_rect(buf, stride, w, h, x, y, size, size, 255, 0, 0);
+        }
+        if let Some(probe) = &controls.probe {
+            probe.draw(buf, stride, w, h, scale, ui);
+        }
+        if state.swap_rb {
+            swap_rb(buf, stride, w, h);
+        }
+        self.profiler.lap(Phase::Draw);
+
+        if args.rotate != Rotation::None {
+            let dst_stride = self.surface.stride();
+            rotate_frame(
+                &self.upright,
+                stride,
+                w,
+                h,
+                &mut self.stage,
+                dst_stride,
+                args.rotate,
+            );
+            self.surface.write_to_back(&self.stage, dst_stride)?;
+        } else {
+            self.surface.write_to_back_scaled(buf, stride, scale)?;
+        }
+        self.profiler.lap(Phase::Write);
+
+        let took = cpu_start.elapsed();
+        if self.frame_budget.record(took, now) {
+            eprintln!(
+                "Warning: drawing {:?} took {:.1} ms, over the {:.1} ms frame interval ({} frames so far)",
+                self.state.pattern,
+                took.as_secs_f64() * 1000.0,
+                self.frame_budget.interval.as_secs_f64() * 1000.0,
+                self.frame_budget.over
+            );
+        }
+
+        // The same count draw_pattern went by
+        let av_flash = (self.state.pattern == PatternKind::AvSync)
+            .then(|| av_flash_on(self.surface.frames_presented(), self.surface.refresh_hz()));
+        self.surface.flip()?;
+        self.profiler.lap(Phase::Flip);
+        self.frame_cap.presented(now);
+
+        self.share_frame(now)?;
+
+        let state = &self.state;
+        let verify = self.flips.pick_verify(self.args.verify, state.script_idx);
+        self.flips.submit(InFlight {
+            gpio_hold: Some(state.current_step().gpio_hold),
+            step: Some((state.script_idx, state.pattern)),
+            av_flash,
+            verify,
+            checksum,
+            sync_pass: self.sync_watch.is_some().then_some(self.sync_pass),
+            animated: animating,
+        });
+        if let Some(watch) = &mut self.sync_watch {
+            let mut flipped = vec![true];
+            for instance in &mut self.instances {
+                flipped.push(instance.follow(state.script_idx, self.sync_pass, now));
+            }
+            watch.submitted(self.sync_pass, &flipped);
+            self.sync_pass += 1;
+        }
+        Ok(())
+    }
+
+    // The overlay's lines about the run and the output, after whatever the
+    // pattern and the probe have to say. `w` and `h` are the size of the
+    // stage drawn into at `scale`.
+    fn status_lines(&self, scale: usize, w: usize, h: usize) -> Vec<String> {
+        let (args, state, surface) = (&self.args, &self.state, &self.surface);
+        let mut lines = Vec::new();
+        if self.library.scripts.len() > 1 {
+            lines.push(format!(
+                "script: {}",
+                self.library.scripts[self.controls.active_script].name
+            ));
+        }
+        if let Some(label) = state.results_label() {
+            lines.push(label);
+        }
+        if state.param_nav {
+            lines.push(match param_space(state.pattern) {
+                Some((name, _)) => format!("param nav: {}", name),
+                None => "param nav: nothing to adjust".to_string(),
+            });
+        }
+        if self.compare_modes.is_some() {
+            lines.push(format!(
+                "mode {}: {}",
+                if self.controls.compare_b { "B" } else { "A" },
+                surface.mode_name()
+            ));
+        }
+        if surface.interlaced() {
+            lines.push("interlaced: expect combing on motion and fine detail".to_string());
+        }
+        if let Some(ct) = &surface.content_type {
+            lines.push(format!("content type {}", ct.to_lowercase()));
+        }
+        // Where banding shows, so it can be put down to the link
+        if args.max_bpc.is_some()
+            || matches!(
+                state.pattern,
+                PatternKind::Gradient | PatternKind::HueSweep | PatternKind::RampScroll
+            )
+        {
+            lines.push(surface.link_depth());
+        }
+        if let Some(nv12) = &surface.nv12 {
+            lines.push(nv12.label());
+        }
+        if let Some(fps) = args.fps_cap {
+            lines.push(format!("fps cap {}", fps));
+        }
+        if let Some(watch) = &self.sync_watch {
+            lines.extend(watch.status());
+        }
+        if let Some(label) = range_label(state) {
+            lines.push(label);
+        }
+        if state.channel_mask != ChannelMask::All {
+            lines.push(format!("showing {}", state.channel_mask.name()));
+        }
+        if self.flips.late_frames.count > 0 {
+            lines.push(format!("late frames: {}", self.flips.late_frames.count));
+        }
+        if self.frame_budget.over > 0 {
+            lines.push(format!(
+                "over frame budget: {} frames",
+                self.frame_budget.over
+            ));
+        }
+        if args.render_scale > 1 {
+            lines.push(if scale > 1 {
+                format!(
+                    "render scale 1/{}: {}x{} stage, not for sharpness",
+                    scale, w, h
+                )
+            } else {
+                "render scale 1: this pattern needs native pixels".to_string()
+            });
+        }
+        if let Some(sum) = self
+            .flips
+            .last_checksum
+            .filter(|_| args.frame_checksum.is_some())
+        {
+            let freeze = &self.flips.freeze_watch;
+            lines.push(format!(
+                "checksum {:016x}, {} identical{}",
+                sum,
+                freeze.identical,
+                if freeze.frozen() {
+                    ": NOT CHANGING"
+                } else {
+                    ""
+                }
+            ));
+        }
+        if self.controls.always_redraw {
+            lines.push(format!(
+                "redraw: every frame, {} flips, {} missed vblanks",
+                surface.flip_stats.count, surface.flip_stats.missed_vblanks
+            ));
+        }
+        lines
+    }
+
+    // Hands the frame just flipped to whatever takes a copy of each one
+    fn share_frame(&mut self, now: Instant) -> Result<()> {
+        let surface = &self.surface;
+        let (stride, w, h) = (surface.stride(), surface.disp_w, surface.disp_h);
+        if let Some(recorder) = &mut self.recorder {
+            recorder.submit(&self.stage, stride);
+        }
+        if let Some(shm) = &mut self.shm {
+            shm.submit(&self.stage, stride, w, h)?;
+        }
+        if let Some(preview) = &mut self.preview {
+            preview.submit(&self.stage, stride, w, h, now);
+        }
+        Ok(())
+    }
+
+    // Closes out the run: the session, recordings and logs, the verdicts,
+    // and the --report. `outcome` is how the loop ended.
+    fn finish(mut self, outcome: Result<()>) -> Result<()> {
+        self.notifier.stopping();
+        // Errors from here on are logged as events at the end, with the verdicts
+        let mut errors = std::mem::take(&mut self.errors);
+        let errors_logged = errors.len();
+
+        if let Err(e) = &outcome {
+            errors.push(format!("{:#}", e));
+        }
+
+        // Keep going after a failure here so the report is still written
+        if let Some(path) = &self.args.save_session {
+            match self.state.session().save(path) {
+                Ok(()) => eprintln!("Saved session: {}", path.display()),
+                Err(e) => {
+                    eprintln!("{:#}", e);
+                    errors.push(format!("{:#}", e));
+                }
+            }
+        }
+
+        if let (Some(recorder), Some(path)) = (self.script_recorder, &self.args.record_script) {
+            match recorder.finish(Instant::now()) {
+                Ok(()) => eprintln!("Recorded script: {}", path.display()),
+                Err(e) => {
+                    eprintln!("{:#}", e);
+                    errors.push(format!("{:#}", e));
+                }
+            }
+        }
+
+        if let Err(e) = self.profiler.finish() {
+            eprintln!("{:#}", e);
+            errors.push(format!("{:#}", e));
+        }
+
+        if let Some(csv) = self.timing_csv
+            && let Err(e) = csv.finish()
+        {
+            eprintln!("{:#}", e);
+            errors.push(format!("{:#}", e));
+        }
+
+        if let Some(recorder) = self.recorder
+            && let Err(e) = recorder.finish()
+        {
+            eprintln!("{:#}", e);
+            errors.push(format!("{:#}", e));
+        }
+
+        let flips = &self.flips;
+        let mut verdicts = Vec::new();
+        let mut verify_failure = None;
+        if self.args.verify.is_some() {
+            if flips.verify_checked == 0 {
+                verify_failure = Some(Exit::VerdictsMissing);
+            } else if flips.verify_mismatches > 0 {
+                verify_failure = Some(Exit::VerifyMismatch);
+            }
+            eprintln!(
+                "Verify: {} frames checked, {} mismatches",
+                flips.verify_checked, flips.verify_mismatches
+            );
+            verdicts.push(Verdict {
+                name: "scanout verify".to_string(),
+                pass: flips.verify_mismatches == 0,
+                detail: format!(
+                    "{} frames checked, {} mismatches",
+                    flips.verify_checked, flips.verify_mismatches
+                ),
+            });
+        }
+
+        let state = &self.state;
+        if !state.results.is_empty() {
+            let failed = state.results.iter().filter(|(_, pass)| !pass).count();
+            eprintln!(
+                "Checklist: {} of {} steps marked, {} failed",
+                state.results.len(),
+                state.script.len(),
+                failed
+            );
+            for (label, pass) in &state.results {
+                eprintln!("  {}  {}", if *pass { "PASS" } else { "FAIL" }, label);
+                verdicts.push(Verdict {
+                    name: format!("step {}", label),
+                    pass: *pass,
+                    detail: "marked by the operator".to_string(),
+                });
+            }
+            if let Some(path) = &self.args.checklist
+                && let Err(e) = write_checklist(path, &state.results, state.script.len())
+            {
+                eprintln!("{:#}", e);
+                errors.push(format!("{:#}", e));
+            }
+        }
+
+        if let Some(roulette) = self.roulette {
+            roulette.finish();
+        }
+        let soak = self.soak.map(|s| s.report(Instant::now()));
+        verdicts.extend(soak.as_ref().map(soak::verdict));
+
+        if let Some(mut log) = self.event_log {
+            for v in &verdicts {
+                log.log(Event::VerdictRecorded {
+                    name: v.name.clone(),
+                    pass: v.pass,
+                    detail: v.detail.clone(),
+                });
+            }
+            for e in &errors[errors_logged..] {
+                log.log(Event::Error { message: e.clone() });
+            }
+            if let Err(e) = log.finish() {
+                eprintln!("{:#}", e);
+                errors.push(format!("{:#}", e));
+            }
+        }
+
+        if let Some(path) = &self.args.report {
+            let surface = &self.surface;
+            let report = Report {
+                version: report::REPORT_VERSION,
+                device: surface.device_path().display().to_string(),
+                connector: surface.connector_label().to_string(),
+                mode: surface.mode_name(),
+                format: self.args.format.to_string(),
+                started_unix_ms: report::unix_ms(self.started),
+                ended_unix_ms: report::unix_ms(SystemTime::now()),
+                steps: self.step_log.finish(Instant::now()),
+                verdicts,
+                flips: FlipSummary::from_stats(&surface.flip_stats),
+                errors,
+                instances: self
+                    .instances
+                    .into_iter()
+                    .map(|i| i.report(Instant::now()))
+                    .collect(),
+                shuffle_seed: self.args.shuffle,
+                sync: self.sync_watch.map_or_else(Vec::new, |w| w.summary()),
+                soak,
+                link_events: self.link_watch.map_or_else(Vec::new, LinkWatch::report),
+            };
+            report.save(path)?;
+            eprintln!("Wrote report: {}", path.display());
+        }
+
+        outcome?;
+        if self.timed_out {
+            return Err(Exit::Timeout.into());
+        }
+        match verify_failure {
+            Some(exit) => Err(exit.into()),
+            None => Ok(()),
+        }
+    }
+}
+
+// Whether frames keep coming without anything asking for a redraw. A soak
+// flips every refresh, to keep the whole pipeline busy, --attract for its
+// fades, and a fade until its last frame, even between static patterns.
+fn animating(controls: &Controls, state: &AppState, soak: bool, attract: bool) -> bool {
+    controls.always_redraw
+        || controls.flip_log
+        || soak
+        || attract
+        || state.animating()
+        || state.fade.is_some()
+        || controls.split.as_ref().is_some_and(|o| o.animating())
+}
+
+// Blends the next frame of `fade` over the incoming step in `buf`, ending the
+// fade with its last frame
+fn blend_fade(fade: &mut Option<Fade>, buf: &mut [u8], stride: usize, w: usize, h: usize) {
+    let Some(f) = fade else {
+        return;
+    };
+    f.drawn += 1;
+    let alpha = (f.drawn * 255 / f.frames) as u8;
+    mix_frame(buf, &f.from, stride, w, h, alpha);
+    if f.drawn >= f.frames {
+        *fade = None;
+    }
+}
+
+// The script library with the script the run starts on loaded into
+// `state`, as picked by --script, --only/--skip, --shuffle, --soak-random
+// and --attract. Also the index of that script, and for --soak-random what
+// draws its steps.
+fn load_scripts(
+    args: &Args,
+    attract: &AttractConfig,
+    state: &mut AppState,
+) -> Result<(ScriptLibrary, usize, Option<Roulette>)> {
+    let mut library = ScriptLibrary::builtin();
+    if let Some(dir) = &args.scripts_dir {
+        library.load_dir(dir)?;
+        eprintln!(
+            "Loaded {} scripts from {}",
+            library.scripts.len() - 1,
+            dir.display()
+        );
+    }
+    let mut active_script = 0;
+    if let Some(path) = &args.script {
+        let script = library::load_script(path)?;
+        eprintln!("Loaded script: {}", path.display());
+        state.load_script(script.steps.clone());
+        library.scripts.push(script);
+        active_script = library.scripts.len() - 1;
+    }
+    if !args.only.is_empty() || !args.skip.is_empty() {
+        let steps = &mut library.scripts[active_script].steps;
+        library::filter_steps(steps, &args.only, &args.skip)?;
+        state.load_script(steps.clone());
+    }
+    if let Some(seed) = args.shuffle {
+        let steps = &mut library.scripts[active_script].steps;
+        let origin = library::shuffle_steps(steps, seed, args.shuffle_by_category);
+        state.load_script(steps.clone());
+        state.script_origin = Some(origin);
+        eprintln!(
+            "Shuffled the script with seed {} (--shuffle={} repeats this order)",
+            seed, seed
+        );
+    }
+    let roulette = match args.soak_random {
+        Some(seed) => {
+            let path = args
+                .soak_log
+                .clone()
+                .unwrap_or_else(|| PathBuf::from(format!("screen_test-soak-{}.log", seed)));
+            let mut roulette = Roulette::open(seed, &path, args.allow_flashing)?;
+            let steps = roulette.batch();
+            state.load_script(steps.clone());
+            library.scripts.push(NamedScript {
+                name: "Random soak".to_string(),
+                steps,
+            });
+            active_script = library.scripts.len() - 1;
+            eprintln!(
+                "Random soak with seed {}, logging each step to {}",
+                seed,
+                path.display()
+            );
+            Some(roulette)
+        }
+        None => None,
+    };
+    if args.attract {
+        let steps = attract.steps.clone().unwrap_or_else(attract::default_steps);
+        state.load_script(steps.clone());
+        library.scripts.push(NamedScript {
+            name: "Attract".to_string(),
+            steps,
+        });
+        active_script = library.scripts.len() - 1;
+        state.labels = false;
+        state.progress = false;
+    }
+    Ok((library, active_script, roulette))
+}
+
+// --backlight, from the command line or the config file
+fn open_backlight(args: &Args, config: &Config) -> Option<Backlight> {
+    if !args.backlight && !config.backlight.enabled() {
+        return None;
+    }
+    let device = args
+        .backlight_device
+        .as_deref()
+        .or(config.backlight.device.as_deref());
+    match Backlight::open(device) {
+        Ok(b) => Some(b),
+        Err(e) => {
+            eprintln!("Warning: backlight control disabled: {:#}", e);
+            None
+        }
+    }
+}
+
+fn open_leds(path: &Path) -> Option<Leds> {
+    match Leds::open(path) {
+        Ok(leds) => Some(leds),
+        Err(e) => {
+            eprintln!("LED feedback disabled: {:#}", e);
+            None
+        }
+    }
+}
+
+// A command from a remote front end: D-Bus, or a WebSocket client. True
+// when it ends the run. Next goes as it does from the keys, by
+// `shown_step` and `looping`.
+fn remote_command(
+    cmd: Command,
+    state: &mut AppState,
+    surface: &Surface,
+    link_watch: Option<&mut LinkWatch>,
+    script: &str,
+    shown_step: Option<usize>,
+    looping: bool,
+) -> bool {
+    match cmd {
+        Command::NextStep => return next_step_seen(state, shown_step, looping),
+        Command::PreviousStep => state.previous_step(),
+        Command::GotoStep(idx) => {
+            if !state.goto_step(idx) {
+                eprintln!("Remote: no step {}", idx);
+            }
+        }
+        Command::GotoName(name) => match state.step_named(&name) {
+            Some(idx) => {
+                state.goto_step(idx);
+            }
+            None => eprintln!("Remote: no step named {}", name),
+        },
+        Command::SetSolid(r, g, b) => {
+            state.pattern = PatternKind::Solid;
+            state.custom_solid = Some((r, g, b));
+        }
+        Command::SetTarget(r, g, b) => state.set_solid_target((r, g, b)),
+        Command::Pause(paused) => {
+            if state.paused != paused {
+                state.toggle_pause();
+            }
+        }
+        Command::Quit => return true,
+        Command::Status(reply) => {
+            let link = link_watch.map(|watch| {
+                // Any change gets logged on the next pass
+                watch.poll_now();
+                match surface.link_props() {
+                    Ok(props) => surface.link_summary(&props),
+                    Err(_) => surface.link_summary(watch.current()),
+                }
+            });
+            let _ = reply.send(Status {
+                step: state.script_idx,
+                steps: state.script.len(),
+                pattern: state.pattern,
+                paused: state.paused,
+                script: script.to_string(),
+                link,
+            });
+        }
+        Command::Instance(..) => {}
+    }
+    false
+}
+
+// The pattern into the logical area, or with a split screen both patterns:
+// side by side, or with --diff-gain where they differ
+#[allow(clippy::too_many_arguments)]
+fn draw_patterns(
+    state: &mut AppState,
+    controls: &mut Controls,
+    surface: &Surface,
+    content: &mut [u8],
+    stride: usize,
+    lw: usize,
+    lh: usize,
+    diff: &mut [Vec<u8>; 2],
+    now: Instant,
+    overlay: &mut Vec<String>,
+) {
+    let half = lw / 2;
+    let focus_right = controls.focus_right;
+    let side = if focus_right { "right" } else { "left" };
+    match (&mut controls.split, controls.diff_gain) {
+        (None, _) => draw_pattern(state, surface, content, stride, lw, lh, now, overlay),
+        (Some(other), Some(gain)) => {
+            let (left, right) = if focus_right {
+                (other, state)
+            } else {
+                (state, other)
+            };
+            // Both over the whole area, then only where they differ
+            let [diff_a, diff_b] = diff;
+            diff_a.resize(lw * 4 * lh, 0);
+            diff_b.resize(lw * 4 * lh, 0);
+            draw_pattern(left, surface, diff_a, lw * 4, lw, lh, now, overlay);
+            draw_pattern(right, surface, diff_b, lw * 4, lw, lh, now, overlay);
+            let (differing, max) = diff_frames(content, stride, diff_a, diff_b, lw, lh, gain);
+            overlay.push(if differing == 0 {
+                "difference: identical".to_string()
+            } else {
+                format!(
+                    "difference x{}: {} pixels differ, by up to {}",
+                    gain, differing, max
+                )
+            });
+            overlay.push(format!("split: editing {} pattern", side));
+        }
+        (Some(other), None) => {
+            let (left, right) = if focus_right {
+                (other, state)
+            } else {
+                (state, other)
+            };
+            draw_pattern(left, surface, content, stride, half, lh, now, overlay);
+            // The right half is just the buffer starting half a row in,
+            // with the same stride
+            draw_pattern(
+                right,
+                surface,
+                &mut content[half * 4..],
+                stride,
+                lw - half,
+                lh,
+                now,
+                overlay,
+            );
+            draw_line(
+                content,
+                stride,
+                lw,
+                lh,
+                half as isize,
+                0,
+                half as isize,
+                lh as isize - 1,
+                2,
+                255,
+                0,
+                255,
+            );
+            overlay.push(format!("split: editing {} half", side));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn static_fade_keeps_drawing_until_done() {
+        let controls = Controls::new(&Args::default(), 0, None);
+        let mut state = AppState::new().unwrap();
+        state.pattern = PatternKind::Solid;
+        assert!(!animating(&controls, &state, false, false));
+
+        // Solid to solid: only the fade itself asks for frames
+        let (w, h) = (4, 2);
+        let mut buf = vec![0u8; w * 4 * h];
+        let frames = 6;
+        state.fade = Some(Fade {
+            from: vec![255; w * 4 * h],
+            frames,
+            drawn: 0,
+        });
+        let mut drawn = 0;
+        while animating(&controls, &state, false, false) {
+            drawn = state.fade.as_ref().unwrap().drawn + 1;
+            blend_fade(&mut state.fade, &mut buf, w * 4, w, h);
+            assert!(drawn <= frames);
+        }
+        assert_eq!(drawn, frames);
+        assert!(state.fade.is_none());
+    }
+}
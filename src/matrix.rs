@@ -0,0 +1,202 @@
+use serde::{Deserialize, Serialize};
+
+/// A 3x3 matrix applied to every output pixel as (r, g, b) = M * (r, g, b),
+/// in code values. The presets are quick approximations for experiments, not
+/// colorimetrically exact transforms.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ColorMatrix {
+    #[default]
+    Identity,
+    Desaturate,
+    SwapRb,
+    Protanopia,
+    Custom([[f64; 3]; 3]),
+}
+
+// Fixed-point fraction bits for the coefficients. 12 bits leaves plenty of
+// headroom in i32 for 3 * 255 * (a few units of gain).
+const FRAC_BITS: u32 = 12;
+
+impl ColorMatrix {
+    pub fn coefficients(self) -> [[f64; 3]; 3] {
+        match self {
+            ColorMatrix::Identity => [[1.0, 0.0, 0.0], [0.0, 1.0, 0.0], [0.0, 0.0, 1.0]],
+            // Rec.709 luma on every channel
+            ColorMatrix::Desaturate => [[0.2126, 0.7152, 0.0722]; 3],
+            ColorMatrix::SwapRb => [[0.0, 0.0, 1.0], [0.0, 1.0, 0.0], [1.0, 0.0, 0.0]],
+            // Machado et al. 2009, full severity
+            ColorMatrix::Protanopia => [
+                [0.152286, 1.052583, -0.204868],
+                [0.114503, 0.786281, 0.099216],
+                [-0.003882, -0.048116, 1.051998],
+            ],
+            ColorMatrix::Custom(m) => m,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            ColorMatrix::Identity => "identity",
+            ColorMatrix::Desaturate => "desaturate",
+            ColorMatrix::SwapRb => "swap R/B",
+            ColorMatrix::Protanopia => "protanopia",
+            ColorMatrix::Custom(_) => "custom",
+        }
+    }
+
+    // Cycles through the presets; a custom matrix goes back to identity
+    pub fn next(self) -> Self {
+        match self {
+            ColorMatrix::Identity => ColorMatrix::Desaturate,
+            ColorMatrix::Desaturate => ColorMatrix::SwapRb,
+            ColorMatrix::SwapRb => ColorMatrix::Protanopia,
+            ColorMatrix::Protanopia | ColorMatrix::Custom(_) => ColorMatrix::Identity,
+        }
+    }
+
+    fn fixed(self) -> [[i32; 3]; 3] {
+        self.coefficients()
+            .map(|row| row.map(|c| (c * (1 << FRAC_BITS) as f64).round() as i32))
+    }
+
+    /// Transforms the w x h BGRX region in place. Identity is skipped without
+    /// touching the buffer; otherwise rows are split across threads.
+    pub fn apply(self, buf: &mut [u8], stride: usize, w: usize, h: usize) {
+        let m = self.fixed();
+        if m == ColorMatrix::Identity.fixed() || w == 0 || h == 0 {
+            return;
+        }
+
+        let threads = std::thread::available_parallelism()
+            .map_or(1, |n| n.get())
+            .min(h);
+        let rows_per = h.div_ceil(threads);
+        let len = ((h - 1) * stride + w * 4).min(buf.len());
+
+        std::thread::scope(|s| {
+            for chunk in buf[..len].chunks_mut(rows_per * stride) {
+                s.spawn(move || {
+                    for row in chunk.chunks_mut(stride) {
+                        transform_row(&m, &mut row[..w * 4]);
+                    }
+                });
+            }
+        });
+    }
+}
+
+fn transform_row(m: &[[i32; 3]; 3], row: &mut [u8]) {
+    const HALF: i32 = 1 << (FRAC_BITS - 1);
+    for px in row.chunks_exact_mut(4) {
+        let (r, g, b) = (px[2] as i32, px[1] as i32, px[0] as i32);
+        let out = m.map(|c| ((c[0] * r + c[1] * g + c[2] * b + HALF) >> FRAC_BITS).clamp(0, 255));
+        px[0] = out[2] as u8;
+        px[1] = out[1] as u8;
+        px[2] = out[0] as u8;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Pixels of the colors given, in rows of `w` with padding after each
+    struct Swatches {
+        buf: Vec<u8>,
+        colors: Vec<(u8, u8, u8)>,
+        w: usize,
+        h: usize,
+        stride: usize,
+    }
+
+    // Every combination of a few levels per channel, one pixel each, in
+    // rows of 16 with 8 bytes of padding after each
+    fn swatches() -> Swatches {
+        const LEVELS: [u8; 7] = [0, 1, 64, 127, 128, 200, 255];
+        let mut colors = Vec::new();
+        for r in LEVELS {
+            for g in LEVELS {
+                for b in LEVELS {
+                    colors.push((r, g, b));
+                }
+            }
+        }
+        let w = 16;
+        let h = colors.len().div_ceil(w);
+        let stride = w * 4 + 8;
+        let mut buf = vec![0x77; stride * h];
+        for (i, &(r, g, b)) in colors.iter().enumerate() {
+            let o = (i / w) * stride + (i % w) * 4;
+            buf[o..o + 4].copy_from_slice(&[b, g, r, 0xff]);
+        }
+        Swatches {
+            buf,
+            colors,
+            w,
+            h,
+            stride,
+        }
+    }
+
+    #[test]
+    fn fixed_point_matches_float_reference() {
+        let custom = ColorMatrix::Custom([[1.2, -0.1, 0.05], [0.3, 0.3, 0.3], [-0.5, 0.25, 1.5]]);
+        for matrix in [
+            ColorMatrix::Desaturate,
+            ColorMatrix::SwapRb,
+            ColorMatrix::Protanopia,
+            custom,
+        ] {
+            let m = matrix.coefficients();
+            let Swatches {
+                mut buf,
+                colors,
+                w,
+                h,
+                stride,
+            } = swatches();
+            matrix.apply(&mut buf, stride, w, h);
+            for (i, &(r, g, b)) in colors.iter().enumerate() {
+                let o = (i / w) * stride + (i % w) * 4;
+                let got = [buf[o + 2], buf[o + 1], buf[o]];
+                for c in 0..3 {
+                    let want = (m[c][0] * r as f64 + m[c][1] * g as f64 + m[c][2] * b as f64)
+                        .round()
+                        .clamp(0.0, 255.0);
+                    assert!(
+                        (got[c] as f64 - want).abs() <= 1.0,
+                        "{} on {:?}: channel {} is {}, want {}",
+                        matrix.name(),
+                        (r, g, b),
+                        c,
+                        got[c],
+                        want
+                    );
+                }
+            }
+            // Padding between rows is left alone
+            for y in 0..h {
+                assert!(
+                    buf[y * stride + w * 4..(y + 1) * stride]
+                        .iter()
+                        .all(|&v| v == 0x77)
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn identity_leaves_buffer_untouched() {
+        let Swatches {
+            mut buf,
+            w,
+            h,
+            stride,
+            ..
+        } = swatches();
+        let before = buf.clone();
+        ColorMatrix::Identity.apply(&mut buf, stride, w, h);
+        assert_eq!(buf, before);
+    }
+}
@@ -0,0 +1,218 @@
+//! Positions of the moving patterns (the motion bar and the line sweep) and
+//! what they do at the edge of the screen.
+
+use serde::{Deserialize, Serialize};
+use std::time::Instant;
+
+/// What a moving pattern does when it reaches the edge
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum EdgeBehavior {
+    /// Leave one edge and come back in at the other
+    #[default]
+    Wrap,
+    /// Turn around at either edge
+    Bounce,
+    /// Halt at the edge it runs into
+    Stop,
+}
+
+impl EdgeBehavior {
+    pub fn next(self) -> Self {
+        match self {
+            EdgeBehavior::Wrap => EdgeBehavior::Bounce,
+            EdgeBehavior::Bounce => EdgeBehavior::Stop,
+            EdgeBehavior::Stop => EdgeBehavior::Wrap,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            EdgeBehavior::Wrap => "wrap",
+            EdgeBehavior::Bounce => "bounce",
+            EdgeBehavior::Stop => "stop",
+        }
+    }
+}
+
+/// A position along one axis, moved by elapsed time so it keeps its speed
+/// when frames are skipped or capped
+#[derive(Clone, Copy, Debug)]
+pub struct Mover {
+    pub pos: f64,
+    // +1 or -1; only bouncing turns it around
+    dir: f64,
+    last: Option<Instant>,
+}
+
+impl Default for Mover {
+    fn default() -> Self {
+        Self {
+            pos: 0.0,
+            dir: 1.0,
+            last: None,
+        }
+    }
+}
+
+impl Mover {
+    /// Forgets the time of the last move, so the next one starts from
+    /// scratch instead of making up for a pause
+    pub fn hold(&mut self) {
+        self.last = None;
+    }
+
+    /// Moves something `size` pixels wide on by `px_per_sec` for the time
+    /// since the last call, across a screen `span` pixels wide. Wrapping
+    /// keeps the position in [0, `span`) without losing the overshoot, so
+    /// the step stays constant across the edge, and leaves the drawing to
+    /// carry what hangs off one edge over to the other. Bouncing and
+    /// stopping keep all of it on screen, in [0, `span` - `size`].
+    pub fn advance(
+        &mut self,
+        now: Instant,
+        px_per_sec: f64,
+        span: usize,
+        size: usize,
+        edge: EdgeBehavior,
+    ) {
+        let dt = self
+            .last
+            .map_or(0.0, |last| now.duration_since(last).as_secs_f64());
+        self.last = Some(now);
+        self.step(px_per_sec * dt, span, size, edge);
+    }
+
+    /// Moves by `delta` pixels in the current direction
+    pub fn step(&mut self, delta: f64, span: usize, size: usize, edge: EdgeBehavior) {
+        let len = match edge {
+            EdgeBehavior::Wrap => span,
+            EdgeBehavior::Bounce | EdgeBehavior::Stop => span.saturating_sub(size),
+        } as f64;
+        let moved = self.pos + self.dir * delta;
+        match edge {
+            EdgeBehavior::Wrap => self.pos = moved.rem_euclid(len.max(1.0)),
+            EdgeBehavior::Stop => self.pos = moved.clamp(0.0, len),
+            EdgeBehavior::Bounce if len <= 0.0 => self.pos = 0.0,
+            EdgeBehavior::Bounce => {
+                // Unfold the path so it only ever runs forward around a loop
+                // twice the length: out along [0, len], back along [len, 2 len).
+                // However far one move overshoots, folding it back gives the
+                // position and direction that many reflections would.
+                let forward = if self.dir > 0.0 {
+                    self.pos.clamp(0.0, len)
+                } else {
+                    2.0 * len - self.pos.clamp(0.0, len)
+                };
+                let u = (forward + delta).rem_euclid(2.0 * len);
+                if u <= len {
+                    self.pos = u;
+                    self.dir = 1.0;
+                } else {
+                    self.pos = 2.0 * len - u;
+                    self.dir = -1.0;
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    // Bouncing one pixel at a time, turning round before any pixel that
+    // would leave [0, len]
+    fn bounce_by_pixels(pos: &mut i64, dir: &mut i64, delta: u32, len: i64) {
+        for _ in 0..delta {
+            if !(0..=len).contains(&(*pos + *dir)) {
+                *dir = -*dir;
+            }
+            *pos += *dir;
+        }
+    }
+
+    #[test]
+    fn wrap_keeps_the_overshoot() {
+        let (span, size) = (100, 10);
+        let mut mover = Mover::default();
+        for n in 1..=500 {
+            mover.step(7.25, span, size, EdgeBehavior::Wrap);
+            let want = (n as f64 * 7.25) % span as f64;
+            assert!(
+                (mover.pos - want).abs() < 1e-9,
+                "frame {}: {} vs {}",
+                n,
+                mover.pos,
+                want
+            );
+        }
+
+        // A step longer than the screen still lands where it would have
+        let mut mover = Mover::default();
+        mover.step(250.0, span, size, EdgeBehavior::Wrap);
+        assert_eq!(mover.pos, 50.0);
+    }
+
+    #[test]
+    fn bounce_matches_pixel_by_pixel_reflection() {
+        let (span, size) = (100, 8);
+        let len = (span - size) as i64;
+        // Ordinary speeds, one that lands exactly on the edge, and some that
+        // cross the screen several times in one frame
+        for delta in [1, 7, 23, 46, 92, 130, 250, 1000] {
+            let mut mover = Mover::default();
+            let (mut pos, mut dir) = (0i64, 1i64);
+            for n in 1..=300 {
+                mover.step(delta as f64, span, size, EdgeBehavior::Bounce);
+                bounce_by_pixels(&mut pos, &mut dir, delta, len);
+                assert_eq!(mover.pos, pos as f64, "delta {} frame {}", delta, n);
+                assert!((0.0..=len as f64).contains(&mover.pos));
+            }
+        }
+    }
+
+    #[test]
+    fn stop_halts_at_the_far_edge() {
+        let (span, size) = (100, 8);
+        let mut mover = Mover::default();
+        for n in 1..=200 {
+            mover.step(3.0, span, size, EdgeBehavior::Stop);
+            assert_eq!(mover.pos, (3.0 * n as f64).min(92.0), "frame {}", n);
+        }
+    }
+
+    #[test]
+    fn nothing_moves_without_room() {
+        for edge in [EdgeBehavior::Bounce, EdgeBehavior::Stop] {
+            let mut mover = Mover::default();
+            for _ in 0..10 {
+                mover.step(13.0, 8, 8, edge);
+                assert_eq!(mover.pos, 0.0, "{:?}", edge);
+            }
+        }
+        let mut mover = Mover::default();
+        mover.step(13.0, 0, 8, EdgeBehavior::Wrap);
+        assert_eq!(mover.pos, 0.0);
+    }
+
+    #[test]
+    fn advance_moves_by_elapsed_time() {
+        let t0 = Instant::now();
+        let at = |ms: u64| t0 + Duration::from_millis(ms);
+        let mut mover = Mover::default();
+        // The first call only starts the clock
+        mover.advance(at(0), 100.0, 1000, 10, EdgeBehavior::Wrap);
+        assert_eq!(mover.pos, 0.0);
+        mover.advance(at(500), 100.0, 1000, 10, EdgeBehavior::Wrap);
+        assert!((mover.pos - 50.0).abs() < 1e-9);
+        // A skipped frame is made up for
+        mover.advance(at(1500), 100.0, 1000, 10, EdgeBehavior::Wrap);
+        assert!((mover.pos - 150.0).abs() < 1e-9);
+        // A pause isn't
+        mover.hold();
+        mover.advance(at(9000), 100.0, 1000, 10, EdgeBehavior::Wrap);
+        assert!((mover.pos - 150.0).abs() < 1e-9);
+    }
+}
@@ -0,0 +1,100 @@
+//! systemd service notifications (sd_notify), spoken directly over the
+//! datagram socket in NOTIFY_SOCKET. Without that variable every call is a
+//! no-op, so interactive runs are unaffected.
+
+use std::ffi::OsStr;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::net::{SocketAddr, UnixDatagram};
+use std::time::{Duration, Instant};
+
+pub struct Notifier {
+    socket: Option<(UnixDatagram, SocketAddr)>,
+    ready: bool,
+    status: String,
+    // Half of WATCHDOG_USEC, as sd_watchdog_enabled(3) recommends
+    watchdog: Option<Duration>,
+    last_ping: Instant,
+}
+
+impl Notifier {
+    pub fn from_env() -> Self {
+        let socket = std::env::var_os("NOTIFY_SOCKET").and_then(|path| {
+            let path = path.into_encoded_bytes();
+            // A leading '@' names a socket in the abstract namespace
+            let addr = match path.strip_prefix(b"@") {
+                Some(name) => {
+                    use std::os::linux::net::SocketAddrExt;
+                    SocketAddr::from_abstract_name(name)
+                }
+                None => SocketAddr::from_pathname(OsStr::from_bytes(&path)),
+            };
+            let addr = addr
+                .inspect_err(|e| eprintln!("Ignoring NOTIFY_SOCKET: {}", e))
+                .ok()?;
+            let sock = UnixDatagram::unbound()
+                .inspect_err(|e| eprintln!("Could not create notify socket: {}", e))
+                .ok()?;
+            Some((sock, addr))
+        });
+
+        // WATCHDOG_PID, when set, says which process the watchdog is for
+        let for_us = std::env::var("WATCHDOG_PID")
+            .ok()
+            .and_then(|pid| pid.parse::<u32>().ok())
+            .is_none_or(|pid| pid == std::process::id());
+        let watchdog = std::env::var("WATCHDOG_USEC")
+            .ok()
+            .and_then(|us| us.parse::<u64>().ok())
+            .filter(|&us| us > 0 && for_us && socket.is_some())
+            .map(|us| Duration::from_micros(us / 2));
+
+        Self {
+            socket,
+            ready: false,
+            status: String::new(),
+            watchdog,
+            last_ping: Instant::now(),
+        }
+    }
+
+    fn send(&self, msg: &str) {
+        if let Some((sock, addr)) = &self.socket
+            && let Err(e) = sock.send_to_addr(msg.as_bytes(), addr)
+        {
+            eprintln!("sd_notify failed: {}", e);
+        }
+    }
+
+    /// Tells systemd the service is up. Only the first call sends anything.
+    pub fn ready(&mut self) {
+        if !self.ready {
+            self.ready = true;
+            self.send("READY=1");
+        }
+    }
+
+    /// Updates the one-line status shown by `systemctl status`, if it changed
+    pub fn status(&mut self, text: &str) {
+        if self.socket.is_some() && self.status != text {
+            self.status = text.to_string();
+            self.send(&format!("STATUS={}", text));
+        }
+    }
+
+    pub fn stopping(&self) {
+        self.send("STOPPING=1");
+    }
+
+    /// When the next watchdog ping is due, if the watchdog is enabled
+    pub fn watchdog_deadline(&self) -> Option<Instant> {
+        self.watchdog.map(|interval| self.last_ping + interval)
+    }
+
+    /// Pings the watchdog if a ping is due
+    pub fn tick(&mut self, now: Instant) {
+        if self.watchdog_deadline().is_some_and(|due| now >= due) {
+            self.last_ping = now;
+            self.send("WATCHDOG=1");
+        }
+    }
+}
@@ -0,0 +1,441 @@
+//! `--nv12 full|overlay`: shows every frame a second time through an NV12
+//! (4:2:0 YCbCr) plane, to exercise the YUV side of a display pipeline.
+//! The stage is converted in software with the chosen matrix in limited
+//! range, and the plane's COLOR_ENCODING and COLOR_RANGE are set to match
+//! where the driver has them.
+//!
+//! `full` covers the whole screen with the plane; `overlay` covers only the
+//! middle quarter, pixel for pixel over the same RGB frame on the primary
+//! plane, so the two paths can be compared across its edges.
+//!
+//! The plane is updated with the legacy SetPlane call, which on most
+//! drivers waits for a vblank of its own, so expect a lower frame rate.
+
+use anyhow::{Context, Result, anyhow};
+use drm::buffer::{Buffer, DrmFourcc, DrmModifier, Handle, PlanarBuffer};
+use drm::control::dumbbuffer::DumbBuffer;
+use drm::control::{Device as CtrlDevice, FbCmd2Flags, crtc, framebuffer, plane};
+
+use crate::color::{YcbcrRange, YuvMatrix, rgb_to_ycbcr};
+use crate::surface::{Card, count_framebuffer, set_enum_property};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Nv12Layout {
+    Full,
+    Overlay,
+}
+
+impl Nv12Layout {
+    pub fn name(self) -> &'static str {
+        match self {
+            Nv12Layout::Full => "full screen",
+            Nv12Layout::Overlay => "overlay",
+        }
+    }
+}
+
+/// Converts a BGRX image to NV12: a full-size Y plane at `dst[0..]` and an
+/// interleaved CbCr plane at `dst[uv_offset..]`, both `pitch` bytes per row.
+///
+/// Chroma is sited as in MPEG-2 and H.264 (the KMS default): each CbCr
+/// sample is co-sited with an even luma column and halfway between two
+/// luma rows. So it averages the two rows and takes a [1, 2, 1] filter
+/// across the column and its neighbours, clamped at the edges. Odd sizes
+/// repeat the last row or column.
+#[allow(clippy::too_many_arguments)]
+pub fn bgrx_to_nv12(
+    src: &[u8],
+    stride: usize,
+    w: usize,
+    h: usize,
+    matrix: YuvMatrix,
+    range: YcbcrRange,
+    dst: &mut [u8],
+    pitch: usize,
+    uv_offset: usize,
+) {
+    let px = |x: usize, y: usize| {
+        let p = &src[y * stride + x * 4..];
+        [p[2] as f64, p[1] as f64, p[0] as f64]
+    };
+
+    for y in 0..h {
+        for x in 0..w {
+            let [r, g, b] = px(x, y);
+            dst[y * pitch + x] = rgb_to_ycbcr(r, g, b, matrix, range).0;
+        }
+    }
+
+    for cy in 0..h.div_ceil(2) {
+        let rows = [2 * cy, (2 * cy + 1).min(h - 1)];
+        for cx in 0..w.div_ceil(2) {
+            let x = 2 * cx;
+            let taps = [
+                (x.saturating_sub(1), 1.0),
+                (x, 2.0),
+                ((x + 1).min(w - 1), 1.0),
+            ];
+            let mut sum = [0.0; 3];
+            for y in rows {
+                for (tx, weight) in taps {
+                    let p = px(tx, y);
+                    for c in 0..3 {
+                        sum[c] += weight * p[c];
+                    }
+                }
+            }
+            let [r, g, b] = sum.map(|s| s / 8.0);
+            let (_, cb, cr) = rgb_to_ycbcr(r, g, b, matrix, range);
+            let i = uv_offset + cy * pitch + x;
+            dst[i] = cb;
+            dst[i + 1] = cr;
+        }
+    }
+}
+
+// One dumb buffer holding both planes, the CbCr rows after the Y rows
+struct Nv12Frame {
+    db: DumbBuffer,
+    fb: framebuffer::Handle,
+}
+
+// What ADDFB2 needs to know about an Nv12Frame's two planes
+struct Nv12Planes<'a> {
+    db: &'a DumbBuffer,
+    size: (u32, u32),
+}
+
+impl PlanarBuffer for Nv12Planes<'_> {
+    fn size(&self) -> (u32, u32) {
+        self.size
+    }
+
+    fn format(&self) -> DrmFourcc {
+        DrmFourcc::Nv12
+    }
+
+    fn modifier(&self) -> Option<DrmModifier> {
+        None
+    }
+
+    fn pitches(&self) -> [u32; 4] {
+        let pitch = self.db.pitch();
+        [pitch, pitch, 0, 0]
+    }
+
+    fn handles(&self) -> [Option<Handle>; 4] {
+        let handle = Some(self.db.handle());
+        [handle, handle, None, None]
+    }
+
+    fn offsets(&self) -> [u32; 4] {
+        [0, self.db.pitch() * self.size.1, 0, 0]
+    }
+}
+
+impl Nv12Frame {
+    fn create(card: &Card, w: u32, h: u32) -> Result<Self> {
+        // One byte per pixel, with half as many CbCr rows again below
+        let db = card
+            .create_dumb_buffer((w, h + h.div_ceil(2)), DrmFourcc::Nv12, 8)
+            .context("could not allocate an NV12 buffer")?;
+        let layout = Nv12Planes {
+            db: &db,
+            size: (w, h),
+        };
+        match card.add_planar_framebuffer(&layout, FbCmd2Flags::empty()) {
+            Ok(fb) => {
+                count_framebuffer(true);
+                Ok(Self { db, fb })
+            }
+            Err(e) => {
+                let _ = card.destroy_dumb_buffer(db);
+                Err(e).context("could not add an NV12 framebuffer")
+            }
+        }
+    }
+
+    fn destroy(&self, card: &Card) {
+        let _ = card.destroy_framebuffer(self.fb);
+        let _ = card.destroy_dumb_buffer(self.db);
+        count_framebuffer(false);
+    }
+}
+
+pub struct Nv12Plane {
+    plane: plane::Handle,
+    crtc: crtc::Handle,
+    pub layout: Nv12Layout,
+    pub matrix: YuvMatrix,
+    frames: [Nv12Frame; 2],
+    back: usize,
+    // Region of the screen the plane covers: x, y, w, h
+    rect: (usize, usize, usize, usize),
+}
+
+impl Nv12Plane {
+    /// Finds a plane on `crtc` that takes NV12 and allocates its buffers
+    /// for a `disp_w` x `disp_h` screen. Fails if there is no such plane.
+    pub fn open(
+        card: &Card,
+        crtc: crtc::Handle,
+        disp_w: usize,
+        disp_h: usize,
+        layout: Nv12Layout,
+        matrix: YuvMatrix,
+    ) -> Result<Self> {
+        let res = card
+            .resource_handles()
+            .context("could not load resource handles")?;
+        let planes = card.plane_handles().context("could not list planes")?;
+        let plane = planes
+            .into_iter()
+            .filter_map(|p| card.get_plane(p).ok())
+            .find(|info| {
+                res.filter_crtcs(info.possible_crtcs()).contains(&crtc)
+                    && info.crtc().is_none_or(|c| c == crtc)
+                    && info.formats().contains(&(DrmFourcc::Nv12 as u32))
+            })
+            .ok_or_else(|| anyhow!("no plane on this CRTC supports NV12"))?
+            .handle();
+
+        // Even offsets and sizes keep the chroma grid on the RGB one
+        let rect = match layout {
+            Nv12Layout::Full => (0, 0, disp_w, disp_h),
+            Nv12Layout::Overlay => (
+                (disp_w / 4) & !1,
+                (disp_h / 4) & !1,
+                (disp_w / 2).max(2) & !1,
+                (disp_h / 2).max(2) & !1,
+            ),
+        };
+        let (w, h) = (rect.2 as u32, rect.3 as u32);
+        let first = Nv12Frame::create(card, w, h)?;
+        let second = match Nv12Frame::create(card, w, h) {
+            Ok(f) => f,
+            Err(e) => {
+                first.destroy(card);
+                return Err(e);
+            }
+        };
+
+        for (prop, value) in [
+            (
+                "COLOR_ENCODING",
+                match matrix {
+                    YuvMatrix::Bt601 => "ITU-R BT.601 YCbCr",
+                    YuvMatrix::Bt709 => "ITU-R BT.709 YCbCr",
+                },
+            ),
+            ("COLOR_RANGE", "YCbCr limited range"),
+        ] {
+            if let Err(e) = set_enum_property(card, plane, prop, value) {
+                eprintln!("NV12 plane: {:#}; the driver's default applies", e);
+            }
+        }
+
+        Ok(Self {
+            plane,
+            crtc,
+            layout,
+            matrix,
+            frames: [first, second],
+            back: 0,
+            rect,
+        })
+    }
+
+    /// Converts the plane's part of `src` into the back buffer
+    pub fn write(&mut self, card: &Card, src: &[u8], stride: usize) -> Result<()> {
+        let (x, y, w, h) = self.rect;
+        let frame = &mut self.frames[self.back];
+        let pitch = frame.db.pitch() as usize;
+        let mut map = card
+            .map_dumb_buffer(&mut frame.db)
+            .context("could not map the NV12 buffer")?;
+        bgrx_to_nv12(
+            &src[y * stride + x * 4..],
+            stride,
+            w,
+            h,
+            self.matrix,
+            YcbcrRange::Limited,
+            &mut map,
+            pitch,
+            pitch * h,
+        );
+        Ok(())
+    }
+
+    /// Puts the back buffer on screen
+    pub fn show(&mut self, card: &Card) -> Result<()> {
+        let (x, y, w, h) = (
+            self.rect.0 as i32,
+            self.rect.1 as i32,
+            self.rect.2 as u32,
+            self.rect.3 as u32,
+        );
+        // Source coordinates are 16.16 fixed point
+        card.set_plane(
+            self.plane,
+            self.crtc,
+            Some(self.frames[self.back].fb),
+            0,
+            (x, y, w, h),
+            (0, 0, w << 16, h << 16),
+        )
+        .context("could not show the NV12 plane")?;
+        self.back ^= 1;
+        Ok(())
+    }
+
+    /// Takes the plane off the screen and frees its buffers
+    pub fn destroy(&self, card: &Card) {
+        let _ = card.set_plane(self.plane, self.crtc, None, 0, (0, 0, 0, 0), (0, 0, 0, 0));
+        for frame in &self.frames {
+            frame.destroy(card);
+        }
+    }
+
+    pub fn label(&self) -> String {
+        format!(
+            "NV12 plane: {}, {} limited",
+            self.layout.name(),
+            self.matrix.name()
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PITCH: usize = 8;
+    const PAD: u8 = 0xa5;
+
+    fn bgrx(w: usize, h: usize, color: impl Fn(usize, usize) -> (u8, u8, u8)) -> Vec<u8> {
+        let mut src = vec![0u8; w * 4 * h];
+        for y in 0..h {
+            for x in 0..w {
+                let (r, g, b) = color(x, y);
+                src[(y * w + x) * 4..][..4].copy_from_slice(&[b, g, r, 0]);
+            }
+        }
+        src
+    }
+
+    // Y rows, and w.div_ceil(2) (Cb, Cr) pairs per chroma row
+    type Planes = (Vec<Vec<u8>>, Vec<Vec<(u8, u8)>>);
+
+    fn convert(src: &[u8], w: usize, h: usize, matrix: YuvMatrix, range: YcbcrRange) -> Planes {
+        let uv_offset = PITCH * h;
+        let mut dst = vec![PAD; uv_offset + PITCH * h.div_ceil(2)];
+        bgrx_to_nv12(src, w * 4, w, h, matrix, range, &mut dst, PITCH, uv_offset);
+
+        // Nothing past each row's samples is written
+        let used = w.div_ceil(2) * 2;
+        for row in dst[..uv_offset].chunks(PITCH) {
+            assert!(row[w..].iter().all(|&b| b == PAD), "Y padding written");
+        }
+        for row in dst[uv_offset..].chunks(PITCH) {
+            assert!(
+                row[used..].iter().all(|&b| b == PAD),
+                "CbCr padding written"
+            );
+        }
+
+        let luma = dst[..uv_offset]
+            .chunks(PITCH)
+            .map(|r| r[..w].to_vec())
+            .collect();
+        let chroma = dst[uv_offset..]
+            .chunks(PITCH)
+            .map(|r| r[..used].chunks(2).map(|c| (c[0], c[1])).collect())
+            .collect();
+        (luma, chroma)
+    }
+
+    #[test]
+    fn solid_colors_match_reference_values() {
+        use YcbcrRange::*;
+        use YuvMatrix::*;
+        // Published studio-swing values, and full range for white and black
+        let cases = [
+            (Bt709, Limited, (255, 255, 255), (235, 128, 128)),
+            (Bt709, Limited, (0, 0, 0), (16, 128, 128)),
+            (Bt709, Limited, (255, 0, 0), (63, 102, 240)),
+            (Bt709, Limited, (0, 255, 0), (173, 42, 26)),
+            (Bt709, Limited, (0, 0, 255), (32, 240, 118)),
+            (Bt601, Limited, (255, 0, 0), (81, 90, 240)),
+            (Bt601, Limited, (0, 255, 0), (145, 54, 34)),
+            (Bt601, Limited, (0, 0, 255), (41, 240, 110)),
+            (Bt709, Full, (255, 255, 255), (255, 128, 128)),
+            (Bt709, Full, (0, 0, 0), (0, 128, 128)),
+        ];
+        for (matrix, range, rgb, (y, cb, cr)) in cases {
+            let (luma, chroma) = convert(&bgrx(6, 4, |_, _| rgb), 6, 4, matrix, range);
+            assert!(
+                luma.iter().flatten().all(|&v| v == y),
+                "{:?} {:?} {:?}: luma {:?}",
+                matrix,
+                range,
+                rgb,
+                luma
+            );
+            assert!(
+                chroma.iter().flatten().all(|&c| c == (cb, cr)),
+                "{:?} {:?} {:?}: chroma {:?}",
+                matrix,
+                range,
+                rgb,
+                chroma
+            );
+        }
+    }
+
+    #[test]
+    fn chroma_is_filtered_across_columns_and_averaged_over_rows() {
+        let (red, blue) = ((255, 0, 0), (0, 0, 255));
+        let ycbcr = |r: f64, b: f64| rgb_to_ycbcr(r, 0.0, b, YuvMatrix::Bt709, YcbcrRange::Limited);
+        let pair = |(_, cb, cr): (u8, u8, u8)| (cb, cr);
+
+        // Red up to column 4, blue from there: the sample at 4 sees one red
+        // neighbour and weights its own column twice
+        let src = bgrx(8, 2, |x, _| if x < 4 { red } else { blue });
+        let (luma, chroma) = convert(&src, 8, 2, YuvMatrix::Bt709, YcbcrRange::Limited);
+        assert_eq!(luma[0][3], ycbcr(255.0, 0.0).0);
+        assert_eq!(luma[0][4], ycbcr(0.0, 255.0).0);
+        assert_eq!(chroma[0][1], pair(ycbcr(255.0, 0.0)));
+        assert_eq!(chroma[0][2], pair(ycbcr(63.75, 191.25)));
+        assert_eq!(chroma[0][3], pair(ycbcr(0.0, 255.0)));
+
+        // Red over blue: each sample sits halfway between the two rows
+        let src = bgrx(4, 2, |_, y| if y == 0 { red } else { blue });
+        let (_, chroma) = convert(&src, 4, 2, YuvMatrix::Bt709, YcbcrRange::Limited);
+        assert!(chroma[0].iter().all(|&c| c == pair(ycbcr(127.5, 127.5))));
+    }
+
+    #[test]
+    fn odd_sizes_repeat_the_last_row_and_column() {
+        let green = (0, 255, 0);
+        let (luma, chroma) = convert(
+            &bgrx(5, 3, |_, _| green),
+            5,
+            3,
+            YuvMatrix::Bt709,
+            YcbcrRange::Limited,
+        );
+        assert_eq!((luma.len(), chroma.len(), chroma[0].len()), (3, 2, 3));
+        assert!(chroma.iter().flatten().all(|&c| c == (42, 26)));
+
+        // A lone pixel is its own chroma
+        let (luma, chroma) = convert(
+            &bgrx(1, 1, |_, _| green),
+            1,
+            1,
+            YuvMatrix::Bt709,
+            YcbcrRange::Limited,
+        );
+        assert_eq!((luma, chroma), (vec![vec![173]], vec![vec![(42, 26)]]));
+    }
+}
@@ -0,0 +1,477 @@
+//! `--patch-server`: a network pattern generator for scripted measurements.
+//! A client asks for a color over HTTP, the main loop draws it, and the reply
+//! is sent once the frame showing it has been flipped to the screen, so the
+//! client knows when it is safe to measure. The protocol is this tool's own;
+//! profiling suites need a small adapter script to drive it.
+//!
+//! Requests look like `GET /patch?rgb=RRGGBB` (a leading `#` or `%23` is
+//! accepted) with an optional `&window=PCT`, the patch area as a percentage
+//! of the screen, centered on black. Without it the patch is full-field.
+//! `GET /` replies with the patch currently shown. A request whose patch is
+//! replaced by a newer one before it is drawn is answered `superseded`.
+
+use anyhow::{Context, Result, anyhow, bail, ensure};
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::TcpListener;
+use std::os::fd::{AsFd, BorrowedFd};
+use std::os::unix::net::UnixStream;
+use std::sync::mpsc::{Receiver, SyncSender, channel, sync_channel};
+use std::time::Duration;
+
+/// How long a request waits for its patch to reach the screen
+const PRESENT_TIMEOUT: Duration = Duration::from_secs(2);
+
+// Requests with a longer head than this are refused
+const MAX_HEAD: usize = 8192;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Patch {
+    pub rgb: (u8, u8, u8),
+    /// Fraction of the screen area the patch covers, 1.0 for full-field
+    pub window: f64,
+}
+
+impl Patch {
+    /// The centered patch rectangle as (x, y, w, h). It keeps the screen's
+    /// aspect ratio so the same window percentage means the same thing on
+    /// any mode.
+    pub fn rect(&self, w: usize, h: usize) -> (usize, usize, usize, usize) {
+        let scale = self.window.sqrt();
+        let pw = ((w as f64 * scale).round() as usize).clamp(1, w);
+        let ph = ((h as f64 * scale).round() as usize).clamp(1, h);
+        ((w - pw) / 2, (h - ph) / 2, pw, ph)
+    }
+}
+
+/// What the main loop does with a patch request. `GET /` only asks for the
+/// current patch, so it carries no patch.
+pub enum Command {
+    Show(Patch),
+    Query,
+}
+
+// A command plus where to send the answer: the flip count once the patch is
+// on screen, or the current patch for a query
+type Request = (Command, SyncSender<Reply>);
+
+pub enum Reply {
+    Presented(u64),
+    Current(Option<Patch>),
+    /// A newer patch replaced this one before it was drawn
+    Superseded,
+}
+
+/// The listening side of `--patch-server`. Connections are handled one at a
+/// time on a background thread; the main loop picks up requests when
+/// `wake_fd` becomes readable.
+pub struct PatchServer {
+    rx: Receiver<Request>,
+    wake: UnixStream,
+    current: Option<Patch>,
+    // Replies owed for patches not drawn yet, and for patches in the frame
+    // whose flip is in flight
+    waiting: Vec<SyncSender<Reply>>,
+    in_flight: Vec<SyncSender<Reply>>,
+}
+
+impl PatchServer {
+    pub fn start(port: u16) -> Result<Self> {
+        let listener = TcpListener::bind(("0.0.0.0", port))
+            .with_context(|| format!("could not listen on port {}", port))?;
+        let (wake, mut notify) = UnixStream::pair().context("could not create wake socket")?;
+        wake.set_nonblocking(true)
+            .context("could not set up wake socket")?;
+        let (tx, rx) = channel();
+
+        std::thread::spawn(move || {
+            for stream in listener.incoming() {
+                let Ok(mut stream) = stream else {
+                    continue;
+                };
+                let _ = stream.set_read_timeout(Some(PRESENT_TIMEOUT));
+                let result = handle_connection(&mut stream, |cmd| {
+                    let (reply_tx, reply_rx) = sync_channel(1);
+                    tx.send((cmd, reply_tx))
+                        .map_err(|_| anyhow!("display loop has exited"))?;
+                    // Any byte will do; the main loop drains them all
+                    let _ = notify.write_all(&[0]);
+                    reply_rx
+                        .recv_timeout(PRESENT_TIMEOUT)
+                        .map_err(|_| anyhow!("patch was not presented in time"))
+                });
+                if let Err(e) = result {
+                    eprintln!("Patch server: {:#}", e);
+                }
+            }
+        });
+
+        eprintln!("Patch server listening on port {}", port);
+        Ok(Self {
+            rx,
+            wake,
+            current: None,
+            waiting: Vec::new(),
+            in_flight: Vec::new(),
+        })
+    }
+
+    pub fn wake_fd(&self) -> BorrowedFd<'_> {
+        self.wake.as_fd()
+    }
+
+    pub fn current(&self) -> Option<Patch> {
+        self.current
+    }
+
+    /// Takes every pending request. Returns true if a new patch needs to be
+    /// drawn.
+    pub fn drain(&mut self) -> bool {
+        let mut buf = [0u8; 64];
+        while matches!((&self.wake).read(&mut buf), Ok(n) if n > 0) {}
+
+        let mut changed = false;
+        while let Ok((cmd, reply)) = self.rx.try_recv() {
+            match cmd {
+                Command::Show(patch) => {
+                    self.current = Some(patch);
+                    // Only the newest patch will ever be drawn
+                    for older in self.waiting.drain(..) {
+                        let _ = older.send(Reply::Superseded);
+                    }
+                    self.waiting.push(reply);
+                    changed = true;
+                }
+                Command::Query => {
+                    let _ = reply.send(Reply::Current(self.current));
+                }
+            }
+        }
+        changed
+    }
+
+    /// The current patch has been drawn and its flip submitted
+    pub fn drawn(&mut self) {
+        self.in_flight.append(&mut self.waiting);
+    }
+
+    /// The flip submitted after `drawn` has completed
+    pub fn presented(&mut self, flip_count: u64) {
+        for reply in self.in_flight.drain(..) {
+            let _ = reply.send(Reply::Presented(flip_count));
+        }
+    }
+}
+
+/// Reads one request from `stream`, passes it to `submit` and writes the
+/// HTTP response. Kept apart from the socket handling so it can be driven
+/// from anything that reads and writes.
+pub fn handle_connection<S: Read + Write>(
+    stream: &mut S,
+    mut submit: impl FnMut(Command) -> Result<Reply>,
+) -> Result<()> {
+    let target = match read_request(stream) {
+        Ok(target) => target,
+        Err(e) => return respond(stream, "400 Bad Request", &format!("{:#}\n", e)),
+    };
+
+    let cmd = match parse_target(&target) {
+        Ok(cmd) => cmd,
+        Err(e) => return respond(stream, "400 Bad Request", &format!("{:#}\n", e)),
+    };
+
+    match submit(cmd) {
+        Ok(Reply::Presented(flip)) => respond(stream, "200 OK", &format!("presented {}\n", flip)),
+        Ok(Reply::Current(Some(p))) => respond(
+            stream,
+            "200 OK",
+            &format!(
+                "rgb={:02X}{:02X}{:02X} window={}\n",
+                p.rgb.0,
+                p.rgb.1,
+                p.rgb.2,
+                p.window * 100.0
+            ),
+        ),
+        Ok(Reply::Current(None)) => respond(stream, "200 OK", "none\n"),
+        Ok(Reply::Superseded) => respond(stream, "409 Conflict", "superseded\n"),
+        Err(e) => respond(stream, "503 Service Unavailable", &format!("{:#}\n", e)),
+    }
+}
+
+// Returns the request target of a GET, after reading the whole head
+fn read_request(stream: &mut impl Read) -> Result<String> {
+    read_request_head(stream).map(|(target, _)| target)
+}
+
+/// Reads a GET request's head and returns its target and header lines
+pub fn read_request_head(stream: &mut impl Read) -> Result<(String, Vec<(String, String)>)> {
+    let mut reader = BufReader::new(stream.take(MAX_HEAD as u64));
+    let mut line = String::new();
+    reader
+        .read_line(&mut line)
+        .context("could not read request")?;
+
+    let mut parts = line.split_whitespace();
+    let (Some(method), Some(target)) = (parts.next(), parts.next()) else {
+        bail!("malformed request line");
+    };
+    ensure!(method == "GET", "only GET is supported");
+    let target = target.to_string();
+
+    // Headers must be consumed before replying, even when unused
+    let mut headers = Vec::new();
+    loop {
+        line.clear();
+        if reader.read_line(&mut line)? == 0 || line.trim().is_empty() {
+            break;
+        }
+        if let Some((name, value)) = line.split_once(':') {
+            headers.push((name.trim().to_string(), value.trim().to_string()));
+        }
+    }
+    Ok((target, headers))
+}
+
+/// Parses `/patch?rgb=RRGGBB&window=PCT` or `/`
+pub fn parse_target(target: &str) -> Result<Command> {
+    let (path, query) = target.split_once('?').unwrap_or((target, ""));
+    match path {
+        "/" => return Ok(Command::Query),
+        "/patch" => {}
+        _ => bail!("unknown path: {}", path),
+    }
+
+    let mut rgb = None;
+    let mut window = 1.0;
+    for pair in query.split('&').filter(|p| !p.is_empty()) {
+        let (key, value) = pair.split_once('=').unwrap_or((pair, ""));
+        match key {
+            "rgb" => rgb = Some(parse_hex(value)?),
+            "window" => {
+                let pct: f64 = value
+                    .parse()
+                    .with_context(|| format!("invalid window: {}", value))?;
+                ensure!(
+                    pct > 0.0 && pct <= 100.0,
+                    "window must be in (0, 100]: {}",
+                    value
+                );
+                window = pct / 100.0;
+            }
+            _ => bail!("unknown parameter: {}", key),
+        }
+    }
+
+    let rgb = rgb.ok_or_else(|| anyhow!("missing rgb parameter"))?;
+    Ok(Command::Show(Patch { rgb, window }))
+}
+
+pub fn parse_hex(s: &str) -> Result<(u8, u8, u8)> {
+    let hex = s
+        .strip_prefix("%23")
+        .or_else(|| s.strip_prefix('#'))
+        .unwrap_or(s);
+    ensure!(
+        hex.len() == 6 && hex.bytes().all(|b| b.is_ascii_hexdigit()),
+        "rgb must be six hex digits: {}",
+        s
+    );
+    let channel = |i: usize| u8::from_str_radix(&hex[i..i + 2], 16).unwrap();
+    Ok((channel(0), channel(2), channel(4)))
+}
+
+fn respond(stream: &mut impl Write, status: &str, body: &str) -> Result<()> {
+    write!(
+        stream,
+        "HTTP/1.1 {}\r\nContent-Type: text/plain\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        body.len(),
+        body
+    )
+    .context("could not send response")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    // A connection whose request is scripted and whose response is kept
+    struct Scripted {
+        input: Cursor<Vec<u8>>,
+        output: Vec<u8>,
+    }
+
+    impl Scripted {
+        fn new(request: &str) -> Self {
+            Self {
+                input: Cursor::new(request.as_bytes().to_vec()),
+                output: Vec::new(),
+            }
+        }
+
+        fn response(&self) -> String {
+            String::from_utf8(self.output.clone()).unwrap()
+        }
+    }
+
+    impl Read for Scripted {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            self.input.read(buf)
+        }
+    }
+
+    impl Write for Scripted {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.output.write(buf)
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    // Runs one request through handle_connection, answering with `reply`,
+    // and returns the command submitted and the response
+    fn exchange(request: &str, reply: impl FnOnce() -> Result<Reply>) -> (Option<Command>, String) {
+        let mut stream = Scripted::new(request);
+        let mut submitted = None;
+        let mut reply = Some(reply);
+        handle_connection(&mut stream, |cmd| {
+            submitted = Some(cmd);
+            reply.take().unwrap()()
+        })
+        .unwrap();
+        (submitted, stream.response())
+    }
+
+    fn status_and_body(response: &str) -> (&str, &str) {
+        let status = response.lines().next().unwrap();
+        let body = response.split_once("\r\n\r\n").unwrap().1;
+        (status, body)
+    }
+
+    #[test]
+    fn patch_is_answered_once_presented() {
+        let (cmd, response) = exchange(
+            "GET /patch?rgb=FF8000&window=10 HTTP/1.1\r\nHost: x\r\n\r\n",
+            || Ok(Reply::Presented(42)),
+        );
+        let Some(Command::Show(patch)) = cmd else {
+            panic!("expected a patch");
+        };
+        assert_eq!(patch.rgb, (0xff, 0x80, 0x00));
+        assert!((patch.window - 0.1).abs() < 1e-9);
+        assert_eq!(
+            status_and_body(&response),
+            ("HTTP/1.1 200 OK", "presented 42\n")
+        );
+    }
+
+    #[test]
+    fn query_reports_current_patch() {
+        let current = Patch {
+            rgb: (1, 2, 3),
+            window: 0.5,
+        };
+        let (cmd, response) = exchange("GET / HTTP/1.1\r\n\r\n", || {
+            Ok(Reply::Current(Some(current)))
+        });
+        assert!(matches!(cmd, Some(Command::Query)));
+        assert_eq!(
+            status_and_body(&response),
+            ("HTTP/1.1 200 OK", "rgb=010203 window=50\n")
+        );
+
+        let (_, response) = exchange("GET / HTTP/1.1\r\n\r\n", || Ok(Reply::Current(None)));
+        assert_eq!(status_and_body(&response).1, "none\n");
+    }
+
+    #[test]
+    fn superseded_patch_is_reported() {
+        let (_, response) = exchange("GET /patch?rgb=000000 HTTP/1.1\r\n\r\n", || {
+            Ok(Reply::Superseded)
+        });
+        assert_eq!(
+            status_and_body(&response),
+            ("HTTP/1.1 409 Conflict", "superseded\n")
+        );
+    }
+
+    #[test]
+    fn timeout_is_service_unavailable() {
+        let (_, response) = exchange("GET /patch?rgb=000000 HTTP/1.1\r\n\r\n", || {
+            Err(anyhow!("patch was not presented in time"))
+        });
+        assert!(response.starts_with("HTTP/1.1 503 Service Unavailable\r\n"));
+    }
+
+    #[test]
+    fn bad_requests_are_refused_without_submitting() {
+        for request in [
+            "POST /patch?rgb=000000 HTTP/1.1\r\n\r\n",
+            "GET /patch?rgb=00000 HTTP/1.1\r\n\r\n",
+            "GET /patch?window=50 HTTP/1.1\r\n\r\n",
+            "GET /patch?rgb=000000&window=0 HTTP/1.1\r\n\r\n",
+            "GET /elsewhere HTTP/1.1\r\n\r\n",
+            "\r\n",
+        ] {
+            let (cmd, response) = exchange(request, || panic!("submitted {:?}", request));
+            assert!(cmd.is_none());
+            assert!(
+                response.starts_with("HTTP/1.1 400 Bad Request\r\n"),
+                "{:?}: {}",
+                request,
+                response
+            );
+        }
+    }
+
+    #[test]
+    fn hex_accepts_hash_prefixes() {
+        for s in ["12abEF", "#12abEF", "%2312abEF"] {
+            assert_eq!(parse_hex(s).unwrap(), (0x12, 0xab, 0xef));
+        }
+        assert!(parse_hex("12abEG").is_err());
+        assert!(parse_hex("#12abE").is_err());
+    }
+
+    #[test]
+    fn waiting_request_is_superseded_by_newer_patch() {
+        let (tx, rx) = channel();
+        let (wake, _notify) = UnixStream::pair().unwrap();
+        wake.set_nonblocking(true).unwrap();
+        let mut server = PatchServer {
+            rx,
+            wake,
+            current: None,
+            waiting: Vec::new(),
+            in_flight: Vec::new(),
+        };
+        let show = |rgb| {
+            let (reply_tx, reply_rx) = sync_channel(1);
+            let patch = Patch { rgb, window: 1.0 };
+            tx.send((Command::Show(patch), reply_tx)).unwrap();
+            reply_rx
+        };
+
+        let first = show((1, 1, 1));
+        let second = show((2, 2, 2));
+        assert!(server.drain());
+        assert!(matches!(first.try_recv(), Ok(Reply::Superseded)));
+        assert!(second.try_recv().is_err());
+        assert_eq!(server.current().map(|p| p.rgb), Some((2, 2, 2)));
+
+        server.drawn();
+        // Arriving while the frame is in flight doesn't take that frame back
+        let third = show((3, 3, 3));
+        assert!(server.drain());
+        server.presented(7);
+        assert!(matches!(second.try_recv(), Ok(Reply::Presented(7))));
+        assert!(third.try_recv().is_err());
+
+        server.drawn();
+        server.presented(8);
+        assert!(matches!(third.try_recv(), Ok(Reply::Presented(8))));
+    }
+}
@@ -0,0 +1,262 @@
+//! `--preview-listen`: a downscaled PNG of what is on screen, served over
+//! HTTP for watching a display in another room. `GET /frame` returns the
+//! latest snapshot and `GET /` a page that reloads it every second. The same
+//! port takes WebSocket control clients on `/ws` (see ws.rs).
+//!
+//! The main loop only copies the stage, at most a few times per second;
+//! scaling and encoding happen on a worker thread so presentation never
+//! waits on them.
+
+use anyhow::{Context, Result};
+use serde_json::{Map, Value};
+use std::io::Write;
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::sync::mpsc::{Receiver, SyncSender, sync_channel};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use crate::control::ControlSender;
+use crate::patch::read_request_head;
+use crate::ws::{self, Hub};
+
+/// Width of the preview image; smaller sources are sent at their own size
+pub const PREVIEW_WIDTH: usize = 480;
+
+// Minimum time between snapshots taken from the stage
+const SNAPSHOT_INTERVAL: Duration = Duration::from_millis(250);
+
+const INDEX: &str = "<!DOCTYPE html>\n<title>screen_test preview</title>\n\
+<body style=\"margin:0;background:#222\">\n\
+<img id=\"f\" src=\"/frame\" style=\"display:block;margin:auto\">\n\
+<script>setInterval(() => f.src = \"/frame?\" + Date.now(), 1000)</script>\n";
+
+// A stage copy: pixels, stride, width, height
+type Snapshot = (Vec<u8>, usize, usize, usize);
+
+pub struct Preview {
+    tx: SyncSender<Snapshot>,
+    last: Option<Instant>,
+    hub: Hub,
+    state: Map<String, Value>,
+}
+
+impl Preview {
+    pub fn start(addr: SocketAddr, control: ControlSender) -> Result<Self> {
+        let listener =
+            TcpListener::bind(addr).with_context(|| format!("could not listen on {}", addr))?;
+        let latest: Arc<Mutex<Option<Arc<Vec<u8>>>>> = Arc::default();
+
+        let (tx, rx) = sync_channel(1);
+        let encoded = latest.clone();
+        std::thread::spawn(move || encode_snapshots(rx, encoded));
+
+        let hub = Hub::start();
+        let clients = hub.clone();
+        std::thread::spawn(move || {
+            // Each client gets a thread of its own, so one that stalls can't
+            // hold up the others
+            for (id, stream) in listener.incoming().enumerate() {
+                let Ok(stream) = stream else {
+                    continue;
+                };
+                let (latest, clients, control) = (latest.clone(), clients.clone(), control.clone());
+                std::thread::spawn(move || {
+                    if let Err(e) = handle_client(stream, &latest, &clients, control, id as u64) {
+                        eprintln!("Preview: {:#}", e);
+                    }
+                });
+            }
+        });
+
+        eprintln!("Preview listening on {}", addr);
+        Ok(Self {
+            tx,
+            last: None,
+            hub,
+            state: Map::new(),
+        })
+    }
+
+    /// Passes the current state on to WebSocket clients if it changed
+    pub fn publish(&mut self, state: Map<String, Value>) {
+        if state != self.state {
+            self.state = state.clone();
+            self.hub.publish(state);
+        }
+    }
+
+    /// Hands a copy of the stage to the encoder, unless one was taken less
+    /// than SNAPSHOT_INTERVAL ago or the encoder is still busy
+    pub fn submit(&mut self, buf: &[u8], stride: usize, w: usize, h: usize, now: Instant) {
+        if self
+            .last
+            .is_some_and(|last| now.duration_since(last) < SNAPSHOT_INTERVAL)
+        {
+            return;
+        }
+        if self
+            .tx
+            .try_send((buf[..stride * h].to_vec(), stride, w, h))
+            .is_ok()
+        {
+            self.last = Some(now);
+        }
+    }
+}
+
+fn handle_client(
+    mut stream: TcpStream,
+    latest: &Mutex<Option<Arc<Vec<u8>>>>,
+    clients: &Hub,
+    control: ControlSender,
+    id: u64,
+) -> Result<()> {
+    stream.set_read_timeout(Some(Duration::from_secs(2)))?;
+    let (target, headers) = read_request_head(&mut stream)?;
+    if target == "/ws" {
+        ws::accept(stream, &headers, clients, control, id)
+    } else {
+        let png = latest.lock().unwrap().clone();
+        serve(&mut stream, &target, png.as_deref().map(Vec::as_slice))
+    }
+}
+
+fn encode_snapshots(rx: Receiver<Snapshot>, latest: Arc<Mutex<Option<Arc<Vec<u8>>>>>) {
+    for (buf, stride, w, h) in rx {
+        let (rgb, out_w, out_h) = downscale(&buf, stride, w, h, PREVIEW_WIDTH);
+        let png = Arc::new(encode_png(&rgb, out_w, out_h));
+        *latest.lock().unwrap() = Some(png);
+    }
+}
+
+fn serve(stream: &mut impl Write, target: &str, png: Option<&[u8]>) -> Result<()> {
+    let path = target.split('?').next().unwrap_or("");
+    let (status, content_type, body) = match (path, png) {
+        ("/", _) => ("200 OK", "text/html", INDEX.as_bytes()),
+        ("/frame", Some(png)) => ("200 OK", "image/png", png),
+        ("/frame", None) => (
+            "503 Service Unavailable",
+            "text/plain",
+            &b"no frame yet\n"[..],
+        ),
+        _ => ("404 Not Found", "text/plain", &b"not found\n"[..]),
+    };
+    write!(
+        stream,
+        "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nCache-Control: no-store\r\nConnection: close\r\n\r\n",
+        status,
+        content_type,
+        body.len()
+    )
+    .and_then(|()| stream.write_all(body))
+    .context("could not send response")
+}
+
+/// Box-filters a BGRX image down to `max_w` pixels wide, keeping the aspect
+/// ratio, and returns packed RGB with its size. Each output pixel averages
+/// every source pixel it covers, so no source size is a special case.
+/// Sources no wider than `max_w` are only converted.
+pub fn downscale(
+    src: &[u8],
+    stride: usize,
+    w: usize,
+    h: usize,
+    max_w: usize,
+) -> (Vec<u8>, usize, usize) {
+    let out_w = w.min(max_w).max(1);
+    let out_h = (h * out_w).div_ceil(w.max(1)).max(1);
+    // Source span of output index i out of n, never empty
+    let span = |i: usize, n: usize, len: usize| {
+        let start = i * len / n;
+        let end = ((i + 1) * len / n).max(start + 1).min(len);
+        start..end
+    };
+
+    let mut out = Vec::with_capacity(out_w * out_h * 3);
+    for oy in 0..out_h {
+        let rows = span(oy, out_h, h);
+        for ox in 0..out_w {
+            let cols = span(ox, out_w, w);
+            let mut sum = [0u64; 3];
+            for y in rows.clone() {
+                let row = &src[y * stride..];
+                for x in cols.clone() {
+                    let px = &row[x * 4..x * 4 + 4];
+                    sum[0] += px[2] as u64;
+                    sum[1] += px[1] as u64;
+                    sum[2] += px[0] as u64;
+                }
+            }
+            let n = (rows.len() * cols.len()) as u64;
+            out.extend(sum.iter().map(|&s| ((s + n / 2) / n) as u8));
+        }
+    }
+    (out, out_w, out_h)
+}
+
+/// Encodes packed RGB as a PNG. The image data goes in stored (uncompressed)
+/// deflate blocks: a preview is small enough that compression isn't worth a
+/// dependency.
+pub fn encode_png(rgb: &[u8], w: usize, h: usize) -> Vec<u8> {
+    // Every row starts with filter type 0 (none)
+    let mut raw = Vec::with_capacity((w * 3 + 1) * h);
+    for row in rgb.chunks_exact(w * 3) {
+        raw.push(0);
+        raw.extend_from_slice(row);
+    }
+
+    let mut zlib = vec![0x78, 0x01];
+    let mut blocks = raw.chunks(0xffff).peekable();
+    if blocks.peek().is_none() {
+        zlib.extend_from_slice(&[1, 0, 0, 0xff, 0xff]);
+    }
+    while let Some(block) = blocks.next() {
+        zlib.push(blocks.peek().is_none() as u8);
+        let len = block.len() as u16;
+        zlib.extend_from_slice(&len.to_le_bytes());
+        zlib.extend_from_slice(&(!len).to_le_bytes());
+        zlib.extend_from_slice(block);
+    }
+    zlib.extend_from_slice(&adler32(&raw).to_be_bytes());
+
+    let mut ihdr = Vec::with_capacity(13);
+    ihdr.extend_from_slice(&(w as u32).to_be_bytes());
+    ihdr.extend_from_slice(&(h as u32).to_be_bytes());
+    // 8 bits per channel, truecolor, default compression, filter, no interlace
+    ihdr.extend_from_slice(&[8, 2, 0, 0, 0]);
+
+    let mut png = b"\x89PNG\r\n\x1a\n".to_vec();
+    for (kind, data) in [(b"IHDR", &ihdr), (b"IDAT", &zlib), (b"IEND", &Vec::new())] {
+        png.extend_from_slice(&(data.len() as u32).to_be_bytes());
+        let start = png.len();
+        png.extend_from_slice(kind);
+        png.extend_from_slice(data);
+        let crc = crc32(&png[start..]);
+        png.extend_from_slice(&crc.to_be_bytes());
+    }
+    png
+}
+
+fn adler32(data: &[u8]) -> u32 {
+    let (mut a, mut b) = (1u32, 0u32);
+    for chunk in data.chunks(5552) {
+        for &byte in chunk {
+            a += byte as u32;
+            b += a;
+        }
+        a %= 65521;
+        b %= 65521;
+    }
+    (b << 16) | a
+}
+
+fn crc32(data: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for &byte in data {
+        crc ^= byte as u32;
+        for _ in 0..8 {
+            crc = (crc >> 1) ^ (0xedb8_8320 & (crc & 1).wrapping_neg());
+        }
+    }
+    !crc
+}
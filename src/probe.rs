@@ -0,0 +1,199 @@
+//! Probe mode (I): a reticle moved with the arrow keys, with the position
+//! and the R, G, B values of the stage pixel under it shown in the overlay
+//! and printed whenever it moves. Shift with the arrows grows the probe
+//! into a rectangle, which is outlined instead and reads the mean, min and
+//! max of each channel over the pixels inside it.
+//!
+//! The value is read once the patterns are drawn and before any overlay
+//! or the reticle itself go on top, and the stage is redrawn from scratch
+//! every frame, so the probe never reads its own pixels. It reads the
+//! stage, i.e. after the color matrix and --invert but before any --lut.
+//! With --render-scale the stage is smaller than the screen, so the probe
+//! reads the stage pixel that its screen position is expanded from.
+
+use evdev::KeyCode;
+
+use crate::draw::fill_rect;
+
+// Arrow key step sizes, cycled with PgUp/PgDn
+const STEPS: [usize; 3] = [1, 8, 64];
+
+// Reticle arms start this far from the probed pixel, which stays visible;
+// both at UI scale 2 and in proportion at others
+const GAP: usize = 2;
+const ARM: usize = 6;
+
+pub struct Probe {
+    // Center of the probe, which is the probed pixel at 1x1
+    pub x: usize,
+    pub y: usize,
+    pub w: usize,
+    pub h: usize,
+    step: usize,
+    // Rectangle last sampled, so stderr gets a line per move rather than
+    // per frame of an animated pattern, and the outline goes where it read
+    shown: Option<Rect>,
+}
+
+// Left, top, width and height on the screen
+type Rect = (usize, usize, usize, usize);
+
+// Per channel sums and extremes over the probe rectangle, in R, G, B order
+struct Stats {
+    sum: [u64; 3],
+    min: [u8; 3],
+    max: [u8; 3],
+    count: u64,
+}
+
+impl Probe {
+    /// A probe in the middle of a `w` x `h` screen
+    pub fn new(w: usize, h: usize) -> Self {
+        Self {
+            x: w / 2,
+            y: h / 2,
+            w: 1,
+            h: 1,
+            step: 0,
+            shown: None,
+        }
+    }
+
+    /// Handles a key meant for the probe; returns false for any other key.
+    /// With `shift`, Left/Right and Up/Down shrink and grow the rectangle.
+    pub fn key(&mut self, code: KeyCode, shift: bool, w: usize, h: usize) -> bool {
+        let d = STEPS[self.step];
+        match code {
+            KeyCode::KEY_LEFT if shift => self.w = self.w.saturating_sub(d).max(1),
+            KeyCode::KEY_RIGHT if shift => self.w = (self.w + d).min(w.max(1)),
+            KeyCode::KEY_UP if shift => self.h = self.h.saturating_sub(d).max(1),
+            KeyCode::KEY_DOWN if shift => self.h = (self.h + d).min(h.max(1)),
+            KeyCode::KEY_LEFT => self.x = self.x.saturating_sub(d),
+            KeyCode::KEY_RIGHT => self.x = (self.x + d).min(w.saturating_sub(1)),
+            KeyCode::KEY_UP => self.y = self.y.saturating_sub(d),
+            KeyCode::KEY_DOWN => self.y = (self.y + d).min(h.saturating_sub(1)),
+            KeyCode::KEY_PAGEUP => self.step = (self.step + 1).min(STEPS.len() - 1),
+            KeyCode::KEY_PAGEDOWN => self.step = self.step.saturating_sub(1),
+            _ => return false,
+        }
+        true
+    }
+
+    // Top left corner and size of the probe on a `w` x `h` screen, kept
+    // whole on it
+    fn rect(&self, w: usize, h: usize) -> Rect {
+        let (rw, rh) = (self.w.min(w).max(1), self.h.min(h).max(1));
+        let x = self.x.saturating_sub(rw / 2).min(w.saturating_sub(rw));
+        let y = self.y.saturating_sub(rh / 2).min(h.saturating_sub(rh));
+        (x, y, rw, rh)
+    }
+
+    // The stage pixels that the screen rectangle `(x, y, rw, rh)` is
+    // expanded from
+    fn stats(buf: &[u8], stride: usize, rect: Rect, scale: usize) -> Stats {
+        let (x, y, rw, rh) = rect;
+        let mut stats = Stats {
+            sum: [0; 3],
+            min: [255; 3],
+            max: [0; 3],
+            count: 0,
+        };
+        for sy in y / scale..=(y + rh - 1) / scale {
+            for sx in x / scale..=(x + rw - 1) / scale {
+                let (r, g, b) = read_rgb(buf, stride, sx, sy);
+                for (c, v) in [r, g, b].into_iter().enumerate() {
+                    stats.sum[c] += v as u64;
+                    stats.min[c] = stats.min[c].min(v);
+                    stats.max[c] = stats.max[c].max(v);
+                }
+                stats.count += 1;
+            }
+        }
+        stats
+    }
+
+    /// Reads the pixels under the probe from a stage 1/`scale` of the
+    /// `w` x `h` screen, prints them if the probe has moved, and returns the
+    /// overlay lines
+    pub fn sample(
+        &mut self,
+        buf: &[u8],
+        stride: usize,
+        w: usize,
+        h: usize,
+        scale: usize,
+    ) -> Vec<String> {
+        // A mode change can leave the probe off a smaller screen
+        self.x = self.x.min(w.saturating_sub(1));
+        self.y = self.y.min(h.saturating_sub(1));
+        let rect = self.rect(w, h);
+        let stats = Self::stats(buf, stride, rect, scale);
+        let mut lines = if (rect.2, rect.3) == (1, 1) {
+            let [r, g, b] = stats.min;
+            vec![format!("({}, {}): R {} G {} B {}", self.x, self.y, r, g, b)]
+        } else {
+            let mean = stats.sum.map(|s| s as f64 / stats.count as f64);
+            let [r0, g0, b0] = stats.min;
+            let [r1, g1, b1] = stats.max;
+            vec![
+                format!(
+                    "{}x{} at ({}, {}): mean R {:.1} G {:.1} B {:.1}",
+                    rect.2, rect.3, rect.0, rect.1, mean[0], mean[1], mean[2]
+                ),
+                format!(
+                    "min R {} G {} B {}, max R {} G {} B {}",
+                    r0, g0, b0, r1, g1, b1
+                ),
+            ]
+        };
+        if self.shown != Some(rect) {
+            for line in &lines {
+                eprintln!("Probe {}", line);
+            }
+        }
+        self.shown = Some(rect);
+        for line in &mut lines {
+            line.insert_str(0, "probe ");
+        }
+        lines[0] += &format!("  step {} px", STEPS[self.step]);
+        lines
+    }
+
+    /// Draws the reticle around the probed pixel, or the outline just
+    /// outside the rectangle, in black or white, whichever stands out from
+    /// what it reads, into a `w` x `h` stage at 1/`scale`, with the reticle
+    /// sized for UI scale `ui`
+    pub fn draw(&self, buf: &mut [u8], stride: usize, w: usize, h: usize, scale: usize, ui: usize) {
+        let Some(rect) = self.shown else {
+            return;
+        };
+        let stats = Self::stats(buf, stride, rect, scale);
+        let [r, g, b] = stats.sum.map(|s| (s / stats.count) as u32);
+        let luma = (2 * r + 5 * g + b) / 8;
+        let c = if luma > 127 { 0 } else { 255 };
+        let (x, y, rw, rh) = rect;
+        if (rw, rh) != (1, 1) {
+            let (x0, y0) = ((x / scale) as isize - 1, (y / scale) as isize - 1);
+            let x1 = ((x + rw - 1) / scale) as isize + 1;
+            let y1 = ((y + rh - 1) / scale) as isize + 1;
+            let (ow, oh) = ((x1 - x0 + 1) as usize, (y1 - y0 + 1) as usize);
+            fill_rect(buf, stride, w, h, x0, y0, ow, 1, c, c, c);
+            fill_rect(buf, stride, w, h, x0, y1, ow, 1, c, c, c);
+            fill_rect(buf, stride, w, h, x0, y0, 1, oh, c, c, c);
+            fill_rect(buf, stride, w, h, x1, y0, 1, oh, c, c, c);
+            return;
+        }
+        let (x, y) = ((x / scale) as isize, (y / scale) as isize);
+        let (gap, arm) = ((GAP * ui).div_ceil(2), (ARM * ui).div_ceil(2));
+        let (near, far) = (gap as isize, (gap + arm) as isize);
+        fill_rect(buf, stride, w, h, x - far + 1, y, arm, 1, c, c, c);
+        fill_rect(buf, stride, w, h, x + near, y, arm, 1, c, c, c);
+        fill_rect(buf, stride, w, h, x, y - far + 1, 1, arm, c, c, c);
+        fill_rect(buf, stride, w, h, x, y + near, 1, arm, c, c, c);
+    }
+}
+
+fn read_rgb(buf: &[u8], stride: usize, x: usize, y: usize) -> (u8, u8, u8) {
+    let p = &buf[y * stride + x * 4..];
+    (p[2], p[1], p[0])
+}
@@ -0,0 +1,273 @@
+//! `--profile`: where the main loop's time goes. Every stretch of the loop
+//! is charged to one `Phase` by calling `Profiler::lap` at its end, so the
+//! phases always add up to the wall clock and the boundaries live in the
+//! `Phase` list below rather than in scattered start/stop pairs.
+//!
+//! Durations go into log2 histograms, one per phase. A breakdown of the
+//! last few seconds goes to stderr at intervals and one of the whole run at
+//! exit. `--profile-trace PATH` also writes every lap as a span in the
+//! Chrome trace event format, which Perfetto and speedscope open as a
+//! flame chart.
+//!
+//! Off, the profiler is a `None` and each lap is one untaken branch.
+
+use anyhow::{Context, Result};
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::Path;
+use std::time::{Duration, Instant};
+
+const REPORT_INTERVAL: Duration = Duration::from_secs(5);
+
+// Bucket i holds durations under 2^i microseconds; the last one takes the rest
+const BUCKETS: usize = 24;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Phase {
+    /// Blocked in poll()
+    Poll,
+    /// DRM events, the patch server, remote control and serial commands
+    Events,
+    /// Keyboard input, including reopening a lost keyboard
+    Input,
+    /// Patterns and overlays into the stage
+    Draw,
+    /// The stage into the back buffer (write_to_back)
+    Write,
+    /// Submitting the page flip
+    Flip,
+    /// Bookkeeping between the others: status updates, building the poll
+    /// set, recorders and previews
+    Other,
+}
+
+impl Phase {
+    // In declaration order, so a phase indexes its histogram
+    const ALL: [Phase; 7] = [
+        Phase::Poll,
+        Phase::Events,
+        Phase::Input,
+        Phase::Draw,
+        Phase::Write,
+        Phase::Flip,
+        Phase::Other,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Phase::Poll => "poll",
+            Phase::Events => "events",
+            Phase::Input => "input",
+            Phase::Draw => "draw",
+            Phase::Write => "write",
+            Phase::Flip => "flip",
+            Phase::Other => "other",
+        }
+    }
+}
+
+#[derive(Clone, Copy)]
+struct Histogram {
+    buckets: [u64; BUCKETS],
+    count: u64,
+    total: Duration,
+    max: Duration,
+}
+
+impl Default for Histogram {
+    fn default() -> Self {
+        Self {
+            buckets: [0; BUCKETS],
+            count: 0,
+            total: Duration::ZERO,
+            max: Duration::ZERO,
+        }
+    }
+}
+
+impl Histogram {
+    fn record(&mut self, d: Duration) {
+        let us = d.as_micros() as u64;
+        let bucket = (u64::BITS - us.leading_zeros()) as usize;
+        self.buckets[bucket.min(BUCKETS - 1)] += 1;
+        self.count += 1;
+        self.total += d;
+        self.max = self.max.max(d);
+    }
+
+    // Upper bound of the bucket holding the q quantile, capped at the max
+    fn quantile(&self, q: f64) -> Duration {
+        let rank = ((self.count as f64 * q).ceil() as u64).max(1);
+        let mut seen = 0;
+        for (i, n) in self.buckets.iter().enumerate() {
+            seen += n;
+            if seen >= rank {
+                return Duration::from_micros(1 << i).min(self.max);
+            }
+        }
+        self.max
+    }
+}
+
+struct Trace {
+    out: BufWriter<File>,
+    first: bool,
+}
+
+struct Inner {
+    origin: Instant,
+    last: Instant,
+    loops: u64,
+    run: [Histogram; Phase::ALL.len()],
+    window: [Histogram; Phase::ALL.len()],
+    window_start: Instant,
+    window_loops: u64,
+    trace: Option<Trace>,
+}
+
+pub struct Profiler(Option<Box<Inner>>);
+
+impl Profiler {
+    pub fn new(enabled: bool, trace: Option<&Path>) -> Result<Self> {
+        if !enabled {
+            return Ok(Self(None));
+        }
+        let trace = match trace {
+            Some(path) => {
+                let mut out = BufWriter::new(
+                    File::create(path)
+                        .with_context(|| format!("could not create {}", path.display()))?,
+                );
+                out.write_all(b"[\n")?;
+                Some(Trace { out, first: true })
+            }
+            None => None,
+        };
+        let now = Instant::now();
+        Ok(Self(Some(Box::new(Inner {
+            origin: now,
+            last: now,
+            loops: 0,
+            run: Default::default(),
+            window: Default::default(),
+            window_start: now,
+            window_loops: 0,
+            trace,
+        }))))
+    }
+
+    /// Charges the time since the previous lap to `phase`
+    #[inline]
+    pub fn lap(&mut self, phase: Phase) {
+        if let Some(inner) = &mut self.0 {
+            inner.lap(phase);
+        }
+    }
+
+    /// Counts one pass of the loop, and prints the breakdown once one is due
+    #[inline]
+    pub fn tick(&mut self) {
+        if let Some(inner) = &mut self.0 {
+            inner.tick();
+        }
+    }
+
+    /// When the next breakdown is due, so poll can wake for it
+    pub fn deadline(&self) -> Option<Instant> {
+        self.0
+            .as_ref()
+            .map(|inner| inner.window_start + REPORT_INTERVAL)
+    }
+
+    /// Prints the whole run's breakdown and closes the trace
+    pub fn finish(self) -> Result<()> {
+        let Some(mut inner) = self.0 else {
+            return Ok(());
+        };
+        inner.lap(Phase::Other);
+        print_breakdown(
+            "whole run",
+            inner.last - inner.origin,
+            inner.loops,
+            &inner.run,
+        );
+        if let Some(mut trace) = inner.trace {
+            trace
+                .out
+                .write_all(b"\n]\n")
+                .and_then(|()| trace.out.flush())
+                .context("could not write the profile trace")?;
+        }
+        Ok(())
+    }
+}
+
+impl Inner {
+    fn lap(&mut self, phase: Phase) {
+        let now = Instant::now();
+        let took = now - self.last;
+        self.run[phase as usize].record(took);
+        self.window[phase as usize].record(took);
+
+        if let Some(trace) = &mut self.trace {
+            // Timestamps in microseconds from the start of the run
+            let ts = (self.last - self.origin).as_secs_f64() * 1e6;
+            let sep = if trace.first { "" } else { ",\n" };
+            trace.first = false;
+            if let Err(e) = write!(
+                trace.out,
+                "{}{{\"name\":\"{}\",\"ph\":\"X\",\"pid\":1,\"tid\":1,\"ts\":{:.3},\"dur\":{:.3}}}",
+                sep,
+                phase.name(),
+                ts,
+                took.as_secs_f64() * 1e6
+            ) {
+                eprintln!("Profile trace stopped: {}", e);
+                self.trace = None;
+            }
+        }
+        self.last = now;
+    }
+
+    fn tick(&mut self) {
+        self.loops += 1;
+        self.window_loops += 1;
+        let elapsed = self.last - self.window_start;
+        if elapsed >= REPORT_INTERVAL {
+            print_breakdown("last", elapsed, self.window_loops, &self.window);
+            self.window = Default::default();
+            self.window_start = self.last;
+            self.window_loops = 0;
+        }
+    }
+}
+
+fn print_breakdown(label: &str, span: Duration, loops: u64, phases: &[Histogram]) {
+    let ms = |d: Duration| d.as_secs_f64() * 1000.0;
+    eprintln!(
+        "Profile, {} {:.1} s: {} loops ({:.0} per second)",
+        label,
+        span.as_secs_f64(),
+        loops,
+        loops as f64 / span.as_secs_f64().max(1e-9)
+    );
+    eprintln!(
+        "  {:<7} {:>6} {:>9} {:>9} {:>9} {:>9} {:>8}",
+        "phase", "share", "mean ms", "p50 ms", "p99 ms", "max ms", "count"
+    );
+    for (phase, h) in Phase::ALL.iter().zip(phases) {
+        if h.count == 0 {
+            continue;
+        }
+        eprintln!(
+            "  {:<7} {:>5.1}% {:>9.3} {:>9.3} {:>9.3} {:>9.3} {:>8}",
+            phase.name(),
+            100.0 * h.total.as_secs_f64() / span.as_secs_f64().max(1e-9),
+            ms(h.total) / h.count as f64,
+            ms(h.quantile(0.5)),
+            ms(h.quantile(0.99)),
+            ms(h.max),
+            h.count
+        );
+    }
+}
@@ -0,0 +1,180 @@
+use anyhow::{Context, Result, anyhow};
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{Receiver, SyncSender, TrySendError, sync_channel};
+use std::thread::JoinHandle;
+
+use crate::color::{YcbcrRange, rgb_to_ycbcr709};
+
+/// Container for recorded frames
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RecordFormat {
+    /// YUV4MPEG2, 4:4:4 BT.709 in the given range
+    Y4m(YcbcrRange),
+    /// Headerless packed 8-bit RGB
+    Raw,
+}
+
+// Frames waiting for the writer. Small on purpose: a slow disk should cost
+// recorded frames, never presentation.
+const QUEUE_DEPTH: usize = 4;
+
+/// Writes presented frames to a file on a background thread. Frames that
+/// arrive while the queue is full are dropped and counted.
+pub struct Recorder {
+    path: PathBuf,
+    tx: Option<SyncSender<Vec<u8>>>,
+    writer: Option<JoinHandle<Result<u64>>>,
+    w: usize,
+    h: usize,
+    limit: u64,
+    queued: u64,
+    dropped: u64,
+}
+
+impl Recorder {
+    pub fn start(
+        path: &Path,
+        format: RecordFormat,
+        w: usize,
+        h: usize,
+        refresh_hz: f64,
+        limit: u64,
+    ) -> Result<Self> {
+        // "-" is stdout, e.g. to pipe into ffmpeg
+        let sink: Box<dyn Write + Send> = if path == Path::new("-") {
+            Box::new(std::io::stdout())
+        } else {
+            let file = File::create(path)
+                .with_context(|| format!("could not create recording {}", path.display()))?;
+            Box::new(file)
+        };
+        let mut out = BufWriter::new(sink);
+
+        let bytes_per_frame = w * h * 3;
+        eprintln!(
+            "Recording {}x{} at {:.2} Hz: {:.0} MB/s to keep up; frames the writer can't take in time are dropped from the recording, not from the screen",
+            w,
+            h,
+            refresh_hz,
+            bytes_per_frame as f64 * refresh_hz / 1e6
+        );
+        if format == RecordFormat::Raw {
+            eprintln!(
+                "Read it with: ffmpeg -f rawvideo -pix_fmt rgb24 -s {}x{} -r {:.3} -i {}",
+                w,
+                h,
+                refresh_hz,
+                path.display()
+            );
+        }
+
+        if let RecordFormat::Y4m(range) = format {
+            // Frame rate as a fraction in millihertz so 59.94 and friends survive
+            let fps_milli = (refresh_hz * 1000.0).round().max(1.0) as u64;
+            writeln!(
+                out,
+                "YUV4MPEG2 W{} H{} F{}:1000 Ip A1:1 C444 XCOLORRANGE={}",
+                w,
+                h,
+                fps_milli,
+                match range {
+                    YcbcrRange::Full => "FULL",
+                    YcbcrRange::Limited => "LIMITED",
+                }
+            )
+            .context("could not write recording header")?;
+        }
+
+        let (tx, rx) = sync_channel(QUEUE_DEPTH);
+        let writer = std::thread::spawn(move || write_frames(rx, out, format, w, h));
+
+        Ok(Self {
+            path: path.to_path_buf(),
+            tx: Some(tx),
+            writer: Some(writer),
+            w,
+            h,
+            limit,
+            queued: 0,
+            dropped: 0,
+        })
+    }
+
+    /// Queues a copy of the frame's visible pixels without blocking. Frames
+    /// past the limit are ignored, and recording stops if the writer failed.
+    pub fn submit(&mut self, buf: &[u8], stride: usize) {
+        if self.queued >= self.limit {
+            self.tx = None;
+        }
+        let Some(tx) = &self.tx else {
+            return;
+        };
+
+        let mut frame = Vec::with_capacity(self.w * self.h * 4);
+        for y in 0..self.h {
+            frame.extend_from_slice(&buf[y * stride..y * stride + self.w * 4]);
+        }
+
+        match tx.try_send(frame) {
+            Ok(()) => self.queued += 1,
+            Err(TrySendError::Full(_)) => self.dropped += 1,
+            Err(TrySendError::Disconnected(_)) => self.tx = None,
+        }
+    }
+
+    /// Waits for queued frames to be written and reports what was recorded
+    pub fn finish(mut self) -> Result<()> {
+        self.tx = None;
+        let written = self
+            .writer
+            .take()
+            .map_or(Ok(Ok(0)), |w| w.join())
+            .map_err(|_| anyhow!("recording thread panicked"))?
+            .with_context(|| format!("recording to {} failed", self.path.display()))?;
+
+        eprintln!(
+            "Recorded {} frames to {} ({} dropped)",
+            written,
+            self.path.display(),
+            self.dropped
+        );
+        Ok(())
+    }
+}
+
+// Writer thread body: converts each BGRX frame and appends it to the output
+fn write_frames(
+    rx: Receiver<Vec<u8>>,
+    mut out: BufWriter<Box<dyn Write + Send>>,
+    format: RecordFormat,
+    w: usize,
+    h: usize,
+) -> Result<u64> {
+    let mut written = 0;
+    let mut planes = vec![0u8; w * h * 3];
+
+    for frame in rx {
+        match format {
+            RecordFormat::Y4m(range) => {
+                let (y, rest) = planes.split_at_mut(w * h);
+                let (cb, cr) = rest.split_at_mut(w * h);
+                for (i, px) in frame.chunks_exact(4).enumerate() {
+                    (y[i], cb[i], cr[i]) = rgb_to_ycbcr709(px[2], px[1], px[0], range);
+                }
+                out.write_all(b"FRAME\n")?;
+            }
+            RecordFormat::Raw => {
+                for (dst, px) in planes.chunks_exact_mut(3).zip(frame.chunks_exact(4)) {
+                    dst.copy_from_slice(&[px[2], px[1], px[0]]);
+                }
+            }
+        }
+        out.write_all(&planes)?;
+        written += 1;
+    }
+
+    out.flush()?;
+    Ok(written)
+}
@@ -0,0 +1,379 @@
+//! The `--report` JSON written at exit. This is a stable interface for
+//! automation: fields may be added in later versions, but existing ones keep
+//! their names, types and meaning. `version` is bumped on any change.
+
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use std::path::Path;
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+
+use crate::PatternKind;
+use crate::timing::FlipStats;
+
+pub const REPORT_VERSION: u32 = 8;
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Report {
+    pub version: u32,
+    /// DRM device node, e.g. "/dev/dri/card0"
+    pub device: String,
+    /// Connector name and monitor, as printed at startup
+    pub connector: String,
+    /// "WxH @ HZ" of the mode that was set
+    pub mode: String,
+    /// Framebuffer fourcc, e.g. "XR24"
+    pub format: String,
+    /// Wall-clock start and end, milliseconds since the Unix epoch
+    pub started_unix_ms: u64,
+    pub ended_unix_ms: u64,
+    /// Every stretch of time spent on one step, in order
+    pub steps: Vec<StepVisit>,
+    /// Pass/fail results of any checks that ran
+    pub verdicts: Vec<Verdict>,
+    pub flips: FlipSummary,
+    /// Problems encountered, including the error that ended the run if any
+    pub errors: Vec<String>,
+    /// One section per extra `--card`; the fields above cover the first
+    #[serde(default)]
+    pub instances: Vec<InstanceReport>,
+    /// The `--shuffle` seed, if the script ran shuffled. Step indices are
+    /// into the shuffled order; each visit's original_index maps it back.
+    #[serde(default)]
+    pub shuffle_seed: Option<u64>,
+    /// With --sync-outputs, each extra card's skew against the first
+    #[serde(default)]
+    pub sync: Vec<SyncSummary>,
+    /// The --soak tally, if the run was one
+    #[serde(default)]
+    pub soak: Option<SoakReport>,
+    /// Changes to the connector's link properties during the run, in order
+    #[serde(default)]
+    pub link_events: Vec<LinkEvent>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct LinkEvent {
+    /// Wall-clock time, milliseconds since the Unix epoch
+    pub unix_ms: u64,
+    /// The property as KMS names it, e.g. "link-status"
+    pub property: String,
+    /// Values as the driver names them; null where it reported none
+    pub from: Option<String>,
+    pub to: Option<String>,
+    /// On link-status going Bad: how setting the mode again turned out
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub recovery: Option<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct SoakReport {
+    /// As asked for with --soak
+    pub duration_s: u64,
+    /// How long it actually ran
+    pub ran_s: u64,
+    /// Whether it ran the whole duration, rather than ending early
+    pub completed: bool,
+    /// Times the script ran through to its end
+    pub cycles: u64,
+    /// Anomalies of each kind, by name as in `anomalies`
+    pub anomaly_counts: std::collections::BTreeMap<String, u64>,
+    /// The first 1000 anomalies, in order
+    pub anomalies: Vec<SoakAnomaly>,
+    /// Times the watchdog set the mode again
+    pub remodesets: u64,
+    pub rss_start_kb: Option<u64>,
+    pub rss_end_kb: Option<u64>,
+    /// Highest RSS seen at a heartbeat
+    pub rss_max_kb: u64,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct SoakAnomaly {
+    /// failed_flip, missed_vblanks, verify_mismatch, device_error,
+    /// keyboard_recovered or pipeline_hung
+    pub kind: String,
+    /// Wall-clock time, milliseconds since the Unix epoch
+    pub unix_ms: u64,
+    /// Since the soak started
+    pub elapsed_s: u64,
+    pub detail: String,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct SyncSummary {
+    /// 1 for the second `--card`, and so on
+    pub output: usize,
+    /// Passes flipped on both this output and the first
+    pub passes: u64,
+    /// Its vblank minus the first output's, positive when it is later
+    pub mean_skew_us: i64,
+    pub max_skew_us: u64,
+    /// Whether it was a frame or more out when the run ended
+    pub late: bool,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct InstanceReport {
+    /// 1 for the second `--card`, and so on
+    pub index: usize,
+    pub device: String,
+    pub connector: String,
+    pub mode: String,
+    pub steps: Vec<StepVisit>,
+    pub flips: FlipSummary,
+    /// Including the failure that disabled the card, if any
+    pub errors: Vec<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct StepVisit {
+    /// Index into the script that was running
+    pub index: usize,
+    /// Index into the script before --shuffle reordered it
+    #[serde(default)]
+    pub original_index: Option<usize>,
+    /// The step's name in the script, which stays put when it's reordered
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    pub pattern: PatternKind,
+    pub dwell_ms: u64,
+    /// Frames of an animation presented later than --late-tolerance allows
+    #[serde(default)]
+    pub late_frames: u64,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Verdict {
+    pub name: String,
+    pub pass: bool,
+    pub detail: String,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct FlipSummary {
+    pub count: u64,
+    /// Vblanks that passed without a new frame between consecutive flips
+    pub missed_vblanks: u64,
+    /// Flips given up on for want of a completion event
+    #[serde(default)]
+    pub timed_out: u64,
+    /// Submit-to-complete latency percentiles in microseconds
+    pub latency_p50_us: u64,
+    pub latency_p90_us: u64,
+    pub latency_p99_us: u64,
+    pub latency_max_us: u64,
+}
+
+impl FlipSummary {
+    pub fn from_stats(stats: &FlipStats) -> Self {
+        Self {
+            count: stats.count,
+            missed_vblanks: stats.missed_vblanks,
+            timed_out: stats.timed_out,
+            latency_p50_us: stats.latency_percentile_us(50.0),
+            latency_p90_us: stats.latency_percentile_us(90.0),
+            latency_p99_us: stats.latency_percentile_us(99.0),
+            latency_max_us: stats.max_latency_us(),
+        }
+    }
+}
+
+impl Report {
+    pub fn save(&self, path: &Path) -> Result<()> {
+        let text = serde_json::to_string_pretty(self).context("could not serialize report")?;
+        std::fs::write(path, text + "\n")
+            .with_context(|| format!("could not write report {}", path.display()))
+    }
+}
+
+pub fn unix_ms(t: SystemTime) -> u64 {
+    t.duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_millis() as u64)
+}
+
+/// Turns the current step, sampled once per loop iteration, into a list of
+/// visits with dwell times
+#[derive(Default)]
+pub struct StepLog {
+    visits: Vec<StepVisit>,
+    // The visit in progress, its dwell filled in when it ends
+    current: Option<(StepVisit, Instant)>,
+}
+
+impl StepLog {
+    pub fn observe(
+        &mut self,
+        index: usize,
+        original_index: Option<usize>,
+        name: Option<&str>,
+        pattern: PatternKind,
+        now: Instant,
+    ) {
+        if let Some((visit, _)) = &self.current
+            && visit.index == index
+            && visit.pattern == pattern
+        {
+            return;
+        }
+        self.close(now);
+        let visit = StepVisit {
+            index,
+            original_index,
+            name: name.map(str::to_string),
+            pattern,
+            dwell_ms: 0,
+            late_frames: 0,
+        };
+        self.current = Some((visit, now));
+    }
+
+    /// Counts a late frame against the visit in progress
+    pub fn late_frame(&mut self) {
+        if let Some((visit, _)) = &mut self.current {
+            visit.late_frames += 1;
+        }
+    }
+
+    pub fn finish(mut self, now: Instant) -> Vec<StepVisit> {
+        self.close(now);
+        self.visits
+    }
+
+    fn close(&mut self, now: Instant) {
+        if let Some((mut visit, since)) = self.current.take() {
+            visit.dwell_ms = now.duration_since(since).as_millis() as u64;
+            self.visits.push(visit);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::{Value, json};
+    use std::time::Duration;
+
+    // A report as a run with every section would write it
+    fn example() -> Value {
+        json!({
+            "version": REPORT_VERSION,
+            "device": "/dev/dri/card0",
+            "connector": "HDMI-A-1 (DELL U2720Q)",
+            "mode": "3840x2160 @ 60",
+            "format": "XR24",
+            "started_unix_ms": 1_700_000_000_000u64,
+            "ended_unix_ms": 1_700_000_060_000u64,
+            "steps": [
+                {
+                    "index": 0,
+                    "original_index": 3,
+                    "name": "white",
+                    "pattern": "solid",
+                    "dwell_ms": 5000,
+                    "late_frames": 0
+                },
+                {
+                    "index": 1,
+                    "original_index": null,
+                    "pattern": "motion",
+                    "dwell_ms": 12034,
+                    "late_frames": 7
+                }
+            ],
+            "verdicts": [{ "name": "verify step 1", "pass": true, "detail": "checksum matched" }],
+            "flips": {
+                "count": 3600,
+                "missed_vblanks": 2,
+                "timed_out": 0,
+                "latency_p50_us": 16000,
+                "latency_p90_us": 16400,
+                "latency_p99_us": 17000,
+                "latency_max_us": 33000
+            },
+            "errors": ["step 4: generator failed"],
+            "instances": [{
+                "index": 1,
+                "device": "/dev/dri/card1",
+                "connector": "DP-1",
+                "mode": "1920x1080 @ 60",
+                "steps": [],
+                "flips": {
+                    "count": 10,
+                    "missed_vblanks": 0,
+                    "timed_out": 1,
+                    "latency_p50_us": 1,
+                    "latency_p90_us": 2,
+                    "latency_p99_us": 3,
+                    "latency_max_us": 4
+                },
+                "errors": []
+            }],
+            "shuffle_seed": 42,
+            "sync": [{ "output": 1, "passes": 10, "mean_skew_us": -120, "max_skew_us": 400, "late": false }],
+            "soak": {
+                "duration_s": 3600,
+                "ran_s": 60,
+                "completed": false,
+                "cycles": 3,
+                "anomaly_counts": { "missed_vblanks": 1 },
+                "anomalies": [{
+                    "kind": "missed_vblanks",
+                    "unix_ms": 1_700_000_030_000u64,
+                    "elapsed_s": 30,
+                    "detail": "3 vblanks between flips"
+                }],
+                "remodesets": 0,
+                "rss_start_kb": 5000,
+                "rss_end_kb": null,
+                "rss_max_kb": 5200
+            },
+            "link_events": [
+                { "unix_ms": 1_700_000_040_000u64, "property": "link-status", "from": "Good", "to": "Bad", "recovery": "mode set again" },
+                { "unix_ms": 1_700_000_041_000u64, "property": "link-status", "from": "Bad", "to": "Good" }
+            ]
+        })
+    }
+
+    #[test]
+    fn example_round_trips() {
+        let report: Report = serde_json::from_value(example()).unwrap();
+        assert_eq!(report.steps[1].late_frames, 7);
+        assert_eq!(
+            report.soak.as_ref().unwrap().anomaly_counts["missed_vblanks"],
+            1
+        );
+        assert_eq!(serde_json::to_value(&report).unwrap(), example());
+    }
+
+    #[test]
+    fn older_report_without_later_sections_loads() {
+        let mut old = example();
+        let fields = old.as_object_mut().unwrap();
+        for later in ["instances", "shuffle_seed", "sync", "soak", "link_events"] {
+            fields.remove(later);
+        }
+        fields["flips"].as_object_mut().unwrap().remove("timed_out");
+        let report: Report = serde_json::from_value(old).unwrap();
+        assert!(report.instances.is_empty() && report.soak.is_none());
+        assert_eq!(report.flips.timed_out, 0);
+    }
+
+    #[test]
+    fn step_log_splits_visits_by_step() {
+        let t0 = Instant::now();
+        let at = |ms| t0 + Duration::from_millis(ms);
+        let mut log = StepLog::default();
+        log.observe(0, None, Some("white"), PatternKind::Solid, at(0));
+        log.observe(0, None, Some("white"), PatternKind::Solid, at(500));
+        log.observe(1, None, None, PatternKind::Motion, at(1000));
+        log.late_frame();
+        log.late_frame();
+        log.observe(0, None, Some("white"), PatternKind::Solid, at(1500));
+        let visits = log.finish(at(1750));
+        let summary: Vec<_> = visits
+            .iter()
+            .map(|v| (v.index, v.dwell_ms, v.late_frames))
+            .collect();
+        assert_eq!(summary, [(0, 1000, 0), (1, 500, 2), (0, 250, 0)]);
+    }
+}
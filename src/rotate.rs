@@ -0,0 +1,227 @@
+//! `--rotate`: turns every frame in software, for panels mounted on their
+//! side where the driver has no rotation property. Patterns are drawn
+//! upright into a buffer of the viewer's size, h x w for a quarter turn, so
+//! "horizontal" and "left to right" mean what the viewer sees, and the
+//! frame is turned on its way to the stage.
+
+// Side of the square tiles a quarter turn copies at a time: 32 rows of
+// 32 pixels is 4 KiB from each side, so reading along one and writing
+// across the other stays in cache even at 4K
+const TILE: usize = 32;
+
+/// How far the picture is turned clockwise on its way to the panel, i.e.
+/// the opposite of how the panel is mounted
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Rotation {
+    #[default]
+    None,
+    Cw90,
+    Half,
+    Cw270,
+}
+
+impl Rotation {
+    pub fn from_degrees(deg: u32) -> Option<Self> {
+        match deg {
+            0 => Some(Rotation::None),
+            90 => Some(Rotation::Cw90),
+            180 => Some(Rotation::Half),
+            270 => Some(Rotation::Cw270),
+            _ => None,
+        }
+    }
+
+    pub fn degrees(self) -> u32 {
+        match self {
+            Rotation::None => 0,
+            Rotation::Cw90 => 90,
+            Rotation::Half => 180,
+            Rotation::Cw270 => 270,
+        }
+    }
+
+    pub fn is_quarter_turn(self) -> bool {
+        matches!(self, Rotation::Cw90 | Rotation::Cw270)
+    }
+
+    /// The size patterns are drawn at for a `w` x `h` screen
+    pub fn upright_size(self, w: usize, h: usize) -> (usize, usize) {
+        if self.is_quarter_turn() {
+            (h, w)
+        } else {
+            (w, h)
+        }
+    }
+}
+
+/// Turns the `w` x `h` upright frame in `src` into `dst`, which is the
+/// screen's size for `rot`
+pub fn rotate_frame(
+    src: &[u8],
+    src_stride: usize,
+    w: usize,
+    h: usize,
+    dst: &mut [u8],
+    dst_stride: usize,
+    rot: Rotation,
+) {
+    // Where upright (x, y) lands on the screen
+    let to = |x: usize, y: usize| match rot {
+        Rotation::None => (x, y),
+        Rotation::Cw90 => (h - 1 - y, x),
+        Rotation::Half => (w - 1 - x, h - 1 - y),
+        Rotation::Cw270 => (y, w - 1 - x),
+    };
+    if matches!(rot, Rotation::None | Rotation::Half) {
+        // Rows stay rows, so plain row order is already cache friendly
+        for y in 0..h {
+            let (_, dy) = to(0, y);
+            let s = &src[y * src_stride..y * src_stride + w * 4];
+            let d = &mut dst[dy * dst_stride..dy * dst_stride + w * 4];
+            if rot == Rotation::None {
+                d.copy_from_slice(s);
+            } else {
+                for (d, s) in d.chunks_exact_mut(4).zip(s.chunks_exact(4).rev()) {
+                    d.copy_from_slice(s);
+                }
+            }
+        }
+        return;
+    }
+    // A quarter turn, a tile at a time: each screen row of a tile is one
+    // upright column, read down or up
+    let (sw, sh) = (h, w);
+    for ty in (0..sh).step_by(TILE) {
+        for tx in (0..sw).step_by(TILE) {
+            let tw = TILE.min(sw - tx);
+            for dy in ty..(ty + TILE).min(sh) {
+                let d = &mut dst[dy * dst_stride + tx * 4..][..tw * 4];
+                for (i, px) in d.chunks_exact_mut(4).enumerate() {
+                    // Upright pixel for screen (tx + i, dy)
+                    let (x, y) = match rot {
+                        Rotation::Cw90 => (dy, h - 1 - (tx + i)),
+                        _ => (w - 1 - dy, tx + i),
+                    };
+                    let s = y * src_stride + x * 4;
+                    px.copy_from_slice(&src[s..s + 4]);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PAD: u8 = 0xa5;
+    const ALL: [Rotation; 4] = [
+        Rotation::None,
+        Rotation::Cw90,
+        Rotation::Half,
+        Rotation::Cw270,
+    ];
+
+    // A frame whose every pixel is different, with padding after each row
+    fn numbered(w: usize, h: usize) -> (Vec<u8>, usize) {
+        let stride = w * 4 + 8;
+        let mut buf = vec![PAD; stride * h];
+        for y in 0..h {
+            for x in 0..w {
+                let n = (y * w + x) as u32;
+                buf[y * stride + x * 4..][..4].copy_from_slice(&n.to_le_bytes());
+            }
+        }
+        (buf, stride)
+    }
+
+    fn pixel(buf: &[u8], stride: usize, x: usize, y: usize) -> u32 {
+        u32::from_le_bytes(buf[y * stride + x * 4..][..4].try_into().unwrap())
+    }
+
+    fn rotate(src: &[u8], stride: usize, w: usize, h: usize, rot: Rotation) -> (Vec<u8>, usize) {
+        let (sw, sh) = rot.upright_size(w, h);
+        let dst_stride = sw * 4 + 12;
+        let mut dst = vec![PAD; dst_stride * sh];
+        rotate_frame(src, stride, w, h, &mut dst, dst_stride, rot);
+        for row in dst.chunks(dst_stride) {
+            assert!(row[sw * 4..].iter().all(|&b| b == PAD), "padding written");
+        }
+        (dst, dst_stride)
+    }
+
+    #[test]
+    fn every_pixel_lands_where_it_should() {
+        // Sizes on, under and over the tile size
+        for (w, h) in [
+            (1, 1),
+            (1, 5),
+            (5, 1),
+            (32, 32),
+            (70, 33),
+            (33, 70),
+            (64, 96),
+        ] {
+            let (src, stride) = numbered(w, h);
+            for rot in ALL {
+                let (dst, dst_stride) = rotate(&src, stride, w, h, rot);
+                for y in 0..h {
+                    for x in 0..w {
+                        let (sx, sy) = match rot {
+                            Rotation::None => (x, y),
+                            Rotation::Cw90 => (h - 1 - y, x),
+                            Rotation::Half => (w - 1 - x, h - 1 - y),
+                            Rotation::Cw270 => (y, w - 1 - x),
+                        };
+                        assert_eq!(
+                            pixel(&dst, dst_stride, sx, sy),
+                            pixel(&src, stride, x, y),
+                            "{}x{} turned {} at {},{}",
+                            w,
+                            h,
+                            rot.degrees(),
+                            x,
+                            y
+                        );
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn top_left_goes_round_the_corners() {
+        let (w, h) = (4, 3);
+        let (src, stride) = numbered(w, h);
+        // Where the upright top left pixel ends up, clockwise from it
+        for (rot, corner) in [
+            (Rotation::None, (0, 0)),
+            (Rotation::Cw90, (h - 1, 0)),
+            (Rotation::Half, (w - 1, h - 1)),
+            (Rotation::Cw270, (0, w - 1)),
+        ] {
+            let (dst, dst_stride) = rotate(&src, stride, w, h, rot);
+            assert_eq!(pixel(&dst, dst_stride, corner.0, corner.1), 0, "{:?}", rot);
+        }
+    }
+
+    #[test]
+    fn opposite_turns_cancel() {
+        let (w, h) = (70, 33);
+        let (src, stride) = numbered(w, h);
+        for (a, b) in [
+            (Rotation::Cw90, Rotation::Cw270),
+            (Rotation::Cw270, Rotation::Cw90),
+            (Rotation::Half, Rotation::Half),
+        ] {
+            let (once, once_stride) = rotate(&src, stride, w, h, a);
+            let (ow, oh) = a.upright_size(w, h);
+            let (back, back_stride) = rotate(&once, once_stride, ow, oh, b);
+            for y in 0..h {
+                for x in 0..w {
+                    assert_eq!(pixel(&back, back_stride, x, y), pixel(&src, stride, x, y));
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,168 @@
+//! `--soak-random[=SEED]`: a soak that, instead of looping the script, shows
+//! patterns picked at random with random parameters for random lengths of
+//! time, to shake out intermittent faults a fixed sequence keeps missing.
+//! The same seed shows the same sequence. Every step shown goes into a log
+//! with the time it came up, so a fault seen at 03:12 can be traced to the
+//! exact pattern on screen.
+
+use anyhow::{Context, Result};
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+use crate::color::Gamut;
+use crate::library::splitmix64;
+use crate::report;
+use crate::{EyeLayout, GradMode, PatternKind, RadialChannels, SOLIDS, Step};
+
+// Steps drawn up at a time; the next lot is drawn when the soak gets to the
+// end of them
+const BATCH: usize = 256;
+
+// How long each step stays up, in seconds
+const MIN_DWELL: f64 = 2.0;
+const MAX_DWELL: f64 = 20.0;
+
+pub struct Roulette {
+    seed: u64,
+    rng: u64,
+    patterns: Vec<PatternKind>,
+    path: PathBuf,
+    log: BufWriter<File>,
+    // Script index of the step last logged, and how many have been logged
+    shown: Option<usize>,
+    count: u64,
+}
+
+impl Roulette {
+    /// Starts the sequence for `seed`, logging to `path`. Patterns that
+    /// flicker by design only come up with `flashing`, and ones that need
+    /// something outside the tool (a generator, a backlight) never do.
+    pub fn open(seed: u64, path: &Path, flashing: bool) -> Result<Self> {
+        let file = File::create(path)
+            .with_context(|| format!("could not create the soak log {}", path.display()))?;
+        let patterns = PatternKind::ALL
+            .into_iter()
+            .filter(|p| {
+                !matches!(p, PatternKind::External | PatternKind::BacklightSweep)
+                    && (flashing || !p.flickers())
+            })
+            .collect();
+        let mut log = BufWriter::new(file);
+        writeln!(log, "# screen_test --soak-random={}", seed)?;
+        writeln!(log, "# unix_ms step pattern dwell_s params")?;
+        Ok(Self {
+            seed,
+            rng: seed,
+            patterns,
+            path: path.to_path_buf(),
+            log,
+            shown: None,
+            count: 0,
+        })
+    }
+
+    fn below(&mut self, n: usize) -> usize {
+        (splitmix64(&mut self.rng) % n as u64) as usize
+    }
+
+    fn chance(&mut self) -> bool {
+        splitmix64(&mut self.rng) & 1 == 1
+    }
+
+    /// The next lot of steps
+    pub fn batch(&mut self) -> Vec<Step> {
+        (0..BATCH).map(|_| self.step()).collect()
+    }
+
+    fn step(&mut self) -> Step {
+        let i = self.below(self.patterns.len());
+        let pat = self.patterns[i];
+        let tenths = self.below(((MAX_DWELL - MIN_DWELL) * 10.0) as usize + 1);
+        let mut step = Step {
+            pat,
+            dwell: Some(MIN_DWELL + tenths as f64 / 10.0),
+            ..Default::default()
+        };
+        // The parameters that change what the pattern looks like the most;
+        // the rest keep their defaults
+        match pat {
+            PatternKind::Solid => step.solid_idx = self.below(SOLIDS.len()),
+            PatternKind::Gradient => {
+                step.grad_vertical = self.chance();
+                step.grad_light = self.chance();
+                step.grad_mode = if self.chance() {
+                    GradMode::Rgb
+                } else {
+                    GradMode::Luma
+                };
+            }
+            PatternKind::Checker => step.checker_cell = 1 << self.below(7),
+            PatternKind::Motion => step.motion_speed = 1 + self.below(32),
+            PatternKind::NearBlack => step.near_black = 1 + self.below(8) as u8,
+            PatternKind::BitDepth => {
+                step.bits_channels = [
+                    RadialChannels::Luma,
+                    RadialChannels::Red,
+                    RadialChannels::Green,
+                    RadialChannels::Blue,
+                ][self.below(4)];
+            }
+            PatternKind::GrayToGray => step.g2g_levels = 2 + self.below(8),
+            PatternKind::IntegerScale => step.int_scale = 2 + self.below(3),
+            PatternKind::GamutCorners => {
+                step.gamut = [Gamut::Rec709, Gamut::DciP3, Gamut::Bt2020][self.below(3)];
+            }
+            PatternKind::EyeAlignment => {
+                step.eyes.layout = [
+                    EyeLayout::SideBySide,
+                    EyeLayout::TopBottom,
+                    EyeLayout::FramePacking,
+                ][self.below(3)];
+            }
+            PatternKind::PovDots => step.dot_seed = splitmix64(&mut self.rng),
+            _ => {}
+        }
+        step
+    }
+
+    /// Logs the step at `index` if it isn't the one logged last
+    pub fn shown(&mut self, index: usize, step: &Step) {
+        if self.shown == Some(index) {
+            return;
+        }
+        self.shown = Some(index);
+        self.count += 1;
+        let params = serde_json::to_string(step).unwrap_or_default();
+        let line = writeln!(
+            self.log,
+            "{} {} {} {:.1} {}",
+            report::unix_ms(SystemTime::now()),
+            self.count,
+            step.pat.name(),
+            step.dwell.unwrap_or(0.0),
+            params
+        );
+        // Flushed every step, so the log is complete up to a crash
+        if let Err(e) = line.and_then(|_| self.log.flush()) {
+            eprintln!("Soak log {}: {}", self.path.display(), e);
+        }
+    }
+
+    /// Forgets the step last logged, for a new batch starting at index 0
+    pub fn new_batch(&mut self) {
+        self.shown = None;
+    }
+
+    pub fn finish(mut self) {
+        let _ = self.log.flush();
+        eprintln!(
+            "Random soak: {} steps shown, seed {} (--soak-random={} repeats the sequence), log {}",
+            self.count,
+            self.seed,
+            self.seed,
+            self.path.display()
+        );
+    }
+}
@@ -0,0 +1,112 @@
+//! `--serial DEV:BAUD`: a measurement instrument on a serial line. Once a
+//! step has settled on screen the configured trigger bytes are sent, and
+//! with `commands` set the instrument can pace the script with N, P and Q.
+//! Only opening the port needs the `serial` feature; after that it is a
+//! plain file descriptor in the main poll set.
+
+use anyhow::{Context, Result, ensure};
+use std::fs::File;
+use std::io::{Read, Write};
+use std::os::fd::{AsFd, BorrowedFd};
+use std::time::{Duration, Instant};
+
+use crate::PatternKind;
+use crate::config::SerialConfig;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SerialCommand {
+    Next,
+    Previous,
+    Quit,
+}
+
+pub struct SerialLink {
+    port: File,
+    device: String,
+    config: SerialConfig,
+    // Step of the frame last presented, and when its trigger is due
+    shown: Option<(usize, PatternKind)>,
+    due: Option<Instant>,
+}
+
+#[cfg(not(feature = "serial"))]
+fn open_port(_device: &str, _baud: u32) -> Result<File> {
+    anyhow::bail!("--serial needs a build with the serial feature")
+}
+
+#[cfg(feature = "serial")]
+fn open_port(device: &str, baud: u32) -> Result<File> {
+    use std::os::fd::{FromRawFd, IntoRawFd};
+
+    // 8N1 raw mode, opened exclusively
+    let port = serialport::new(device, baud).open_native()?;
+    // SAFETY: into_raw_fd hands over sole ownership of the descriptor
+    Ok(unsafe { File::from_raw_fd(port.into_raw_fd()) })
+}
+
+impl SerialLink {
+    pub fn open(device: &str, baud: u32, config: SerialConfig) -> Result<Self> {
+        let port = open_port(device, baud)
+            .with_context(|| format!("could not open serial port {}", device))?;
+        Ok(Self {
+            port,
+            device: device.to_string(),
+            config,
+            shown: None,
+            due: None,
+        })
+    }
+
+    pub fn fd(&self) -> BorrowedFd<'_> {
+        self.port.as_fd()
+    }
+
+    /// Called on every completed flip with the step that frame was drawn
+    /// for. A new step arms the trigger `settle_frames` frame periods later.
+    pub fn presented(&mut self, step: (usize, PatternKind), now: Instant, frame: Duration) {
+        if self.shown != Some(step) {
+            self.shown = Some(step);
+            self.due =
+                (!self.config.trigger.is_empty()).then(|| now + frame * self.config.settle_frames);
+        }
+    }
+
+    /// When the pending trigger is due, if one is armed
+    pub fn deadline(&self) -> Option<Instant> {
+        self.due
+    }
+
+    /// Sends the trigger if it is due. A failed write is reported but the
+    /// run goes on, the same as the GPIO trigger.
+    pub fn tick(&mut self, now: Instant) {
+        if self.due.is_some_and(|due| now >= due) {
+            self.due = None;
+            if let Err(e) = self.port.write_all(self.config.trigger.as_bytes()) {
+                eprintln!("Serial trigger on {} failed: {}", self.device, e);
+            }
+        }
+    }
+
+    /// Reads whatever the instrument sent. Bytes other than N, P and Q
+    /// (either case) are ignored, as is everything without `commands`.
+    pub fn read_commands(&mut self) -> Result<Vec<SerialCommand>> {
+        let mut buf = [0u8; 64];
+        let n = self
+            .port
+            .read(&mut buf)
+            .with_context(|| format!("could not read serial port {}", self.device))?;
+        ensure!(n > 0, "serial port {} hung up", self.device);
+        if !self.config.commands {
+            return Ok(Vec::new());
+        }
+        Ok(buf[..n]
+            .iter()
+            .filter_map(|b| match b.to_ascii_uppercase() {
+                b'N' => Some(SerialCommand::Next),
+                b'P' => Some(SerialCommand::Previous),
+                b'Q' => Some(SerialCommand::Quit),
+                _ => None,
+            })
+            .collect())
+    }
+}
@@ -0,0 +1,241 @@
+use anyhow::{Context, Result, bail, ensure};
+use serde::{Deserialize, Serialize};
+use std::path::Path;
+use std::time::Instant;
+
+use crate::color::{DoubleRange, OutputRange};
+use crate::draw::SubpixelOrder;
+use crate::library::validate_script;
+use crate::matrix::ColorMatrix;
+use crate::motion::EdgeBehavior;
+use crate::{
+    AppState, ChannelFn, ChannelMask, CheckerSweep, ColorPair, Focus, GradMode, HueSweep, Keystone,
+    LineSweep, PatternKind, Radial, RampScroll, SOLIDS, SeqColors, Step,
+};
+
+const SESSION_VERSION: u32 = 1;
+
+/// A complete, reproducible test configuration: the script, the position in
+/// it, and every interactive adjustment on top of the current step.
+/// Runtime-only state like the motion bar position is left out.
+#[derive(Serialize, Deserialize)]
+pub struct Session {
+    version: u32,
+    script_idx: usize,
+    live: Live,
+    script: Vec<Step>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct Live {
+    pattern: PatternKind,
+    solid_idx: usize,
+    grad_mode: GradMode,
+    grad_vertical: bool,
+    #[serde(default)]
+    grad_light: bool,
+    checker_cell: usize,
+    #[serde(default)]
+    checker_colors: Option<ColorPair>,
+    #[serde(default)]
+    checker_sweep: Option<CheckerSweep>,
+    motion_speed: usize,
+    seq_colors: SeqColors,
+    seq_hold: usize,
+    #[serde(default)]
+    int_scale: usize,
+    #[serde(default)]
+    osc: [ChannelFn; 3],
+    motion_aa: bool,
+    #[serde(default)]
+    grad_ticks: bool,
+    #[serde(default)]
+    line: LineSweep,
+    #[serde(default)]
+    edge: EdgeBehavior,
+    #[serde(default)]
+    keystone: Keystone,
+    #[serde(default)]
+    focus: Focus,
+    #[serde(default)]
+    radial: Radial,
+    #[serde(default)]
+    hue: HueSweep,
+    #[serde(default)]
+    ramp: RampScroll,
+    #[serde(default)]
+    subpixel: Option<SubpixelOrder>,
+    labels: bool,
+    paused: bool,
+    #[serde(default)]
+    matrix: ColorMatrix,
+    #[serde(default)]
+    invert: bool,
+    #[serde(default)]
+    channel_mask: ChannelMask,
+    #[serde(default)]
+    output_range: OutputRange,
+    #[serde(default)]
+    double_range: DoubleRange,
+}
+
+impl Session {
+    pub fn load(path: &Path) -> Result<Self> {
+        let text = std::fs::read_to_string(path)
+            .with_context(|| format!("could not read session {}", path.display()))?;
+        let session: Session = toml::from_str(&text)
+            .with_context(|| format!("could not parse session {}", path.display()))?;
+
+        if session.version != SESSION_VERSION {
+            bail!(
+                "session {} has version {}, this build only understands version {}",
+                path.display(),
+                session.version,
+                SESSION_VERSION
+            );
+        }
+
+        Ok(session)
+    }
+
+    pub fn save(&self, path: &Path) -> Result<()> {
+        let text = toml::to_string(self).context("could not serialize session")?;
+        std::fs::write(path, text)
+            .with_context(|| format!("could not write session {}", path.display()))
+    }
+}
+
+impl AppState {
+    pub fn session(&self) -> Session {
+        Session {
+            version: SESSION_VERSION,
+            script_idx: self.script_idx,
+            live: self.live(),
+            script: self.script.clone(),
+        }
+    }
+
+    /// The live parameters, as saved in a session, for remote clients
+    pub fn live_json(&self) -> serde_json::Value {
+        serde_json::to_value(self.live()).unwrap_or_default()
+    }
+
+    fn live(&self) -> Live {
+        Live {
+            pattern: self.pattern,
+            solid_idx: self.solid_idx,
+            grad_mode: self.grad_mode,
+            grad_vertical: self.grad_vertical,
+            grad_light: self.grad_light,
+            checker_cell: self.checker_cell,
+            checker_colors: Some(self.checker_colors),
+            checker_sweep: self.checker_sweep,
+            motion_speed: self.motion_speed,
+            seq_colors: self.seq_colors,
+            seq_hold: self.seq_hold,
+            int_scale: self.int_scale,
+            osc: self.osc,
+            motion_aa: self.motion_aa,
+            grad_ticks: self.grad_ticks,
+            line: self.line,
+            edge: self.edge,
+            keystone: self.keystone,
+            focus: self.focus,
+            radial: self.radial,
+            hue: self.hue,
+            ramp: self.ramp,
+            subpixel: self.subpixel,
+            labels: self.labels,
+            paused: self.paused,
+            matrix: self.matrix,
+            invert: self.invert,
+            channel_mask: self.channel_mask,
+            output_range: self.output_range,
+            double_range: self.double_range,
+        }
+    }
+
+    pub fn restore(&mut self, session: Session) -> Result<()> {
+        validate_script(&session.script, "session script")?;
+        ensure!(
+            session.script_idx < session.script.len(),
+            "session step {} is past the end of its {}-step script",
+            session.script_idx,
+            session.script.len()
+        );
+        let live = session.live;
+        ensure!(
+            live.solid_idx < SOLIDS.len(),
+            "session solid index {} out of range",
+            live.solid_idx
+        );
+        ensure!(
+            live.matrix
+                .coefficients()
+                .iter()
+                .flatten()
+                .all(|c| c.is_finite()),
+            "session color matrix is not finite"
+        );
+        ensure!(
+            live.osc
+                .iter()
+                .all(|f| f.freq_hz.is_finite() && f.phase.is_finite()),
+            "session oscillator settings are not finite"
+        );
+        ensure!(
+            live.checker_sweep
+                .is_none_or(|sweep| sweep.period_s.is_finite() && sweep.period_s > 0.0),
+            "session checker sweep period is invalid"
+        );
+        ensure!(
+            live.radial.exponent.is_finite() && live.radial.exponent > 0.0,
+            "session radial exponent is invalid"
+        );
+        ensure!(
+            (0.0..=1.0).contains(&live.hue.saturation) && (0.0..=1.0).contains(&live.hue.value),
+            "session hue sweep saturation or value is out of range"
+        );
+
+        self.script = session.script;
+        self.script_idx = session.script_idx;
+        self.apply_current_step();
+
+        self.pattern = live.pattern;
+        self.solid_idx = live.solid_idx;
+        self.grad_mode = live.grad_mode;
+        self.grad_vertical = live.grad_vertical;
+        self.grad_light = live.grad_light;
+        self.checker_cell = live.checker_cell;
+        if let Some(colors) = live.checker_colors {
+            self.checker_colors = colors;
+        }
+        self.checker_sweep = live.checker_sweep;
+        self.motion_speed = live.motion_speed;
+        self.seq_colors = live.seq_colors;
+        self.seq_hold = live.seq_hold.max(1);
+        self.int_scale = live.int_scale.clamp(2, 4);
+        self.osc = live.osc;
+        self.motion_aa = live.motion_aa;
+        self.grad_ticks = live.grad_ticks;
+        self.line = live.line;
+        self.line.width = self.line.width.max(1);
+        self.edge = live.edge;
+        self.keystone = live.keystone;
+        self.focus = live.focus;
+        self.radial = live.radial;
+        self.hue = live.hue;
+        self.ramp = live.ramp;
+        self.subpixel = live.subpixel;
+        self.labels = live.labels;
+        self.paused = live.paused;
+        self.matrix = live.matrix;
+        self.invert = live.invert;
+        self.channel_mask = live.channel_mask;
+        self.output_range = live.output_range;
+        self.double_range = live.double_range;
+        self.step_started = Instant::now();
+
+        Ok(())
+    }
+}
@@ -0,0 +1,165 @@
+//! `--shm-export NAME`: the presented frame in /dev/shm/NAME for analysis
+//! tools running alongside. The file is a `Header` followed by the pixels,
+//! `stride` bytes per row, rewritten from the stage after every flip; that
+//! one copy is all an export costs.
+//!
+//! The sequence number works as a seqlock: it is odd while a frame is being
+//! written and bumped to the next even number last, once the frame is
+//! complete. A reader takes the sequence, copies what it needs, then takes
+//! the sequence again, and retries if the two differ or the first was odd.
+//! Frames exported so far are `sequence / 2`. The file is resized, and the
+//! header rewritten, when the mode changes, so readers should check the
+//! geometry on every read. See examples/shm_reader.rs.
+
+use anyhow::{Context, Result, bail};
+use drm::buffer::DrmFourcc;
+use nix::sys::mman::{MapFlags, ProtFlags, mmap, munmap};
+use std::ffi::c_void;
+use std::fs::{File, OpenOptions};
+use std::num::NonZeroUsize;
+use std::path::PathBuf;
+use std::ptr::NonNull;
+use std::sync::atomic::{AtomicU64, Ordering, fence};
+
+use crate::events::monotonic_ns;
+
+pub const MAGIC: [u8; 8] = *b"SCRTSHM1";
+pub const VERSION: u32 = 1;
+
+/// The start of the file. All fields are native endian.
+#[repr(C, packed)]
+#[derive(Clone, Copy)]
+pub struct Header {
+    pub magic: [u8; 8],
+    pub version: u32,
+    /// Offset of the first pixel, i.e. the size of this header
+    pub header_size: u32,
+    pub width: u32,
+    pub height: u32,
+    /// Bytes per row of pixels, at least width * 4
+    pub stride: u32,
+    /// DRM fourcc of the pixels; always XR24, bytes B, G, R, X
+    pub format: u32,
+    /// Odd while a frame is being written, see above
+    pub sequence: u64,
+    /// CLOCK_MONOTONIC when the frame was written, in ns
+    pub timestamp_ns: u64,
+}
+
+pub const HEADER_SIZE: usize = size_of::<Header>();
+const SEQUENCE_OFFSET: usize = std::mem::offset_of!(Header, sequence);
+const TIMESTAMP_OFFSET: usize = std::mem::offset_of!(Header, timestamp_ns);
+
+// The sequence is updated atomically, so it needs its natural alignment
+const _: () = assert!(HEADER_SIZE == 48 && SEQUENCE_OFFSET.is_multiple_of(8));
+
+pub struct ShmExport {
+    file: File,
+    path: PathBuf,
+    map: NonNull<c_void>,
+    map_len: usize,
+    geometry: (usize, usize, usize),
+    sequence: u64,
+}
+
+impl ShmExport {
+    pub fn create(name: &str) -> Result<Self> {
+        if name.is_empty() || name.contains('/') {
+            bail!("invalid --shm-export name: {} (expected a file name)", name);
+        }
+        let path = PathBuf::from("/dev/shm").join(name);
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(&path)
+            .with_context(|| format!("could not create {}", path.display()))?;
+        eprintln!("Exporting frames to {}", path.display());
+        Ok(Self {
+            file,
+            path,
+            map: NonNull::dangling(),
+            map_len: 0,
+            geometry: (0, 0, 0),
+            sequence: 0,
+        })
+    }
+
+    /// Copies a presented frame in, first resizing the file if the geometry
+    /// changed
+    pub fn submit(&mut self, buf: &[u8], stride: usize, w: usize, h: usize) -> Result<()> {
+        if self.geometry != (w, h, stride) {
+            self.remap(w, h, stride)?;
+        }
+        let base = self.map.as_ptr() as *mut u8;
+        // SAFETY: the mapping is HEADER_SIZE + stride * h long and SEQUENCE_OFFSET
+        // is 8-aligned in a page-aligned mapping
+        let sequence = unsafe { &*(base.add(SEQUENCE_OFFSET) as *const AtomicU64) };
+
+        self.sequence += 1;
+        sequence.store(self.sequence, Ordering::Relaxed);
+        fence(Ordering::Release);
+        // SAFETY: as above; `buf` holds at least stride * h bytes
+        unsafe {
+            (base.add(TIMESTAMP_OFFSET) as *mut u64).write_unaligned(monotonic_ns());
+            std::ptr::copy_nonoverlapping(buf.as_ptr(), base.add(HEADER_SIZE), stride * h);
+        }
+        self.sequence += 1;
+        sequence.store(self.sequence, Ordering::Release);
+        Ok(())
+    }
+
+    fn remap(&mut self, w: usize, h: usize, stride: usize) -> Result<()> {
+        self.unmap();
+        let map_len = HEADER_SIZE + stride * h;
+        self.file
+            .set_len(map_len as u64)
+            .with_context(|| format!("could not resize {}", self.path.display()))?;
+        // SAFETY: a fresh shared mapping of the file, unmapped in unmap
+        self.map = unsafe {
+            mmap(
+                None,
+                NonZeroUsize::new(map_len).unwrap(),
+                ProtFlags::PROT_READ | ProtFlags::PROT_WRITE,
+                MapFlags::MAP_SHARED,
+                &self.file,
+                0,
+            )
+        }
+        .with_context(|| format!("could not map {}", self.path.display()))?;
+        self.map_len = map_len;
+        self.geometry = (w, h, stride);
+
+        let header = Header {
+            magic: MAGIC,
+            version: VERSION,
+            header_size: HEADER_SIZE as u32,
+            width: w as u32,
+            height: h as u32,
+            stride: stride as u32,
+            format: DrmFourcc::Xrgb8888 as u32,
+            sequence: self.sequence,
+            timestamp_ns: 0,
+        };
+        // SAFETY: the mapping is at least HEADER_SIZE long
+        unsafe { (self.map.as_ptr() as *mut Header).write_unaligned(header) };
+        Ok(())
+    }
+
+    fn unmap(&mut self) {
+        if self.map_len > 0 {
+            // SAFETY: the mapping came from mmap with this length and is not
+            // used after this
+            let _ = unsafe { munmap(self.map, self.map_len) };
+            self.map_len = 0;
+        }
+    }
+}
+
+impl Drop for ShmExport {
+    fn drop(&mut self) {
+        self.unmap();
+        let _ = std::fs::remove_file(&self.path);
+    }
+}
@@ -0,0 +1,69 @@
+use anyhow::{Context, Result};
+use nix::sys::signal::{SaFlags, SigAction, SigHandler, SigSet, Signal, sigaction};
+use std::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+
+static SHUTDOWN: AtomicBool = AtomicBool::new(false);
+static NEXT: AtomicU32 = AtomicU32::new(0);
+static PREVIOUS: AtomicU32 = AtomicU32::new(0);
+
+extern "C" fn on_signal(_: nix::libc::c_int) {
+    SHUTDOWN.store(true, Ordering::SeqCst);
+}
+
+extern "C" fn on_nav(sig: nix::libc::c_int) {
+    let counter = if sig == Signal::SIGUSR1 as nix::libc::c_int {
+        &NEXT
+    } else {
+        &PREVIOUS
+    };
+    counter.fetch_add(1, Ordering::SeqCst);
+}
+
+/// Turns SIGINT, SIGTERM and SIGHUP into a shutdown request the main loop
+/// picks up, so the normal exit path (report, session, LED restore) still
+/// runs. SA_RESTART is left off so a blocked poll returns with EINTR.
+pub fn install() -> Result<()> {
+    let action = SigAction::new(
+        SigHandler::Handler(on_signal),
+        SaFlags::empty(),
+        SigSet::empty(),
+    );
+    for sig in [Signal::SIGINT, Signal::SIGTERM, Signal::SIGHUP] {
+        // SAFETY: the handler only stores to an atomic, which is
+        // async-signal-safe
+        unsafe { sigaction(sig, &action) }
+            .with_context(|| format!("could not install {} handler", sig))?;
+    }
+    Ok(())
+}
+
+pub fn shutdown_requested() -> bool {
+    SHUTDOWN.load(Ordering::SeqCst)
+}
+
+/// Turns SIGUSR1 into a step forward and SIGUSR2 into a step back, as Right
+/// and Left would, so plain `kill` can drive a run. Only for the interactive
+/// run: anywhere else the signals keep their default of ending the process.
+pub fn install_nav() -> Result<()> {
+    let action = SigAction::new(
+        SigHandler::Handler(on_nav),
+        SaFlags::empty(),
+        SigSet::empty(),
+    );
+    for sig in [Signal::SIGUSR1, Signal::SIGUSR2] {
+        // SAFETY: the handler only adds to an atomic, which is
+        // async-signal-safe
+        unsafe { sigaction(sig, &action) }
+            .with_context(|| format!("could not install {} handler", sig))?;
+    }
+    Ok(())
+}
+
+/// The SIGUSR1s and SIGUSR2s received since the last call, so a burst of
+/// them steps as far as it asks
+pub fn take_nav() -> (u32, u32) {
+    (
+        NEXT.swap(0, Ordering::SeqCst),
+        PREVIOUS.swap(0, Ordering::SeqCst),
+    )
+}
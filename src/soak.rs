@@ -0,0 +1,341 @@
+//! `--soak DURATION`: loops the script unattended for hours or days and
+//! keeps an account of what went wrong along the way. Transient failures are
+//! recorded and ridden out; only losing the device ends the run early. The
+//! tally goes into the `--report`.
+
+use anyhow::{Context, Result, anyhow};
+use std::collections::BTreeMap;
+use std::time::{Duration, Instant, SystemTime};
+
+use nix::errno::Errno;
+
+use crate::diag::current_rss_kb;
+use crate::events::{Event, EventLog};
+use crate::exit::Exit;
+use crate::report::{self, SoakAnomaly, SoakReport, Verdict};
+use crate::surface::{Surface, live_framebuffers};
+use crate::timing::FlipSample;
+
+// Between heartbeat lines
+const HEARTBEAT: Duration = Duration::from_secs(300);
+
+// Anomalies kept in full for the report; later ones are only counted
+const MAX_RECORDED: usize = 1000;
+
+// A single flip that skips more vblanks than this is an anomaly
+pub const MISSED_VBLANK_THRESHOLD: u64 = 2;
+
+// Errors in a row, without a flip completing in between, that count as the
+// device being gone for good
+const MAX_CONSECUTIVE_ERRORS: u32 = 10;
+
+// No flip completing for this long means the pipeline has hung
+const WATCHDOG: Duration = Duration::from_secs(5);
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum AnomalyKind {
+    /// A flip whose completion event never came
+    FailedFlip,
+    /// A flip that skipped more than MISSED_VBLANK_THRESHOLD vblanks
+    MissedVblanks,
+    /// --verify found the scanout differing from what was drawn
+    VerifyMismatch,
+    /// A device call failed and the run carried on
+    DeviceError,
+    /// The keyboard went away and a new one was found
+    KeyboardRecovered,
+    /// The watchdog found no flip completing and set the mode again
+    PipelineHung,
+}
+
+impl AnomalyKind {
+    pub fn name(self) -> &'static str {
+        match self {
+            AnomalyKind::FailedFlip => "failed_flip",
+            AnomalyKind::MissedVblanks => "missed_vblanks",
+            AnomalyKind::VerifyMismatch => "verify_mismatch",
+            AnomalyKind::DeviceError => "device_error",
+            AnomalyKind::KeyboardRecovered => "keyboard_recovered",
+            AnomalyKind::PipelineHung => "pipeline_hung",
+        }
+    }
+}
+
+/// What the watchdog wants done about the pipeline
+pub enum Watchdog {
+    Fine,
+    /// Nothing has flipped for a while: set the mode again
+    Remodeset,
+    /// Still nothing after setting the mode again
+    GiveUp,
+}
+
+pub struct Soak {
+    duration: Duration,
+    started: Instant,
+    next_heartbeat: Instant,
+    cycles: u64,
+    anomalies: Vec<SoakAnomaly>,
+    counts: BTreeMap<AnomalyKind, u64>,
+    rss_start_kb: Option<u64>,
+    rss_max_kb: u64,
+    // Flip timeouts already accounted for
+    timed_out: u64,
+    consecutive_errors: u32,
+    kb_lost: Option<Instant>,
+    // Watchdog: when a flip last completed, and whether the mode has been
+    // set again since
+    last_progress: Instant,
+    flips_seen: u64,
+    remodeset: bool,
+    remodesets: u64,
+}
+
+impl Soak {
+    pub fn new(duration: Duration) -> Self {
+        let now = Instant::now();
+        let rss = current_rss_kb();
+        eprintln!(
+            "Soak test: looping the script for {}",
+            format_span(duration)
+        );
+        Self {
+            duration,
+            started: now,
+            next_heartbeat: now + HEARTBEAT,
+            cycles: 0,
+            anomalies: Vec::new(),
+            counts: BTreeMap::new(),
+            rss_start_kb: rss,
+            rss_max_kb: rss.unwrap_or(0),
+            timed_out: 0,
+            consecutive_errors: 0,
+            kb_lost: None,
+            last_progress: now,
+            flips_seen: 0,
+            remodeset: false,
+            remodesets: 0,
+        }
+    }
+
+    /// When the soak is over
+    pub fn end(&self) -> Instant {
+        self.started + self.duration
+    }
+
+    /// The earliest of the soak's own timers, so poll can wake for it
+    pub fn deadline(&self) -> Instant {
+        self.next_heartbeat
+            .min(self.last_progress + WATCHDOG)
+            .min(self.end())
+    }
+
+    /// The script came round to its start again
+    pub fn cycle_done(&mut self) {
+        self.cycles += 1;
+    }
+
+    pub fn record(&mut self, kind: AnomalyKind, detail: String) {
+        let elapsed = self.started.elapsed();
+        eprintln!(
+            "Soak anomaly at {}: {}: {}",
+            format_span(elapsed),
+            kind.name(),
+            detail
+        );
+        *self.counts.entry(kind).or_default() += 1;
+        if self.anomalies.len() < MAX_RECORDED {
+            self.anomalies.push(SoakAnomaly {
+                kind: kind.name().to_string(),
+                unix_ms: report::unix_ms(SystemTime::now()),
+                elapsed_s: elapsed.as_secs(),
+                detail,
+            });
+        }
+    }
+
+    /// Records an error the run is carrying on from. Returns false once so
+    /// many have come in a row that the device looks gone for good.
+    pub fn device_error(&mut self, e: &anyhow::Error) -> bool {
+        self.record(AnomalyKind::DeviceError, format!("{:#}", e));
+        self.consecutive_errors += 1;
+        self.consecutive_errors < MAX_CONSECUTIVE_ERRORS
+    }
+
+    /// Takes back the error the main loop stopped on, to carry on from.
+    /// Losing the device, or too many errors in a row, passes it on as the
+    /// end of the run.
+    pub fn resume(&mut self, e: anyhow::Error) -> Result<()> {
+        if device_gone(&e) || !self.device_error(&e) {
+            return Err(e.context(Exit::DeviceLost));
+        }
+        Ok(())
+    }
+
+    /// A pass of the main loop's flip accounting, setting the mode again
+    /// once flips have stopped. True when it did, so nothing is in flight
+    /// any more.
+    pub fn check_flips(&mut self, surface: &mut Surface, flipped: bool) -> Result<bool> {
+        let sample = surface.last_flip.as_ref().filter(|_| flipped);
+        self.flipped(sample, surface.flip_stats.timed_out);
+        match self.watchdog(Instant::now(), surface.flips_completed()) {
+            Watchdog::Fine => Ok(false),
+            Watchdog::Remodeset => {
+                surface.remodeset().context(Exit::DeviceLost)?;
+                Ok(true)
+            }
+            Watchdog::GiveUp => Err(anyhow!(
+                "no flip completed even after setting the mode again"
+            ))
+            .context(Exit::DeviceLost),
+        }
+    }
+
+    /// Accounts for a completed flip and any that timed out since the last
+    /// call
+    pub fn flipped(&mut self, sample: Option<&FlipSample>, timed_out: u64) {
+        if timed_out > self.timed_out {
+            let n = timed_out - self.timed_out;
+            self.timed_out = timed_out;
+            self.record(
+                AnomalyKind::FailedFlip,
+                format!("{} flip(s) got no completion event", n),
+            );
+        }
+        if let Some(sample) = sample {
+            self.consecutive_errors = 0;
+            if sample.missed > MISSED_VBLANK_THRESHOLD {
+                self.record(
+                    AnomalyKind::MissedVblanks,
+                    format!("flip {} missed {} vblanks", sample.seq, sample.missed),
+                );
+            }
+        }
+    }
+
+    pub fn keyboard_lost(&mut self, now: Instant) {
+        self.kb_lost.get_or_insert(now);
+    }
+
+    pub fn keyboard_back(&mut self, now: Instant) {
+        if let Some(lost) = self.kb_lost.take() {
+            self.record(
+                AnomalyKind::KeyboardRecovered,
+                format!("keyboard back after {}", format_span(now - lost)),
+            );
+        }
+    }
+
+    /// Checks on the pipeline given the flips completed so far
+    pub fn watchdog(&mut self, now: Instant, flips: u64) -> Watchdog {
+        if flips != self.flips_seen {
+            self.flips_seen = flips;
+            self.last_progress = now;
+            self.remodeset = false;
+            return Watchdog::Fine;
+        }
+        if now < self.last_progress + WATCHDOG {
+            return Watchdog::Fine;
+        }
+        if self.remodeset {
+            return Watchdog::GiveUp;
+        }
+        self.record(
+            AnomalyKind::PipelineHung,
+            format!(
+                "no flip completed for {} s; setting the mode again",
+                WATCHDOG.as_secs()
+            ),
+        );
+        self.remodeset = true;
+        self.remodesets += 1;
+        self.last_progress = now;
+        Watchdog::Remodeset
+    }
+
+    /// Prints a heartbeat line if one is due, and logs the RSS with it
+    pub fn tick(&mut self, now: Instant, log: Option<&mut EventLog>) {
+        if now < self.next_heartbeat {
+            return;
+        }
+        self.next_heartbeat = now + HEARTBEAT;
+        let rss = current_rss_kb();
+        if let Some(rss) = rss {
+            self.rss_max_kb = self.rss_max_kb.max(rss);
+            if let Some(log) = log {
+                log.log(Event::ResourceUsage {
+                    rss_kb: rss,
+                    framebuffers: live_framebuffers(),
+                });
+            }
+        }
+        eprintln!(
+            "Soak: {} of {}, {} cycles, {} anomalies, RSS {}",
+            format_span(now - self.started),
+            format_span(self.duration),
+            self.cycles,
+            self.counts.values().sum::<u64>(),
+            rss.map_or("unknown".to_string(), |kb| format!("{} kB", kb))
+        );
+    }
+
+    pub fn report(&self, now: Instant) -> SoakReport {
+        let ran = now - self.started;
+        SoakReport {
+            duration_s: self.duration.as_secs(),
+            ran_s: ran.as_secs(),
+            completed: ran >= self.duration,
+            cycles: self.cycles,
+            anomaly_counts: self
+                .counts
+                .iter()
+                .map(|(kind, n)| (kind.name().to_string(), *n))
+                .collect(),
+            anomalies: self.anomalies.clone(),
+            remodesets: self.remodesets,
+            rss_start_kb: self.rss_start_kb,
+            rss_end_kb: current_rss_kb(),
+            rss_max_kb: self.rss_max_kb,
+        }
+    }
+}
+
+/// Prints the tally of a finished soak and gives its verdict for the report:
+/// a pass needs the whole duration run without an anomaly
+pub fn verdict(report: &SoakReport) -> Verdict {
+    let anomalies: u64 = report.anomaly_counts.values().sum();
+    eprintln!(
+        "Soak: {} cycles in {} s, {} anomalies",
+        report.cycles, report.ran_s, anomalies
+    );
+    Verdict {
+        name: "soak".to_string(),
+        pass: report.completed && anomalies == 0,
+        detail: format!(
+            "{} cycles in {} of {} s, {} anomalies",
+            report.cycles, report.ran_s, report.duration_s, anomalies
+        ),
+    }
+}
+
+/// Whether `e` means the display device itself is gone, past recovering
+/// from
+pub fn device_gone(e: &anyhow::Error) -> bool {
+    e.chain().any(|cause| {
+        cause
+            .downcast_ref::<std::io::Error>()
+            .and_then(std::io::Error::raw_os_error)
+            .or_else(|| cause.downcast_ref::<Errno>().map(|e| *e as i32))
+            .is_some_and(|errno| errno == Errno::ENODEV as i32)
+    })
+}
+
+// 1h05m, 3m20s, 45s
+fn format_span(d: Duration) -> String {
+    let s = d.as_secs();
+    match (s / 3600, s / 60 % 60, s % 60) {
+        (0, 0, s) => format!("{}s", s),
+        (0, m, s) => format!("{}m{:02}s", m, s),
+        (h, m, _) => format!("{}h{:02}m", h, m),
+    }
+}
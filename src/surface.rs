@@ -0,0 +1,1972 @@
+use anyhow::{Context, Result, anyhow, bail, ensure};
+
+use drm::Device as DrmDevice;
+use drm::buffer::{Buffer, DrmFourcc};
+use drm::control as ctrl;
+use drm::control::dumbbuffer::DumbBuffer;
+use drm::control::{
+    Device as CtrlDevice, PageFlipFlags, ResourceHandle, connector, crtc, framebuffer, property,
+};
+use nix::poll::{PollFd, PollFlags, PollTimeout, poll};
+use std::collections::VecDeque;
+use std::fs::{File, OpenOptions};
+
+use crate::backend::Backend;
+use crate::color::YuvMatrix;
+use crate::damage::Damage;
+use crate::draw::SubpixelOrder;
+use crate::edid;
+use crate::exit::Exit;
+use crate::lut::{self, Lut};
+use crate::nv12::{Nv12Layout, Nv12Plane};
+use crate::timing::{FlipSample, FlipStats};
+use std::os::unix::io::{AsFd, BorrowedFd};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+
+// Flips kept in Surface::recent_flips
+const FLIP_HISTORY: usize = 8;
+
+#[derive(Debug)]
+pub struct Card(File, PathBuf);
+
+impl AsFd for Card {
+    fn as_fd(&self) -> BorrowedFd<'_> {
+        self.0.as_fd()
+    }
+}
+
+impl DrmDevice for Card {}
+
+impl CtrlDevice for Card {}
+
+impl Card {
+    pub fn open(path: &Path) -> Result<Self> {
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(path)
+            .with_context(|| format!("could not open DRM device {}", path.display()))?;
+        eprintln!("Opened DRM device: {}", path.display());
+        Ok(Card(file, path.to_path_buf()))
+    }
+
+    pub fn open_default() -> Result<Self> {
+        let mut options = OpenOptions::new();
+        options.read(true).write(true);
+
+        // Try card0 first, then card1, then card2
+        for i in 0..=2 {
+            let path = format!("/dev/dri/card{}", i);
+            if let Ok(file) = options.open(&path) {
+                eprintln!("Opened DRM device: {}", path);
+                return Ok(Card(file, path.into()));
+            }
+        }
+
+        Err(anyhow!(
+            "Could not open any DRM device (tried card0, card1, card2)"
+        ))
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.1
+    }
+}
+
+// Kernel-style connector name, e.g. "HDMI-A-2"
+pub fn connector_name(info: &connector::Info) -> String {
+    format!("{}-{}", info.interface().as_str(), info.interface_id())
+}
+
+// Kernel-style connector name plus a guess at the physical port it refers to,
+// e.g. "HDMI-A-2 (HDMI port 2)"
+fn connector_label(info: &connector::Info) -> String {
+    use connector::Interface;
+
+    let iface = info.interface();
+    let idx = info.interface_id();
+    let port = match iface {
+        Interface::HDMIA | Interface::HDMIB => format!("HDMI port {}", idx),
+        Interface::DisplayPort => format!("DisplayPort {}", idx),
+        Interface::DVII | Interface::DVID | Interface::DVIA => format!("DVI port {}", idx),
+        Interface::VGA => format!("VGA port {}", idx),
+        Interface::EmbeddedDisplayPort => "built-in panel, eDP".to_string(),
+        Interface::LVDS => "built-in panel, LVDS".to_string(),
+        Interface::DSI => "built-in panel, MIPI DSI".to_string(),
+        Interface::DPI => "parallel RGB panel".to_string(),
+        Interface::SPI => "SPI panel".to_string(),
+        Interface::USB => "USB display adapter".to_string(),
+        Interface::Virtual => "virtual output".to_string(),
+        Interface::Writeback => "writeback, no physical display".to_string(),
+        _ => format!("{} port {}", iface.as_str(), idx),
+    };
+
+    format!("{} ({})", connector_name(info), port)
+}
+
+// Prints every connector with its state and modes, for --list
+pub fn list_outputs(card: &Card) -> Result<()> {
+    let res = card
+        .resource_handles()
+        .context("could not load resource handles")?;
+
+    for &con in res.connectors() {
+        let info = card.get_connector(con, false)?;
+        println!("{}: {:?}", connector_label(&info), info.state());
+        for mode in info.modes() {
+            let (w, h) = mode.size();
+            let preferred = mode.mode_type().contains(ctrl::ModeTypeFlags::PREFERRED);
+            println!(
+                "    {}x{}{} @ {}Hz{}",
+                w,
+                h,
+                if is_interlaced(mode) { "i" } else { "" },
+                mode.vrefresh(),
+                if preferred { " (preferred)" } else { "" }
+            );
+        }
+    }
+
+    Ok(())
+}
+
+/// A connector found by connected_outputs
+pub struct ConnectedOutput {
+    pub name: String,
+    /// The native size its EDID gives, if it has one
+    pub native: Option<(u32, u32)>,
+}
+
+/// Every connector that is connected and has modes
+pub fn connected_outputs(card: &Card) -> Result<Vec<ConnectedOutput>> {
+    let res = card
+        .resource_handles()
+        .context("could not load resource handles")?;
+
+    let mut outputs = Vec::new();
+    for &con in res.connectors() {
+        let info = card.get_connector(con, false)?;
+        if info.state() == connector::State::Connected && !info.modes().is_empty() {
+            let native = edid::read_edid(card, con).and_then(|e| edid::native_size(&e));
+            outputs.push(ConnectedOutput {
+                name: connector_name(&info),
+                native,
+            });
+        }
+    }
+    Ok(outputs)
+}
+
+/// What to do when a page flip's completion event never arrives, as on some
+/// buggy drivers and virtual GPUs, rather than wait on it forever
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum FlipTimeout {
+    /// After two frame intervals, carry on as if the flip had completed
+    #[default]
+    Assume,
+    /// As Assume, then present every frame from then on with set_crtc,
+    /// paced at the refresh rate, with no flip events at all
+    SetCrtc,
+    /// Wait for the event however long it takes
+    Off,
+}
+
+/// Which connector a surface should drive.
+#[derive(Clone, Debug, Default)]
+pub enum ConnectorSelector {
+    /// The first connector that is connected and has modes
+    #[default]
+    FirstConnected,
+    /// A connector by kernel name, e.g. "HDMI-A-1" (case-insensitive)
+    Name(String),
+}
+
+/// Which of the connector's modes to set.
+#[derive(Clone, Debug, Default)]
+pub enum ModeSelector {
+    /// The mode flagged preferred, else the first listed
+    #[default]
+    Preferred,
+    /// A specific size, optionally at a specific refresh rate. Without a
+    /// refresh rate the fastest matching mode wins.
+    Size {
+        w: u16,
+        h: u16,
+        refresh: Option<u32>,
+    },
+    /// Explicit timings, set whether or not the connector lists them
+    Custom(ctrl::Mode),
+}
+
+/// Modeline timings, as `cvt` and `gtf` print them: the pixel clock, then
+/// display, sync start, sync end and total for each axis.
+#[derive(Clone, Copy, Debug)]
+pub struct ModeTimings {
+    pub clock_khz: u32,
+    pub h: [u16; 4],
+    pub v: [u16; 4],
+    pub flags: ctrl::ModeFlags,
+}
+
+impl ModeTimings {
+    /// Checks the timings hang together and builds the mode, with its
+    /// vrefresh rounded from the clock and totals
+    pub fn to_mode(self) -> Result<ctrl::Mode> {
+        ensure!(self.clock_khz > 0, "the pixel clock must be above 0");
+        for (axis, [display, sync_start, sync_end, total]) in [("h", self.h), ("v", self.v)] {
+            ensure!(display > 0, "{}display must be above 0", axis);
+            ensure!(
+                display <= sync_start && sync_start < sync_end && sync_end <= total,
+                "{} timings must satisfy {0}display <= {0}sync_start < {0}sync_end <= {0}total, got {} {} {} {}",
+                axis,
+                display,
+                sync_start,
+                sync_end,
+                total
+            );
+        }
+        let refresh = timings_refresh(self.clock_khz, self.h[3], self.v[3], self.flags);
+        ensure!(
+            refresh >= 1.0,
+            "the timings come to {:.3} Hz, below 1 Hz",
+            refresh
+        );
+
+        let mut name = [0; 32];
+        let label = format!("{}x{}", self.h[0], self.v[0]);
+        for (dst, src) in name.iter_mut().zip(label.bytes()) {
+            *dst = src as _;
+        }
+        Ok(drm_ffi::drm_mode_modeinfo {
+            clock: self.clock_khz,
+            hdisplay: self.h[0],
+            hsync_start: self.h[1],
+            hsync_end: self.h[2],
+            htotal: self.h[3],
+            vdisplay: self.v[0],
+            vsync_start: self.v[1],
+            vsync_end: self.v[2],
+            vtotal: self.v[3],
+            vrefresh: refresh.round() as u32,
+            flags: self.flags.bits(),
+            type_: ctrl::ModeTypeFlags::USERDEF.bits(),
+            name,
+            ..Default::default()
+        }
+        .into())
+    }
+}
+
+// Field (interlaced) or frame rate from the timings, as the kernel works it out
+fn timings_refresh(clock_khz: u32, htotal: u16, vtotal: u16, flags: ctrl::ModeFlags) -> f64 {
+    let mut hz = clock_khz as f64 * 1000.0 / (htotal as f64 * vtotal as f64);
+    if flags.contains(ctrl::ModeFlags::INTERLACE) {
+        hz *= 2.0;
+    }
+    if flags.contains(ctrl::ModeFlags::DBLSCAN) {
+        hz /= 2.0;
+    }
+    hz
+}
+
+/// The refresh rate of `mode` from its clock and totals, unrounded
+pub fn exact_refresh(mode: &ctrl::Mode) -> f64 {
+    let (htotal, vtotal) = (mode.hsync().2, mode.vsync().2);
+    timings_refresh(mode.clock(), htotal, vtotal, mode.flags())
+}
+
+/// What connector selection needs to know, detached from the device so the
+/// policy can run over synthetic lists.
+pub struct ConnectorDesc {
+    pub name: String,
+    pub state: connector::State,
+    pub modes: Vec<ctrl::Mode>,
+}
+
+/// Only Connected connectors with modes are usable, unless `accept_unknown`
+/// lets in those whose detection came back Unknown but that still have modes.
+pub fn select_connector(
+    cons: &[ConnectorDesc],
+    sel: &ConnectorSelector,
+    accept_unknown: bool,
+) -> Option<usize> {
+    let usable = |c: &ConnectorDesc| {
+        let state_ok = match c.state {
+            connector::State::Connected => true,
+            connector::State::Unknown => accept_unknown,
+            _ => false,
+        };
+        state_ok && !c.modes.is_empty()
+    };
+
+    match sel {
+        ConnectorSelector::FirstConnected => cons.iter().position(usable),
+        ConnectorSelector::Name(name) => cons
+            .iter()
+            .position(|c| c.name.eq_ignore_ascii_case(name) && usable(c)),
+    }
+}
+
+pub fn is_interlaced(mode: &ctrl::Mode) -> bool {
+    mode.flags().contains(ctrl::ModeFlags::INTERLACE)
+}
+
+/// Interlaced modes are skipped unless `allow_interlaced` is set or the
+/// connector offers nothing else, since the patterns assume progressive scan.
+pub fn select_mode(
+    modes: &[ctrl::Mode],
+    sel: &ModeSelector,
+    allow_interlaced: bool,
+) -> Option<ctrl::Mode> {
+    let progressive: Vec<ctrl::Mode> = modes
+        .iter()
+        .filter(|m| !is_interlaced(m))
+        .copied()
+        .collect();
+    let modes = if allow_interlaced || progressive.is_empty() {
+        modes
+    } else {
+        &progressive
+    };
+
+    match *sel {
+        ModeSelector::Custom(mode) => Some(mode),
+        ModeSelector::Preferred => modes
+            .iter()
+            .find(|m| m.mode_type().contains(ctrl::ModeTypeFlags::PREFERRED))
+            .or_else(|| modes.first())
+            .copied(),
+        ModeSelector::Size { w, h, refresh } => modes
+            .iter()
+            .filter(|m| m.size() == (w, h))
+            .filter(|m| refresh.is_none_or(|hz| m.vrefresh() == hz))
+            .max_by_key(|m| m.vrefresh())
+            .copied(),
+    }
+}
+
+// Depth and bits per pixel for the formats the patterns can render into. All
+// of them share the B, G, R, X/A byte order the drawing code writes.
+fn format_depth_bpp(format: DrmFourcc) -> Result<(u32, u32)> {
+    match format {
+        DrmFourcc::Xrgb8888 => Ok((24, 32)),
+        DrmFourcc::Argb8888 => Ok((32, 32)),
+        _ => bail!("unsupported framebuffer format {}", format),
+    }
+}
+
+// Sets the connector's "content type" enum property, which HDMI sinks use to
+// pick a picture mode
+fn set_content_type(card: &Card, con: connector::Handle, name: &str) -> Result<String> {
+    set_enum_property(card, con, "content type", name)
+}
+
+/// Sets the enum property `prop_name` of a KMS object. `value` matches the
+/// driver's value names ignoring case, with '-' standing for a space
+/// ("no-data" is "No Data"). Returns the name as the driver spells it.
+pub fn set_enum_property<T: ResourceHandle>(
+    card: &Card,
+    handle: T,
+    prop_name: &str,
+    value: &str,
+) -> Result<String> {
+    let props = card
+        .get_properties(handle)
+        .context("could not read properties")?;
+    let (handles, _) = props.as_props_and_values();
+    let (prop, info) = handles
+        .iter()
+        .find_map(|&prop| {
+            let info = card.get_property(prop).ok()?;
+            (info.name().to_bytes() == prop_name.as_bytes()).then_some((prop, info))
+        })
+        .ok_or_else(|| anyhow!("there is no {} property", prop_name))?;
+
+    let property::ValueType::Enum(values) = info.value_type() else {
+        bail!("the {} property is not an enum", prop_name);
+    };
+    let (_, entries) = values.values();
+    let wanted = value.replace('-', " ");
+    let entry = entries
+        .iter()
+        .find(|e| e.name().to_string_lossy().eq_ignore_ascii_case(&wanted))
+        .ok_or_else(|| {
+            let names: Vec<_> = entries
+                .iter()
+                .map(|e| e.name().to_string_lossy().to_lowercase().replace(' ', "-"))
+                .collect();
+            anyhow!(
+                "unknown {} {} (this driver offers {})",
+                prop_name,
+                value,
+                names.join(", ")
+            )
+        })?;
+
+    card.set_property(handle, prop, entry.value())
+        .with_context(|| format!("could not set the {} property", prop_name))?;
+    Ok(entry.name().to_string_lossy().into_owned())
+}
+
+/// The connector's "max bpc" property: the most bits per channel the driver
+/// may run the link at, which it lowers further to fit the bandwidth
+#[derive(Clone, Copy, Debug)]
+pub struct MaxBpc {
+    pub value: u64,
+    // What the driver accepts
+    pub min: u64,
+    pub max: u64,
+}
+
+fn find_max_bpc(card: &Card, con: connector::Handle) -> Result<(property::Handle, MaxBpc)> {
+    let props = card
+        .get_properties(con)
+        .context("could not read properties")?;
+    let (handles, values) = props.as_props_and_values();
+    let (prop, info, value) = handles
+        .iter()
+        .zip(values)
+        .find_map(|(&prop, &raw)| {
+            let info = card.get_property(prop).ok()?;
+            (info.name().to_bytes() == b"max bpc").then_some((prop, info, raw))
+        })
+        .ok_or_else(|| anyhow!("the driver has no max bpc property on this connector"))?;
+    let property::ValueType::UnsignedRange(min, max) = info.value_type() else {
+        bail!("the max bpc property is not a range");
+    };
+    Ok((prop, MaxBpc { value, min, max }))
+}
+
+fn read_max_bpc(card: &Card, con: connector::Handle) -> Result<MaxBpc> {
+    find_max_bpc(card, con).map(|(_, bpc)| bpc)
+}
+
+// Sets "max bpc", before the modeset that should go by it
+fn set_max_bpc(card: &Card, con: connector::Handle, bpc: u64) -> Result<MaxBpc> {
+    let (prop, current) = find_max_bpc(card, con)?;
+    ensure!(
+        (current.min..=current.max).contains(&bpc),
+        "max bpc {} is outside the {} to {} this driver allows",
+        bpc,
+        current.min,
+        current.max
+    );
+    card.set_property(con, prop, bpc)
+        .context("could not set the max bpc property")?;
+    Ok(MaxBpc {
+        value: bpc,
+        ..current
+    })
+}
+
+/// Bits per channel the link to `con_name` actually runs at. No property
+/// says; amdgpu alone reports it, in debugfs, so this takes root and a
+/// mounted debugfs as well.
+fn output_bpc(card: &Card, con_name: &str) -> Option<u32> {
+    let minor = card.path().file_name()?.to_str()?.strip_prefix("card")?;
+    let path = format!("/sys/kernel/debug/dri/{}/{}/output_bpc", minor, con_name);
+    let text = std::fs::read_to_string(path).ok()?;
+    text.lines()
+        .find_map(|line| line.strip_prefix("Current:"))
+        .and_then(|bpc| bpc.trim().parse().ok())
+}
+
+/// Connector properties that can change while a run goes on, with enum
+/// values as the driver names them; None where the driver has no such
+/// property
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct LinkProps {
+    /// "Good" or "Bad"; Bad means the link failed to train and the mode
+    /// should be set again
+    pub link_status: Option<String>,
+    /// HDCP: "Undesired", "Desired" or "Enabled"
+    pub content_protection: Option<String>,
+    pub non_desktop: Option<bool>,
+    pub max_bpc: Option<u64>,
+    /// Bits per channel the link runs at, where the driver says
+    pub output_bpc: Option<u32>,
+}
+
+impl LinkProps {
+    pub fn link_bad(&self) -> bool {
+        self.link_status.as_deref() == Some("Bad")
+    }
+
+    /// Each property by its KMS name, for logging what changed
+    pub fn fields(&self) -> [(&'static str, Option<String>); 5] {
+        [
+            ("link-status", self.link_status.clone()),
+            ("Content Protection", self.content_protection.clone()),
+            ("non-desktop", self.non_desktop.map(|v| v.to_string())),
+            ("max bpc", self.max_bpc.map(|v| v.to_string())),
+            ("output bpc", self.output_bpc.map(|v| v.to_string())),
+        ]
+    }
+}
+
+fn read_link_props(card: &Card, con: connector::Handle, con_name: &str) -> Result<LinkProps> {
+    let props = card
+        .get_properties(con)
+        .context("could not read properties")?;
+    let (handles, values) = props.as_props_and_values();
+    let mut link = LinkProps {
+        output_bpc: output_bpc(card, con_name),
+        ..Default::default()
+    };
+    for (&prop, &raw) in handles.iter().zip(values) {
+        let Ok(info) = card.get_property(prop) else {
+            continue;
+        };
+        let enum_name = || match info.value_type() {
+            property::ValueType::Enum(values) => values
+                .values()
+                .1
+                .iter()
+                .find(|e| e.value() == raw)
+                .map(|e| e.name().to_string_lossy().into_owned()),
+            _ => None,
+        };
+        match info.name().to_bytes() {
+            b"link-status" => link.link_status = enum_name(),
+            b"Content Protection" => link.content_protection = enum_name(),
+            b"non-desktop" => link.non_desktop = Some(raw != 0),
+            b"max bpc" => link.max_bpc = Some(raw),
+            _ => {}
+        }
+    }
+    Ok(link)
+}
+
+// The connector, CRTC and mode a surface drives
+pub struct Output {
+    pub con: connector::Handle,
+    // Kernel name, e.g. "DP-1"
+    pub name: String,
+    pub crtc: crtc::Handle,
+    pub mode: ctrl::Mode,
+    pub label: String,
+    pub subpixel: Option<SubpixelOrder>,
+    pub size_mm: Option<(u32, u32)>,
+}
+
+// Dumb buffers with a framebuffer on them that haven't been destroyed yet,
+// for --leak-watch
+static LIVE_FRAMEBUFFERS: AtomicUsize = AtomicUsize::new(0);
+
+/// How many framebuffers this process has created and not yet destroyed
+pub fn live_framebuffers() -> usize {
+    LIVE_FRAMEBUFFERS.load(Ordering::Relaxed)
+}
+
+pub fn count_framebuffer(created: bool) {
+    if created {
+        LIVE_FRAMEBUFFERS.fetch_add(1, Ordering::Relaxed);
+    } else {
+        LIVE_FRAMEBUFFERS.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+pub struct Frame {
+    pub db: DumbBuffer,
+    pub fb: framebuffer::Handle,
+    pub disp_h: usize,
+    pub stride: usize,
+}
+
+impl Frame {
+    pub fn create(card: &Card, w: u32, h: u32, format: DrmFourcc) -> Result<Self> {
+        let (depth, bpp) = format_depth_bpp(format)?;
+
+        let db = card.create_dumb_buffer((w, h), format, bpp)?;
+
+        let fb = match card.add_framebuffer(&db, depth, bpp) {
+            Ok(fb) => fb,
+            Err(e) => {
+                let _ = card.destroy_dumb_buffer(db);
+                return Err(e.into());
+            }
+        };
+
+        let stride = db.pitch();
+        count_framebuffer(true);
+
+        Ok(Frame {
+            db,
+            fb,
+            disp_h: h as usize,
+            stride: stride as usize,
+        })
+    }
+
+    pub fn destroy(&self, card: &Card) {
+        let _ = card.destroy_framebuffer(self.fb);
+        let _ = card.destroy_dumb_buffer(self.db);
+        count_framebuffer(false);
+    }
+}
+
+/// Configures how a Surface picks its device, output and buffers. With no
+/// customization it opens the first card, drives the first connected
+/// connector at its preferred mode, and double buffers in XRGB8888.
+pub struct SurfaceBuilder {
+    card: Option<PathBuf>,
+    connector: ConnectorSelector,
+    mode: ModeSelector,
+    format: DrmFourcc,
+    buffer_count: usize,
+    content_type: Option<String>,
+    max_bpc: Option<u32>,
+    allow_interlaced: bool,
+    accept_unknown: bool,
+    nv12: Option<(Nv12Layout, YuvMatrix)>,
+    flip_timeout: FlipTimeout,
+}
+
+impl Default for SurfaceBuilder {
+    fn default() -> Self {
+        Self {
+            card: None,
+            connector: ConnectorSelector::default(),
+            mode: ModeSelector::default(),
+            format: DrmFourcc::Xrgb8888,
+            buffer_count: 2,
+            content_type: None,
+            max_bpc: None,
+            allow_interlaced: false,
+            accept_unknown: false,
+            nv12: None,
+            flip_timeout: FlipTimeout::default(),
+        }
+    }
+}
+
+impl SurfaceBuilder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn card(mut self, path: impl Into<PathBuf>) -> Self {
+        self.card = Some(path.into());
+        self
+    }
+
+    pub fn connector(mut self, sel: ConnectorSelector) -> Self {
+        self.connector = sel;
+        self
+    }
+
+    pub fn mode(mut self, sel: ModeSelector) -> Self {
+        self.mode = sel;
+        self
+    }
+
+    pub fn format(mut self, format: DrmFourcc) -> Self {
+        self.format = format;
+        self
+    }
+
+    pub fn flip_timeout(mut self, timeout: FlipTimeout) -> Self {
+        self.flip_timeout = timeout;
+        self
+    }
+
+    pub fn buffer_count(mut self, n: usize) -> Self {
+        self.buffer_count = n;
+        self
+    }
+
+    /// HDMI content type to signal to the sink, e.g. "game"
+    pub fn content_type(mut self, name: Option<String>) -> Self {
+        self.content_type = name;
+        self
+    }
+
+    /// Value for the connector's max bpc property, e.g. 6 or 8 to force a
+    /// lower link depth
+    pub fn max_bpc(mut self, bpc: Option<u32>) -> Self {
+        self.max_bpc = bpc;
+        self
+    }
+
+    /// Let mode selection pick interlaced modes
+    pub fn allow_interlaced(mut self, allow: bool) -> Self {
+        self.allow_interlaced = allow;
+        self
+    }
+
+    /// Also consider connectors whose state is Unknown, after forcing a
+    /// fresh probe of them, as long as they report modes
+    pub fn accept_unknown(mut self, accept: bool) -> Self {
+        self.accept_unknown = accept;
+        self
+    }
+
+    /// Also present every frame through an NV12 plane (see nv12.rs)
+    pub fn nv12(mut self, nv12: Option<(Nv12Layout, YuvMatrix)>) -> Self {
+        self.nv12 = nv12;
+        self
+    }
+
+    pub fn open_card(&self) -> Result<Card> {
+        match &self.card {
+            Some(path) => Card::open(path),
+            None => Card::open_default(),
+        }
+        .context(Exit::DeviceOpen)
+    }
+
+    /// Whether another process, such as a compositor, is DRM master on the
+    /// card open_card would pick, so setting a mode would fail. Opens the
+    /// card again on its own, quietly; None if it can't be opened.
+    pub fn master_taken(&self) -> Option<bool> {
+        let paths = match &self.card {
+            Some(path) => vec![path.clone()],
+            None => (0..=2)
+                .map(|i| PathBuf::from(format!("/dev/dri/card{}", i)))
+                .collect(),
+        };
+        let (file, path) = paths.into_iter().find_map(|path| {
+            let file = OpenOptions::new().read(true).write(true).open(&path);
+            file.ok().map(|file| (file, path))
+        })?;
+        // The first to open a card without a master becomes it, so only a
+        // master held elsewhere refuses this
+        let busy = Card(file, path).acquire_master_lock().err()?;
+        Some(busy.raw_os_error() == Some(nix::errno::Errno::EBUSY as i32))
+    }
+
+    /// Resolves the connector, mode and CRTC on an open card without
+    /// touching the display.
+    pub fn select_output(&self, card: &Card) -> Result<Output> {
+        let res = card
+            .resource_handles()
+            .context("could not load resource handles")?;
+
+        let mut infos = Vec::new();
+        for &con in res.connectors() {
+            let mut info = card.get_connector(con, false)?;
+            if self.accept_unknown {
+                // A forced probe runs detection again, which settles some
+                // connectors that a cached Unknown left undecided
+                if info.state() == connector::State::Unknown {
+                    info = card.get_connector(con, true)?;
+                }
+                eprintln!(
+                    "{}: {:?}, {} modes",
+                    connector_name(&info),
+                    info.state(),
+                    info.modes().len()
+                );
+            }
+            infos.push(info);
+        }
+
+        let descs: Vec<ConnectorDesc> = infos
+            .iter()
+            .map(|info| ConnectorDesc {
+                name: connector_name(info),
+                state: info.state(),
+                modes: info.modes().to_vec(),
+            })
+            .collect();
+
+        let hint =
+            if !self.accept_unknown && select_connector(&descs, &self.connector, true).is_some() {
+                "; one is in an unknown state, pass --accept-unknown to try it"
+            } else {
+                ""
+            };
+        let idx = select_connector(&descs, &self.connector, self.accept_unknown)
+            .ok_or_else(|| match &self.connector {
+                ConnectorSelector::FirstConnected => anyhow!("no connected display{}", hint),
+                ConnectorSelector::Name(name) => {
+                    anyhow!("connector {} not found or not connected{}", name, hint)
+                }
+            })
+            .context(Exit::NoDisplay)?;
+        let info = &infos[idx];
+
+        let mode = match select_mode(info.modes(), &self.mode, self.allow_interlaced) {
+            Some(mode) => mode,
+            None if select_mode(info.modes(), &self.mode, true).is_some() => bail!(
+                "{} only has interlaced modes matching {:?}; pass --allow-interlaced to use one",
+                descs[idx].name,
+                self.mode
+            ),
+            None => bail!("{} has no mode matching {:?}", descs[idx].name, self.mode),
+        };
+
+        // Keep the CRTC already driving this connector if there is one,
+        // otherwise take the first CRTC any of its encoders can use
+        let crtc = match info.current_encoder() {
+            Some(enc) => card.get_encoder(enc)?.crtc(),
+            None => None,
+        };
+        let crtc = match crtc {
+            Some(crtc) => crtc,
+            None => info
+                .encoders()
+                .iter()
+                .filter_map(|&enc| card.get_encoder(enc).ok())
+                .flat_map(|enc| res.filter_crtcs(enc.possible_crtcs()))
+                .next()
+                .ok_or_else(|| anyhow!("no crtc for {}", descs[idx].name))?,
+        };
+
+        Ok(Output {
+            con: info.handle(),
+            name: descs[idx].name.clone(),
+            crtc,
+            mode,
+            label: connector_label(info),
+            subpixel: match info.subpixel() {
+                connector::SubPixel::HorizontalRgb => Some(SubpixelOrder::Rgb),
+                connector::SubPixel::HorizontalBgr => Some(SubpixelOrder::Bgr),
+                connector::SubPixel::VerticalRgb => Some(SubpixelOrder::VerticalRgb),
+                connector::SubPixel::VerticalBgr => Some(SubpixelOrder::VerticalBgr),
+                _ => None,
+            },
+            size_mm: info.size().filter(|&(w, h)| w > 0 && h > 0),
+        })
+    }
+
+    pub fn build(self) -> Result<Surface> {
+        ensure!(self.buffer_count >= 2, "need at least 2 buffers");
+
+        let card = self.open_card()?;
+
+        let Output {
+            con,
+            name: connector_name,
+            crtc,
+            mode,
+            label: connector_label,
+            subpixel,
+            size_mm,
+        } = self.select_output(&card)?;
+        eprintln!("Using connector: {}", connector_label);
+        if is_interlaced(&mode) {
+            eprintln!(
+                "Warning: {}x{} @ {}Hz is an interlaced mode; the patterns assume progressive scan, so motion and fine detail will show combing",
+                mode.size().0,
+                mode.size().1,
+                mode.vrefresh()
+            );
+        }
+
+        let (disp_w, disp_h) = (mode.size().0 as u32, mode.size().1 as u32);
+
+        let mut frames = Vec::with_capacity(self.buffer_count);
+        for _ in 0..self.buffer_count {
+            match Frame::create(&card, disp_w, disp_h, self.format) {
+                Ok(f) => frames.push(f),
+                Err(e) => {
+                    frames.iter().for_each(|f| f.destroy(&card));
+                    return Err(e);
+                }
+            }
+        }
+
+        // Set before the modeset so the first AVI InfoFrame already carries it
+        let content_type = self.content_type.as_deref().and_then(|name| {
+            set_content_type(&card, con, name)
+                .inspect_err(|e| eprintln!("Content type not set: {:#}", e))
+                .ok()
+        });
+        let max_bpc = self
+            .max_bpc
+            .and_then(|bpc| {
+                set_max_bpc(&card, con, bpc as u64)
+                    .inspect_err(|e| eprintln!("Max bpc not set: {:#}", e))
+                    .ok()
+            })
+            .or_else(|| read_max_bpc(&card, con).ok());
+
+        if let Err(e) = card.set_crtc(crtc, Some(frames[0].fb), (0, 0), &[con], Some(mode)) {
+            frames.iter().for_each(|f| f.destroy(&card));
+            return Err(e).context("failed to set crtc");
+        }
+        // A cursor the compositor left on the CRTC would sit over the
+        // patterns; there's no pointer here, so clear it
+        #[allow(deprecated)]
+        let _ = card.set_cursor(crtc, None::<&DumbBuffer>);
+
+        let nv12 = match self.nv12 {
+            Some((layout, matrix)) => {
+                let (w, h) = (disp_w as usize, disp_h as usize);
+                match Nv12Plane::open(&card, crtc, w, h, layout, matrix) {
+                    Ok(plane) => Some(plane),
+                    Err(e) => {
+                        let _ = card.set_crtc(crtc, None, (0, 0), &[], None);
+                        frames.iter().for_each(|f| f.destroy(&card));
+                        return Err(e).context("--nv12 is not possible on this output");
+                    }
+                }
+            }
+            None => None,
+        };
+
+        let edid = edid::read_edid(&card, con);
+        let output_bpc = output_bpc(&card, &connector_name);
+
+        Ok(Surface {
+            card,
+            con,
+            crtc,
+            mode,
+            format: self.format,
+            connector_label,
+            edid,
+            subpixel,
+            size_mm,
+            content_type,
+            max_bpc,
+            output_bpc,
+            connector_name,
+            disp_w: disp_w as usize,
+            disp_h: disp_h as usize,
+            flips: FlipQueue::new(frames.len()),
+            frames,
+            queue_frames: false,
+            flip_submitted: None,
+            flip_timeout: self.flip_timeout,
+            set_crtc_fallback: false,
+            flip_stats: FlipStats::default(),
+            last_flip: None,
+            recent_flips: VecDeque::with_capacity(FLIP_HISTORY),
+            lut: None,
+            nv12,
+            damage: None,
+        })
+    }
+}
+
+// Which buffer is scanned out and which flips are outstanding, apart from
+// the DRM calls that move them along. A frame is written to target(); then
+// request() says whether to submit its flip now or hold it until complete()
+// finishes the flip ahead of it.
+#[derive(Debug)]
+struct FlipQueue {
+    buffers: usize,
+    front: usize,
+    // A flip has been submitted and not completed
+    pending: bool,
+    // A frame is in the spare buffer, waiting for the pending flip
+    queued: bool,
+    // Flips submitted so far
+    submitted: u64,
+}
+
+impl FlipQueue {
+    fn new(buffers: usize) -> Self {
+        Self {
+            buffers,
+            front: 0,
+            pending: false,
+            queued: false,
+            submitted: 0,
+        }
+    }
+
+    // The buffer the next submitted flip shows
+    fn back(&self) -> usize {
+        (self.front + 1) % self.buffers
+    }
+
+    // Where the next frame goes: the back buffer, or the one after it while
+    // the back buffer waits to be scanned out
+    fn target(&self) -> usize {
+        (self.front + 1 + usize::from(self.pending)) % self.buffers
+    }
+
+    // With `queue`, one frame may wait behind the pending flip when there is
+    // a third buffer to hold it
+    fn can_present(&self, queue: bool) -> bool {
+        !self.pending || (queue && self.buffers >= 3 && !self.queued)
+    }
+
+    // A flip of the target was asked for: true to submit it now, false if it
+    // waits for the pending one
+    fn request(&mut self) -> bool {
+        self.queued = self.pending;
+        !self.pending
+    }
+
+    fn submitted(&mut self) {
+        self.pending = true;
+        self.submitted += 1;
+    }
+
+    // The pending flip completed. Returns whether a queued frame is now due
+    // to be submitted.
+    fn complete(&mut self) -> bool {
+        self.front = self.back();
+        self.pending = false;
+        std::mem::take(&mut self.queued)
+    }
+
+    // Forgets the pending flip and anything queued behind it
+    fn abandon(&mut self) {
+        self.pending = false;
+        self.queued = false;
+    }
+
+    fn completed(&self) -> u64 {
+        self.submitted - u64::from(self.pending)
+    }
+}
+
+pub struct Surface {
+    pub card: Card,
+    con: connector::Handle,
+    crtc: crtc::Handle,
+    pub mode: ctrl::Mode,
+    format: DrmFourcc,
+    connector_label: String,
+    pub edid: Option<Vec<u8>>,
+    /// Subpixel layout the connector reports, if it knows one
+    pub subpixel: Option<SubpixelOrder>,
+    /// Picture size the connector reports, usually from the EDID
+    pub size_mm: Option<(u32, u32)>,
+    /// The HDMI content type that was set, as the driver names it
+    pub content_type: Option<String>,
+    /// The connector's max bpc property, None when the driver has none
+    pub max_bpc: Option<MaxBpc>,
+    /// Bits per channel the link runs at, where the driver says
+    pub output_bpc: Option<u32>,
+    // Kernel name of the connector, e.g. "DP-1"
+    connector_name: String,
+    pub disp_w: usize,
+    pub disp_h: usize,
+    frames: Vec<Frame>,
+    flips: FlipQueue,
+    /// Let a frame be written to a spare buffer while a flip is pending and
+    /// flip it the moment that one completes. Needs three or more buffers.
+    pub queue_frames: bool,
+    flip_submitted: Option<Instant>,
+    flip_timeout: FlipTimeout,
+    // FlipTimeout::SetCrtc has kicked in: frames go out with set_crtc and
+    // "complete" one frame interval after they are submitted
+    set_crtc_fallback: bool,
+    pub flip_stats: FlipStats,
+    pub last_flip: Option<FlipSample>,
+    /// The last few flips, newest last
+    pub recent_flips: VecDeque<FlipSample>,
+    // Software LUT applied on the way into the framebuffer
+    lut: Option<Box<Lut>>,
+    /// The NV12 plane every frame is also shown through, with --nv12
+    pub nv12: Option<Nv12Plane>,
+    // With --damage, what each buffer is missing, so only that is copied
+    damage: Option<Damage>,
+}
+
+impl Surface {
+    pub fn refresh_hz(&self) -> f64 {
+        match self.mode.vrefresh() {
+            0 => 60.0,
+            hz => hz as f64,
+        }
+    }
+
+    pub fn device_path(&self) -> &Path {
+        self.card.path()
+    }
+
+    pub fn connector_label(&self) -> &str {
+        &self.connector_label
+    }
+
+    /// The link's bit depth as far as the driver tells, e.g. "link at 8 bpc,
+    /// max bpc 10 (6-16)"
+    pub fn link_depth(&self) -> String {
+        let link = match self.output_bpc {
+            Some(bpc) => format!("link at {} bpc", bpc),
+            None => "link bpc not reported by the driver".to_string(),
+        };
+        match self.max_bpc {
+            Some(m) => format!("{}, max bpc {} ({}-{})", link, m.value, m.min, m.max),
+            None => format!("{}, no max bpc property", link),
+        }
+    }
+
+    /// Reads the connector's link properties afresh
+    pub fn link_props(&self) -> Result<LinkProps> {
+        read_link_props(&self.card, self.con, &self.connector_name)
+    }
+
+    /// The output in one line, e.g. "DP-1 1920x1080 @ 60Hz, link Good, 8 bpc
+    /// (max 10), HDCP Undesired", leaving out what the driver doesn't report
+    pub fn link_summary(&self, link: &LinkProps) -> String {
+        let mut parts = vec![format!("{} {}", self.connector_name, self.mode_name())];
+        parts.push(match &link.link_status {
+            Some(status) => format!("link {}", status),
+            None => "link status not reported".to_string(),
+        });
+        match (link.output_bpc, link.max_bpc) {
+            (Some(bpc), Some(max)) => parts.push(format!("{} bpc (max {})", bpc, max)),
+            (Some(bpc), None) => parts.push(format!("{} bpc", bpc)),
+            (None, Some(max)) => parts.push(format!("max bpc {}", max)),
+            (None, None) => {}
+        }
+        if let Some(hdcp) = &link.content_protection {
+            parts.push(format!("HDCP {}", hdcp));
+        }
+        if link.non_desktop == Some(true) {
+            parts.push("non-desktop".to_string());
+        }
+        parts.join(", ")
+    }
+
+    pub fn interlaced(&self) -> bool {
+        is_interlaced(&self.mode)
+    }
+
+    /// The mode as `WxH @ HZHz`, with an `i` after the size if interlaced
+    pub fn mode_name(&self) -> String {
+        format!(
+            "{}x{}{} @ {}Hz",
+            self.disp_w,
+            self.disp_h,
+            if self.interlaced() { "i" } else { "" },
+            self.mode.vrefresh()
+        )
+    }
+
+    pub fn mode_label(&self) -> String {
+        format!("{} {}", self.connector_label, self.mode_name())
+    }
+
+    /// Resolves `sel` against the connector's current mode list, with the
+    /// same rules as the initial mode
+    pub fn find_mode(&self, sel: &ModeSelector, allow_interlaced: bool) -> Result<ctrl::Mode> {
+        let info = self
+            .card
+            .get_connector(self.con, false)
+            .context("could not read the connector")?;
+        select_mode(info.modes(), sel, allow_interlaced)
+            .ok_or_else(|| anyhow!("{} has no mode matching {:?}", self.connector_label, sel))
+    }
+
+    /// Switches to `mode`, reallocating the buffers at its size. Call it
+    /// between flips; on failure the old mode and buffers stay in place.
+    pub fn set_mode(&mut self, mode: ctrl::Mode) -> Result<()> {
+        ensure!(!self.flips.pending, "flip pending");
+        let (w, h) = (mode.size().0 as u32, mode.size().1 as u32);
+
+        let mut frames = Vec::with_capacity(self.frames.len());
+        for _ in 0..self.frames.len() {
+            match Frame::create(&self.card, w, h, self.format) {
+                Ok(f) => frames.push(f),
+                Err(e) => {
+                    frames.iter().for_each(|f| f.destroy(&self.card));
+                    return Err(e);
+                }
+            }
+        }
+
+        if let Err(e) = self.card.set_crtc(
+            self.crtc,
+            Some(frames[0].fb),
+            (0, 0),
+            &[self.con],
+            Some(mode),
+        ) {
+            frames.iter().for_each(|f| f.destroy(&self.card));
+            return Err(e).context("failed to set crtc");
+        }
+
+        for f in std::mem::replace(&mut self.frames, frames) {
+            f.destroy(&self.card);
+        }
+        // The NV12 plane is sized from the mode, so it starts over too
+        if let Some(old) = self.nv12.take() {
+            old.destroy(&self.card);
+            let (layout, matrix) = (old.layout, old.matrix);
+            match Nv12Plane::open(
+                &self.card, self.crtc, w as usize, h as usize, layout, matrix,
+            ) {
+                Ok(plane) => self.nv12 = Some(plane),
+                Err(e) => eprintln!("NV12 plane dropped after the mode switch: {:#}", e),
+            }
+        }
+        self.mode = mode;
+        if let Some(damage) = &mut self.damage {
+            damage.reset();
+        }
+        // The new mode may need more bandwidth than the old depth leaves
+        self.output_bpc = output_bpc(&self.card, &self.connector_name);
+        self.disp_w = w as usize;
+        self.disp_h = h as usize;
+        self.flips.front = 0;
+        // The modeset is not a missed vblank
+        self.flip_stats.resync();
+        Ok(())
+    }
+
+    /// Abandons any pending flip and sets the current mode again from
+    /// scratch, to get a stalled pipeline going
+    pub fn remodeset(&mut self) -> Result<()> {
+        self.flips.abandon();
+        self.flip_submitted = None;
+        self.set_mode(self.mode)
+    }
+
+    /// Whether a flip has been submitted and not yet completed
+    pub fn is_flipping(&self) -> bool {
+        self.flips.pending
+    }
+
+    /// Whether a frame can be written and flipped now, either straight away
+    /// or queued behind the pending flip
+    pub fn can_present(&self) -> bool {
+        self.flips
+            .can_present(self.queue_frames && self.nv12.is_none())
+    }
+
+    /// Flips that have completed, as opposed to been submitted
+    pub fn flips_completed(&self) -> u64 {
+        self.flips.completed()
+    }
+
+    #[inline]
+    pub fn stride(&self) -> usize {
+        self.frames[0].stride
+    }
+
+    /// Copies only what changed since each buffer was last written, from
+    /// now on (--damage)
+    pub fn track_damage(&mut self) {
+        self.damage = Some(Damage::new(self.frames.len()));
+    }
+
+    pub fn write_to_back(&mut self, src: &[u8], src_stride: usize) -> Result<()> {
+        self.write_to_back_scaled(src, src_stride, 1)
+    }
+
+    /// Like write_to_back, but `src` is 1/`scale` of the screen's size and
+    /// every pixel of it becomes a `scale` x `scale` block (--render-scale).
+    /// The NV12 plane, if any, is left alone at scales above 1.
+    pub fn write_to_back_scaled(
+        &mut self,
+        src: &[u8],
+        src_stride: usize,
+        scale: usize,
+    ) -> Result<()> {
+        ensure!(self.can_present(), "no buffer free to write to");
+        let back = self.flips.target();
+        let disp_w = self.disp_w;
+        let frame = &mut self.frames[back];
+        let src_rows = frame.disp_h.div_ceil(scale);
+        ensure!(
+            src.len() >= src_stride * src_rows,
+            "source buffer too small"
+        );
+
+        let mut map = self.card.map_dumb_buffer(&mut frame.db)?;
+
+        // What the scaled path writes isn't what the tracker compares
+        let regions = match &mut self.damage {
+            Some(damage) if scale == 1 => damage.take(back, src, src_stride, frame.disp_h),
+            Some(damage) => {
+                damage.reset();
+                None
+            }
+            None => None,
+        };
+
+        if scale > 1 {
+            // Each row is expanded once into memory that is cheap to read back,
+            // since the mapping may be uncached
+            let n = frame.stride.min(disp_w * 4);
+            let mut row = vec![0u8; disp_w.div_ceil(scale) * scale * 4];
+            let mut mapped = vec![0u8; n];
+            let rows = &mut map[..frame.stride * frame.disp_h];
+            for (sy, chunk) in rows.chunks_mut(frame.stride * scale).enumerate() {
+                expand_row(&mut row, &src[sy * src_stride..], scale);
+                let out = match &self.lut {
+                    None => &row[..n],
+                    Some(table) => {
+                        lut::apply_row(table, &mut mapped, &row[..n]);
+                        &mapped
+                    }
+                };
+                for d in chunk.chunks_mut(frame.stride) {
+                    d[..n].copy_from_slice(out);
+                    d[n..].fill(0);
+                }
+            }
+            return Ok(());
+        }
+
+        if let Some(regions) = regions {
+            let n = src_stride.min(frame.stride);
+            for region in regions {
+                let bytes = region.bytes.start.min(n)..region.bytes.end.min(n);
+                for y in region.rows {
+                    let d = &mut map[y * frame.stride..][bytes.clone()];
+                    let s = &src[y * src_stride..][bytes.clone()];
+                    match &self.lut {
+                        None => d.copy_from_slice(s),
+                        Some(table) => lut::apply_row(table, d, s),
+                    }
+                }
+            }
+        } else {
+            match &self.lut {
+                None => copy_rows(&mut map, frame.stride, src, src_stride, frame.disp_h),
+                Some(table) => {
+                    let n = src_stride.min(frame.stride);
+                    for y in 0..frame.disp_h {
+                        let d = &mut map[y * frame.stride..(y + 1) * frame.stride];
+                        lut::apply_row(
+                            table,
+                            &mut d[..n],
+                            &src[y * src_stride..y * src_stride + n],
+                        );
+                        d[n..].fill(0);
+                    }
+                }
+            }
+        }
+        drop(map);
+
+        // The LUT is for the RGB path only
+        if let Some(nv12) = &mut self.nv12 {
+            nv12.write(&self.card, src, src_stride)?;
+        }
+
+        Ok(())
+    }
+
+    /// Sets the LUT write_to_back passes every pixel through, or none
+    pub fn set_lut(&mut self, table: Option<Lut>) {
+        self.lut = table.map(Box::new);
+        if let Some(damage) = &mut self.damage {
+            damage.reset();
+        }
+    }
+
+    // Maps the buffer currently being scanned out and compares it with `src`,
+    // row by row over the bytes write_to_back would have copied. Returns the
+    // first row that differs, or None if the contents match.
+    pub fn front_mismatch(&mut self, src: &[u8], src_stride: usize) -> Result<Option<usize>> {
+        let frame = &mut self.frames[self.flips.front];
+        ensure!(
+            src.len() >= src_stride * frame.disp_h,
+            "source buffer too small"
+        );
+        let (stride, rows) = (frame.stride, frame.disp_h);
+        let n = src_stride.min(stride);
+
+        let map = self.card.map_dumb_buffer(&mut frame.db)?;
+
+        // With a LUT the buffer holds the mapped pixels, so compare with those
+        let mut mapped = vec![0u8; n];
+        Ok((0..rows).find(|&y| {
+            let mut expected = &src[y * src_stride..y * src_stride + n];
+            if let Some(table) = &self.lut {
+                lut::apply_row(table, &mut mapped, expected);
+                expected = &mapped;
+            }
+            map[y * stride..y * stride + n] != *expected
+        }))
+    }
+
+    pub fn flip(&mut self) -> Result<()> {
+        ensure!(self.can_present(), "flip already pending");
+        // A queued frame is submitted by complete_flip once the pending
+        // flip is done
+        if self.flips.request() {
+            self.submit_flip()?;
+        }
+        Ok(())
+    }
+
+    fn submit_flip(&mut self) -> Result<()> {
+        if let Some(nv12) = &mut self.nv12 {
+            nv12.show(&self.card)?;
+        }
+
+        let target_frame = &self.frames[self.flips.back()];
+
+        if self.set_crtc_fallback {
+            self.card
+                .set_crtc(
+                    self.crtc,
+                    Some(target_frame.fb),
+                    (0, 0),
+                    &[self.con],
+                    Some(self.mode),
+                )
+                .context("set_crtc failed")?;
+        } else {
+            self.card
+                .page_flip(self.crtc, target_frame.fb, PageFlipFlags::EVENT, None)?;
+        }
+
+        self.flips.submitted();
+        self.flip_submitted = Some(Instant::now());
+
+        Ok(())
+    }
+
+    // Drains every event from one read; returning early would drop the rest
+    pub fn handle_drm_events(&mut self) -> Result<bool> {
+        let mut flipped = false;
+        for event in self.card.receive_events()? {
+            // An event arriving after its flip timed out completes whichever
+            // flip is pending by then, a frame early; there is no telling them
+            // apart
+            if let ctrl::Event::PageFlip(ev) = event
+                && self.flips.pending
+            {
+                flipped = true;
+                if let Some(submitted) = self.flip_submitted {
+                    let latency = submitted.elapsed();
+                    let missed = self.flip_stats.record(latency, ev.frame);
+                    let sample = FlipSample {
+                        timestamp: ev.duration,
+                        seq: ev.frame,
+                        latency,
+                        missed,
+                    };
+                    self.last_flip = Some(sample);
+                    if self.recent_flips.len() == FLIP_HISTORY {
+                        self.recent_flips.pop_front();
+                    }
+                    self.recent_flips.push_back(sample);
+                }
+                self.complete_flip()?;
+            }
+        }
+        debug_assert!(self.flips.pending || !self.flips.queued);
+
+        Ok(flipped)
+    }
+
+    fn complete_flip(&mut self) -> Result<()> {
+        self.flip_submitted = None;
+
+        // The queued frame goes out before anything else can run
+        if self.flips.complete() {
+            self.submit_flip()
+                .context("could not flip the queued frame")?;
+        }
+        Ok(())
+    }
+
+    /// When the pending flip counts as lost if its event hasn't arrived, or
+    /// with the set_crtc fallback, when it counts as done
+    pub fn flip_deadline(&self) -> Option<Instant> {
+        let submitted = self.flip_submitted.filter(|_| self.flips.pending)?;
+        let interval = Duration::from_secs_f64(1.0 / self.refresh_hz());
+        match self.flip_timeout {
+            _ if self.set_crtc_fallback => Some(submitted + interval),
+            FlipTimeout::Off => None,
+            FlipTimeout::Assume | FlipTimeout::SetCrtc => Some(submitted + 2 * interval),
+        }
+    }
+
+    /// Completes a pending flip whose event is overdue, as configured by
+    /// FlipTimeout. Returns whether it did.
+    pub fn expire_flip(&mut self, now: Instant) -> Result<bool> {
+        if self.flip_deadline().is_none_or(|deadline| now < deadline) {
+            return Ok(false);
+        }
+        if !self.set_crtc_fallback {
+            self.flip_stats.timed_out += 1;
+            if self.flip_stats.timed_out == 1 {
+                eprintln!(
+                    "Warning: {}: a page flip got no completion event within two frame intervals; carrying on as if it completed (later ones are only counted)",
+                    self.connector_label
+                );
+            }
+            if self.flip_timeout == FlipTimeout::SetCrtc {
+                let frame = &self.frames[self.flips.back()];
+                self.card
+                    .set_crtc(
+                        self.crtc,
+                        Some(frame.fb),
+                        (0, 0),
+                        &[self.con],
+                        Some(self.mode),
+                    )
+                    .context("set_crtc failed")?;
+                self.set_crtc_fallback = true;
+                eprintln!(
+                    "{}: presenting with set_crtc from now on, without vsync",
+                    self.connector_label
+                );
+            }
+        }
+        self.complete_flip()?;
+        Ok(true)
+    }
+
+    // Services a flip that completed while the caller was busy with something
+    // else, without blocking if none is pending
+    pub fn poll_flip(&mut self) -> Result<bool> {
+        if !self.flips.pending {
+            return Ok(false);
+        }
+
+        let mut fds = [PollFd::new(self.card.as_fd(), PollFlags::POLLIN)];
+        poll(&mut fds, PollTimeout::ZERO)?;
+        if fds[0]
+            .revents()
+            .is_some_and(|r| r.contains(PollFlags::POLLIN))
+        {
+            self.handle_drm_events()
+        } else {
+            Ok(false)
+        }
+    }
+}
+
+// Copies `rows` rows between buffers whose strides may differ, e.g. a tightly
+// packed source into a driver-aligned dumb buffer. Each row copies as many
+// bytes as both strides allow; destination bytes past the source row are
+// zeroed so no stale data is left in the padding.
+pub fn copy_rows(dst: &mut [u8], dst_stride: usize, src: &[u8], src_stride: usize, rows: usize) {
+    let n = src_stride.min(dst_stride);
+    for y in 0..rows {
+        let d = &mut dst[y * dst_stride..(y + 1) * dst_stride];
+        d[..n].copy_from_slice(&src[y * src_stride..y * src_stride + n]);
+        d[n..].fill(0);
+    }
+}
+
+/// Fills `dst` with the first `dst.len() / 4 / scale` pixels of `src`, each
+/// repeated `scale` times. Pairs go out as one 64-bit store.
+pub fn expand_row(dst: &mut [u8], src: &[u8], scale: usize) {
+    let px = |s: &[u8]| u32::from_ne_bytes([s[0], s[1], s[2], s[3]]);
+    if scale == 2 {
+        for (d, s) in dst.chunks_exact_mut(8).zip(src.chunks_exact(4)) {
+            let p = px(s) as u64;
+            d.copy_from_slice(&(p | p << 32).to_ne_bytes());
+        }
+    } else {
+        for (d, s) in dst.chunks_exact_mut(4 * scale).zip(src.chunks_exact(4)) {
+            let p = px(s).to_ne_bytes();
+            for out in d.chunks_exact_mut(4) {
+                out.copy_from_slice(&p);
+            }
+        }
+    }
+}
+
+impl Backend for Surface {
+    fn size(&self) -> (usize, usize) {
+        (self.disp_w, self.disp_h)
+    }
+
+    fn stride(&self) -> usize {
+        Surface::stride(self)
+    }
+
+    fn refresh_hz(&self) -> f64 {
+        Surface::refresh_hz(self)
+    }
+
+    fn frames_presented(&self) -> u64 {
+        self.flips.submitted
+    }
+
+    fn mode_label(&self) -> String {
+        Surface::mode_label(self)
+    }
+
+    fn edid(&self) -> Option<&[u8]> {
+        self.edid.as_deref()
+    }
+
+    fn subpixel(&self) -> Option<SubpixelOrder> {
+        self.subpixel
+    }
+
+    fn size_mm(&self) -> Option<(u32, u32)> {
+        self.size_mm
+            .or_else(|| self.edid().and_then(edid::physical_size_mm))
+    }
+
+    fn link_summary(&self) -> Option<String> {
+        let link = self.link_props().ok()?;
+        Some(Surface::link_summary(self, &link))
+    }
+
+    fn write_and_present(&mut self, src: &[u8], src_stride: usize) -> Result<()> {
+        self.write_to_back(src, src_stride)?;
+        self.flip()
+    }
+}
+
+impl Drop for Surface {
+    fn drop(&mut self) {
+        if let Some(nv12) = &self.nv12 {
+            nv12.destroy(&self.card);
+        }
+        let _ = self.card.set_crtc(self.crtc, None, (0, 0), &[], None);
+        for f in &self.frames {
+            f.destroy(&self.card);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn mode(w: u16, h: u16, hz: u32, preferred: bool, interlaced: bool) -> ctrl::Mode {
+        let mut flags = ctrl::ModeFlags::empty();
+        if interlaced {
+            flags |= ctrl::ModeFlags::INTERLACE;
+        }
+        let mut type_ = ctrl::ModeTypeFlags::DRIVER;
+        if preferred {
+            type_ |= ctrl::ModeTypeFlags::PREFERRED;
+        }
+        drm_ffi::drm_mode_modeinfo {
+            hdisplay: w,
+            vdisplay: h,
+            vrefresh: hz,
+            flags: flags.bits(),
+            type_: type_.bits(),
+            ..Default::default()
+        }
+        .into()
+    }
+
+    fn con(name: &str, state: connector::State, modes: Vec<ctrl::Mode>) -> ConnectorDesc {
+        ConnectorDesc {
+            name: name.to_string(),
+            state,
+            modes,
+        }
+    }
+
+    fn shape(m: Option<ctrl::Mode>) -> Option<((u16, u16), u32, bool)> {
+        m.map(|m| (m.size(), m.vrefresh(), is_interlaced(&m)))
+    }
+
+    #[test]
+    fn first_connected_connector_with_modes() {
+        use connector::State::*;
+        let cons = [
+            con("DP-1", Disconnected, vec![mode(640, 480, 60, true, false)]),
+            con("DP-2", Connected, vec![]),
+            con("DP-3", Unknown, vec![mode(800, 600, 60, true, false)]),
+            con(
+                "HDMI-A-1",
+                Connected,
+                vec![mode(1920, 1080, 60, true, false)],
+            ),
+        ];
+        let first = ConnectorSelector::FirstConnected;
+        assert_eq!(select_connector(&cons, &first, false), Some(3));
+        assert_eq!(select_connector(&cons, &first, true), Some(2));
+        assert_eq!(select_connector(&cons[..3], &first, false), None);
+        assert_eq!(select_connector(&[], &first, true), None);
+    }
+
+    #[test]
+    fn connector_by_name() {
+        use connector::State::*;
+        let cons = [
+            con("DP-1", Connected, vec![mode(640, 480, 60, true, false)]),
+            con(
+                "HDMI-A-1",
+                Connected,
+                vec![mode(1920, 1080, 60, true, false)],
+            ),
+            con("DP-2", Disconnected, vec![mode(640, 480, 60, true, false)]),
+            con("DP-3", Unknown, vec![mode(640, 480, 60, true, false)]),
+        ];
+        let by = |name: &str| ConnectorSelector::Name(name.to_string());
+        assert_eq!(select_connector(&cons, &by("hdmi-a-1"), false), Some(1));
+        assert_eq!(select_connector(&cons, &by("DP-2"), true), None);
+        assert_eq!(select_connector(&cons, &by("DP-3"), false), None);
+        assert_eq!(select_connector(&cons, &by("DP-3"), true), Some(3));
+        assert_eq!(select_connector(&cons, &by("VGA-1"), true), None);
+    }
+
+    #[test]
+    fn preferred_mode_else_first() {
+        let modes = [
+            mode(1280, 720, 60, false, false),
+            mode(1920, 1080, 60, true, false),
+        ];
+        let pick =
+            |modes: &[ctrl::Mode]| shape(select_mode(modes, &ModeSelector::Preferred, false));
+        assert_eq!(pick(&modes), Some(((1920, 1080), 60, false)));
+        assert_eq!(pick(&modes[..1]), Some(((1280, 720), 60, false)));
+        assert_eq!(pick(&[]), None);
+    }
+
+    #[test]
+    fn mode_by_size_takes_fastest_unless_rate_given() {
+        let modes = [
+            mode(1920, 1080, 50, false, false),
+            mode(1920, 1080, 144, false, false),
+            mode(1920, 1080, 60, true, false),
+            mode(1280, 720, 240, false, false),
+        ];
+        let size = |refresh| ModeSelector::Size {
+            w: 1920,
+            h: 1080,
+            refresh,
+        };
+        assert_eq!(
+            shape(select_mode(&modes, &size(None), false)),
+            Some(((1920, 1080), 144, false))
+        );
+        assert_eq!(
+            shape(select_mode(&modes, &size(Some(50)), false)),
+            Some(((1920, 1080), 50, false))
+        );
+        assert_eq!(select_mode(&modes, &size(Some(75)), false), None);
+        let missing = ModeSelector::Size {
+            w: 3840,
+            h: 2160,
+            refresh: None,
+        };
+        assert_eq!(select_mode(&modes, &missing, false), None);
+    }
+
+    #[test]
+    fn interlaced_modes_only_when_allowed_or_alone() {
+        let modes = [
+            mode(1920, 1080, 60, true, true),
+            mode(1920, 1080, 30, false, false),
+        ];
+        let size = ModeSelector::Size {
+            w: 1920,
+            h: 1080,
+            refresh: None,
+        };
+        assert_eq!(
+            shape(select_mode(&modes, &ModeSelector::Preferred, false)),
+            Some(((1920, 1080), 30, false))
+        );
+        assert_eq!(
+            shape(select_mode(&modes, &ModeSelector::Preferred, true)),
+            Some(((1920, 1080), 60, true))
+        );
+        assert_eq!(
+            shape(select_mode(&modes, &size, false)),
+            Some(((1920, 1080), 30, false))
+        );
+        assert_eq!(
+            shape(select_mode(&modes[..1], &size, false)),
+            Some(((1920, 1080), 60, true))
+        );
+    }
+
+    #[test]
+    fn custom_mode_bypasses_the_list() {
+        let custom = ModeTimings {
+            clock_khz: 148_500,
+            h: [1920, 2008, 2052, 2200],
+            v: [1080, 1084, 1089, 1125],
+            flags: ctrl::ModeFlags::PHSYNC | ctrl::ModeFlags::PVSYNC,
+        }
+        .to_mode()
+        .unwrap();
+        assert_eq!(custom.size(), (1920, 1080));
+        assert_eq!(custom.vrefresh(), 60);
+        assert!((exact_refresh(&custom) - 60.0).abs() < 1e-9);
+        let picked = select_mode(&[], &ModeSelector::Custom(custom), false).unwrap();
+        assert_eq!(picked.size(), (1920, 1080));
+    }
+
+    #[test]
+    fn custom_timings_are_checked() {
+        let good = ModeTimings {
+            clock_khz: 25_175,
+            h: [640, 656, 752, 800],
+            v: [480, 490, 492, 525],
+            flags: ctrl::ModeFlags::empty(),
+        };
+        assert_eq!(good.to_mode().unwrap().vrefresh(), 60);
+        let bad = [
+            ModeTimings {
+                clock_khz: 0,
+                ..good
+            },
+            ModeTimings {
+                h: [640, 656, 656, 800],
+                ..good
+            },
+            ModeTimings {
+                v: [480, 470, 492, 525],
+                ..good
+            },
+            ModeTimings {
+                v: [0, 490, 492, 525],
+                ..good
+            },
+            // Under 1 Hz
+            ModeTimings {
+                clock_khz: 1,
+                ..good
+            },
+        ];
+        for timings in bad {
+            assert!(timings.to_mode().is_err(), "{:?}", timings);
+        }
+    }
+
+    // Rows of `stride` bytes where byte i of row y is y * 64 + i
+    fn numbered(stride: usize, rows: usize) -> Vec<u8> {
+        (0..rows)
+            .flat_map(|y| (0..stride).map(move |i| (y * 64 + i) as u8))
+            .collect()
+    }
+
+    #[test]
+    fn copy_rows_from_wider_source_truncates() {
+        // 5 pixels of 20 bytes, source padded to 28 and destination to 24
+        let src = numbered(28, 3);
+        let mut dst = vec![0xee; 24 * 3];
+        copy_rows(&mut dst, 24, &src, 28, 3);
+        for y in 0..3 {
+            assert_eq!(dst[y * 24..(y + 1) * 24], src[y * 28..y * 28 + 24]);
+        }
+    }
+
+    #[test]
+    fn copy_rows_from_narrower_source_zeroes_padding() {
+        let src = numbered(20, 3);
+        let mut dst = vec![0xee; 36 * 3];
+        copy_rows(&mut dst, 36, &src, 20, 3);
+        for y in 0..3 {
+            assert_eq!(dst[y * 36..y * 36 + 20], src[y * 20..(y + 1) * 20]);
+            assert!(dst[y * 36 + 20..(y + 1) * 36].iter().all(|&b| b == 0));
+        }
+    }
+
+    #[test]
+    fn copy_rows_with_equal_strides_copies_everything() {
+        let src = numbered(32, 4);
+        let mut dst = vec![0xee; 32 * 4];
+        copy_rows(&mut dst, 32, &src, 32, 4);
+        assert_eq!(dst, src);
+    }
+
+    #[test]
+    fn copy_rows_stops_after_rows() {
+        let src = numbered(12, 4);
+        let mut dst = vec![0xee; 16 * 4];
+        copy_rows(&mut dst, 16, &src, 12, 2);
+        assert_eq!(dst[16..28], src[12..24]);
+        assert!(dst[32..].iter().all(|&b| b == 0xee));
+    }
+
+    // Frames drawn into a FlipQueue by a loop that draws whenever it may and
+    // a display that completes flips when `vblank` says, returning the order
+    // frames reached the screen
+    fn run_flips(buffers: usize, queue: bool, vblank: impl Fn(u32) -> bool) -> Vec<u32> {
+        let mut flips = FlipQueue::new(buffers);
+        let mut contents: Vec<Option<u32>> = vec![None; buffers];
+        let mut shown = Vec::new();
+        let mut drawn = 0;
+        for tick in 0..500 {
+            if vblank(tick) && flips.pending {
+                let due = flips.complete();
+                shown.push(contents[flips.front].expect("flipped to an empty buffer"));
+                assert_eq!(flips.completed(), shown.len() as u64);
+                if due {
+                    flips.submitted();
+                }
+            }
+            if flips.can_present(queue) {
+                let target = flips.target();
+                assert_ne!(target, flips.front, "drawing into the scanned-out buffer");
+                if flips.pending {
+                    assert_ne!(
+                        target,
+                        flips.back(),
+                        "drawing into the buffer being flipped to"
+                    );
+                }
+                contents[target] = Some(drawn);
+                drawn += 1;
+                if flips.request() {
+                    flips.submitted();
+                }
+            }
+            assert!(
+                flips.pending || !flips.queued,
+                "a frame queued with nothing pending"
+            );
+        }
+        // Whatever wasn't shown is still pending or queued behind it
+        assert_eq!(flips.submitted + u64::from(flips.queued), drawn as u64);
+        assert_eq!(
+            drawn - shown.len() as u32,
+            u32::from(flips.pending) + u32::from(flips.queued)
+        );
+        shown
+    }
+
+    #[test]
+    fn each_frame_is_shown_once_in_order() {
+        let vblanks: [fn(u32) -> bool; 4] = [
+            |_| true,
+            |t| t % 3 == 0,
+            |t| t % 7 < 2,
+            |t| t.wrapping_mul(2654435761) >> 29 == 0,
+        ];
+        for buffers in 2..=4 {
+            for queue in [false, true] {
+                for (i, vblank) in vblanks.iter().enumerate() {
+                    let shown = run_flips(buffers, queue, vblank);
+                    assert!(!shown.is_empty());
+                    let want: Vec<u32> = (0..shown.len() as u32).collect();
+                    assert_eq!(
+                        shown, want,
+                        "{} buffers, queue {}, vblank pattern {}",
+                        buffers, queue, i
+                    );
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn only_a_third_buffer_lets_a_frame_queue() {
+        for buffers in 2..=4 {
+            let mut flips = FlipQueue::new(buffers);
+            assert!(flips.request());
+            flips.submitted();
+            assert!(!flips.can_present(false));
+            assert_eq!(flips.can_present(true), buffers >= 3);
+            if buffers >= 3 {
+                assert_eq!(flips.target(), 2 % buffers);
+                assert!(!flips.request(), "submitted while a flip is pending");
+                // One frame waits; a second has nowhere to go
+                assert!(!flips.can_present(true));
+                assert!(flips.complete(), "the queued frame was dropped");
+                flips.submitted();
+                assert_eq!((flips.front, flips.back()), (1, 2 % buffers));
+            } else {
+                assert!(!flips.complete());
+            }
+            assert_eq!(flips.completed(), 1);
+        }
+    }
+
+    #[test]
+    fn abandoning_a_flip_drops_the_queued_frame() {
+        let mut flips = FlipQueue::new(3);
+        flips.request();
+        flips.submitted();
+        assert!(!flips.request());
+        flips.abandon();
+        assert!(flips.can_present(false));
+        assert_eq!(flips.target(), flips.back());
+        assert!(flips.request());
+        assert_eq!(flips.front, 0);
+    }
+}
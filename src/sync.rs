@@ -0,0 +1,210 @@
+//! --sync-outputs: the extra `--card`s follow the first card's script and
+//! are flipped from the same render pass, each frame numbered in a strip
+//! along the top so a camera can tell which pass each output shows. The
+//! vblank timestamps of a pass's flips, all on CLOCK_MONOTONIC, give each
+//! output's skew against the first.
+
+use std::collections::VecDeque;
+use std::time::Duration;
+
+use crate::draw::{draw_label, fill_rect};
+use crate::report::SyncSummary;
+
+// Skews averaged for the rolling figure and the lateness check
+const WINDOW: usize = 120;
+
+// Passes still waiting on a flip are given up on this many passes later
+const MAX_PENDING: usize = 8;
+
+// Bits of the pass number shown in the strip
+const STRIP_BITS: usize = 16;
+
+#[derive(Default)]
+struct Skew {
+    recent: VecDeque<f64>,
+    sum: f64,
+    count: u64,
+    max: f64,
+    // Set while the rolling average is a frame or more out
+    late: bool,
+}
+
+pub struct SyncWatch {
+    period: f64,
+    // Vblank per output for passes not yet complete on all of them, oldest
+    // first; None for an output that wasn't flipped in that pass
+    passes: VecDeque<(u64, Vec<Option<Option<Duration>>>)>,
+    // One per extra output
+    skews: Vec<Skew>,
+}
+
+impl SyncWatch {
+    /// For the first output and `extra` more, refreshing at `refresh_hz`
+    pub fn new(extra: usize, refresh_hz: f64) -> Self {
+        Self {
+            period: 1000.0 / refresh_hz,
+            passes: VecDeque::new(),
+            skews: (0..extra).map(|_| Skew::default()).collect(),
+        }
+    }
+
+    /// Notes that `pass` was flipped on the outputs marked in `flipped`
+    pub fn submitted(&mut self, pass: u64, flipped: &[bool]) {
+        let slots = flipped.iter().map(|&f| f.then_some(None)).collect();
+        self.passes.push_back((pass, slots));
+        while self.passes.len() > MAX_PENDING {
+            self.passes.pop_front();
+        }
+    }
+
+    /// Records the vblank that `output` showed `pass` at. Once every output
+    /// flipped in the pass has one, returns them all (None where an output
+    /// sat the pass out).
+    pub fn completed(
+        &mut self,
+        output: usize,
+        pass: u64,
+        vblank: Duration,
+    ) -> Option<Vec<Option<Duration>>> {
+        let i = self.passes.iter().position(|(p, _)| *p == pass)?;
+        let slot = self.passes[i].1.get_mut(output)?;
+        if slot.is_none() {
+            return None;
+        }
+        *slot = Some(Some(vblank));
+        if self.passes[i].1.iter().any(|s| s == &Some(None)) {
+            return None;
+        }
+        let (_, slots) = self.passes.remove(i)?;
+        let vblanks: Vec<Option<Duration>> = slots.into_iter().map(Option::flatten).collect();
+        if let Some(first) = vblanks[0] {
+            for (n, vblank) in vblanks.iter().enumerate().skip(1) {
+                if let Some(vblank) = vblank {
+                    let ms = (vblank.as_secs_f64() - first.as_secs_f64()) * 1000.0;
+                    self.record(n, ms);
+                }
+            }
+        }
+        Some(vblanks)
+    }
+
+    fn record(&mut self, output: usize, ms: f64) {
+        let period = self.period;
+        let Some(skew) = self.skews.get_mut(output - 1) else {
+            return;
+        };
+        skew.recent.push_back(ms);
+        if skew.recent.len() > WINDOW {
+            skew.recent.pop_front();
+        }
+        skew.sum += ms;
+        skew.count += 1;
+        skew.max = skew.max.max(ms.abs());
+
+        let rolling = skew.recent.iter().sum::<f64>() / skew.recent.len() as f64;
+        if !skew.late && skew.recent.len() == WINDOW && rolling.abs() >= 0.75 * period {
+            skew.late = true;
+            eprintln!(
+                "Warning: output {} presents {:.1} frames {} output 0 (rolling average {:+.2} ms)",
+                output,
+                rolling.abs() / period,
+                if rolling > 0.0 { "after" } else { "before" },
+                rolling
+            );
+        } else if skew.late && rolling.abs() < 0.25 * period {
+            skew.late = false;
+            eprintln!("Output {} is back in step with output 0", output);
+        }
+    }
+
+    /// Overlay lines, one per extra output that has been measured
+    pub fn status(&self) -> Vec<String> {
+        self.skews
+            .iter()
+            .enumerate()
+            .filter(|(_, s)| !s.recent.is_empty())
+            .map(|(i, s)| {
+                let rolling = s.recent.iter().sum::<f64>() / s.recent.len() as f64;
+                format!(
+                    "sync output {}: skew {:+.2} ms avg, {:.2} ms max{}",
+                    i + 1,
+                    rolling,
+                    s.max,
+                    if s.late { ", A FRAME OUT" } else { "" }
+                )
+            })
+            .collect()
+    }
+
+    pub fn summary(&self) -> Vec<SyncSummary> {
+        self.skews
+            .iter()
+            .enumerate()
+            .map(|(i, s)| SyncSummary {
+                output: i + 1,
+                passes: s.count,
+                mean_skew_us: match s.count {
+                    0 => 0,
+                    n => (s.sum / n as f64 * 1000.0).round() as i64,
+                },
+                max_skew_us: (s.max * 1000.0).round() as u64,
+                late: s.late,
+            })
+            .collect()
+    }
+}
+
+/// Numbers the frame along the top: the low bits of `pass` as white (1) and
+/// black (0) cells, most significant first, over a grey bar, with the
+/// output and pass written under it
+pub fn draw_sync_strip(
+    buf: &mut [u8],
+    stride: usize,
+    w: usize,
+    h: usize,
+    output: usize,
+    pass: u64,
+) {
+    if w < STRIP_BITS || h == 0 {
+        return;
+    }
+    let cell = w / 2 / STRIP_BITS;
+    let strip_h = (h / 40).max(8);
+    fill_rect(
+        buf,
+        stride,
+        w,
+        h,
+        0,
+        0,
+        cell * STRIP_BITS,
+        strip_h,
+        128,
+        128,
+        128,
+    );
+    for bit in 0..STRIP_BITS {
+        let v = if pass >> (STRIP_BITS - 1 - bit) & 1 == 1 {
+            255
+        } else {
+            0
+        };
+        let x = (bit * cell + 1) as isize;
+        fill_rect(
+            buf,
+            stride,
+            w,
+            h,
+            x,
+            1,
+            cell.saturating_sub(2),
+            strip_h - 2,
+            v,
+            v,
+            v,
+        );
+    }
+    let scale = (h / 540).max(1);
+    let text = format!("output {} pass {}", output, pass);
+    draw_label(buf, stride, w, h, 0, strip_h, &text, scale);
+}
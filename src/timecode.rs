@@ -0,0 +1,219 @@
+//! The timecode pattern's lower part: the frame index in large digits, the
+//! wall-clock time the frame was drawn, and the index again as a strip of
+//! black and white cells a capture can be decoded from.
+//!
+//! Strip format: CELLS cells of equal width across the whole width of the
+//! strip, each white (1) or black (0), cell i spanning columns
+//! i * w / CELLS to (i + 1) * w / CELLS.
+//!
+//! - cells 0 and 1: sync, white then black
+//! - cells 2 to 33: the frame index as an unsigned 32-bit number, most
+//!   significant bit first; it wraps after 2^32 frames
+//! - cell 34: even parity, white if the data cells hold an odd number of
+//!   whites, so data and parity together always hold an even number
+//!
+//! To read a capture, take one row through the middle of the strip, sample
+//! the center of each cell and threshold its luma at mid gray; `read_row`
+//! does this, and `decode` takes cells already sampled.
+
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::draw::{GLYPH_H, draw_text, fill_rect, text_width};
+
+pub const CELLS: usize = 35;
+
+const SYNC: [bool; 2] = [true, false];
+const DATA_BITS: usize = 32;
+
+/// Cell levels for frame `index`, left to right, white as true
+pub fn encode(index: u32) -> [bool; CELLS] {
+    let mut cells = [false; CELLS];
+    cells[..2].copy_from_slice(&SYNC);
+    for bit in 0..DATA_BITS {
+        cells[2 + bit] = index >> (DATA_BITS - 1 - bit) & 1 == 1;
+    }
+    cells[CELLS - 1] = index.count_ones() % 2 == 1;
+    cells
+}
+
+/// The frame index in `cells`, or None if there aren't CELLS of them, or
+/// the sync or parity is wrong
+pub fn decode(cells: &[bool]) -> Option<u32> {
+    if cells.len() != CELLS || cells[..2] != SYNC {
+        return None;
+    }
+    let data = &cells[2..2 + DATA_BITS];
+    let ones = data.iter().filter(|&&c| c).count();
+    if (ones % 2 == 1) != cells[CELLS - 1] {
+        return None;
+    }
+    Some(data.iter().fold(0, |index, &c| index << 1 | c as u32))
+}
+
+/// Decodes one BGRX row through the strip, `w` pixels wide
+pub fn read_row(row: &[u8], w: usize) -> Option<u32> {
+    if w < CELLS || row.len() < w * 4 {
+        return None;
+    }
+    let cells: Vec<bool> = (0..CELLS)
+        .map(|i| {
+            let x = (2 * i + 1) * w / (2 * CELLS);
+            let px = &row[x * 4..x * 4 + 4];
+            // BT.709 luma of B, G, R
+            let luma = 0.0722 * px[0] as f64 + 0.7152 * px[1] as f64 + 0.2126 * px[2] as f64;
+            luma >= 128.0
+        })
+        .collect();
+    decode(&cells)
+}
+
+/// Fills the `w` x `h` area with black, the frame counter and clock
+/// centered in it and the strip along its bottom, all sized from UI scale
+/// `ui` so they survive 4:2:0 and a lossy stream. Returns the row through
+/// the middle of the strip.
+pub fn draw(
+    buf: &mut [u8],
+    stride: usize,
+    w: usize,
+    h: usize,
+    frame: u64,
+    now: SystemTime,
+    ui: usize,
+) -> usize {
+    fill_rect(buf, stride, w, h, 0, 0, w, h, 0, 0, 0);
+
+    let strip_h = (16 * ui).min(h / 3);
+    let strip_y = h - strip_h;
+    let index = frame as u32;
+    for (i, white) in encode(index).into_iter().enumerate() {
+        let (x0, x1) = (i * w / CELLS, (i + 1) * w / CELLS);
+        let v = if white { 255 } else { 0 };
+        let y = strip_y as isize;
+        fill_rect(buf, stride, w, h, x0 as isize, y, x1 - x0, strip_h, v, v, v);
+    }
+
+    let since_epoch = now.duration_since(UNIX_EPOCH).unwrap_or_default();
+    let secs = since_epoch.as_secs();
+    let clock = format!(
+        "{:02}:{:02}:{:02}.{:03} UTC",
+        secs / 3600 % 24,
+        secs / 60 % 60,
+        secs % 60,
+        since_epoch.subsec_millis()
+    );
+    let counter = format!("{}", frame);
+    // The counter three times the size of the clock, both as large as fits
+    let room = strip_y;
+    let mut scale = 2 * ui;
+    while scale > 1
+        && (text_width(&clock, scale) > w
+            || text_width(&counter, 3 * scale) > w
+            || 5 * GLYPH_H * scale > room)
+    {
+        scale -= 1;
+    }
+    let block_h = 5 * GLYPH_H * scale;
+    let top = room.saturating_sub(block_h) / 2;
+    let cx = |text: &str, s: usize| w.saturating_sub(text_width(text, s)) / 2;
+    // Clipped above the strip, which text too big for a tiny area would
+    // otherwise cover
+    draw_text(
+        buf,
+        stride,
+        w,
+        room,
+        cx(&counter, 3 * scale),
+        top,
+        &counter,
+        3 * scale,
+        255,
+        255,
+        255,
+    );
+    let clock_y = top + 4 * GLYPH_H * scale;
+    draw_text(
+        buf,
+        stride,
+        w,
+        room,
+        cx(&clock, scale),
+        clock_y,
+        &clock,
+        scale,
+        255,
+        255,
+        255,
+    );
+    strip_y + strip_h / 2
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    const SAMPLES: [u32; 7] = [0, 1, 2, 0x8000_0000, 0x5555_5555, 123_456_789, u32::MAX];
+
+    #[test]
+    fn encode_decode_round_trips() {
+        for index in SAMPLES {
+            let cells = encode(index);
+            assert_eq!(&cells[..2], &SYNC);
+            // Data and parity always hold an even number of whites
+            assert_eq!(cells[2..].iter().filter(|&&c| c).count() % 2, 0);
+            assert_eq!(decode(&cells), Some(index), "{:#x}", index);
+        }
+        // Most significant bit first
+        let cells = encode(0x8000_0001);
+        assert!(cells[2] && !cells[3] && cells[CELLS - 2]);
+    }
+
+    #[test]
+    fn decode_rejects_damaged_cells() {
+        let cells = encode(123_456_789);
+        assert_eq!(decode(&cells[..CELLS - 1]), None);
+        assert_eq!(decode(&[&cells[..], &[false]].concat()), None);
+        // Any single flipped cell is caught, by the sync or the parity
+        for i in 0..CELLS {
+            let mut bad = cells;
+            bad[i] = !bad[i];
+            assert_eq!(decode(&bad), None, "cell {} flipped", i);
+        }
+    }
+
+    #[test]
+    fn drawn_strip_reads_back() {
+        let now = UNIX_EPOCH + Duration::from_millis(45_296_789);
+        for (w, h, ui) in [(1920, 1080, 2), (641, 481, 1), (CELLS, 48, 1), (100, 3, 1)] {
+            for frame in [0, 7, 65_535, (1 << 32) + 5] {
+                let stride = w * 4;
+                let mut buf = vec![0x55; stride * h];
+                let row = draw(&mut buf, stride, w, h, frame, now, ui);
+                assert!(row < h);
+                let line = &buf[row * stride..(row + 1) * stride];
+                // The index wraps at 2^32
+                assert_eq!(
+                    read_row(line, w),
+                    Some(frame as u32),
+                    "{}x{} frame {}",
+                    w,
+                    h,
+                    frame
+                );
+
+                // A dim, washed out capture still reads
+                let washed: Vec<u8> = line.iter().map(|&v| 60 + v / 2).collect();
+                assert_eq!(read_row(&washed, w), Some(frame as u32));
+            }
+        }
+    }
+
+    #[test]
+    fn read_row_needs_a_cell_per_pixel() {
+        let w = CELLS - 1;
+        let mut buf = vec![0; w * 4 * 3];
+        draw(&mut buf, w * 4, w, 3, 9, UNIX_EPOCH, 1);
+        assert_eq!(read_row(&buf[..w * 4], w), None);
+        assert_eq!(read_row(&buf[..8], w), None);
+    }
+}
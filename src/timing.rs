@@ -0,0 +1,401 @@
+use anyhow::{Context, Result};
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::Path;
+use std::time::{Duration, Instant};
+
+/// Limits presentation to at most N frames per second, independent of the
+/// display refresh rate. Uncapped, every frame is allowed immediately.
+pub struct FrameCap {
+    interval: Option<Duration>,
+    last: Option<Instant>,
+}
+
+impl FrameCap {
+    pub fn new(fps: Option<f64>) -> Self {
+        Self {
+            interval: fps.map(|fps| Duration::from_secs_f64(1.0 / fps)),
+            last: None,
+        }
+    }
+
+    /// Earliest time the next frame may be presented, or None if it may go now.
+    pub fn ready_at(&self) -> Option<Instant> {
+        Some(self.last? + self.interval?)
+    }
+
+    pub fn ready(&self, now: Instant) -> bool {
+        self.ready_at().is_none_or(|t| now >= t)
+    }
+
+    pub fn presented(&mut self, now: Instant) {
+        self.last = Some(now);
+    }
+}
+
+// Minimum gap between over-budget warnings, so a pattern that is always too
+// heavy doesn't flood stderr
+const BUDGET_WARN_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Counts frames whose CPU work (drawing plus the copy into the back buffer)
+/// took longer than one refresh interval. Such a frame can't be ready by the
+/// next vblank, so the flip after it is late.
+pub struct FrameBudget {
+    pub interval: Duration,
+    pub over: u64,
+    last_warning: Option<Instant>,
+}
+
+impl FrameBudget {
+    pub fn new(refresh_hz: f64) -> Self {
+        Self {
+            interval: Duration::from_secs_f64(1.0 / refresh_hz.max(1.0)),
+            over: 0,
+            last_warning: None,
+        }
+    }
+
+    /// Records one frame's CPU time. Returns true if it went over budget and
+    /// a warning is due.
+    pub fn record(&mut self, took: Duration, now: Instant) -> bool {
+        if took <= self.interval {
+            return false;
+        }
+        self.over += 1;
+        if self
+            .last_warning
+            .is_some_and(|last| now.duration_since(last) < BUDGET_WARN_INTERVAL)
+        {
+            return false;
+        }
+        self.last_warning = Some(now);
+        true
+    }
+}
+
+/// Presented frames per second, measured over one-second windows
+#[derive(Default)]
+pub struct FpsMeter {
+    pub fps: f64,
+    window_start: Option<Instant>,
+    frames: u64,
+}
+
+impl FpsMeter {
+    pub fn presented(&mut self, now: Instant) {
+        self.frames += 1;
+        self.tick(now);
+    }
+
+    /// Closes the window once a second has passed, so the rate falls to
+    /// zero when nothing is being presented
+    pub fn tick(&mut self, now: Instant) {
+        let start = *self.window_start.get_or_insert(now);
+        let elapsed = now.duration_since(start);
+        if elapsed >= Duration::from_secs(1) {
+            self.fps = self.frames as f64 / elapsed.as_secs_f64();
+            self.frames = 0;
+            self.window_start = Some(now);
+        }
+    }
+}
+
+// Latency histogram resolution and range; slower flips land in the last bucket
+const BUCKET_US: u64 = 50;
+const BUCKETS: usize = 2000;
+
+/// Running statistics over completed page flips. Latencies go into a fixed
+/// histogram so arbitrarily long runs use constant memory.
+pub struct FlipStats {
+    pub count: u64,
+    pub missed_vblanks: u64,
+    /// Flips whose completion event never came in time (FlipTimeout)
+    pub timed_out: u64,
+    last_seq: Option<u32>,
+    hist: Vec<u64>,
+    max_us: u64,
+}
+
+impl Default for FlipStats {
+    fn default() -> Self {
+        Self {
+            count: 0,
+            missed_vblanks: 0,
+            timed_out: 0,
+            last_seq: None,
+            hist: vec![0; BUCKETS],
+            max_us: 0,
+        }
+    }
+}
+
+impl FlipStats {
+    /// Records one completion: its submit-to-complete latency and the vblank
+    /// sequence number it landed on. Returns how many vblanks were skipped
+    /// since the previous flip.
+    pub fn record(&mut self, latency: Duration, seq: u32) -> u64 {
+        let us = latency.as_micros() as u64;
+        self.hist[((us / BUCKET_US) as usize).min(BUCKETS - 1)] += 1;
+        self.max_us = self.max_us.max(us);
+        self.count += 1;
+
+        let missed = self
+            .last_seq
+            .map_or(0, |last| (seq.wrapping_sub(last) as u64).saturating_sub(1));
+        self.missed_vblanks += missed;
+        self.last_seq = Some(seq);
+        missed
+    }
+
+    /// Forgets the last vblank sequence, e.g. across a modeset, so the next
+    /// flip isn't counted against the one before it
+    pub fn resync(&mut self) {
+        self.last_seq = None;
+    }
+
+    /// Upper edge of the bucket holding the given percentile (0..=100), in
+    /// microseconds, or 0 if nothing was recorded
+    pub fn latency_percentile_us(&self, pct: f64) -> u64 {
+        if self.count == 0 {
+            return 0;
+        }
+        let target = ((pct / 100.0) * self.count as f64).ceil().max(1.0) as u64;
+        let mut seen = 0;
+        for (i, &n) in self.hist.iter().enumerate() {
+            seen += n;
+            if seen >= target {
+                return ((i as u64 + 1) * BUCKET_US).min(self.max_us);
+            }
+        }
+        self.max_us
+    }
+
+    pub fn max_latency_us(&self) -> u64 {
+        self.max_us
+    }
+}
+
+/// One completed page flip as reported by the kernel
+#[derive(Clone, Copy, Debug)]
+pub struct FlipSample {
+    /// Vblank timestamp from the flip event, on CLOCK_MONOTONIC
+    pub timestamp: Duration,
+    /// Vblank sequence number
+    pub seq: u32,
+    /// From submitting the flip to handling its completion event
+    pub latency: Duration,
+    /// Vblanks skipped since the previous flip
+    pub missed: u64,
+}
+
+/// Sorts presented frames into on time and late. A frame is late when its
+/// vblank comes more than `tolerance` of a frame period after the one
+/// before, e.g. 0.5 for half a frame. The period comes with each frame, as
+/// the mode (or frame cap) had it then, and a frame whose period differs
+/// from the one before starts a fresh run instead of being judged by the
+/// old rate.
+pub struct LateFrames {
+    tolerance: f64,
+    // Vblank time and period of the frame before
+    last: Option<(Duration, Duration)>,
+    pub count: u64,
+}
+
+impl LateFrames {
+    pub fn new(tolerance: f64) -> Self {
+        Self {
+            tolerance,
+            last: None,
+            count: 0,
+        }
+    }
+
+    /// Forgets the frame before, e.g. after a still frame that was meant to
+    /// stay up
+    pub fn resync(&mut self) {
+        self.last = None;
+    }
+
+    /// Takes the vblank time of a frame due `period` after the one before.
+    /// Returns the interval if it came late.
+    pub fn frame(&mut self, timestamp: Duration, period: Duration) -> Option<Duration> {
+        let (prev, prev_period) = self.last.replace((timestamp, period))?;
+        if prev_period.abs_diff(period) > period / 100 {
+            return None;
+        }
+        let interval = timestamp.checked_sub(prev)?;
+        let late = interval.as_secs_f64() > period.as_secs_f64() * (1.0 + self.tolerance);
+        late.then(|| {
+            self.count += 1;
+            interval
+        })
+    }
+}
+
+// How often buffered rows are pushed to the file
+const CSV_FLUSH_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Appends one row per flip to a CSV file. Columns, in order:
+///
+/// - `timestamp_us`: vblank time on CLOCK_MONOTONIC, microseconds
+/// - `seq`: vblank sequence number
+/// - `latency_us`: flip submit to completion, microseconds
+/// - `interval_us`: since the previous flip's vblank (empty on the first row)
+/// - `step`: script step index the frame belongs to
+/// - `late`: 1 if any vblank was missed before this flip, else 0
+pub struct TimingCsv {
+    out: BufWriter<File>,
+    last_timestamp: Option<Duration>,
+    last_flush: Instant,
+}
+
+impl TimingCsv {
+    pub fn create(path: &Path) -> Result<Self> {
+        let file = File::create(path)
+            .with_context(|| format!("could not create timing CSV {}", path.display()))?;
+        let mut out = BufWriter::new(file);
+        writeln!(
+            out,
+            "timestamp_us,seq,latency_us,interval_us,step,late,checksum"
+        )?;
+        Ok(Self {
+            out,
+            last_timestamp: None,
+            last_flush: Instant::now(),
+        })
+    }
+
+    /// Writes a row for a completed flip, with the frame's checksum if
+    /// --frame-checksum is on
+    pub fn write(&mut self, sample: &FlipSample, step: usize, checksum: Option<u64>) -> Result<()> {
+        let interval = self
+            .last_timestamp
+            .map(|last| {
+                sample
+                    .timestamp
+                    .saturating_sub(last)
+                    .as_micros()
+                    .to_string()
+            })
+            .unwrap_or_default();
+        self.last_timestamp = Some(sample.timestamp);
+
+        writeln!(
+            self.out,
+            "{},{},{},{},{},{},{}",
+            sample.timestamp.as_micros(),
+            sample.seq,
+            sample.latency.as_micros(),
+            interval,
+            step,
+            u8::from(sample.missed > 0),
+            checksum.map(|c| format!("{:016x}", c)).unwrap_or_default()
+        )?;
+
+        if self.last_flush.elapsed() >= CSV_FLUSH_INTERVAL {
+            self.out.flush()?;
+            self.last_flush = Instant::now();
+        }
+        Ok(())
+    }
+
+    pub fn finish(mut self) -> Result<()> {
+        self.out.flush().context("could not flush timing CSV")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const HZ60: Duration = Duration::from_nanos(16_666_667);
+    const HZ30: Duration = Duration::from_nanos(33_333_333);
+
+    // Feeds vblank times `gaps` apart, each due `period` after the one before,
+    // and returns which frames came late
+    fn late_at(
+        late: &mut LateFrames,
+        start: Duration,
+        gaps: &[(Duration, Duration)],
+    ) -> Vec<usize> {
+        let mut t = start;
+        let mut found = Vec::new();
+        for (i, &(gap, period)) in gaps.iter().enumerate() {
+            t += gap;
+            if late.frame(t, period).is_some() {
+                found.push(i);
+            }
+        }
+        found
+    }
+
+    #[test]
+    fn steady_frames_are_on_time() {
+        let mut late = LateFrames::new(0.5);
+        assert!(late_at(&mut late, Duration::ZERO, &[(HZ60, HZ60); 600]).is_empty());
+        // Jitter inside the tolerance is fine too
+        let jittery: Vec<_> = (0..600)
+            .map(|i| {
+                let gap = if i % 2 == 0 {
+                    HZ60 + HZ60 / 3
+                } else {
+                    HZ60 - HZ60 / 3
+                };
+                (gap, HZ60)
+            })
+            .collect();
+        late.resync();
+        assert!(late_at(&mut late, Duration::from_secs(20), &jittery).is_empty());
+        assert_eq!(late.count, 0);
+    }
+
+    #[test]
+    fn a_missed_vblank_is_late() {
+        let mut late = LateFrames::new(0.5);
+        let mut gaps = vec![(HZ60, HZ60); 100];
+        gaps[40].0 = 2 * HZ60;
+        gaps[70].0 = HZ60 * 8 / 5;
+        assert_eq!(late_at(&mut late, Duration::ZERO, &gaps), [40, 70]);
+        assert_eq!(late.count, 2);
+
+        // The interval comes back with it
+        let mut late = LateFrames::new(0.5);
+        late.frame(Duration::from_secs(1), HZ60);
+        assert_eq!(
+            late.frame(Duration::from_secs(1) + 3 * HZ60, HZ60),
+            Some(3 * HZ60)
+        );
+    }
+
+    #[test]
+    fn a_refresh_change_starts_a_fresh_run() {
+        let mut late = LateFrames::new(0.5);
+        let mut gaps = vec![(HZ60, HZ60); 50];
+        // The mode drops to 30 Hz: the first frame at the new rate comes a
+        // long 60 Hz frame after the last, and isn't counted against it
+        gaps.extend([(HZ30, HZ30); 50]);
+        // Back up to 60, then a skip at 60 that would pass at 30
+        gaps.extend([(HZ60, HZ60); 20]);
+        gaps.push((HZ30, HZ60));
+        // A skip at 30 Hz
+        gaps.extend([(HZ30, HZ30); 5]);
+        gaps.push((2 * HZ30, HZ30));
+        assert_eq!(late_at(&mut late, Duration::ZERO, &gaps), [120, 126]);
+
+        // Periods within a percent of each other are the same rate
+        let mut late = LateFrames::new(0.5);
+        late.frame(Duration::ZERO, HZ60);
+        let drifted = HZ60 + HZ60 / 200;
+        assert!(late.frame(2 * HZ60, drifted).is_some());
+    }
+
+    #[test]
+    fn resync_and_backward_timestamps_are_not_judged() {
+        let mut late = LateFrames::new(0.5);
+        late.frame(Duration::from_secs(1), HZ60);
+        late.resync();
+        assert_eq!(late.frame(Duration::from_secs(5), HZ60), None);
+        assert_eq!(late.frame(Duration::from_secs(4), HZ60), None);
+        assert_eq!(late.count, 0);
+    }
+}
@@ -0,0 +1,359 @@
+//! `--writeback PATH`: presents on a writeback connector, which renders
+//! into a buffer in memory instead of driving a display, and saves what it
+//! rendered to PATH as a PNG after every frame. With vkms this runs the
+//! real DRM pipeline headless, in CI or a VM.
+//!
+//! Writeback connectors only exist for atomic clients, so unlike `Surface`
+//! this sets up the CRTC, primary plane and connector with atomic commits.
+//! Every commit attaches the capture buffer and asks for an out-fence,
+//! which signals once the frame has been written to it.
+
+use anyhow::{Context, Result, anyhow, bail, ensure};
+use drm::ClientCapability;
+use drm::Device as DrmDevice;
+use drm::buffer::DrmFourcc;
+use drm::control as ctrl;
+use drm::control::atomic::AtomicModeReq;
+use drm::control::{
+    AtomicCommitFlags, Device as CtrlDevice, ResourceHandle, connector, crtc, plane, property,
+};
+use nix::poll::{PollFd, PollFlags, PollTimeout, poll};
+use std::os::fd::{AsFd, FromRawFd, OwnedFd};
+use std::path::{Path, PathBuf};
+
+use crate::backend::Backend;
+use crate::preview::encode_png;
+use crate::surface::{
+    Card, ConnectorSelector, Frame, ModeSelector, connector_name, copy_rows, select_mode,
+};
+
+// How long a frame may take to be written back before it counts as lost
+const FENCE_TIMEOUT_MS: u16 = 1000;
+
+// DRM_PLANE_TYPE_PRIMARY, the value of a plane's "type" property
+const PLANE_TYPE_PRIMARY: u64 = 1;
+
+// Handles of the properties every commit sets, looked up once
+struct Props {
+    // CRTC_ID, WRITEBACK_FB_ID, WRITEBACK_OUT_FENCE_PTR
+    connector: [property::Handle; 3],
+    // ACTIVE, MODE_ID
+    crtc: [property::Handle; 2],
+    // FB_ID, CRTC_ID, then SRC_ and CRTC_ X, Y, W, H
+    plane: [property::Handle; 10],
+}
+
+pub struct Writeback {
+    card: Card,
+    con: connector::Handle,
+    crtc: crtc::Handle,
+    plane: plane::Handle,
+    props: Props,
+    mode: ctrl::Mode,
+    mode_blob: u64,
+    label: String,
+    // What the plane scans out, and what the connector writes into
+    source: Frame,
+    capture: Frame,
+    path: PathBuf,
+    frames: u64,
+}
+
+impl Writeback {
+    /// Enables atomic modesetting and writeback connectors on `card`, then
+    /// picks the writeback connector `sel` names (or the first one) and
+    /// a mode from its list.
+    pub fn open(
+        card: Card,
+        sel: &ConnectorSelector,
+        mode: &ModeSelector,
+        path: &Path,
+    ) -> Result<Self> {
+        card.set_client_capability(ClientCapability::Atomic, true)
+            .context("the driver has no atomic modesetting, which --writeback needs")?;
+        card.set_client_capability(ClientCapability::WritebackConnectors, true)
+            .context("the driver has no writeback connectors")?;
+
+        let res = card
+            .resource_handles()
+            .context("could not load resource handles")?;
+        let info = res
+            .connectors()
+            .iter()
+            .filter_map(|&con| card.get_connector(con, false).ok())
+            .filter(|info| info.interface() == connector::Interface::Writeback)
+            .find(|info| match sel {
+                ConnectorSelector::FirstConnected => true,
+                ConnectorSelector::Name(name) => connector_name(info) == *name,
+            })
+            .ok_or_else(|| match sel {
+                ConnectorSelector::FirstConnected => anyhow!("no writeback connector"),
+                ConnectorSelector::Name(name) => anyhow!("no writeback connector {}", name),
+            })?;
+        let label = connector_name(&info);
+
+        let Some(mode) = select_mode(info.modes(), mode, false) else {
+            bail!("{} has no mode matching {:?}", label, mode);
+        };
+
+        let crtc = info
+            .encoders()
+            .iter()
+            .filter_map(|&enc| card.get_encoder(enc).ok())
+            .flat_map(|enc| res.filter_crtcs(enc.possible_crtcs()))
+            .next()
+            .ok_or_else(|| anyhow!("no crtc for {}", label))?;
+        let plane = card
+            .plane_handles()
+            .context("could not list planes")?
+            .into_iter()
+            .filter_map(|p| card.get_plane(p).ok())
+            .find(|info| {
+                res.filter_crtcs(info.possible_crtcs()).contains(&crtc)
+                    && prop_value(&card, info.handle(), "type") == Some(PLANE_TYPE_PRIMARY)
+            })
+            .ok_or_else(|| anyhow!("no primary plane for {}", label))?
+            .handle();
+
+        let props = Props {
+            connector: prop_handles(
+                &card,
+                info.handle(),
+                ["CRTC_ID", "WRITEBACK_FB_ID", "WRITEBACK_OUT_FENCE_PTR"],
+            )?,
+            crtc: prop_handles(&card, crtc, ["ACTIVE", "MODE_ID"])?,
+            plane: prop_handles(
+                &card,
+                plane,
+                [
+                    "FB_ID", "CRTC_ID", "SRC_X", "SRC_Y", "SRC_W", "SRC_H", "CRTC_X", "CRTC_Y",
+                    "CRTC_W", "CRTC_H",
+                ],
+            )?,
+        };
+
+        let (w, h) = (mode.size().0 as u32, mode.size().1 as u32);
+        let source = Frame::create(&card, w, h, DrmFourcc::Xrgb8888)?;
+        let capture = match Frame::create(&card, w, h, DrmFourcc::Xrgb8888) {
+            Ok(f) => f,
+            Err(e) => {
+                source.destroy(&card);
+                return Err(e);
+            }
+        };
+        let mode_blob = match card.create_property_blob(&mode) {
+            Ok(property::Value::Blob(id)) => id,
+            Ok(_) => unreachable!("a property blob is always a blob"),
+            Err(e) => {
+                source.destroy(&card);
+                capture.destroy(&card);
+                return Err(e).context("could not create the mode blob");
+            }
+        };
+
+        eprintln!("Using writeback connector: {}", label);
+        Ok(Self {
+            card,
+            con: info.handle(),
+            crtc,
+            plane,
+            props,
+            mode,
+            mode_blob,
+            label,
+            source,
+            capture,
+            path: path.to_path_buf(),
+            frames: 0,
+        })
+    }
+
+    // Shows the source buffer and writes it back into the capture buffer.
+    // Returns the out-fence, which signals once the capture is complete.
+    fn commit(&self) -> Result<OwnedFd> {
+        let [con_crtc, wb_fb, wb_fence] = self.props.connector;
+        let [active, mode_id] = self.props.crtc;
+        let [
+            fb,
+            plane_crtc,
+            src_x,
+            src_y,
+            src_w,
+            src_h,
+            crtc_x,
+            crtc_y,
+            crtc_w,
+            crtc_h,
+        ] = self.props.plane;
+        let (w, h) = (self.mode.size().0 as u64, self.mode.size().1 as u64);
+
+        // The kernel stores the fence's file descriptor here
+        let mut fence: i32 = -1;
+        let mut req = AtomicModeReq::new();
+        req.add_property(self.con, con_crtc, property::Value::CRTC(Some(self.crtc)));
+        req.add_property(
+            self.con,
+            wb_fb,
+            property::Value::Framebuffer(Some(self.capture.fb)),
+        );
+        req.add_property(
+            self.con,
+            wb_fence,
+            property::Value::UnsignedRange(&mut fence as *mut i32 as u64),
+        );
+        req.add_property(self.crtc, active, property::Value::Boolean(true));
+        req.add_property(self.crtc, mode_id, property::Value::Blob(self.mode_blob));
+        // Source coordinates are 16.16 fixed point
+        for (prop, value) in [
+            (fb, property::Value::Framebuffer(Some(self.source.fb))),
+            (plane_crtc, property::Value::CRTC(Some(self.crtc))),
+            (src_x, property::Value::UnsignedRange(0)),
+            (src_y, property::Value::UnsignedRange(0)),
+            (src_w, property::Value::UnsignedRange(w << 16)),
+            (src_h, property::Value::UnsignedRange(h << 16)),
+            (crtc_x, property::Value::SignedRange(0)),
+            (crtc_y, property::Value::SignedRange(0)),
+            (crtc_w, property::Value::UnsignedRange(w)),
+            (crtc_h, property::Value::UnsignedRange(h)),
+        ] {
+            req.add_property(self.plane, prop, value);
+        }
+
+        self.card
+            .atomic_commit(AtomicCommitFlags::ALLOW_MODESET, req)
+            .context("atomic commit to the writeback connector failed")?;
+        ensure!(fence >= 0, "the driver returned no writeback fence");
+        // SAFETY: the kernel just opened this descriptor for us, and nothing
+        // else owns it
+        Ok(unsafe { OwnedFd::from_raw_fd(fence) })
+    }
+
+    // Waits for the writeback to finish and saves the capture buffer
+    fn save_capture(&mut self, fence: OwnedFd) -> Result<()> {
+        let mut fds = [PollFd::new(fence.as_fd(), PollFlags::POLLIN)];
+        let ready = poll(&mut fds, PollTimeout::from(FENCE_TIMEOUT_MS))
+            .context("could not wait for the writeback fence")?;
+        ensure!(
+            ready > 0,
+            "the writeback did not finish within {} ms",
+            FENCE_TIMEOUT_MS
+        );
+
+        let (w, h) = self.size();
+        let stride = self.capture.stride;
+        let map = self
+            .card
+            .map_dumb_buffer(&mut self.capture.db)
+            .context("could not map the capture buffer")?;
+        let mut rgb = Vec::with_capacity(w * h * 3);
+        for row in map.chunks(stride).take(h) {
+            for px in row[..w * 4].chunks_exact(4) {
+                rgb.extend_from_slice(&[px[2], px[1], px[0]]);
+            }
+        }
+        drop(map);
+
+        // Renamed into place, so a reader never sees half a file
+        let tmp = self.path.with_extension("tmp");
+        std::fs::write(&tmp, encode_png(&rgb, w, h))
+            .with_context(|| format!("could not write {}", tmp.display()))?;
+        std::fs::rename(&tmp, &self.path)
+            .with_context(|| format!("could not write {}", self.path.display()))
+    }
+}
+
+impl Backend for Writeback {
+    fn size(&self) -> (usize, usize) {
+        (self.mode.size().0 as usize, self.mode.size().1 as usize)
+    }
+
+    fn stride(&self) -> usize {
+        self.source.stride
+    }
+
+    fn refresh_hz(&self) -> f64 {
+        match self.mode.vrefresh() {
+            0 => 60.0,
+            hz => hz as f64,
+        }
+    }
+
+    fn frames_presented(&self) -> u64 {
+        self.frames
+    }
+
+    fn mode_label(&self) -> String {
+        let (w, h) = self.size();
+        format!("{} {}x{} @ {}Hz", self.label, w, h, self.mode.vrefresh())
+    }
+
+    fn write_and_present(&mut self, src: &[u8], src_stride: usize) -> Result<()> {
+        let (stride, rows) = (self.source.stride, self.source.disp_h);
+        let mut map = self
+            .card
+            .map_dumb_buffer(&mut self.source.db)
+            .context("could not map the source buffer")?;
+        copy_rows(&mut map, stride, src, src_stride, rows);
+        drop(map);
+
+        let fence = self.commit()?;
+        self.frames += 1;
+        self.save_capture(fence)
+    }
+}
+
+impl Drop for Writeback {
+    fn drop(&mut self) {
+        let [con_crtc, ..] = self.props.connector;
+        let [active, mode_id] = self.props.crtc;
+        let [fb, plane_crtc, ..] = self.props.plane;
+        let mut req = AtomicModeReq::new();
+        req.add_property(self.con, con_crtc, property::Value::CRTC(None));
+        req.add_property(self.crtc, active, property::Value::Boolean(false));
+        req.add_property(self.crtc, mode_id, property::Value::Blob(0));
+        req.add_property(self.plane, fb, property::Value::Framebuffer(None));
+        req.add_property(self.plane, plane_crtc, property::Value::CRTC(None));
+        let _ = self
+            .card
+            .atomic_commit(AtomicCommitFlags::ALLOW_MODESET, req);
+        let _ = self.card.destroy_property_blob(self.mode_blob);
+        self.source.destroy(&self.card);
+        self.capture.destroy(&self.card);
+    }
+}
+
+// Handles of the named properties of a KMS object, in the order given
+fn prop_handles<T: ResourceHandle, const N: usize>(
+    card: &Card,
+    handle: T,
+    names: [&str; N],
+) -> Result<[property::Handle; N]> {
+    let props = card
+        .get_properties(handle)
+        .context("could not read properties")?;
+    let (handles, _) = props.as_props_and_values();
+    let named: Vec<_> = handles
+        .iter()
+        .filter_map(|&prop| Some((prop, card.get_property(prop).ok()?)))
+        .collect();
+
+    let mut found = Vec::with_capacity(N);
+    for name in names {
+        let (prop, _) = named
+            .iter()
+            .find(|(_, info)| info.name().to_bytes() == name.as_bytes())
+            .ok_or_else(|| anyhow!("there is no {} property", name))?;
+        found.push(*prop);
+    }
+    Ok(found.try_into().expect("one handle per name"))
+}
+
+// The raw value of a KMS object's property, if it has one by that name
+fn prop_value<T: ResourceHandle>(card: &Card, handle: T, name: &str) -> Option<u64> {
+    let props = card.get_properties(handle).ok()?;
+    let (handles, values) = props.as_props_and_values();
+    handles.iter().zip(values).find_map(|(&prop, &value)| {
+        let info = card.get_property(prop).ok()?;
+        (info.name().to_bytes() == name.as_bytes()).then_some(value)
+    })
+}
@@ -0,0 +1,595 @@
+//! `/ws` on the `--preview-listen` port: a WebSocket (RFC 6455) for remote
+//! control pages. Clients send JSON commands such as `{"cmd":"next"}` or
+//! `{"cmd":"goto","step":3}` (or `"name"` for a named step), which go
+//! through the control channel like any other front end. Adding
+//! `"instance":N` sends a command to the Nth extra `--card` instead of the
+//! first. The server pushes `{"type":"state",...}` messages: the whole
+//! state when a client connects, then only the fields that changed.
+//!
+//! Every client gets a reader thread; a single hub thread owns the write
+//! side of all of them, so a stalled or vanished client never reaches the
+//! render loop.
+
+use anyhow::{Context, Result, anyhow, bail, ensure};
+use serde::Deserialize;
+use serde_json::{Map, Value, json};
+use std::io::{Read, Write};
+use std::net::TcpStream;
+use std::sync::mpsc::{Receiver, Sender, channel};
+use std::time::Duration;
+
+use crate::AppState;
+use crate::control::{Command, ControlSender};
+
+const GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+
+// Messages from clients larger than this close the connection
+const MAX_MESSAGE: usize = 64 * 1024;
+
+// A client that can't take a push within this long is dropped
+const WRITE_TIMEOUT: Duration = Duration::from_secs(1);
+
+const OP_CONTINUATION: u8 = 0x0;
+const OP_TEXT: u8 = 0x1;
+const OP_CLOSE: u8 = 0x8;
+const OP_PING: u8 = 0x9;
+const OP_PONG: u8 = 0xa;
+
+/// Commands as clients send them
+#[derive(Debug, Deserialize)]
+#[serde(tag = "cmd", rename_all = "snake_case", deny_unknown_fields)]
+enum ClientCommand {
+    Next,
+    Previous,
+    /// By index or by the step's name, one or the other
+    Goto {
+        step: Option<usize>,
+        name: Option<String>,
+    },
+    Solid {
+        rgb: [u8; 3],
+    },
+    Target {
+        rgb: [u8; 3],
+    },
+    Pause {
+        paused: bool,
+    },
+    Quit,
+    /// Resend the whole state
+    State,
+}
+
+enum HubMsg {
+    Join(u64, TcpStream),
+    Leave(u64),
+    State(Map<String, Value>),
+    Resend(u64),
+    Send(u64, Vec<u8>),
+}
+
+/// The main loop's handle for pushing state to every connected client
+#[derive(Clone)]
+pub struct Hub {
+    tx: Sender<HubMsg>,
+}
+
+impl Hub {
+    pub fn start() -> Self {
+        let (tx, rx) = channel();
+        std::thread::spawn(move || run_hub(rx));
+        Self { tx }
+    }
+
+    /// Sends the current state; clients get the fields that differ from the
+    /// last one
+    pub fn publish(&self, state: Map<String, Value>) {
+        let _ = self.tx.send(HubMsg::State(state));
+    }
+}
+
+fn run_hub(rx: Receiver<HubMsg>) {
+    let mut clients: Vec<(u64, TcpStream)> = Vec::new();
+    let mut state = Map::new();
+
+    let send = |clients: &mut Vec<(u64, TcpStream)>, id: Option<u64>, frame: &[u8]| {
+        clients.retain_mut(|(cid, stream)| {
+            id.is_some_and(|id| id != *cid) || stream.write_all(frame).is_ok()
+        });
+    };
+
+    for msg in rx {
+        match msg {
+            HubMsg::Join(id, stream) => {
+                let _ = stream.set_write_timeout(Some(WRITE_TIMEOUT));
+                clients.push((id, stream));
+                send(&mut clients, Some(id), &state_frame(&state));
+            }
+            HubMsg::Leave(id) => clients.retain(|(cid, _)| *cid != id),
+            HubMsg::State(new) => {
+                let diff: Map<String, Value> = new
+                    .iter()
+                    .filter(|(k, v)| state.get(*k) != Some(*v))
+                    .map(|(k, v)| (k.clone(), v.clone()))
+                    .collect();
+                state = new;
+                if !diff.is_empty() {
+                    send(&mut clients, None, &state_frame(&diff));
+                }
+            }
+            HubMsg::Resend(id) => send(&mut clients, Some(id), &state_frame(&state)),
+            HubMsg::Send(id, frame) => send(&mut clients, Some(id), &frame),
+        }
+    }
+}
+
+fn state_frame(fields: &Map<String, Value>) -> Vec<u8> {
+    let mut msg = Map::new();
+    msg.insert("type".to_string(), json!("state"));
+    msg.extend(fields.clone());
+    encode_frame(OP_TEXT, Value::Object(msg).to_string().as_bytes())
+}
+
+/// The whole state as pushed to clients, before the hub cuts it down to
+/// what changed: `script` is the name of the script in use and `fps` the
+/// rate frames are being presented at
+pub fn state_message(state: &AppState, script: &str, fps: f64) -> Map<String, Value> {
+    let mut msg = Map::new();
+    msg.insert("step".into(), state.script_idx.into());
+    msg.insert("steps".into(), state.script.len().into());
+    msg.insert(
+        "pattern".into(),
+        serde_json::to_value(state.pattern).unwrap_or_default(),
+    );
+    msg.insert("paused".into(), state.paused.into());
+    msg.insert("script".into(), script.into());
+    msg.insert("params".into(), state.live_json());
+    msg.insert(
+        "results".into(),
+        serde_json::to_value(&state.results).unwrap_or_default(),
+    );
+    msg.insert("fps".into(), (fps.round() as u64).into());
+    msg
+}
+
+/// Completes the handshake for a request to `/ws` and serves the client on
+/// its own thread. `headers` are the request's header lines.
+pub fn accept(
+    mut stream: TcpStream,
+    headers: &[(String, String)],
+    hub: &Hub,
+    control: ControlSender,
+    id: u64,
+) -> Result<()> {
+    let header = |name: &str| {
+        headers
+            .iter()
+            .find(|(k, _)| k.eq_ignore_ascii_case(name))
+            .map(|(_, v)| v.as_str())
+    };
+    let key = match header("Sec-WebSocket-Key") {
+        Some(key) if header("Upgrade").is_some_and(|u| u.eq_ignore_ascii_case("websocket")) => key,
+        _ => {
+            let body = "expected a WebSocket upgrade\n";
+            write!(
+                stream,
+                "HTTP/1.1 400 Bad Request\r\nContent-Type: text/plain\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                body.len(),
+                body
+            )?;
+            bail!("not a WebSocket upgrade");
+        }
+    };
+
+    write!(
+        stream,
+        "HTTP/1.1 101 Switching Protocols\r\nUpgrade: websocket\r\nConnection: Upgrade\r\nSec-WebSocket-Accept: {}\r\n\r\n",
+        accept_key(key)
+    )
+    .context("could not send handshake")?;
+
+    let writer = stream.try_clone().context("could not clone socket")?;
+    let _ = stream.set_read_timeout(None);
+    hub.tx
+        .send(HubMsg::Join(id, writer))
+        .map_err(|_| anyhow!("WebSocket hub has stopped"))?;
+
+    let tx = hub.tx.clone();
+    std::thread::spawn(move || {
+        if let Err(e) = serve_client(&mut stream, &tx, &control, id) {
+            eprintln!("WebSocket client {}: {:#}", id, e);
+        }
+        let _ = stream.shutdown(std::net::Shutdown::Both);
+        let _ = tx.send(HubMsg::Leave(id));
+    });
+    Ok(())
+}
+
+fn serve_client(
+    stream: &mut TcpStream,
+    hub: &Sender<HubMsg>,
+    control: &ControlSender,
+    id: u64,
+) -> Result<()> {
+    let reply = |frame| {
+        hub.send(HubMsg::Send(id, frame))
+            .map_err(|_| anyhow!("WebSocket hub has stopped"))
+    };
+
+    let mut partial = None;
+    loop {
+        let Some((op, payload)) = read_message(stream, &mut partial)? else {
+            return Ok(());
+        };
+        match op {
+            OP_TEXT => {
+                let text = String::from_utf8(payload).context("text message is not UTF-8")?;
+                let (instance, cmd) = match parse_command(&text) {
+                    Ok(parsed) => parsed,
+                    Err(e) => {
+                        let msg = json!({ "type": "error", "message": e.to_string() });
+                        reply(encode_frame(OP_TEXT, msg.to_string().as_bytes()))?;
+                        continue;
+                    }
+                };
+                let cmd = match cmd {
+                    ClientCommand::Next => Command::NextStep,
+                    ClientCommand::Previous => Command::PreviousStep,
+                    ClientCommand::Goto {
+                        step: Some(step),
+                        name: None,
+                    } => Command::GotoStep(step),
+                    ClientCommand::Goto {
+                        step: None,
+                        name: Some(name),
+                    } => Command::GotoName(name),
+                    ClientCommand::Goto { .. } => {
+                        let msg = json!({
+                            "type": "error",
+                            "message": "goto takes either step or name",
+                        });
+                        reply(encode_frame(OP_TEXT, msg.to_string().as_bytes()))?;
+                        continue;
+                    }
+                    ClientCommand::Solid { rgb: [r, g, b] } => Command::SetSolid(r, g, b),
+                    ClientCommand::Target { rgb: [r, g, b] } => Command::SetTarget(r, g, b),
+                    ClientCommand::Pause { paused } => Command::Pause(paused),
+                    ClientCommand::Quit => Command::Quit,
+                    ClientCommand::State => {
+                        hub.send(HubMsg::Resend(id))
+                            .map_err(|_| anyhow!("WebSocket hub has stopped"))?;
+                        continue;
+                    }
+                };
+                ensure!(
+                    control.send(cmd.to(instance)),
+                    "the display loop has exited"
+                );
+            }
+            OP_PING => reply(encode_frame(OP_PONG, &payload))?,
+            OP_CLOSE => {
+                // Echo the status code, as the closing handshake asks
+                reply(encode_frame(OP_CLOSE, &payload[..payload.len().min(2)]))?;
+                return Ok(());
+            }
+            _ => {}
+        }
+    }
+}
+
+// Splits the optional "instance" off a client message and parses the rest
+// as a command, still rejecting any other unknown field
+fn parse_command(text: &str) -> serde_json::Result<(usize, ClientCommand)> {
+    let mut msg: Map<String, Value> = serde_json::from_str(text)?;
+    let instance = match msg.remove("instance") {
+        Some(v) => serde_json::from_value(v)?,
+        None => 0,
+    };
+    Ok((instance, serde_json::from_value(Value::Object(msg))?))
+}
+
+// Reads one whole message, joining fragments. Control frames can arrive
+// between fragments; they are returned on their own and the fragments so far
+// wait in `message` for the next call. None means the peer went away
+// without a close frame.
+fn read_message(
+    stream: &mut impl Read,
+    message: &mut Option<(u8, Vec<u8>)>,
+) -> Result<Option<(u8, Vec<u8>)>> {
+    loop {
+        let mut head = [0u8; 2];
+        if stream.read_exact(&mut head).is_err() {
+            return Ok(None);
+        }
+        let fin = head[0] & 0x80 != 0;
+        let op = head[0] & 0x0f;
+        ensure!(head[1] & 0x80 != 0, "client frame is not masked");
+
+        let len = match head[1] & 0x7f {
+            126 => {
+                let mut ext = [0u8; 2];
+                stream.read_exact(&mut ext)?;
+                u16::from_be_bytes(ext) as u64
+            }
+            127 => {
+                let mut ext = [0u8; 8];
+                stream.read_exact(&mut ext)?;
+                u64::from_be_bytes(ext)
+            }
+            n => n as u64,
+        };
+        let so_far = message.as_ref().map_or(0, |(_, m)| m.len());
+        ensure!(
+            so_far as u64 + len <= MAX_MESSAGE as u64,
+            "message longer than {} bytes",
+            MAX_MESSAGE
+        );
+
+        let mut mask = [0u8; 4];
+        stream.read_exact(&mut mask)?;
+        let mut payload = vec![0u8; len as usize];
+        stream.read_exact(&mut payload)?;
+        for (i, b) in payload.iter_mut().enumerate() {
+            *b ^= mask[i % 4];
+        }
+
+        if op >= OP_CLOSE {
+            ensure!(fin, "fragmented control frame");
+            return Ok(Some((op, payload)));
+        }
+        match (message.as_mut(), op) {
+            (None, OP_CONTINUATION) => bail!("continuation without a message"),
+            (None, _) => *message = Some((op, payload)),
+            (Some((_, m)), OP_CONTINUATION) => m.extend_from_slice(&payload),
+            (Some(_), _) => bail!("new message before the last one finished"),
+        }
+        if fin {
+            return Ok(message.take());
+        }
+    }
+}
+
+// A single unmasked frame, as servers send them
+fn encode_frame(op: u8, payload: &[u8]) -> Vec<u8> {
+    let mut frame = vec![0x80 | op];
+    match payload.len() {
+        n if n < 126 => frame.push(n as u8),
+        n if n <= 0xffff => {
+            frame.push(126);
+            frame.extend_from_slice(&(n as u16).to_be_bytes());
+        }
+        n => {
+            frame.push(127);
+            frame.extend_from_slice(&(n as u64).to_be_bytes());
+        }
+    }
+    frame.extend_from_slice(payload);
+    frame
+}
+
+// Sec-WebSocket-Accept for a client's key
+fn accept_key(key: &str) -> String {
+    base64(&sha1(format!("{}{}", key.trim(), GUID).as_bytes()))
+}
+
+fn sha1(data: &[u8]) -> [u8; 20] {
+    let mut h: [u32; 5] = [0x67452301, 0xefcdab89, 0x98badcfe, 0x10325476, 0xc3d2e1f0];
+
+    let mut msg = data.to_vec();
+    msg.push(0x80);
+    while msg.len() % 64 != 56 {
+        msg.push(0);
+    }
+    msg.extend_from_slice(&(data.len() as u64 * 8).to_be_bytes());
+
+    for block in msg.chunks_exact(64) {
+        let mut w = [0u32; 80];
+        for (i, word) in block.chunks_exact(4).enumerate() {
+            w[i] = u32::from_be_bytes([word[0], word[1], word[2], word[3]]);
+        }
+        for i in 16..80 {
+            w[i] = (w[i - 3] ^ w[i - 8] ^ w[i - 14] ^ w[i - 16]).rotate_left(1);
+        }
+
+        let [mut a, mut b, mut c, mut d, mut e] = h;
+        for (i, &wi) in w.iter().enumerate() {
+            let (f, k) = match i {
+                0..20 => ((b & c) | (!b & d), 0x5a827999),
+                20..40 => (b ^ c ^ d, 0x6ed9eba1),
+                40..60 => ((b & c) | (b & d) | (c & d), 0x8f1bbcdc),
+                _ => (b ^ c ^ d, 0xca62c1d6),
+            };
+            let t = a
+                .rotate_left(5)
+                .wrapping_add(f)
+                .wrapping_add(e)
+                .wrapping_add(k)
+                .wrapping_add(wi);
+            e = d;
+            d = c;
+            c = b.rotate_left(30);
+            b = a;
+            a = t;
+        }
+        for (hv, v) in h.iter_mut().zip([a, b, c, d, e]) {
+            *hv = hv.wrapping_add(v);
+        }
+    }
+
+    let mut out = [0u8; 20];
+    for (chunk, v) in out.chunks_exact_mut(4).zip(h) {
+        chunk.copy_from_slice(&v.to_be_bytes());
+    }
+    out
+}
+
+fn base64(data: &[u8]) -> String {
+    const ALPHABET: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+    let mut out = String::new();
+    for chunk in data.chunks(3) {
+        let n = chunk
+            .iter()
+            .enumerate()
+            .fold(0u32, |n, (i, &b)| n | (b as u32) << (16 - 8 * i));
+        for i in 0..4 {
+            if i <= chunk.len() {
+                out.push(ALPHABET[(n >> (18 - 6 * i) & 0x3f) as usize] as char);
+            } else {
+                out.push('=');
+            }
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    // A frame as a client sends it: masked, with the shortest length form
+    // unless `long` asks for the 8-byte one
+    fn client_frame(fin: bool, op: u8, payload: &[u8], long: bool) -> Vec<u8> {
+        let mask = [0x37, 0xfa, 0x21, 0x3d];
+        let mut frame = vec![if fin { 0x80 | op } else { op }];
+        match payload.len() {
+            n if n < 126 && !long => frame.push(0x80 | n as u8),
+            n if n <= 0xffff && !long => {
+                frame.push(0x80 | 126);
+                frame.extend_from_slice(&(n as u16).to_be_bytes());
+            }
+            n => {
+                frame.push(0x80 | 127);
+                frame.extend_from_slice(&(n as u64).to_be_bytes());
+            }
+        }
+        frame.extend_from_slice(&mask);
+        frame.extend(payload.iter().enumerate().map(|(i, b)| b ^ mask[i % 4]));
+        frame
+    }
+
+    // Every message in `bytes`, and whether they ended with the peer going
+    // away rather than an error
+    fn read_all(bytes: Vec<u8>) -> (Vec<(u8, Vec<u8>)>, bool) {
+        let mut stream = Cursor::new(bytes);
+        let mut partial = None;
+        let mut msgs = Vec::new();
+        loop {
+            match read_message(&mut stream, &mut partial) {
+                Ok(Some(msg)) => msgs.push(msg),
+                Ok(None) => return (msgs, true),
+                Err(_) => return (msgs, false),
+            }
+        }
+    }
+
+    #[test]
+    fn accept_key_matches_rfc_example() {
+        assert_eq!(
+            accept_key("dGhlIHNhbXBsZSBub25jZQ=="),
+            "s3pPLMBiTxaQ9kYGzzhZRbK+xOo="
+        );
+    }
+
+    #[test]
+    fn sha1_and_base64_known_answers() {
+        let hex = |d: [u8; 20]| d.iter().map(|b| format!("{:02x}", b)).collect::<String>();
+        assert_eq!(
+            hex(sha1(b"abc")),
+            "a9993e364706816aba3e25717850c26c9cd0d89d"
+        );
+        // Long enough that the padding spills into a second block
+        assert_eq!(
+            hex(sha1(&[b'a'; 56])),
+            "c2db330f6083854c99d4b5bfb6e8f29f201be699"
+        );
+
+        assert_eq!(base64(b""), "");
+        assert_eq!(base64(b"f"), "Zg==");
+        assert_eq!(base64(b"fo"), "Zm8=");
+        assert_eq!(base64(b"foo"), "Zm9v");
+        assert_eq!(base64(b"foobar"), "Zm9vYmFy");
+    }
+
+    #[test]
+    fn masked_frame_is_unmasked() {
+        let text = br#"{"cmd":"next"}"#;
+        let (msgs, clean) = read_all(client_frame(true, OP_TEXT, text, false));
+        assert_eq!(msgs, [(OP_TEXT, text.to_vec())]);
+        assert!(clean);
+    }
+
+    #[test]
+    fn unmasked_frame_is_refused() {
+        let (msgs, clean) = read_all(encode_frame(OP_TEXT, b"hi"));
+        assert!(msgs.is_empty() && !clean);
+    }
+
+    #[test]
+    fn fragments_are_joined_around_a_control_frame() {
+        let mut bytes = client_frame(false, OP_TEXT, b"{\"cmd\":", false);
+        bytes.extend(client_frame(true, OP_PING, b"hello", false));
+        bytes.extend(client_frame(false, OP_CONTINUATION, b"\"prev", false));
+        bytes.extend(client_frame(true, OP_CONTINUATION, b"ious\"}", false));
+
+        let (msgs, clean) = read_all(bytes);
+        assert_eq!(
+            msgs,
+            [
+                (OP_PING, b"hello".to_vec()),
+                (OP_TEXT, br#"{"cmd":"previous"}"#.to_vec())
+            ]
+        );
+        assert!(clean);
+    }
+
+    #[test]
+    fn continuation_without_a_message_is_refused() {
+        let (msgs, clean) = read_all(client_frame(true, OP_CONTINUATION, b"x", false));
+        assert!(msgs.is_empty() && !clean);
+    }
+
+    #[test]
+    fn extended_lengths_are_read() {
+        let payload: Vec<u8> = (0..300).map(|i| i as u8).collect();
+        for long in [false, true] {
+            let (msgs, clean) = read_all(client_frame(true, OP_TEXT, &payload, long));
+            assert_eq!(msgs, [(OP_TEXT, payload.clone())]);
+            assert!(clean);
+        }
+
+        let fits = vec![b'x'; MAX_MESSAGE];
+        let (msgs, _) = read_all(client_frame(true, OP_TEXT, &fits, true));
+        assert_eq!(msgs, [(OP_TEXT, fits)]);
+    }
+
+    #[test]
+    fn oversized_messages_are_refused() {
+        let big = vec![b'x'; MAX_MESSAGE + 1];
+        let (msgs, clean) = read_all(client_frame(true, OP_TEXT, &big, true));
+        assert!(msgs.is_empty() && !clean);
+
+        // Fragments count together
+        let half = vec![b'x'; MAX_MESSAGE / 2 + 1];
+        let mut bytes = client_frame(false, OP_TEXT, &half, false);
+        bytes.extend(client_frame(true, OP_CONTINUATION, &half, false));
+        let (msgs, clean) = read_all(bytes);
+        assert!(msgs.is_empty() && !clean);
+    }
+
+    #[test]
+    fn encode_frame_picks_the_length_form() {
+        let frame = encode_frame(OP_TEXT, &[b'a'; 125]);
+        assert_eq!(frame[..2], [0x81, 125]);
+        assert_eq!(frame.len(), 2 + 125);
+
+        let frame = encode_frame(OP_TEXT, &[b'a'; 126]);
+        assert_eq!(frame[..4], [0x81, 126, 0, 126]);
+        assert_eq!(frame.len(), 4 + 126);
+
+        let frame = encode_frame(OP_PONG, &[b'a'; 0x10000]);
+        assert_eq!(frame[..2], [0x8a, 127]);
+        assert_eq!(frame[2..10], 0x10000u64.to_be_bytes());
+        assert_eq!(frame.len(), 10 + 0x10000);
+    }
+}